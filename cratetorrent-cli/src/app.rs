@@ -1,19 +1,30 @@
 use std::collections::HashMap;
-use std::{fs, path::PathBuf, time::Duration};
+use std::{
+    fs,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
 use cratetorrent::{
     alert::AlertReceiver,
-    conf::{Conf, TorrentAlertConf, TorrentConf},
-    engine::{EngineHandle, Mode, TorrentParams},
+    conf::{Conf, SeedGoalConf, TorrentAlertConf, TorrentConf},
+    engine::{AddTorrent, EngineHandle, Mode, TorrentParams},
+    magnet::Magnet,
     metainfo::Metainfo,
     storage_info::StorageInfo,
-    torrent::stats::{Channel, Peers, PieceStats, Thruput, TorrentStats},
+    torrent::stats::{
+        Channel, Peers, PieceStats, Thruput, TorrentStats, TrackerInfo,
+    },
     FileInfo, TorrentId,
 };
 use futures::stream::{Fuse, StreamExt};
 
 use crate::{Args, Result};
 
+/// How often the tracker list of a torrent is refreshed. Their statuses only
+/// change with announces, so this needn't be done with each stats update.
+const TRACKERS_QUERY_INTERVAL: Duration = Duration::from_secs(5);
+
 /// Holds the application state.
 pub struct App {
     pub download_dir: PathBuf,
@@ -38,12 +49,34 @@ impl App {
     }
 
     pub fn create_torrent(&mut self, args: Args) -> Result<()> {
+        let conf = TorrentConf {
+            alerts: TorrentAlertConf {
+                completed_pieces: true,
+                peers: true,
+            },
+            // once the ratio is reached the torrent is paused, after which
+            // the app quits
+            seed_goals: SeedGoalConf {
+                ratio: args.seed_ratio,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        if let Some(uri) = &args.magnet {
+            return self.create_magnet_torrent(uri, conf);
+        }
+
         // read in torrent metainfo
-        let metainfo = fs::read(&args.metainfo)?;
+        let metainfo = match &args.metainfo {
+            Some(path) => fs::read(path)?,
+            None => {
+                return Err("either a metainfo or a magnet is needed".into())
+            }
+        };
         let metainfo = Metainfo::from_bytes(&metainfo)?;
         let info_hash = hex::encode(&metainfo.info_hash);
         let piece_count = metainfo.piece_count();
-        let download_len = metainfo.download_len();
         let is_seed = matches!(args.mode, Mode::Seed);
 
         let storage = StorageInfo::new(&metainfo, self.download_dir.clone());
@@ -74,36 +107,70 @@ impl App {
         let torrent_id = self.engine.create_torrent(TorrentParams {
             metainfo: metainfo.clone(),
             listen_addr: args.listen,
+            piece_picker: None,
             mode: args.mode,
-            conf: Some(TorrentConf {
-                alerts: TorrentAlertConf {
-                    completed_pieces: true,
-                    peers: true,
-                },
-                ..Default::default()
-            }),
+            conf: Some(conf),
         })?;
 
-        let torrent = Torrent {
-            name: metainfo.name,
-            info_hash,
-            piece_len: metainfo.piece_len,
-            download_len,
-            storage,
+        let mut torrent = Torrent::new(metainfo.name, info_hash);
+        torrent.storage = Some(storage);
+        torrent.pieces = pieces;
+        torrent.files = files;
+        self.torrents.insert(torrent_id, torrent);
 
-            run_duration: Default::default(),
-            pieces,
-            files,
-            peers: Default::default(),
-            protocol: Default::default(),
-            payload: Default::default(),
-            wasted_payload_count: Default::default(),
-        };
+        Ok(())
+    }
+
+    /// Adds a torrent from its magnet link.
+    ///
+    /// Its files and pieces are not known until its metadata is downloaded,
+    /// so until then only the torrent's stats are shown for it.
+    fn create_magnet_torrent(
+        &mut self,
+        uri: &str,
+        conf: TorrentConf,
+    ) -> Result<()> {
+        let magnet = Magnet::parse(uri)?;
+        let info_hash = hex::encode(&magnet.info_hash);
+        let name = magnet.name.unwrap_or_else(|| info_hash.clone());
+
+        let torrent_id = self
+            .engine
+            .add_torrent(AddTorrent::magnet(uri).conf(conf))?;
+
+        let mut torrent = Torrent::new(name, info_hash);
+        torrent.pieces.latest_completed = Some(Vec::new());
         self.torrents.insert(torrent_id, torrent);
 
         Ok(())
     }
 
+    /// Fills in the files and pieces of a torrent added from a magnet link
+    /// once its metadata is received.
+    pub fn update_torrent_metainfo(
+        &mut self,
+        torrent_id: TorrentId,
+        metainfo: &[u8],
+    ) -> Result<()> {
+        let metainfo = Metainfo::from_bytes(metainfo)?;
+        if let Some(torrent) = self.torrents.get_mut(&torrent_id) {
+            let storage =
+                StorageInfo::new(&metainfo, self.download_dir.clone());
+            torrent.files = storage
+                .files
+                .iter()
+                .map(|f| FileStats {
+                    info: f.clone(),
+                    complete: 0,
+                })
+                .collect();
+            torrent.pieces.total = metainfo.piece_count();
+            torrent.storage = Some(storage);
+            torrent.name = metainfo.name;
+        }
+        Ok(())
+    }
+
     pub fn update_torrent_state(
         &mut self,
         torrent_id: TorrentId,
@@ -117,16 +184,17 @@ impl App {
             // TODO: consider letting tradetorrent send file completion progress
             // since if a client is not listening continuously for completed
             // pieces they won't be able to reconsruct this
-            if let Some(pieces) = &stats.pieces.latest_completed {
+            if let (Some(storage), Some(pieces)) =
+                (&torrent.storage, &stats.pieces.latest_completed)
+            {
                 // for each piece, check which
                 for piece in pieces.iter().cloned() {
-                    let piece_len = torrent.storage.piece_len(piece);
+                    let piece_len = storage.piece_len(piece);
                     let mut torrent_piece_offset =
-                        torrent.storage.torrent_piece_offset(piece);
+                        storage.torrent_piece_offset(piece);
                     let mut consumed = 0;
 
-                    let file_range =
-                        torrent.storage.files_intersecting_piece(piece);
+                    let file_range = storage.files_intersecting_piece(piece);
                     let files = &mut torrent.files[file_range];
                     for file in files.iter_mut() {
                         let remaining_piece_len = piece_len as u64 - consumed;
@@ -190,6 +258,7 @@ impl App {
                 }
             }
             torrent.run_duration = stats.run_duration;
+            torrent.is_paused = stats.is_paused;
 
             const HISTORY_LIMIT: usize = 300;
             for (history, curr) in [
@@ -202,6 +271,37 @@ impl App {
             }
         }
     }
+
+    /// Asks the engine for the torrent's tracker list, unless it was done
+    /// recently.
+    pub fn query_torrent_trackers(
+        &mut self,
+        torrent_id: TorrentId,
+    ) -> Result<()> {
+        let torrent = match self.torrents.get_mut(&torrent_id) {
+            Some(torrent) => torrent,
+            None => return Ok(()),
+        };
+        let now = Instant::now();
+        let is_due = torrent.trackers_query_time.map_or(true, |t| {
+            now.saturating_duration_since(t) >= TRACKERS_QUERY_INTERVAL
+        });
+        if is_due {
+            torrent.trackers_query_time = Some(now);
+            self.engine.query_trackers(torrent_id)?;
+        }
+        Ok(())
+    }
+
+    pub fn update_torrent_trackers(
+        &mut self,
+        torrent_id: TorrentId,
+        trackers: Vec<TrackerInfo>,
+    ) {
+        if let Some(torrent) = self.torrents.get_mut(&torrent_id) {
+            torrent.trackers = trackers;
+        }
+    }
 }
 
 /// Holds state about a single torrent.
@@ -209,14 +309,18 @@ pub struct Torrent {
     // static info
     pub name: String,
     pub info_hash: String,
-    pub piece_len: u32,
-    pub download_len: u64,
-    pub storage: StorageInfo,
+    /// The torrent's storage layout, which is not known for a torrent added
+    /// from a magnet link.
+    pub storage: Option<StorageInfo>,
 
     // dynamic info
     pub run_duration: Duration,
+    pub is_paused: bool,
     pub pieces: PieceStats,
     pub peers: Peers,
+    pub trackers: Vec<TrackerInfo>,
+    /// When the tracker list was last asked for.
+    pub trackers_query_time: Option<Instant>,
 
     pub files: Vec<FileStats>,
 
@@ -226,9 +330,46 @@ pub struct Torrent {
 }
 
 impl Torrent {
+    fn new(name: String, info_hash: String) -> Self {
+        Self {
+            name,
+            info_hash,
+            storage: None,
+            run_duration: Default::default(),
+            is_paused: false,
+            pieces: Default::default(),
+            peers: Default::default(),
+            trackers: Vec::new(),
+            trackers_query_time: None,
+            files: Vec::new(),
+            protocol: Default::default(),
+            payload: Default::default(),
+            wasted_payload_count: Default::default(),
+        }
+    }
+
     fn is_seed(&self) -> bool {
         self.pieces.is_seed()
     }
+
+    /// Returns the ratio of the uploaded to the downloaded payload bytes.
+    ///
+    /// As with the engine's seed ratio goal, the torrent's size is used if
+    /// less than that was downloaded, e.g. because the torrent was seeded
+    /// from the start.
+    pub fn ratio(&self) -> f64 {
+        let download_len = self
+            .storage
+            .as_ref()
+            .map(|s| s.download_len)
+            .unwrap_or_default();
+        let downloaded = self.payload.down.total.max(download_len);
+        if downloaded == 0 {
+            0.0
+        } else {
+            self.payload.up.total as f64 / downloaded as f64
+        }
+    }
 }
 
 #[derive(Default)]
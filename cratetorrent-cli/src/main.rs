@@ -33,8 +33,13 @@ pub struct Args {
     download_dir: PathBuf,
 
     /// The path to the torrent metainfo file.
-    #[structopt(short, long)]
-    metainfo: PathBuf,
+    #[structopt(short, long, required_unless = "magnet")]
+    metainfo: Option<PathBuf>,
+
+    /// The magnet link of the torrent, which is downloaded instead of
+    /// a metainfo file. Its metadata is downloaded from peers.
+    #[structopt(long, conflicts_with = "metainfo")]
+    magnet: Option<String>,
 
     /// A comma separated list of <ip>:<port> pairs of the seeds.
     #[structopt(short, long)]
@@ -46,6 +51,11 @@ pub struct Args {
 
     #[structopt(short, long)]
     quit_after_complete: bool,
+
+    /// Keep seeding the torrent after it's complete, until this many times
+    /// its size is uploaded, and then quit.
+    #[structopt(long)]
+    seed_ratio: Option<f64>,
 }
 
 fn parse_mode(s: &str) -> Mode {
@@ -68,7 +78,10 @@ async fn main() -> Result<()> {
         *seeds = args.seeds.clone().unwrap_or_default();
     };
 
-    let quit_after_complete = args.quit_after_complete;
+    // when seeding until a ratio, the app quits once that's reached instead
+    // of on completion
+    let quit_after_complete =
+        args.quit_after_complete && args.seed_ratio.is_none();
 
     // set up TUI backend
     let stdout = io::stdout().into_raw_mode()?;
@@ -100,8 +113,16 @@ async fn main() -> Result<()> {
             }
             alert = app.alert_rx.select_next_some() => {
                 match alert {
+                    Alert::MetadataReceived { id, metainfo } => {
+                        app.update_torrent_metainfo(id, &metainfo)?;
+                    }
                     Alert::TorrentStats { id, stats } => {
                         app.update_torrent_state(id, *stats);
+                        // refresh the tracker list every few stats updates
+                        app.query_torrent_trackers(id)?;
+                    }
+                    Alert::TorrentTrackers { id, trackers } => {
+                        app.update_torrent_trackers(id, trackers);
                     }
                     Alert::TorrentComplete(_) => {
                         // TODO: some notification/popup
@@ -109,6 +130,9 @@ async fn main() -> Result<()> {
                             run = false;
                         }
                     }
+                    Alert::SeedGoalReached { .. } => {
+                        run = false;
+                    }
                     // TODO(https://github.com/mandreyel/cratetorrent/issues/85):
                     // handle errors
                     _ => (),
@@ -1,6 +1,9 @@
 use std::borrow::Cow;
 
-use cratetorrent::{peer::ConnectionState, torrent::stats::Peers};
+use cratetorrent::{
+    peer::ConnectionState,
+    torrent::stats::{Peers, TrackerStatus},
+};
 use tui::{
     backend::Backend,
     layout::{Alignment, Constraint, Corner, Direction, Layout, Rect},
@@ -24,8 +27,8 @@ pub fn draw(f: &mut Frame<impl Backend>, app: &mut App) {
         // split window into 3 horizontal chunks:
         // - first is split vertically between torrent info and throughput rates
         // - second is a thin slice for the progress bar
-        // - third is the remaining (large) area split vertically between files
-        //   and later peers info
+        // - third is the remaining (large) area split vertically between files,
+        //   pieces, and peers and trackers info
         let chunks = Layout::default()
             .direction(Direction::Vertical)
             .constraints(
@@ -68,7 +71,19 @@ pub fn draw(f: &mut Frame<impl Backend>, app: &mut App) {
                 .split(area);
             draw_files(f, torrent, chunks[0]);
             draw_pieces(f, torrent, chunks[1]);
-            draw_peers(f, torrent, chunks[2]);
+
+            // the peers take most of the last column, with the trackers
+            // below them
+            let area = chunks[2];
+            let chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints(
+                    [Constraint::Percentage(70), Constraint::Percentage(30)]
+                        .as_ref(),
+                )
+                .split(area);
+            draw_peers(f, torrent, chunks[0]);
+            draw_trackers(f, torrent, chunks[1]);
         }
     }
 }
@@ -78,30 +93,42 @@ pub fn draw_metadata(
     torrent: &Torrent,
     area: Rect,
 ) {
-    let text = vec![
+    let mut text = vec![
         // TODO: avoid clones here
         create_key_value_spans("Name: ", torrent.name.clone()),
-        create_key_value_spans(
-            "Path: ",
-            torrent
-                .storage
-                .download_dir
-                .join(&torrent.name)
-                .display()
-                .to_string(),
-        ),
         create_key_value_spans("Info hash: ", torrent.info_hash.clone()),
-        create_key_value_spans(
-            "Size: ",
-            Unit::new(torrent.storage.download_len).to_string(),
-        ),
-        create_key_value_spans(
-            "Piece len: ",
-            Unit::new(torrent.piece_len as u64).to_string(),
-        ),
+    ];
+    // a magnet torrent's storage is not known until its metadata is
+    // downloaded
+    match &torrent.storage {
+        Some(storage) => text.extend(vec![
+            create_key_value_spans(
+                "Path: ",
+                storage
+                    .download_dir
+                    .join(&torrent.name)
+                    .display()
+                    .to_string(),
+            ),
+            create_key_value_spans(
+                "Size: ",
+                Unit::new(storage.download_len).to_string(),
+            ),
+            create_key_value_spans(
+                "Piece len: ",
+                Unit::new(storage.piece_len as u64).to_string(),
+            ),
+        ]),
+        None => text.push(create_key_value_spans("Size: ", "unknown")),
+    }
+    text.extend(vec![
         create_key_value_spans(
             "Elapsed: ",
-            format!("{} s", torrent.run_duration.as_secs()),
+            format!(
+                "{} s{}",
+                torrent.run_duration.as_secs(),
+                if torrent.is_paused { " (paused)" } else { "" }
+            ),
         ),
         create_key_value_spans(
             "Pieces: ",
@@ -116,7 +143,8 @@ pub fn draw_metadata(
             "Connected peers: ",
             torrent.peers.len().to_string(),
         ),
-    ];
+        create_key_value_spans("Ratio: ", format!("{:.2}", torrent.ratio())),
+    ]);
 
     let paragraph = Paragraph::new(text.clone())
         .block(create_block("Metadata"))
@@ -329,6 +357,45 @@ pub fn draw_peers(f: &mut Frame<impl Backend>, torrent: &Torrent, area: Rect) {
     }
 }
 
+pub fn draw_trackers(
+    f: &mut Frame<impl Backend>,
+    torrent: &Torrent,
+    area: Rect,
+) {
+    let trackers: Vec<ListItem> = torrent
+        .trackers
+        .iter()
+        .map(|tracker| {
+            let mut buf = format!("[{}] {}", tracker.tier, tracker.url);
+
+            buf += match tracker.status {
+                TrackerStatus::NotContacted => " :: not contacted",
+                TrackerStatus::Working => " :: working",
+                TrackerStatus::Failed => " :: failed",
+            };
+
+            // the swarm size, if the tracker reported it
+            if let (Some(seeders), Some(leechers)) =
+                (tracker.seeder_count, tracker.leecher_count)
+            {
+                buf += &format!(" :: {} seeds, {} leeches", seeders, leechers);
+            }
+
+            let mut lines = vec![Spans::from(Span::styled(
+                buf,
+                Style::default().add_modifier(Modifier::BOLD),
+            ))];
+            // the error or warning of the last announce
+            if let Some(message) = &tracker.message {
+                lines.push(Spans::from(message.as_str()));
+            }
+            ListItem::new(lines)
+        })
+        .collect();
+    let trackers = List::new(trackers).block(create_block("Trackers"));
+    f.render_widget(trackers, area);
+}
+
 fn create_block<'a>(title: impl Into<Cow<'a, str>>) -> Block<'a> {
     let title =
         Span::styled(title, Style::default().add_modifier(Modifier::BOLD));
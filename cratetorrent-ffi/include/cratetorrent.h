@@ -0,0 +1,104 @@
+/*
+ * C bindings for the cratetorrent BitTorrent engine.
+ *
+ * See the documentation of the cratetorrent-ffi crate for the details of each
+ * function. Strings are NUL terminated and UTF-8 encoded. The event callback
+ * is called on one of the engine's threads, and must not free the engine.
+ */
+
+#ifndef CRATETORRENT_H
+#define CRATETORRENT_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum CtResult {
+    CT_RESULT_OK = 0,
+    CT_RESULT_INVALID_ARGUMENT = 1,
+    CT_RESULT_FAILED = 2,
+    CT_RESULT_NOT_FOUND = 3,
+} CtResult;
+
+typedef enum CtEventKind {
+    CT_EVENT_TORRENT_ADDED = 0,
+    CT_EVENT_TORRENT_COMPLETE = 1,
+    CT_EVENT_TORRENT_PAUSED = 2,
+    CT_EVENT_TORRENT_RESUMED = 3,
+    CT_EVENT_TORRENT_REMOVED = 4,
+    CT_EVENT_TORRENT_STATS = 5,
+    CT_EVENT_ERROR = 6,
+} CtEventKind;
+
+typedef struct CtEvent {
+    CtEventKind kind;
+    bool has_torrent_id;
+    uint32_t torrent_id;
+    /* Only valid for the duration of the callback, and null unless the event
+     * is an error. */
+    const char *message;
+} CtEvent;
+
+typedef void (*CtEventCallback)(const CtEvent *event, void *user_data);
+
+typedef enum CtStatus {
+    CT_STATUS_PAUSED = 0,
+    CT_STATUS_QUEUED = 1,
+    CT_STATUS_DOWNLOADING = 2,
+    CT_STATUS_SEEDING = 3,
+} CtStatus;
+
+typedef struct CtTorrentStats {
+    CtStatus status;
+    bool is_errored;
+    /* Between 0.0 and 1.0. */
+    double progress;
+    /* -1 if nothing is being downloaded. */
+    int64_t eta_secs;
+    uint64_t download_rate;
+    uint64_t upload_rate;
+    uint64_t downloaded;
+    uint64_t uploaded;
+    size_t piece_count;
+    size_t complete_piece_count;
+    size_t peer_count;
+} CtTorrentStats;
+
+typedef struct CtEngine CtEngine;
+
+CtEngine *ct_engine_new(
+    const char *download_dir,
+    CtEventCallback callback,
+    void *user_data);
+
+void ct_engine_free(CtEngine *engine);
+
+CtResult ct_engine_add_torrent_file(
+    CtEngine *engine,
+    const char *path,
+    uint32_t *id);
+
+CtResult ct_engine_add_magnet(
+    CtEngine *engine,
+    const char *uri,
+    uint32_t *id);
+
+CtResult ct_engine_remove_torrent(
+    CtEngine *engine,
+    uint32_t id,
+    bool delete_files);
+
+CtResult ct_engine_torrent_stats(
+    const CtEngine *engine,
+    uint32_t id,
+    CtTorrentStats *stats);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* CRATETORRENT_H */
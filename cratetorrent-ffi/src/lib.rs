@@ -0,0 +1,536 @@
+//! C bindings for the cratetorrent engine, so that it can be embedded in
+//! applications not written in Rust, such as GUI clients and mobile apps.
+//!
+//! The functions and types are declared in `include/cratetorrent.h`. The
+//! engine runs on its own tokio runtime, which is created with the engine and
+//! shut down with it, so the application needs no async runtime of its own.
+//!
+//! The engine's alerts are passed to an optional callback as [`CtEvent`]s.
+//! The callback is called on one of the runtime's threads, so it must not
+//! block for long, and it must not call [`ct_engine_free`]. The torrents'
+//! stats, which the engine posts each second, are kept so that they can be
+//! polled with [`ct_engine_torrent_stats`], e.g. from a UI timer.
+//!
+//! Strings passed to the functions must be NUL terminated and UTF-8 encoded.
+//! Torrents are identified by the integer value of their [`TorrentId`].
+//!
+//! As unwinding into the application's frames is undefined behavior, panics
+//! are caught at the boundary, and the call fails as if it returned an error.
+
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString},
+    os::raw::{c_char, c_void},
+    panic::{self, AssertUnwindSafe},
+    ptr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use cratetorrent::{
+    engine::{self, DeleteFiles},
+    prelude::*,
+    torrent::stats::{Status, TorrentStats},
+};
+use tokio::runtime::{self, Runtime};
+
+/// The result of a call.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CtResult {
+    /// The call succeeded.
+    Ok = 0,
+    /// A pointer argument was null, or a string was not valid UTF-8.
+    InvalidArgument = 1,
+    /// The call failed, e.g. because the torrent is invalid, the engine
+    /// is no longer running, or the call panicked. The reason is logged.
+    Failed = 2,
+    /// The torrent doesn't exist, or hasn't reported its stats yet.
+    NotFound = 3,
+}
+
+/// What a [`CtEvent`] is about.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CtEventKind {
+    /// The torrent was allocated on disk and started.
+    TorrentAdded = 0,
+    /// The torrent finished downloading.
+    TorrentComplete = 1,
+    /// The torrent was paused.
+    TorrentPaused = 2,
+    /// The torrent was resumed.
+    TorrentResumed = 3,
+    /// The torrent was removed and has shut down.
+    TorrentRemoved = 4,
+    /// The torrent's stats were updated, which can be polled with
+    /// [`ct_engine_torrent_stats`].
+    TorrentStats = 5,
+    /// An error occurred, as described by the event's message.
+    Error = 6,
+}
+
+/// An event passed to the event callback.
+#[repr(C)]
+#[derive(Debug)]
+pub struct CtEvent {
+    pub kind: CtEventKind,
+    /// Whether the event concerns a torrent, in which case its id is set.
+    pub has_torrent_id: bool,
+    pub torrent_id: u32,
+    /// The description of an error, or null for other events. It's only
+    /// valid for the duration of the callback.
+    pub message: *const c_char,
+}
+
+/// The callback to which events are passed, along with the user data pointer
+/// the engine was created with.
+pub type CtEventCallback =
+    Option<extern "C" fn(event: *const CtEvent, user_data: *mut c_void)>;
+
+/// What a torrent is doing.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CtStatus {
+    Paused = 0,
+    Queued = 1,
+    Downloading = 2,
+    Seeding = 3,
+}
+
+/// The latest stats of a torrent.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct CtTorrentStats {
+    pub status: CtStatus,
+    /// Whether the torrent is stopped by an error accessing its files.
+    pub is_errored: bool,
+    /// The completion of the selected files, between 0.0 and 1.0.
+    pub progress: f64,
+    /// The estimated number of seconds until the selected files are
+    /// downloaded, or -1 if nothing is being downloaded.
+    pub eta_secs: i64,
+    /// The payload download rate, in bytes per second.
+    pub download_rate: u64,
+    /// The payload upload rate, in bytes per second.
+    pub upload_rate: u64,
+    /// The number of payload bytes downloaded in this session.
+    pub downloaded: u64,
+    /// The number of payload bytes uploaded in this session.
+    pub uploaded: u64,
+    pub piece_count: usize,
+    pub complete_piece_count: usize,
+    pub peer_count: usize,
+}
+
+impl From<&TorrentStats> for CtTorrentStats {
+    fn from(stats: &TorrentStats) -> Self {
+        let payload = &stats.thruput.payload;
+        Self {
+            status: match stats.status() {
+                Status::Paused => CtStatus::Paused,
+                Status::Queued => CtStatus::Queued,
+                Status::Downloading => CtStatus::Downloading,
+                Status::Seeding => CtStatus::Seeding,
+            },
+            is_errored: stats.is_errored,
+            progress: stats.progress(),
+            eta_secs: stats.eta().map_or(-1, |eta| eta.as_secs() as i64),
+            download_rate: payload.down.rate,
+            upload_rate: payload.up.rate,
+            downloaded: payload.down.total,
+            uploaded: payload.up.total,
+            piece_count: stats.pieces.total,
+            complete_piece_count: stats.pieces.complete,
+            peer_count: stats.peers.len(),
+        }
+    }
+}
+
+/// A running engine, created with [`ct_engine_new`].
+pub struct CtEngine {
+    /// The runtime on which the engine and the alert forwarding task run.
+    runtime: Runtime,
+    /// The handle is taken when the engine is shut down.
+    handle: Option<EngineHandle>,
+    /// The latest stats of each torrent.
+    stats: Arc<Mutex<HashMap<TorrentId, CtTorrentStats>>>,
+}
+
+/// The user data pointer, which is only passed back to the callback.
+struct UserData(*mut c_void);
+
+// the application is responsible for the pointer being usable from the
+// runtime's threads
+unsafe impl Send for UserData {}
+
+/// Creates an engine that downloads torrents into the directory, and which
+/// passes its events to the callback, if not null.
+///
+/// Returns null if the arguments are invalid, the engine can't be started,
+/// or the call panicked.
+/// The engine must be freed with [`ct_engine_free`].
+///
+/// # Safety
+///
+/// The directory must be a valid C string, and the user data must remain
+/// valid until the engine is freed.
+#[no_mangle]
+pub unsafe extern "C" fn ct_engine_new(
+    download_dir: *const c_char,
+    callback: CtEventCallback,
+    user_data: *mut c_void,
+) -> *mut CtEngine {
+    catch_panic(ptr::null_mut(), || {
+        let download_dir = match to_str(download_dir) {
+            Some(dir) => dir,
+            None => return ptr::null_mut(),
+        };
+        let runtime = match runtime::Builder::new()
+            .threaded_scheduler()
+            .enable_all()
+            .build()
+        {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                log::error!("Cannot create engine runtime: {}", e);
+                return ptr::null_mut();
+            }
+        };
+        // the engine spawns its tasks on the runtime
+        let (handle, alert_rx) =
+            match runtime.enter(|| engine::spawn(Conf::new(download_dir))) {
+                Ok(engine) => engine,
+                Err(e) => {
+                    log::error!("Cannot spawn engine: {}", e);
+                    return ptr::null_mut();
+                }
+            };
+        let stats = Arc::new(Mutex::new(HashMap::new()));
+        runtime.spawn(forward_alerts(
+            alert_rx,
+            Arc::clone(&stats),
+            callback,
+            UserData(user_data),
+        ));
+        Box::into_raw(Box::new(CtEngine {
+            runtime,
+            handle: Some(handle),
+            stats,
+        }))
+    })
+}
+
+/// Shuts down the engine, waiting for its torrents to shut down, and frees
+/// it. Freeing null is a no-op.
+///
+/// # Safety
+///
+/// The engine must have been created with [`ct_engine_new`] and not freed
+/// already. It must not be freed from the event callback.
+#[no_mangle]
+pub unsafe extern "C" fn ct_engine_free(engine: *mut CtEngine) {
+    if engine.is_null() {
+        return;
+    }
+    catch_panic((), || {
+        let mut engine = Box::from_raw(engine);
+        if let Some(handle) = engine.handle.take() {
+            if let Err(e) = engine.runtime.block_on(handle.shutdown()) {
+                log::warn!("Error shutting down engine: {}", e);
+            }
+        }
+    })
+}
+
+/// Adds the torrent of the metainfo file at the path, and sets its id.
+///
+/// # Safety
+///
+/// The engine must be valid, the path a valid C string, and the id a valid
+/// pointer.
+#[no_mangle]
+pub unsafe extern "C" fn ct_engine_add_torrent_file(
+    engine: *mut CtEngine,
+    path: *const c_char,
+    id: *mut u32,
+) -> CtResult {
+    catch_panic(CtResult::Failed, || {
+        add_torrent(engine, to_str(path).map(AddTorrent::file), id)
+    })
+}
+
+/// Adds the torrent of the magnet link, and sets its id. The torrent's
+/// metadata is then downloaded from peers.
+///
+/// # Safety
+///
+/// The engine must be valid, the link a valid C string, and the id a valid
+/// pointer.
+#[no_mangle]
+pub unsafe extern "C" fn ct_engine_add_magnet(
+    engine: *mut CtEngine,
+    uri: *const c_char,
+    id: *mut u32,
+) -> CtResult {
+    catch_panic(CtResult::Failed, || {
+        add_torrent(engine, to_str(uri).map(AddTorrent::magnet), id)
+    })
+}
+
+unsafe fn add_torrent(
+    engine: *mut CtEngine,
+    torrent: Option<AddTorrent>,
+    id: *mut u32,
+) -> CtResult {
+    let (handle, torrent) = match (handle(engine), torrent) {
+        (Some(handle), Some(torrent)) if !id.is_null() => (handle, torrent),
+        _ => return CtResult::InvalidArgument,
+    };
+    match handle.add_torrent(torrent) {
+        Ok(torrent_id) => {
+            *id = torrent_id.into();
+            CtResult::Ok
+        }
+        Err(e) => {
+            log::warn!("Cannot add torrent: {}", e);
+            CtResult::Failed
+        }
+    }
+}
+
+/// Removes the torrent, and if set, deletes its files.
+///
+/// # Safety
+///
+/// The engine must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn ct_engine_remove_torrent(
+    engine: *mut CtEngine,
+    id: u32,
+    delete_files: bool,
+) -> CtResult {
+    catch_panic(CtResult::Failed, || {
+        let handle = match handle(engine) {
+            Some(handle) => handle,
+            None => return CtResult::InvalidArgument,
+        };
+        let delete_files = if delete_files {
+            DeleteFiles::Yes
+        } else {
+            DeleteFiles::No
+        };
+        match handle.remove_torrent(id.into(), delete_files) {
+            Ok(()) => CtResult::Ok,
+            Err(e) => {
+                log::warn!("Cannot remove torrent: {}", e);
+                CtResult::Failed
+            }
+        }
+    })
+}
+
+/// Copies the latest stats of the torrent into `stats`.
+///
+/// # Safety
+///
+/// The engine must be valid, and the stats a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn ct_engine_torrent_stats(
+    engine: *const CtEngine,
+    id: u32,
+    stats: *mut CtTorrentStats,
+) -> CtResult {
+    catch_panic(CtResult::Failed, || {
+        let engine = match engine.as_ref() {
+            Some(engine) if !stats.is_null() => engine,
+            _ => return CtResult::InvalidArgument,
+        };
+        match lock(&engine.stats).get(&TorrentId::from(id)) {
+            Some(latest) => {
+                *stats = *latest;
+                CtResult::Ok
+            }
+            None => CtResult::NotFound,
+        }
+    })
+}
+
+/// Keeps the torrents' latest stats and passes the alerts that have an event
+/// kind to the callback, until the engine shuts down.
+async fn forward_alerts(
+    mut alert_rx: AlertReceiver,
+    stats: Arc<Mutex<HashMap<TorrentId, CtTorrentStats>>>,
+    callback: CtEventCallback,
+    user_data: UserData,
+) {
+    while let Some(alert) = alert_rx.next().await {
+        let (kind, id, message) = match alert {
+            Alert::TorrentAdded(id) => {
+                (CtEventKind::TorrentAdded, Some(id), None)
+            }
+            Alert::TorrentComplete(id) => {
+                (CtEventKind::TorrentComplete, Some(id), None)
+            }
+            Alert::TorrentPaused(id) => {
+                (CtEventKind::TorrentPaused, Some(id), None)
+            }
+            Alert::TorrentResumed(id) => {
+                (CtEventKind::TorrentResumed, Some(id), None)
+            }
+            Alert::TorrentRemoved(id) => {
+                lock(&stats).remove(&id);
+                (CtEventKind::TorrentRemoved, Some(id), None)
+            }
+            Alert::TorrentStats { id, stats: latest } => {
+                let latest = CtTorrentStats::from(latest.as_ref());
+                lock(&stats).insert(id, latest);
+                (CtEventKind::TorrentStats, Some(id), None)
+            }
+            Alert::Error(e) => {
+                let id = match &e {
+                    Error::InvalidMetadata { id, .. }
+                    | Error::Torrent { id, .. }
+                    | Error::Disk { id, .. }
+                    | Error::Tracker { id, .. } => Some(*id),
+                    _ => None,
+                };
+                (CtEventKind::Error, id, Some(e.to_string()))
+            }
+            _ => continue,
+        };
+        let callback = match callback {
+            Some(callback) => callback,
+            None => continue,
+        };
+        // error messages don't contain NUL bytes, but in case one does, the
+        // message is left out rather than the whole event
+        let message = message.and_then(|m| CString::new(m).ok());
+        let event = CtEvent {
+            kind,
+            has_torrent_id: id.is_some(),
+            torrent_id: id.map_or(0, u32::from),
+            message: message.as_ref().map_or(ptr::null(), |m| m.as_ptr()),
+        };
+        callback(&event, user_data.0);
+    }
+    log::info!("Engine alert channel closed");
+}
+
+/// Runs the body of an exported function, returning the fallback if it
+/// panics.
+fn catch_panic<T>(fallback: T, f: impl FnOnce() -> T) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(_) => {
+            log::error!("Call into the engine panicked");
+            fallback
+        }
+    }
+}
+
+/// Locks the stats even if a thread panicked while holding the lock, as
+/// they are only ever updated by single inserts and removals, which leave
+/// them consistent.
+fn lock<T>(stats: &Mutex<T>) -> MutexGuard<'_, T> {
+    stats.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Returns the handle of the engine, if the engine is valid and running.
+unsafe fn handle<'a>(engine: *mut CtEngine) -> Option<&'a EngineHandle> {
+    engine.as_ref()?.handle.as_ref()
+}
+
+/// Returns the string, if it's not null and valid UTF-8.
+unsafe fn to_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, mem::MaybeUninit, thread, time::Duration};
+
+    use super::*;
+
+    /// Tests that null pointers are rejected rather than dereferenced.
+    #[test]
+    fn should_reject_null_arguments() {
+        unsafe {
+            let engine = ct_engine_new(ptr::null(), None, ptr::null_mut());
+            assert!(engine.is_null());
+            let mut id = 0;
+            let uri = CString::new("magnet:?xt=urn:btih:").unwrap();
+            assert_eq!(
+                ct_engine_add_magnet(ptr::null_mut(), uri.as_ptr(), &mut id),
+                CtResult::InvalidArgument
+            );
+            assert_eq!(
+                ct_engine_remove_torrent(ptr::null_mut(), 0, false),
+                CtResult::InvalidArgument
+            );
+            assert_eq!(
+                ct_engine_torrent_stats(ptr::null(), 0, ptr::null_mut()),
+                CtResult::InvalidArgument
+            );
+            // freeing null is a no-op
+            ct_engine_free(ptr::null_mut());
+        }
+    }
+
+    /// Tests a session through the bindings: a torrent is added, its stats
+    /// are read once posted, and the torrent and engine are then freed.
+    #[test]
+    fn should_add_torrent_and_read_stats() {
+        let dir = std::env::temp_dir().join("cratetorrent_ffi_test");
+        fs::create_dir_all(&dir).unwrap();
+        let metainfo_path = dir.join("test.torrent");
+        fs::write(
+            &metainfo_path,
+            b"d4:infod6:lengthi100e4:name8:test.bin12:piece lengthi16384e\
+            6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+        )
+        .unwrap();
+        let dir = CString::new(dir.to_str().unwrap()).unwrap();
+        let metainfo_path =
+            CString::new(metainfo_path.to_str().unwrap()).unwrap();
+
+        unsafe {
+            let engine = ct_engine_new(dir.as_ptr(), None, ptr::null_mut());
+            assert!(!engine.is_null());
+
+            let mut id = 0;
+            assert_eq!(
+                ct_engine_add_torrent_file(
+                    engine,
+                    metainfo_path.as_ptr(),
+                    &mut id
+                ),
+                CtResult::Ok
+            );
+
+            // the stats are posted each second once the torrent is started
+            let mut stats = MaybeUninit::<CtTorrentStats>::uninit();
+            let mut result = CtResult::NotFound;
+            for _ in 0..50 {
+                result =
+                    ct_engine_torrent_stats(engine, id, stats.as_mut_ptr());
+                if result != CtResult::NotFound {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+            assert_eq!(result, CtResult::Ok);
+            let stats = stats.assume_init();
+            assert_eq!(stats.piece_count, 1);
+            assert_eq!(stats.complete_piece_count, 0);
+
+            assert_eq!(
+                ct_engine_remove_torrent(engine, id, true),
+                CtResult::Ok
+            );
+            ct_engine_free(engine);
+        }
+    }
+}
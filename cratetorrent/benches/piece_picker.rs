@@ -0,0 +1,91 @@
+//! Benchmarks rarest-first piece picking in a torrent with a large number of
+//! pieces.
+//!
+//! Run them with `cargo bench --bench piece_picker`.
+
+use std::time::{Duration, Instant};
+
+use cratetorrent::{
+    piece_picker::{PiecePicker, RarestFirstPicker},
+    Bitfield,
+};
+use criterion::{
+    black_box, criterion_group, criterion_main, BatchSize, Criterion,
+};
+use rand::Rng;
+
+const PIECE_COUNT: usize = 500_000;
+const PEER_COUNT: usize = 50;
+
+/// Returns the pieces of the peers in a swarm in which most peers are seeds,
+/// while the rest have about half the pieces.
+fn swarm() -> Vec<Bitfield> {
+    let mut rng = rand::thread_rng();
+    (0..PEER_COUNT)
+        .map(|i| {
+            let mut pieces = Bitfield::repeat(true, PIECE_COUNT);
+            if i % 2 == 1 {
+                for index in 0..PIECE_COUNT {
+                    pieces.set(index, rng.gen_range(0, 2) == 0);
+                }
+            }
+            pieces
+        })
+        .collect()
+}
+
+fn bench_register_peer(c: &mut Criterion) {
+    let swarm = swarm();
+    c.bench_function("register peer pieces", |b| {
+        b.iter_batched(
+            || RarestFirstPicker::new(Bitfield::repeat(false, PIECE_COUNT)),
+            |mut piece_picker| {
+                for pieces in swarm.iter() {
+                    piece_picker.register_peer_pieces(pieces);
+                }
+                piece_picker
+            },
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+fn bench_pick_piece(c: &mut Criterion) {
+    let mut piece_picker =
+        RarestFirstPicker::new(Bitfield::repeat(false, PIECE_COUNT));
+    for pieces in swarm().iter() {
+        piece_picker.register_peer_pieces(pieces);
+    }
+    let all = Bitfield::repeat(true, PIECE_COUNT);
+    c.bench_function("pick piece", |b| {
+        b.iter_custom(|iters| {
+            let mut elapsed = Duration::default();
+            let mut picked = Vec::new();
+            for _ in 0..iters {
+                let start = Instant::now();
+                let index = piece_picker.pick_piece(black_box(&all));
+                elapsed += start.elapsed();
+                picked.extend(index);
+                // make the picked pieces pickable again before the picker
+                // runs low on them, so that all picks are made in a torrent
+                // of the same size
+                if picked.len() == PIECE_COUNT / 10 {
+                    release(&mut piece_picker, &mut picked);
+                }
+            }
+            release(&mut piece_picker, &mut picked);
+            elapsed
+        })
+    });
+}
+
+/// Makes the picked pieces pickable again.
+fn release(piece_picker: &mut RarestFirstPicker, picked: &mut Vec<usize>) {
+    for index in picked.drain(..) {
+        piece_picker.received_piece(index);
+        piece_picker.lost_piece(index);
+    }
+}
+
+criterion_group!(benches, bench_register_peer, bench_pick_piece);
+criterion_main!(benches);
@@ -5,6 +5,18 @@
 //! channels](tokio::sync::mpsc). Thus, the application in which the engine is
 //! integrated may be driven partially or entirely by cratetorrent alerts.
 //!
+//! The [`AlertReceiver`] returned by [`engine::spawn`](crate::engine::spawn)
+//! is also a [`Stream`](futures::stream::Stream), so alerts may be awaited with
+//! [`StreamExt::next`](futures::stream::StreamExt::next), which is exported
+//! by the [prelude](crate::prelude).
+//!
+//! Alerts cover the lifecycle of torrents (added, queued, paused, resumed,
+//! completed, seed goals reached, removed, restored from the saved session),
+//! their progress (completed pieces, periodic stats), their peers (connected,
+//! disconnected), their trackers (announces, warnings), the engine-wide
+//! services (DHT, port mapping, external IP), and errors, among which are
+//! tracker and disk errors, in [`Alert::Error`].
+//!
 //! # Optional information
 //!
 //! By default only the most basic alerts are broadcast from the engine. The
@@ -19,9 +31,24 @@
 //! statistics about a torrent's [peers](crate::conf::TorrentAlertConf::peers).
 //! More will be added later.
 
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+use reqwest::Url;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 
-use crate::{error::Error, torrent::stats::TorrentStats, TorrentId};
+use crate::{
+    conf::SeedGoal,
+    dht::{stats::DhtStats, storage::MutableItem},
+    error::{Error, IoError, PeerError},
+    ip_filter::IpFilterStats,
+    port_mapping::{Method, Protocol},
+    torrent::stats::{Connectability, PieceInfo, TorrentStats, TrackerInfo},
+    FileIndex, PeerId, PieceIndex, Sha1Hash, TorrentId,
+};
 
 pub(crate) type AlertSender = UnboundedSender<Alert>;
 /// The channel on which alerts from the engine can be received. See [`Alert`]
@@ -29,17 +56,291 @@ pub(crate) type AlertSender = UnboundedSender<Alert>;
 pub type AlertReceiver = UnboundedReceiver<Alert>;
 
 /// The alerts that the engine may send the library user.
+///
+/// With the `serde` feature, alerts can be serialized, e.g. to forward
+/// them to another process, with their errors serialized as messages. They
+/// can't be deserialized, as the errors can't be reconstructed.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize), serde(crate = "serde_crate"))]
 #[non_exhaustive]
 pub enum Alert {
+    /// Posted when a torrent created with
+    /// [`EngineHandle::create_torrent`](crate::engine::EngineHandle::create_torrent)
+    /// was allocated on disk. If allocation fails, an [`Error::Disk`] error is
+    /// posted instead.
+    TorrentAdded(TorrentId),
+    /// Posted when the metadata of a torrent added with
+    /// [`EngineHandle::add_magnet`](crate::engine::EngineHandle::add_magnet)
+    /// was downloaded from a peer and verified. The torrent is then allocated
+    /// on disk, after which [`Alert::TorrentAdded`] is posted, as for other
+    /// torrents.
+    ///
+    /// The alert holds the bencoded metainfo file reconstructed from the
+    /// metadata and the magnet's trackers, which may be parsed with
+    /// [`Metainfo::from_bytes`](crate::metainfo::Metainfo::from_bytes), e.g.
+    /// to learn the torrent's files, or saved as a `.torrent` file.
+    MetadataReceived { id: TorrentId, metainfo: Vec<u8> },
+    /// Posted when a torrent started listening for peer connections, with the
+    /// address it's bound to. This is the port that is announced to trackers
+    /// and peers, and that should be forwarded to us.
+    Listening { id: TorrentId, addr: SocketAddr },
     /// Posted when the torrent has finished downloading.
     TorrentComplete(TorrentId),
+    /// Posted when all blocks of a piece were downloaded and the piece was
+    /// hashed. If it's valid, it was also written to disk, otherwise it's
+    /// downloaded again.
+    PieceComplete {
+        id: TorrentId,
+        piece_index: PieceIndex,
+        is_valid: bool,
+    },
+    /// Posted when all pieces of a file were downloaded and written to disk,
+    /// so that the file can be used before the rest of the torrent is done.
+    ///
+    /// Files are indexed in the order they appear in the torrent's metainfo.
+    FileComplete {
+        id: TorrentId,
+        file_index: FileIndex,
+    },
+    /// Posted when a file renamed with
+    /// [`EngineHandle::rename_file`](crate::engine::EngineHandle::rename_file)
+    /// was moved to its new path, relative to the torrent's root. This is
+    /// also posted when the root of a single file torrent is renamed, as that
+    /// renames its file. If renaming fails, an [`Error::Disk`] error is
+    /// posted instead.
+    FileRenamed {
+        id: TorrentId,
+        file_index: FileIndex,
+        path: PathBuf,
+    },
+    /// Posted when the directory of an archive torrent renamed with
+    /// [`EngineHandle::rename_root`](crate::engine::EngineHandle::rename_root)
+    /// was renamed.
+    RootRenamed { id: TorrentId, name: String },
+    /// Posted when a torrent paused with
+    /// [`EngineHandle::pause_torrent`](crate::engine::EngineHandle::pause_torrent)
+    /// has disconnected its peers and told its trackers it stopped.
+    TorrentPaused(TorrentId),
+    /// Posted when a torrent resumed with
+    /// [`EngineHandle::resume_torrent`](crate::engine::EngineHandle::resume_torrent)
+    /// was announced again.
+    TorrentResumed(TorrentId),
+    /// Posted when the session was paused with
+    /// [`EngineHandle::pause_all`](crate::engine::EngineHandle::pause_all).
+    /// The torrents stop in the background, without posting alerts of their
+    /// own.
+    SessionPaused,
+    /// Posted when the session was resumed with
+    /// [`EngineHandle::resume_all`](crate::engine::EngineHandle::resume_all).
+    SessionResumed,
+    /// Posted when a torrent was stopped by an error accessing its files,
+    /// which is posted as an [`Error::Disk`] alert before it. If the torrent
+    /// is retried automatically, `retry_in` is the time until it is.
+    ///
+    /// See [`TorrentConf::error_retry_interval`](crate::conf::TorrentConf::error_retry_interval).
+    TorrentErrored {
+        id: TorrentId,
+        retry_in: Option<Duration>,
+    },
+    /// Posted when an errored torrent was started again, either as it was
+    /// retried automatically or as the user cleared its error with
+    /// [`EngineHandle::clear_torrent_error`](crate::engine::EngineHandle::clear_torrent_error).
+    /// If the error persists, the torrent is errored again.
+    TorrentErrorCleared(TorrentId),
+    /// Posted when a torrent was stopped because it lost its slot to
+    /// a torrent ahead of it in the queue, or when it's added without a free
+    /// slot (see [`QueueConf`](crate::conf::QueueConf)).
+    TorrentQueued(TorrentId),
+    /// Posted when a queued torrent was given a slot and started.
+    TorrentActivated(TorrentId),
+    /// Posted when a torrent rechecked with
+    /// [`EngineHandle::force_recheck`](crate::engine::EngineHandle::force_recheck)
+    /// has verified its data on disk, with the number of pieces found valid.
+    TorrentChecked { id: TorrentId, piece_count: usize },
+    /// Posted when a seeding torrent reached one of its
+    /// [`SeedGoalConf`](crate::conf::SeedGoalConf) goals. The torrent is then
+    /// paused or removed, and the corresponding alert follows.
+    SeedGoalReached { id: TorrentId, goal: SeedGoal },
+    /// Posted when a torrent removed with
+    /// [`EngineHandle::remove_torrent`](crate::engine::EngineHandle::remove_torrent)
+    /// has shut down.
+    TorrentRemoved(TorrentId),
+    /// Posted in response to
+    /// [`EngineHandle::query_torrents`](crate::engine::EngineHandle::query_torrents)
+    /// with the ids of the torrents in the engine, in ascending order.
+    Torrents(Vec<TorrentId>),
+    /// Posted in response to
+    /// [`EngineHandle::query_labels`](crate::engine::EngineHandle::query_labels)
+    /// with the labels of each torrent in the engine, in ascending order of
+    /// the torrent ids.
+    TorrentLabels(Vec<(TorrentId, Vec<String>)>),
+    /// Posted in response to
+    /// [`EngineHandle::query_queue`](crate::engine::EngineHandle::query_queue)
+    /// with the ids of the torrents in queue order.
+    TorrentQueue(Vec<TorrentId>),
+    /// Posted when the session was restored with
+    /// [`EngineHandle::load_state`](crate::engine::EngineHandle::load_state),
+    /// with the ids of the restored torrents in queue order. If the state
+    /// couldn't be read at all, an [`Error::Io`] is posted instead.
+    StateLoaded(Vec<TorrentId>),
+    /// Posted when a torrent was added from a file in the directory watched
+    /// per [`EngineConf::watch`](crate::conf::EngineConf::watch). The torrent
+    /// is then started as any other, after which [`Alert::TorrentAdded`] is
+    /// posted.
+    WatchedTorrentAdded { id: TorrentId, path: PathBuf },
+    /// Posted when a torrent couldn't be added from a file in the watched
+    /// directory, e.g. because it's not valid metainfo.
+    WatchedFileRejected { path: PathBuf, error: Error },
+    /// Posted when the engine switched to the alternative rate limits of
+    /// [`EngineConf::alt_rate_limit`](crate::conf::EngineConf::alt_rate_limit)
+    /// as their schedule came into effect, or back to the normal limits.
+    AltRateLimits { is_active: bool },
     /// Each running torrent sends an update of its latest statistics every
-    /// second via this alert.
+    /// second via this alert. It's also posted in response to
+    /// [`EngineHandle::query_stats`](crate::engine::EngineHandle::query_stats).
     TorrentStats {
         id: TorrentId,
         stats: Box<TorrentStats>,
     },
+    /// Posted in response to
+    /// [`EngineHandle::query_pieces`](crate::engine::EngineHandle::query_pieces)
+    /// with the state of each of the torrent's pieces, in piece index order.
+    TorrentPieces {
+        id: TorrentId,
+        pieces: Vec<PieceInfo>,
+    },
+    /// Posted in response to
+    /// [`EngineHandle::query_trackers`](crate::engine::EngineHandle::query_trackers)
+    /// with the status of each of the torrent's trackers, in tier order.
+    TorrentTrackers {
+        id: TorrentId,
+        trackers: Vec<TrackerInfo>,
+    },
+    /// Posted when it's determined whether peers can connect to a torrent's
+    /// listen port, when that changes, and in response to
+    /// [`EngineHandle::check_connectability`](crate::engine::EngineHandle::check_connectability).
+    ConnectabilityChanged {
+        id: TorrentId,
+        connectability: Connectability,
+    },
+    /// Posted when an announce to a tracker succeeded, with the number of
+    /// peers it returned.
+    ///
+    /// Failed announces are posted as [`Error::Tracker`] errors.
+    TrackerAnnounced {
+        id: TorrentId,
+        url: Url,
+        peer_count: usize,
+    },
+    /// Posted when a tracker's announce response contains a warning message.
+    /// The announce itself succeeded.
+    ///
+    /// Tracker errors, including the failure reasons of rejected announces,
+    /// are posted as [`Error::Tracker`] errors.
+    TrackerWarning {
+        id: TorrentId,
+        url: Url,
+        message: String,
+    },
+    /// Posted when our external IP address of either address family changes,
+    /// as determined by the majority of the addresses reported by trackers
+    /// and peers, and in response to
+    /// [`EngineHandle::query_external_ip`](crate::engine::EngineHandle::query_external_ip).
+    ExternalIp {
+        ipv4: Option<Ipv4Addr>,
+        ipv6: Option<Ipv6Addr>,
+    },
+    /// Posted in response to
+    /// [`EngineHandle::query_ip_filter_stats`](crate::engine::EngineHandle::query_ip_filter_stats)
+    /// with the number of peers blocked by the engine's IP filter.
+    IpFilterStats(IpFilterStats),
+    /// Posted in response to
+    /// [`EngineHandle::query_dht_stats`](crate::engine::EngineHandle::query_dht_stats)
+    /// with the statistics of the engine's DHT node.
+    DhtStats(Box<DhtStats>),
+    /// Posted when the lookup started by
+    /// [`EngineHandle::dht_get_immutable`](crate::engine::EngineHandle::dht_get_immutable)
+    /// finishes, with the bencoded value stored at the target, if found.
+    DhtImmutableItem {
+        target: Sha1Hash,
+        value: Option<Vec<u8>>,
+    },
+    /// Posted when the lookup started by
+    /// [`EngineHandle::dht_get_mutable`](crate::engine::EngineHandle::dht_get_mutable)
+    /// finishes, with the validly signed item with the highest sequence number
+    /// found, if any.
+    DhtMutableItem {
+        public_key: [u8; 32],
+        salt: Vec<u8>,
+        item: Option<Box<MutableItem>>,
+    },
+    /// Posted when an item put with
+    /// [`EngineHandle::dht_put_immutable`](crate::engine::EngineHandle::dht_put_immutable)
+    /// or
+    /// [`EngineHandle::dht_put_mutable`](crate::engine::EngineHandle::dht_put_mutable)
+    /// was sent to the nodes closest to its target. If no nodes were found,
+    /// e.g. because we haven't joined the DHT yet, `node_count` is 0.
+    DhtItemPut { target: Sha1Hash, node_count: usize },
+    /// Posted when the node queried with
+    /// [`EngineHandle::dht_sample_infohashes`](crate::engine::EngineHandle::dht_sample_infohashes)
+    /// responds.
+    DhtSampleInfohashes {
+        /// The address of the queried node.
+        addr: SocketAddr,
+        /// The time after which the node may return a different sample.
+        interval: Duration,
+        /// The number of info hashes the node stores peers of.
+        num: usize,
+        /// A random sample of the info hashes the node stores peers of.
+        samples: Vec<Sha1Hash>,
+        /// The ids and addresses of the nodes the queried node knows that
+        /// are closest to the target, with which the DHT can be traversed.
+        nodes: Vec<([u8; 20], SocketAddr)>,
+    },
+    /// Posted when a port was mapped on the gateway, or when the external port
+    /// or address of its mapping changed. Renewals of the mapping are not
+    /// posted.
+    PortMapped {
+        method: Method,
+        protocol: Protocol,
+        /// Our port.
+        port: u16,
+        /// The port on the gateway forwarded to ours, which peers should
+        /// connect to.
+        external_port: u16,
+        /// The gateway's external address, if it reported it.
+        external_ip: Option<IpAddr>,
+    },
+    /// Posted when a port could not be mapped on the gateway, or the gateway
+    /// could not be found. The mapping is retried periodically.
+    PortMappingError {
+        method: Method,
+        protocol: Protocol,
+        port: u16,
+        #[cfg_attr(
+            feature = "serde",
+            serde(serialize_with = "crate::error::serialize_message")
+        )]
+        error: IoError,
+    },
+    /// Posted when a torrent's session with a peer completed the handshake.
+    PeerConnected {
+        id: TorrentId,
+        addr: SocketAddr,
+        /// The peer's 20 byte BitTorrent id, sent in the handshake.
+        peer_id: PeerId,
+    },
+    /// Posted when a torrent's session with a peer is stopped, either as
+    /// a result of a clean shutdown or an error.
+    ///
+    /// If the session was stopped due to an error, it is included. See
+    /// [`PeerError::is_protocol_violation`] to tell misbehaving peers apart from
+    /// network issues.
+    PeerDisconnected {
+        id: TorrentId,
+        addr: SocketAddr,
+        error: Option<PeerError>,
+    },
     /// An error from somewhere inside the engine.
     Error(Error),
 }
@@ -95,6 +95,12 @@ impl SlidingAvg {
             (self.deviation + 32) / 64
         }
     }
+
+    /// Returns the number of samples received, but no more than the inverted
+    /// gain.
+    pub fn sample_count(&self) -> usize {
+        self.sample_count
+    }
 }
 
 impl Default for SlidingAvg {
@@ -131,6 +137,10 @@ impl SlidingDurationAvg {
         let ms = self.0.deviation() as u64;
         Duration::from_millis(ms)
     }
+
+    pub fn sample_count(&self) -> usize {
+        self.0.sample_count()
+    }
 }
 
 impl Default for SlidingDurationAvg {
@@ -1,14 +1,52 @@
 //! This module defines types used to configure the engine and its parts.
 
-use std::{path::PathBuf, time::Duration};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    ops::RangeInclusive,
+    path::PathBuf,
+    time::Duration,
+};
 
-use crate::PeerId;
+use log::LevelFilter;
+
+use crate::{ip_filter::IpFilter, logging::Subsystem, PeerId, BLOCK_LEN};
 
 /// The default cratetorrent client id.
 pub const CRATETORRENT_CLIENT_ID: &PeerId = b"cbt-0000000000000000";
 
+/// The user agent sent to HTTP trackers by default.
+pub const CRATETORRENT_USER_AGENT: &str =
+    concat!("cratetorrent/", env!("CARGO_PKG_VERSION"));
+
+/// Generates a client id that starts with the given prefix, such as
+/// `-CT0100-` in the common Azureus-style convention, followed by random
+/// alphanumeric characters.
+///
+/// The prefix is truncated to the length of the id. To be identified
+/// consistently across restarts, e.g. by private trackers, the generated id
+/// should be persisted and set as [`EngineConf::client_id`] on later runs.
+pub fn generate_client_id(prefix: &[u8]) -> PeerId {
+    use rand::{distributions::Alphanumeric, Rng};
+
+    let mut client_id = [0; 20];
+    let prefix_len = prefix.len().min(client_id.len());
+    client_id[..prefix_len].copy_from_slice(&prefix[..prefix_len]);
+    let mut rng = rand::thread_rng();
+    for b in client_id[prefix_len..].iter_mut() {
+        *b = rng.sample(Alphanumeric) as u8;
+    }
+    client_id
+}
+
 /// The global configuration for the torrent engine and all its parts.
 #[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 pub struct Conf {
     pub engine: EngineConf,
     pub torrent: TorrentConf,
@@ -24,6 +62,29 @@ impl Conf {
             engine: EngineConf {
                 client_id: *CRATETORRENT_CLIENT_ID,
                 download_dir: download_dir.into(),
+                socket: SocketConf::default(),
+                listen_ports: None,
+                bind_addr: None,
+                proxy: None,
+                tracker_proxy: None,
+                announce: AnnounceConf::default(),
+                tracker_tls: TrackerTlsConf::default(),
+                dht: None,
+                lsd: None,
+                port_mapping: None,
+                ip_filter: IpFilter::default(),
+                download_rate_limit: None,
+                upload_rate_limit: None,
+                alt_rate_limit: None,
+                exempt_local_peers_from_rate_limit: false,
+                rate_limit_protocol_overhead: true,
+                read_cache_len: 1000,
+                queue: QueueConf::default(),
+                state_dir: None,
+                state_save_interval: Duration::from_secs(5 * 60),
+                labels: HashMap::new(),
+                watch: None,
+                log_levels: HashMap::new(),
             },
             torrent: TorrentConf::default(),
         }
@@ -32,12 +93,605 @@ impl Conf {
 
 /// Configuration related to the engine itself.
 #[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 pub struct EngineConf {
     /// The ID of the client to announce to trackers and other peers.
     pub client_id: PeerId,
     /// The directory in which a torrent's files are placed upon download and
     /// from which they are seeded.
     pub download_dir: PathBuf,
+    /// The options applied to each peer connection's socket.
+    pub socket: SocketConf,
+    /// If set, torrents listen for peer connections on the first free port in
+    /// this range, e.g. one that is forwarded to us. A single port is given
+    /// as a range of one, such as `6881..=6881`.
+    ///
+    /// If not set, or if all ports in the range are taken, the OS assigns
+    /// a free port. The port a torrent is listening on is posted as an
+    /// [`Alert::Listening`](crate::alert::Alert::Listening).
+    pub listen_ports: Option<RangeInclusive<u16>>,
+    /// If set, outbound peer connections and the torrents' listeners are
+    /// bound to this local address or network interface, so that no peer
+    /// traffic leaves through any other interface, e.g. when it must only go
+    /// through a VPN.
+    ///
+    /// The interface's address is looked up again every second. While it has
+    /// none, e.g. because the VPN is down, no peers are connected and an
+    /// [`Alert::Error`](crate::alert::Alert::Error) is posted.
+    pub bind_addr: Option<BindAddr>,
+    /// If set, outbound peer connections and tracker announces are tunneled
+    /// through this SOCKS5 proxy.
+    pub proxy: Option<ProxyConf>,
+    /// If set, HTTP tracker announces are routed through this proxy instead
+    /// of [`EngineConf::proxy`], while peer traffic is not affected by it.
+    /// This can be used to announce to private trackers from a specific IP.
+    ///
+    /// UDP trackers are skipped when this is set, as they can't be reached
+    /// through the proxy.
+    pub tracker_proxy: Option<TrackerProxyConf>,
+    /// The parameters with which we identify ourselves to trackers.
+    pub announce: AnnounceConf,
+    /// The TLS settings of connections to HTTPS trackers.
+    pub tracker_tls: TrackerTlsConf,
+    /// If set, the engine joins the Mainline DHT, in which public torrents
+    /// look for peers in addition to their trackers.
+    ///
+    /// The DHT is not used if [`EngineConf::proxy`] is set, as it can't be
+    /// reached through the proxy.
+    pub dht: Option<DhtConf>,
+    /// If set, public torrents are announced on the local network, and look
+    /// for peers there, via Local Service Discovery.
+    ///
+    /// Like the DHT, it's not used if [`EngineConf::proxy`] is set.
+    pub lsd: Option<LsdConf>,
+    /// If set, the torrents' listening ports and the DHT node's port are
+    /// mapped on the local network's gateway, so that peers can connect to
+    /// us when we are behind a NAT.
+    ///
+    /// Like the DHT, it's not used if [`EngineConf::proxy`] is set.
+    pub port_mapping: Option<PortMappingConf>,
+    /// The IP address ranges of peers that torrents don't connect to or accept
+    /// connections from, regardless of where the peers were learned from. By
+    /// default, no addresses are blocked.
+    pub ip_filter: IpFilter,
+    /// If set, the maximum download rate of all torrents combined, in bytes
+    /// per second.
+    ///
+    /// The rate is divided among the torrents that are downloading in
+    /// proportion to their [`TorrentConf::bandwidth_priority`], and the share
+    /// a torrent doesn't use is given to the others.
+    pub download_rate_limit: Option<u64>,
+    /// If set, the maximum upload rate of all torrents combined, in bytes per
+    /// second.
+    ///
+    /// It's divided among the torrents that are seeding the same way as
+    /// [`EngineConf::download_rate_limit`].
+    pub upload_rate_limit: Option<u64>,
+    /// If set, the alternative engine-wide rate limits that replace
+    /// [`EngineConf::download_rate_limit`] and
+    /// [`EngineConf::upload_rate_limit`] while their schedule says so, e.g.
+    /// to throttle transfers during work hours.
+    ///
+    /// The schedule is checked on each engine tick, and an
+    /// [`Alert::AltRateLimits`](crate::alert::Alert::AltRateLimits) alert is
+    /// posted when the limits are switched.
+    pub alt_rate_limit: Option<AltRateLimitConf>,
+    /// If set, transfers with peers on the local network neither count towards
+    /// nor are held back by [`EngineConf::download_rate_limit`] and
+    /// [`EngineConf::upload_rate_limit`], nor by the torrents' own
+    /// [`TorrentConf::rate_limit`], as they don't use the internet
+    /// connection's bandwidth. Per peer limits still apply.
+    ///
+    /// A peer is considered local if it was found via LSD, if its address is
+    /// not publicly routable, or if it shares our external address.
+    pub exempt_local_peers_from_rate_limit: bool,
+    /// If set, the protocol messages exchanged with peers (requests, haves,
+    /// message headers, and so on) count towards the engine-wide and per peer
+    /// rate limits, so that the limits apply to the bytes actually sent and
+    /// received. Otherwise only the block payloads are counted.
+    ///
+    /// The overhead is accounted for after the fact, once a second, so the
+    /// rates may briefly exceed the limits. Enabled by default.
+    pub rate_limit_protocol_overhead: bool,
+    /// The maximum number of pieces each torrent keeps in memory after reading
+    /// them from disk for upload, so that the other blocks of the piece,
+    /// which peers tend to request next, are not read again.
+    pub read_cache_len: usize,
+    /// The limits on how many torrents may be active at the same time.
+    pub queue: QueueConf,
+    /// If set, the session is saved in this directory: the torrents in queue
+    /// order, their metainfo and resume data, and the engine-wide rate
+    /// limits. It's saved on shutdown and every
+    /// [`EngineConf::state_save_interval`], and is restored with
+    /// [`EngineHandle::load_state`](crate::engine::EngineHandle::load_state).
+    pub state_dir: Option<PathBuf>,
+    /// How often the session is saved in [`EngineConf::state_dir`], so that
+    /// little progress is lost if the engine is not shut down gracefully.
+    pub state_save_interval: Duration,
+    /// The defaults of the torrents added with each label, by label name.
+    ///
+    /// Labels without an entry are just names by which torrents are grouped.
+    /// See [`AddTorrent::label`](crate::engine::AddTorrent::label) for how
+    /// the defaults are applied.
+    pub labels: HashMap<String, LabelConf>,
+    /// If set, the directory that is watched for new metainfo and magnet
+    /// files, which are added as torrents with the default options.
+    pub watch: Option<WatchConf>,
+    /// The levels of the subsystems' log records, which are set when the
+    /// engine is spawned. Subsystems without an entry keep their current
+    /// level, which is initially [`LevelFilter::Trace`].
+    ///
+    /// The levels are only applied if the application's logger is wrapped in
+    /// a [`logging::Filter`](crate::logging::Filter).
+    pub log_levels: HashMap<Subsystem, LevelFilter>,
+}
+
+/// The defaults of the torrents added with a label, which take the place of
+/// the engine's defaults.
+///
+/// If a torrent has several labels, each setting is taken from the first of
+/// its labels that sets it.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", default)
+)]
+pub struct LabelConf {
+    /// If set, the torrents are downloaded into this directory instead of
+    /// [`EngineConf::download_dir`].
+    pub download_dir: Option<PathBuf>,
+    /// If set, the torrents are limited to these rates instead of
+    /// [`TorrentConf::rate_limit`].
+    pub rate_limit: Option<RateLimitConf>,
+    /// If set, the torrents stop seeding by these goals instead of
+    /// [`TorrentConf::seed_goals`].
+    pub seed_goals: Option<SeedGoalConf>,
+}
+
+/// The alternative engine-wide rate limits and the schedule by which they
+/// are in effect.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", default)
+)]
+pub struct AltRateLimitConf {
+    /// If set, the maximum download rate of all torrents combined while the
+    /// alternative limits are in effect, in bytes per second.
+    pub download_rate_limit: Option<u64>,
+    /// If set, the maximum upload rate of all torrents combined while the
+    /// alternative limits are in effect, in bytes per second.
+    pub upload_rate_limit: Option<u64>,
+    /// The periods during which the alternative limits are in effect. If
+    /// empty, they never are.
+    pub schedule: Vec<ScheduleRule>,
+    /// The offset of local time from UTC, in seconds, by which the schedule
+    /// is matched. The engine doesn't read the system's time zone, so it's
+    /// UTC by default.
+    pub utc_offset: i32,
+}
+
+/// A recurring period of the week.
+#[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct ScheduleRule {
+    /// The days on which the period starts. If empty, it starts every day.
+    pub days: Vec<Weekday>,
+    /// The time of day at which the period starts, since midnight.
+    pub start: Duration,
+    /// The time of day at which the period ends, since midnight.
+    ///
+    /// If it's not after `start`, the period ends on the next day, so e.g.
+    /// a period from 22:00 to 06:00 on Fridays lasts until Saturday morning,
+    /// and one whose start and end are the same lasts a full day.
+    pub end: Duration,
+}
+
+/// A day of the week.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+/// The local address or network interface to which peer sockets are bound.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub enum BindAddr {
+    /// A local IP address.
+    Ip(IpAddr),
+    /// The name of a network interface, such as `tun0`. Its first IPv4
+    /// address is used, or if it has none, its first IPv6 address.
+    Interface(String),
+}
+
+impl fmt::Display for BindAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(ip) => write!(f, "address {}", ip),
+            Self::Interface(name) => write!(f, "interface {}", name),
+        }
+    }
+}
+
+/// A SOCKS5 proxy through which to route the engine's outbound traffic.
+///
+/// Tracker host names are resolved by the proxy rather than locally, so that
+/// DNS queries don't leak outside the proxy. Note that incoming peer
+/// connections are not affected by the proxy.
+#[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct ProxyConf {
+    /// The address of the proxy server.
+    pub addr: SocketAddr,
+    /// The credentials to use, if the proxy requires authentication.
+    pub auth: Option<ProxyAuth>,
+}
+
+/// The parameters with which the engine identifies itself to trackers.
+///
+/// Private trackers may require some of these to be set to specific values,
+/// or to remain the same across restarts.
+#[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", default)
+)]
+pub struct AnnounceConf {
+    /// The `User-Agent` header sent to HTTP trackers.
+    pub user_agent: String,
+    /// A random value that lets trackers identify us even if our IP address
+    /// changes. It is randomly generated by default, so it should be
+    /// persisted and set on later runs if it's to remain the same.
+    pub key: u32,
+    /// If set, we always request this many peers from trackers, instead of
+    /// as many as the torrent needs.
+    pub numwant: Option<usize>,
+    /// If set, this port is announced to trackers instead of the one the
+    /// torrent listens on, e.g. if a different port is forwarded to it.
+    pub port: Option<u16>,
+}
+
+impl Default for AnnounceConf {
+    fn default() -> Self {
+        Self {
+            user_agent: CRATETORRENT_USER_AGENT.to_string(),
+            key: rand::random(),
+            numwant: None,
+            port: None,
+        }
+    }
+}
+
+/// Limits on how many torrents may be active at the same time.
+///
+/// Torrents beyond the limits are queued: they are stopped, as if paused, until
+/// a slot frees up. Slots are given to torrents in queue order, which is the
+/// order in which they were added unless changed with
+/// [`EngineHandle::set_queue_position`](crate::engine::EngineHandle::set_queue_position).
+/// Paused torrents don't take up a slot.
+///
+/// By default, the number of active torrents is not limited.
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", default)
+)]
+pub struct QueueConf {
+    /// If set, the maximum number of torrents that may be downloading at the
+    /// same time.
+    pub active_downloads: Option<usize>,
+    /// If set, the maximum number of torrents that may be seeding at the same
+    /// time.
+    pub active_seeds: Option<usize>,
+}
+
+/// TLS settings for connections to HTTPS trackers.
+///
+/// By default, tracker certificates are verified against the system's root
+/// certificates. Private trackers with self-signed certificates need their
+/// certificate added to [`TrackerTlsConf::root_certs`], or as a last resort,
+/// verification turned off.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", default)
+)]
+pub struct TrackerTlsConf {
+    /// Additional PEM encoded root certificates to trust.
+    pub root_certs: Vec<Vec<u8>>,
+    /// If set, invalid certificates, e.g. expired or self-signed ones, are
+    /// accepted.
+    ///
+    /// This makes announces vulnerable to man-in-the-middle attacks, so it
+    /// should only be used if the tracker's certificate can't be added to
+    /// [`TrackerTlsConf::root_certs`].
+    pub accept_invalid_certs: bool,
+    /// If set, certificates that are not issued for the tracker's host name
+    /// are accepted. This has the same dangers as
+    /// [`TrackerTlsConf::accept_invalid_certs`].
+    pub accept_invalid_hostnames: bool,
+    /// If set, the Server Name Indication extension is not sent, which some
+    /// trackers behind misconfigured servers require, and which hides the
+    /// tracker's host name from observers of the connection.
+    pub disable_sni: bool,
+}
+
+impl TrackerTlsConf {
+    /// Returns whether the settings are the defaults, in which case the HTTP
+    /// client's default TLS connector is used.
+    pub(crate) fn is_default(&self) -> bool {
+        self.root_certs.is_empty()
+            && !self.accept_invalid_certs
+            && !self.accept_invalid_hostnames
+            && !self.disable_sni
+    }
+}
+
+/// Configuration of the engine's DHT node.
+#[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", default)
+)]
+pub struct DhtConf {
+    /// The UDP address on which the DHT node listens.
+    pub listen_addr: SocketAddr,
+    /// The nodes through which we join the DHT, as `host:port` pairs. By
+    /// default, these are the well-known routers run by popular clients.
+    ///
+    /// If empty, the DHT is only joined through the nodes restored from
+    /// [`DhtConf::state_path`], those suggested by torrents' metainfo, and
+    /// those added with
+    /// [`EngineHandle::add_dht_node`](crate::engine::EngineHandle::add_dht_node).
+    pub bootstrap_nodes: Vec<String>,
+    /// Each torrent's peers are looked up and the torrent is announced this
+    /// often.
+    pub announce_interval: Duration,
+    /// A torrent that runs low on peers is looked up before its next
+    /// periodic lookup, but at most this often.
+    pub min_lookup_interval: Duration,
+    /// If set, nodes whose id doesn't match their IP address as specified in
+    /// [BEP 42](https://www.bittorrent.org/beps/bep_0042.html) are not added
+    /// to our routing table. Our own id is always made to match our external
+    /// address once it's known, regardless of this setting.
+    pub enforce_node_id: bool,
+    /// If set, our node id and routing table are saved to this file on
+    /// shutdown and restored from it on startup, so that the node doesn't
+    /// have to join the DHT from scratch after every restart.
+    pub state_path: Option<PathBuf>,
+    /// If set, the node only sends queries, flagging them as sent by
+    /// a read-only node as specified in
+    /// [BEP 43](https://www.bittorrent.org/beps/bep_0043.html), and doesn't
+    /// answer other nodes' queries or store peers and items for them.
+    ///
+    /// This suits clients that can't be reached from the internet, e.g.
+    /// behind a restrictive NAT, whose answers would be lost anyway. Other
+    /// nodes don't add read-only nodes to their routing tables, so they
+    /// aren't sent queries they can't answer.
+    pub read_only: bool,
+}
+
+impl Default for DhtConf {
+    fn default() -> Self {
+        Self {
+            listen_addr: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 6881),
+            // the well-known routers run by popular clients
+            bootstrap_nodes: vec![
+                "router.bittorrent.com:6881".into(),
+                "router.utorrent.com:6881".into(),
+                "dht.transmissionbt.com:6881".into(),
+            ],
+            // the same as libtorrent's default
+            announce_interval: Duration::from_secs(15 * 60),
+            min_lookup_interval: Duration::from_secs(60),
+            enforce_node_id: true,
+            state_path: None,
+            read_only: false,
+        }
+    }
+}
+
+/// Configuration of Local Service Discovery.
+#[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", default)
+)]
+pub struct LsdConf {
+    /// The address of the local interface on which to join the multicast
+    /// group. If unspecified, the OS picks the interface.
+    pub interface: Ipv4Addr,
+    /// Each torrent is announced this often. Torrents are not announced more
+    /// often than once a minute, even if this is shorter.
+    pub announce_interval: Duration,
+}
+
+impl Default for LsdConf {
+    fn default() -> Self {
+        Self {
+            interface: Ipv4Addr::UNSPECIFIED,
+            // the same as libtorrent's default
+            announce_interval: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// Configuration of port mapping on the local network's gateway.
+#[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", default)
+)]
+pub struct PortMappingConf {
+    /// Whether to map ports with UPnP IGD.
+    pub upnp: bool,
+    /// Whether to map ports with NAT-PMP, or with PCP if the gateway supports
+    /// it.
+    pub natpmp: bool,
+    /// The lifetime requested for mappings, which are renewed before they
+    /// expire. Gateways may grant a shorter one.
+    pub lease_duration: Duration,
+    /// The gateway to which NAT-PMP and PCP requests are sent. If not set,
+    /// the default gateway is looked up in the routing table, which is only
+    /// supported on Linux.
+    pub gateway: Option<Ipv4Addr>,
+}
+
+impl Default for PortMappingConf {
+    fn default() -> Self {
+        Self {
+            upnp: true,
+            natpmp: true,
+            // the same as libtorrent's default
+            lease_duration: Duration::from_secs(60 * 60),
+            gateway: None,
+        }
+    }
+}
+
+/// Configuration of the directory watched for torrents to add.
+///
+/// Files with the `.torrent` extension are added as metainfo files, and files
+/// with the `.magnet` extension as text files of magnet links, one per line.
+/// Once a file is added, it's moved to [`WatchConf::added_dir`] if set, or
+/// renamed with the `.added` suffix otherwise, so that it's not added again.
+/// Files that can't be added are renamed with the `.invalid` suffix.
+#[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct WatchConf {
+    /// The watched directory. Its subdirectories are not watched.
+    pub dir: PathBuf,
+    /// How often the directory is scanned for new files.
+    pub scan_interval: Duration,
+    /// If set, the added files are moved to this directory.
+    pub added_dir: Option<PathBuf>,
+}
+
+impl WatchConf {
+    /// Returns the configuration of watching the directory, with reasonable
+    /// defaults.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            scan_interval: Duration::from_secs(5),
+            added_dir: None,
+        }
+    }
+}
+
+/// A proxy used only for tracker announces.
+#[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct TrackerProxyConf {
+    /// The protocol spoken by the proxy.
+    pub kind: TrackerProxyKind,
+    /// The address of the proxy server.
+    pub addr: SocketAddr,
+    /// The credentials to use, if the proxy requires authentication.
+    pub auth: Option<ProxyAuth>,
+}
+
+/// The protocol of a [`TrackerProxyConf`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub enum TrackerProxyKind {
+    /// An HTTP proxy. HTTPS announces are tunneled through it with the
+    /// `CONNECT` method.
+    Http,
+    /// A SOCKS5 proxy, which also resolves tracker host names.
+    Socks5,
+}
+
+/// Username and password credentials for a proxy.
+#[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct ProxyAuth {
+    pub username: String,
+    pub password: String,
+}
+
+/// Options applied to the TCP socket of each peer connection, both inbound and
+/// outbound.
+///
+/// By default, the operating system's defaults are used. These are usually
+/// fine, but high-throughput seedboxes may benefit from larger socket buffers,
+/// while marking packets with a DSCP value can tell routers to treat torrent
+/// traffic as background traffic.
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", default)
+)]
+pub struct SocketConf {
+    /// Whether to disable Nagle's algorithm (`TCP_NODELAY`), sending small
+    /// messages (like requests) immediately rather than buffering them.
+    pub nodelay: bool,
+    /// The size of the socket's send buffer (`SO_SNDBUF`), in bytes.
+    ///
+    /// Note that the OS may round or double this value.
+    pub send_buffer_size: Option<usize>,
+    /// The size of the socket's receive buffer (`SO_RCVBUF`), in bytes.
+    ///
+    /// Note that the OS may round or double this value.
+    pub recv_buffer_size: Option<usize>,
+    /// The type of service field (`IP_TOS` or `IPV6_TCLASS`) set on outgoing
+    /// packets. The upper 6 bits are the DSCP value.
+    ///
+    /// For example, `0x20` marks packets as low priority background traffic
+    /// (DSCP CS1).
+    pub tos: Option<u8>,
 }
 
 /// Configuration for a torrent.
@@ -45,6 +699,11 @@ pub struct EngineConf {
 /// The engine will have a default instance of this applied to all torrents by
 /// default, but individual torrents may override this configuration.
 #[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", default)
+)]
 pub struct TorrentConf {
     /// The minimum number of peers we want to keep in torrent at all times.
     /// This will be configurable later.
@@ -53,24 +712,249 @@ pub struct TorrentConf {
     /// The max number of connected peers the torrent should have.
     pub max_connected_peer_count: usize,
 
-    /// If the tracker doesn't provide a minimum announce interval, we default
-    /// to announcing every 30 seconds.
+    /// If the tracker doesn't provide an announce interval, we default to
+    /// announcing this often.
     pub announce_interval: Duration,
 
-    /// After this many attempts, the torrent stops announcing to a tracker.
-    pub tracker_error_threshold: usize,
+    /// If the tracker doesn't provide a minimum announce interval, we don't
+    /// announce more often than this, even if we need peers.
+    pub min_announce_interval: Duration,
+
+    /// If set, all trackers are announced to concurrently, regardless of their
+    /// tier, instead of only the first tracker that responds as per BEP 12.
+    ///
+    /// This is what most clients do, as it finds more peers, but it puts more
+    /// load on trackers.
+    pub announce_to_all_trackers: bool,
+
+    /// After a failed announce, we wait this long before retrying the
+    /// tracker. The wait is doubled with each consecutive failure, up to
+    /// `max_tracker_retry_interval`.
+    pub tracker_retry_interval: Duration,
+
+    /// The longest we wait before retrying a failing tracker.
+    pub max_tracker_retry_interval: Duration,
+
+    /// If set, a torrent stopped by an error accessing its files, e.g.
+    /// because the disk is full or the files' permissions changed, is started
+    /// again after this long, in case the condition has cleared. The wait is
+    /// doubled with each consecutive error, up to `max_error_retry_interval`.
+    ///
+    /// Only auto-managed torrents are retried; others stay stopped until
+    /// [`EngineHandle::clear_torrent_error`](crate::engine::EngineHandle::clear_torrent_error)
+    /// is called.
+    pub error_retry_interval: Option<Duration>,
+
+    /// The longest we wait before retrying an errored torrent.
+    pub max_error_retry_interval: Duration,
+
+    /// When the torrent is shut down, we wait at most this long for trackers
+    /// to receive the stopped event.
+    pub stop_announce_timeout: Duration,
+
+    /// The upload and download rate limits applied to each peer connection
+    /// individually, so that a single peer can't monopolize the bandwidth.
+    ///
+    /// By default, peers are not limited.
+    pub peer_rate_limit: RateLimitConf,
+
+    /// The upload and download rate limits of the torrent as a whole, shared
+    /// by all its peer connections.
+    ///
+    /// These apply under the engine-wide limits: the torrent never exceeds
+    /// them, even if the engine-wide limits would allow it, and the part of
+    /// the engine-wide rate it can't use is given to the other torrents. This
+    /// way bulk downloads can be held back while others run at full speed.
+    /// They may be changed at runtime with
+    /// [`EngineHandle::set_torrent_rate_limits`](crate::engine::EngineHandle::set_torrent_rate_limits).
+    ///
+    /// By default, the torrent is not limited.
+    pub rate_limit: RateLimitConf,
+
+    /// The torrent's weight when dividing the engine-wide rate limits among
+    /// torrents.
+    ///
+    /// A torrent with priority 2 gets twice the share of a torrent with
+    /// priority 1, if both can use it. Zero is treated as 1.
+    pub bandwidth_priority: u32,
+
+    /// The maximum number of pieces that may be in progress at the same time.
+    ///
+    /// Peers continue the pieces that are already in progress before starting
+    /// new ones, but if a peer has none of these pieces, it will start a new
+    /// piece. As the blocks of a piece are buffered in memory until the whole
+    /// piece is downloaded, this limits the memory used by write buffers to
+    /// this many pieces. Once the limit is reached, peers that don't have any
+    /// of the pieces in progress stay idle until one completes, except for
+    /// possible super-seeders, which may start a couple more pieces.
+    pub max_partial_piece_count: usize,
+
+    /// The length of the blocks in which pieces are requested from peers.
+    ///
+    /// The de facto standard is 16 KiB and many clients reject (or even
+    /// disconnect on) requests for larger blocks, so this should only be
+    /// changed if all peers are known to accept other lengths. The value is
+    /// capped at 128 KiB, the largest block we accept from peers, and must not
+    /// be zero.
+    pub block_len: u32,
+
+    /// The time after which a pending block request is considered lost and
+    /// the block is freed, so that it can be requested from another peer.
+    ///
+    /// Each peer session also times out its requests based on the peer's
+    /// round trip times, but this catches blocks left pending by a peer that
+    /// is still connected but no longer responding, so that the download
+    /// doesn't stall on them.
+    pub block_request_timeout: Duration,
+
+    /// The number of pieces to pick at random before switching to
+    /// rarest-first picking.
+    ///
+    /// Rare pieces are slower to download as few peers have them, while at the
+    /// start of a download we want to get some pieces as soon as possible, so
+    /// that we can offer them to peers in return for being unchoked.
+    pub random_first_piece_count: usize,
+
+    /// Download pieces in order rather than rarest-first.
+    ///
+    /// This is useful for media files that are played while they are being
+    /// downloaded, but it's worse for the health of the swarm and may be slower
+    /// since it can't take advantage of piece availability, so it is turned off
+    /// by default.
+    pub sequential_download: bool,
+
+    /// In sequential download mode, how many pieces after the first missing
+    /// piece may be downloaded in parallel.
+    ///
+    /// A larger window allows more peers to download at the same time, while
+    /// a smaller window gets the next needed piece sooner.
+    pub sequential_lookahead: usize,
+
+    /// Download the first and last pieces of each selected file before its
+    /// other pieces of the same priority.
+    ///
+    /// These pieces usually contain the headers and indices of media files,
+    /// so this allows previewing or starting playback of a file early.
+    pub prioritize_first_last_pieces: bool,
+
+    /// Download the last missing piece of each selected file before other
+    /// pieces of the same priority.
+    ///
+    /// In torrents with many files, this makes files complete one by one
+    /// rather than all at the end of the download, so that they can be used
+    /// (or moved elsewhere) sooner.
+    pub prioritize_file_completion: bool,
+
+    /// The conditions under which the torrent stops seeding.
+    ///
+    /// By default, torrents seed until they are paused or removed.
+    pub seed_goals: SeedGoalConf,
 
     /// Specifies which optional alerts to send, besides the default periodic
     /// stats update.
     pub alerts: TorrentAlertConf,
 }
 
+/// The conditions under which a seeding torrent is stopped, and how.
+///
+/// When any of the set goals is reached, the torrent is paused or removed, as
+/// per [`SeedGoalConf::action`], and an
+/// [`Alert::SeedGoalReached`](crate::alert::Alert::SeedGoalReached) alert is
+/// posted. Only the time spent seeding counts towards the time goals, not the
+/// time the torrent was downloading, paused, or queued. A goal is reported
+/// only once per run, so a torrent resumed after being paused for reaching
+/// one keeps seeding.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", default)
+)]
+pub struct SeedGoalConf {
+    /// If set, the torrent stops once it uploaded this many times the bytes
+    /// it downloaded, or the size of the torrent if it downloaded less than
+    /// that (e.g. because it was added as a seed).
+    pub ratio: Option<f64>,
+    /// If set, the torrent stops after seeding for this long.
+    pub seed_time: Option<Duration>,
+    /// If set, the torrent stops after seeding for this long without
+    /// uploading anything.
+    pub idle_time: Option<Duration>,
+    /// What happens to the torrent once a goal is reached.
+    pub action: SeedGoalAction,
+}
+
+impl Default for SeedGoalConf {
+    fn default() -> Self {
+        Self {
+            ratio: None,
+            seed_time: None,
+            idle_time: None,
+            action: SeedGoalAction::Pause,
+        }
+    }
+}
+
+/// What happens to a torrent that reached one of its seed goals.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub enum SeedGoalAction {
+    /// The torrent is paused, as with
+    /// [`EngineHandle::pause_torrent`](crate::engine::EngineHandle::pause_torrent).
+    Pause,
+    /// The torrent is removed, as with
+    /// [`EngineHandle::remove_torrent`](crate::engine::EngineHandle::remove_torrent).
+    /// The downloaded files are kept.
+    Remove,
+}
+
+/// The seed goal a torrent reached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub enum SeedGoal {
+    /// [`SeedGoalConf::ratio`]
+    Ratio,
+    /// [`SeedGoalConf::seed_time`]
+    SeedTime,
+    /// [`SeedGoalConf::idle_time`]
+    IdleTime,
+}
+
+/// Upload and download rate limits, in bytes per second.
+///
+/// If a direction's limit is not set, that direction is unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", default)
+)]
+pub struct RateLimitConf {
+    /// The maximum upload rate, in bytes per second.
+    pub upload: Option<u64>,
+    /// The maximum download rate, in bytes per second.
+    pub download: Option<u64>,
+}
+
 /// Configuration of a torrent's optional alerts.
 ///
 /// By default, all optional alerts are turned off. This is because some of
 /// these alerts may have overhead that shouldn't be paid when the alerts are
 /// not used.
 #[derive(Clone, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", default)
+)]
 pub struct TorrentAlertConf {
     /// Receive the pieces that were completed each round.
     ///
@@ -98,8 +982,32 @@ impl Default for TorrentConf {
             max_connected_peer_count: 50,
             // needs teting
             announce_interval: Duration::from_secs(60 * 60),
+            min_announce_interval: Duration::from_secs(30),
+            announce_to_all_trackers: false,
             // needs testing
-            tracker_error_threshold: 15,
+            tracker_retry_interval: Duration::from_secs(60),
+            max_tracker_retry_interval: Duration::from_secs(60 * 60),
+            error_retry_interval: Some(Duration::from_secs(60)),
+            max_error_retry_interval: Duration::from_secs(60 * 60),
+            stop_announce_timeout: Duration::from_secs(5),
+            peer_rate_limit: Default::default(),
+            rate_limit: Default::default(),
+            bandwidth_priority: 1,
+            // allows for a reasonable number of peers to download different
+            // pieces while bounding memory use
+            max_partial_piece_count: 64,
+            block_len: BLOCK_LEN,
+            // the upper bound of the peer sessions' own request timeout
+            block_request_timeout: Duration::from_secs(60),
+            // the same as libtorrent's default
+            random_first_piece_count: 4,
+            sequential_download: false,
+            // enough for a few peers to download in parallel without getting
+            // too far ahead of playback
+            sequential_lookahead: 8,
+            prioritize_first_last_pieces: false,
+            prioritize_file_completion: false,
+            seed_goals: Default::default(),
             alerts: Default::default(),
         }
     }
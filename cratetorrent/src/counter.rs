@@ -127,6 +127,12 @@ impl Counter {
         self.total
     }
 
+    /// Sets the total number recorded, e.g. to carry it over from an earlier
+    /// run.
+    pub fn set_total(&mut self, total: u64) {
+        self.total = total;
+    }
+
     /// Returns the number recorded in the current round.
     pub fn round(&self) -> u64 {
         self.round
@@ -0,0 +1,900 @@
+//! A JSON-RPC control interface for running the engine as a daemon.
+//!
+//! This is enabled with the `daemon` feature. A [`Daemon`] takes over the
+//! engine's handle and alerts, and serves
+//! [JSON-RPC 2.0](https://www.jsonrpc.org/specification) requests POSTed to
+//! it over HTTP, one per connection, so that remote UIs can drive the engine.
+//! The methods and their parameters (given by name) are:
+//!
+//! - `torrent.add`: adds a torrent from a `metainfo` file path or a `magnet`
+//!   link, optionally into a `download_dir` and `paused`. Returns the
+//!   torrent's `id`.
+//! - `torrent.remove`: removes the torrent with the `id`, and deletes its
+//!   files if `delete_files` is true.
+//! - `torrent.pause` and `torrent.resume`: pause or resume the torrent with
+//!   the `id`.
+//! - `torrent.list`: returns the ids of the torrents, each with its latest
+//!   stats, which are null until the torrent first reports them.
+//! - `torrent.stats`: returns the latest stats of the torrent with the `id`.
+//! - `torrent.set_limits`: sets the `download` and `upload` rate limits of the
+//!   torrent with the `id`, in bytes per second. Missing limits are lifted.
+//! - `session.set_limits`: sets the engine-wide rate limits, likewise.
+//! - `session.shutdown`: shuts down the engine, after which the daemon stops.
+//!
+//! A daemon bound with [`Daemon::bind`] only listens on a loopback address,
+//! and only accepts requests from the local host: their `Host` header, and
+//! `Origin` header if any, must name `localhost` or a loopback address, so
+//! that web pages can't reach the daemon through DNS rebinding. If a secret is
+//! set with [`Daemon::set_secret`], requests must also send it as a bearer
+//! token in the `Authorization` header. For remote UIs, the daemon is bound to
+//! any address with [`Daemon::bind_remote`], which requires a secret, and
+//! then accepts requests for any host.
+//!
+//! So that pages open in a browser can't forge requests either, each request
+//! must carry the daemon's session id in the `X-Cratetorrent-Session-Id`
+//! header. A request without it is answered with `409 Conflict` and the
+//! session id in the same header, with which the client retries, as with
+//! Transmission's RPC.
+
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{select, stream::StreamExt};
+use rand::{distributions::Alphanumeric, Rng};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{self, UnboundedSender},
+};
+
+use crate::{
+    alert::{Alert, AlertReceiver},
+    conf::RateLimitConf,
+    engine::{AddTorrent, DeleteFiles, EngineHandle},
+    error::*,
+    logging, rt,
+    torrent::stats::{Status, TorrentStats},
+    TorrentId,
+};
+
+/// The longest request head accepted, in bytes.
+const MAX_HEAD_LEN: usize = 8 * 1024;
+/// The longest request body accepted, in bytes.
+const MAX_BODY_LEN: usize = 1024 * 1024;
+/// The time a client has to send its request, after which it's dropped, so
+/// that a stalled client doesn't hold up the others.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// The length of the generated session id.
+const SESSION_ID_LEN: usize = 32;
+/// The header carrying the session id.
+const SESSION_ID_HEADER: &str = "X-Cratetorrent-Session-Id";
+
+/// The JSON-RPC error codes.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// An error returned by the engine.
+const ENGINE_ERROR: i64 = -32000;
+/// The torrent doesn't exist, or hasn't reported its stats yet.
+const TORRENT_NOT_FOUND: i64 = -32001;
+
+/// Serves the engine's JSON-RPC interface.
+pub struct Daemon {
+    listener: TcpListener,
+    /// The id clients must send with their requests, generated anew each
+    /// time the daemon is bound.
+    session_id: String,
+    /// The secret clients must authenticate with, if set. It's always set
+    /// if the daemon is remote.
+    secret: Option<String>,
+    /// Whether the daemon accepts requests from other hosts.
+    is_remote: bool,
+}
+
+impl Daemon {
+    /// Binds the daemon's HTTP endpoint to the address, which must be
+    /// a loopback address, so that only local clients can connect.
+    pub async fn bind(addr: SocketAddr) -> std::io::Result<Self> {
+        if !addr.ip().is_loopback() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "daemon bound to a non-loopback address without a secret",
+            ));
+        }
+        Self::bind_with(addr, None).await
+    }
+
+    /// Binds the daemon's HTTP endpoint to the address, e.g. to all
+    /// interfaces, so that remote UIs can connect.
+    ///
+    /// Requests are then accepted for any host, as long as they send the
+    /// secret as a bearer token in the `Authorization` header. As the token is
+    /// sent in the clear, the daemon should be reached over a trusted network
+    /// or through a TLS terminating proxy.
+    pub async fn bind_remote(
+        addr: SocketAddr,
+        secret: impl Into<String>,
+    ) -> std::io::Result<Self> {
+        let secret = secret.into();
+        if secret.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "remote daemon secret is empty",
+            ));
+        }
+        Self::bind_with(addr, Some(secret)).await
+    }
+
+    async fn bind_with(
+        addr: SocketAddr,
+        secret: Option<String>,
+    ) -> std::io::Result<Self> {
+        let is_remote = secret.is_some();
+        let listener = TcpListener::bind(addr).await?;
+        log::info!(
+            target: logging::ENGINE,
+            "Daemon listening on {}",
+            listener.local_addr()?
+        );
+        let mut rng = rand::thread_rng();
+        let session_id = (0..SESSION_ID_LEN)
+            .map(|_| rng.sample(Alphanumeric))
+            .collect();
+        Ok(Self {
+            listener,
+            session_id,
+            secret,
+            is_remote,
+        })
+    }
+
+    /// Requires clients to send the secret as a bearer token in the
+    /// `Authorization` header of their requests, or replaces the secret
+    /// given when binding.
+    pub fn set_secret(&mut self, secret: impl Into<String>) {
+        self.secret = Some(secret.into());
+    }
+
+    /// Returns the address the daemon is bound to, e.g. to find the port
+    /// chosen if it was bound to port 0.
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serves requests until the engine is shut down with the
+    /// `session.shutdown` method.
+    ///
+    /// The daemon keeps the latest stats of the torrents from the engine's
+    /// alerts, so the alerts are not available to the application while it
+    /// runs. Each connection is served on its own task, so that a slow client
+    /// doesn't hold up the others or the processing of alerts.
+    pub async fn run(
+        mut self,
+        engine: EngineHandle,
+        alert_rx: AlertReceiver,
+    ) -> Result<()> {
+        let (shutdown_tx, shutdown_rx) = mpsc::unbounded_channel();
+        let ctx = Arc::new(Context {
+            session_id: self.session_id,
+            secret: self.secret,
+            is_remote: self.is_remote,
+            state: Mutex::new(State {
+                engine: Some(engine),
+                torrents: BTreeMap::new(),
+                shutdown_tx,
+            }),
+        });
+        let mut incoming = self.listener.incoming().fuse();
+        let mut alert_rx = alert_rx.fuse();
+        let mut shutdown_rx = shutdown_rx.fuse();
+        loop {
+            select! {
+                socket = incoming.select_next_some() => {
+                    let socket = match socket {
+                        Ok(socket) => socket,
+                        Err(e) => {
+                            log::warn!(
+                                target: logging::ENGINE,
+                                "Error accepting connection: {}",
+                                e
+                            );
+                            continue;
+                        }
+                    };
+                    let ctx = Arc::clone(&ctx);
+                    rt::spawn(async move {
+                        if let Err(e) = ctx.serve(socket).await {
+                            log::debug!(
+                                target: logging::ENGINE,
+                                "Error serving request: {}",
+                                e
+                            );
+                        }
+                    });
+                }
+                alert = alert_rx.next() => match alert {
+                    Some(alert) => {
+                        ctx.state.lock().unwrap().handle_alert(alert);
+                    }
+                    // the engine has stopped on its own
+                    None => return Ok(()),
+                },
+                _ = shutdown_rx.select_next_some() => break,
+            }
+        }
+
+        log::info!(target: logging::ENGINE, "Shutting down engine by request");
+        // the requests served after this fail with an engine error
+        let engine = ctx.state.lock().unwrap().engine.take();
+        match engine {
+            Some(engine) => engine.shutdown().await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// What the connections' tasks share.
+struct Context {
+    session_id: String,
+    secret: Option<String>,
+    is_remote: bool,
+    state: Mutex<State>,
+}
+
+impl Context {
+    /// Reads a request from the socket and writes the response to it, after
+    /// which the connection is closed.
+    async fn serve(&self, mut socket: TcpStream) -> std::io::Result<()> {
+        let (head, body) =
+            rt::timeout(REQUEST_TIMEOUT, read_request(&mut socket))
+                .await
+                .map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "request timed out",
+                    )
+                })??;
+        let response = match self.check(&head) {
+            Err(response) => response,
+            Ok(()) if !head.is_post => {
+                Response::empty("405 Method Not Allowed")
+            }
+            Ok(()) => Response {
+                status: "200 OK",
+                headers: String::new(),
+                body: self.state.lock().unwrap().call(&body).to_string(),
+            },
+        };
+        let response = format!(
+            "HTTP/1.1 {}\r\n\
+            Content-Type: application/json\r\n\
+            Content-Length: {}\r\n{}\
+            Connection: close\r\n\r\n{}",
+            response.status,
+            response.body.len(),
+            response.headers,
+            response.body
+        );
+        socket.write_all(response.as_bytes()).await?;
+        socket.shutdown(std::net::Shutdown::Write)
+    }
+
+    /// Checks that the request comes from the local host, unless the daemon
+    /// is remote, and is authorized, or returns the response rejecting it.
+    fn check(&self, head: &Head) -> std::result::Result<(), Response> {
+        let is_host_local = head.host.as_deref().map_or(false, is_local_host);
+        let is_origin_local =
+            head.origin.as_deref().map_or(true, is_local_origin);
+        if !self.is_remote && (!is_host_local || !is_origin_local) {
+            log::warn!(
+                target: logging::ENGINE,
+                "Daemon rejected request from host {:?}, origin {:?}",
+                head.host,
+                head.origin
+            );
+            return Err(Response::empty("403 Forbidden"));
+        }
+
+        if let Some(secret) = &self.secret {
+            let token = head
+                .authorization
+                .as_deref()
+                .and_then(|auth| auth.strip_prefix("Bearer "));
+            let is_authorized = token.map_or(false, |token| {
+                constant_time_eq(token.as_bytes(), secret.as_bytes())
+            });
+            if !is_authorized {
+                return Err(Response {
+                    status: "401 Unauthorized",
+                    headers: "WWW-Authenticate: Bearer\r\n".into(),
+                    body: String::new(),
+                });
+            }
+        }
+
+        if head.session_id.as_deref() != Some(self.session_id.as_str()) {
+            return Err(Response {
+                status: "409 Conflict",
+                headers: format!(
+                    "{}: {}\r\n",
+                    SESSION_ID_HEADER, self.session_id
+                ),
+                body: String::new(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// An HTTP response to a request.
+struct Response {
+    status: &'static str,
+    /// The headers besides those always sent, each terminated by CRLF.
+    headers: String,
+    body: String,
+}
+
+impl Response {
+    fn empty(status: &'static str) -> Self {
+        Self {
+            status,
+            headers: String::new(),
+            body: String::new(),
+        }
+    }
+}
+
+struct State {
+    /// The engine, taken when it's being shut down.
+    engine: Option<EngineHandle>,
+    /// The torrents in the engine, with their latest stats, if reported.
+    torrents: BTreeMap<TorrentId, Option<StatsSummary>>,
+    /// Tells the daemon to shut down the engine.
+    shutdown_tx: UnboundedSender<()>,
+}
+
+impl State {
+    fn handle_alert(&mut self, alert: Alert) {
+        match alert {
+            Alert::TorrentAdded(id) => {
+                self.torrents.entry(id).or_insert(None);
+            }
+            Alert::StateLoaded(ids) => {
+                for id in ids {
+                    self.torrents.entry(id).or_insert(None);
+                }
+            }
+            Alert::TorrentStats { id, stats } => {
+                self.torrents.insert(id, Some(StatsSummary::from(&*stats)));
+            }
+            Alert::TorrentRemoved(id) => {
+                self.torrents.remove(&id);
+            }
+            _ => (),
+        }
+    }
+
+    /// Executes the JSON-RPC request and returns the response.
+    fn call(&mut self, body: &[u8]) -> Value {
+        let request: Value = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(e) => return error_response(Value::Null, PARSE_ERROR, e),
+        };
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let method = match request.get("method").and_then(Value::as_str) {
+            Some(method) => method,
+            None => {
+                return error_response(id, INVALID_REQUEST, "missing method")
+            }
+        };
+        let params = request.get("params").cloned().unwrap_or(json!({}));
+        log::debug!(target: logging::ENGINE, "Daemon request: {}", method);
+        match self.execute(method, params) {
+            Ok(result) => {
+                json!({ "jsonrpc": "2.0", "result": result, "id": id })
+            }
+            Err(e) => error_response(id, e.code, e.message),
+        }
+    }
+
+    fn execute(
+        &mut self,
+        method: &str,
+        params: Value,
+    ) -> std::result::Result<Value, RpcError> {
+        let engine = self.engine.as_ref().ok_or_else(|| {
+            RpcError::new(ENGINE_ERROR, "engine is shutting down")
+        })?;
+        match method {
+            "torrent.add" => {
+                let params: AddParams = parse_params(params)?;
+                let mut torrent = match (params.metainfo, params.magnet) {
+                    (Some(path), None) => AddTorrent::file(path),
+                    (None, Some(uri)) => AddTorrent::magnet(uri),
+                    _ => {
+                        return Err(RpcError::new(
+                            INVALID_PARAMS,
+                            "one of metainfo or magnet must be given",
+                        ))
+                    }
+                };
+                if let Some(dir) = params.download_dir {
+                    torrent = torrent.download_dir(dir);
+                }
+                let id = engine.add_torrent(torrent.paused(params.paused))?;
+                self.torrents.entry(id).or_insert(None);
+                Ok(json!({ "id": u32::from(id) }))
+            }
+            "torrent.remove" => {
+                let params: RemoveParams = parse_params(params)?;
+                let delete_files = if params.delete_files {
+                    DeleteFiles::Yes
+                } else {
+                    DeleteFiles::No
+                };
+                engine.remove_torrent(params.id.into(), delete_files)?;
+                Ok(Value::Null)
+            }
+            "torrent.pause" => {
+                let params: IdParams = parse_params(params)?;
+                engine.pause_torrent(params.id.into())?;
+                Ok(Value::Null)
+            }
+            "torrent.resume" => {
+                let params: IdParams = parse_params(params)?;
+                engine.resume_torrent(params.id.into())?;
+                Ok(Value::Null)
+            }
+            "torrent.list" => {
+                let torrents: Vec<_> = self
+                    .torrents
+                    .iter()
+                    .map(|(&id, stats)| {
+                        json!({ "id": u32::from(id), "stats": stats })
+                    })
+                    .collect();
+                Ok(Value::Array(torrents))
+            }
+            "torrent.stats" => {
+                let params: IdParams = parse_params(params)?;
+                match self.torrents.get(&params.id.into()) {
+                    Some(Some(stats)) => Ok(json!(stats)),
+                    _ => Err(RpcError::new(
+                        TORRENT_NOT_FOUND,
+                        "torrent not found or has no stats yet",
+                    )),
+                }
+            }
+            "torrent.set_limits" => {
+                let params: TorrentLimitParams = parse_params(params)?;
+                engine.set_torrent_rate_limits(
+                    params.id.into(),
+                    RateLimitConf {
+                        download: params.download,
+                        upload: params.upload,
+                    },
+                )?;
+                Ok(Value::Null)
+            }
+            "session.set_limits" => {
+                let params: LimitParams = parse_params(params)?;
+                engine.set_download_rate_limit(params.download)?;
+                engine.set_upload_rate_limit(params.upload)?;
+                Ok(Value::Null)
+            }
+            "session.shutdown" => {
+                // the daemon may only be gone if the engine is too
+                let _ = self.shutdown_tx.send(());
+                Ok(Value::Null)
+            }
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("unknown method {}", method),
+            )),
+        }
+    }
+}
+
+/// The error of a failed JSON-RPC call.
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<Error> for RpcError {
+    fn from(e: Error) -> Self {
+        Self::new(ENGINE_ERROR, e)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct AddParams {
+    metainfo: Option<PathBuf>,
+    magnet: Option<String>,
+    download_dir: Option<PathBuf>,
+    #[serde(default)]
+    paused: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct RemoveParams {
+    id: u32,
+    #[serde(default)]
+    delete_files: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct IdParams {
+    id: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct TorrentLimitParams {
+    id: u32,
+    download: Option<u64>,
+    upload: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct LimitParams {
+    download: Option<u64>,
+    upload: Option<u64>,
+}
+
+fn parse_params<T: serde::de::DeserializeOwned>(
+    params: Value,
+) -> std::result::Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e))
+}
+
+fn error_response(id: Value, code: i64, message: impl ToString) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": code, "message": message.to_string() },
+        "id": id,
+    })
+}
+
+/// The stats of a torrent returned by the daemon.
+#[derive(Clone, Debug, Serialize)]
+#[serde(crate = "serde_crate")]
+struct StatsSummary {
+    status: &'static str,
+    is_errored: bool,
+    /// The completion of the selected files, between 0.0 and 1.0.
+    progress: f64,
+    /// The estimated time until the selected files are downloaded, if
+    /// anything is being downloaded.
+    eta_secs: Option<u64>,
+    download_rate: u64,
+    upload_rate: u64,
+    downloaded: u64,
+    uploaded: u64,
+    piece_count: usize,
+    complete_piece_count: usize,
+    peer_count: usize,
+}
+
+impl From<&TorrentStats> for StatsSummary {
+    fn from(stats: &TorrentStats) -> Self {
+        let payload = &stats.thruput.payload;
+        Self {
+            status: match stats.status() {
+                Status::Paused => "paused",
+                Status::Queued => "queued",
+                Status::Downloading => "downloading",
+                Status::Seeding => "seeding",
+            },
+            is_errored: stats.is_errored,
+            progress: stats.progress(),
+            eta_secs: stats.eta().map(|eta| eta.as_secs()),
+            download_rate: payload.down.rate,
+            upload_rate: payload.up.rate,
+            downloaded: payload.down.total,
+            uploaded: payload.up.total,
+            piece_count: stats.pieces.total,
+            complete_piece_count: stats.pieces.complete,
+            peer_count: stats.peers.len(),
+        }
+    }
+}
+
+/// The parts of an HTTP request head that the daemon looks at.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Head {
+    is_post: bool,
+    /// The length of the head, including the blank line ending it.
+    len: usize,
+    content_len: usize,
+    host: Option<String>,
+    origin: Option<String>,
+    session_id: Option<String>,
+    authorization: Option<String>,
+}
+
+/// Reads an HTTP request and returns its head, and its body if it's a POST
+/// request.
+async fn read_request(
+    socket: &mut TcpStream,
+) -> std::io::Result<(Head, Vec<u8>)> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+    let head = loop {
+        let read_count = socket.read(&mut chunk).await?;
+        if read_count == 0 {
+            return Err(invalid_request("connection closed"));
+        }
+        buf.extend_from_slice(&chunk[..read_count]);
+        if let Some(head) = parse_head(&buf) {
+            break head;
+        }
+        if buf.len() > MAX_HEAD_LEN {
+            return Err(invalid_request("request head too long"));
+        }
+    };
+    if !head.is_post {
+        return Ok((head, Vec::new()));
+    }
+    if head.content_len > MAX_BODY_LEN {
+        return Err(invalid_request("request body too long"));
+    }
+    let mut body = buf.split_off(head.len);
+    if body.len() < head.content_len {
+        let start = body.len();
+        body.resize(head.content_len, 0);
+        socket.read_exact(&mut body[start..]).await?;
+    }
+    body.truncate(head.content_len);
+    Ok((head, body))
+}
+
+/// Parses the head of an HTTP request, once it's complete.
+fn parse_head(buf: &[u8]) -> Option<Head> {
+    let len = buf.windows(4).position(|w| w == b"\r\n\r\n")? + 4;
+    let text = String::from_utf8_lossy(&buf[..len]);
+    let mut lines = text.split("\r\n");
+    let mut head = Head {
+        is_post: lines.next()?.starts_with("POST "),
+        len,
+        ..Default::default()
+    };
+    for line in lines {
+        let colon = match line.find(':') {
+            Some(colon) => colon,
+            None => continue,
+        };
+        let name = &line[..colon];
+        let value = line[colon + 1..].trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            head.content_len = value.parse().unwrap_or(0);
+        } else if name.eq_ignore_ascii_case("host") {
+            head.host = Some(value.to_string());
+        } else if name.eq_ignore_ascii_case("origin") {
+            head.origin = Some(value.to_string());
+        } else if name.eq_ignore_ascii_case(SESSION_ID_HEADER) {
+            head.session_id = Some(value.to_string());
+        } else if name.eq_ignore_ascii_case("authorization") {
+            head.authorization = Some(value.to_string());
+        }
+    }
+    Some(head)
+}
+
+/// Returns whether the value of a `Host` header, with or without a port,
+/// names the local host.
+fn is_local_host(host: &str) -> bool {
+    let name = if let Some(rest) = host.strip_prefix('[') {
+        // an IPv6 address
+        match rest.find(']') {
+            Some(end) => &rest[..end],
+            None => return false,
+        }
+    } else {
+        host.split(':').next().unwrap_or(host)
+    };
+    name.eq_ignore_ascii_case("localhost")
+        || name.parse::<IpAddr>().map_or(false, |ip| ip.is_loopback())
+}
+
+/// Returns whether the value of an `Origin` header is on the local host.
+fn is_local_origin(origin: &str) -> bool {
+    match origin.find("://") {
+        Some(pos) => is_local_host(&origin[pos + 3..]),
+        // e.g. the opaque `null` origin
+        None => false,
+    }
+}
+
+/// Compares the bytes in time independent of where they first differ, so that
+/// the secret can't be guessed byte by byte from the response times.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn invalid_request(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_request_head() {
+        let req = b"POST /rpc HTTP/1.1\r\nHost: localhost:9091\r\n\
+            content-length: 12\r\nx-cratetorrent-session-id: abc\r\n\
+            Authorization: Bearer s3cret\r\n\r\n{\"id\":";
+        assert_eq!(
+            parse_head(req),
+            Some(Head {
+                is_post: true,
+                len: req.len() - 6,
+                content_len: 12,
+                host: Some("localhost:9091".into()),
+                origin: None,
+                session_id: Some("abc".into()),
+                authorization: Some("Bearer s3cret".into()),
+            })
+        );
+        // the head is not complete yet
+        assert_eq!(parse_head(b"POST /rpc HTTP/1.1\r\nHost: x\r\n"), None);
+        let head = parse_head(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(!head.is_post);
+        assert_eq!(head.len, 18);
+    }
+
+    #[test]
+    fn should_only_accept_local_hosts_and_origins() {
+        assert!(is_local_host("localhost"));
+        assert!(is_local_host("LOCALHOST:9091"));
+        assert!(is_local_host("127.0.0.1:9091"));
+        assert!(is_local_host("[::1]:9091"));
+        assert!(!is_local_host("192.168.1.2:9091"));
+        assert!(!is_local_host("evil.example.com"));
+        assert!(!is_local_host("localhost.example.com:9091"));
+        assert!(!is_local_host("[::1"));
+
+        assert!(is_local_origin("http://localhost:8080"));
+        assert!(is_local_origin("https://127.0.0.1"));
+        assert!(!is_local_origin("https://evil.example.com"));
+        assert!(!is_local_origin("null"));
+    }
+
+    /// Tests that requests are rejected unless they come from the local host,
+    /// with the session id and the secret, if set.
+    #[test]
+    fn should_check_requests() {
+        let (shutdown_tx, _shutdown_rx) = mpsc::unbounded_channel();
+        let mut ctx = Context {
+            session_id: "abc".into(),
+            secret: None,
+            is_remote: false,
+            state: Mutex::new(State {
+                engine: None,
+                torrents: BTreeMap::new(),
+                shutdown_tx,
+            }),
+        };
+        let head = Head {
+            is_post: true,
+            host: Some("127.0.0.1:9091".into()),
+            session_id: Some("abc".into()),
+            ..Default::default()
+        };
+        let status = |ctx: &Context, head: &Head| match ctx.check(head) {
+            Ok(()) => "200 OK",
+            Err(response) => response.status,
+        };
+        assert_eq!(status(&ctx, &head), "200 OK");
+
+        let foreign_host = Head {
+            host: Some("rebound.example.com:9091".into()),
+            ..head.clone()
+        };
+        assert_eq!(status(&ctx, &foreign_host), "403 Forbidden");
+        let no_host = Head {
+            host: None,
+            ..head.clone()
+        };
+        assert_eq!(status(&ctx, &no_host), "403 Forbidden");
+        let foreign_origin = Head {
+            origin: Some("https://evil.example.com".into()),
+            ..head.clone()
+        };
+        assert_eq!(status(&ctx, &foreign_origin), "403 Forbidden");
+
+        // the client is told the session id to retry with
+        let no_session_id = Head {
+            session_id: None,
+            ..head.clone()
+        };
+        match ctx.check(&no_session_id) {
+            Err(response) => {
+                assert_eq!(response.status, "409 Conflict");
+                assert_eq!(
+                    response.headers,
+                    "X-Cratetorrent-Session-Id: abc\r\n"
+                );
+            }
+            Ok(()) => panic!("request without session id accepted"),
+        }
+
+        ctx.secret = Some("s3cret".into());
+        assert_eq!(status(&ctx, &head), "401 Unauthorized");
+        let wrong_secret = Head {
+            authorization: Some("Bearer s3cre".into()),
+            ..head.clone()
+        };
+        assert_eq!(status(&ctx, &wrong_secret), "401 Unauthorized");
+        let authorized = Head {
+            authorization: Some("Bearer s3cret".into()),
+            ..head.clone()
+        };
+        assert_eq!(status(&ctx, &authorized), "200 OK");
+
+        // a remote daemon accepts authorized requests for any host
+        ctx.is_remote = true;
+        let remote = Head {
+            host: Some("seedbox.example.com:9091".into()),
+            origin: Some("https://ui.example.com".into()),
+            ..authorized.clone()
+        };
+        assert_eq!(status(&ctx, &remote), "200 OK");
+        let unauthorized = Head {
+            authorization: None,
+            ..remote.clone()
+        };
+        assert_eq!(status(&ctx, &unauthorized), "401 Unauthorized");
+    }
+
+    /// Tests that only a remote daemon, which has a secret, can be bound to
+    /// a non-loopback address.
+    #[tokio::test]
+    async fn should_require_secret_for_remote_daemon() {
+        let any_addr = "0.0.0.0:0".parse().unwrap();
+        assert!(Daemon::bind(any_addr).await.is_err());
+        assert!(Daemon::bind_remote(any_addr, "").await.is_err());
+        let daemon = Daemon::bind_remote(any_addr, "s3cret").await.unwrap();
+        assert!(daemon.is_remote);
+        assert_eq!(daemon.secret.as_deref(), Some("s3cret"));
+
+        let daemon =
+            Daemon::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        assert!(!daemon.is_remote);
+    }
+
+    #[test]
+    fn should_build_error_response() {
+        assert_eq!(
+            error_response(json!(1), METHOD_NOT_FOUND, "unknown method x"),
+            json!({
+                "jsonrpc": "2.0",
+                "error": { "code": -32601, "message": "unknown method x" },
+                "id": 1,
+            })
+        );
+    }
+}
@@ -0,0 +1,1446 @@
+//! The Mainline DHT, as specified in
+//! [BEP 5](https://www.bittorrent.org/beps/bep_0005.html).
+//!
+//! The DHT is a distributed hash table spanning the nodes of all BitTorrent
+//! clients that support it, in which the peers of a torrent are stored at the
+//! nodes whose ids are closest to its info hash. This lets torrents find peers
+//! without trackers.
+//!
+//! The engine runs a single DHT node on its own task, which joins the DHT by
+//! looking up its own id through the configured bootstrap nodes, and then
+//! periodically looks up the peers of each of the engine's public torrents,
+//! announcing them to the nodes closest to their info hash. The peers found are
+//! sent to the torrents, which connect to them as they would to peers returned
+//! by trackers. The node also answers the queries of other nodes, storing the
+//! peers announced to it.
+//!
+//! Applications may also store and look up arbitrary small items in the DHT,
+//! as specified in [BEP 44](https://www.bittorrent.org/beps/bep_0044.html).
+//! See [`storage`].
+//!
+//! Indexers may sample the info hashes that other nodes store peers of, as
+//! specified in [BEP 51](https://www.bittorrent.org/beps/bep_0051.html), and
+//! our node answers such queries in turn.
+//!
+//! Only IPv4 is supported.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use futures::{
+    select,
+    stream::{Fuse, SplitSink, SplitStream, StreamExt},
+    SinkExt,
+};
+use rand::seq::IteratorRandom;
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use tokio::{
+    net::{self, UdpSocket},
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+};
+use tokio_util::{codec::BytesCodec, udp::UdpFramed};
+use tracing::Instrument;
+
+use crate::{
+    alert::{Alert, AlertSender},
+    conf::DhtConf,
+    counter::Counter,
+    error::*,
+    external_ip::{ExternalIp, Voter},
+    metrics, rt, torrent, Sha1Hash,
+};
+use lookup::Lookup;
+use msg::{Message, Query, Response};
+use routing::{RoutingTable, K};
+use stats::DhtStats;
+use storage::{Item, ItemStore};
+
+mod lookup;
+mod msg;
+mod routing;
+mod security;
+mod state;
+pub mod stats;
+pub mod storage;
+
+/// The 160 bit id of a DHT node, which is in the same key space as info
+/// hashes.
+pub(crate) type NodeId = [u8; 20];
+
+/// A query that isn't answered in this time is considered lost.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Routing table maintenance, such as pinging questionable nodes, runs this
+/// often.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The secret from which announce tokens are derived is changed this often.
+/// Tokens derived from the previous secret are still accepted, so a token is
+/// valid for up to twice this long.
+const TOKEN_ROTATION_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// The length of the announce tokens we hand out.
+const TOKEN_LEN: usize = 8;
+
+/// A peer announced to us is forgotten if it isn't announced again in this
+/// time.
+const PEER_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// The maximum number of torrents whose peers we store for other nodes.
+const MAX_STORED_TORRENT_COUNT: usize = 1000;
+
+/// The maximum number of peers we store per torrent.
+const MAX_STORED_PEER_COUNT: usize = 100;
+
+/// The maximum number of peers returned in a `get_peers` response, so that it
+/// fits in a single datagram.
+const MAX_RETURNED_PEER_COUNT: usize = 50;
+
+/// The maximum number of info hashes returned in a `sample_infohashes`
+/// response, so that it fits in a single datagram along with the closest
+/// nodes.
+const MAX_SAMPLE_COUNT: usize = 20;
+
+/// The sample of stored info hashes we return is refreshed this often, so
+/// that crawlers can't enumerate them faster by querying us repeatedly.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Spawns the DHT node on a new task, listening on the configured address.
+///
+/// Our node id is made to match our external address, as detected by the
+/// engine, once it's known.
+pub(crate) fn spawn(
+    conf: DhtConf,
+    external_ip: Arc<ExternalIp>,
+    alert_tx: AlertSender,
+) -> Result<(JoinHandle, Sender)> {
+    log::info!("Spawning DHT task on {}", conf.listen_addr);
+    let socket = std::net::UdpSocket::bind(conf.listen_addr)?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket)?;
+    let (socket_tx, socket_rx) =
+        UdpFramed::new(socket, BytesCodec::new()).split();
+
+    let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+    let mut dht = Dht::new(conf, external_ip, alert_tx, socket_tx, cmd_rx);
+    let join_handle = rt::spawn(
+        async move { dht.run(socket_rx).await }
+            .instrument(tracing::info_span!("dht")),
+    );
+    log::info!("Spawned DHT task");
+
+    Ok((join_handle, cmd_tx))
+}
+
+pub(crate) type JoinHandle = rt::JoinHandle<Result<()>>;
+
+/// The channel for sending commands to the DHT task.
+pub(crate) type Sender = UnboundedSender<Command>;
+/// The channel on which the DHT task listens for commands.
+type Receiver = UnboundedReceiver<Command>;
+
+/// The commands the DHT task can receive.
+#[derive(Debug)]
+pub(crate) enum Command {
+    /// Starts looking up the peers of the torrent, announcing it periodically
+    /// with the given port, and sending the peers found to the torrent.
+    ///
+    /// If the port is 0, the torrent is only looked up but not announced,
+    /// e.g. while its metadata is being fetched from a magnet link.
+    AddTorrent {
+        info_hash: Sha1Hash,
+        port: u16,
+        torrent_tx: torrent::Sender,
+    },
+    /// Looks up the peers of the torrent before its next periodic lookup, as
+    /// it's running low on them. This is ignored if the torrent was looked up
+    /// less than [`DhtConf::min_lookup_interval`] ago.
+    GetPeers { info_hash: Sha1Hash },
+    /// Looks up and announces the torrent right away, regardless of when it
+    /// was last looked up, as the user asked for it. This is ignored if the
+    /// torrent is already being looked up.
+    AnnounceTorrent { info_hash: Sha1Hash },
+    /// Stops looking up and announcing the torrent.
+    RemoveTorrent { info_hash: Sha1Hash },
+    /// Looks up the immutable item stored at the target, posting the result
+    /// as an alert.
+    GetImmutableItem { target: Sha1Hash },
+    /// Looks up the mutable item with the public key and salt, posting the
+    /// result as an alert.
+    GetMutableItem { public_key: [u8; 32], salt: Vec<u8> },
+    /// Stores the item at the nodes closest to its target, posting the
+    /// number of nodes it was stored at as an alert.
+    PutItem(Item),
+    /// Sends a `sample_infohashes` query to the node at the address, posting
+    /// its response as an alert.
+    SampleInfohashes { addr: SocketAddr, target: NodeId },
+    /// Pings the nodes, given as `host:port` pairs, adding them to the
+    /// routing table if they respond.
+    AddNodes(Vec<String>),
+    /// Posts the node's statistics as an alert.
+    QueryStats,
+    /// Shuts down the DHT task.
+    Shutdown,
+}
+
+/// The sending half of the node's socket.
+type SocketSender = SplitSink<UdpFramed<BytesCodec>, (Bytes, SocketAddr)>;
+/// The receiving half of the node's socket.
+type SocketReceiver = SplitStream<UdpFramed<BytesCodec>>;
+
+type TransactionId = u16;
+type LookupId = u64;
+
+/// A query we sent that awaits a response.
+struct Transaction {
+    addr: SocketAddr,
+    /// The id of the queried node, unless it's a bootstrap node.
+    node_id: Option<NodeId>,
+    kind: TransactionKind,
+    sent_time: Instant,
+}
+
+enum TransactionKind {
+    Ping,
+    Lookup(LookupId),
+    Announce,
+    Put,
+    SampleInfohashes,
+}
+
+#[derive(Debug)]
+enum LookupKind {
+    /// Joins the DHT by looking up our own id, filling our routing table.
+    Bootstrap,
+    /// Looks up the peers of the torrent whose info hash is the target.
+    GetPeers,
+    /// Looks up the immutable item whose value hashes to the target.
+    GetImmutableItem,
+    /// Looks up the mutable item with the public key and salt, which hash to
+    /// the target.
+    GetMutableItem { public_key: [u8; 32], salt: Vec<u8> },
+    /// Stores the item at the nodes closest to its target, which hand out
+    /// the tokens required to do so in response to `get` queries.
+    PutItem(Item),
+}
+
+struct ActiveLookup {
+    lookup: Lookup,
+    kind: LookupKind,
+    /// The item found by an item lookup. Of mutable items, the one with the
+    /// highest sequence number is kept.
+    item: Option<Item>,
+}
+
+/// A torrent that is looked up and announced in the DHT.
+struct TorrentEntry {
+    /// The port announced for the torrent.
+    port: u16,
+    /// The peers found are sent to the torrent on this channel.
+    torrent_tx: torrent::Sender,
+    /// The time the last lookup of the torrent was started.
+    last_announce_time: Option<Instant>,
+    /// The torrent's lookup in progress, if any.
+    lookup_id: Option<LookupId>,
+}
+
+struct Dht {
+    /// Our node id, which is chosen randomly, unless it's restored from the
+    /// saved state. Once our external address is known, it's derived from
+    /// it.
+    id: NodeId,
+    socket_tx: SocketSender,
+    routing_table: RoutingTable,
+    /// The queries awaiting a response.
+    transactions: HashMap<TransactionId, Transaction>,
+    next_transaction_id: TransactionId,
+    /// The lookups in progress.
+    lookups: HashMap<LookupId, ActiveLookup>,
+    next_lookup_id: LookupId,
+    /// The torrents of the engine, by info hash.
+    torrents: HashMap<Sha1Hash, TorrentEntry>,
+    /// The peers other nodes announced to us, and the times they were last
+    /// announced.
+    peer_store: HashMap<Sha1Hash, Vec<(SocketAddr, Instant)>>,
+    /// The items other nodes stored at our node.
+    item_store: ItemStore,
+    /// The info hashes of the peer store that we return to
+    /// `sample_infohashes` queries, and the time they were sampled.
+    info_hash_sample: Option<(Vec<Sha1Hash>, Instant)>,
+    /// The current and the previous secret from which announce tokens are
+    /// derived.
+    token_secrets: [[u8; 20]; 2],
+    last_token_rotation_time: Instant,
+    last_maintenance_time: Instant,
+    /// Our external address, which our id must match, and to which the
+    /// nodes we query contribute their view of it.
+    external_ip: Arc<ExternalIp>,
+    /// Counts the queries received from other nodes.
+    incoming_query_counter: Counter,
+    alert_tx: AlertSender,
+    cmd_rx: Fuse<Receiver>,
+    conf: DhtConf,
+}
+
+impl Dht {
+    fn new(
+        conf: DhtConf,
+        external_ip: Arc<ExternalIp>,
+        alert_tx: AlertSender,
+        socket_tx: SocketSender,
+        cmd_rx: Receiver,
+    ) -> Self {
+        let now = Instant::now();
+        let state = match &conf.state_path {
+            Some(path) => match state::load(path, now) {
+                Ok(state) => Some(state),
+                // there is no saved state on the first run
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => {
+                    log::warn!(
+                        "Error loading DHT state from {:?}: {}",
+                        path,
+                        e
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+        let id = state.as_ref().map_or_else(rand::random, |state| state.id);
+        let mut routing_table = RoutingTable::new(id);
+        if let Some(state) = state {
+            log::info!("Restoring {} DHT node(s)", state.nodes.len());
+            for (node_id, addr, last_seen) in state.nodes {
+                routing_table.insert(node_id, addr, last_seen);
+            }
+        }
+
+        Self {
+            id,
+            socket_tx,
+            routing_table,
+            transactions: HashMap::new(),
+            next_transaction_id: rand::random(),
+            lookups: HashMap::new(),
+            next_lookup_id: 0,
+            torrents: HashMap::new(),
+            peer_store: HashMap::new(),
+            item_store: ItemStore::default(),
+            info_hash_sample: None,
+            token_secrets: [rand::random(), rand::random()],
+            last_token_rotation_time: now,
+            last_maintenance_time: now,
+            external_ip,
+            incoming_query_counter: Counter::default(),
+            alert_tx,
+            cmd_rx: cmd_rx.fuse(),
+            conf,
+        }
+    }
+
+    /// Runs the node until it's shut down.
+    async fn run(&mut self, socket_rx: SocketReceiver) -> Result<()> {
+        log::info!("Starting DHT node {}", hex::encode(&self.id));
+        let mut tick_timer = rt::interval(Duration::from_secs(1)).fuse();
+        let mut socket_rx = socket_rx.fuse();
+
+        self.bootstrap(Instant::now()).await;
+
+        loop {
+            select! {
+                tick_time = tick_timer.select_next_some() => {
+                    self.tick(tick_time).await;
+                }
+                msg = socket_rx.select_next_some() => match msg {
+                    Ok((buf, addr)) => {
+                        self.handle_message(&buf, addr, Instant::now()).await;
+                    }
+                    Err(e) => {
+                        log::debug!("Error receiving DHT message: {}", e);
+                    }
+                },
+                cmd = self.cmd_rx.select_next_some() => match cmd {
+                    Command::AddTorrent {
+                        info_hash,
+                        port,
+                        torrent_tx,
+                    } => {
+                        log::info!(
+                            "Adding torrent {} to DHT",
+                            hex::encode(&info_hash)
+                        );
+                        self.torrents.insert(
+                            info_hash,
+                            TorrentEntry {
+                                port,
+                                torrent_tx,
+                                last_announce_time: None,
+                                lookup_id: None,
+                            },
+                        );
+                        // look up the torrent right away rather than on the
+                        // next tick
+                        self.announce_torrents(Instant::now()).await;
+                    }
+                    Command::GetPeers { info_hash } => {
+                        self.request_peers(info_hash, Instant::now()).await;
+                    }
+                    Command::AnnounceTorrent { info_hash } => {
+                        self.force_announce(info_hash, Instant::now()).await;
+                    }
+                    Command::RemoveTorrent { info_hash } => {
+                        log::info!(
+                            "Removing torrent {} from DHT",
+                            hex::encode(&info_hash)
+                        );
+                        self.torrents.remove(&info_hash);
+                    }
+                    Command::GetImmutableItem { target } => {
+                        let kind = LookupKind::GetImmutableItem;
+                        self.lookup_item(target, kind, Instant::now()).await;
+                    }
+                    Command::GetMutableItem { public_key, salt } => {
+                        let target =
+                            storage::mutable_target(&public_key, &salt);
+                        let kind =
+                            LookupKind::GetMutableItem { public_key, salt };
+                        self.lookup_item(target, kind, Instant::now()).await;
+                    }
+                    Command::PutItem(item) => {
+                        let target = item.target();
+                        let kind = LookupKind::PutItem(item);
+                        self.lookup_item(target, kind, Instant::now()).await;
+                    }
+                    Command::SampleInfohashes { addr, target } => {
+                        self.send_query(
+                            addr,
+                            None,
+                            Query::SampleInfohashes { target },
+                            TransactionKind::SampleInfohashes,
+                            Instant::now(),
+                        )
+                        .await;
+                    }
+                    Command::AddNodes(nodes) => {
+                        self.add_nodes(nodes, Instant::now()).await;
+                    }
+                    Command::QueryStats => {
+                        let stats = self.build_stats(Instant::now());
+                        self.alert_tx
+                            .send(Alert::DhtStats(Box::new(stats)))
+                            .ok();
+                    }
+                    Command::Shutdown => {
+                        log::info!("Shutting down DHT node");
+                        break;
+                    }
+                },
+            }
+        }
+
+        if let Some(path) = &self.conf.state_path {
+            // the state is small, so it's fine to block the executor briefly
+            // while writing it on shutdown
+            log::info!("Saving DHT state to {:?}", path);
+            if let Err(e) =
+                state::save(path, &self.id, &self.routing_table, Instant::now())
+            {
+                log::warn!("Error saving DHT state to {:?}: {}", path, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Performs the periodic tasks of the node: timing out queries, looking
+    /// up torrents that are due, and maintaining the routing table.
+    async fn tick(&mut self, now: Instant) {
+        let timed_out: Vec<_> = self
+            .transactions
+            .iter()
+            .filter(|(_, t)| {
+                now.saturating_duration_since(t.sent_time) >= QUERY_TIMEOUT
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in timed_out {
+            if let Some(transaction) = self.transactions.remove(&id) {
+                log::trace!("DHT query to {} timed out", transaction.addr);
+                if let Some(node_id) = &transaction.node_id {
+                    self.routing_table.mark_failed(node_id);
+                }
+                self.handle_failure(transaction, now).await;
+            }
+        }
+
+        // our external address may have only just been detected, or it may
+        // have changed, in which case our id no longer matches it
+        if let Some(ip) = self.external_ip.ipv4() {
+            if !security::is_valid_id(&self.id, ip.into()) {
+                self.change_id(security::generate_id(ip), now).await;
+            }
+        }
+
+        self.announce_torrents(now).await;
+        self.incoming_query_counter.reset();
+        metrics::set_dht_node_count(self.routing_table.len());
+
+        if now.saturating_duration_since(self.last_maintenance_time)
+            >= MAINTENANCE_INTERVAL
+        {
+            self.last_maintenance_time = now;
+            self.maintain(now).await;
+        }
+    }
+
+    fn build_stats(&self, now: Instant) -> DhtStats {
+        DhtStats {
+            node_id: self.id,
+            node_count: self.routing_table.len(),
+            buckets: self.routing_table.bucket_infos(now),
+            in_flight_query_count: self.transactions.len(),
+            lookup_count: self.lookups.len(),
+            torrent_count: self.torrents.len(),
+            stored_torrent_count: self.peer_store.len(),
+            stored_peer_count: self.peer_store.values().map(Vec::len).sum(),
+            stored_item_count: self.item_store.len(),
+            incoming_query_rate: self.incoming_query_counter.avg(),
+            incoming_query_count: self.incoming_query_counter.total(),
+        }
+    }
+
+    /// Changes our node id, keeping the nodes we know, and rejoins the DHT
+    /// so that the nodes close to our new id learn about us.
+    async fn change_id(&mut self, id: NodeId, now: Instant) {
+        log::info!(
+            "Changing DHT node id from {} to {}",
+            hex::encode(&self.id),
+            hex::encode(&id)
+        );
+        self.id = id;
+        self.routing_table.set_own_id(id);
+        if !self.is_bootstrapping() {
+            self.bootstrap(now).await;
+        }
+    }
+
+    /// Rotates the token secret, forgets stale peers and items, pings
+    /// questionable
+    /// nodes, and rejoins the DHT if we've lost most of our nodes.
+    async fn maintain(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.last_token_rotation_time)
+            >= TOKEN_ROTATION_INTERVAL
+        {
+            self.last_token_rotation_time = now;
+            self.token_secrets[1] = self.token_secrets[0];
+            self.token_secrets[0] = rand::random();
+        }
+
+        for peers in self.peer_store.values_mut() {
+            peers.retain(|(_, announce_time)| {
+                now.saturating_duration_since(*announce_time) < PEER_TIMEOUT
+            });
+        }
+        self.peer_store.retain(|_, peers| !peers.is_empty());
+        self.item_store.expire(now);
+
+        for node in self.routing_table.questionable_nodes(now) {
+            self.send_query(
+                node.addr,
+                Some(node.id),
+                Query::Ping,
+                TransactionKind::Ping,
+                now,
+            )
+            .await;
+        }
+
+        if self.routing_table.len() < K && !self.is_bootstrapping() {
+            self.bootstrap(now).await;
+        }
+
+        log::debug!(
+            "DHT routing table has {} nodes, storing peers of {} torrents",
+            self.routing_table.len(),
+            self.peer_store.len()
+        );
+    }
+
+    /// Joins the DHT by looking up our own id, starting from the bootstrap
+    /// nodes and the nodes we already know.
+    async fn bootstrap(&mut self, now: Instant) {
+        // if we know enough nodes, e.g. because they were restored from the
+        // saved state, they are enough to join the DHT and the bootstrap
+        // nodes are spared the load
+        let addrs = if self.routing_table.len() >= K {
+            Vec::new()
+        } else {
+            resolve_nodes(&self.conf.bootstrap_nodes).await
+        };
+        self.bootstrap_from(addrs, now).await;
+    }
+
+    /// Joins the DHT by looking up our own id, starting from the nodes at the
+    /// given addresses, whose ids we don't know, and the nodes we already
+    /// know.
+    async fn bootstrap_from(&mut self, addrs: Vec<SocketAddr>, now: Instant) {
+        log::info!("Bootstrapping DHT from {} node(s)", addrs.len());
+
+        let nodes = self
+            .routing_table
+            .closest(&self.id, K)
+            .into_iter()
+            .map(|node| (node.id, node.addr))
+            .collect();
+        let mut lookup = Lookup::new(self.id, nodes);
+        for _ in addrs.iter() {
+            lookup.add_in_flight();
+        }
+        let lookup_id = self.start_lookup(lookup, LookupKind::Bootstrap);
+        // the ids of the bootstrap nodes are not known, so they are not
+        // candidates of the lookup, only the nodes they return
+        for addr in addrs {
+            self.send_query(
+                addr,
+                None,
+                Query::FindNode { target: self.id },
+                TransactionKind::Lookup(lookup_id),
+                now,
+            )
+            .await;
+        }
+        self.step_lookup(lookup_id, now).await;
+    }
+
+    /// Returns whether we're joining the DHT.
+    fn is_bootstrapping(&self) -> bool {
+        self.lookups
+            .values()
+            .any(|l| matches!(l.kind, LookupKind::Bootstrap))
+    }
+
+    /// Adds the nodes given by the user or a torrent's metainfo.
+    ///
+    /// While we know few nodes, they are used to join the DHT. Otherwise they
+    /// are just pinged, which adds them to the routing table if they respond.
+    async fn add_nodes(&mut self, nodes: Vec<String>, now: Instant) {
+        let addrs = resolve_nodes(&nodes).await;
+        log::info!("Adding {} DHT node(s)", addrs.len());
+        if self.routing_table.len() < K && !self.is_bootstrapping() {
+            self.bootstrap_from(addrs, now).await;
+        } else {
+            for addr in addrs {
+                self.send_query(
+                    addr,
+                    None,
+                    Query::Ping,
+                    TransactionKind::Ping,
+                    now,
+                )
+                .await;
+            }
+        }
+    }
+
+    /// Starts the lookups of the torrents that are due to be announced.
+    async fn announce_torrents(&mut self, now: Instant) {
+        if self.routing_table.len() == 0 {
+            return;
+        }
+        let announce_interval = self.conf.announce_interval;
+        let due: Vec<_> = self
+            .torrents
+            .iter()
+            .filter(|(_, torrent)| {
+                torrent.lookup_id.is_none()
+                    && torrent.last_announce_time.map_or(true, |t| {
+                        now.saturating_duration_since(t) >= announce_interval
+                    })
+            })
+            .map(|(info_hash, _)| *info_hash)
+            .collect();
+        for info_hash in due {
+            self.lookup_torrent(info_hash, now).await;
+        }
+    }
+
+    /// Looks up the torrent right away, as it's running low on peers, unless
+    /// it's already being looked up or was looked up recently.
+    ///
+    /// This counts as the torrent's periodic lookup, so its next one is
+    /// scheduled from now.
+    async fn request_peers(&mut self, info_hash: Sha1Hash, now: Instant) {
+        if self.routing_table.len() == 0 {
+            return;
+        }
+        let min_lookup_interval = self.conf.min_lookup_interval;
+        let is_allowed = match self.torrents.get(&info_hash) {
+            Some(torrent) => {
+                torrent.lookup_id.is_none()
+                    && torrent.last_announce_time.map_or(true, |t| {
+                        now.saturating_duration_since(t) >= min_lookup_interval
+                    })
+            }
+            None => false,
+        };
+        if is_allowed {
+            self.lookup_torrent(info_hash, now).await;
+        }
+    }
+
+    /// Looks up the torrent right away, unless it's already being looked up,
+    /// regardless of the minimum lookup interval.
+    ///
+    /// As with [`Self::request_peers`], this counts as the torrent's periodic
+    /// lookup.
+    async fn force_announce(&mut self, info_hash: Sha1Hash, now: Instant) {
+        if self.routing_table.len() == 0 {
+            log::info!("Cannot announce torrent, no DHT nodes known");
+            return;
+        }
+        let is_idle = self
+            .torrents
+            .get(&info_hash)
+            .map_or(false, |torrent| torrent.lookup_id.is_none());
+        if is_idle {
+            self.lookup_torrent(info_hash, now).await;
+        }
+    }
+
+    /// Starts the lookup of the torrent's peers, at the end of which the
+    /// torrent is announced.
+    async fn lookup_torrent(&mut self, info_hash: Sha1Hash, now: Instant) {
+        log::debug!("Looking up torrent {} in DHT", hex::encode(&info_hash));
+        let nodes = self
+            .routing_table
+            .closest(&info_hash, K)
+            .into_iter()
+            .map(|node| (node.id, node.addr))
+            .collect();
+        let lookup = Lookup::new(info_hash, nodes);
+        let lookup_id = self.start_lookup(lookup, LookupKind::GetPeers);
+        if let Some(torrent) = self.torrents.get_mut(&info_hash) {
+            torrent.last_announce_time = Some(now);
+            torrent.lookup_id = Some(lookup_id);
+        }
+        self.step_lookup(lookup_id, now).await;
+    }
+
+    /// Starts the lookup of the item at the target, which either gets the
+    /// item or stores it at the closest nodes, depending on the lookup kind.
+    ///
+    /// If we know no nodes, the lookup finishes right away, without an item.
+    async fn lookup_item(
+        &mut self,
+        target: Sha1Hash,
+        kind: LookupKind,
+        now: Instant,
+    ) {
+        log::debug!("Looking up item {} in DHT", hex::encode(&target));
+        let nodes = self
+            .routing_table
+            .closest(&target, K)
+            .into_iter()
+            .map(|node| (node.id, node.addr))
+            .collect();
+        let lookup_id = self.start_lookup(Lookup::new(target, nodes), kind);
+        self.step_lookup(lookup_id, now).await;
+    }
+
+    fn start_lookup(&mut self, lookup: Lookup, kind: LookupKind) -> LookupId {
+        let lookup_id = self.next_lookup_id;
+        self.next_lookup_id += 1;
+        self.lookups.insert(
+            lookup_id,
+            ActiveLookup {
+                lookup,
+                kind,
+                item: None,
+            },
+        );
+        lookup_id
+    }
+
+    /// Sends the next queries of the lookup, or finishes it if it's done.
+    async fn step_lookup(&mut self, lookup_id: LookupId, now: Instant) {
+        let (queries, query, is_done) = match self.lookups.get_mut(&lookup_id) {
+            Some(active) => {
+                let target = *active.lookup.target();
+                let query = match active.kind {
+                    LookupKind::Bootstrap => Query::FindNode { target },
+                    LookupKind::GetPeers => {
+                        Query::GetPeers { info_hash: target }
+                    }
+                    LookupKind::GetImmutableItem
+                    | LookupKind::GetMutableItem { .. }
+                    | LookupKind::PutItem(_) => {
+                        Query::Get { target, seq: None }
+                    }
+                };
+                let queries = active.lookup.next_queries();
+                (queries, query, active.lookup.is_done())
+            }
+            None => return,
+        };
+        for (node_id, addr) in queries {
+            self.send_query(
+                addr,
+                Some(node_id),
+                query.clone(),
+                TransactionKind::Lookup(lookup_id),
+                now,
+            )
+            .await;
+        }
+        if is_done {
+            self.finish_lookup(lookup_id, now).await;
+        }
+    }
+
+    /// Removes the finished lookup, announcing the torrent to the closest
+    /// nodes if it was a torrent lookup, storing the item if it was a put,
+    /// and posting the item found if it was a get.
+    async fn finish_lookup(&mut self, lookup_id: LookupId, now: Instant) {
+        let active = match self.lookups.remove(&lookup_id) {
+            Some(active) => active,
+            None => return,
+        };
+        match active.kind {
+            LookupKind::Bootstrap => {
+                log::info!(
+                    "DHT bootstrapped with {} nodes",
+                    self.routing_table.len()
+                );
+            }
+            LookupKind::GetPeers => {
+                let info_hash = *active.lookup.target();
+                let port = match self.torrents.get_mut(&info_hash) {
+                    Some(torrent) => {
+                        torrent.lookup_id = None;
+                        torrent.port
+                    }
+                    // the torrent was removed during the lookup
+                    None => return,
+                };
+                // the torrent is only looked up, as it can't accept
+                // connections yet
+                if port == 0 {
+                    return;
+                }
+                let targets = active.lookup.announce_targets();
+                log::debug!(
+                    "Announcing torrent {} to {} DHT node(s)",
+                    hex::encode(&info_hash),
+                    targets.len()
+                );
+                for (node_id, addr, token) in targets {
+                    self.send_query(
+                        addr,
+                        Some(node_id),
+                        Query::AnnouncePeer {
+                            info_hash,
+                            port,
+                            implied_port: false,
+                            token,
+                        },
+                        TransactionKind::Announce,
+                        now,
+                    )
+                    .await;
+                }
+            }
+            LookupKind::GetImmutableItem => {
+                let value = match active.item {
+                    Some(Item::Immutable(value)) => Some(value),
+                    _ => None,
+                };
+                self.alert_tx
+                    .send(Alert::DhtImmutableItem {
+                        target: *active.lookup.target(),
+                        value,
+                    })
+                    .ok();
+            }
+            LookupKind::GetMutableItem { public_key, salt } => {
+                let item = match active.item {
+                    Some(Item::Mutable(item)) => Some(Box::new(item)),
+                    _ => None,
+                };
+                self.alert_tx
+                    .send(Alert::DhtMutableItem {
+                        public_key,
+                        salt,
+                        item,
+                    })
+                    .ok();
+            }
+            LookupKind::PutItem(item) => {
+                let target = *active.lookup.target();
+                let targets = active.lookup.announce_targets();
+                log::debug!(
+                    "Storing item {} at {} DHT node(s)",
+                    hex::encode(&target),
+                    targets.len()
+                );
+                let node_count = targets.len();
+                for (node_id, addr, token) in targets {
+                    self.send_query(
+                        addr,
+                        Some(node_id),
+                        Query::Put {
+                            item: item.clone(),
+                            token,
+                            cas: None,
+                        },
+                        TransactionKind::Put,
+                        now,
+                    )
+                    .await;
+                }
+                self.alert_tx
+                    .send(Alert::DhtItemPut { target, node_count })
+                    .ok();
+            }
+        }
+    }
+
+    async fn handle_message(
+        &mut self,
+        buf: &[u8],
+        addr: SocketAddr,
+        now: Instant,
+    ) {
+        let msg = match Message::decode(buf) {
+            Ok(msg) => msg,
+            Err(e) => {
+                log::trace!("Invalid DHT message from {}: {}", addr, e);
+                return;
+            }
+        };
+        if msg.is_query() {
+            self.incoming_query_counter += 1;
+            // a read-only node doesn't answer queries, so that it doesn't
+            // store anything for other nodes, which are told not to add it to
+            // their routing table
+            if !self.conf.read_only {
+                self.handle_query(msg, addr, now).await;
+            }
+        } else if msg.is_response() || msg.is_error() {
+            self.handle_response(msg, addr, now).await;
+        }
+    }
+
+    /// Answers the query of another node.
+    async fn handle_query(
+        &mut self,
+        msg: Message,
+        addr: SocketAddr,
+        now: Instant,
+    ) {
+        let (node_id, query) = match msg.parse_query() {
+            Ok(query) => query,
+            Err((code, message)) => {
+                log::trace!("Invalid DHT query from {}: {}", addr, message);
+                let error = Message::error(&msg.transaction_id, code, message);
+                self.send(&error, addr).await;
+                return;
+            }
+        };
+        log::trace!("DHT query from {}: {:?}", addr, query);
+        // read-only nodes can't be queried, so they don't belong in the
+        // routing table
+        if !msg.is_read_only() {
+            self.routing_table.touch(&node_id, addr, now);
+        }
+
+        let response = match query {
+            Query::Ping => Response::new(&self.id),
+            Query::FindNode { target } => Response {
+                nodes: Some(self.closest_nodes(&target)),
+                ..Response::new(&self.id)
+            },
+            Query::GetPeers { info_hash } => {
+                let peers: Vec<_> = self
+                    .peer_store
+                    .get(&info_hash)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|(peer, _)| msg::encode_peer(peer))
+                    .take(MAX_RETURNED_PEER_COUNT)
+                    .collect();
+                Response {
+                    nodes: Some(self.closest_nodes(&info_hash)),
+                    values: if peers.is_empty() { None } else { Some(peers) },
+                    token: Some(ByteBuf::from(
+                        self.token(addr.ip(), &self.token_secrets[0]),
+                    )),
+                    ..Response::new(&self.id)
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => {
+                if !self.is_valid_token(&token, addr.ip()) {
+                    let error = Message::error(
+                        &msg.transaction_id,
+                        msg::PROTOCOL_ERROR,
+                        "invalid token",
+                    );
+                    self.send(&error, addr).await;
+                    return;
+                }
+                let port = if implied_port { addr.port() } else { port };
+                self.store_peer(
+                    info_hash,
+                    SocketAddr::new(addr.ip(), port),
+                    now,
+                );
+                Response::new(&self.id)
+            }
+            Query::Get { target, seq } => {
+                let mut response = Response {
+                    nodes: Some(self.closest_nodes(&target)),
+                    token: Some(ByteBuf::from(
+                        self.token(addr.ip(), &self.token_secrets[0]),
+                    )),
+                    ..Response::new(&self.id)
+                };
+                // the querying node may already have the item
+                let item = self.item_store.get(&target).filter(|item| {
+                    match (item, seq) {
+                        (Item::Mutable(item), Some(seq)) => item.seq > seq,
+                        _ => true,
+                    }
+                });
+                if let Some(item) = item {
+                    response.set_item(item);
+                }
+                response
+            }
+            Query::Put { item, token, cas } => {
+                let result = if self.is_valid_token(&token, addr.ip()) {
+                    self.item_store.put(item, cas, now)
+                } else {
+                    Err((msg::PROTOCOL_ERROR, "invalid token"))
+                };
+                if let Err((code, message)) = result {
+                    log::trace!("Invalid DHT put from {}: {}", addr, message);
+                    let error =
+                        Message::error(&msg.transaction_id, code, message);
+                    self.send(&error, addr).await;
+                    return;
+                }
+                Response::new(&self.id)
+            }
+            Query::SampleInfohashes { target } => {
+                let samples = self.sample_info_hashes(now).concat();
+                Response {
+                    nodes: Some(self.closest_nodes(&target)),
+                    interval: Some(SAMPLE_INTERVAL.as_secs() as i64),
+                    num: Some(self.peer_store.len() as i64),
+                    samples: Some(ByteBuf::from(samples)),
+                    ..Response::new(&self.id)
+                }
+            }
+        };
+        let response = Message::response(&msg.transaction_id, response, &addr);
+        self.send(&response, addr).await;
+    }
+
+    /// Handles the response or error sent to one of our queries.
+    async fn handle_response(
+        &mut self,
+        msg: Message,
+        addr: SocketAddr,
+        now: Instant,
+    ) {
+        let transaction_id = match parse_transaction_id(&msg.transaction_id) {
+            Some(id) => id,
+            None => return,
+        };
+        // only accept the response from the queried address, so that other
+        // nodes can't answer in its stead
+        let transaction = match self.transactions.get(&transaction_id) {
+            Some(transaction) if transaction.addr == addr => self
+                .transactions
+                .remove(&transaction_id)
+                .expect("transaction should exist"),
+            _ => {
+                log::trace!("Unexpected DHT message from {}", addr);
+                return;
+            }
+        };
+
+        // read before the response is moved out of the message
+        let reported_addr = msg.reported_addr();
+        let response = match msg.response {
+            Some(response) if msg.is_response() => response,
+            _ => {
+                log::debug!("DHT error from {}: {:?}", addr, msg.error);
+                self.handle_failure(transaction, now).await;
+                return;
+            }
+        };
+        let node_id = match response.node_id() {
+            Some(node_id) => node_id,
+            None => {
+                log::debug!("Invalid DHT response from {}", addr);
+                self.handle_failure(transaction, now).await;
+                return;
+            }
+        };
+        if let Some(reported_addr) = reported_addr {
+            self.external_ip
+                .vote(Voter::DhtNode(addr.ip()), reported_addr.ip());
+        }
+        // a node whose id doesn't match its address still takes part in our
+        // lookups, but it isn't trusted enough to be added to our routing
+        // table, where it'd be returned to other nodes
+        if !self.conf.enforce_node_id
+            || security::is_valid_id(&node_id, addr.ip())
+        {
+            self.routing_table.insert(node_id, addr, now);
+        } else {
+            log::trace!("DHT node {} has an invalid id", addr);
+        }
+
+        if let TransactionKind::SampleInfohashes = transaction.kind {
+            let interval = response.interval.unwrap_or(0).max(0) as u64;
+            self.alert_tx
+                .send(Alert::DhtSampleInfohashes {
+                    addr,
+                    interval: Duration::from_secs(interval),
+                    num: response.num.unwrap_or(0).max(0) as usize,
+                    samples: response.samples(),
+                    nodes: response.nodes(),
+                })
+                .ok();
+            return;
+        }
+
+        if let TransactionKind::Lookup(lookup_id) = transaction.kind {
+            if let Some(active) = self.lookups.get_mut(&lookup_id) {
+                let peers = response.peers();
+                let item =
+                    response_item(&active.kind, &active.lookup, &response);
+                active.lookup.handle_response(
+                    addr,
+                    response.nodes(),
+                    response.token.map(ByteBuf::into_vec),
+                );
+                if !peers.is_empty() {
+                    if let Some(torrent) =
+                        self.torrents.get(active.lookup.target())
+                    {
+                        log::debug!(
+                            "Found {} peer(s) of torrent {} in DHT",
+                            peers.len(),
+                            hex::encode(active.lookup.target())
+                        );
+                        // the torrent may no longer be running
+                        torrent
+                            .torrent_tx
+                            .send(torrent::Command::DhtPeers(peers))
+                            .ok();
+                    }
+                }
+                // an immutable item can't change, so the first one found ends
+                // the lookup, while a mutable item may have newer versions at
+                // other nodes
+                let mut is_item_final = false;
+                if let Some(item) = item {
+                    is_item_final = matches!(item, Item::Immutable(_));
+                    let is_newer = match (&active.item, &item) {
+                        (Some(Item::Mutable(current)), Item::Mutable(new)) => {
+                            new.seq > current.seq
+                        }
+                        (None, _) => true,
+                        _ => false,
+                    };
+                    if is_newer {
+                        active.item = Some(item);
+                    }
+                }
+                if is_item_final {
+                    self.finish_lookup(lookup_id, now).await;
+                } else {
+                    self.step_lookup(lookup_id, now).await;
+                }
+            }
+        }
+    }
+
+    /// Handles a query that timed out or was answered with an error,
+    /// continuing its lookup with other nodes.
+    async fn handle_failure(&mut self, transaction: Transaction, now: Instant) {
+        if let TransactionKind::Lookup(lookup_id) = transaction.kind {
+            if let Some(active) = self.lookups.get_mut(&lookup_id) {
+                active.lookup.handle_failure(transaction.addr);
+                self.step_lookup(lookup_id, now).await;
+            }
+        }
+    }
+
+    /// Sends the query to the node at the given address.
+    async fn send_query(
+        &mut self,
+        addr: SocketAddr,
+        node_id: Option<NodeId>,
+        query: Query,
+        kind: TransactionKind,
+        now: Instant,
+    ) {
+        let transaction_id = self.next_transaction_id;
+        self.next_transaction_id = transaction_id.wrapping_add(1);
+        let mut msg =
+            Message::query(&transaction_id.to_be_bytes(), &self.id, query);
+        if self.conf.read_only {
+            msg.ro = Some(1);
+        }
+        self.transactions.insert(
+            transaction_id,
+            Transaction {
+                addr,
+                node_id,
+                kind,
+                sent_time: now,
+            },
+        );
+        self.send(&msg, addr).await;
+    }
+
+    async fn send(&mut self, msg: &Message, addr: SocketAddr) {
+        let buf = Bytes::from(msg.encode());
+        if let Err(e) = self.socket_tx.send((buf, addr)).await {
+            log::debug!("Error sending DHT message to {}: {}", addr, e);
+        }
+    }
+
+    /// Returns the nodes closest to the target in the compact node info
+    /// format.
+    fn closest_nodes(&self, target: &NodeId) -> ByteBuf {
+        let nodes: Vec<_> = self
+            .routing_table
+            .closest(target, K)
+            .into_iter()
+            .map(|node| (node.id, node.addr))
+            .collect();
+        ByteBuf::from(msg::encode_nodes(&nodes))
+    }
+
+    /// Remembers the peer announced to us, if we have room for it.
+    fn store_peer(
+        &mut self,
+        info_hash: Sha1Hash,
+        addr: SocketAddr,
+        now: Instant,
+    ) {
+        if !self.peer_store.contains_key(&info_hash)
+            && self.peer_store.len() >= MAX_STORED_TORRENT_COUNT
+        {
+            return;
+        }
+        let peers = self.peer_store.entry(info_hash).or_default();
+        if let Some(peer) = peers.iter_mut().find(|(peer, _)| *peer == addr) {
+            peer.1 = now;
+        } else if peers.len() < MAX_STORED_PEER_COUNT {
+            peers.push((addr, now));
+        }
+    }
+
+    /// Returns the sample of the info hashes we store peers of, picking a new
+    /// random one if the current one is older than [`SAMPLE_INTERVAL`].
+    fn sample_info_hashes(&mut self, now: Instant) -> &[Sha1Hash] {
+        let is_stale = match &self.info_hash_sample {
+            Some((_, sample_time)) => {
+                now.saturating_duration_since(*sample_time) >= SAMPLE_INTERVAL
+            }
+            None => true,
+        };
+        if is_stale {
+            let sample = self
+                .peer_store
+                .keys()
+                .copied()
+                .choose_multiple(&mut rand::thread_rng(), MAX_SAMPLE_COUNT);
+            self.info_hash_sample = Some((sample, now));
+        }
+        self.info_hash_sample
+            .as_ref()
+            .map(|(sample, _)| sample.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Returns the token with which the node at the given IP may announce to
+    /// us. Tying the token to the IP prevents nodes from announcing others.
+    fn token(&self, ip: IpAddr, secret: &[u8; 20]) -> Vec<u8> {
+        let mut buf = match ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        buf.extend_from_slice(secret);
+        Sha1::digest(&buf)[..TOKEN_LEN].to_vec()
+    }
+
+    fn is_valid_token(&self, token: &[u8], ip: IpAddr) -> bool {
+        self.token_secrets
+            .iter()
+            .any(|secret| self.token(ip, secret) == token)
+    }
+}
+
+/// Resolves the IPv4 addresses of the nodes given as `host:port` pairs.
+async fn resolve_nodes(nodes: &[String]) -> Vec<SocketAddr> {
+    let mut addrs = Vec::new();
+    for node in nodes.iter() {
+        match net::lookup_host(node.as_str()).await {
+            Ok(resolved) => addrs.extend(resolved.filter(|a| a.is_ipv4())),
+            Err(e) => {
+                log::warn!("Error resolving DHT node {}: {}", node, e);
+            }
+        }
+    }
+    addrs
+}
+
+/// Returns the item in the response to a `get` query of an item lookup, if
+/// it's the item looked up and, if mutable, is validly signed.
+fn response_item(
+    kind: &LookupKind,
+    lookup: &Lookup,
+    response: &Response,
+) -> Option<Item> {
+    let item = match kind {
+        LookupKind::GetImmutableItem => response.item(&[])?,
+        LookupKind::GetMutableItem { salt, .. } => response.item(salt)?,
+        _ => return None,
+    };
+    let is_valid = item.target() == *lookup.target()
+        && match &item {
+            Item::Immutable(_) => {
+                matches!(kind, LookupKind::GetImmutableItem)
+            }
+            Item::Mutable(item) => {
+                matches!(kind, LookupKind::GetMutableItem { .. })
+                    && item.is_signature_valid()
+            }
+        };
+    if is_valid {
+        Some(item)
+    } else {
+        None
+    }
+}
+
+/// Returns the XOR distance of the two ids. Distances compare as big endian
+/// numbers, which is how byte arrays are ordered.
+fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut distance = [0; 20];
+    for (d, (a, b)) in distance.iter_mut().zip(a.iter().zip(b.iter())) {
+        *d = a ^ b;
+    }
+    distance
+}
+
+/// Parses the transaction id of our queries, which is a 2 byte integer.
+fn parse_transaction_id(b: &[u8]) -> Option<TransactionId> {
+    if b.len() == 2 {
+        Some(u16::from_be_bytes([b[0], b[1]]))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    /// Tests that a read-only node flags its queries as such, and neither
+    /// answers other nodes' queries nor stores anything for them, as
+    /// specified in [BEP 43](https://www.bittorrent.org/beps/bep_0043.html).
+    #[tokio::test]
+    async fn should_only_send_queries_in_read_only_mode() {
+        let localhost = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+        let mut peer = UdpSocket::bind(localhost).await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+
+        let socket = std::net::UdpSocket::bind(localhost).unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let socket = UdpSocket::from_std(socket).unwrap();
+        let (socket_tx, socket_rx) =
+            UdpFramed::new(socket, BytesCodec::new()).split();
+        let conf = DhtConf {
+            listen_addr: addr,
+            bootstrap_nodes: vec![peer_addr.to_string()],
+            read_only: true,
+            ..Default::default()
+        };
+        let (alert_tx, mut alert_rx) = mpsc::unbounded_channel();
+        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        let external_ip = Arc::new(ExternalIp::new(alert_tx.clone()));
+        let mut dht = Dht::new(conf, external_ip, alert_tx, socket_tx, cmd_rx);
+        let join_handle = rt::spawn(async move { dht.run(socket_rx).await });
+
+        // the node joins the DHT through us
+        let mut buf = [0; 1500];
+        let (len, from) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(from, addr);
+        let msg = Message::decode(&buf[..len]).unwrap();
+        assert!(msg.is_query());
+        assert!(msg.is_read_only());
+
+        let info_hash = [1; 20];
+        let queries = vec![
+            Query::Ping,
+            Query::GetPeers { info_hash },
+            Query::AnnouncePeer {
+                info_hash,
+                port: 6881,
+                implied_port: false,
+                token: b"token".to_vec(),
+            },
+        ];
+        for (i, query) in queries.into_iter().enumerate() {
+            let msg = Message::query(&[0, i as u8], &[2; 20], query);
+            peer.send_to(&msg.encode(), &addr).await.unwrap();
+        }
+        // the node may query us again, but it doesn't respond
+        let recv = async {
+            loop {
+                let (len, _) = peer.recv_from(&mut buf).await.unwrap();
+                let msg = Message::decode(&buf[..len]).unwrap();
+                assert!(msg.is_query());
+                assert!(msg.is_read_only());
+            }
+        };
+        assert!(rt::timeout(Duration::from_millis(500), recv).await.is_err());
+
+        cmd_tx.send(Command::QueryStats).unwrap();
+        let stats = loop {
+            if let Alert::DhtStats(stats) = alert_rx.recv().await.unwrap() {
+                break stats;
+            }
+        };
+        assert_eq!(stats.stored_torrent_count, 0);
+        assert_eq!(stats.stored_peer_count, 0);
+        // nor is the querying node added to the routing table
+        assert_eq!(stats.node_count, 0);
+
+        cmd_tx.send(Command::Shutdown).unwrap();
+        join_handle.await.unwrap().unwrap();
+    }
+}
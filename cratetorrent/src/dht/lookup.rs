@@ -0,0 +1,238 @@
+//! Iterative Kademlia lookups.
+//!
+//! A lookup converges on the nodes closest to a target id by repeatedly
+//! querying the closest nodes it knows of that it hasn't queried yet, each of
+//! which returns nodes that are even closer to the target. At most [`ALPHA`]
+//! queries are in flight at a time, and the lookup is done when the [`K`]
+//! closest nodes that responded have all been queried.
+
+use std::net::SocketAddr;
+
+use super::{distance, routing::K, NodeId};
+
+/// The number of queries a lookup keeps in flight at once.
+pub(super) const ALPHA: usize = 3;
+
+/// The maximum number of candidates kept, so that a lookup isn't flooded
+/// with far away nodes.
+const MAX_CANDIDATE_COUNT: usize = K * 4;
+
+/// The state of a node found during a lookup.
+#[derive(Clone, Debug, PartialEq)]
+enum CandidateState {
+    /// The node has not been queried yet.
+    New,
+    /// A query to the node is in flight.
+    Queried,
+    /// The node responded, with a token if the query was a `get_peers`.
+    Responded { token: Option<Vec<u8>> },
+    /// The node failed to respond.
+    Failed,
+}
+
+#[derive(Clone, Debug)]
+struct Candidate {
+    id: NodeId,
+    addr: SocketAddr,
+    state: CandidateState,
+}
+
+pub(super) struct Lookup {
+    target: NodeId,
+    /// The nodes found so far, closest to the target first.
+    candidates: Vec<Candidate>,
+    /// The number of queries in flight, including those sent to nodes
+    /// without a known id, e.g. bootstrap nodes.
+    in_flight_count: usize,
+}
+
+impl Lookup {
+    /// Starts a lookup of the target id from the given nodes.
+    pub fn new(target: NodeId, nodes: Vec<(NodeId, SocketAddr)>) -> Self {
+        let mut lookup = Self {
+            target,
+            candidates: Vec::new(),
+            in_flight_count: 0,
+        };
+        lookup.add_nodes(nodes);
+        lookup
+    }
+
+    /// Returns the target of the lookup.
+    pub fn target(&self) -> &NodeId {
+        &self.target
+    }
+
+    /// Records a query sent to a node that is not a candidate, such as
+    /// a bootstrap node whose id we don't know.
+    pub fn add_in_flight(&mut self) {
+        self.in_flight_count += 1;
+    }
+
+    /// Returns the nodes that should be queried next, marking them as
+    /// queried, so that at most [`ALPHA`] queries are in flight.
+    pub fn next_queries(&mut self) -> Vec<(NodeId, SocketAddr)> {
+        let mut queries = Vec::new();
+        // only the closest nodes that haven't failed are queried, as the
+        // lookup converges on them
+        for candidate in self
+            .candidates
+            .iter_mut()
+            .filter(|c| c.state != CandidateState::Failed)
+            .take(K)
+        {
+            if self.in_flight_count >= ALPHA {
+                break;
+            }
+            if candidate.state == CandidateState::New {
+                candidate.state = CandidateState::Queried;
+                self.in_flight_count += 1;
+                queries.push((candidate.id, candidate.addr));
+            }
+        }
+        queries
+    }
+
+    /// Records the response of the node at the given address, which may have
+    /// returned nodes closer to the target and a token.
+    pub fn handle_response(
+        &mut self,
+        addr: SocketAddr,
+        nodes: Vec<(NodeId, SocketAddr)>,
+        token: Option<Vec<u8>>,
+    ) {
+        self.in_flight_count = self.in_flight_count.saturating_sub(1);
+        if let Some(candidate) =
+            self.candidates.iter_mut().find(|c| c.addr == addr)
+        {
+            candidate.state = CandidateState::Responded { token };
+        }
+        self.add_nodes(nodes);
+    }
+
+    /// Records that the node at the given address failed to respond.
+    pub fn handle_failure(&mut self, addr: SocketAddr) {
+        self.in_flight_count = self.in_flight_count.saturating_sub(1);
+        if let Some(candidate) =
+            self.candidates.iter_mut().find(|c| c.addr == addr)
+        {
+            candidate.state = CandidateState::Failed;
+        }
+    }
+
+    /// Returns whether the lookup has converged: no queries are in flight
+    /// and the closest nodes have all been queried.
+    pub fn is_done(&self) -> bool {
+        self.in_flight_count == 0
+            && self
+                .candidates
+                .iter()
+                .filter(|c| c.state != CandidateState::Failed)
+                .take(K)
+                .all(|c| c.state != CandidateState::New)
+    }
+
+    /// Returns the closest nodes that responded with a token, along with the
+    /// token, to which the target info hash can be announced.
+    pub fn announce_targets(&self) -> Vec<(NodeId, SocketAddr, Vec<u8>)> {
+        self.candidates
+            .iter()
+            .filter_map(|c| match &c.state {
+                CandidateState::Responded { token: Some(token) } => {
+                    Some((c.id, c.addr, token.clone()))
+                }
+                _ => None,
+            })
+            .take(K)
+            .collect()
+    }
+
+    /// Adds the nodes that are not yet candidates, keeping the candidates
+    /// sorted by their distance to the target.
+    fn add_nodes(&mut self, nodes: Vec<(NodeId, SocketAddr)>) {
+        for (id, addr) in nodes.into_iter() {
+            if self.candidates.iter().any(|c| c.id == id || c.addr == addr) {
+                continue;
+            }
+            self.candidates.push(Candidate {
+                id,
+                addr,
+                state: CandidateState::New,
+            });
+        }
+        let target = self.target;
+        self.candidates.sort_by_key(|c| distance(&c.id, &target));
+        // far away nodes that haven't been queried are dropped, but the
+        // state of queried ones must be kept to account for their responses
+        let mut count = 0;
+        self.candidates.retain(|c| {
+            count += 1;
+            count <= MAX_CANDIDATE_COUNT || c.state != CandidateState::New
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(i: u8) -> (NodeId, SocketAddr) {
+        let mut id = [0; 20];
+        id[0] = i;
+        (id, SocketAddr::from(([1, 2, 3, i], 6881)))
+    }
+
+    #[test]
+    fn should_query_closest_nodes_first() {
+        let mut lookup = Lookup::new([0; 20], (1..=10).map(node).collect());
+
+        let queries = lookup.next_queries();
+        assert_eq!(queries, vec![node(1), node(2), node(3)]);
+        // no more than ALPHA queries are in flight
+        assert!(lookup.next_queries().is_empty());
+
+        // a closer node is queried next
+        lookup.handle_response(node(1).1, vec![node(0x80), node(0)], None);
+        assert_eq!(lookup.next_queries(), vec![node(0)]);
+    }
+
+    #[test]
+    fn should_finish_when_closest_nodes_are_queried() {
+        let mut lookup = Lookup::new([0; 20], (1..=10).map(node).collect());
+        assert!(!lookup.is_done());
+
+        while !lookup.is_done() {
+            let queries = lookup.next_queries();
+            assert!(!queries.is_empty());
+            for (id, addr) in queries {
+                if id[0] == 2 {
+                    lookup.handle_failure(addr);
+                } else {
+                    lookup.handle_response(addr, Vec::new(), Some(vec![id[0]]));
+                }
+            }
+        }
+
+        // the failed node is replaced by the next closest one
+        let targets = lookup.announce_targets();
+        let targets: Vec<_> = targets.iter().map(|(id, _, _)| id[0]).collect();
+        assert_eq!(targets, vec![1, 3, 4, 5, 6, 7, 8, 9]);
+        assert!(lookup
+            .announce_targets()
+            .iter()
+            .all(|(id, _, token)| token == &vec![id[0]]));
+    }
+
+    #[test]
+    fn should_wait_for_bootstrap_nodes() {
+        let mut lookup = Lookup::new([0; 20], Vec::new());
+        lookup.add_in_flight();
+        assert!(!lookup.is_done());
+
+        let bootstrap_addr = SocketAddr::from(([9, 9, 9, 9], 6881));
+        lookup.handle_response(bootstrap_addr, vec![node(1)], None);
+        assert_eq!(lookup.next_queries(), vec![node(1)]);
+        lookup.handle_response(node(1).1, Vec::new(), None);
+        assert!(lookup.is_done());
+    }
+}
@@ -0,0 +1,717 @@
+//! The KRPC messages exchanged by DHT nodes, as specified in
+//! [BEP 5](https://www.bittorrent.org/beps/bep_0005.html).
+//!
+//! Each message is a bencoded dictionary sent in a single UDP datagram. It is
+//! either a query, a response to a query, or an error, and carries the
+//! transaction id of the query, which the querying node uses to match
+//! responses to queries.
+
+use std::net::{Ipv4Addr, SocketAddr};
+
+use bytes::{Buf, BufMut};
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+
+use super::{
+    storage::{Item, MutableItem},
+    NodeId,
+};
+use crate::Sha1Hash;
+
+/// The length of a node in the compact node info format: a 20 byte node id
+/// followed by a 4 byte IPv4 address and a 2 byte port.
+const COMPACT_NODE_LEN: usize = 26;
+
+/// The length of a peer in the compact peer info format: a 4 byte IPv4
+/// address followed by a 2 byte port.
+const COMPACT_PEER_LEN: usize = 6;
+
+/// The error code of malformed queries and invalid arguments.
+pub(super) const PROTOCOL_ERROR: i64 = 203;
+/// The error code of queries with an unknown method.
+pub(super) const METHOD_UNKNOWN: i64 = 204;
+
+/// A KRPC message as it is encoded on the wire.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "serde_crate")]
+pub(super) struct Message {
+    #[serde(rename = "t")]
+    pub transaction_id: ByteBuf,
+    /// The type of the message: "q" for queries, "r" for responses and "e"
+    /// for errors.
+    #[serde(rename = "y")]
+    pub kind: ByteBuf,
+    /// The method name of a query.
+    #[serde(default)]
+    #[serde(rename = "q")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub method: Option<ByteBuf>,
+    /// The arguments of a query.
+    #[serde(default)]
+    #[serde(rename = "a")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub args: Option<Args>,
+    /// The return values of a response.
+    #[serde(default)]
+    #[serde(rename = "r")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<Response>,
+    /// The error code and message of an error.
+    #[serde(default)]
+    #[serde(rename = "e")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<(i64, ByteBuf)>,
+    /// The address of the querying node as seen by the responding node, in
+    /// the compact peer info format, as specified in
+    /// [BEP 42](https://www.bittorrent.org/beps/bep_0042.html).
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<ByteBuf>,
+    /// Set to 1 in the queries of read-only nodes, which don't answer
+    /// queries, as specified in
+    /// [BEP 43](https://www.bittorrent.org/beps/bep_0043.html).
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ro: Option<u8>,
+}
+
+/// The arguments of all query types. Which ones are set depends on the
+/// method.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "serde_crate")]
+pub(super) struct Args {
+    /// The id of the querying node.
+    pub id: ByteBuf,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<ByteBuf>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub info_hash: Option<ByteBuf>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+    /// If set to 1, the peer's port is the source port of the query rather
+    /// than the `port` argument, which supports peers behind a NAT.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub implied_port: Option<u8>,
+    /// The value of a stored item.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub v: Option<Value>,
+    /// The public key of a mutable item.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k: Option<ByteBuf>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub salt: Option<ByteBuf>,
+    /// The sequence number of a mutable item. In a `get` query, the item is
+    /// only returned if its sequence number is higher.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<i64>,
+    /// The signature of a mutable item.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sig: Option<ByteBuf>,
+    /// If set, a mutable item is only stored if the sequence number of the
+    /// item it replaces is this.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cas: Option<i64>,
+}
+
+/// The return values of all response types. Which ones are set depends on
+/// the method of the query.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "serde_crate")]
+pub(super) struct Response {
+    /// The id of the responding node.
+    pub id: ByteBuf,
+    /// The nodes closest to the queried target in the compact node info
+    /// format.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<ByteBuf>,
+    /// The peers of the queried info hash in the compact peer info format.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<ByteBuf>>,
+    /// The token that must be sent with a subsequent announce to the node.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+    /// The value of the item stored at the queried target.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub v: Option<Value>,
+    /// The public key of the returned mutable item.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k: Option<ByteBuf>,
+    /// The sequence number of the returned mutable item.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<i64>,
+    /// The signature of the returned mutable item.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sig: Option<ByteBuf>,
+    /// The number of seconds for which the responding node returns the same
+    /// sample of info hashes.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval: Option<i64>,
+    /// The number of info hashes the responding node stores peers of.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num: Option<i64>,
+    /// A sample of the info hashes the responding node stores peers of, as
+    /// concatenated 20 byte hashes.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub samples: Option<ByteBuf>,
+}
+
+/// The queries we can send or receive.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: Sha1Hash,
+    },
+    AnnouncePeer {
+        info_hash: Sha1Hash,
+        port: u16,
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+    /// Gets the item stored at the target. A mutable item is only returned if
+    /// its sequence number is higher than `seq`, if set.
+    Get {
+        target: Sha1Hash,
+        seq: Option<i64>,
+    },
+    Put {
+        item: Item,
+        token: Vec<u8>,
+        cas: Option<i64>,
+    },
+    /// Gets a sample of the info hashes the node stores peers of, as
+    /// specified in [BEP 51](https://www.bittorrent.org/beps/bep_0051.html),
+    /// along with the nodes closest to the target.
+    SampleInfohashes {
+        target: NodeId,
+    },
+}
+
+impl Message {
+    /// Creates a query sent by the node with the given id.
+    pub fn query(transaction_id: &[u8], own_id: &NodeId, query: Query) -> Self {
+        let mut args = Args {
+            id: ByteBuf::from(own_id.to_vec()),
+            ..Default::default()
+        };
+        let method = match query {
+            Query::Ping => "ping",
+            Query::FindNode { target } => {
+                args.target = Some(ByteBuf::from(target.to_vec()));
+                "find_node"
+            }
+            Query::GetPeers { info_hash } => {
+                args.info_hash = Some(ByteBuf::from(info_hash.to_vec()));
+                "get_peers"
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => {
+                args.info_hash = Some(ByteBuf::from(info_hash.to_vec()));
+                args.port = Some(port);
+                args.implied_port = Some(implied_port as u8);
+                args.token = Some(ByteBuf::from(token));
+                "announce_peer"
+            }
+            Query::Get { target, seq } => {
+                args.target = Some(ByteBuf::from(target.to_vec()));
+                args.seq = seq;
+                "get"
+            }
+            Query::Put { item, token, cas } => {
+                args.token = Some(ByteBuf::from(token));
+                args.cas = cas;
+                args.v = decode_value(item.value());
+                if let Item::Mutable(item) = item {
+                    args.k = Some(ByteBuf::from(item.public_key.to_vec()));
+                    if !item.salt.is_empty() {
+                        args.salt = Some(ByteBuf::from(item.salt));
+                    }
+                    args.seq = Some(item.seq);
+                    args.sig = Some(ByteBuf::from(item.signature));
+                }
+                "put"
+            }
+            Query::SampleInfohashes { target } => {
+                args.target = Some(ByteBuf::from(target.to_vec()));
+                "sample_infohashes"
+            }
+        };
+        Self {
+            transaction_id: ByteBuf::from(transaction_id.to_vec()),
+            kind: ByteBuf::from(b"q".to_vec()),
+            method: Some(ByteBuf::from(method.as_bytes().to_vec())),
+            args: Some(args),
+            ..Default::default()
+        }
+    }
+
+    /// Creates a response to the query with the given transaction id, sent
+    /// by the node at the given address.
+    pub fn response(
+        transaction_id: &[u8],
+        response: Response,
+        querying_addr: &SocketAddr,
+    ) -> Self {
+        Self {
+            transaction_id: ByteBuf::from(transaction_id.to_vec()),
+            kind: ByteBuf::from(b"r".to_vec()),
+            response: Some(response),
+            ip: encode_peer(querying_addr),
+            ..Default::default()
+        }
+    }
+
+    /// Returns our address as seen by the responding node, if it reported
+    /// it.
+    pub fn reported_addr(&self) -> Option<SocketAddr> {
+        self.ip.as_ref().and_then(|ip| decode_peer(ip))
+    }
+
+    /// Creates an error response to the query with the given transaction id.
+    pub fn error(transaction_id: &[u8], code: i64, message: &str) -> Self {
+        Self {
+            transaction_id: ByteBuf::from(transaction_id.to_vec()),
+            kind: ByteBuf::from(b"e".to_vec()),
+            error: Some((code, ByteBuf::from(message.as_bytes().to_vec()))),
+            ..Default::default()
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        serde_bencode::to_bytes(self).expect("KRPC message should serialize")
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, serde_bencode::Error> {
+        serde_bencode::from_bytes(buf)
+    }
+
+    pub fn is_query(&self) -> bool {
+        self.kind.as_slice() == b"q"
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.kind.as_slice() == b"r"
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.kind.as_slice() == b"e"
+    }
+
+    /// Returns whether the query was sent by a read-only node.
+    pub fn is_read_only(&self) -> bool {
+        self.ro == Some(1)
+    }
+
+    /// Parses the querying node's id and the query, if this is a query.
+    ///
+    /// On failure, the error code and message with which the query should be
+    /// answered is returned.
+    pub fn parse_query(&self) -> Result<(NodeId, Query), (i64, &'static str)> {
+        let args = self
+            .args
+            .as_ref()
+            .ok_or((PROTOCOL_ERROR, "missing arguments"))?;
+        let id = parse_id(&args.id).ok_or((PROTOCOL_ERROR, "invalid id"))?;
+        let info_hash = || {
+            args.info_hash
+                .as_ref()
+                .and_then(|info_hash| parse_id(info_hash))
+                .ok_or((PROTOCOL_ERROR, "invalid info_hash"))
+        };
+        let token = || {
+            args.token
+                .as_ref()
+                .map(|token| token.to_vec())
+                .ok_or((PROTOCOL_ERROR, "missing token"))
+        };
+        let target = || {
+            args.target
+                .as_ref()
+                .and_then(|target| parse_id(target))
+                .ok_or((PROTOCOL_ERROR, "invalid target"))
+        };
+        let method = self
+            .method
+            .as_ref()
+            .ok_or((PROTOCOL_ERROR, "missing method"))?;
+        let query = match method.as_slice() {
+            b"ping" => Query::Ping,
+            b"find_node" => Query::FindNode { target: target()? },
+            b"get_peers" => Query::GetPeers {
+                info_hash: info_hash()?,
+            },
+            b"announce_peer" => Query::AnnouncePeer {
+                info_hash: info_hash()?,
+                port: args.port.ok_or((PROTOCOL_ERROR, "missing port"))?,
+                implied_port: args.implied_port == Some(1),
+                token: token()?,
+            },
+            b"get" => Query::Get {
+                target: target()?,
+                seq: args.seq,
+            },
+            b"put" => {
+                let value = args
+                    .v
+                    .as_ref()
+                    .map(encode_value)
+                    .ok_or((PROTOCOL_ERROR, "missing value"))?;
+                let item = match &args.k {
+                    Some(public_key) => Item::Mutable(MutableItem {
+                        public_key: parse_key(public_key)
+                            .ok_or((PROTOCOL_ERROR, "invalid key"))?,
+                        salt: args
+                            .salt
+                            .as_ref()
+                            .map(|salt| salt.to_vec())
+                            .unwrap_or_default(),
+                        seq: args.seq.ok_or((PROTOCOL_ERROR, "missing seq"))?,
+                        value,
+                        signature: args
+                            .sig
+                            .as_ref()
+                            .ok_or((PROTOCOL_ERROR, "missing signature"))?
+                            .to_vec(),
+                    }),
+                    None => Item::Immutable(value),
+                };
+                Query::Put {
+                    item,
+                    token: token()?,
+                    cas: args.cas,
+                }
+            }
+            b"sample_infohashes" => {
+                Query::SampleInfohashes { target: target()? }
+            }
+            _ => return Err((METHOD_UNKNOWN, "method unknown")),
+        };
+        Ok((id, query))
+    }
+}
+
+impl Response {
+    /// Creates a response with only the responding node's id.
+    pub fn new(own_id: &NodeId) -> Self {
+        Self {
+            id: ByteBuf::from(own_id.to_vec()),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the id of the responding node, if valid.
+    pub fn node_id(&self) -> Option<NodeId> {
+        parse_id(&self.id)
+    }
+
+    /// Returns the nodes in the response. Malformed entries are skipped.
+    pub fn nodes(&self) -> Vec<(NodeId, SocketAddr)> {
+        self.nodes
+            .as_ref()
+            .map(|nodes| decode_nodes(nodes))
+            .unwrap_or_default()
+    }
+
+    /// Returns the peers in the response. Malformed entries are skipped.
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.values
+            .iter()
+            .flatten()
+            .filter_map(|peer| decode_peer(peer))
+            .collect()
+    }
+
+    /// Returns the info hashes sampled in response to a `sample_infohashes`
+    /// query. A trailing partial hash is ignored.
+    pub fn samples(&self) -> Vec<Sha1Hash> {
+        self.samples
+            .iter()
+            .flat_map(|samples| samples.chunks_exact(20))
+            .filter_map(parse_id)
+            .collect()
+    }
+
+    /// Returns the item in the response to a `get` query, if any. The salt of
+    /// a mutable item is not sent back, so it must be given.
+    pub fn item(&self, salt: &[u8]) -> Option<Item> {
+        let value = encode_value(self.v.as_ref()?);
+        match &self.k {
+            Some(public_key) => Some(Item::Mutable(MutableItem {
+                public_key: parse_key(public_key)?,
+                salt: salt.to_vec(),
+                seq: self.seq?,
+                value,
+                signature: self.sig.as_ref()?.to_vec(),
+            })),
+            None => Some(Item::Immutable(value)),
+        }
+    }
+
+    /// Sets the item returned in response to a `get` query.
+    pub fn set_item(&mut self, item: &Item) {
+        self.v = decode_value(item.value());
+        if let Item::Mutable(item) = item {
+            self.k = Some(ByteBuf::from(item.public_key.to_vec()));
+            self.seq = Some(item.seq);
+            self.sig = Some(ByteBuf::from(item.signature.clone()));
+        }
+    }
+}
+
+/// Parses a 20 byte node id or info hash.
+pub(super) fn parse_id(b: &[u8]) -> Option<NodeId> {
+    if b.len() != 20 {
+        return None;
+    }
+    let mut id = [0; 20];
+    id.copy_from_slice(b);
+    Some(id)
+}
+
+/// Parses a 32 byte ed25519 public key.
+fn parse_key(b: &[u8]) -> Option<[u8; 32]> {
+    if b.len() != 32 {
+        return None;
+    }
+    let mut key = [0; 32];
+    key.copy_from_slice(b);
+    Some(key)
+}
+
+/// Bencodes the value of an item. Dictionary keys are sorted, so the encoding
+/// is canonical.
+fn encode_value(value: &Value) -> Vec<u8> {
+    serde_bencode::to_bytes(value).expect("bencode value should serialize")
+}
+
+/// Decodes the bencoded value of an item, so that it can be embedded in
+/// a message.
+fn decode_value(b: &[u8]) -> Option<Value> {
+    serde_bencode::from_bytes(b).ok()
+}
+
+/// Encodes the nodes in the compact node info format. Only IPv4 nodes can be
+/// encoded, others are skipped.
+pub(super) fn encode_nodes(nodes: &[(NodeId, SocketAddr)]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(nodes.len() * COMPACT_NODE_LEN);
+    for (id, addr) in nodes.iter() {
+        if let SocketAddr::V4(addr) = addr {
+            buf.extend_from_slice(id);
+            buf.put_u32(u32::from(*addr.ip()));
+            buf.put_u16(addr.port());
+        }
+    }
+    buf
+}
+
+/// Decodes nodes in the compact node info format, skipping a trailing
+/// partial entry.
+pub(super) fn decode_nodes(b: &[u8]) -> Vec<(NodeId, SocketAddr)> {
+    b.chunks_exact(COMPACT_NODE_LEN)
+        .map(|mut node| {
+            let mut id = [0; 20];
+            node.copy_to_slice(&mut id);
+            let ip = Ipv4Addr::from(node.get_u32());
+            let port = node.get_u16();
+            (id, SocketAddr::new(ip.into(), port))
+        })
+        .collect()
+}
+
+/// Encodes an IPv4 peer in the compact peer info format.
+pub(super) fn encode_peer(addr: &SocketAddr) -> Option<ByteBuf> {
+    match addr {
+        SocketAddr::V4(addr) => {
+            let mut buf = Vec::with_capacity(COMPACT_PEER_LEN);
+            buf.put_u32(u32::from(*addr.ip()));
+            buf.put_u16(addr.port());
+            Some(ByteBuf::from(buf))
+        }
+        SocketAddr::V6(_) => None,
+    }
+}
+
+/// Decodes a peer in the compact peer info format.
+fn decode_peer(mut b: &[u8]) -> Option<SocketAddr> {
+    if b.len() != COMPACT_PEER_LEN {
+        return None;
+    }
+    let ip = Ipv4Addr::from(b.get_u32());
+    let port = b.get_u16();
+    Some(SocketAddr::new(ip.into(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_decode_query() {
+        // the example ping query from BEP 5
+        let msg = Message::decode(
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe",
+        )
+        .unwrap();
+        assert!(msg.is_query());
+        assert_eq!(msg.transaction_id.as_slice(), b"aa");
+        assert_eq!(
+            msg.parse_query(),
+            Ok((*b"abcdefghij0123456789", Query::Ping))
+        );
+
+        // and the example announce_peer query
+        let msg = Message::decode(
+            b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e\
+            9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe\
+            1:q13:announce_peer1:t2:aa1:y1:qe",
+        )
+        .unwrap();
+        assert_eq!(
+            msg.parse_query(),
+            Ok((
+                *b"abcdefghij0123456789",
+                Query::AnnouncePeer {
+                    info_hash: *b"mnopqrstuvwxyz123456",
+                    port: 6881,
+                    implied_port: true,
+                    token: b"aoeusnth".to_vec(),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn should_encode_and_decode_query() {
+        let query = Query::GetPeers { info_hash: [3; 20] };
+        let msg = Message::query(b"ab", &[1; 20], query.clone());
+        let decoded = Message::decode(&msg.encode()).unwrap();
+        assert_eq!(decoded, msg);
+        assert_eq!(decoded.parse_query(), Ok(([1; 20], query)));
+        assert!(!decoded.is_read_only());
+
+        let msg = Message {
+            ro: Some(1),
+            ..Message::query(b"ab", &[1; 20], Query::Ping)
+        };
+        assert!(Message::decode(&msg.encode()).unwrap().is_read_only());
+
+        let msg = Message {
+            method: Some(ByteBuf::from(b"vote".to_vec())),
+            ..Message::query(b"ab", &[1; 20], Query::Ping)
+        };
+        assert_eq!(msg.parse_query().unwrap_err().0, METHOD_UNKNOWN);
+    }
+
+    #[test]
+    fn should_encode_and_decode_put_query() {
+        let item = Item::Mutable(MutableItem::sign(
+            &[7; 32],
+            b"salt".to_vec(),
+            3,
+            b"d1:ai1ee".to_vec(),
+        ));
+        let query = Query::Put {
+            item: item.clone(),
+            token: b"token".to_vec(),
+            cas: Some(2),
+        };
+        let msg = Message::query(b"ab", &[1; 20], query.clone());
+        let decoded = Message::decode(&msg.encode()).unwrap();
+        assert_eq!(decoded.parse_query(), Ok(([1; 20], query)));
+
+        // the item is returned in the response to a get query
+        let mut response = Response::new(&[2; 20]);
+        response.set_item(&item);
+        let querying_addr = SocketAddr::from(([9, 9, 9, 9], 6881));
+        let msg = Message::response(b"ab", response, &querying_addr);
+        let decoded = Message::decode(&msg.encode()).unwrap();
+        assert_eq!(decoded.response.unwrap().item(b"salt"), Some(item));
+    }
+
+    #[test]
+    fn should_encode_and_decode_response() {
+        let node = ([2; 20], SocketAddr::from(([1, 2, 3, 4], 6881)));
+        let peer = SocketAddr::from(([5, 6, 7, 8], 51413));
+        let response = Response {
+            nodes: Some(ByteBuf::from(encode_nodes(&[node]))),
+            values: Some(vec![encode_peer(&peer).unwrap()]),
+            token: Some(ByteBuf::from(b"token".to_vec())),
+            ..Response::new(&[1; 20])
+        };
+        let querying_addr = SocketAddr::from(([9, 9, 9, 9], 6881));
+        let msg = Message::response(b"ab", response, &querying_addr);
+        let decoded = Message::decode(&msg.encode()).unwrap();
+        assert!(decoded.is_response());
+        assert_eq!(decoded.reported_addr(), Some(querying_addr));
+        let response = decoded.response.unwrap();
+        assert_eq!(response.node_id(), Some([1; 20]));
+        assert_eq!(response.nodes(), vec![node]);
+        assert_eq!(response.peers(), vec![peer]);
+    }
+
+    #[test]
+    fn should_encode_and_decode_samples() {
+        let query = Query::SampleInfohashes { target: [3; 20] };
+        let msg = Message::query(b"ab", &[1; 20], query.clone());
+        let decoded = Message::decode(&msg.encode()).unwrap();
+        assert_eq!(decoded.parse_query(), Ok(([1; 20], query)));
+
+        let mut samples = [[4; 20], [5; 20]].concat();
+        // the trailing partial hash is ignored
+        samples.push(6);
+        let response = Response {
+            interval: Some(21600),
+            num: Some(2),
+            samples: Some(ByteBuf::from(samples)),
+            ..Response::new(&[2; 20])
+        };
+        let querying_addr = SocketAddr::from(([9, 9, 9, 9], 6881));
+        let msg = Message::response(b"ab", response, &querying_addr);
+        let decoded = Message::decode(&msg.encode()).unwrap();
+        let response = decoded.response.unwrap();
+        assert_eq!(response.interval, Some(21600));
+        assert_eq!(response.num, Some(2));
+        assert_eq!(response.samples(), vec![[4; 20], [5; 20]]);
+    }
+
+    #[test]
+    fn should_encode_and_decode_error() {
+        let msg = Message::error(b"ab", PROTOCOL_ERROR, "invalid id");
+        let decoded = Message::decode(&msg.encode()).unwrap();
+        assert!(decoded.is_error());
+        assert_eq!(
+            decoded.error,
+            Some((PROTOCOL_ERROR, ByteBuf::from(b"invalid id".to_vec())))
+        );
+    }
+}
@@ -0,0 +1,320 @@
+//! The Kademlia routing table of the DHT node.
+//!
+//! Nodes are sorted into buckets by the length of the prefix their id shares
+//! with ours, so that we know many nodes close to us and progressively fewer
+//! nodes farther away. Each bucket holds at most [`K`] nodes.
+
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use super::{distance, stats::BucketInfo, NodeId};
+
+/// The maximum number of nodes in a bucket, which is also the number of
+/// closest nodes a lookup converges on.
+pub(super) const K: usize = 8;
+
+/// The number of buckets, one for each possible length of the prefix shared
+/// with our id.
+const BUCKET_COUNT: usize = 160;
+
+/// A node that hasn't been heard from for this long is questionable and is
+/// pinged to check whether it's still alive.
+const NODE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// A node that failed to respond to this many queries in a row is removed.
+const MAX_FAILED_QUERY_COUNT: u32 = 2;
+
+/// A node in the routing table.
+#[derive(Clone, Debug)]
+pub(super) struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+    /// The last time the node responded to us.
+    pub last_seen: Instant,
+    /// The number of consecutive queries the node didn't respond to.
+    pub failed_query_count: u32,
+}
+
+impl Node {
+    /// Returns whether the node hasn't been heard from recently, or failed to
+    /// respond to our last query.
+    fn is_questionable(&self, now: Instant) -> bool {
+        self.failed_query_count > 0
+            || now.saturating_duration_since(self.last_seen) >= NODE_TIMEOUT
+    }
+}
+
+pub(super) struct RoutingTable {
+    own_id: NodeId,
+    /// The bucket at index `i` holds the nodes whose id shares a prefix of
+    /// exactly `i` bits with our id.
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        Self {
+            own_id,
+            buckets: vec![Vec::new(); BUCKET_COUNT],
+        }
+    }
+
+    /// Changes our id, sorting the nodes into the buckets of the new id.
+    /// Nodes that don't fit into their new bucket are dropped.
+    pub fn set_own_id(&mut self, own_id: NodeId) {
+        let nodes: Vec<_> = self
+            .buckets
+            .iter_mut()
+            .flat_map(|bucket| bucket.drain(..))
+            .collect();
+        self.own_id = own_id;
+        for node in nodes.into_iter() {
+            if let Some(index) = self.bucket_index(&node.id) {
+                let bucket = &mut self.buckets[index];
+                if bucket.len() < K {
+                    bucket.push(node);
+                }
+            }
+        }
+    }
+
+    /// Returns the total number of nodes in the table.
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    /// Returns all nodes in the table.
+    pub fn nodes(&self) -> impl Iterator<Item = &Node> {
+        self.buckets.iter().flatten()
+    }
+
+    /// Records that the node responded to us, adding it to the table if its
+    /// bucket has room.
+    ///
+    /// If the bucket is full, the node replaces one that is questionable.
+    /// Otherwise it is dropped, as nodes that have been around for long are
+    /// likely to stay around, so they are preferred to new ones.
+    pub fn insert(&mut self, id: NodeId, addr: SocketAddr, now: Instant) {
+        let index = match self.bucket_index(&id) {
+            Some(index) => index,
+            None => return,
+        };
+        let bucket = &mut self.buckets[index];
+        if let Some(node) = bucket.iter_mut().find(|node| node.id == id) {
+            node.addr = addr;
+            node.last_seen = now;
+            node.failed_query_count = 0;
+            return;
+        }
+
+        let node = Node {
+            id,
+            addr,
+            last_seen: now,
+            failed_query_count: 0,
+        };
+        if bucket.len() < K {
+            log::trace!("Adding DHT node {} to bucket {}", addr, index);
+            bucket.push(node);
+        } else if let Some(questionable) =
+            bucket.iter_mut().find(|node| node.is_questionable(now))
+        {
+            log::trace!(
+                "Replacing DHT node {} with {} in bucket {}",
+                questionable.addr,
+                addr,
+                index
+            );
+            *questionable = node;
+        }
+    }
+
+    /// Records that the node queried us, which keeps it good if it's in the
+    /// table.
+    pub fn touch(&mut self, id: &NodeId, addr: SocketAddr, now: Instant) {
+        let index = match self.bucket_index(id) {
+            Some(index) => index,
+            None => return,
+        };
+        if let Some(node) = self.buckets[index]
+            .iter_mut()
+            .find(|node| &node.id == id && node.addr == addr)
+        {
+            node.last_seen = now;
+        }
+    }
+
+    /// Records that the node failed to respond to a query, removing it from
+    /// the table if it failed too many times in a row.
+    pub fn mark_failed(&mut self, id: &NodeId) {
+        let index = match self.bucket_index(id) {
+            Some(index) => index,
+            None => return,
+        };
+        let bucket = &mut self.buckets[index];
+        if let Some(pos) = bucket.iter().position(|node| &node.id == id) {
+            bucket[pos].failed_query_count += 1;
+            if bucket[pos].failed_query_count >= MAX_FAILED_QUERY_COUNT {
+                log::trace!("Removing DHT node {}", bucket[pos].addr);
+                bucket.remove(pos);
+            }
+        }
+    }
+
+    /// Returns at most `count` nodes closest to the target, closest first.
+    /// Nodes that failed to respond to their last query are skipped.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+        let mut nodes: Vec<_> = self
+            .nodes()
+            .filter(|node| node.failed_query_count == 0)
+            .cloned()
+            .collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    /// Returns the nodes that haven't been heard from for long, and so
+    /// should be pinged.
+    pub fn questionable_nodes(&self, now: Instant) -> Vec<Node> {
+        self.nodes()
+            .filter(|node| node.is_questionable(now))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the statistics of the buckets that have nodes.
+    pub fn bucket_infos(&self, now: Instant) -> Vec<BucketInfo> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, bucket)| !bucket.is_empty())
+            .map(|(prefix_len, bucket)| BucketInfo {
+                prefix_len,
+                node_count: bucket.len(),
+                questionable_node_count: bucket
+                    .iter()
+                    .filter(|node| node.is_questionable(now))
+                    .count(),
+            })
+            .collect()
+    }
+
+    /// Returns the index of the bucket of the node with the given id, or
+    /// `None` if it's our own id.
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let distance = distance(&self.own_id, id);
+        distance
+            .iter()
+            .position(|&b| b != 0)
+            .map(|i| i * 8 + distance[i].leading_zeros() as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([1, 2, 3, 4], port))
+    }
+
+    #[test]
+    fn should_sort_nodes_into_buckets() {
+        let own_id = [0; 20];
+        let mut table = RoutingTable::new(own_id);
+        let now = Instant::now();
+
+        // our own id is never added
+        table.insert(own_id, addr(1), now);
+        assert_eq!(table.len(), 0);
+
+        // ids with the first bit set share no prefix with ours and so all go
+        // in the first bucket
+        for i in 0..K as u8 + 1 {
+            let mut id = [0; 20];
+            id[0] = 0x80;
+            id[19] = i;
+            table.insert(id, addr(i as u16), now);
+        }
+        assert_eq!(table.buckets[0].len(), K);
+
+        let mut id = [0; 20];
+        id[19] = 1;
+        table.insert(id, addr(100), now);
+        assert_eq!(table.buckets[159].len(), 1);
+        assert_eq!(table.len(), K + 1);
+    }
+
+    #[test]
+    fn should_replace_failed_node_in_full_bucket() {
+        let mut table = RoutingTable::new([0; 20]);
+        let now = Instant::now();
+        let id = |i| {
+            let mut id = [0; 20];
+            id[0] = 0x80;
+            id[19] = i;
+            id
+        };
+        for i in 0..K as u8 {
+            table.insert(id(i), addr(i as u16), now);
+        }
+
+        // a new node is dropped if all nodes are good
+        table.insert(id(100), addr(100), now);
+        assert!(table.nodes().all(|node| node.id != id(100)));
+
+        table.mark_failed(&id(3));
+        table.insert(id(100), addr(100), now);
+        assert!(table.nodes().any(|node| node.id == id(100)));
+        assert!(table.nodes().all(|node| node.id != id(3)));
+        assert_eq!(table.len(), K);
+
+        // nodes that fail repeatedly are removed
+        table.mark_failed(&id(4));
+        table.mark_failed(&id(4));
+        assert_eq!(table.len(), K - 1);
+    }
+
+    #[test]
+    fn should_keep_nodes_when_changing_id() {
+        let mut table = RoutingTable::new([0; 20]);
+        let now = Instant::now();
+        let mut ids = Vec::new();
+        for i in 1..=3u8 {
+            let mut id = [0; 20];
+            id[0] = 0x80;
+            id[19] = i;
+            ids.push(id);
+            table.insert(id, addr(i as u16), now);
+        }
+        assert_eq!(table.buckets[0].len(), 3);
+
+        // the nodes now share a long prefix with our id, except the one that
+        // is our new id, which is dropped
+        table.set_own_id(ids[0]);
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.buckets[0].len(), 0);
+        assert_eq!(table.buckets[158].len(), 2);
+    }
+
+    #[test]
+    fn should_return_closest_nodes() {
+        let mut table = RoutingTable::new([0; 20]);
+        let now = Instant::now();
+        for i in 1..=20u8 {
+            let mut id = [0; 20];
+            id[0] = i;
+            table.insert(id, addr(i as u16), now);
+        }
+
+        let mut target = [0; 20];
+        target[0] = 6;
+        let closest = table.closest(&target, 3);
+        let closest: Vec<_> = closest.iter().map(|node| node.id[0]).collect();
+        // 6 ^ 6 = 0, 6 ^ 7 = 1, 6 ^ 4 = 2
+        assert_eq!(closest, vec![6, 7, 4]);
+    }
+}
@@ -0,0 +1,143 @@
+//! Node ids tied to IP addresses, as specified in
+//! [BEP 42](https://www.bittorrent.org/beps/bep_0042.html).
+//!
+//! Nodes may choose their ids freely in the basic protocol, which lets an
+//! attacker place many nodes next to a target info hash and so take control
+//! of its peers. To prevent this, the first 21 bits of a node's id are
+//! derived from its external IP address, so that each address can only have
+//! a handful of ids. Nodes whose id doesn't match their address are not added
+//! to the routing table.
+//!
+//! Addresses on the local network are exempt, as their nodes can't know
+//! their external address.
+
+use std::net::{IpAddr, Ipv4Addr};
+
+use super::NodeId;
+use crate::external_ip::is_global;
+
+/// The masks applied to the octets of an IPv4 address before hashing it, so
+/// that the nodes of a /8 network share at most 2^(2+4+6+8) = 2^20 ids.
+const IPV4_MASK: [u8; 4] = [0x03, 0x0f, 0x3f, 0xff];
+
+/// Generates a random node id that is valid for the given external address.
+pub(super) fn generate_id(ip: Ipv4Addr) -> NodeId {
+    let mut id: NodeId = rand::random();
+    let r = id[19];
+    let prefix = id_prefix(ip, r);
+    id[0] = (prefix >> 24) as u8;
+    id[1] = (prefix >> 16) as u8;
+    // only the top 5 bits of the third byte are derived from the address
+    id[2] = ((prefix >> 8) as u8 & 0xf8) | (id[2] & 0x07);
+    id
+}
+
+/// Returns whether the node id is valid for the node's address.
+///
+/// Ids of nodes on the local network, and of IPv6 nodes, which we don't
+/// support, are always valid.
+pub(super) fn is_valid_id(id: &NodeId, ip: IpAddr) -> bool {
+    let ip = match ip {
+        IpAddr::V4(ip) if is_global(ip.into()) => ip,
+        _ => return true,
+    };
+    let prefix = id_prefix(ip, id[19]);
+    id[0] == (prefix >> 24) as u8
+        && id[1] == (prefix >> 16) as u8
+        && id[2] & 0xf8 == (prefix >> 8) as u8 & 0xf8
+}
+
+/// Returns the hash of the masked address, whose top 21 bits are the prefix
+/// of the ids valid for the address. Only the lowest 3 bits of `r`, which is
+/// the last byte of the id, are used.
+fn id_prefix(ip: Ipv4Addr, r: u8) -> u32 {
+    let mut octets = ip.octets();
+    for (octet, mask) in octets.iter_mut().zip(IPV4_MASK.iter()) {
+        *octet &= mask;
+    }
+    octets[0] |= (r & 0x07) << 5;
+    crc32c(&octets)
+}
+
+/// Computes the CRC-32C (Castagnoli) checksum of the buffer.
+///
+/// Only a few bytes are hashed at a time, so a simple bitwise implementation
+/// suffices.
+fn crc32c(buf: &[u8]) -> u32 {
+    // the reversed Castagnoli polynomial
+    const POLY: u32 = 0x82f6_3b78;
+    let mut crc = !0u32;
+    for &b in buf.iter() {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_compute_crc32c() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn should_validate_ids_of_bep_examples() {
+        let examples = [
+            (
+                [124, 31, 75, 21],
+                "5fbfbff10c5d6a4ec8a88e4c6ab4c28b95eee401",
+            ),
+            (
+                [21, 75, 31, 124],
+                "5a3ce9c14e7a08645677bbd1cfe7d8f956d53256",
+            ),
+            (
+                [65, 23, 51, 170],
+                "a5d43220bc8f112a3d426c84764f8c2a1150e616",
+            ),
+            (
+                [84, 124, 73, 14],
+                "1b0321dd1bb1fe518101ceef99462b947a01ff41",
+            ),
+            (
+                [43, 213, 53, 83],
+                "e56f6cbf5b7c4be0237986d5243b87aa6d51305a",
+            ),
+        ];
+        for (ip, id) in examples.iter() {
+            let ip = Ipv4Addr::from(*ip);
+            let mut node_id = [0; 20];
+            hex::decode_to_slice(id, &mut node_id).unwrap();
+            assert!(is_valid_id(&node_id, ip.into()));
+
+            // the id is not valid for another address
+            let other_ip = Ipv4Addr::from(u32::from(ip) ^ 0x0100_0000);
+            assert!(!is_valid_id(&node_id, other_ip.into()));
+        }
+    }
+
+    #[test]
+    fn should_generate_valid_id() {
+        let ip = Ipv4Addr::new(124, 31, 75, 21);
+        for _ in 0..10 {
+            assert!(is_valid_id(&generate_id(ip), ip.into()));
+        }
+    }
+
+    #[test]
+    fn should_exempt_local_addresses() {
+        let id = [0; 20];
+        assert!(!is_valid_id(&id, Ipv4Addr::new(124, 31, 75, 21).into()));
+        assert!(is_valid_id(&id, Ipv4Addr::new(192, 168, 1, 2).into()));
+        assert!(is_valid_id(&id, Ipv4Addr::LOCALHOST.into()));
+    }
+}
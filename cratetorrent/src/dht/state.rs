@@ -0,0 +1,166 @@
+//! The state of the DHT node that is persisted across restarts.
+//!
+//! Joining the DHT from the bootstrap nodes alone takes a while, as the
+//! routing table is filled one lookup at a time. So that a restarted node is
+//! useful right away, its id and the good nodes of its routing table are
+//! saved on shutdown and restored on startup. The id must be kept as well, as
+//! the routing table is organized around it.
+
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde_bytes::ByteBuf;
+
+use super::{
+    msg::{decode_nodes, encode_nodes, parse_id},
+    routing::RoutingTable,
+    NodeId,
+};
+
+/// The bencoded state of the node, as saved in the state file.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(crate = "serde_crate")]
+struct RawState {
+    /// Our node id.
+    id: ByteBuf,
+    nodes: Vec<RawNode>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(crate = "serde_crate")]
+struct RawNode {
+    /// The node in the compact node info format.
+    node: ByteBuf,
+    /// The time the node was last seen, in seconds since the Unix epoch, so
+    /// that nodes not seen for long are restored as questionable.
+    last_seen: u64,
+}
+
+/// The state of the node restored from the state file.
+#[derive(Debug, PartialEq)]
+pub(super) struct State {
+    pub id: NodeId,
+    /// The saved nodes and the times they were last seen.
+    pub nodes: Vec<(NodeId, SocketAddr, Instant)>,
+}
+
+/// Loads the state saved at the given path.
+///
+/// The last seen times are converted to instants relative to `now`. Nodes
+/// that were last seen at a time in the future, e.g. because the system clock
+/// changed, are restored as seen just now.
+pub(super) fn load(path: &Path, now: Instant) -> io::Result<State> {
+    let buf = fs::read(path)?;
+    let raw: RawState = serde_bencode::from_bytes(&buf)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    let id = parse_id(&raw.id).ok_or_else(|| {
+        io::Error::new(ErrorKind::InvalidData, "invalid DHT node id")
+    })?;
+
+    let sys_now = SystemTime::now();
+    let mut nodes = Vec::with_capacity(raw.nodes.len());
+    for node in raw.nodes.iter() {
+        let last_seen = UNIX_EPOCH + Duration::from_secs(node.last_seen);
+        let age = sys_now.duration_since(last_seen).unwrap_or_default();
+        // an instant can't be earlier than the start of the system, in which
+        // case the node is old enough to be questionable anyway
+        let last_seen = match now.checked_sub(age) {
+            Some(last_seen) => last_seen,
+            None => continue,
+        };
+        nodes.extend(
+            decode_nodes(&node.node)
+                .into_iter()
+                .map(|(id, addr)| (id, addr, last_seen)),
+        );
+    }
+
+    Ok(State { id, nodes })
+}
+
+/// Saves our id and the nodes of the routing table that didn't fail to
+/// respond to their last query at the given path.
+pub(super) fn save(
+    path: &Path,
+    id: &NodeId,
+    routing_table: &RoutingTable,
+    now: Instant,
+) -> io::Result<()> {
+    let sys_now = SystemTime::now();
+    let nodes = routing_table
+        .nodes()
+        .filter(|node| node.failed_query_count == 0)
+        .filter_map(|node| {
+            let age = now.saturating_duration_since(node.last_seen);
+            let last_seen = sys_now.checked_sub(age)?;
+            let last_seen = last_seen.duration_since(UNIX_EPOCH).ok()?;
+            Some(RawNode {
+                node: ByteBuf::from(encode_nodes(&[(node.id, node.addr)])),
+                last_seen: last_seen.as_secs(),
+            })
+        })
+        // IPv6 nodes can't be encoded
+        .filter(|node| !node.node.is_empty())
+        .collect();
+    let raw = RawState {
+        id: ByteBuf::from(id.to_vec()),
+        nodes,
+    };
+    let buf = serde_bencode::to_bytes(&raw)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    fs::write(path, buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_restore_saved_nodes() {
+        let path = std::env::temp_dir().join("cratetorrent_dht_state_test");
+        let own_id = [0; 20];
+        let now = Instant::now();
+        let mut table = RoutingTable::new(own_id);
+        let mut ids = Vec::new();
+        for i in 1..=3u8 {
+            let mut id = [0; 20];
+            id[0] = i;
+            ids.push(id);
+            let addr = SocketAddr::from(([1, 2, 3, i], 6881));
+            table.insert(id, addr, now - Duration::from_secs(i as u64 * 60));
+        }
+        // nodes that failed to respond are not saved
+        table.mark_failed(&ids[2]);
+
+        save(&path, &own_id, &table, now).unwrap();
+        let mut state = load(&path, now).unwrap();
+        fs::remove_file(&path).unwrap();
+        state.nodes.sort_by_key(|(id, _, _)| *id);
+
+        assert_eq!(state.id, own_id);
+        assert_eq!(state.nodes.len(), 2);
+        for (i, (id, addr, last_seen)) in state.nodes.iter().enumerate() {
+            let i = i as u8 + 1;
+            assert_eq!(id[0], i);
+            assert_eq!(*addr, SocketAddr::from(([1, 2, 3, i], 6881)));
+            // the times are saved with a precision of seconds
+            let age = now.duration_since(*last_seen).as_secs();
+            assert!(age >= i as u64 * 60 - 1 && age <= i as u64 * 60 + 1);
+        }
+    }
+
+    #[test]
+    fn should_reject_invalid_state() {
+        let path =
+            std::env::temp_dir().join("cratetorrent_dht_invalid_state_test");
+        fs::write(&path, b"d2:id3:abce").unwrap();
+        let result = load(&path, Instant::now());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,52 @@
+/// Statistics of the engine's DHT node, with which one can tell whether the
+/// node is taking part in the DHT.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct DhtStats {
+    /// Our node id.
+    pub node_id: [u8; 20],
+    /// The number of nodes in our routing table.
+    pub node_count: usize,
+    /// The buckets of our routing table that have nodes, in order of the
+    /// length of the prefix their nodes share with our id.
+    pub buckets: Vec<BucketInfo>,
+    /// The number of our queries awaiting a response.
+    pub in_flight_query_count: usize,
+    /// The number of lookups in progress.
+    pub lookup_count: usize,
+    /// The number of the engine's torrents that are looked up and announced
+    /// in the DHT.
+    pub torrent_count: usize,
+    /// The number of torrents whose peers other nodes announced to us.
+    pub stored_torrent_count: usize,
+    /// The number of peers other nodes announced to us, across all torrents.
+    pub stored_peer_count: usize,
+    /// The number of items other nodes stored at our node.
+    pub stored_item_count: usize,
+    /// The number of queries received from other nodes per second, as
+    /// a 5 second moving average.
+    pub incoming_query_rate: u64,
+    /// The total number of queries received from other nodes.
+    pub incoming_query_count: u64,
+}
+
+/// Statistics of a bucket of our routing table.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct BucketInfo {
+    /// The length of the prefix the bucket's nodes share with our id.
+    pub prefix_len: usize,
+    /// The number of nodes in the bucket.
+    pub node_count: usize,
+    /// The number of nodes in the bucket that haven't been heard from
+    /// recently, or failed to respond to our last query.
+    pub questionable_node_count: usize,
+}
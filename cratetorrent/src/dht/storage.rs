@@ -0,0 +1,358 @@
+//! Storage of arbitrary items in the DHT, as specified in
+//! [BEP 44](https://www.bittorrent.org/beps/bep_0044.html).
+//!
+//! Besides the peers of torrents, nodes store small bencoded values for other
+//! nodes. An item is stored at the nodes closest to its target, and is either
+//! immutable, in which case its target is the SHA-1 hash of its value, or
+//! mutable, in which case its target is the hash of the ed25519 public key
+//! that signs it, followed by an optional salt. A mutable item can be updated
+//! by its owner by storing a new value with a higher sequence number, which
+//! allows building updatable pointers, e.g. to the latest release of
+//! a torrent.
+
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    time::{Duration, Instant},
+};
+
+use ed25519_dalek::{
+    ExpandedSecretKey, PublicKey, SecretKey, Signature, Verifier,
+};
+use serde_bencode::value::Value;
+use sha1::{Digest, Sha1};
+
+use crate::Sha1Hash;
+
+/// The maximum length of a bencoded value.
+pub(crate) const MAX_VALUE_LEN: usize = 1000;
+
+/// The maximum length of the salt of a mutable item.
+pub(crate) const MAX_SALT_LEN: usize = 64;
+
+/// An item is forgotten if it isn't stored again in this time.
+const ITEM_TIMEOUT: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// The maximum number of items we store for other nodes.
+const MAX_STORED_ITEM_COUNT: usize = 1000;
+
+/// The error code of values that are too long.
+pub(super) const VALUE_TOO_BIG: i64 = 205;
+/// The error code of mutable items with an invalid signature.
+pub(super) const INVALID_SIGNATURE: i64 = 206;
+/// The error code of salts that are too long.
+pub(super) const SALT_TOO_BIG: i64 = 207;
+/// The error code of puts whose expected sequence number doesn't match that
+/// of the stored item.
+pub(super) const CAS_MISMATCH: i64 = 301;
+/// The error code of puts with a lower sequence number than the stored item.
+pub(super) const SEQ_TOO_LOW: i64 = 302;
+
+/// A mutable item: a value signed by the owner of an ed25519 key.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct MutableItem {
+    /// The public key of the owner.
+    pub public_key: [u8; 32],
+    /// The salt which, along with the public key, identifies the item. This
+    /// lets the owner of a key store multiple items.
+    pub salt: Vec<u8>,
+    /// The version of the item, which increases with each update.
+    pub seq: i64,
+    /// The bencoded value.
+    pub value: Vec<u8>,
+    /// The signature of the salt, sequence number and value.
+    pub signature: Vec<u8>,
+}
+
+impl MutableItem {
+    /// Creates an item signed with the given ed25519 secret key.
+    ///
+    /// The value must already be in canonical bencoding.
+    pub(crate) fn sign(
+        secret_key: &[u8; 32],
+        salt: Vec<u8>,
+        seq: i64,
+        value: Vec<u8>,
+    ) -> Self {
+        let secret_key = SecretKey::from_bytes(secret_key)
+            .expect("secret key should have valid length");
+        let public_key = PublicKey::from(&secret_key);
+        let signature = ExpandedSecretKey::from(&secret_key)
+            .sign(&signed_payload(&salt, seq, &value), &public_key);
+        Self {
+            public_key: public_key.to_bytes(),
+            salt,
+            seq,
+            value,
+            signature: signature.to_bytes().to_vec(),
+        }
+    }
+
+    /// Returns whether the item was signed by the owner of its public key.
+    pub(crate) fn is_signature_valid(&self) -> bool {
+        let public_key = match PublicKey::from_bytes(&self.public_key) {
+            Ok(public_key) => public_key,
+            Err(_) => return false,
+        };
+        let signature = match Signature::try_from(self.signature.as_slice()) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        public_key
+            .verify(
+                &signed_payload(&self.salt, self.seq, &self.value),
+                &signature,
+            )
+            .is_ok()
+    }
+}
+
+/// An item stored in the DHT.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Item {
+    /// The bencoded value of an immutable item.
+    Immutable(Vec<u8>),
+    Mutable(MutableItem),
+}
+
+impl Item {
+    /// Returns the target at which the item is stored.
+    pub fn target(&self) -> Sha1Hash {
+        match self {
+            Self::Immutable(value) => sha1(&[value.as_slice()]),
+            Self::Mutable(item) => mutable_target(&item.public_key, &item.salt),
+        }
+    }
+
+    /// Returns the bencoded value of the item.
+    pub fn value(&self) -> &[u8] {
+        match self {
+            Self::Immutable(value) => value,
+            Self::Mutable(item) => &item.value,
+        }
+    }
+}
+
+/// Returns the target of the mutable item with the given public key and salt.
+pub(crate) fn mutable_target(public_key: &[u8; 32], salt: &[u8]) -> Sha1Hash {
+    sha1(&[&public_key[..], salt])
+}
+
+/// Parses and re-encodes the bencoded value, so that it's in canonical form,
+/// e.g. with sorted dictionary keys, which the value's hash and signature
+/// depend on. Returns `None` if the value is not valid bencode.
+pub(crate) fn canonicalize(value: &[u8]) -> Option<Vec<u8>> {
+    let value: Value = serde_bencode::from_bytes(value).ok()?;
+    serde_bencode::to_bytes(&value).ok()
+}
+
+/// Returns the buffer that is signed for a mutable item: the bencoded salt, if
+/// any, sequence number and value, without the enclosing dictionary.
+fn signed_payload(salt: &[u8], seq: i64, value: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(salt.len() + value.len() + 32);
+    if !salt.is_empty() {
+        buf.extend_from_slice(format!("4:salt{}:", salt.len()).as_bytes());
+        buf.extend_from_slice(salt);
+    }
+    buf.extend_from_slice(format!("3:seqi{}e1:v", seq).as_bytes());
+    buf.extend_from_slice(value);
+    buf
+}
+
+fn sha1(bufs: &[&[u8]]) -> Sha1Hash {
+    let mut hasher = Sha1::new();
+    for buf in bufs.iter() {
+        hasher.update(buf);
+    }
+    let mut hash = [0; 20];
+    hash.copy_from_slice(&hasher.finalize());
+    hash
+}
+
+/// The items other nodes stored at our node.
+#[derive(Default)]
+pub(super) struct ItemStore {
+    /// The items by target, and the times they were last stored.
+    items: HashMap<Sha1Hash, (Item, Instant)>,
+}
+
+impl ItemStore {
+    /// Returns the number of items stored.
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    /// Returns the item stored at the target, if any.
+    pub fn get(&self, target: &Sha1Hash) -> Option<&Item> {
+        self.items.get(target).map(|(item, _)| item)
+    }
+
+    /// Stores the item, replacing the one at the same target, if any.
+    ///
+    /// A mutable item must be validly signed, and may only replace an item
+    /// with the same or a lower sequence number. If `cas` is set, it only
+    /// replaces the item if that has this sequence number.
+    ///
+    /// On failure, the error code and message with which the query should be
+    /// answered is returned.
+    pub fn put(
+        &mut self,
+        item: Item,
+        cas: Option<i64>,
+        now: Instant,
+    ) -> Result<(), (i64, &'static str)> {
+        if item.value().len() > MAX_VALUE_LEN {
+            return Err((VALUE_TOO_BIG, "value too big"));
+        }
+        let target = item.target();
+        if let Item::Mutable(new) = &item {
+            if new.salt.len() > MAX_SALT_LEN {
+                return Err((SALT_TOO_BIG, "salt too big"));
+            }
+            if !new.is_signature_valid() {
+                return Err((INVALID_SIGNATURE, "invalid signature"));
+            }
+            if let Some((Item::Mutable(current), _)) = self.items.get(&target) {
+                if cas.map_or(false, |cas| cas != current.seq) {
+                    return Err((CAS_MISMATCH, "CAS mismatch"));
+                }
+                if new.seq < current.seq {
+                    return Err((SEQ_TOO_LOW, "sequence number too low"));
+                }
+            }
+        }
+
+        // make room by forgetting the item that was stored the longest ago
+        if !self.items.contains_key(&target)
+            && self.items.len() >= MAX_STORED_ITEM_COUNT
+        {
+            let oldest = self
+                .items
+                .iter()
+                .min_by_key(|(_, (_, put_time))| *put_time)
+                .map(|(target, _)| *target);
+            if let Some(oldest) = oldest {
+                self.items.remove(&oldest);
+            }
+        }
+        self.items.insert(target, (item, now));
+        Ok(())
+    }
+
+    /// Forgets the items that weren't stored again for long.
+    pub fn expire(&mut self, now: Instant) {
+        self.items.retain(|_, (_, put_time)| {
+            now.saturating_duration_since(*put_time) < ITEM_TIMEOUT
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the test vector from BEP 44
+    const PUBLIC_KEY: &str =
+        "77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548";
+    const SIGNATURE: &str = "305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d\
+                             856091e5e853cff1260d3f39e4999684aa92eb73ffd136e6f\
+                             4f3ecbfda0ce53a1608ecd7ae21f01";
+
+    fn example_item() -> MutableItem {
+        let mut public_key = [0; 32];
+        hex::decode_to_slice(PUBLIC_KEY, &mut public_key).unwrap();
+        MutableItem {
+            public_key,
+            salt: Vec::new(),
+            seq: 1,
+            value: b"12:Hello World!".to_vec(),
+            signature: hex::decode(SIGNATURE).unwrap(),
+        }
+    }
+
+    #[test]
+    fn should_compute_targets() {
+        let item = Item::Immutable(b"12:Hello World!".to_vec());
+        assert_eq!(
+            hex::encode(item.target()),
+            "e5f96f6f38320f0f33959cb4d3d656452117aadb"
+        );
+
+        let item = example_item();
+        assert_eq!(
+            hex::encode(Item::Mutable(item.clone()).target()),
+            "4a533d47ec9c7d95b1ad75f576cffc641853b750"
+        );
+        assert_eq!(
+            hex::encode(mutable_target(&item.public_key, b"foobar")),
+            "411eba73b6f087ca51a3795d9c8c938d365e32c1"
+        );
+    }
+
+    #[test]
+    fn should_verify_signature() {
+        let mut item = example_item();
+        assert!(item.is_signature_valid());
+        item.seq = 2;
+        assert!(!item.is_signature_valid());
+
+        let item = MutableItem::sign(
+            &[7; 32],
+            b"salt".to_vec(),
+            5,
+            b"5:hello".to_vec(),
+        );
+        assert!(item.is_signature_valid());
+    }
+
+    #[test]
+    fn should_canonicalize_value() {
+        assert_eq!(
+            canonicalize(b"d1:bi2e1:ai1ee"),
+            Some(b"d1:ai1e1:bi2ee".to_vec())
+        );
+        assert_eq!(canonicalize(b"5:hi"), None);
+    }
+
+    #[test]
+    fn should_only_replace_item_with_higher_seq() {
+        let mut store = ItemStore::default();
+        let now = Instant::now();
+        let key = [3; 32];
+        let item = |seq| {
+            Item::Mutable(MutableItem::sign(
+                &key,
+                Vec::new(),
+                seq,
+                format!("i{}e", seq).into_bytes(),
+            ))
+        };
+        let target = item(0).target();
+
+        store.put(item(2), None, now).unwrap();
+        assert_eq!(store.put(item(1), None, now).unwrap_err().0, SEQ_TOO_LOW);
+        assert_eq!(
+            store.put(item(3), Some(1), now).unwrap_err().0,
+            CAS_MISMATCH
+        );
+        store.put(item(3), Some(2), now).unwrap();
+        assert_eq!(store.get(&target), Some(&item(3)));
+
+        let mut forged = item(4);
+        if let Item::Mutable(forged) = &mut forged {
+            forged.value = b"i5e".to_vec();
+        }
+        assert_eq!(
+            store.put(forged, None, now).unwrap_err().0,
+            INVALID_SIGNATURE
+        );
+
+        store.expire(now + ITEM_TIMEOUT);
+        assert_eq!(store.len(), 0);
+    }
+}
@@ -3,17 +3,18 @@
 
 use std::collections::HashMap;
 
-use tokio::{
-    sync::{
-        mpsc::{self, UnboundedReceiver, UnboundedSender},
-        RwLock,
-    },
-    task,
+use tokio::sync::{
+    mpsc::{self, UnboundedReceiver, UnboundedSender},
+    oneshot, RwLock,
 };
+use tracing::Instrument;
 
 use crate::{
-    engine, error::Error, peer, storage_info::StorageInfo, torrent, BlockInfo,
-    TorrentId,
+    engine,
+    error::Error,
+    peer, rt,
+    storage_info::{Rename, StorageInfo, StorageMode},
+    torrent, BlockInfo, CachedBlock, PieceIndex, TorrentId,
 };
 use error::*;
 use io::torrent::Torrent;
@@ -23,23 +24,34 @@ mod io;
 
 /// Spawns a disk IO task and returns a tuple with the task join handle and the
 /// disk handle used for sending commands.
-pub(crate) fn spawn(engine_tx: engine::Sender) -> Result<(JoinHandle, Sender)> {
+pub(crate) fn spawn(
+    engine_tx: engine::Sender,
+    read_cache_len: usize,
+) -> Result<(JoinHandle, Sender)> {
     log::info!("Spawning disk IO task");
-    let (mut disk, disk_tx) = Disk::new(engine_tx)?;
+    let (mut disk, disk_tx) = Disk::new(engine_tx, read_cache_len)?;
     // spawn disk event loop on a new task
-    let join_handle = task::spawn(async move { disk.start().await });
+    let join_handle = rt::spawn(
+        async move { disk.start().await }
+            .instrument(tracing::info_span!("disk")),
+    );
     log::info!("Spawned disk IO task");
 
     Ok((join_handle, disk_tx))
 }
 
-pub(crate) type JoinHandle = task::JoinHandle<Result<()>>;
+pub(crate) type JoinHandle = rt::JoinHandle<Result<()>>;
 
 /// The channel for sendng commands to the disk task.
 pub(crate) type Sender = UnboundedSender<Command>;
 /// The channel the disk task uses to listen for commands.
 type Receiver = UnboundedReceiver<Command>;
 
+/// The channel on which the blocks of a piece read with
+/// [`Command::ReadPiece`] are sent.
+pub(crate) type PieceSender =
+    oneshot::Sender<std::result::Result<Vec<CachedBlock>, ReadError>>;
+
 /// The type of commands that the disk can execute.
 #[derive(Debug)]
 pub(crate) enum Command {
@@ -47,6 +59,7 @@ pub(crate) enum Command {
     NewTorrent {
         id: TorrentId,
         storage_info: StorageInfo,
+        storage_mode: StorageMode,
         piece_hashes: Vec<u8>,
         torrent_tx: torrent::Sender,
     },
@@ -63,10 +76,75 @@ pub(crate) enum Command {
         block_info: BlockInfo,
         result_tx: peer::Sender,
     },
-    /// Eventually shut down the disk task.
+    /// Request to read a whole piece from disk and return its blocks via the
+    /// sender.
+    ReadPiece {
+        id: TorrentId,
+        piece_index: PieceIndex,
+        result_tx: PieceSender,
+    },
+    /// Releases the torrent's storage. Blocks of incomplete pieces that are
+    /// still buffered are dropped.
+    ///
+    /// If set, the torrent's files are deleted once its pending writes are
+    /// done, after which the result is sent to the engine.
+    RemoveTorrent { id: TorrentId, delete_files: bool },
+    /// Hashes all of the torrent's pieces on disk, once its pending writes are
+    /// done, and sends the torrent the pieces that are valid.
+    CheckPieces(TorrentId),
+    /// Moves a file of the torrent or renames its root, after which the
+    /// result is sent to the engine.
+    Rename { id: TorrentId, rename: Rename },
+    /// Eventually shut down the disk task, once the pending writes are done
+    /// and all files are synced to disk.
     Shutdown,
 }
 
+impl Command {
+    /// Returns the span in which the command is executed, so that its events
+    /// can be correlated with those of the torrent it concerns.
+    fn span(&self) -> tracing::Span {
+        match self {
+            Self::NewTorrent { id, .. } => {
+                tracing::debug_span!("new_torrent", torrent = %id)
+            }
+            Self::WriteBlock { id, block_info, .. } => {
+                tracing::trace_span!(
+                    "write_block",
+                    torrent = %id,
+                    block = %block_info
+                )
+            }
+            Self::ReadBlock { id, block_info, .. } => {
+                tracing::trace_span!(
+                    "read_block",
+                    torrent = %id,
+                    block = %block_info
+                )
+            }
+            Self::ReadPiece {
+                id, piece_index, ..
+            } => {
+                tracing::trace_span!(
+                    "read_piece",
+                    torrent = %id,
+                    piece = piece_index
+                )
+            }
+            Self::RemoveTorrent { id, .. } => {
+                tracing::debug_span!("remove_torrent", torrent = %id)
+            }
+            Self::CheckPieces(id) => {
+                tracing::debug_span!("check_pieces", torrent = %id)
+            }
+            Self::Rename { id, .. } => {
+                tracing::debug_span!("rename", torrent = %id)
+            }
+            Self::Shutdown => tracing::debug_span!("shutdown"),
+        }
+    }
+}
+
 /// The entity responsible for saving downloaded file blocks to disk and
 /// verifying whether downloaded pieces are valid.
 struct Disk {
@@ -78,18 +156,24 @@ struct Disk {
     cmd_rx: Receiver,
     /// Channel on which `Disk` sends alerts to the torrent engine.
     engine_tx: engine::Sender,
+    /// The maximum number of pieces in the read cache of each torrent.
+    read_cache_len: usize,
 }
 
 impl Disk {
     /// Creates a new `Disk` instance and returns a command sender and an alert
     /// receiver.
-    fn new(engine_tx: engine::Sender) -> Result<(Self, Sender)> {
+    fn new(
+        engine_tx: engine::Sender,
+        read_cache_len: usize,
+    ) -> Result<(Self, Sender)> {
         let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
         Ok((
             Self {
                 torrents: HashMap::new(),
                 cmd_rx,
                 engine_tx,
+                read_cache_len,
             },
             cmd_tx,
         ))
@@ -100,85 +184,165 @@ impl Disk {
     async fn start(&mut self) -> Result<()> {
         log::info!("Starting disk IO event loop");
         while let Some(cmd) = self.cmd_rx.recv().await {
-            match cmd {
-                Command::NewTorrent {
+            let is_shutdown = matches!(cmd, Command::Shutdown);
+            let span = cmd.span();
+            self.execute(cmd).instrument(span).await?;
+            if is_shutdown {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Executes a single command.
+    async fn execute(&mut self, cmd: Command) -> Result<()> {
+        match cmd {
+            Command::NewTorrent {
+                id,
+                storage_info,
+                storage_mode,
+                piece_hashes,
+                torrent_tx,
+            } => {
+                log::trace!(
+                    "Disk received NewTorrent command: id={}, info={:?}",
                     id,
+                    storage_info
+                );
+                if self.torrents.contains_key(&id) {
+                    log::warn!("Torrent {} already allocated", id);
+                    self.engine_tx.send(
+                        engine::Command::TorrentAllocation {
+                            id,
+                            result: Err(NewTorrentError::AlreadyExists),
+                        },
+                    )?;
+                    return Ok(());
+                }
+
+                // NOTE: Do _NOT_ return on failure, we don't want to kill
+                // the disk task due to potential disk IO errors: we just
+                // want to log it and notify engine of it.
+                let torrent_res = Torrent::new(
                     storage_info,
+                    storage_mode,
                     piece_hashes,
                     torrent_tx,
-                } => {
-                    log::trace!(
-                        "Disk received NewTorrent command: id={}, info={:?}",
-                        id,
-                        storage_info
-                    );
-                    if self.torrents.contains_key(&id) {
-                        log::warn!("Torrent {} already allocated", id);
+                    self.read_cache_len,
+                );
+                match torrent_res {
+                    Ok(torrent) => {
+                        log::info!("Torrent {} successfully allocated", id);
+                        self.torrents.insert(id, RwLock::new(torrent));
+                        // send notificaiton of allocation success
                         self.engine_tx.send(
                             engine::Command::TorrentAllocation {
                                 id,
-                                result: Err(NewTorrentError::AlreadyExists),
+                                result: Ok(()),
                             },
                         )?;
-                        continue;
                     }
-
-                    // NOTE: Do _NOT_ return on failure, we don't want to kill
-                    // the disk task due to potential disk IO errors: we just
-                    // want to log it and notify engine of it.
-                    let torrent_res =
-                        Torrent::new(storage_info, piece_hashes, torrent_tx);
-                    match torrent_res {
-                        Ok(torrent) => {
-                            log::info!("Torrent {} successfully allocated", id);
-                            self.torrents.insert(id, RwLock::new(torrent));
-                            // send notificaiton of allocation success
-                            self.engine_tx.send(
-                                engine::Command::TorrentAllocation {
-                                    id,
-                                    result: Ok(()),
-                                },
-                            )?;
-                        }
-                        Err(e) => {
-                            log::error!(
-                                "Torrent {} allocation failure: {}",
+                    Err(e) => {
+                        log::error!("Torrent {} allocation failure: {}", id, e);
+                        // send notificaiton of allocation failure
+                        self.engine_tx.send(
+                            engine::Command::TorrentAllocation {
                                 id,
-                                e
-                            );
-                            // send notificaiton of allocation failure
-                            self.engine_tx.send(
-                                engine::Command::TorrentAllocation {
-                                    id,
-                                    result: Err(e),
-                                },
-                            )?;
-                        }
+                                result: Err(e),
+                            },
+                        )?;
                     }
                 }
-                Command::WriteBlock {
-                    id,
-                    block_info,
-                    data,
-                } => {
-                    self.write_block(id, block_info, data).await?;
+            }
+            Command::WriteBlock {
+                id,
+                block_info,
+                data,
+            } => {
+                self.write_block(id, block_info, data).await?;
+            }
+            Command::ReadBlock {
+                id,
+                block_info,
+                result_tx,
+            } => {
+                self.read_block(id, block_info, result_tx).await?;
+            }
+            Command::ReadPiece {
+                id,
+                piece_index,
+                result_tx,
+            } => match self.torrents.get(&id) {
+                Some(torrent) => {
+                    torrent.read().await.read_piece(piece_index, result_tx)
                 }
-                Command::ReadBlock {
-                    id,
-                    block_info,
-                    result_tx,
-                } => {
-                    self.read_block(id, block_info, result_tx).await?;
+                // the reader then sees the torrent as gone
+                None => log::warn!("Torrent {} not found", id),
+            },
+            Command::RemoveTorrent { id, delete_files } => {
+                match self.torrents.remove(&id) {
+                    Some(torrent) => {
+                        log::info!("Torrent {} removed from disk", id);
+                        if delete_files {
+                            self.delete_files(id, torrent.into_inner());
+                        }
+                    }
+                    None => log::warn!("Torrent {} not found", id),
                 }
-                Command::Shutdown => {
-                    log::info!("Shutting down disk event loop");
-                    break;
+            }
+            Command::CheckPieces(id) => match self.torrents.get(&id) {
+                Some(torrent) => {
+                    log::info!("Checking torrent {} pieces", id);
+                    torrent.write().await.check_pieces().await;
+                }
+                None => log::warn!("Torrent {} not found", id),
+            },
+            Command::Rename { id, rename } => match self.torrents.get(&id) {
+                Some(torrent) => {
+                    let result = torrent.write().await.rename(rename).await;
+                    self.engine_tx
+                        .send(engine::Command::StorageRenamed { id, result })?;
                 }
+                None => log::warn!("Torrent {} not found", id),
+            },
+            Command::Shutdown => {
+                log::info!("Shutting down disk event loop");
+                self.flush().await;
             }
         }
         Ok(())
     }
 
+    /// Deletes the files of the removed torrent in the background, and tells
+    /// the engine whether it succeeded.
+    fn delete_files(&self, id: TorrentId, torrent: Torrent) {
+        log::info!("Deleting torrent {} files", id);
+        let engine_tx = self.engine_tx.clone();
+        rt::spawn(
+            async move {
+                let result = torrent.delete_files().await;
+                // the engine may have been shut down in the meantime
+                engine_tx
+                    .send(engine::Command::FilesDeleted { id, result })
+                    .ok();
+            }
+            .instrument(tracing::debug_span!("delete_files", torrent = %id)),
+        );
+    }
+
+    /// Waits for the pieces being written and syncs the files of all
+    /// torrents to disk.
+    ///
+    /// Errors are only logged, so that one torrent's failure doesn't keep the
+    /// others from being synced.
+    async fn flush(&self) {
+        for (id, torrent) in self.torrents.iter() {
+            if let Err(e) = torrent.write().await.flush().await {
+                log::error!("Error syncing torrent {} to disk: {}", id, e);
+            }
+        }
+    }
+
     /// Queues a block for writing.
     ///
     /// Returns an error if the torrent id is invalid.
@@ -246,12 +410,14 @@ mod tests {
     use super::*;
     use crate::{block_count, FileInfo, BLOCK_LEN};
 
+    const READ_CACHE_LEN: usize = 10;
+
     /// Tests the allocation of a torrent, and then the allocation of the same
     /// torrent returning an error.
     #[tokio::test]
     async fn should_allocate_new_torrent() {
         let (tx, mut rx) = mpsc::unbounded_channel();
-        let (_, disk_tx) = spawn(tx).unwrap();
+        let (_, disk_tx) = spawn(tx, READ_CACHE_LEN).unwrap();
 
         let Env {
             id,
@@ -266,6 +432,7 @@ mod tests {
             .send(Command::NewTorrent {
                 id,
                 storage_info: info.clone(),
+                storage_mode: StorageMode::Sparse,
                 piece_hashes: piece_hashes.clone(),
                 torrent_tx: torrent_tx.clone(),
             })
@@ -289,6 +456,7 @@ mod tests {
             .send(Command::NewTorrent {
                 id,
                 storage_info: info,
+                storage_mode: StorageMode::Sparse,
                 piece_hashes,
                 torrent_tx: torrent_tx.clone(),
             })
@@ -305,12 +473,254 @@ mod tests {
         ));
     }
 
+    /// Tests that the files of a torrent allocated in full take up their whole
+    /// length on disk before any piece is written.
+    #[tokio::test]
+    async fn should_allocate_full_files() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (_, disk_tx) = spawn(tx, READ_CACHE_LEN).unwrap();
+
+        let Env {
+            id,
+            piece_hashes,
+            info,
+            torrent_tx,
+            ..
+        } = Env::new("allocate_full_files");
+
+        disk_tx
+            .send(Command::NewTorrent {
+                id,
+                storage_info: info.clone(),
+                storage_mode: StorageMode::Allocate,
+                piece_hashes,
+                torrent_tx,
+            })
+            .unwrap();
+        let alert = rx.recv().await.unwrap();
+        assert!(matches!(
+            alert,
+            engine::Command::TorrentAllocation { result: Ok(()), .. }
+        ));
+
+        let file = info.files.first().unwrap();
+        let metadata =
+            fs::metadata(info.download_dir.join(&file.path)).unwrap();
+        assert_eq!(metadata.len(), file.len);
+    }
+
+    /// Tests that the files of a torrent removed with the deletion of its files
+    /// are deleted, after which the engine is told.
+    #[tokio::test]
+    async fn should_delete_files_of_removed_torrent() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (_, disk_tx) = spawn(tx, READ_CACHE_LEN).unwrap();
+
+        let Env {
+            id,
+            piece_hashes,
+            info,
+            torrent_tx,
+            ..
+        } = Env::new("delete_files_of_removed_torrent");
+
+        disk_tx
+            .send(Command::NewTorrent {
+                id,
+                storage_info: info.clone(),
+                storage_mode: StorageMode::Sparse,
+                piece_hashes,
+                torrent_tx,
+            })
+            .unwrap();
+        rx.recv().await.expect("cannot allocate torrent");
+        let path = info.download_dir.join(&info.files[0].path);
+        assert!(path.is_file());
+
+        disk_tx
+            .send(Command::RemoveTorrent {
+                id,
+                delete_files: true,
+            })
+            .unwrap();
+        let alert = rx.recv().await.unwrap();
+        assert!(matches!(
+            alert,
+            engine::Command::FilesDeleted { result: Ok(()), .. }
+        ));
+        assert!(!path.exists());
+    }
+
+    /// Tests that a torrent's file is moved on disk when it's renamed, and
+    /// that renaming the root of a single file torrent renames its file.
+    #[tokio::test]
+    async fn should_rename_torrent_file() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (_, disk_tx) = spawn(tx, READ_CACHE_LEN).unwrap();
+
+        let Env {
+            id,
+            piece_hashes,
+            info,
+            torrent_tx,
+            ..
+        } = Env::new("rename_torrent_file");
+
+        disk_tx
+            .send(Command::NewTorrent {
+                id,
+                storage_info: info.clone(),
+                storage_mode: StorageMode::Sparse,
+                piece_hashes,
+                torrent_tx,
+            })
+            .unwrap();
+        rx.recv().await.expect("cannot allocate torrent");
+        let old_path = info.download_dir.join(&info.files[0].path);
+
+        let renamed_dir = "torrent_disk_test_rename_torrent_file_dir";
+        let rename = Rename::File {
+            index: 0,
+            path: Path::new(renamed_dir).join("file"),
+        };
+        disk_tx
+            .send(Command::Rename {
+                id,
+                rename: rename.clone(),
+            })
+            .unwrap();
+        match rx.recv().await.unwrap() {
+            engine::Command::StorageRenamed { result, .. } => {
+                assert_eq!(result.unwrap(), rename);
+            }
+            _ => panic!("unexpected engine command"),
+        }
+        assert!(!old_path.exists());
+        let renamed_path = info.download_dir.join(renamed_dir).join("file");
+        assert!(renamed_path.is_file());
+
+        // a path outside the torrent is rejected
+        disk_tx
+            .send(Command::Rename {
+                id,
+                rename: Rename::File {
+                    index: 0,
+                    path: PathBuf::from("../file"),
+                },
+            })
+            .unwrap();
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            engine::Command::StorageRenamed { result: Err(_), .. }
+        ));
+
+        let root_name = "torrent_disk_test_rename_torrent_file_root";
+        disk_tx
+            .send(Command::Rename {
+                id,
+                rename: Rename::Root(root_name.into()),
+            })
+            .unwrap();
+        match rx.recv().await.unwrap() {
+            engine::Command::StorageRenamed { result, .. } => {
+                assert_eq!(
+                    result.unwrap(),
+                    Rename::File {
+                        index: 0,
+                        path: PathBuf::from(root_name),
+                    }
+                );
+            }
+            _ => panic!("unexpected engine command"),
+        }
+        let root_path = info.download_dir.join(root_name);
+        assert!(root_path.is_file());
+        // the directory left empty is deleted
+        assert!(!info.download_dir.join(renamed_dir).exists());
+
+        fs::remove_file(&root_path).expect("cannot clean up disk test file");
+    }
+
+    /// Tests that checking a torrent's pieces reports only the pieces whose
+    /// data on disk is valid, including after the data is modified.
+    #[tokio::test]
+    async fn should_check_pieces_on_disk() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (_, disk_tx) = spawn(tx, READ_CACHE_LEN).unwrap();
+
+        let Env {
+            id,
+            pieces,
+            piece_hashes,
+            info,
+            torrent_tx,
+            mut torrent_rx,
+        } = Env::new("check_pieces_on_disk");
+
+        disk_tx
+            .send(Command::NewTorrent {
+                id,
+                storage_info: info.clone(),
+                storage_mode: StorageMode::Sparse,
+                piece_hashes,
+                torrent_tx,
+            })
+            .unwrap();
+        rx.recv().await.expect("cannot allocate torrent");
+
+        // write all but the last piece, so that the file is short
+        let piece_count = pieces.len();
+        for (index, piece) in pieces.iter().enumerate().take(piece_count - 1) {
+            for_each_block(index, piece.len() as u32, |block| {
+                let block_end = block.offset + block.len;
+                let data = &piece[block.offset as usize..block_end as usize];
+                disk_tx
+                    .send(Command::WriteBlock {
+                        id,
+                        block_info: block,
+                        data: data.to_vec(),
+                    })
+                    .unwrap();
+            });
+        }
+        disk_tx.send(Command::CheckPieces(id)).unwrap();
+        let mut checked_pieces = None;
+        while let Some(cmd) = torrent_rx.recv().await {
+            if let torrent::Command::PiecesChecked(pieces) = cmd {
+                checked_pieces = Some(pieces);
+                break;
+            }
+        }
+        let checked_pieces = checked_pieces.expect("pieces not checked");
+        assert_eq!(checked_pieces.len(), piece_count);
+        assert!(checked_pieces[..piece_count - 1].all());
+        assert!(!checked_pieces[piece_count - 1]);
+
+        // corrupt the first piece outside of the disk task
+        let path = info.download_dir.join(&info.files[0].path);
+        let mut content = fs::read(&path).unwrap();
+        content[0] = content[0].wrapping_add(1);
+        fs::write(&path, &content).unwrap();
+
+        disk_tx.send(Command::CheckPieces(id)).unwrap();
+        if let Some(torrent::Command::PiecesChecked(checked_pieces)) =
+            torrent_rx.recv().await
+        {
+            assert!(!checked_pieces[0]);
+            assert!(checked_pieces[1..piece_count - 1].all());
+        } else {
+            assert!(false, "pieces not checked");
+        }
+
+        fs::remove_file(&path).expect("cannot clean up disk test torrent file");
+    }
+
     /// Tests writing of a complete valid torrent's pieces and verifying that an
     /// alert of each disk write is returned by the disk task.
     #[tokio::test]
     async fn should_write_all_pieces() {
         let (tx, mut rx) = mpsc::unbounded_channel();
-        let (_, disk_tx) = spawn(tx).unwrap();
+        let (_, disk_tx) = spawn(tx, READ_CACHE_LEN).unwrap();
 
         let Env {
             id,
@@ -326,6 +736,7 @@ mod tests {
             .send(Command::NewTorrent {
                 id,
                 storage_info: info.clone(),
+                storage_mode: StorageMode::Sparse,
                 piece_hashes: piece_hashes.clone(),
                 torrent_tx: torrent_tx.clone(),
             })
@@ -351,7 +762,7 @@ mod tests {
             });
 
             // wait for disk write result
-            if let Some(torrent::Command::PieceCompletion(Ok(piece))) =
+            if let Some(torrent::Command::PieceCompletion(piece)) =
                 torrent_rx.recv().await
             {
                 // piece is complete so it should be hashed and valid
@@ -368,12 +779,64 @@ mod tests {
             .expect("cannot clean up disk test torrent file");
     }
 
+    /// Tests that the disk task finishes writing the pieces it was sent before
+    /// it shuts down.
+    #[tokio::test]
+    async fn should_flush_pending_writes_on_shutdown() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (join_handle, disk_tx) = spawn(tx, READ_CACHE_LEN).unwrap();
+
+        let Env {
+            id,
+            pieces,
+            piece_hashes,
+            info,
+            torrent_tx,
+            ..
+        } = Env::new("flush_on_shutdown");
+
+        disk_tx
+            .send(Command::NewTorrent {
+                id,
+                storage_info: info.clone(),
+                storage_mode: StorageMode::Sparse,
+                piece_hashes,
+                torrent_tx,
+            })
+            .unwrap();
+        rx.recv().await.expect("cannot allocate torrent");
+
+        // shut down without waiting for the write results
+        for (index, piece) in pieces.iter().enumerate() {
+            for_each_block(index, piece.len() as u32, |block| {
+                let block_end = block.offset + block.len;
+                let data = &piece[block.offset as usize..block_end as usize];
+                disk_tx
+                    .send(Command::WriteBlock {
+                        id,
+                        block_info: block,
+                        data: data.to_vec(),
+                    })
+                    .unwrap();
+            });
+        }
+        disk_tx.send(Command::Shutdown).unwrap();
+        join_handle.await.unwrap().unwrap();
+
+        // all pieces must be on disk by the time the task is done
+        let file = info.files.first().unwrap();
+        let path = info.download_dir.join(&file.path);
+        let content = fs::read(&path).expect("cannot read torrent file");
+        fs::remove_file(&path).expect("cannot clean up disk test torrent file");
+        assert_eq!(content, pieces.concat());
+    }
+
     /// Tests writing of an invalid piece and verifying that an alert of it
     /// is returned by the disk task.
     #[tokio::test]
     async fn should_reject_writing_invalid_piece() {
         let (tx, mut rx) = mpsc::unbounded_channel();
-        let (_, disk_tx) = spawn(tx).unwrap();
+        let (_, disk_tx) = spawn(tx, READ_CACHE_LEN).unwrap();
 
         let Env {
             id,
@@ -389,6 +852,7 @@ mod tests {
             .send(Command::NewTorrent {
                 id,
                 storage_info: info.clone(),
+                storage_mode: StorageMode::Sparse,
                 piece_hashes: piece_hashes.clone(),
                 torrent_tx: torrent_tx.clone(),
             })
@@ -416,7 +880,7 @@ mod tests {
         });
 
         // wait for disk write result
-        if let Some(torrent::Command::PieceCompletion(Ok(piece))) =
+        if let Some(torrent::Command::PieceCompletion(piece)) =
             torrent_rx.recv().await
         {
             assert_eq!(piece.index, index);
@@ -431,7 +895,7 @@ mod tests {
     #[tokio::test]
     async fn should_read_piece_blocks() {
         let (tx, mut rx) = mpsc::unbounded_channel();
-        let (_, disk_tx) = spawn(tx).unwrap();
+        let (_, disk_tx) = spawn(tx, READ_CACHE_LEN).unwrap();
 
         let Env {
             id,
@@ -447,6 +911,7 @@ mod tests {
             .send(Command::NewTorrent {
                 id,
                 storage_info: info.clone(),
+                storage_mode: StorageMode::Sparse,
                 piece_hashes: piece_hashes.clone(),
                 torrent_tx: torrent_tx.clone(),
             })
@@ -617,6 +1082,7 @@ mod tests {
                 last_piece_len,
                 download_len,
                 download_dir: download_dir.to_path_buf(),
+                is_archive: false,
                 files: vec![FileInfo {
                     path: download_rel_path,
                     torrent_offset: 0,
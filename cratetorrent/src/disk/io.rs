@@ -7,7 +7,6 @@ mod tests {
     use std::{
         collections::BTreeMap,
         fs,
-        io::Read,
         ops::Range,
         path::{Path, PathBuf},
         sync,
@@ -60,9 +59,7 @@ mod tests {
         assert!(tail.is_empty(), "not all blocks were written to disk");
 
         // read and compare
-        let mut file_content = Vec::new();
-        file.handle
-            .read_to_end(&mut file_content)
+        let file_content = fs::read(download_dir.join(&file.info.path))
             .expect("cannot read test file");
         assert_eq!(
             file_content,
@@ -99,10 +96,8 @@ mod tests {
             .expect("cannot write piece to file");
 
         // compare file content to piece
-        let mut file = files[0].write().unwrap();
-        let mut file_content = Vec::new();
-        file.handle
-            .read_to_end(&mut file_content)
+        let file = files[0].read().unwrap();
+        let file_content = fs::read(download_dir.join(&file.info.path))
             .expect("cannot read test file");
         assert_eq!(
             file_content,
@@ -186,6 +181,50 @@ mod tests {
             .expect("cannot remove test file");
     }
 
+    /// Tests that a piece written to a file in memory can be read back, and
+    /// that nothing is written to disk.
+    #[test]
+    fn should_write_and_read_piece_in_memory() {
+        let file_range = 0..1;
+        let piece = make_piece(file_range.clone());
+        let download_dir = Path::new(DOWNLOAD_DIR);
+        let file = TorrentFile::new_in_memory(FileInfo {
+            path: PathBuf::from("Piece_read_write_memory.test"),
+            torrent_offset: 0,
+            len: 2 * piece.len as u64,
+        });
+        let files = &[sync::RwLock::new(file)];
+
+        // nothing was written yet
+        let torrent_piece_offset = 0;
+        let result = piece::read(
+            torrent_piece_offset,
+            file_range.clone(),
+            files,
+            piece.len,
+        );
+        assert!(matches!(result, Err(ReadError::MissingData)));
+
+        piece
+            .write(torrent_piece_offset, files)
+            .expect("cannot write piece to memory");
+        let blocks =
+            piece::read(torrent_piece_offset, file_range, files, piece.len)
+                .expect("cannot read piece from memory");
+        let actual: Vec<_> = blocks
+            .iter()
+            .map(AsRef::as_ref)
+            .cloned()
+            .flatten()
+            .collect();
+        let expected: Vec<_> =
+            piece.blocks.values().flatten().copied().collect();
+        assert_eq!(actual, expected);
+
+        let path = download_dir.join(&files[0].read().unwrap().info.path);
+        assert!(!path.exists());
+    }
+
     /// Tests that writing piece to multiple files works.
     #[test]
     fn should_write_piece_to_multiple_files() {
@@ -234,10 +273,8 @@ mod tests {
 
         // compare contents of files to piece
         for file in files.iter() {
-            let mut file = file.write().unwrap();
-            let mut file_content = Vec::new();
-            file.handle
-                .read_to_end(&mut file_content)
+            let file = file.read().unwrap();
+            let file_content = fs::read(download_dir.join(&file.info.path))
                 .expect("cannot read test file");
             // compare the content of file to the portion that corresponds to
             // piece
@@ -325,6 +362,104 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    /// Tests that a piece downloaded in blocks of non-standard lengths is
+    /// complete once all its bytes are in the write buffer.
+    #[test]
+    fn should_complete_piece_with_non_standard_blocks() {
+        let piece = make_piece(0..1);
+        let data: Vec<u8> = piece.blocks.values().flatten().copied().collect();
+        let mut custom_piece = Piece {
+            expected_hash: piece.expected_hash,
+            len: piece.len,
+            blocks: BTreeMap::new(),
+            file_range: 0..1,
+        };
+
+        // the first block is 3 default blocks long, the second the remainder
+        let split = 3 * BLOCK_LEN as usize;
+        custom_piece.enqueue_block(0, data[..split].to_vec());
+        assert!(!custom_piece.is_complete());
+        custom_piece.enqueue_block(split as u32, data[split..].to_vec());
+        assert!(custom_piece.is_complete());
+        assert!(custom_piece.matches_hash());
+    }
+
+    /// Tests that only the parts of overlapping blocks that are not yet in the
+    /// write buffer are kept, so that the piece is not considered complete
+    /// before all its bytes are downloaded.
+    #[test]
+    fn should_trim_overlapping_blocks() {
+        let piece = make_piece(0..1);
+        let data: Vec<u8> = piece.blocks.values().flatten().copied().collect();
+        let mut custom_piece = Piece {
+            expected_hash: piece.expected_hash,
+            len: piece.len,
+            blocks: BTreeMap::new(),
+            file_range: 0..1,
+        };
+        let block =
+            |start: u32, end: u32| data[start as usize..end as usize].to_vec();
+
+        custom_piece.enqueue_block(BLOCK_LEN, block(BLOCK_LEN, 2 * BLOCK_LEN));
+        // the same block and one within it add nothing
+        custom_piece.enqueue_block(BLOCK_LEN, block(BLOCK_LEN, 2 * BLOCK_LEN));
+        custom_piece.enqueue_block(
+            BLOCK_LEN + 10,
+            block(BLOCK_LEN + 10, 2 * BLOCK_LEN - 10),
+        );
+        // a block overlapping the previous one from both sides only fills
+        // the gaps around it
+        custom_piece.enqueue_block(0, block(0, 3 * BLOCK_LEN));
+        assert_eq!(custom_piece.blocks.len(), 3);
+        assert!(!custom_piece.is_complete());
+
+        // a block extending past the end of the piece is truncated, and one
+        // entirely past it is dropped
+        custom_piece.enqueue_block(piece.len, vec![0; 10]);
+        custom_piece.enqueue_block(
+            3 * BLOCK_LEN - 100,
+            [block(3 * BLOCK_LEN - 100, piece.len), vec![0; 10]].concat(),
+        );
+        assert!(custom_piece.is_complete());
+        assert!(custom_piece.matches_hash());
+    }
+
+    /// Tests extracting blocks of arbitrary offsets and lengths from the
+    /// blocks of a piece read from disk.
+    #[test]
+    fn should_extract_block_from_piece() {
+        let piece = make_piece(0..1);
+        let data: Vec<u8> = piece.blocks.values().flatten().copied().collect();
+        let blocks: Vec<_> =
+            piece.blocks.values().cloned().map(sync::Arc::new).collect();
+
+        // an aligned block is returned as is
+        let block = piece::extract_block(&blocks, BLOCK_LEN, BLOCK_LEN)
+            .expect("block should be in piece");
+        assert!(sync::Arc::ptr_eq(&block, &blocks[1]));
+
+        // an unaligned block overlapping several blocks
+        let offset = BLOCK_LEN / 2;
+        let len = 2 * BLOCK_LEN + 100;
+        let block = piece::extract_block(&blocks, offset, len)
+            .expect("block should be in piece");
+        assert_eq!(
+            block.as_slice(),
+            &data[offset as usize..(offset + len) as usize]
+        );
+
+        // a block larger than the default block length up to the piece end
+        let block = piece::extract_block(&blocks, BLOCK_LEN, 3 * BLOCK_LEN)
+            .expect("block should be in piece");
+        assert_eq!(block.as_slice(), &data[BLOCK_LEN as usize..]);
+
+        // blocks not within the piece
+        assert!(piece::extract_block(&blocks, 3 * BLOCK_LEN, 2 * BLOCK_LEN)
+            .is_none());
+        assert!(piece::extract_block(&blocks, 4 * BLOCK_LEN, 1).is_none());
+        assert!(piece::extract_block(&blocks, 0, 0).is_none());
+    }
+
     /// Creates a piece for testing that has 4 blocks of length `BLOCK_LEN`.
     fn make_piece(files: Range<FileIndex>) -> Piece {
         let blocks = vec![
@@ -1,22 +1,110 @@
 use std::{
     fs::{File, OpenOptions},
+    io,
     os::unix::io::AsRawFd,
     path::Path,
 };
 
-use nix::sys::uio::{preadv, pwritev};
+use nix::{
+    fcntl::posix_fallocate,
+    sys::uio::{self, preadv, pwritev},
+};
 
 use crate::{
     disk::error::*,
     iovecs,
     iovecs::{IoVec, IoVecs},
+    lsd::nix_to_io_error,
     storage_info::FileSlice,
     FileInfo,
 };
 
 pub(crate) struct TorrentFile {
     pub info: FileInfo,
-    pub handle: File,
+    storage: Box<dyn FileStorage>,
+}
+
+/// Where a torrent file's data is kept.
+///
+/// The disk IO code only accesses a file's data through this trait, so
+/// storing files elsewhere than in the platform's file system, e.g. in
+/// a browser's storage, only takes another implementation of it.
+pub(crate) trait FileStorage: Send + Sync {
+    /// Writes the buffers at the offset in the file, and returns the number
+    /// of bytes written, which may be less than the buffers' length.
+    fn write_at(&mut self, bufs: &[&[u8]], offset: u64) -> io::Result<usize>;
+
+    /// Reads the file at the offset into the buffers, and returns the number
+    /// of bytes read, which is 0 past the end of the data written.
+    fn read_at(&self, bufs: &mut [&mut [u8]], offset: u64)
+        -> io::Result<usize>;
+
+    /// Reserves the length of the file, if the storage supports it.
+    fn allocate(&self, len: u64) -> io::Result<()>;
+
+    /// Makes sure the data written is persisted.
+    fn sync(&self) -> io::Result<()>;
+}
+
+/// The file on disk, accessed with vectored IO syscalls.
+struct DiskStorage(File);
+
+impl FileStorage for DiskStorage {
+    fn write_at(&mut self, bufs: &[&[u8]], offset: u64) -> io::Result<usize> {
+        let iovecs: Vec<_> =
+            bufs.iter().map(|buf| uio::IoVec::from_slice(buf)).collect();
+        pwritev(self.0.as_raw_fd(), &iovecs, offset as i64)
+            .map_err(nix_to_io_error)
+    }
+
+    fn read_at(
+        &self,
+        bufs: &mut [&mut [u8]],
+        offset: u64,
+    ) -> io::Result<usize> {
+        let mut iovecs: Vec<_> = bufs
+            .iter_mut()
+            .map(|buf| uio::IoVec::from_mut_slice(buf))
+            .collect();
+        preadv(self.0.as_raw_fd(), &mut iovecs, offset as i64)
+            .map_err(nix_to_io_error)
+    }
+
+    fn allocate(&self, len: u64) -> io::Result<()> {
+        posix_fallocate(self.0.as_raw_fd(), 0, len as i64)
+            .map_err(nix_to_io_error)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.0.sync_all()
+    }
+}
+
+/// A buffer in memory, which grows as pieces are written to it and is lost
+/// when the torrent is removed.
+struct MemoryStorage(Vec<u8>);
+
+impl FileStorage for MemoryStorage {
+    fn write_at(&mut self, bufs: &[&[u8]], offset: u64) -> io::Result<usize> {
+        Ok(write_to_memory(&mut self.0, bufs, offset as usize))
+    }
+
+    fn read_at(
+        &self,
+        bufs: &mut [&mut [u8]],
+        offset: u64,
+    ) -> io::Result<usize> {
+        Ok(read_from_memory(&self.0, bufs, offset as usize))
+    }
+
+    fn allocate(&self, _len: u64) -> io::Result<()> {
+        // memory is only taken up as the file is written
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 impl TorrentFile {
@@ -42,12 +130,44 @@ impl TorrentFile {
                 NewTorrentError::Io(e)
             })?;
         debug_assert!(path.exists());
-        Ok(Self { info, handle })
+        Ok(Self {
+            info,
+            storage: Box::new(DiskStorage(handle)),
+        })
+    }
+
+    /// Creates the file in memory, without touching the disk.
+    pub fn new_in_memory(info: FileInfo) -> Self {
+        log::trace!("Creating file {:?} in memory", info);
+        Self {
+            info,
+            storage: Box::new(MemoryStorage(Vec::new())),
+        }
+    }
+
+    /// Flushes the file's data to disk. This is a no-op for files in memory.
+    pub fn sync(&self) -> io::Result<()> {
+        self.storage.sync()
+    }
+
+    /// Allocates the whole length of the file on disk, if not already done.
+    pub fn allocate(&self) -> Result<(), NewTorrentError> {
+        log::trace!(
+            "Allocating {} bytes for file {:?}",
+            self.info.len,
+            self.info.path
+        );
+        if self.info.len == 0 {
+            return Ok(());
+        }
+        self.storage
+            .allocate(self.info.len)
+            .map_err(NewTorrentError::Io)
     }
 
     /// Writes to file at most the slice length number of bytes of blocks at the
     /// file slice's offset, using pwritev, called repeteadly until all blocks are
-    /// written to disk.
+    /// written to disk. Files in memory are written in one go.
     ///
     /// It returns the slice of blocks that weren't written to disk. That is, it
     /// returns the second half of `blocks` as though they were split at the
@@ -59,7 +179,7 @@ impl TorrentFile {
     /// Since the syscall may be invoked repeatedly to perform disk IO, this
     /// means that this operation is not guaranteed to be atomic.
     pub fn write<'a>(
-        &self,
+        &mut self,
         file_slice: FileSlice,
         blocks: &'a mut [IoVec<&'a [u8]>],
     ) -> Result<&'a mut [IoVec<&'a [u8]>], WriteError> {
@@ -80,15 +200,14 @@ impl TorrentFile {
         // transferred to disk (or an error occurs)
         let mut total_write_count = 0;
         while !iovecs.as_slice().is_empty() {
-            let write_count = pwritev(
-                self.handle.as_raw_fd(),
-                iovecs.as_slice(),
-                file_slice.offset as i64,
-            )
-            .map_err(|e| {
+            let bufs: Vec<_> =
+                iovecs.as_slice().iter().map(IoVec::as_slice).collect();
+            let write_count = self
+                .storage
+                .write_at(&bufs, file_slice.offset)
+                .map_err(|e| {
                 log::warn!("File {:?} write error: {}", self.info.path, e);
-                // FIXME: convert actual error here
-                WriteError::Io(std::io::Error::last_os_error())
+                WriteError::Io(e)
             })?;
 
             // tally up the total write count
@@ -111,7 +230,7 @@ impl TorrentFile {
 
     /// Reads from file at most the slice length number of bytes of blocks at
     /// the file slice's offset, using preadv, called repeteadly until all
-    /// blocks are read from disk.
+    /// blocks are read from disk. Files in memory are read in one go.
     ///
     /// It returns the slice of block buffers that weren't filled by the
     /// disk-read. That is, it returns the second half of `blocks` as though
@@ -138,16 +257,15 @@ impl TorrentFile {
         // transferred to disk (or an error occurs)
         let mut total_read_count = 0;
         while !iovecs.is_empty() && (total_read_count as u64) < file_slice.len {
-            let read_count = preadv(
-                self.handle.as_raw_fd(),
-                iovecs,
-                file_slice.offset as i64,
-            )
-            .map_err(|e| {
-                log::warn!("File {:?} read error: {}", self.info.path, e);
-                // FIXME: convert actual error here
-                ReadError::Io(std::io::Error::last_os_error())
-            })?;
+            let mut bufs: Vec<_> =
+                iovecs.iter_mut().map(iovec_as_mut_slice).collect();
+            let read_count = self
+                .storage
+                .read_at(&mut bufs, file_slice.offset)
+                .map_err(|e| {
+                    log::warn!("File {:?} read error: {}", self.info.path, e);
+                    ReadError::Io(e)
+                })?;
 
             // if there was nothing to read from file it means we tried to
             // read a piece from a portion of a file not yet downloaded or
@@ -167,3 +285,52 @@ impl TorrentFile {
         Ok(iovecs)
     }
 }
+
+/// Copies the buffers into memory at the offset, growing it if they extend
+/// past its end, and returns the number of bytes copied.
+fn write_to_memory(mem: &mut Vec<u8>, bufs: &[&[u8]], offset: usize) -> usize {
+    let mut pos = offset;
+    for buf in bufs.iter() {
+        let end = pos + buf.len();
+        if mem.len() < end {
+            mem.resize(end, 0);
+        }
+        mem[pos..end].copy_from_slice(buf);
+        pos = end;
+    }
+    pos - offset
+}
+
+/// Copies the memory starting at the offset into the buffers, until either
+/// runs out, and returns the number of bytes copied. Like reading past the
+/// end of a file, nothing is copied if the offset is past the memory's end.
+fn read_from_memory(
+    mem: &[u8],
+    bufs: &mut [&mut [u8]],
+    offset: usize,
+) -> usize {
+    let mut data = mem.get(offset..).unwrap_or(&[]);
+    let mut read_count = 0;
+    for buf in bufs.iter_mut() {
+        if data.is_empty() {
+            break;
+        }
+        let len = buf.len().min(data.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        data = &data[len..];
+        read_count += len;
+    }
+    read_count
+}
+
+/// Returns the buffer of a read `IoVec` as a mutable slice.
+fn iovec_as_mut_slice<'a>(iov: &'a mut IoVec<&mut [u8]>) -> &'a mut [u8] {
+    let buf = iov.as_slice();
+    // Safety: read buffers are created from mutable slices (which is why
+    // preadv may write to them), and `IoVec` only gives an immutable view of
+    // them, so the slice is reconstructed as mutable from its parts. The
+    // `IoVec` is borrowed mutably for as long as the slice is.
+    unsafe {
+        std::slice::from_raw_parts_mut(buf.as_ptr() as *mut u8, buf.len())
+    }
+}
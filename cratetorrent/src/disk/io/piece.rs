@@ -10,7 +10,7 @@ use crate::{
     block_count, block_len,
     disk::{error::*, io::file::TorrentFile},
     iovecs::IoVec,
-    CachedBlock, FileIndex, Sha1Hash,
+    CachedBlock, FileIndex, Sha1Hash, BLOCK_LEN,
 };
 
 /// An in-progress piece download that keeps in memory the so far downloaded
@@ -20,11 +20,12 @@ pub(crate) struct Piece {
     pub expected_hash: Sha1Hash,
     /// The length of the piece, in bytes.
     pub len: u32,
-    /// The so far downloaded blocks. Once the size of this map reaches the
-    /// number of blocks in piece, the piece is complete and, if the hash is
+    /// The so far downloaded blocks. Once the length of all blocks in this map
+    /// reaches the piece length, the piece is complete and, if the hash is
     /// correct, saved to disk.
     ///
-    /// Each block must be 16 KiB and is mapped to its offset within piece. A
+    /// Blocks may be of any length (though they are usually 16 KiB) and are
+    /// mapped to their offset within piece. A
     /// BTreeMap is used to keep blocks sorted by their offsets, which is
     /// important when iterating over the map to hash each block in the right
     /// order.
@@ -40,21 +41,62 @@ pub(crate) struct Piece {
 }
 
 impl Piece {
-    /// Places block into piece's write buffer if it doesn't exist. TODO: should
-    /// we return an error if it does?
-    pub fn enqueue_block(&mut self, offset: u32, data: Vec<u8>) {
-        use std::collections::btree_map::Entry;
-        let entry = self.blocks.entry(offset);
-        if matches!(entry, Entry::Occupied(_)) {
+    /// Places the block into the piece's write buffer.
+    ///
+    /// As the piece is considered complete once the length of its blocks adds
+    /// up to its own, only the parts of the block that are within the piece
+    /// and not already in the buffer are kept.
+    pub fn enqueue_block(&mut self, offset: u32, mut data: Vec<u8>) {
+        let end = self.len.min(offset.saturating_add(data.len() as u32));
+        if offset >= end {
+            log::warn!("Piece block at offset {} is out of bounds", offset);
+            return;
+        }
+
+        // collect the ranges of the block not covered by other blocks,
+        // starting with the block before it, which may extend into it
+        let first = self
+            .blocks
+            .range(..=offset)
+            .next_back()
+            .map(|(o, _)| *o)
+            .unwrap_or(offset);
+        let mut gaps = Vec::new();
+        let mut gap_start = offset;
+        for (block_offset, block) in self.blocks.range(first..end) {
+            let block_end = block_offset + block.len() as u32;
+            if *block_offset > gap_start {
+                gaps.push(gap_start..*block_offset);
+            }
+            gap_start = gap_start.max(block_end);
+        }
+        if gap_start < end {
+            gaps.push(gap_start..end);
+        }
+
+        if gaps.is_empty() {
             log::warn!("Duplicate piece block at offset {}", offset);
+        } else if gaps.len() == 1 && gaps[0].start == offset {
+            data.truncate((gaps[0].end - offset) as usize);
+            self.blocks.insert(offset, data);
         } else {
-            entry.or_insert(data);
+            log::warn!("Piece block at offset {} overlaps others", offset);
+            for gap in gaps {
+                let range =
+                    (gap.start - offset) as usize..(gap.end - offset) as usize;
+                self.blocks.insert(gap.start, data[range].to_vec());
+            }
         }
     }
 
     /// Returns true if the piece has all its blocks in its write buffer.
     pub fn is_complete(&self) -> bool {
-        self.blocks.len() == block_count(self.len)
+        self.downloaded_len() == self.len as usize
+    }
+
+    /// Returns the total length of the blocks in the write buffer.
+    fn downloaded_len(&self) -> usize {
+        self.blocks.values().map(Vec::len).sum()
     }
 
     /// Calculates the piece's hash using all its blocks and returns if it
@@ -67,7 +109,7 @@ impl Piece {
     pub fn matches_hash(&self) -> bool {
         // sanity check that we only call this method if we have all blocks in
         // piece
-        debug_assert_eq!(self.blocks.len(), block_count(self.len));
+        debug_assert_eq!(self.downloaded_len(), self.len as usize);
         let mut hasher = Sha1::new();
         for block in self.blocks.values() {
             hasher.update(&block);
@@ -108,7 +150,7 @@ impl Piece {
         let mut total_write_count = 0;
 
         for file in files.iter() {
-            let file = file.write().unwrap();
+            let mut file = file.write().unwrap();
 
             // determine which part of the file we need to write to
             debug_assert!(self.len as u64 > total_write_count);
@@ -220,3 +262,47 @@ pub(super) fn read(
 
     Ok(blocks)
 }
+
+/// Returns the block at the given offset and of the given length from the
+/// piece's blocks, as returned by [`read`].
+///
+/// If the block is exactly one of the blocks in the piece, it is returned
+/// without copying. Otherwise (if a peer requested a block of non-standard
+/// length, for example) the block is assembled from the blocks it overlaps.
+///
+/// `None` is returned if the block is not within the piece.
+pub(super) fn extract_block(
+    blocks: &[CachedBlock],
+    offset: u32,
+    len: u32,
+) -> Option<CachedBlock> {
+    if len == 0 {
+        return None;
+    }
+
+    // all blocks but the last are of the default length
+    let first = (offset / BLOCK_LEN) as usize;
+    let first_offset = (offset % BLOCK_LEN) as usize;
+    let block = blocks.get(first)?;
+    if first_offset >= block.len() {
+        return None;
+    }
+    if first_offset == 0 && block.len() == len as usize {
+        return Some(Arc::clone(block));
+    }
+
+    let mut buf = Vec::with_capacity(len as usize);
+    let mut block_offset = first_offset;
+    for block in blocks[first..].iter() {
+        let remaining = len as usize - buf.len();
+        let end = block.len().min(block_offset + remaining);
+        buf.extend_from_slice(&block[block_offset..end]);
+        if buf.len() == len as usize {
+            return Some(Arc::new(buf));
+        }
+        block_offset = 0;
+    }
+
+    // the block extends past the end of the piece
+    None
+}
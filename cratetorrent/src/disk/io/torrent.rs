@@ -1,6 +1,7 @@
 use std::{
     collections::{BTreeMap, HashMap},
-    fs,
+    fs, io,
+    path::{Path, PathBuf},
     sync::{
         self,
         atomic::{AtomicU64, AtomicUsize, Ordering},
@@ -8,8 +9,12 @@ use std::{
     },
 };
 
+use futures::{
+    future::FutureExt,
+    stream::{FuturesUnordered, StreamExt},
+};
 use lru::LruCache;
-use tokio::task;
+use sha1::{Digest, Sha1};
 
 use crate::{
     disk::{
@@ -18,11 +23,12 @@ use crate::{
             file::TorrentFile,
             piece::{self, Piece},
         },
+        PieceSender,
     },
-    peer,
-    storage_info::StorageInfo,
+    metrics, peer, rt,
+    storage_info::{Rename, StorageInfo, StorageMode},
     torrent::{self, PieceCompletion},
-    Block, BlockInfo, CachedBlock, PieceIndex,
+    Bitfield, Block, BlockInfo, CachedBlock, PieceIndex,
 };
 
 /// Torrent information related to disk IO.
@@ -32,12 +38,17 @@ use crate::{
 pub(crate) struct Torrent {
     /// All information concerning this torrent's storage.
     info: StorageInfo,
+    /// Whether the torrent's files are on disk or in memory.
+    storage_mode: StorageMode,
 
     /// The in-progress piece downloads and disk writes. This is the torrent's
     /// disk write buffer. Each piece is mapped to its index for faster lookups.
     // TODO(https://github.com/mandreyel/cratetorrent/issues/22): Currently
     // there is no upper bound on this.
     write_buf: HashMap<PieceIndex, Piece>,
+    /// The complete pieces being hashed and written on blocking threads, which
+    /// are waited for before the files are synced on shutdown.
+    pending_writes: FuturesUnordered<rt::JoinHandle<()>>,
 
     /// Contains the fields that may be accessed by other threads.
     ///
@@ -138,11 +149,40 @@ impl Torrent {
     /// For a single file, there is a path validity check and then the file is
     /// opened. For multi-file torrents, if there are any subdirectories in the
     /// torrent archive, they are created and all files are opened.
+    ///
+    /// If the torrent is kept in memory, nothing is created on disk.
     pub fn new(
         info: StorageInfo,
+        storage_mode: StorageMode,
         piece_hashes: Vec<u8>,
         torrent_tx: torrent::Sender,
+        read_cache_len: usize,
     ) -> Result<Self, NewTorrentError> {
+        // TODO: return error instead
+        debug_assert_ne!(info.files.len(), 0, "torrent must have files");
+        // files in memory need neither directories nor allocation
+        if storage_mode == StorageMode::Memory {
+            log::debug!(
+                "Keeping {} bytes of files in memory",
+                info.download_len
+            );
+            let files = info
+                .files
+                .iter()
+                .map(|file| {
+                    sync::RwLock::new(TorrentFile::new_in_memory(file.clone()))
+                })
+                .collect();
+            return Ok(Self::with_files(
+                info,
+                storage_mode,
+                files,
+                piece_hashes,
+                torrent_tx,
+                read_cache_len,
+            ));
+        }
+
         // TODO: since this is done as part of a tokio::task, should we use
         // tokio_fs here?
         if !info.download_dir.is_dir() {
@@ -154,8 +194,6 @@ impl Torrent {
             log::info!("Download directory {:?} created", info.download_dir);
         }
 
-        // TODO: return error instead
-        debug_assert_ne!(info.files.len(), 0, "torrent must have files");
         let files = if info.files.len() == 1 {
             let file = &info.files[0];
             log::debug!(
@@ -205,19 +243,48 @@ impl Torrent {
             torrent_files
         };
 
-        Ok(Self {
+        if storage_mode == StorageMode::Allocate {
+            log::debug!("Allocating {} bytes of files", info.download_len);
+            for file in files.iter() {
+                file.read().unwrap().allocate()?;
+            }
+        }
+
+        Ok(Self::with_files(
             info,
+            storage_mode,
+            files,
+            piece_hashes,
+            torrent_tx,
+            read_cache_len,
+        ))
+    }
+
+    /// Creates the torrent from its already opened files.
+    fn with_files(
+        info: StorageInfo,
+        storage_mode: StorageMode,
+        files: Vec<sync::RwLock<TorrentFile>>,
+        piece_hashes: Vec<u8>,
+        torrent_tx: torrent::Sender,
+        read_cache_len: usize,
+    ) -> Self {
+        Self {
+            info,
+            storage_mode,
             write_buf: HashMap::new(),
+            pending_writes: FuturesUnordered::new(),
             thread_ctx: Arc::new(ThreadContext {
                 tx: torrent_tx,
+                // the cache needs room for at least the piece being read
                 read_cache: sync::Mutex::new(LruCache::new(
-                    READ_CACHE_UPPER_BOUND,
+                    read_cache_len.max(1),
                 )),
                 files,
                 stats: Stats::default(),
             }),
             piece_hashes,
-        })
+        }
     }
 
     pub fn write_block(
@@ -257,7 +324,10 @@ impl Torrent {
             let torrent_piece_offset =
                 self.info.torrent_piece_offset(piece_index);
             let ctx = Arc::clone(&self.thread_ctx);
-            task::spawn_blocking(move || {
+            // the blocking thread doesn't inherit the command's span
+            let span = tracing::Span::current();
+            let write = rt::spawn_blocking(move || {
+                let _span = span.enter();
                 let is_piece_valid = piece.matches_hash();
 
                 // save piece to disk if it's valid
@@ -275,15 +345,16 @@ impl Torrent {
                             piece_index,
                             e
                         );
-                        // TODO(https://github.com/mandreyel/cratetorrent/issues/23):
-                        // also place back piece write buffer in torrent and
-                        // retry later
                         ctx.stats
                             .write_failure_count
                             .fetch_add(1, Ordering::Relaxed);
-                        // alert torrent of block write failure
+                        // alert torrent of the write failure, so that it
+                        // downloads the piece again
                         ctx.tx
-                            .send(torrent::Command::PieceCompletion(Err(e)))
+                            .send(torrent::Command::PieceWriteFailed {
+                                index: piece_index,
+                                error: e,
+                            })
                             .map_err(|e| {
                                 log::error!(
                                     "Error sending piece result: {}",
@@ -292,6 +363,7 @@ impl Torrent {
                                 e
                             })
                             .ok();
+                        metrics::disk_write_done();
                         return;
                     }
 
@@ -305,23 +377,217 @@ impl Torrent {
 
                 // alert torrent of piece completion and hash result
                 ctx.tx
-                    .send(torrent::Command::PieceCompletion(Ok(
-                        PieceCompletion {
-                            index: piece_index,
-                            is_valid: is_piece_valid,
-                        },
-                    )))
+                    .send(torrent::Command::PieceCompletion(PieceCompletion {
+                        index: piece_index,
+                        is_valid: is_piece_valid,
+                    }))
                     .map_err(|e| {
                         log::error!("Error sending piece result: {}", e);
                         e
                     })
                     .ok();
+                metrics::disk_write_done();
             });
+            metrics::disk_write_queued();
+            // forget the writes that are done, so that the list doesn't grow
+            let pending_writes = &mut self.pending_writes;
+            while let Some(Some(_)) = pending_writes.next().now_or_never() {}
+            pending_writes.push(write);
         }
 
         Ok(())
     }
 
+    /// Waits for the complete pieces to be written and syncs the torrent's
+    /// files, so that the pieces reported to the torrent as written are
+    /// really on disk, e.g. before resume data claiming them is saved.
+    ///
+    /// The blocks of incomplete pieces in the write buffer are dropped, as
+    /// they can't be verified.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        while let Some(result) = self.pending_writes.next().await {
+            if let Err(e) = result {
+                log::error!("Piece write task error: {}", e);
+            }
+        }
+        let ctx = Arc::clone(&self.thread_ctx);
+        rt::spawn_blocking(move || {
+            for file in ctx.files.iter() {
+                file.read().unwrap().sync()?;
+            }
+            Ok(())
+        })
+        .await
+        .expect("disk sync task has panicked")
+    }
+
+    /// Hashes all pieces on disk once the pieces being written are done, and
+    /// sends the torrent the pieces that are valid.
+    ///
+    /// This is used to verify the torrent's data after its files may have
+    /// been modified outside of the engine. The read cache is cleared, as its
+    /// pieces may no longer match the disk. Pieces that can't be read, e.g.
+    /// because their file is shorter than expected, are considered missing.
+    pub async fn check_pieces(&mut self) {
+        while let Some(result) = self.pending_writes.next().await {
+            if let Err(e) = result {
+                log::error!("Piece write task error: {}", e);
+            }
+        }
+        self.thread_ctx.read_cache.lock().unwrap().clear();
+
+        let info = self.info.clone();
+        let piece_hashes = self.piece_hashes.clone();
+        let ctx = Arc::clone(&self.thread_ctx);
+        let span = tracing::Span::current();
+        rt::spawn_blocking(move || {
+            let _span = span.enter();
+            let mut pieces = Bitfield::repeat(false, info.piece_count);
+            for index in 0..info.piece_count {
+                let piece_len = info.piece_len(index);
+                let blocks = match piece::read(
+                    info.torrent_piece_offset(index),
+                    info.files_intersecting_piece(index),
+                    &ctx.files[..],
+                    piece_len,
+                ) {
+                    Ok(blocks) => blocks,
+                    Err(e) => {
+                        log::debug!("Cannot read piece {}: {}", index, e);
+                        continue;
+                    }
+                };
+                ctx.stats
+                    .read_count
+                    .fetch_add(piece_len as u64, Ordering::Relaxed);
+
+                let mut hasher = Sha1::new();
+                for block in blocks.iter() {
+                    hasher.update(block.as_slice());
+                }
+                let hash_pos = index * 20;
+                let is_valid = hasher.finalize().as_slice()
+                    == &piece_hashes[hash_pos..hash_pos + 20];
+                pieces.set(index, is_valid);
+            }
+            log::info!(
+                "Checked pieces, {} of {} valid",
+                pieces.count_ones(),
+                info.piece_count
+            );
+            ctx.tx
+                .send(torrent::Command::PiecesChecked(pieces))
+                .map_err(|e| {
+                    log::error!("Error sending checked pieces: {}", e);
+                    e
+                })
+                .ok();
+        });
+    }
+
+    /// Deletes the torrent's files once the pieces being written are done.
+    ///
+    /// If the torrent is an archive, the directories that are left empty are
+    /// deleted too, including the torrent's own directory. Files that are
+    /// already gone are skipped. Files in memory are just dropped.
+    pub async fn delete_files(mut self) -> io::Result<()> {
+        while let Some(result) = self.pending_writes.next().await {
+            if let Err(e) = result {
+                log::error!("Piece write task error: {}", e);
+            }
+        }
+        // the files are closed before they are deleted
+        drop(self.thread_ctx);
+        if self.storage_mode == StorageMode::Memory {
+            return Ok(());
+        }
+        let info = self.info;
+        rt::spawn_blocking(move || {
+            for file in info.files.iter() {
+                let path = info.download_dir.join(&file.path);
+                log::debug!("Deleting file {:?}", path);
+                match fs::remove_file(&path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                        return Err(e)
+                    }
+                    _ => (),
+                }
+            }
+            if info.files.len() > 1 {
+                // the deepest directories are deleted first, so that their
+                // parents are empty by the time they are deleted
+                let mut dirs: Vec<_> = info
+                    .files
+                    .iter()
+                    .flat_map(|file| file.path.ancestors().skip(1))
+                    .map(|dir| info.download_dir.join(dir))
+                    .collect();
+                dirs.sort_unstable_by(|a, b| {
+                    let depth = |dir: &PathBuf| dir.components().count();
+                    depth(b).cmp(&depth(a)).then_with(|| a.cmp(b))
+                });
+                dirs.dedup();
+                for dir in dirs {
+                    // directories with other files in them are kept
+                    if let Err(e) = fs::remove_dir(&dir) {
+                        log::debug!("Not deleting dir {:?}: {}", dir, e);
+                    }
+                }
+            }
+            Ok(())
+        })
+        .await
+        .expect("disk delete task has panicked")
+    }
+
+    /// Moves a file of the torrent, or renames its root, along with the data
+    /// already written, and returns the rename as it was done.
+    ///
+    /// The files are moved while open, so pieces being written are written
+    /// to the new paths. Directories of an archive that are left empty by
+    /// moving a file out of them are deleted.
+    pub async fn rename(&mut self, rename: Rename) -> io::Result<Rename> {
+        if !rename.is_valid() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "new path must be relative and within the torrent",
+            ));
+        }
+        let old_info = self.info.clone();
+        let rename = self.info.rename(rename).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "invalid file index")
+        })?;
+        log::info!("Renaming torrent storage: {:?}", rename);
+
+        if self.storage_mode != StorageMode::Memory {
+            let (from, to) = match &rename {
+                Rename::File { index, .. } => (
+                    old_info.download_dir.join(&old_info.files[*index].path),
+                    self.info.download_dir.join(&self.info.files[*index].path),
+                ),
+                Rename::Root(_) => (
+                    old_info.download_dir.clone(),
+                    self.info.download_dir.clone(),
+                ),
+            };
+            let root = self.info.download_dir.clone();
+            let result =
+                rt::spawn_blocking(move || move_path(&from, &to, &root))
+                    .await
+                    .expect("disk rename task has panicked");
+            if let Err(e) = result {
+                self.info = old_info;
+                return Err(e);
+            }
+        }
+
+        if let Rename::File { index, path } = &rename {
+            self.thread_ctx.files[*index].write().unwrap().info.path =
+                path.clone();
+        }
+        Ok(rename)
+    }
+
     /// Starts a new in-progress piece, creating metadata for it in self.
     ///
     /// This involves getting the expected hash of the piece, its length, and
@@ -388,30 +654,36 @@ impl Torrent {
         log::trace!("Reading {} from disk", block_info);
 
         let piece_index = block_info.piece_index;
-        let block_index = block_info.index_in_piece();
 
         // check if piece is in the read cache
         if let Some(blocks) =
             self.thread_ctx.read_cache.lock().unwrap().get(&piece_index)
         {
             log::debug!("Piece {} is in the read cache", piece_index);
-            // the block's index in piece may be invalid
-            if block_index >= blocks.len() {
-                log::debug!(
-                    "Piece {} block offset {} is invalid",
-                    piece_index,
-                    block_info.offset
-                );
-                self.thread_ctx.tx.send(torrent::Command::ReadError {
-                    block_info,
-                    error: ReadError::InvalidBlockOffset,
-                })?;
-                // the disk task itself mustn't be aborted due to invalid input
-                return Ok(());
-            }
+            // the block's offset and length in piece may be invalid
+            let block = match piece::extract_block(
+                blocks,
+                block_info.offset,
+                block_info.len,
+            ) {
+                Some(block) => block,
+                None => {
+                    log::debug!(
+                        "Piece {} {} is invalid",
+                        piece_index,
+                        block_info
+                    );
+                    self.thread_ctx.tx.send(torrent::Command::ReadError {
+                        block_info,
+                        error: ReadError::InvalidBlockOffset,
+                    })?;
+                    // the disk task itself mustn't be aborted due to invalid
+                    // input
+                    return Ok(());
+                }
+            };
 
             // return block via sender
-            let block = Arc::clone(&blocks[block_index]);
             result_tx
                 .send(peer::Command::Block(Block::new(block_info, block)))?;
         } else {
@@ -432,7 +704,9 @@ impl Torrent {
                 self.info.torrent_piece_offset(piece_index);
             let piece_len = self.info.piece_len(piece_index);
             let ctx = Arc::clone(&self.thread_ctx);
-            task::spawn_blocking(move || {
+            let span = tracing::Span::current();
+            rt::spawn_blocking(move || {
+                let _span = span.enter();
                 match piece::read(
                     torrent_piece_offset,
                     file_range,
@@ -442,7 +716,11 @@ impl Torrent {
                     Ok(blocks) => {
                         log::debug!("Read piece {}", piece_index);
                         // pick requested block
-                        let block = Arc::clone(&blocks[block_index]);
+                        let block = piece::extract_block(
+                            &blocks,
+                            block_info.offset,
+                            block_info.len,
+                        );
 
                         // Place piece in read cache. Another concurrent read
                         // could already have read the piece just before this
@@ -453,19 +731,41 @@ impl Torrent {
                             .read_count
                             .fetch_add(piece_len as u64, Ordering::Relaxed);
 
-                        // send block to peer
-                        result_tx
-                            .send(peer::Command::Block(Block::new(
-                                block_info, block,
-                            )))
-                            .map_err(|e| {
-                                log::error!(
-                                    "Error sending block to peer: {}",
+                        // send block to peer, or the error if the block is
+                        // not within the piece
+                        if let Some(block) = block {
+                            result_tx
+                                .send(peer::Command::Block(Block::new(
+                                    block_info, block,
+                                )))
+                                .map_err(|e| {
+                                    log::error!(
+                                        "Error sending block to peer: {}",
+                                        e
+                                    );
                                     e
-                                );
-                                e
-                            })
-                            .ok();
+                                })
+                                .ok();
+                        } else {
+                            log::debug!(
+                                "Piece {} {} is invalid",
+                                piece_index,
+                                block_info
+                            );
+                            ctx.tx
+                                .send(torrent::Command::ReadError {
+                                    block_info,
+                                    error: ReadError::InvalidBlockOffset,
+                                })
+                                .map_err(|e| {
+                                    log::error!(
+                                        "Error sending read error: {}",
+                                        e
+                                    );
+                                    e
+                                })
+                                .ok();
+                        }
                     }
                     Err(e) => {
                         log::error!(
@@ -494,8 +794,93 @@ impl Torrent {
 
         Ok(())
     }
+
+    /// Reads the whole piece, from the read cache if it's there, and sends
+    /// its blocks, or the error if it can't be read, via the sender.
+    ///
+    /// The piece is expected to have been downloaded.
+    pub fn read_piece(&self, piece_index: PieceIndex, result_tx: PieceSender) {
+        log::trace!("Reading piece {} from disk", piece_index);
+
+        if piece_index >= self.info.piece_count {
+            log::debug!("Piece {} is invalid", piece_index);
+            // the reader may have been dropped in the meantime
+            result_tx.send(Err(ReadError::InvalidPieceIndex)).ok();
+            return;
+        }
+
+        if let Some(blocks) =
+            self.thread_ctx.read_cache.lock().unwrap().get(&piece_index)
+        {
+            log::debug!("Piece {} is in the read cache", piece_index);
+            result_tx.send(Ok(blocks.clone())).ok();
+            return;
+        }
+
+        let file_range = self.info.files_intersecting_piece(piece_index);
+        let torrent_piece_offset = self.info.torrent_piece_offset(piece_index);
+        let piece_len = self.info.piece_len(piece_index);
+        let ctx = Arc::clone(&self.thread_ctx);
+        let span = tracing::Span::current();
+        rt::spawn_blocking(move || {
+            let _span = span.enter();
+            let result = piece::read(
+                torrent_piece_offset,
+                file_range,
+                &ctx.files[..],
+                piece_len,
+            );
+            match &result {
+                Ok(blocks) => {
+                    log::debug!("Read piece {}", piece_index);
+                    ctx.read_cache
+                        .lock()
+                        .unwrap()
+                        .put(piece_index, blocks.clone());
+                    ctx.stats
+                        .read_count
+                        .fetch_add(piece_len as u64, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    log::error!(
+                        "Error reading piece {} from disk: {}",
+                        piece_index,
+                        e
+                    );
+                    ctx.stats
+                        .read_failure_count
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            result_tx.send(result).ok();
+        });
+    }
 }
 
-// TODO(https://github.com/mandreyel/cratetorrent/issues/22):
-// make this configurable
-const READ_CACHE_UPPER_BOUND: usize = 1000;
+/// Moves the file or directory, creating the new parent directories, and
+/// then deletes the old parent directories within the root that were left
+/// empty.
+///
+/// Unlike a plain rename, an existing file or directory at the new path is
+/// not overwritten.
+fn move_path(from: &Path, to: &Path, root: &Path) -> io::Result<()> {
+    log::debug!("Moving {:?} to {:?}", from, to);
+    if to.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "new path already exists",
+        ));
+    }
+    if let Some(dir) = to.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::rename(from, to)?;
+    for dir in from.ancestors().skip(1) {
+        // directories with other files in them are kept
+        if dir == root || !dir.starts_with(root) || fs::remove_dir(dir).is_err()
+        {
+            break;
+        }
+    }
+    Ok(())
+}
@@ -1,11 +1,16 @@
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 
-use crate::{block_count, block_len, BlockInfo, PieceIndex, BLOCK_LEN};
+use crate::{logging, peer, BlockInfo, PieceIndex};
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub(crate) enum BlockStatus {
     Free,
-    Requested,
+    /// The block was requested at the given time.
+    Requested(Instant),
     Received,
 }
 
@@ -22,23 +27,95 @@ pub(crate) struct PieceDownload {
     index: PieceIndex,
     /// The piece's length in bytes.
     len: u32,
+    /// The length of the blocks in which the piece is downloaded. The last
+    /// block may be shorter.
+    block_len: u32,
     /// The blocks in this piece, tracking which are downloaded, pending, or
     /// received. The vec is preallocated to the number of blocks in piece.
     blocks: Vec<BlockStatus>,
+    /// The peers that sent us blocks of this piece. If the piece turns out to
+    /// be corrupt, these are the suspects.
+    contributors: HashSet<SocketAddr>,
+    /// If set, only this peer may download the piece. This is used to download
+    /// a whole piece from a peer on parole, so that if the piece is corrupt,
+    /// we know for sure who sent the bad data.
+    exclusive_peer: Option<SocketAddr>,
 }
 
 impl PieceDownload {
-    /// Creates a new piece download instance for the given piece.
-    pub fn new(index: PieceIndex, len: u32) -> Self {
-        let block_count = block_count(len);
+    /// Creates a new piece download instance for the given piece, that is
+    /// downloaded in blocks of the given length.
+    pub fn new(index: PieceIndex, len: u32, block_len: u32) -> Self {
+        debug_assert!(block_len > 0);
+        // the last block may be shorter, so round up
+        let block_count =
+            (len as usize + (block_len as usize - 1)) / block_len as usize;
         let mut blocks = Vec::new();
         blocks.resize_with(block_count, Default::default);
-        Self { index, len, blocks }
+        Self {
+            index,
+            len,
+            block_len,
+            blocks,
+            contributors: HashSet::new(),
+            exclusive_peer: None,
+        }
+    }
+
+    /// Returns the peers that sent us blocks of this piece.
+    pub fn contributors(&self) -> &HashSet<SocketAddr> {
+        &self.contributors
+    }
+
+    /// Records that the peer sent us a block of this piece.
+    pub fn add_contributor(&mut self, addr: SocketAddr) {
+        self.contributors.insert(addr);
+    }
+
+    /// Returns the peer that has exclusive access to the piece, if any.
+    pub fn exclusive_peer(&self) -> Option<SocketAddr> {
+        self.exclusive_peer
+    }
+
+    /// Sets or clears the peer that has exclusive access to the piece.
+    pub fn set_exclusive_peer(&mut self, addr: Option<SocketAddr>) {
+        self.exclusive_peer = addr;
+    }
+
+    /// Returns whether the peer may download blocks of this piece, which is
+    /// the case unless the piece is exclusive to another peer.
+    pub fn is_available_to(&self, addr: SocketAddr) -> bool {
+        self.exclusive_peer.map(|p| p == addr).unwrap_or(true)
+    }
+
+    /// Returns whether any block of the piece is currently requested.
+    pub fn has_requested_blocks(&self) -> bool {
+        self.blocks
+            .iter()
+            .any(|b| matches!(b, BlockStatus::Requested(_)))
     }
 
-    /// Returns the index of the piece that is downloaded.
-    pub fn piece_index(&self) -> PieceIndex {
-        self.index
+    /// Returns whether any block of the piece has been received.
+    pub fn has_received_blocks(&self) -> bool {
+        self.blocks.iter().any(|b| *b == BlockStatus::Received)
+    }
+
+    /// Returns the number of blocks that are neither requested nor received.
+    pub fn count_free_blocks(&self) -> usize {
+        self.blocks
+            .iter()
+            .filter(|b| **b == BlockStatus::Free)
+            .count()
+    }
+
+    /// Returns whether the block is one of the blocks of this download, that
+    /// is, whether it is aligned to the block length and its length is that of
+    /// the block at its offset.
+    pub fn contains_block(&self, block: &BlockInfo) -> bool {
+        block.piece_index == self.index
+            && block.offset % self.block_len == 0
+            && block.offset < self.len
+            && block.len == self.nth_block_len(self.block_index(block))
     }
 
     /// Picks the requested number of blocks or fewer, if fewer are remaining.
@@ -48,64 +125,62 @@ impl PieceDownload {
         count: usize,
         pick_buf: &mut Vec<BlockInfo>,
         in_end_game: bool,
-        prev_picked: &HashSet<BlockInfo>,
+        prev_picked: &HashMap<BlockInfo, Instant>,
     ) {
         log::trace!(
+            target: logging::TORRENT,
             "Trying to pick {} block(s) in piece {} (length: {}, blocks: {})",
             count,
             self.index,
             self.len,
-            self.blocks.len(),
+            self.blocks.len()
         );
 
         let mut picked = 0;
+        let now = Instant::now();
 
-        for (i, block) in self.blocks.iter_mut().enumerate() {
+        for i in 0..self.blocks.len() {
             // don't pick more than requested
             if picked == count {
                 break;
             }
 
             // only pick block if it's free
-            if *block == BlockStatus::Free {
-                pick_buf.push(BlockInfo {
-                    piece_index: self.index,
-                    offset: i as u32 * BLOCK_LEN,
-                    len: block_len(self.len, i),
-                });
-                *block = BlockStatus::Requested;
+            let block = self.blocks[i];
+            if block == BlockStatus::Free {
+                pick_buf.push(self.nth_block(i));
+                self.blocks[i] = BlockStatus::Requested(now);
                 picked += 1;
-            } else if in_end_game && *block == BlockStatus::Requested {
+            } else if in_end_game && matches!(block, BlockStatus::Requested(_))
+            {
                 // in endgame it's fair to pick blocks already requested but
                 // don't pick the same block twice from the same peer
-                let block_info = BlockInfo {
-                    piece_index: self.index,
-                    offset: i as u32 * BLOCK_LEN,
-                    len: block_len(self.len, i),
-                };
+                let block_info = self.nth_block(i);
                 // TODO: we could probably optimize this by saving some cheap
                 // peer id in the block metadata and check if peer is present
                 // (we'll need something like this for parole downloads at some
                 // point anyway)
-                if !prev_picked.contains(&block_info) {
+                if !prev_picked.contains_key(&block_info) {
                     pick_buf.push(block_info);
                     picked += 1;
                 }
             }
-
-            // TODO(https://github.com/mandreyel/cratetorrent/issues/18): if we
-            // requested block too long ago, time out block
         }
 
         if picked > 0 {
             log::trace!(
+                target: logging::TORRENT,
                 "Picked {} block(s) for piece {}: {:?}",
                 picked,
                 self.index,
                 &pick_buf[pick_buf.len() - picked..]
             );
         } else {
-            log::trace!("Cannot pick any blocks in piece {}", self.index);
+            log::trace!(
+                target: logging::TORRENT,
+                "Cannot pick any blocks in piece {}",
+                self.index
+            );
         }
     }
 
@@ -114,7 +189,12 @@ impl PieceDownload {
     /// The previous status of the block is returned. This can be used to check
     /// whether the block has already been downloaded, for example.
     pub fn received_block(&mut self, block: &BlockInfo) -> BlockStatus {
-        log::trace!("Received piece {} block {:?}", self.index, block);
+        log::trace!(
+            target: logging::TORRENT,
+            "Received piece {} block {:?}",
+            self.index,
+            block
+        );
 
         // TODO(https://github.com/mandreyel/cratetorrent/issues/16): this
         // information is sanitized in PeerSession but maybe we want to return
@@ -123,32 +203,37 @@ impl PieceDownload {
         debug_assert!(block.offset < self.len);
         debug_assert!(block.len <= self.len);
 
-        // we should only receive blocks that we have requested before
-        debug_assert!(matches!(
-            self.blocks[block.index_in_piece()],
-            BlockStatus::Requested
-        ));
+        // NOTE: the block may already be free again if its request timed out
+        // and the peer sent it late, so we can't assert that it was requested
 
         // TODO(https://github.com/mandreyel/cratetorrent/issues/9): record
         // rount trip time for this block
 
-        let block = &mut self.blocks[block.index_in_piece()];
+        let i = self.block_index(block);
+        let block = &mut self.blocks[i];
         let prev_status = *block;
         *block = BlockStatus::Received;
         prev_status
     }
 
-    /// Marks all blocks free to be requested again.
-    pub fn free_all_blocks(&mut self) {
-        log::trace!("Canceling all blocks in piece {}", self.index);
+    /// Marks all blocks free to be requested again and forgets the peers
+    /// that contributed to the piece, as well as its exclusivity.
+    ///
+    /// This is used when the piece fails the hash check and so needs to be
+    /// downloaded anew.
+    pub fn reset(&mut self) {
+        log::trace!(target: logging::TORRENT, "Resetting piece {}", self.index);
         for block in self.blocks.iter_mut() {
             *block = BlockStatus::Free;
         }
+        self.contributors.clear();
+        self.exclusive_peer = None;
     }
 
     /// Marks a previously requested block free to request again.
     pub fn free_block(&mut self, block: &BlockInfo) {
         log::trace!(
+            target: logging::TORRENT,
             "Canceling request for piece {} block {:?}",
             self.index,
             block
@@ -161,15 +246,134 @@ impl PieceDownload {
         debug_assert!(block.offset < self.len);
         debug_assert!(block.len <= self.len);
 
-        self.blocks[block.index_in_piece()] = BlockStatus::Free;
+        let i = self.block_index(block);
+        self.blocks[i] = BlockStatus::Free;
+    }
+
+    /// Marks all blocks that were requested longer than `timeout` ago free to
+    /// request again, and returns them.
+    ///
+    /// This is so that blocks requested from a peer that is still connected
+    /// but has stopped responding can be picked from other peers, rather than
+    /// having to wait for that peer to be disconnected.
+    pub fn free_timed_out_blocks(
+        &mut self,
+        now: Instant,
+        timeout: Duration,
+    ) -> Vec<BlockInfo> {
+        let mut freed = Vec::new();
+        for i in 0..self.blocks.len() {
+            if let BlockStatus::Requested(requested_at) = self.blocks[i] {
+                if now.saturating_duration_since(requested_at) >= timeout {
+                    self.blocks[i] = BlockStatus::Free;
+                    freed.push(self.nth_block(i));
+                }
+            }
+        }
+        if !freed.is_empty() {
+            log::debug!(
+                target: logging::TORRENT,
+                "Freed {} timed out block(s) in piece {}",
+                freed.len(),
+                self.index
+            );
+        }
+        freed
+    }
+
+    /// Returns the index of the block within the piece.
+    fn block_index(&self, block: &BlockInfo) -> usize {
+        (block.offset / self.block_len) as usize
+    }
+
+    /// Returns the length of the block at the given index, which is only
+    /// shorter than the block length if it's the last block in the piece.
+    fn nth_block_len(&self, index: usize) -> u32 {
+        let offset = index as u32 * self.block_len;
+        debug_assert!(offset < self.len);
+        (self.len - offset).min(self.block_len)
+    }
+
+    /// Returns the block at the given index.
+    fn nth_block(&self, index: usize) -> BlockInfo {
+        BlockInfo {
+            piece_index: self.index,
+            offset: index as u32 * self.block_len,
+            len: self.nth_block_len(index),
+        }
+    }
+}
+
+/// The block requests in flight in a torrent, with the peers from which they
+/// were requested.
+///
+/// This is shared by all peer sessions in the torrent so that, outside of
+/// endgame mode, a block is not requested from more than one peer, and so that
+/// when a block requested from multiple peers arrives, the requests to the
+/// other peers can be cancelled right away.
+#[derive(Default)]
+pub(crate) struct InFlightRequests {
+    /// The peers to which each block's request was sent, with their session's
+    /// command channel, through which the requests are cancelled.
+    requests: HashMap<BlockInfo, Vec<(SocketAddr, peer::Sender)>>,
+}
+
+impl InFlightRequests {
+    /// Records that the block was requested from the peer.
+    pub fn insert(
+        &mut self,
+        block: BlockInfo,
+        addr: SocketAddr,
+        tx: peer::Sender,
+    ) {
+        let peers = self.requests.entry(block).or_insert_with(Vec::new);
+        if !peers.iter().any(|(a, _)| *a == addr) {
+            peers.push((addr, tx));
+        }
+    }
+
+    /// Returns whether the block is requested from a peer other than the one
+    /// given.
+    pub fn is_requested_by_other(
+        &self,
+        block: &BlockInfo,
+        addr: SocketAddr,
+    ) -> bool {
+        self.requests
+            .get(block)
+            .map(|peers| peers.iter().any(|(a, _)| *a != addr))
+            .unwrap_or(false)
+    }
+
+    /// Removes the peer's request of the block and returns whether the block
+    /// is still requested from other peers.
+    pub fn remove(&mut self, block: &BlockInfo, addr: SocketAddr) -> bool {
+        if let Some(peers) = self.requests.get_mut(block) {
+            peers.retain(|(a, _)| *a != addr);
+            if !peers.is_empty() {
+                return true;
+            }
+            self.requests.remove(block);
+        }
+        false
+    }
+
+    /// Removes all requests of the block, returning the peers from which it
+    /// was requested.
+    pub fn take(
+        &mut self,
+        block: &BlockInfo,
+    ) -> Vec<(SocketAddr, peer::Sender)> {
+        self.requests.remove(block).unwrap_or_default()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashSet;
+    use std::{collections::HashMap, time::Instant};
 
     use super::*;
+    use crate::{block_count, BLOCK_LEN};
 
     /// Tests that repeatedly requesting as many blocks as are in the piece
     /// returns all blocks, none of them previously picked.
@@ -180,9 +384,9 @@ mod tests {
         let block_count = block_count(piece_len);
         let in_end_game = false;
 
-        let mut download = PieceDownload::new(index, piece_len);
+        let mut download = PieceDownload::new(index, piece_len, BLOCK_LEN);
         // save picked blocks
-        let mut picked = HashSet::with_capacity(block_count);
+        let mut picked = HashMap::with_capacity(block_count);
 
         // pick all blocks one by one
         for _ in 0..block_count {
@@ -191,15 +395,15 @@ mod tests {
             assert_eq!(picked_blocks.len(), 1);
             let block = *picked_blocks.first().unwrap();
             // assert that this block hasn't been picked before
-            assert!(!picked.contains(&block));
+            assert!(!picked.contains_key(&block));
             // mark block as picked
-            picked.insert(block);
+            picked.insert(block, Instant::now());
         }
 
         // assert that we picked all blocks
         assert_eq!(picked.len(), block_count);
         for block in download.blocks.iter() {
-            assert!(matches!(block, BlockStatus::Requested));
+            assert!(matches!(block, BlockStatus::Requested(_)));
         }
     }
 
@@ -211,7 +415,8 @@ mod tests {
         let piece_len = 6 * BLOCK_LEN;
         let in_end_game = false;
 
-        let mut download = PieceDownload::new(piece_index, piece_len);
+        let mut download =
+            PieceDownload::new(piece_index, piece_len, BLOCK_LEN);
 
         // pick all blocks
         let block_count = block_count(piece_len);
@@ -220,13 +425,13 @@ mod tests {
             block_count,
             &mut picked_blocks,
             in_end_game,
-            &HashSet::new(),
+            &HashMap::new(),
         );
         assert_eq!(picked_blocks.len(), block_count);
 
         // assert that we picked all blocks
         for block in download.blocks.iter() {
-            assert!(matches!(block, BlockStatus::Requested));
+            assert!(matches!(block, BlockStatus::Requested(_)));
         }
     }
 
@@ -239,14 +444,15 @@ mod tests {
         let block_count = block_count(piece_len);
         let in_end_game = false;
 
-        let mut download = PieceDownload::new(piece_index, piece_len);
+        let mut download =
+            PieceDownload::new(piece_index, piece_len, BLOCK_LEN);
 
         let mut picked_blocks = Vec::new();
         download.pick_blocks(
             block_count,
             &mut picked_blocks,
             in_end_game,
-            &HashSet::new(),
+            &HashMap::new(),
         );
         assert_eq!(picked_blocks.len(), block_count);
 
@@ -260,7 +466,7 @@ mod tests {
             block_count,
             &mut picked_blocks,
             in_end_game,
-            &HashSet::new(),
+            &HashMap::new(),
         );
         assert!(picked_blocks.is_empty());
     }
@@ -273,7 +479,8 @@ mod tests {
         let piece_len = 6 * BLOCK_LEN;
         let in_end_game = false;
 
-        let mut download = PieceDownload::new(piece_index, piece_len);
+        let mut download =
+            PieceDownload::new(piece_index, piece_len, BLOCK_LEN);
 
         // pick 4 blocks
         let picked_block_indices = [0, 1, 2, 3];
@@ -282,7 +489,7 @@ mod tests {
             picked_block_indices.len(),
             &mut picked_blocks,
             in_end_game,
-            &HashSet::new(),
+            &HashMap::new(),
         );
         assert_eq!(picked_blocks.len(), picked_block_indices.len());
 
@@ -311,7 +518,7 @@ mod tests {
             block_count,
             &mut picked_blocks,
             in_end_game,
-            &HashSet::new(),
+            &HashMap::new(),
         );
         assert_eq!(
             picked_blocks.len(),
@@ -328,7 +535,8 @@ mod tests {
         let block_count = block_count(piece_len);
         let in_end_game = true;
 
-        let mut download = PieceDownload::new(piece_index, piece_len);
+        let mut download =
+            PieceDownload::new(piece_index, piece_len, BLOCK_LEN);
 
         // pick all blocks multiple times
         for _ in 0..2 {
@@ -337,12 +545,108 @@ mod tests {
                 block_count,
                 &mut picked_blocks,
                 in_end_game,
-                &HashSet::new(),
+                &HashMap::new(),
             );
             assert_eq!(picked_blocks.len(), block_count);
         }
     }
 
+    /// Tests that free blocks are counted correctly as they are picked and
+    /// freed.
+    #[test]
+    fn should_count_free_blocks() {
+        let piece_len = 4 * BLOCK_LEN;
+        let mut download = PieceDownload::new(0, piece_len, BLOCK_LEN);
+        assert_eq!(download.count_free_blocks(), 4);
+        assert!(!download.has_requested_blocks());
+        assert!(!download.has_received_blocks());
+
+        let mut picked_blocks = Vec::new();
+        download.pick_blocks(3, &mut picked_blocks, false, &HashMap::new());
+        assert_eq!(download.count_free_blocks(), 1);
+        assert!(download.has_requested_blocks());
+
+        download.received_block(&picked_blocks[0]);
+        download.free_block(&picked_blocks[1]);
+        assert_eq!(download.count_free_blocks(), 2);
+        assert!(download.has_received_blocks());
+
+        download.free_block(&picked_blocks[2]);
+        assert!(!download.has_requested_blocks());
+    }
+
+    /// Tests that only blocks whose requests have timed out are freed, and
+    /// that they can be picked again afterwards.
+    #[test]
+    fn should_free_timed_out_blocks() {
+        let piece_len = 4 * BLOCK_LEN;
+        let timeout = Duration::from_secs(30);
+        let mut download = PieceDownload::new(0, piece_len, BLOCK_LEN);
+
+        let mut picked_blocks = Vec::new();
+        download.pick_blocks(3, &mut picked_blocks, false, &HashMap::new());
+        download.received_block(&picked_blocks[0]);
+        assert_eq!(download.count_free_blocks(), 1);
+
+        // nothing is freed before the timeout elapses
+        let now = Instant::now();
+        assert!(download.free_timed_out_blocks(now, timeout).is_empty());
+        assert_eq!(download.count_free_blocks(), 1);
+
+        // only the two pending requests are freed, not the received block
+        let later = now + timeout;
+        assert_eq!(
+            download.free_timed_out_blocks(later, timeout),
+            &picked_blocks[1..]
+        );
+        assert_eq!(download.count_free_blocks(), 3);
+
+        let mut repicked_blocks = Vec::new();
+        download.pick_blocks(3, &mut repicked_blocks, false, &HashMap::new());
+        assert_eq!(repicked_blocks.len(), 3);
+        assert_eq!(&repicked_blocks[..2], &picked_blocks[1..]);
+
+        // a block arriving late for a freed request is still accepted
+        download.free_timed_out_blocks(later + timeout, timeout);
+        assert_eq!(
+            download.received_block(&picked_blocks[1]),
+            BlockStatus::Free
+        );
+        assert_eq!(download.count_free_blocks(), 2);
+    }
+
+    /// Tests that the in-flight requests of a block are tracked per peer.
+    #[test]
+    fn should_track_in_flight_requests() {
+        let block = BlockInfo {
+            piece_index: 0,
+            offset: 0,
+            len: BLOCK_LEN,
+        };
+        let addr1 = "1.1.1.1:6881".parse().unwrap();
+        let addr2 = "2.2.2.2:6881".parse().unwrap();
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut requests = InFlightRequests::default();
+
+        requests.insert(block, addr1, tx.clone());
+        assert!(!requests.is_requested_by_other(&block, addr1));
+        assert!(requests.is_requested_by_other(&block, addr2));
+
+        requests.insert(block, addr2, tx);
+        assert!(requests.remove(&block, addr1));
+        assert!(!requests.remove(&block, addr2));
+        assert!(!requests.is_requested_by_other(&block, addr1));
+
+        // once the block arrives, all its requests are removed
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        requests.insert(block, addr1, tx.clone());
+        requests.insert(block, addr2, tx);
+        let peers: Vec<_> =
+            requests.take(&block).into_iter().map(|(a, _)| a).collect();
+        assert_eq!(peers, vec![addr1, addr2]);
+        assert!(requests.take(&block).is_empty());
+    }
+
     /// Tests that blocks that were already picked by a peer are not picked
     /// again for the same peer (only relevant in endgame mode).
     #[test]
@@ -352,9 +656,10 @@ mod tests {
         let block_count = block_count(piece_len);
         let in_end_game = true;
 
-        let mut download = PieceDownload::new(piece_index, piece_len);
+        let mut download =
+            PieceDownload::new(piece_index, piece_len, BLOCK_LEN);
         // save picked blocks
-        let mut picked = HashSet::with_capacity(block_count);
+        let mut picked = HashMap::with_capacity(block_count);
 
         // pick all blocks one by one
         for _ in 0..block_count {
@@ -363,9 +668,90 @@ mod tests {
             assert_eq!(picked_blocks.len(), 1);
             let block = *picked_blocks.first().unwrap();
             // assert that this block hasn't been picked before
-            assert!(!picked.contains(&block));
+            assert!(!picked.contains_key(&block));
             // mark block as picked
-            picked.insert(block);
+            picked.insert(block, Instant::now());
+        }
+    }
+
+    /// Tests that pieces are split into blocks of the configured length, with
+    /// only the last block being shorter, and that only blocks matching this
+    /// layout are accepted.
+    #[test]
+    fn should_pick_blocks_of_custom_len() {
+        let block_len = 3 * BLOCK_LEN;
+        let piece_len = 2 * block_len + 100;
+        let mut download = PieceDownload::new(0, piece_len, block_len);
+        assert_eq!(download.count_free_blocks(), 3);
+
+        let mut picked_blocks = Vec::new();
+        download.pick_blocks(3, &mut picked_blocks, false, &HashMap::new());
+        assert_eq!(
+            picked_blocks,
+            vec![
+                BlockInfo {
+                    piece_index: 0,
+                    offset: 0,
+                    len: block_len,
+                },
+                BlockInfo {
+                    piece_index: 0,
+                    offset: block_len,
+                    len: block_len,
+                },
+                BlockInfo {
+                    piece_index: 0,
+                    offset: 2 * block_len,
+                    len: 100,
+                },
+            ]
+        );
+        for block in picked_blocks.iter() {
+            assert!(download.contains_block(block));
+        }
+
+        // a block that is not aligned to the block length
+        assert!(!download.contains_block(&BlockInfo {
+            piece_index: 0,
+            offset: BLOCK_LEN,
+            len: block_len,
+        }));
+        // a block shorter than the one at its offset
+        assert!(!download.contains_block(&BlockInfo {
+            piece_index: 0,
+            offset: block_len,
+            len: BLOCK_LEN,
+        }));
+    }
+
+    /// Tests that an exclusive piece is only available to its peer, and that
+    /// resetting the piece frees all its blocks and forgets its contributors
+    /// and exclusivity.
+    #[test]
+    fn should_reset_exclusive_piece() {
+        let peer: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let other_peer: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let mut download = PieceDownload::new(0, 4 * BLOCK_LEN, BLOCK_LEN);
+        assert!(download.is_available_to(peer));
+        assert!(download.is_available_to(other_peer));
+
+        download.set_exclusive_peer(Some(peer));
+        assert!(download.is_available_to(peer));
+        assert!(!download.is_available_to(other_peer));
+
+        let mut picked_blocks = Vec::new();
+        download.pick_blocks(4, &mut picked_blocks, false, &HashMap::new());
+        for block in picked_blocks.iter() {
+            download.received_block(block);
         }
+        download.add_contributor(peer);
+        assert_eq!(download.contributors().len(), 1);
+        assert_eq!(download.count_free_blocks(), 0);
+
+        download.reset();
+        assert!(download.contributors().is_empty());
+        assert_eq!(download.exclusive_peer(), None);
+        assert!(download.is_available_to(other_peer));
+        assert_eq!(download.count_free_blocks(), 4);
     }
 }
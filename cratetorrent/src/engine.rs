@@ -15,26 +15,57 @@
 //! For usage examples, see the [library documentation](crate).
 
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 
-use futures::stream::StreamExt;
-use tokio::{
-    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
-    task,
+use futures::{
+    select,
+    stream::{Fuse, StreamExt},
 };
+use reqwest::Url;
+use tokio::sync::{
+    mpsc::{self, UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+use tracing::Instrument;
 
 use crate::{
-    alert::{AlertReceiver, AlertSender},
-    conf::{Conf, TorrentConf},
+    alert::{Alert, AlertReceiver, AlertSender},
+    conf::{
+        Conf, LabelConf, RateLimitConf, SeedGoal, SeedGoalAction, TorrentConf,
+    },
+    dht::{
+        self,
+        storage::{self, Item, MutableItem},
+    },
     disk::{self, error::NewTorrentError},
     error::*,
-    metainfo::Metainfo,
-    storage_info::StorageInfo,
-    torrent::{self, Torrent},
-    tracker::Tracker,
-    Bitfield, TorrentId,
+    external_ip::ExternalIp,
+    ip_filter::{IpFilter, SharedIpFilter},
+    local_addr::LocalAddr,
+    logging, lsd,
+    magnet::Magnet,
+    metainfo::{self, Metainfo},
+    metrics,
+    piece_picker::PiecePickerFactory,
+    port_mapping::{self, Protocol},
+    queue::TorrentQueue,
+    rate_limit::{self, BandwidthShare},
+    rt,
+    state::{self, ResumeData, SavedStats, TorrentState},
+    storage_info::{Rename, StorageInfo, StorageMode},
+    stream::FileStream,
+    torrent::{
+        self,
+        metadata::{self as metadata_fetch, MetadataFetch},
+        Torrent,
+    },
+    tracker::{self, HttpClients, Tracker},
+    watch, Bitfield, FileIndex, FilePriority, PieceIndex, Sha1Hash, TorrentId,
 };
 
 /// Spawns the engine as a tokio task.
@@ -46,13 +77,22 @@ use crate::{
 /// send the engine commands, and an [`crate::alert::AlertReceiver`], to which
 /// various components in the engine will send alerts of events.
 pub fn spawn(conf: Conf) -> Result<(EngineHandle, AlertReceiver)> {
+    for (subsystem, level) in conf.engine.log_levels.iter() {
+        logging::set_level(*subsystem, *level);
+    }
+
     log::info!("Spawning engine task");
 
+    metrics::describe();
+
     // create alert channels and return alert port to user
     let (alert_tx, alert_rx) = mpsc::unbounded_channel();
     let (mut engine, tx) = Engine::new(conf, alert_tx)?;
 
-    let join_handle = task::spawn(async move { engine.run().await });
+    let join_handle = rt::spawn(
+        async move { engine.run().await }
+            .instrument(tracing::info_span!("engine")),
+    );
     log::info!("Spawned engine task");
 
     Ok((
@@ -64,7 +104,7 @@ pub fn spawn(conf: Conf) -> Result<(EngineHandle, AlertReceiver)> {
     ))
 }
 
-type JoinHandle = task::JoinHandle<Result<()>>;
+type JoinHandle = rt::JoinHandle<Result<()>>;
 
 /// A handle to the currently running torrent engine.
 pub struct EngineHandle {
@@ -84,9 +124,726 @@ impl EngineHandle {
         Ok(id)
     }
 
+    /// Adds a torrent from its source, with the options set in the builder.
+    ///
+    /// The torrent's metainfo is read and parsed, or its magnet link is
+    /// parsed, before the torrent is handed to the engine, so an invalid
+    /// source is reported right away. If successful, the id of the torrent is
+    /// returned.
+    ///
+    /// The torrent is allocated on disk and started like one created with
+    /// [`Self::create_torrent`]. See [`Self::add_magnet`] for how torrents
+    /// added from magnet links are started.
+    pub fn add_torrent(&self, torrent: AddTorrent) -> Result<TorrentId> {
+        add_torrent(&self.tx, torrent)
+    }
+
+    /// Adds a torrent from a magnet link, whose metadata is downloaded from
+    /// its peers before the torrent is started.
+    ///
+    /// The id of the torrent is returned right away, if the link is valid.
+    /// The peers are found via the link's trackers and peers, and the DHT.
+    /// Once the metadata is received, an
+    /// [`Alert::MetadataReceived`](crate::alert::Alert::MetadataReceived)
+    /// alert is posted and the torrent is allocated and downloaded with the
+    /// default torrent configuration, like one created with
+    /// [`Self::create_torrent`].
+    ///
+    /// Until then, the torrent can only be removed: other commands sent to it
+    /// are ignored.
+    ///
+    /// This is a shorthand for adding an [`AddTorrent::magnet`] with
+    /// [`Self::add_torrent`].
+    pub fn add_magnet(&self, uri: &str) -> Result<TorrentId> {
+        self.add_torrent(AddTorrent::magnet(uri))
+    }
+
+    /// Stops the torrent and removes it from the engine, along with its
+    /// saved state. Its downloaded files are deleted if requested, and are
+    /// left on disk otherwise.
+    ///
+    /// Like on engine shutdown, the torrent announces its leave to its
+    /// trackers and waits for its peer sessions to shut down, after which
+    /// an [`Alert::TorrentRemoved`](crate::alert::Alert::TorrentRemoved) alert
+    /// is posted. Commands sent to the torrent in the meantime are ignored.
+    ///
+    /// The files are deleted in the background once the torrent's pending
+    /// writes are done. If they can't be, an [`Error::Disk`] error with
+    /// [`DiskError::Deletion`] is posted.
+    pub fn remove_torrent(
+        &self,
+        id: TorrentId,
+        delete_files: DeleteFiles,
+    ) -> Result<()> {
+        log::trace!("Removing torrent {}", id);
+        self.tx.send(Command::RemoveTorrent { id, delete_files })?;
+        Ok(())
+    }
+
+    /// Pauses the torrent: disconnects its peers and tells its trackers, the
+    /// DHT, and the local network that it stopped. It keeps its state, and
+    /// keeps posting its stats, until it's resumed with
+    /// [`Self::resume_torrent`].
+    ///
+    /// An [`Alert::TorrentPaused`](crate::alert::Alert::TorrentPaused) alert
+    /// is posted once the trackers were told. Pausing a paused torrent is
+    /// a no-op.
+    pub fn pause_torrent(&self, id: TorrentId) -> Result<()> {
+        log::trace!("Pausing torrent {}", id);
+        self.tx.send(Command::PauseTorrent { id })?;
+        Ok(())
+    }
+
+    /// Resumes a torrent paused with [`Self::pause_torrent`], announcing it
+    /// again and connecting to peers.
+    ///
+    /// An [`Alert::TorrentResumed`](crate::alert::Alert::TorrentResumed) alert
+    /// is posted once it's done. Resuming a torrent that is not paused is
+    /// a no-op.
+    pub fn resume_torrent(&self, id: TorrentId) -> Result<()> {
+        log::trace!("Resuming torrent {}", id);
+        self.tx.send(Command::ResumeTorrent { id })?;
+        Ok(())
+    }
+
+    /// Sets whether the torrent is auto-managed, i.e. whether the engine's
+    /// queue, seed goals and error retries may stop or start it, as is the
+    /// default.
+    ///
+    /// A torrent that is not auto-managed only changes state by explicit
+    /// calls: it's active regardless of the queue's limits, without taking
+    /// up a slot, keeps seeding after reaching a seed goal, though the
+    /// [`Alert::SeedGoalReached`](crate::alert::Alert::SeedGoalReached)
+    /// alert is still posted, and once errored stays stopped until
+    /// [`Self::clear_torrent_error`] is called. It keeps its position in the queue, which
+    /// applies again once it's auto-managed again.
+    pub fn set_auto_managed(
+        &self,
+        id: TorrentId,
+        is_auto_managed: bool,
+    ) -> Result<()> {
+        log::trace!("Setting torrent {} auto-managed: {}", id, is_auto_managed);
+        self.tx.send(Command::SetAutoManaged {
+            id,
+            is_auto_managed,
+        })?;
+        Ok(())
+    }
+
+    /// Starts a torrent stopped by an error accessing its files again, e.g.
+    /// once the user freed up disk space, without waiting for its automatic
+    /// retry, if any.
+    ///
+    /// An [`Alert::TorrentErrorCleared`](crate::alert::Alert::TorrentErrorCleared)
+    /// alert is posted once it's done. Clearing the error of a torrent that is
+    /// not errored is a no-op.
+    pub fn clear_torrent_error(&self, id: TorrentId) -> Result<()> {
+        log::trace!("Clearing torrent {} error", id);
+        self.tx.send(Command::ClearTorrentError { id })?;
+        Ok(())
+    }
+
+    /// Pauses the whole session: all torrents disconnect their peers and tell
+    /// their trackers, the DHT, and the local network that they stopped, e.g.
+    /// while on a metered connection. Unlike shutting down the engine, all
+    /// state is kept, and torrents added in the meantime start out stopped.
+    ///
+    /// The torrents' own paused states are left as they are, so the session
+    /// continues as it was once resumed with [`Self::resume_all`]. An
+    /// [`Alert::SessionPaused`](crate::alert::Alert::SessionPaused) alert is
+    /// posted right away, and pausing a paused session is a no-op.
+    pub fn pause_all(&self) -> Result<()> {
+        log::trace!("Pausing session");
+        self.tx.send(Command::PauseSession)?;
+        Ok(())
+    }
+
+    /// Resumes the session paused with [`Self::pause_all`], starting again the
+    /// torrents that are neither paused nor queued.
+    ///
+    /// An [`Alert::SessionResumed`](crate::alert::Alert::SessionResumed)
+    /// alert is posted right away, and resuming a session that is not paused
+    /// is a no-op.
+    pub fn resume_all(&self) -> Result<()> {
+        log::trace!("Resuming session");
+        self.tx.send(Command::ResumeSession)?;
+        Ok(())
+    }
+
+    /// Changes the upload and download rate limits of the torrent as a whole,
+    /// set initially by
+    /// [`TorrentConf::rate_limit`](crate::conf::TorrentConf::rate_limit). A
+    /// direction whose limit is `None` is only limited by the engine-wide
+    /// limit, if any.
+    ///
+    /// The limits apply under the engine-wide limits: a torrent never gets
+    /// more than its own limit, and the part of the engine-wide rate it can't
+    /// use is given to the other torrents.
+    pub fn set_torrent_rate_limits(
+        &self,
+        id: TorrentId,
+        limits: RateLimitConf,
+    ) -> Result<()> {
+        log::trace!("Setting torrent {} rate limits to {:?}", id, limits);
+        self.tx.send(Command::SetTorrentRateLimits { id, limits })?;
+        Ok(())
+    }
+
+    /// Replaces the labels of the torrent, which are saved with its resume
+    /// data. Duplicate labels are dropped.
+    ///
+    /// Unlike the labels a torrent is added with, these don't apply the
+    /// defaults in
+    /// [`EngineConf::labels`](crate::conf::EngineConf::labels), as the
+    /// torrent is already set up.
+    pub fn set_torrent_labels(
+        &self,
+        id: TorrentId,
+        labels: Vec<String>,
+    ) -> Result<()> {
+        log::trace!("Setting torrent {} labels to {:?}", id, labels);
+        self.tx.send(Command::SetTorrentLabels { id, labels })?;
+        Ok(())
+    }
+
+    /// Requests the labels of the torrents in the engine.
+    ///
+    /// The result is posted as an
+    /// [`Alert::TorrentLabels`](crate::alert::Alert::TorrentLabels) alert.
+    pub fn query_labels(&self) -> Result<()> {
+        log::trace!("Querying torrent labels");
+        self.tx.send(Command::QueryLabels)?;
+        Ok(())
+    }
+
+    /// Requests the ids of the torrents in the engine.
+    ///
+    /// The result is posted as an
+    /// [`Alert::Torrents`](crate::alert::Alert::Torrents) alert.
+    pub fn query_torrents(&self) -> Result<()> {
+        log::trace!("Querying torrents");
+        self.tx.send(Command::QueryTorrents)?;
+        Ok(())
+    }
+
+    /// Moves the torrent to the given position in the queue, 0 being the
+    /// front, or to the back if the position is past it.
+    ///
+    /// The slots limited by [`QueueConf`](crate::conf::QueueConf) are handed
+    /// out again right away, so moving a queued torrent ahead of an active one
+    /// may queue the latter and activate the former.
+    pub fn set_queue_position(
+        &self,
+        id: TorrentId,
+        position: usize,
+    ) -> Result<()> {
+        log::trace!("Moving torrent {} to queue position {}", id, position);
+        self.tx.send(Command::SetQueuePosition { id, position })?;
+        Ok(())
+    }
+
+    /// Requests the order of the torrents in the queue.
+    ///
+    /// The result is posted as an
+    /// [`Alert::TorrentQueue`](crate::alert::Alert::TorrentQueue) alert.
+    pub fn query_queue(&self) -> Result<()> {
+        log::trace!("Querying queue");
+        self.tx.send(Command::QueryQueue)?;
+        Ok(())
+    }
+
+    /// Sets the time by which a piece of the torrent should be downloaded, or
+    /// clears it if `deadline` is `None`.
+    ///
+    /// Pieces with a deadline are downloaded before all other pieces, in the
+    /// order of their deadlines. If a piece's deadline is at risk, its blocks
+    /// are requested from multiple peers at once. This can be used to stream
+    /// media files: the pieces about to be played are given deadlines so that
+    /// playback is not interrupted.
+    ///
+    /// Invalid piece indices and pieces that have already been downloaded are
+    /// ignored.
+    pub fn set_piece_deadline(
+        &self,
+        id: TorrentId,
+        piece_index: PieceIndex,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        log::trace!("Setting torrent {} piece {} deadline", id, piece_index);
+        self.tx.send(Command::SetPieceDeadline {
+            id,
+            piece_index,
+            deadline,
+        })?;
+        Ok(())
+    }
+
+    /// Sets the download priority of a file of the torrent.
+    ///
+    /// Files are indexed in the order they appear in the torrent's metainfo.
+    /// All files have [`FilePriority::Normal`] priority by default. Invalid
+    /// file indices are ignored.
+    pub fn set_file_priority(
+        &self,
+        id: TorrentId,
+        file_index: FileIndex,
+        priority: FilePriority,
+    ) -> Result<()> {
+        log::trace!("Setting torrent {} file {} priority", id, file_index);
+        self.tx.send(Command::SetFilePriority {
+            id,
+            file_index,
+            priority,
+        })?;
+        Ok(())
+    }
+
+    /// Opens a file of the torrent for reading while it's being downloaded,
+    /// e.g. to play a media file.
+    ///
+    /// Reading a part of the file that's not downloaded yet waits until it
+    /// is, and the pieces being waited for are downloaded before the others,
+    /// via their deadlines (see [`Self::set_piece_deadline`]). See
+    /// [`FileStream`] for details.
+    ///
+    /// Returns an [`Error::InvalidTorrentId`] error if the torrent doesn't
+    /// exist or its metadata is not yet received, and an
+    /// [`Error::InvalidFileIndex`] error if the torrent has no such file.
+    pub async fn open_file(
+        &self,
+        id: TorrentId,
+        file_index: FileIndex,
+    ) -> Result<FileStream> {
+        log::trace!("Opening torrent {} file {}", id, file_index);
+        let (result_tx, result_rx) = oneshot::channel();
+        self.tx.send(Command::OpenFile {
+            id,
+            file_index,
+            result_tx,
+        })?;
+        // the torrent may be removed before it answers
+        result_rx.await.map_err(|_| Error::InvalidTorrentId)?
+    }
+
+    /// Moves a file of the torrent to the path, relative to the torrent's
+    /// root, along with its data already downloaded. The torrent's root is
+    /// its own directory if it's an archive, and the download directory
+    /// otherwise.
+    ///
+    /// An [`Alert::FileRenamed`](crate::alert::Alert::FileRenamed) alert is
+    /// posted once the file is moved, or an [`Error::Disk`] error if the path
+    /// is outside the root, or if a file already exists there. The new path
+    /// is kept across restarts with the torrent's resume data.
+    pub fn rename_file(
+        &self,
+        id: TorrentId,
+        file_index: FileIndex,
+        path: impl Into<PathBuf>,
+    ) -> Result<()> {
+        log::trace!("Renaming torrent {} file {}", id, file_index);
+        self.tx.send(Command::Rename {
+            id,
+            rename: Rename::File {
+                index: file_index,
+                path: path.into(),
+            },
+        })?;
+        Ok(())
+    }
+
+    /// Renames the torrent's own directory, if it's an archive, or its file
+    /// otherwise, which is then named as given instead of as the torrent.
+    ///
+    /// An [`Alert::RootRenamed`](crate::alert::Alert::RootRenamed) alert is
+    /// posted once the directory is renamed, or for a single file torrent, an
+    /// [`Alert::FileRenamed`](crate::alert::Alert::FileRenamed) alert. As with
+    /// [`Self::rename_file`], the new name is kept across restarts.
+    pub fn rename_root(
+        &self,
+        id: TorrentId,
+        name: impl Into<String>,
+    ) -> Result<()> {
+        log::trace!("Renaming torrent {} root", id);
+        self.tx.send(Command::Rename {
+            id,
+            rename: Rename::Root(name.into()),
+        })?;
+        Ok(())
+    }
+
+    /// Requests the latest statistics of the torrent: its status, progress,
+    /// transfer rates, ETA, peer counts, and so on.
+    ///
+    /// The result is posted as an
+    /// [`Alert::TorrentStats`](crate::alert::Alert::TorrentStats) alert. These
+    /// are the stats collected in the torrent's last tick, so querying them
+    /// is cheap enough for UIs to poll, though the torrent also posts them
+    /// each second on its own.
+    pub fn query_stats(&self, id: TorrentId) -> Result<()> {
+        log::trace!("Querying torrent {} stats", id);
+        self.tx.send(Command::QueryStats { id })?;
+        Ok(())
+    }
+
+    /// Requests the download state and availability of each of the torrent's
+    /// pieces.
+    ///
+    /// The result is posted as an
+    /// [`Alert::TorrentPieces`](crate::alert::Alert::TorrentPieces) alert.
+    /// This can be used to render a bar of the torrent's pieces.
+    pub fn query_pieces(&self, id: TorrentId) -> Result<()> {
+        log::trace!("Querying torrent {} pieces", id);
+        self.tx.send(Command::QueryPieces { id })?;
+        Ok(())
+    }
+
+    /// Requests the status of each of the torrent's trackers.
+    ///
+    /// The result is posted as an
+    /// [`Alert::TorrentTrackers`](crate::alert::Alert::TorrentTrackers)
+    /// alert.
+    pub fn query_trackers(&self, id: TorrentId) -> Result<()> {
+        log::trace!("Querying torrent {} trackers", id);
+        self.tx.send(Command::QueryTrackers { id })?;
+        Ok(())
+    }
+
+    /// Checks whether peers on the internet can connect to the torrent's
+    /// listen port, by connecting to it through our external address.
+    ///
+    /// The torrent also checks this on its own after starting, and keeps the
+    /// result in its
+    /// [stats](crate::torrent::stats::TorrentStats::connectability). The
+    /// result is posted as an
+    /// [`Alert::ConnectabilityChanged`](crate::alert::Alert::ConnectabilityChanged)
+    /// alert once our external address is known.
+    pub fn check_connectability(&self, id: TorrentId) -> Result<()> {
+        log::trace!("Checking torrent {} connectability", id);
+        self.tx.send(Command::CheckConnectability { id })?;
+        Ok(())
+    }
+
+    /// Verifies the torrent's data on disk, e.g. after its files were
+    /// modified outside of the engine.
+    ///
+    /// The torrent is stopped while all its pieces are hashed, after which it
+    /// continues with the pieces that are valid: pieces that no longer match
+    /// are downloaded again, and pieces that were placed there are no longer
+    /// downloaded. Once done, an
+    /// [`Alert::TorrentChecked`](crate::alert::Alert::TorrentChecked) alert is
+    /// posted.
+    pub fn force_recheck(&self, id: TorrentId) -> Result<()> {
+        log::trace!("Forcing torrent {} recheck", id);
+        self.tx.send(Command::ForceRecheck { id })?;
+        Ok(())
+    }
+
+    /// Announces the torrent to its trackers outside the regular announce
+    /// interval, e.g. to get more peers.
+    ///
+    /// Unless `ignore_min_interval` is set, the announce is deferred until the
+    /// tracker's minimum announce interval allows it.
+    pub fn force_reannounce(
+        &self,
+        id: TorrentId,
+        ignore_min_interval: bool,
+    ) -> Result<()> {
+        log::trace!("Forcing torrent {} reannounce", id);
+        self.tx.send(Command::ForceReannounce {
+            id,
+            tracker: None,
+            ignore_min_interval,
+        })?;
+        Ok(())
+    }
+
+    /// Announces the torrent to the tracker with the given URL, as listed by
+    /// [`Self::query_trackers`], outside the regular announce interval.
+    ///
+    /// Unlike [`Self::force_reannounce`], this announces to the tracker even
+    /// if it's not the one in use in its tier, e.g. to check whether
+    /// a failing tracker works again. Unless `ignore_min_interval` is set,
+    /// the announce is deferred until the tracker's minimum announce interval
+    /// and, if it's failing, its retry backoff allow it.
+    pub fn force_tracker_reannounce(
+        &self,
+        id: TorrentId,
+        url: Url,
+        ignore_min_interval: bool,
+    ) -> Result<()> {
+        log::trace!("Forcing torrent {} reannounce to tracker {}", id, url);
+        self.tx.send(Command::ForceReannounce {
+            id,
+            tracker: Some(url),
+            ignore_min_interval,
+        })?;
+        Ok(())
+    }
+
+    /// Looks up and announces the torrent in the DHT right away, rather than
+    /// waiting for its next periodic lookup, e.g. after our network
+    /// connectivity changed.
+    ///
+    /// This is a no-op if the DHT is disabled, if the torrent is stopped, or
+    /// if it's already being looked up.
+    pub fn force_dht_announce(&self, id: TorrentId) -> Result<()> {
+        log::trace!("Forcing torrent {} DHT announce", id);
+        self.tx.send(Command::ForceDhtAnnounce { id })?;
+        Ok(())
+    }
+
+    /// Adds the tracker at the given URL to the torrent, at the end of the tier
+    /// with the given index. If the index is past the torrent's last tier, the
+    /// tracker is added in a new last tier.
+    ///
+    /// The tracker is used from the torrent's next announce on. Adding a tracker
+    /// the torrent already has is a no-op.
+    ///
+    /// An error is returned if the tracker's protocol is not supported. If the
+    /// tracker can't be reached through the configured proxy, it is not added
+    /// and an [`Error::InvalidTrackerUrl`] error alert is posted.
+    pub fn add_tracker(
+        &self,
+        id: TorrentId,
+        url: Url,
+        tier: usize,
+    ) -> Result<()> {
+        log::trace!("Adding tracker {} to torrent {}", url, id);
+        if !metainfo::is_supported_tracker(&url) {
+            return Err(Error::InvalidTrackerUrl(url));
+        }
+        self.tx.send(Command::AddTracker { id, url, tier })?;
+        Ok(())
+    }
+
+    /// Removes the tracker at the given URL from the torrent.
+    ///
+    /// If the torrent has announced to the tracker, the tracker is sent
+    /// a stopped event in the background.
+    pub fn remove_tracker(&self, id: TorrentId, url: Url) -> Result<()> {
+        log::trace!("Removing tracker {} from torrent {}", url, id);
+        self.tx.send(Command::RemoveTracker { id, url })?;
+        Ok(())
+    }
+
+    /// Adds a node through which to join the DHT, as a `host:port` pair.
+    ///
+    /// The node is pinged and added to the routing table if it responds. This
+    /// can be used when the configured bootstrap nodes are unreachable, or
+    /// to join a private DHT. It has no effect if the DHT is not enabled.
+    pub fn add_dht_node(&self, node: impl Into<String>) -> Result<()> {
+        let node = node.into();
+        log::trace!("Adding DHT node {}", node);
+        self.tx.send(Command::AddDhtNode(node))?;
+        Ok(())
+    }
+
+    /// Looks up the immutable item stored in the DHT at the target, which is
+    /// the SHA-1 hash of its bencoded value.
+    ///
+    /// The result is posted as an
+    /// [`Alert::DhtImmutableItem`](crate::alert::Alert::DhtImmutableItem)
+    /// alert. Nothing is posted if the DHT is not enabled.
+    pub fn dht_get_immutable(&self, target: Sha1Hash) -> Result<()> {
+        log::trace!("Getting DHT item {}", hex::encode(&target));
+        self.tx.send(Command::GetDhtImmutableItem(target))?;
+        Ok(())
+    }
+
+    /// Looks up the mutable item stored in the DHT under the ed25519 public
+    /// key and salt, which may be empty.
+    ///
+    /// The result is posted as an
+    /// [`Alert::DhtMutableItem`](crate::alert::Alert::DhtMutableItem) alert.
+    /// Nothing is posted if the DHT is not enabled.
+    pub fn dht_get_mutable(
+        &self,
+        public_key: [u8; 32],
+        salt: Vec<u8>,
+    ) -> Result<()> {
+        log::trace!("Getting DHT item of key {}", hex::encode(&public_key));
+        if salt.len() > storage::MAX_SALT_LEN {
+            return Err(Error::InvalidDhtItem);
+        }
+        self.tx
+            .send(Command::GetDhtMutableItem { public_key, salt })?;
+        Ok(())
+    }
+
+    /// Stores the bencoded value in the DHT as an immutable item, returning
+    /// its target, with which it can be looked up.
+    ///
+    /// The item is stored at the nodes closest to the target, which forget it
+    /// after two hours, so it must be stored again periodically for as long
+    /// as it should be available. Once it was sent to the nodes, an
+    /// [`Alert::DhtItemPut`](crate::alert::Alert::DhtItemPut) alert is
+    /// posted.
+    ///
+    /// An error is returned if the value is not valid bencode or it's longer
+    /// than 1000 bytes.
+    pub fn dht_put_immutable(&self, value: &[u8]) -> Result<Sha1Hash> {
+        let value = canonical_dht_value(value)?;
+        let item = Item::Immutable(value);
+        let target = item.target();
+        log::trace!("Putting DHT item {}", hex::encode(&target));
+        self.tx.send(Command::PutDhtItem(item))?;
+        Ok(target)
+    }
+
+    /// Stores the bencoded value in the DHT as a mutable item, signed with
+    /// the ed25519 secret key, returning its target.
+    ///
+    /// The item is identified by the key's public key and the salt, which
+    /// may be empty. It replaces the item stored under them if its sequence
+    /// number is higher. As with immutable items, it must be stored again
+    /// periodically, and an
+    /// [`Alert::DhtItemPut`](crate::alert::Alert::DhtItemPut) alert is
+    /// posted once it was sent to the nodes.
+    ///
+    /// An error is returned if the value is not valid bencode, it's longer
+    /// than 1000 bytes, or the salt is longer than 64 bytes.
+    pub fn dht_put_mutable(
+        &self,
+        secret_key: &[u8; 32],
+        salt: Vec<u8>,
+        seq: i64,
+        value: &[u8],
+    ) -> Result<Sha1Hash> {
+        if salt.len() > storage::MAX_SALT_LEN {
+            return Err(Error::InvalidDhtItem);
+        }
+        let value = canonical_dht_value(value)?;
+        let item =
+            Item::Mutable(MutableItem::sign(secret_key, salt, seq, value));
+        let target = item.target();
+        log::trace!("Putting DHT item {}", hex::encode(&target));
+        self.tx.send(Command::PutDhtItem(item))?;
+        Ok(target)
+    }
+
+    /// Asks the DHT node at the address for a sample of the info hashes it
+    /// stores peers of, along with the nodes it knows closest to the target
+    /// ([BEP 51](https://www.bittorrent.org/beps/bep_0051.html)).
+    ///
+    /// By querying the returned nodes in turn with varying targets, an
+    /// indexer can discover the torrents in the DHT. The response is posted as
+    /// an
+    /// [`Alert::DhtSampleInfohashes`](crate::alert::Alert::DhtSampleInfohashes)
+    /// alert. Nothing is posted if the node doesn't respond or doesn't support
+    /// the query, or if the DHT is not enabled.
+    pub fn dht_sample_infohashes(
+        &self,
+        addr: SocketAddr,
+        target: Sha1Hash,
+    ) -> Result<()> {
+        log::trace!("Sampling info hashes of DHT node {}", addr);
+        self.tx
+            .send(Command::SampleDhtInfohashes { addr, target })?;
+        Ok(())
+    }
+
+    /// Requests the statistics of the engine's DHT node, such as the size of
+    /// its routing table and the rate of queries it receives, with which one
+    /// can check that the node takes part in the DHT.
+    ///
+    /// The result is posted as an
+    /// [`Alert::DhtStats`](crate::alert::Alert::DhtStats) alert. Nothing is
+    /// posted if the DHT is not enabled.
+    pub fn query_dht_stats(&self) -> Result<()> {
+        log::trace!("Querying DHT stats");
+        self.tx.send(Command::QueryDhtStats)?;
+        Ok(())
+    }
+
+    /// Replaces the engine's IP filter, set initially by
+    /// [`EngineConf::ip_filter`](crate::conf::EngineConf::ip_filter).
+    ///
+    /// The new filter applies to peers found and connections accepted from
+    /// then on. Peers that are already connected are not disconnected.
+    pub fn set_ip_filter(&self, filter: IpFilter) -> Result<()> {
+        log::trace!("Setting IP filter");
+        self.tx.send(Command::SetIpFilter(filter))?;
+        Ok(())
+    }
+
+    /// Changes the maximum download rate of all torrents combined, set
+    /// initially by
+    /// [`EngineConf::download_rate_limit`](crate::conf::EngineConf::download_rate_limit),
+    /// or lifts the limit if `None`.
+    ///
+    /// The new limit is divided among the torrents on the engine's next tick.
+    pub fn set_download_rate_limit(&self, limit: Option<u64>) -> Result<()> {
+        log::trace!("Setting download rate limit to {:?}", limit);
+        self.tx.send(Command::SetDownloadRateLimit(limit))?;
+        Ok(())
+    }
+
+    /// Changes the maximum upload rate of all torrents combined, set
+    /// initially by
+    /// [`EngineConf::upload_rate_limit`](crate::conf::EngineConf::upload_rate_limit),
+    /// or lifts the limit if `None`.
+    ///
+    /// The new limit is divided among the torrents on the engine's next tick.
+    pub fn set_upload_rate_limit(&self, limit: Option<u64>) -> Result<()> {
+        log::trace!("Setting upload rate limit to {:?}", limit);
+        self.tx.send(Command::SetUploadRateLimit(limit))?;
+        Ok(())
+    }
+
+    /// Requests the number of peers blocked by
+    /// [`EngineConf::ip_filter`](crate::conf::EngineConf::ip_filter).
+    ///
+    /// The result is posted as an
+    /// [`Alert::IpFilterStats`](crate::alert::Alert::IpFilterStats) alert.
+    pub fn query_ip_filter_stats(&self) -> Result<()> {
+        log::trace!("Querying IP filter stats");
+        self.tx.send(Command::QueryIpFilterStats)?;
+        Ok(())
+    }
+
+    /// Requests our external IP addresses, as determined by the majority of
+    /// the addresses reported by trackers and peers.
+    ///
+    /// The result is posted as an
+    /// [`Alert::ExternalIp`](crate::alert::Alert::ExternalIp) alert.
+    pub fn query_external_ip(&self) -> Result<()> {
+        log::trace!("Querying external IP");
+        self.tx.send(Command::QueryExternalIp)?;
+        Ok(())
+    }
+
+    /// Restores the session saved in the given directory, e.g. in
+    /// [`EngineConf::state_dir`](crate::conf::EngineConf::state_dir) by an
+    /// earlier run.
+    ///
+    /// The saved engine-wide rate limits replace the current ones, and the
+    /// saved torrents are added in their queue order, after the torrents
+    /// already in the engine. They continue where they stopped, with the
+    /// pieces, pause state, statistics, and rate limits they had, but
+    /// otherwise use the default
+    /// [`Conf::torrent`](crate::conf::Conf::torrent) configuration. Torrents
+    /// that are already in the engine are not added again.
+    ///
+    /// The ids of the restored torrents are posted as an
+    /// [`Alert::StateLoaded`](crate::alert::Alert::StateLoaded) alert.
+    pub fn load_state(&self, path: impl Into<PathBuf>) -> Result<()> {
+        let path = path.into();
+        log::trace!("Loading state from {:?}", path);
+        self.tx.send(Command::LoadState(path))?;
+        Ok(())
+    }
+
     /// Gracefully shuts down the engine and waits for all its torrents to do
     /// the same.
     ///
+    /// Torrents announce their exit to their trackers, waiting at most
+    /// [`TorrentConf::stop_announce_timeout`](crate::conf::TorrentConf::stop_announce_timeout),
+    /// and close their peer connections. The downloaded pieces are then
+    /// synced to disk, and if
+    /// [`EngineConf::state_dir`](crate::conf::EngineConf::state_dir) is set,
+    /// the session is saved there. The returned future resolves once all of
+    /// this is done.
+    ///
     /// # Panics
     ///
     /// This method panics if the engine has already been shut down.
@@ -106,6 +863,49 @@ impl EngineHandle {
     }
 }
 
+/// Parses the source of the torrent and sends it to the engine to be added,
+/// returning its id. This is also how the watch task adds torrents.
+pub(crate) fn add_torrent(
+    tx: &Sender,
+    torrent: AddTorrent,
+) -> Result<TorrentId> {
+    log::trace!("Adding torrent");
+    let AddTorrent { source, params } = torrent;
+    let source = match source {
+        TorrentSource::Metainfo(buf) => Source::Metainfo(
+            Metainfo::from_bytes(&buf).map_err(Error::InvalidMetainfo)?,
+        ),
+        TorrentSource::File(path) => {
+            let buf = std::fs::read(&path)?;
+            Source::Metainfo(
+                Metainfo::from_bytes(&buf).map_err(Error::InvalidMetainfo)?,
+            )
+        }
+        TorrentSource::Magnet(uri) => {
+            Source::Magnet(Magnet::parse(&uri).map_err(Error::InvalidMagnet)?)
+        }
+    };
+    if let Some(url) = params
+        .trackers
+        .iter()
+        .find(|url| !metainfo::is_supported_tracker(url))
+    {
+        return Err(Error::InvalidTrackerUrl(url.clone()));
+    }
+    let id = TorrentId::new();
+    tx.send(Command::AddTorrent { id, source, params })?;
+    Ok(id)
+}
+
+/// Returns the canonical bencoding of the value of a DHT item, on which its
+/// hash and signature are based.
+fn canonical_dht_value(value: &[u8]) -> Result<Vec<u8>> {
+    match storage::canonicalize(value) {
+        Some(value) if value.len() <= storage::MAX_VALUE_LEN => Ok(value),
+        _ => Err(Error::InvalidDhtItem),
+    }
+}
+
 /// Information for creating a new torrent.
 pub struct TorrentParams {
     /// Contains the torrent's metadata.
@@ -114,16 +914,24 @@ pub struct TorrentParams {
     pub conf: Option<TorrentConf>,
     /// Whether to download or seed the torrent.
     ///
-    /// This is expected to be removed as this will become automatic once
-    /// torrent resume data is supported.
+    /// Torrents restored with [`EngineHandle::load_state`] are resumed from
+    /// their saved resume data instead.
     pub mode: Mode,
     /// The address on which the torrent should listen for new peers.
     ///
     /// This has to be unique for each torrent. If not set, or if already in
-    /// use, a random port is assigned.
+    /// use, a port from [`EngineConf::listen_ports`] is used, or if those are
+    /// taken too, a random port is assigned. A port of 0 also means that
+    /// a random port is assigned.
+    ///
+    /// [`EngineConf::listen_ports`]: crate::conf::EngineConf::listen_ports
     // TODO: probably use an engine wide address, but requires some
     // rearchitecting
     pub listen_addr: Option<SocketAddr>,
+    /// If set, creates the piece picker of the torrent, which decides which
+    /// pieces are downloaded. Otherwise the built-in rarest-first picker is
+    /// used, configured by the torrent's configuration.
+    pub piece_picker: Option<PiecePickerFactory>,
 }
 
 /// The download mode.
@@ -132,8 +940,222 @@ pub struct TorrentParams {
 // seeded exist and are complete
 #[derive(Debug)]
 pub enum Mode {
-    Download { seeds: Vec<SocketAddr> },
+    Download {
+        seeds: Vec<SocketAddr>,
+    },
     Seed,
+    /// Continues a partially complete download, only downloading the pieces
+    /// that are not set in `own_pieces`.
+    ///
+    /// The pieces set in the bitfield must already have been verified, e.g.
+    /// when they were saved in resume data or by re-hashing the files, as
+    /// they are not checked again. The bitfield must have as many bits as
+    /// there are pieces in the torrent.
+    Resume {
+        own_pieces: Bitfield,
+        seeds: Vec<SocketAddr>,
+    },
+}
+
+/// Whether the files of a torrent removed with
+/// [`EngineHandle::remove_torrent`] are deleted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeleteFiles {
+    /// The torrent's downloaded files are deleted.
+    Yes,
+    /// The torrent's downloaded files are left on disk.
+    No,
+}
+
+/// A torrent to be added with [`EngineHandle::add_torrent`], along with the
+/// options it's added with.
+///
+/// The builder is created from the torrent's source. The options that are not
+/// set take their defaults: the torrent is downloaded with the engine's
+/// torrent configuration into the engine's download directory, and is started
+/// right away.
+///
+/// ```no_run
+/// use cratetorrent::{engine::AddTorrent, FilePriority};
+///
+/// let torrent = AddTorrent::file("/tmp/example.torrent")
+///     .download_dir("/tmp/downloads")
+///     .file_priority(0, FilePriority::Skip)
+///     .paused(true);
+/// ```
+#[derive(Debug)]
+pub struct AddTorrent {
+    source: TorrentSource,
+    params: AddParams,
+}
+
+/// Where the metainfo of a torrent added with [`EngineHandle::add_torrent`]
+/// comes from.
+#[derive(Clone, Debug)]
+pub enum TorrentSource {
+    /// The contents of a metainfo file.
+    Metainfo(Vec<u8>),
+    /// The path of a metainfo file.
+    File(PathBuf),
+    /// A magnet link, whose metadata is downloaded from peers.
+    Magnet(String),
+}
+
+impl AddTorrent {
+    /// Creates the builder of a torrent added from the given source.
+    pub fn new(source: TorrentSource) -> Self {
+        Self {
+            source,
+            params: AddParams::default(),
+        }
+    }
+
+    /// Creates the builder of a torrent added from the contents of its
+    /// metainfo file.
+    pub fn metainfo(buf: Vec<u8>) -> Self {
+        Self::new(TorrentSource::Metainfo(buf))
+    }
+
+    /// Creates the builder of a torrent added from the metainfo file at the
+    /// path.
+    pub fn file(path: impl Into<PathBuf>) -> Self {
+        Self::new(TorrentSource::File(path.into()))
+    }
+
+    /// Creates the builder of a torrent added from a magnet link.
+    pub fn magnet(uri: impl Into<String>) -> Self {
+        Self::new(TorrentSource::Magnet(uri.into()))
+    }
+
+    /// Overrides the engine's default torrent configuration.
+    pub fn conf(mut self, conf: TorrentConf) -> Self {
+        self.params.conf = Some(conf);
+        self
+    }
+
+    /// Sets the directory into which the torrent is downloaded, instead of
+    /// [`EngineConf::download_dir`](crate::conf::EngineConf::download_dir).
+    pub fn download_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.params.options.download_dir = Some(dir.into());
+        self
+    }
+
+    /// Sets the initial download priority of the file with the index, as
+    /// with [`EngineHandle::set_file_priority`].
+    pub fn file_priority(
+        mut self,
+        file_index: FileIndex,
+        priority: FilePriority,
+    ) -> Self {
+        self.params
+            .options
+            .file_priorities
+            .push((file_index, priority));
+        self
+    }
+
+    /// Sets whether the torrent is auto-managed, which it is by default. See
+    /// [`EngineHandle::set_auto_managed`].
+    pub fn auto_managed(mut self, is_auto_managed: bool) -> Self {
+        self.params.options.is_auto_managed = is_auto_managed;
+        self
+    }
+
+    /// Sets whether the torrent starts out paused, until resumed with
+    /// [`EngineHandle::resume_torrent`].
+    pub fn paused(mut self, is_paused: bool) -> Self {
+        self.params.options.is_paused = is_paused;
+        self
+    }
+
+    /// Sets whether the torrent's files are already complete, in which case
+    /// the torrent is seeded, as with [`Mode::Seed`].
+    pub fn seed(mut self, is_seed: bool) -> Self {
+        self.params.is_seed = is_seed;
+        self
+    }
+
+    /// Sets how the torrent's files are allocated on disk.
+    pub fn storage_mode(mut self, storage_mode: StorageMode) -> Self {
+        self.params.options.storage_mode = storage_mode;
+        self
+    }
+
+    /// Adds a tracker to those of the torrent, in a new tier after the
+    /// torrent's own trackers. Trackers the torrent already has are skipped.
+    pub fn tracker(mut self, url: Url) -> Self {
+        self.params.trackers.push(url);
+        self
+    }
+
+    /// Adds a label to the torrent, by which it can be grouped with others.
+    /// Adding a label twice is a no-op.
+    ///
+    /// The defaults of the label in
+    /// [`EngineConf::labels`](crate::conf::EngineConf::labels) apply to the
+    /// torrent, unless they are set for the torrent itself: the download
+    /// directory unless one is set with [`Self::download_dir`], and the rate
+    /// limits and seed goals unless a configuration is set with
+    /// [`Self::conf`].
+    pub fn label(mut self, label: impl Into<String>) -> Self {
+        let label = label.into();
+        if !self.params.options.labels.contains(&label) {
+            self.params.options.labels.push(label);
+        }
+        self
+    }
+}
+
+/// The options of a torrent set with [`AddTorrent`], other than its source.
+#[derive(Debug, Default)]
+pub(crate) struct AddParams {
+    conf: Option<TorrentConf>,
+    is_seed: bool,
+    trackers: Vec<Url>,
+    options: TorrentOptions,
+}
+
+/// The options with which a torrent is created that are not part of
+/// [`TorrentParams`].
+#[derive(Debug)]
+pub(crate) struct TorrentOptions {
+    /// Overrides the engine's download directory.
+    download_dir: Option<PathBuf>,
+    storage_mode: StorageMode,
+    /// The priorities of files that are not downloaded with the default
+    /// priority.
+    file_priorities: Vec<(FileIndex, FilePriority)>,
+    is_paused: bool,
+    labels: Vec<String>,
+    is_auto_managed: bool,
+    /// The new name of the torrent's directory, restored from its resume
+    /// data.
+    root_name: Option<String>,
+    /// The paths of the torrent's renamed files, restored from its resume
+    /// data.
+    file_paths: BTreeMap<FileIndex, PathBuf>,
+}
+
+impl Default for TorrentOptions {
+    fn default() -> Self {
+        Self {
+            download_dir: None,
+            storage_mode: StorageMode::default(),
+            file_priorities: Vec::new(),
+            is_paused: false,
+            labels: Vec::new(),
+            is_auto_managed: true,
+            root_name: None,
+            file_paths: BTreeMap::new(),
+        }
+    }
+}
+
+/// The source of a torrent added with [`EngineHandle::add_torrent`], once its
+/// metainfo or magnet link is parsed.
+pub(crate) enum Source {
+    Metainfo(Metainfo),
+    Magnet(Magnet),
 }
 
 /// The channel through which the user can send commands to the engine.
@@ -148,6 +1170,74 @@ pub(crate) enum Command {
         id: TorrentId,
         params: TorrentParams,
     },
+    /// Adds a torrent from its parsed source, or starts fetching its
+    /// metadata if it was added from a magnet link.
+    AddTorrent {
+        id: TorrentId,
+        source: Source,
+        params: AddParams,
+    },
+    /// Sent by a magnet link's metadata fetch task with the metadata it
+    /// downloaded, and the peers it found, with which the torrent is created.
+    MetadataReceived {
+        id: TorrentId,
+        metadata: Vec<u8>,
+        peers: Vec<SocketAddr>,
+    },
+    /// Shuts down a torrent and removes it from the engine.
+    RemoveTorrent {
+        id: TorrentId,
+        delete_files: DeleteFiles,
+    },
+    /// Sent by the disk task once it deleted the files of a removed torrent,
+    /// or failed to.
+    FilesDeleted {
+        id: TorrentId,
+        result: std::io::Result<()>,
+    },
+    /// Requests the ids of all torrents.
+    QueryTorrents,
+    /// Pauses a torrent.
+    PauseTorrent { id: TorrentId },
+    /// Resumes a paused torrent.
+    ResumeTorrent { id: TorrentId },
+    /// Changes whether a torrent is subject to the queue and seed goals.
+    SetAutoManaged {
+        id: TorrentId,
+        is_auto_managed: bool,
+    },
+    /// Starts a torrent stopped by an error again.
+    ClearTorrentError { id: TorrentId },
+    /// Stops all torrents until the session is resumed.
+    PauseSession,
+    /// Continues a paused session.
+    ResumeSession,
+    /// Changes the rate limits of a torrent.
+    SetTorrentRateLimits {
+        id: TorrentId,
+        limits: RateLimitConf,
+    },
+    /// Replaces the labels of a torrent.
+    SetTorrentLabels { id: TorrentId, labels: Vec<String> },
+    /// Requests the labels of all torrents.
+    QueryLabels,
+    /// Moves a torrent in the queue.
+    SetQueuePosition { id: TorrentId, position: usize },
+    /// Requests the order of the torrents in the queue.
+    QueryQueue,
+    /// Sent by a torrent when it has downloaded all its pieces, or when
+    /// a recheck found that it's no longer (or now) a seed.
+    TorrentSeeding { id: TorrentId, is_seed: bool },
+    /// Sent by a torrent with its state to be saved as its resume data.
+    TorrentState { id: TorrentId, state: TorrentState },
+    /// Restores the session saved in the given directory.
+    LoadState(PathBuf),
+    /// Sent by a torrent when it reached one of its seed goals.
+    SeedGoalReached {
+        id: TorrentId,
+        goal: SeedGoal,
+        action: SeedGoalAction,
+    },
     /// Torrent allocation result. If successful, the id of the allocated
     /// torrent is returned for identification, if not, the reason of the error
     /// is included.
@@ -155,6 +1245,80 @@ pub(crate) enum Command {
         id: TorrentId,
         result: Result<(), NewTorrentError>,
     },
+    /// Sets or clears the deadline of a torrent's piece.
+    SetPieceDeadline {
+        id: TorrentId,
+        piece_index: PieceIndex,
+        deadline: Option<Instant>,
+    },
+    /// Sets the download priority of a torrent's file.
+    SetFilePriority {
+        id: TorrentId,
+        file_index: FileIndex,
+        priority: FilePriority,
+    },
+    /// Opens a torrent's file for streaming.
+    OpenFile {
+        id: TorrentId,
+        file_index: FileIndex,
+        result_tx: oneshot::Sender<Result<FileStream>>,
+    },
+    /// Moves a torrent's file or renames its root.
+    Rename { id: TorrentId, rename: Rename },
+    /// Sent by the disk task once it renamed a torrent's file or root, or
+    /// failed to.
+    StorageRenamed {
+        id: TorrentId,
+        result: std::io::Result<Rename>,
+    },
+    /// Requests the latest stats of a torrent.
+    QueryStats { id: TorrentId },
+    /// Requests the state of a torrent's pieces.
+    QueryPieces { id: TorrentId },
+    /// Requests the status of a torrent's trackers.
+    QueryTrackers { id: TorrentId },
+    /// Checks whether a torrent's listen port is reachable.
+    CheckConnectability { id: TorrentId },
+    /// Rechecks a torrent's data on disk.
+    ForceRecheck { id: TorrentId },
+    /// Announces a torrent to its trackers, or to one of them.
+    ForceReannounce {
+        id: TorrentId,
+        tracker: Option<Url>,
+        ignore_min_interval: bool,
+    },
+    /// Announces a torrent in the DHT.
+    ForceDhtAnnounce { id: TorrentId },
+    /// Adds a tracker to a torrent.
+    AddTracker {
+        id: TorrentId,
+        url: Url,
+        tier: usize,
+    },
+    /// Removes a tracker from a torrent.
+    RemoveTracker { id: TorrentId, url: Url },
+    /// Adds a node, as a `host:port` pair, through which to join the DHT.
+    AddDhtNode(String),
+    /// Looks up an immutable item in the DHT.
+    GetDhtImmutableItem(Sha1Hash),
+    /// Looks up a mutable item in the DHT.
+    GetDhtMutableItem { public_key: [u8; 32], salt: Vec<u8> },
+    /// Stores an item in the DHT.
+    PutDhtItem(Item),
+    /// Samples the info hashes stored by a DHT node.
+    SampleDhtInfohashes { addr: SocketAddr, target: Sha1Hash },
+    /// Requests the statistics of the DHT node.
+    QueryDhtStats,
+    /// Requests the number of peers blocked by the IP filter.
+    QueryIpFilterStats,
+    /// Replaces the engine's IP filter.
+    SetIpFilter(IpFilter),
+    /// Changes or lifts the engine-wide download rate limit.
+    SetDownloadRateLimit(Option<u64>),
+    /// Changes or lifts the engine-wide upload rate limit.
+    SetUploadRateLimit(Option<u64>),
+    /// Requests our external IP addresses.
+    QueryExternalIp,
     /// Gracefully shuts down the engine and waits for all its torrents to do
     /// the same.
     Shutdown,
@@ -163,10 +1327,17 @@ pub(crate) enum Command {
 struct Engine {
     /// All currently running torrents in engine.
     torrents: HashMap<TorrentId, TorrentEntry>,
+    /// The torrents added from magnet links whose metadata is being fetched.
+    magnets: HashMap<TorrentId, MagnetEntry>,
+    /// The order of the torrents, which decides which of them are active.
+    queue: TorrentQueue,
 
     /// The port on which other entities in the engine, or the API consumer
     /// sends the engine commands.
-    cmd_rx: Receiver,
+    cmd_rx: Fuse<Receiver>,
+    /// The sending half of the above, handed to torrents so that they can
+    /// report back to the engine.
+    cmd_tx: Sender,
 
     /// The disk channel.
     disk_tx: disk::Sender,
@@ -175,6 +1346,37 @@ struct Engine {
     /// The channel on which tasks in the engine post alerts to user.
     alert_tx: AlertSender,
 
+    /// The HTTP clients shared by all trackers in the engine.
+    http_clients: HttpClients,
+
+    /// The DHT channel, if the DHT is enabled.
+    dht_tx: Option<dht::Sender>,
+    dht_join_handle: Option<dht::JoinHandle>,
+    /// The LSD channel, if Local Service Discovery is enabled.
+    lsd_tx: Option<lsd::Sender>,
+    lsd_join_handle: Option<lsd::JoinHandle>,
+    /// The channel of the port mapping task, if port mapping is enabled.
+    port_mapping_tx: Option<port_mapping::Sender>,
+    port_mapping_join_handle: Option<port_mapping::JoinHandle>,
+    /// The channel of the watch task, if a directory is watched.
+    watch_tx: Option<watch::Sender>,
+    watch_join_handle: Option<watch::JoinHandle>,
+
+    /// Our external IP addresses, as reported by the trackers and peers of
+    /// all torrents.
+    external_ip: Arc<ExternalIp>,
+    /// The IP filter applied to the peers of all torrents.
+    ip_filter: Arc<SharedIpFilter>,
+    /// The local address to which the peer sockets of all torrents are
+    /// bound, kept up to date on each tick.
+    local_addr: Arc<LocalAddr>,
+    /// Whether the alternative rate limits are in effect, per their schedule
+    /// checked on each tick.
+    is_alt_rate_limit_active: bool,
+    /// Set while the whole session is paused with
+    /// [`EngineHandle::pause_all`], during which all torrents are stopped.
+    is_session_paused: bool,
+
     /// The global engine configuration that includes defaults for torrents
     /// whose config is not overridden.
     conf: Conf,
@@ -182,56 +1384,686 @@ struct Engine {
 
 /// A running torrent's entry in the engine.
 struct TorrentEntry {
+    /// The torrent's info hash, which names its files in the state directory.
+    info_hash: Sha1Hash,
+    /// The directory the torrent was added with, if it's not the engine's
+    /// download directory.
+    download_dir: Option<PathBuf>,
+    /// The labels of the torrent, saved with its resume data.
+    labels: Vec<String>,
+    /// Whether the queue and seed goals may stop the torrent.
+    is_auto_managed: bool,
+    /// The new name of the torrent's directory, if it was renamed.
+    root_name: Option<String>,
+    /// The paths of the torrent's renamed files, relative to its root.
+    file_paths: BTreeMap<FileIndex, PathBuf>,
     /// The torrent's command channel on which engine sends commands to torrent.
     tx: torrent::Sender,
     /// The torrent task's join handle, used during shutdown.
-    join_handle: Option<task::JoinHandle<torrent::error::Result<()>>>,
+    join_handle: Option<rt::JoinHandle<torrent::error::Result<()>>>,
+    /// The torrent's share of the engine-wide download rate limit.
+    download_bandwidth: Arc<BandwidthShare>,
+    /// The torrent's share of the engine-wide upload rate limit.
+    upload_bandwidth: Arc<BandwidthShare>,
+}
+
+/// The entry of a torrent added from a magnet link, until its metadata is
+/// received.
+struct MagnetEntry {
+    info_hash: Sha1Hash,
+    /// The trackers of the magnet link, with which the torrent is created.
+    trackers: Vec<Url>,
+    /// The options with which the torrent is created.
+    params: AddParams,
+    /// The metadata fetch task's command channel.
+    tx: torrent::Sender,
+    /// The metadata fetch task's join handle, used during shutdown.
+    join_handle: Option<rt::JoinHandle<()>>,
 }
 
 impl Engine {
     /// Creates a new engine, spawning the disk task.
     fn new(conf: Conf, alert_tx: AlertSender) -> Result<(Self, Sender)> {
         let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
-        let (disk_join_handle, disk_tx) = disk::spawn(cmd_tx.clone())?;
+        let (disk_join_handle, disk_tx) =
+            disk::spawn(cmd_tx.clone(), conf.engine.read_cache_len)?;
+        let http_clients = tracker::http_clients(
+            conf.engine.proxy.as_ref(),
+            conf.engine.tracker_proxy.as_ref(),
+            &conf.engine.announce,
+            &conf.engine.tracker_tls,
+        )?;
+        let external_ip = Arc::new(ExternalIp::new(alert_tx.clone()));
+        let ip_filter =
+            Arc::new(SharedIpFilter::new(conf.engine.ip_filter.clone()));
+        let local_addr =
+            Arc::new(LocalAddr::new(conf.engine.bind_addr.clone()));
+        let (dht_join_handle, dht_tx) = match &conf.engine.dht {
+            Some(_) if conf.engine.proxy.is_some() => {
+                log::warn!(
+                    "Not starting DHT as it can't be used through the proxy"
+                );
+                (None, None)
+            }
+            Some(dht_conf) => {
+                let (join_handle, dht_tx) = dht::spawn(
+                    dht_conf.clone(),
+                    Arc::clone(&external_ip),
+                    alert_tx.clone(),
+                )?;
+                (Some(join_handle), Some(dht_tx))
+            }
+            None => (None, None),
+        };
+        let (lsd_join_handle, lsd_tx) = match &conf.engine.lsd {
+            Some(_) if conf.engine.proxy.is_some() => {
+                log::warn!("Not starting LSD as peers are reached via proxy");
+                (None, None)
+            }
+            Some(lsd_conf) => {
+                let (join_handle, lsd_tx) = lsd::spawn(lsd_conf.clone())?;
+                (Some(join_handle), Some(lsd_tx))
+            }
+            None => (None, None),
+        };
+        let port_mapping_conf = &conf.engine.port_mapping;
+        let (port_mapping_join_handle, port_mapping_tx) =
+            match port_mapping_conf {
+                Some(_) if conf.engine.proxy.is_some() => {
+                    log::warn!(
+                        "Not mapping ports as peers are reached via proxy"
+                    );
+                    (None, None)
+                }
+                Some(port_mapping_conf) => {
+                    let (join_handle, port_mapping_tx) = port_mapping::spawn(
+                        port_mapping_conf.clone(),
+                        alert_tx.clone(),
+                    )?;
+                    (Some(join_handle), Some(port_mapping_tx))
+                }
+                None => (None, None),
+            };
+        // torrents map their own ports once they are bound, but the DHT
+        // node's port is known from the start
+        if let (Some(port_mapping_tx), Some(dht_conf)) =
+            (&port_mapping_tx, &conf.engine.dht)
+        {
+            let port = dht_conf.listen_addr.port();
+            if dht_tx.is_some() && port != 0 {
+                port_mapping_tx
+                    .send(port_mapping::Command::AddPort {
+                        protocol: Protocol::Udp,
+                        port,
+                    })
+                    .ok();
+            }
+        }
+
+        let (watch_join_handle, watch_tx) = match &conf.engine.watch {
+            Some(watch_conf) => {
+                let (join_handle, watch_tx) = watch::spawn(
+                    watch_conf.clone(),
+                    cmd_tx.clone(),
+                    alert_tx.clone(),
+                );
+                (Some(join_handle), Some(watch_tx))
+            }
+            None => (None, None),
+        };
 
         Ok((
             Self {
                 torrents: HashMap::new(),
-                cmd_rx,
+                magnets: HashMap::new(),
+                queue: TorrentQueue::default(),
+                cmd_rx: cmd_rx.fuse(),
+                cmd_tx: cmd_tx.clone(),
                 disk_tx,
                 disk_join_handle: Some(disk_join_handle),
+                external_ip,
+                ip_filter,
+                local_addr,
+                is_alt_rate_limit_active: false,
+                is_session_paused: false,
                 alert_tx,
+                http_clients,
+                dht_tx,
+                dht_join_handle,
+                lsd_tx,
+                lsd_join_handle,
+                port_mapping_tx,
+                port_mapping_join_handle,
+                watch_tx,
+                watch_join_handle,
                 conf,
             },
             cmd_tx,
         ))
     }
 
+    /// Creates the client of the tracker at the given URL.
+    ///
+    /// Returns `None` if the tracker can't be reached through the configured
+    /// proxy, as announcing to trackers that bypass the proxy would leak our
+    /// address.
+    fn new_tracker(&self, url: Url) -> Option<Tracker> {
+        let tracker = Tracker::new(url, self.http_clients.clone());
+        let is_proxied = self.conf.engine.proxy.is_some()
+            || self.conf.engine.tracker_proxy.is_some();
+        if is_proxied && !tracker.can_use_proxy() {
+            log::warn!(
+                "Skipping tracker {} as it can't be reached through the proxy",
+                tracker
+            );
+            None
+        } else {
+            Some(tracker)
+        }
+    }
+
+    /// Tells the user that the configured interface has no address, so peers
+    /// can't be connected until it gets one.
+    fn post_bind_addr_unavailable(&self) -> Result<()> {
+        if let Some(bind_addr) = &self.conf.engine.bind_addr {
+            log::warn!("Not connecting peers as {} has no address", bind_addr);
+            self.alert_tx.send(Alert::Error(Error::BindAddrUnavailable(
+                bind_addr.clone(),
+            )))?;
+        }
+        Ok(())
+    }
+
     /// Runs the engine until an unrecoverable error occurs, or until the user
     /// sends a shutdown command.
     async fn run(&mut self) -> Result<()> {
         log::info!("Starting engine");
 
-        while let Some(cmd) = self.cmd_rx.next().await {
-            match cmd {
-                Command::CreateTorrent { id, params } => {
-                    self.create_torrent(id, params).await?;
-                }
-                Command::TorrentAllocation { id, result } => match result {
-                    Ok(_) => {
-                        log::info!("Torrent {} allocated on disk", id);
+        // the engine loop is triggered every second by the loop timer, to
+        // redistribute bandwidth among torrents, and by commands
+        let mut tick_timer = rt::interval(Duration::from_secs(1)).fuse();
+        let mut last_tick_time = Instant::now();
+        let mut last_state_save_time = last_tick_time;
+
+        if self.local_addr.ip().is_err() {
+            self.post_bind_addr_unavailable()?;
+        }
+
+        loop {
+            select! {
+                now = tick_timer.select_next_some() => {
+                    self.update_alt_rate_limit()?;
+                    self.allocate_bandwidth(
+                        now.saturating_duration_since(last_tick_time),
+                        now,
+                    );
+                    last_tick_time = now;
+                    if self.local_addr.update() == Some(false) {
+                        self.post_bind_addr_unavailable()?;
+                    }
+                    let state_save_elapsed =
+                        now.saturating_duration_since(last_state_save_time);
+                    if self.conf.engine.state_dir.is_some()
+                        && state_save_elapsed
+                            >= self.conf.engine.state_save_interval
+                    {
+                        self.save_state();
+                        last_state_save_time = now;
                     }
-                    Err(e) => {
-                        log::error!(
-                            "Error allocating torrent {} on disk: {}",
+                }
+                cmd = self.cmd_rx.select_next_some() => {
+                    match cmd {
+                        Command::CreateTorrent { id, params } => {
+                            self.create_torrent(
+                                id,
+                                params,
+                                TorrentOptions::default(),
+                                SavedStats::default(),
+                            )
+                            .await?;
+                        }
+                        Command::AddTorrent { id, source, params } => {
+                            match source {
+                                Source::Metainfo(metainfo) => {
+                                    self.add_torrent(
+                                        id,
+                                        metainfo,
+                                        params,
+                                        Vec::new(),
+                                    )
+                                    .await?;
+                                }
+                                Source::Magnet(magnet) => {
+                                    self.add_magnet(id, magnet, params);
+                                }
+                            }
+                        }
+                        Command::MetadataReceived { id, metadata, peers } => {
+                            self.create_magnet_torrent(id, metadata, peers)
+                                .await?;
+                        }
+                        Command::RemoveTorrent { id, delete_files } => {
+                            self.remove_torrent(id, delete_files);
+                        }
+                        Command::FilesDeleted { id, result } => match result {
+                            Ok(()) => {
+                                log::info!("Torrent {} files deleted", id);
+                            }
+                            Err(e) => {
+                                log::warn!(
+                                    "Error deleting torrent {} files: {}",
+                                    id,
+                                    e
+                                );
+                                self.alert_tx.send(Alert::Error(
+                                    Error::Disk {
+                                        id,
+                                        error: DiskError::Deletion(e),
+                                    },
+                                ))?;
+                            }
+                        },
+                        Command::PauseTorrent { id } => {
+                            self.set_paused(id, true);
+                        }
+                        Command::ResumeTorrent { id } => {
+                            self.set_paused(id, false);
+                        }
+                        Command::SetAutoManaged {
+                            id,
+                            is_auto_managed,
+                        } => {
+                            self.set_auto_managed(id, is_auto_managed);
+                        }
+                        Command::ClearTorrentError { id } => {
+                            if let Some(torrent) = self.torrents.get(&id) {
+                                // the torrent task may no longer be running
+                                torrent
+                                    .tx
+                                    .send(torrent::Command::ClearError)
+                                    .ok();
+                            } else {
+                                log::warn!("Torrent {} not found", id);
+                            }
+                        }
+                        Command::PauseSession => {
+                            self.set_session_paused(true)?;
+                        }
+                        Command::ResumeSession => {
+                            self.set_session_paused(false)?;
+                        }
+                        Command::SetTorrentRateLimits { id, limits } => {
+                            self.set_torrent_rate_limits(id, limits);
+                        }
+                        Command::SetTorrentLabels { id, labels } => {
+                            self.set_torrent_labels(id, labels);
+                        }
+                        Command::QueryLabels => {
+                            let mut labels: Vec<_> = self
+                                .torrents
+                                .iter()
+                                .map(|(id, t)| (*id, t.labels.clone()))
+                                .chain(self.magnets.iter().map(|(id, m)| {
+                                    (*id, m.params.options.labels.clone())
+                                }))
+                                .collect();
+                            labels.sort_unstable_by_key(|(id, _)| *id);
+                            self.alert_tx.send(Alert::TorrentLabels(labels))?;
+                        }
+                        Command::QueryTorrents => {
+                            let mut ids: Vec<_> = self
+                                .torrents
+                                .keys()
+                                .chain(self.magnets.keys())
+                                .copied()
+                                .collect();
+                            ids.sort_unstable();
+                            self.alert_tx.send(Alert::Torrents(ids))?;
+                        }
+                        Command::SetQueuePosition { id, position } => {
+                            if self.torrents.contains_key(&id) {
+                                self.queue.set_position(id, position);
+                                self.update_queue();
+                            } else {
+                                log::warn!("Torrent {} not found", id);
+                            }
+                        }
+                        Command::QueryQueue => {
+                            self.alert_tx
+                                .send(Alert::TorrentQueue(self.queue.ids()))?;
+                        }
+                        Command::TorrentSeeding { id, is_seed } => {
+                            if is_seed {
+                                log::info!("Torrent {} is seeding", id);
+                            } else {
+                                log::info!("Torrent {} is downloading", id);
+                            }
+                            self.queue.set_seed(id, is_seed);
+                            self.update_queue();
+                        }
+                        Command::TorrentState { id, state } => {
+                            self.save_resume_data(id, state);
+                        }
+                        Command::LoadState(path) => {
+                            self.load_state(&path).await?;
+                        }
+                        Command::SeedGoalReached { id, goal, action } => {
+                            log::info!(
+                                "Torrent {} reached seed goal {:?}",
+                                id,
+                                goal
+                            );
+                            self.alert_tx
+                                .send(Alert::SeedGoalReached { id, goal })?;
+                            // torrents that are not auto-managed keep seeding
+                            let is_auto_managed = self
+                                .torrents
+                                .get(&id)
+                                .map_or(false, |t| t.is_auto_managed);
+                            match action {
+                                _ if !is_auto_managed => {}
+                                SeedGoalAction::Pause => {
+                                    self.set_paused(id, true)
+                                }
+                                SeedGoalAction::Remove => {
+                                    self.remove_torrent(id, DeleteFiles::No)
+                                }
+                            }
+                        }
+                        Command::TorrentAllocation { id, result } => match result {
+                            Ok(_) => {
+                                log::info!("Torrent {} allocated on disk", id);
+                                self.alert_tx.send(Alert::TorrentAdded(id))?;
+                            }
+                            Err(e) => {
+                                log::error!(
+                                    "Error allocating torrent {} on disk: {}",
+                                    id,
+                                    e
+                                );
+                                if let NewTorrentError::Io(e) = e {
+                                    self.alert_tx.send(Alert::Error(
+                                        Error::Disk {
+                                            id,
+                                            error: DiskError::Allocation(e),
+                                        },
+                                    ))?;
+                                }
+                            }
+                        },
+                        Command::SetPieceDeadline {
                             id,
-                            e
-                        );
+                            piece_index,
+                            deadline,
+                        } => {
+                            if let Some(torrent) = self.torrents.get(&id) {
+                                // the torrent task may no longer be running
+                                torrent
+                                    .tx
+                                    .send(torrent::Command::SetPieceDeadline {
+                                        piece_index,
+                                        deadline,
+                                    })
+                                    .ok();
+                            } else {
+                                log::warn!("Torrent {} not found", id);
+                            }
+                        }
+                        Command::SetFilePriority {
+                            id,
+                            file_index,
+                            priority,
+                        } => {
+                            if let Some(torrent) = self.torrents.get(&id) {
+                                // the torrent task may no longer be running
+                                torrent
+                                    .tx
+                                    .send(torrent::Command::SetFilePriority {
+                                        file_index,
+                                        priority,
+                                    })
+                                    .ok();
+                            } else {
+                                log::warn!("Torrent {} not found", id);
+                            }
+                        }
+                        Command::OpenFile {
+                            id,
+                            file_index,
+                            result_tx,
+                        } => {
+                            if let Some(torrent) = self.torrents.get(&id) {
+                                // the torrent task may no longer be running,
+                                // in which case the sender is dropped
+                                torrent
+                                    .tx
+                                    .send(torrent::Command::OpenFile {
+                                        file_index,
+                                        result_tx,
+                                    })
+                                    .ok();
+                            } else {
+                                log::warn!("Torrent {} not found", id);
+                                result_tx
+                                    .send(Err(Error::InvalidTorrentId))
+                                    .ok();
+                            }
+                        }
+                        Command::Rename { id, rename } => {
+                            if self.torrents.contains_key(&id) {
+                                self.disk_tx
+                                    .send(disk::Command::Rename { id, rename })?;
+                            } else {
+                                log::warn!("Torrent {} not found", id);
+                            }
+                        }
+                        Command::StorageRenamed { id, result } => {
+                            self.handle_storage_renamed(id, result)?;
+                        }
+                        Command::QueryStats { id } => {
+                            if let Some(torrent) = self.torrents.get(&id) {
+                                // the torrent task may no longer be running
+                                torrent
+                                    .tx
+                                    .send(torrent::Command::QueryStats)
+                                    .ok();
+                            } else {
+                                log::warn!("Torrent {} not found", id);
+                            }
+                        }
+                        Command::QueryPieces { id } => {
+                            if let Some(torrent) = self.torrents.get(&id) {
+                                // the torrent task may no longer be running
+                                torrent
+                                    .tx
+                                    .send(torrent::Command::QueryPieces)
+                                    .ok();
+                            } else {
+                                log::warn!("Torrent {} not found", id);
+                            }
+                        }
+                        Command::QueryTrackers { id } => {
+                            if let Some(torrent) = self.torrents.get(&id) {
+                                // the torrent task may no longer be running
+                                torrent
+                                    .tx
+                                    .send(torrent::Command::QueryTrackers)
+                                    .ok();
+                            } else {
+                                log::warn!("Torrent {} not found", id);
+                            }
+                        }
+                        Command::CheckConnectability { id } => {
+                            if let Some(torrent) = self.torrents.get(&id) {
+                                // the torrent task may no longer be running
+                                torrent
+                                    .tx
+                                    .send(torrent::Command::CheckConnectability)
+                                    .ok();
+                            } else {
+                                log::warn!("Torrent {} not found", id);
+                            }
+                        }
+                        Command::ForceRecheck { id } => {
+                            if let Some(torrent) = self.torrents.get(&id) {
+                                // the torrent task may no longer be running
+                                torrent
+                                    .tx
+                                    .send(torrent::Command::ForceRecheck)
+                                    .ok();
+                            } else {
+                                log::warn!("Torrent {} not found", id);
+                            }
+                        }
+                        Command::ForceReannounce {
+                            id,
+                            tracker,
+                            ignore_min_interval,
+                        } => {
+                            if let Some(torrent) = self.torrents.get(&id) {
+                                // the torrent task may no longer be running
+                                torrent
+                                    .tx
+                                    .send(torrent::Command::ForceReannounce {
+                                        tracker,
+                                        ignore_min_interval,
+                                    })
+                                    .ok();
+                            } else {
+                                log::warn!("Torrent {} not found", id);
+                            }
+                        }
+                        Command::ForceDhtAnnounce { id } => {
+                            if let Some(torrent) = self.torrents.get(&id) {
+                                // the torrent task may no longer be running
+                                torrent
+                                    .tx
+                                    .send(torrent::Command::ForceDhtAnnounce)
+                                    .ok();
+                            } else {
+                                log::warn!("Torrent {} not found", id);
+                            }
+                        }
+                        Command::AddTracker { id, url, tier } => {
+                            if let Some(torrent) = self.torrents.get(&id) {
+                                match self.new_tracker(url.clone()) {
+                                    Some(tracker) => {
+                                        // the torrent task may no longer be
+                                        // running
+                                        torrent
+                                            .tx
+                                            .send(torrent::Command::AddTracker {
+                                                tracker,
+                                                tier,
+                                            })
+                                            .ok();
+                                    }
+                                    None => {
+                                        self.alert_tx.send(Alert::Error(
+                                            Error::InvalidTrackerUrl(url),
+                                        ))?;
+                                    }
+                                }
+                            } else {
+                                log::warn!("Torrent {} not found", id);
+                            }
+                        }
+                        Command::RemoveTracker { id, url } => {
+                            if let Some(torrent) = self.torrents.get(&id) {
+                                // the torrent task may no longer be running
+                                torrent
+                                    .tx
+                                    .send(torrent::Command::RemoveTracker(url))
+                                    .ok();
+                            } else {
+                                log::warn!("Torrent {} not found", id);
+                            }
+                        }
+                        Command::AddDhtNode(node) => {
+                            if let Some(dht_tx) = &self.dht_tx {
+                                // the DHT task may no longer be running
+                                dht_tx
+                                    .send(dht::Command::AddNodes(vec![node]))
+                                    .ok();
+                            } else {
+                                log::warn!(
+                                    "Cannot add DHT node {} as DHT is disabled",
+                                    node
+                                );
+                            }
+                        }
+                        Command::GetDhtImmutableItem(target) => {
+                            self.send_dht_item_cmd(
+                                dht::Command::GetImmutableItem { target },
+                            );
+                        }
+                        Command::GetDhtMutableItem { public_key, salt } => {
+                            self.send_dht_item_cmd(
+                                dht::Command::GetMutableItem {
+                                    public_key,
+                                    salt,
+                                },
+                            );
+                        }
+                        Command::PutDhtItem(item) => {
+                            self.send_dht_item_cmd(dht::Command::PutItem(item));
+                        }
+                        Command::SampleDhtInfohashes { addr, target } => {
+                            if let Some(dht_tx) = &self.dht_tx {
+                                // the DHT task may no longer be running
+                                dht_tx
+                                    .send(dht::Command::SampleInfohashes {
+                                        addr,
+                                        target,
+                                    })
+                                    .ok();
+                            } else {
+                                log::warn!(
+                                    "Cannot sample DHT info hashes as DHT is \
+                                    disabled"
+                                );
+                            }
+                        }
+                        Command::QueryDhtStats => {
+                            if let Some(dht_tx) = &self.dht_tx {
+                                // the DHT task may no longer be running
+                                dht_tx.send(dht::Command::QueryStats).ok();
+                            } else {
+                                log::warn!(
+                                    "Cannot query DHT stats as DHT is disabled"
+                                );
+                            }
+                        }
+                        Command::QueryIpFilterStats => {
+                            self.alert_tx.send(Alert::IpFilterStats(
+                                self.ip_filter.stats(),
+                            ))?;
+                        }
+                        Command::SetIpFilter(filter) => {
+                            log::info!(
+                                "Setting IP filter with {} range(s)",
+                                filter.range_count()
+                            );
+                            self.conf.engine.ip_filter = filter.clone();
+                            self.ip_filter.set_filter(filter);
+                        }
+                        Command::SetDownloadRateLimit(limit) => {
+                            self.set_download_rate_limit(limit);
+                        }
+                        Command::SetUploadRateLimit(limit) => {
+                            self.set_upload_rate_limit(limit);
+                        }
+                        Command::QueryExternalIp => {
+                            self.alert_tx.send(Alert::ExternalIp {
+                                ipv4: self.external_ip.ipv4(),
+                                ipv6: self.external_ip.ipv6(),
+                            })?;
+                        }
+                        Command::Shutdown => {
+                            self.shutdown().await?;
+                            break;
+                        }
                     }
-                },
-                Command::Shutdown => {
-                    self.shutdown().await?;
-                    break;
                 }
             }
         }
@@ -240,26 +2072,101 @@ impl Engine {
     }
 
     /// Creates and spawns a new torrent based on the parameters given.
+    ///
+    /// Torrents restored from the saved session may start out paused, and
+    /// carry over the statistics of their earlier runs.
     async fn create_torrent(
         &mut self,
         id: TorrentId,
         params: TorrentParams,
+        options: TorrentOptions,
+        saved_stats: SavedStats,
     ) -> Result<()> {
         let conf = params.conf.unwrap_or_else(|| self.conf.torrent.clone());
-        let storage_info = StorageInfo::new(
+        let is_paused = options.is_paused;
+        let mut storage_info = StorageInfo::new(
             &params.metainfo,
-            self.conf.engine.download_dir.clone(),
+            options
+                .download_dir
+                .clone()
+                .unwrap_or_else(|| self.conf.engine.download_dir.clone()),
         );
+        // the renames were validated when they were made
+        if let Some(name) = &options.root_name {
+            storage_info.rename(Rename::Root(name.clone()));
+        }
+        for (&index, path) in options.file_paths.iter() {
+            storage_info.rename(Rename::File {
+                index,
+                path: path.clone(),
+            });
+        }
+        // the info dictionary served to peers is taken before the trackers are
+        // moved out of the metainfo
+        let metadata = params.metainfo.info_bytes().map(<[u8]>::to_vec);
         // TODO: don't duplicate trackers if multiple torrents use the same
         // ones (common in practice)
         let trackers = params
             .metainfo
             .trackers
             .into_iter()
-            .map(Tracker::new)
+            .map(|tier| {
+                tier.into_iter()
+                    .filter_map(|url| self.new_tracker(url))
+                    .collect::<Vec<_>>()
+            })
+            .filter(|tier| !tier.is_empty())
             .collect();
         let own_pieces = params.mode.own_pieces(storage_info.piece_count);
 
+        // the nodes suggested by the metainfo help join the DHT, but private
+        // torrents must not leak into it
+        if !params.metainfo.is_private && !params.metainfo.dht_nodes.is_empty()
+        {
+            if let Some(dht_tx) = &self.dht_tx {
+                // the DHT task may no longer be running
+                dht_tx
+                    .send(dht::Command::AddNodes(
+                        params.metainfo.dht_nodes.clone(),
+                    ))
+                    .ok();
+            }
+        }
+
+        // start the torrent with its proportional share of the global rate
+        // limits, which are adjusted to its actual use in subsequent ticks
+        let priority = conf.bandwidth_priority.max(1) as u64;
+        let priority_sum = priority
+            + self
+                .torrents
+                .values()
+                .map(|t| t.download_bandwidth.priority() as u64)
+                .sum::<u64>();
+        let new_share = |limit: Option<u64>, cap: Option<u64>| {
+            Arc::new(BandwidthShare::new(
+                conf.bandwidth_priority,
+                limit.map(|limit| limit * priority / priority_sum),
+                cap,
+                Instant::now(),
+            ))
+        };
+        let download_bandwidth =
+            new_share(self.download_rate_limit(), conf.rate_limit.download);
+        let upload_bandwidth =
+            new_share(self.upload_rate_limit(), conf.rate_limit.upload);
+
+        // the torrent takes its place at the back of the queue, and starts out
+        // queued if there is no free slot for it
+        self.queue.push(id, own_pieces.all());
+        self.queue.set_paused(id, is_paused);
+        self.queue.set_auto_managed(id, options.is_auto_managed);
+        self.update_queue();
+        let is_queued = !self.queue.is_active(id);
+        if is_queued && !is_paused {
+            log::info!("Torrent {} queued", id);
+            self.alert_tx.send(Alert::TorrentQueued(id))?;
+        }
+
         // create and spawn torrent
         // TODO: For now we spawn automatically, but later when we add torrent
         // pause/restart APIs, this will be a separate step. There should be
@@ -270,15 +2177,53 @@ impl Engine {
             disk_tx: self.disk_tx.clone(),
             info_hash: params.metainfo.info_hash,
             storage_info: storage_info.clone(),
+            metadata,
             own_pieces,
+            piece_picker: params.piece_picker,
             trackers,
             client_id: self.conf.engine.client_id,
+            socket_conf: self.conf.engine.socket,
+            proxy: self.conf.engine.proxy.clone(),
             listen_addr: params.listen_addr.unwrap_or_else(|| {
                 // the port 0 tells the kernel to assign a free port from the
-                // dynamic range
+                // dynamic range, unless a port of the listen port range is
+                // free
                 SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)
             }),
+            listen_ports: self.conf.engine.listen_ports.clone(),
+            announce_conf: self.conf.engine.announce.clone(),
+            external_ip: Arc::clone(&self.external_ip),
+            ip_filter: Arc::clone(&self.ip_filter),
+            local_addr: Arc::clone(&self.local_addr),
+            // private torrents must only get peers from their trackers
+            dht_tx: if params.metainfo.is_private {
+                None
+            } else {
+                self.dht_tx.clone()
+            },
+            lsd_tx: if params.metainfo.is_private {
+                None
+            } else {
+                self.lsd_tx.clone()
+            },
+            port_mapping_tx: self.port_mapping_tx.clone(),
+            engine_tx: self.cmd_tx.clone(),
+            is_queued,
+            is_paused,
+            is_session_paused: self.is_session_paused,
+            is_auto_managed: options.is_auto_managed,
+            saved_stats,
             conf,
+            download_bandwidth: Arc::clone(&download_bandwidth),
+            upload_bandwidth: Arc::clone(&upload_bandwidth),
+            exempt_local_peers_from_rate_limit: self
+                .conf
+                .engine
+                .exempt_local_peers_from_rate_limit,
+            rate_limit_protocol_overhead: self
+                .conf
+                .engine
+                .rate_limit_protocol_overhead,
             alert_tx: self.alert_tx.clone(),
         });
 
@@ -300,29 +2245,784 @@ impl Engine {
         self.disk_tx.send(disk::Command::NewTorrent {
             id,
             storage_info,
+            storage_mode: options.storage_mode,
             piece_hashes: params.metainfo.pieces,
             torrent_tx: torrent_tx.clone(),
         })?;
+        for (file_index, priority) in options.file_priorities {
+            torrent_tx
+                .send(torrent::Command::SetFilePriority {
+                    file_index,
+                    priority,
+                })
+                .ok();
+        }
 
         let seeds = params.mode.seeds();
-        let join_handle =
-            task::spawn(async move { torrent.start(&seeds).await });
+        let join_handle = rt::spawn(
+            async move { torrent.start(&seeds).await }
+                .instrument(tracing::info_span!("torrent", %id)),
+        );
 
+        if let Some(dir) = &self.conf.engine.state_dir {
+            if let Err(e) = state::save_metainfo(
+                dir,
+                &params.metainfo.info_hash,
+                &params.metainfo.bytes,
+            ) {
+                log::warn!("Error saving torrent {} metainfo: {}", id, e);
+            }
+        }
         self.torrents.insert(
             id,
             TorrentEntry {
+                info_hash: params.metainfo.info_hash,
+                download_dir: options.download_dir,
+                labels: options.labels,
+                is_auto_managed: options.is_auto_managed,
+                root_name: options.root_name,
+                file_paths: options.file_paths,
                 tx: torrent_tx,
                 join_handle: Some(join_handle),
+                download_bandwidth,
+                upload_bandwidth,
+            },
+        );
+        self.save_session();
+
+        Ok(())
+    }
+
+    /// Restores the session saved in the given directory.
+    ///
+    /// Saved torrents that can't be restored are skipped, as are those
+    /// already in the engine.
+    async fn load_state(&mut self, dir: &Path) -> Result<()> {
+        log::info!("Loading state from {:?}", dir);
+        // the blocking reads are brief, like those of the DHT state
+        let session = match state::load(dir) {
+            Ok(session) => session,
+            Err(e) => {
+                log::warn!("Error loading state from {:?}: {}", dir, e);
+                self.alert_tx.send(Alert::Error(Error::Io(e)))?;
+                return Ok(());
+            }
+        };
+        self.set_download_rate_limit(session.download_rate_limit);
+        self.set_upload_rate_limit(session.upload_rate_limit);
+
+        let mut ids = Vec::with_capacity(session.torrents.len());
+        for saved in session.torrents {
+            let metainfo = match Metainfo::from_bytes(&saved.metainfo) {
+                Ok(metainfo) => metainfo,
+                Err(e) => {
+                    log::warn!("Skipping invalid saved torrent: {}", e);
+                    continue;
+                }
+            };
+            if self
+                .torrents
+                .values()
+                .any(|t| t.info_hash == metainfo.info_hash)
+            {
+                log::info!(
+                    "Skipping saved torrent {} as it's already added",
+                    hex::encode(&metainfo.info_hash)
+                );
+                continue;
+            }
+
+            // torrents without resume data are downloaded from scratch, as
+            // the pieces on disk haven't been verified
+            let (mode, conf, options, saved_stats) = match saved.resume_data {
+                Some(ResumeData {
+                    state,
+                    rate_limit,
+                    download_dir,
+                    labels,
+                    is_auto_managed,
+                    root_name,
+                    file_paths,
+                }) => (
+                    Mode::Resume {
+                        own_pieces: state.own_pieces,
+                        seeds: Vec::new(),
+                    },
+                    // the seed goals are not saved, so they're those of the
+                    // torrent's labels, if any
+                    TorrentConf {
+                        rate_limit,
+                        seed_goals: self
+                            .label_default(&labels, |l| l.seed_goals)
+                            .unwrap_or(self.conf.torrent.seed_goals),
+                        ..self.conf.torrent.clone()
+                    },
+                    TorrentOptions {
+                        download_dir,
+                        is_paused: state.is_paused,
+                        labels,
+                        is_auto_managed,
+                        root_name,
+                        file_paths,
+                        ..TorrentOptions::default()
+                    },
+                    state.stats,
+                ),
+                None => (
+                    Mode::Download { seeds: Vec::new() },
+                    self.conf.torrent.clone(),
+                    TorrentOptions::default(),
+                    SavedStats::default(),
+                ),
+            };
+            let id = TorrentId::new();
+            let params = TorrentParams {
+                metainfo,
+                conf: Some(conf),
+                mode,
+                listen_addr: None,
+                piece_picker: None,
+            };
+            self.create_torrent(id, params, options, saved_stats)
+                .await?;
+            ids.push(id);
+        }
+
+        log::info!("Restored {} torrent(s) from {:?}", ids.len(), dir);
+        self.alert_tx.send(Alert::StateLoaded(ids))?;
+        Ok(())
+    }
+
+    /// Saves the session in the state directory, if set, and asks the
+    /// torrents for their state, which is saved as their resume data once
+    /// they report it.
+    fn save_state(&self) {
+        if self.conf.engine.state_dir.is_none() {
+            return;
+        }
+        log::debug!("Saving state");
+        for torrent in self.torrents.values() {
+            // the torrent task may no longer be running
+            torrent.tx.send(torrent::Command::SaveState).ok();
+        }
+        self.save_session();
+    }
+
+    /// Saves the torrents in queue order and the engine-wide rate limits in
+    /// the state directory, if set.
+    fn save_session(&self) {
+        let dir = match &self.conf.engine.state_dir {
+            Some(dir) => dir,
+            None => return,
+        };
+        let info_hashes: Vec<_> = self
+            .queue
+            .ids()
+            .iter()
+            .filter_map(|id| self.torrents.get(id))
+            .map(|t| t.info_hash)
+            .collect();
+        if let Err(e) = state::save_session(
+            dir,
+            &info_hashes,
+            self.conf.engine.download_rate_limit,
+            self.conf.engine.upload_rate_limit,
+        ) {
+            log::warn!("Error saving session: {}", e);
+        }
+    }
+
+    /// Saves the state reported by the torrent, along with its rate limits,
+    /// as its resume data in the state directory, if set.
+    ///
+    /// The state of a torrent that was removed in the meantime is dropped.
+    fn save_resume_data(&self, id: TorrentId, state: TorrentState) {
+        let (dir, torrent) =
+            match (&self.conf.engine.state_dir, self.torrents.get(&id)) {
+                (Some(dir), Some(torrent)) => (dir, torrent),
+                _ => return,
+            };
+        let resume_data = ResumeData {
+            state,
+            rate_limit: RateLimitConf {
+                download: torrent.download_bandwidth.cap(),
+                upload: torrent.upload_bandwidth.cap(),
             },
+            download_dir: torrent.download_dir.clone(),
+            labels: torrent.labels.clone(),
+            is_auto_managed: torrent.is_auto_managed,
+            root_name: torrent.root_name.clone(),
+            file_paths: torrent.file_paths.clone(),
+        };
+        if let Err(e) =
+            state::save_resume_data(dir, &torrent.info_hash, &resume_data)
+        {
+            log::warn!("Error saving torrent {} resume data: {}", id, e);
+        }
+    }
+
+    /// Records the torrent's renamed file or root, so that it's saved with its
+    /// resume data, and posts the result.
+    fn handle_storage_renamed(
+        &mut self,
+        id: TorrentId,
+        result: std::io::Result<Rename>,
+    ) -> Result<()> {
+        let rename = match result {
+            Ok(rename) => rename,
+            Err(e) => {
+                log::warn!("Error renaming torrent {} storage: {}", id, e);
+                self.alert_tx.send(Alert::Error(Error::Disk {
+                    id,
+                    error: DiskError::Rename(e),
+                }))?;
+                return Ok(());
+            }
+        };
+        // the torrent may have been removed in the meantime
+        let torrent = match self.torrents.get_mut(&id) {
+            Some(torrent) => torrent,
+            None => return Ok(()),
+        };
+        log::info!("Torrent {} storage renamed: {:?}", id, rename);
+        let alert = match rename {
+            Rename::File { index, path } => {
+                torrent.file_paths.insert(index, path.clone());
+                Alert::FileRenamed {
+                    id,
+                    file_index: index,
+                    path,
+                }
+            }
+            Rename::Root(name) => {
+                torrent.root_name = Some(name.clone());
+                Alert::RootRenamed { id, name }
+            }
+        };
+        // the new paths are saved right away, rather than on the next
+        // periodic save, so that they're not lost if the engine crashes
+        if self.conf.engine.state_dir.is_some() {
+            // the torrent task may no longer be running
+            torrent.tx.send(torrent::Command::SaveState).ok();
+        }
+        self.alert_tx.send(alert)?;
+        Ok(())
+    }
+
+    /// Sends the DHT item command to the DHT task, if the DHT is enabled.
+    fn send_dht_item_cmd(&self, cmd: dht::Command) {
+        if let Some(dht_tx) = &self.dht_tx {
+            // the DHT task may no longer be running
+            dht_tx.send(cmd).ok();
+        } else {
+            log::warn!("Cannot get or put DHT item as DHT is disabled");
+        }
+    }
+
+    /// Changes the engine-wide download rate limit.
+    ///
+    /// A new limit is divided among torrents by the next
+    /// [`Self::allocate_bandwidth`] call, while lifting the limit lifts that
+    /// of each torrent right away.
+    ///
+    /// While the alternative rate limits are in effect, the new limit only
+    /// applies once they no longer are.
+    fn set_download_rate_limit(&mut self, limit: Option<u64>) {
+        log::info!("Setting download rate limit to {:?}", limit);
+        self.conf.engine.download_rate_limit = limit;
+        self.clear_unlimited_rates();
+    }
+
+    /// Changes the engine-wide upload rate limit, like
+    /// [`Self::set_download_rate_limit`].
+    fn set_upload_rate_limit(&mut self, limit: Option<u64>) {
+        log::info!("Setting upload rate limit to {:?}", limit);
+        self.conf.engine.upload_rate_limit = limit;
+        self.clear_unlimited_rates();
+    }
+
+    /// Returns the engine-wide download rate limit currently in effect,
+    /// which is the alternative one while its schedule says so.
+    fn download_rate_limit(&self) -> Option<u64> {
+        match &self.conf.engine.alt_rate_limit {
+            Some(alt) if self.is_alt_rate_limit_active => {
+                alt.download_rate_limit
+            }
+            _ => self.conf.engine.download_rate_limit,
+        }
+    }
+
+    /// Returns the engine-wide upload rate limit currently in effect, like
+    /// [`Self::download_rate_limit`].
+    fn upload_rate_limit(&self) -> Option<u64> {
+        match &self.conf.engine.alt_rate_limit {
+            Some(alt) if self.is_alt_rate_limit_active => alt.upload_rate_limit,
+            _ => self.conf.engine.upload_rate_limit,
+        }
+    }
+
+    /// Lifts the engine-wide limit from the torrents' shares in the
+    /// directions that are no longer limited, right away.
+    fn clear_unlimited_rates(&self) {
+        let now = Instant::now();
+        let clear_download = self.download_rate_limit().is_none();
+        let clear_upload = self.upload_rate_limit().is_none();
+        for torrent in self.torrents.values() {
+            if clear_download {
+                torrent.download_bandwidth.clear_rate(now);
+            }
+            if clear_upload {
+                torrent.upload_bandwidth.clear_rate(now);
+            }
+        }
+    }
+
+    /// Switches between the normal and the alternative rate limits if the
+    /// latter's schedule says so, posting an alert on each switch.
+    ///
+    /// The new limits are divided among the torrents by the following
+    /// [`Self::allocate_bandwidth`] call.
+    fn update_alt_rate_limit(&mut self) -> Result<()> {
+        let is_active = match &self.conf.engine.alt_rate_limit {
+            Some(alt) => {
+                rate_limit::is_alt_rate_limit_active(alt, SystemTime::now())
+            }
+            None => false,
+        };
+        if is_active == self.is_alt_rate_limit_active {
+            return Ok(());
+        }
+        log::info!(
+            "{} alternative rate limits",
+            if is_active { "Enabling" } else { "Disabling" }
         );
+        self.is_alt_rate_limit_active = is_active;
+        self.clear_unlimited_rates();
+        self.alert_tx.send(Alert::AltRateLimits { is_active })?;
+        Ok(())
+    }
+
+    /// Pauses or resumes the torrent.
+    ///
+    /// A paused torrent gives up its slot in the queue while a resumed one may
+    /// take one, so the slots are handed out before the torrent is told. This
+    /// way a torrent resumed without a free slot learns that it's queued
+    /// first, and is not started only to be stopped again.
+    fn set_paused(&mut self, id: TorrentId, is_paused: bool) {
+        if !self.torrents.contains_key(&id) {
+            log::warn!("Torrent {} not found", id);
+            return;
+        }
+        self.queue.set_paused(id, is_paused);
+        self.update_queue();
+        let cmd = if is_paused {
+            torrent::Command::Pause
+        } else {
+            torrent::Command::Resume
+        };
+        if let Some(torrent) = self.torrents.get(&id) {
+            // the torrent task may no longer be running
+            torrent.tx.send(cmd).ok();
+        }
+    }
 
+    /// Hands out the slots of the queue and tells the torrents whose slot
+    /// changed to start or stop.
+    fn update_queue(&mut self) {
+        for (id, is_active) in self.queue.update(&self.conf.engine.queue) {
+            if let Some(torrent) = self.torrents.get(&id) {
+                log::info!(
+                    "Torrent {} {}",
+                    id,
+                    if is_active { "activated" } else { "queued" }
+                );
+                // the torrent task may no longer be running
+                torrent
+                    .tx
+                    .send(torrent::Command::SetQueued(!is_active))
+                    .ok();
+            }
+        }
+    }
+
+    /// Changes whether the torrent is subject to the queue and seed goals,
+    /// which may start or stop it right away, as it leaves or rejoins the
+    /// queue's limits.
+    fn set_auto_managed(&mut self, id: TorrentId, is_auto_managed: bool) {
+        if let Some(torrent) = self.torrents.get_mut(&id) {
+            log::info!(
+                "Setting torrent {} auto-managed: {}",
+                id,
+                is_auto_managed
+            );
+            torrent.is_auto_managed = is_auto_managed;
+            // the torrent task may no longer be running
+            torrent
+                .tx
+                .send(torrent::Command::SetAutoManaged(is_auto_managed))
+                .ok();
+            self.queue.set_auto_managed(id, is_auto_managed);
+            self.update_queue();
+        } else if let Some(magnet) = self.magnets.get_mut(&id) {
+            magnet.params.options.is_auto_managed = is_auto_managed;
+        } else {
+            log::warn!("Torrent {} not found", id);
+        }
+    }
+
+    /// Stops or starts again all torrents as the session is paused or
+    /// resumed. Each torrent keeps its own paused state and queue slot, so
+    /// only those that are neither paused nor queued are started again.
+    fn set_session_paused(&mut self, is_paused: bool) -> Result<()> {
+        if self.is_session_paused == is_paused {
+            return Ok(());
+        }
+        log::info!(
+            "{} session",
+            if is_paused { "Pausing" } else { "Resuming" }
+        );
+        self.is_session_paused = is_paused;
+        for torrent in self.torrents.values() {
+            // the torrent task may no longer be running
+            torrent
+                .tx
+                .send(torrent::Command::SetSessionPaused(is_paused))
+                .ok();
+        }
+        let alert = if is_paused {
+            Alert::SessionPaused
+        } else {
+            Alert::SessionResumed
+        };
+        self.alert_tx.send(alert)?;
         Ok(())
     }
 
+    /// Changes the torrent's own rate limits, which take effect right away.
+    fn set_torrent_rate_limits(&self, id: TorrentId, limits: RateLimitConf) {
+        let torrent = match self.torrents.get(&id) {
+            Some(torrent) => torrent,
+            None => {
+                log::warn!("Torrent {} not found", id);
+                return;
+            }
+        };
+        log::info!("Setting torrent {} rate limits to {:?}", id, limits);
+        let now = Instant::now();
+        torrent.download_bandwidth.set_cap(limits.download, now);
+        torrent.upload_bandwidth.set_cap(limits.upload, now);
+    }
+
+    /// Replaces the torrent's labels, dropping duplicates. The labels are
+    /// saved with the torrent's next resume data.
+    fn set_torrent_labels(&mut self, id: TorrentId, labels: Vec<String>) {
+        let mut deduped = Vec::with_capacity(labels.len());
+        for label in labels {
+            if !deduped.contains(&label) {
+                deduped.push(label);
+            }
+        }
+        log::info!("Setting torrent {} labels to {:?}", id, deduped);
+        if let Some(torrent) = self.torrents.get_mut(&id) {
+            torrent.labels = deduped;
+        } else if let Some(magnet) = self.magnets.get_mut(&id) {
+            magnet.params.options.labels = deduped;
+        } else {
+            log::warn!("Torrent {} not found", id);
+        }
+    }
+
+    /// Returns the first setting of the given labels' defaults, in the order
+    /// of the labels.
+    fn label_default<T>(
+        &self,
+        labels: &[String],
+        f: impl Fn(&LabelConf) -> Option<T>,
+    ) -> Option<T> {
+        labels
+            .iter()
+            .filter_map(|label| self.conf.engine.labels.get(label))
+            .find_map(f)
+    }
+
+    /// Divides the engine-wide rate limits among the torrents, based on their
+    /// priorities and on how much of their previous shares they used.
+    fn allocate_bandwidth(&mut self, elapsed: Duration, now: Instant) {
+        if let Some(limit) = self.download_rate_limit() {
+            let shares: Vec<_> = self
+                .torrents
+                .values()
+                .map(|t| t.download_bandwidth.as_ref())
+                .collect();
+            rate_limit::reallocate_shares(limit, &shares, elapsed, now);
+        }
+        if let Some(limit) = self.upload_rate_limit() {
+            let shares: Vec<_> = self
+                .torrents
+                .values()
+                .map(|t| t.upload_bandwidth.as_ref())
+                .collect();
+            rate_limit::reallocate_shares(limit, &shares, elapsed, now);
+        }
+    }
+
+    /// Spawns the task that fetches the metadata of the torrent added from
+    /// the magnet link.
+    ///
+    /// The link is ignored if its torrent is already in the engine.
+    fn add_magnet(&mut self, id: TorrentId, magnet: Magnet, params: AddParams) {
+        let is_added = self
+            .torrents
+            .values()
+            .map(|t| &t.info_hash)
+            .chain(self.magnets.values().map(|m| &m.info_hash))
+            .any(|info_hash| *info_hash == magnet.info_hash);
+        if is_added {
+            log::warn!(
+                "Torrent {} already added, ignoring magnet",
+                hex::encode(&magnet.info_hash)
+            );
+            return;
+        }
+
+        log::info!("Adding magnet as torrent {}", id);
+        let trackers = magnet
+            .trackers
+            .iter()
+            .chain(params.trackers.iter())
+            .cloned()
+            .filter_map(|url| self.new_tracker(url))
+            .collect();
+        let (mut fetch, tx) = MetadataFetch::new(metadata_fetch::Params {
+            id,
+            info_hash: magnet.info_hash,
+            client_id: self.conf.engine.client_id,
+            trackers,
+            peers: magnet.peers,
+            proxy: self.conf.engine.proxy.clone(),
+            announce_conf: self.conf.engine.announce.clone(),
+            ip_filter: Arc::clone(&self.ip_filter),
+            local_addr: Arc::clone(&self.local_addr),
+            dht_tx: self.dht_tx.clone(),
+            engine_tx: self.cmd_tx.clone(),
+            conf: params
+                .conf
+                .clone()
+                .unwrap_or_else(|| self.conf.torrent.clone()),
+        });
+        let join_handle = rt::spawn(
+            async move { fetch.start().await }
+                .instrument(tracing::info_span!("torrent", %id)),
+        );
+        self.magnets.insert(
+            id,
+            MagnetEntry {
+                info_hash: magnet.info_hash,
+                trackers: magnet.trackers,
+                params,
+                tx,
+                join_handle: Some(join_handle),
+            },
+        );
+    }
+
+    /// Creates the torrent added from a magnet link once its metadata is
+    /// received, downloading it from the peers found so far.
+    async fn create_magnet_torrent(
+        &mut self,
+        id: TorrentId,
+        metadata: Vec<u8>,
+        peers: Vec<SocketAddr>,
+    ) -> Result<()> {
+        // the magnet may have been removed in the meantime
+        let magnet = match self.magnets.remove(&id) {
+            Some(magnet) => magnet,
+            None => return Ok(()),
+        };
+        // all trackers of a magnet link are in the same tier
+        let tiers = if magnet.trackers.is_empty() {
+            Vec::new()
+        } else {
+            vec![magnet.trackers]
+        };
+        let metainfo = match Metainfo::from_info_bytes(&metadata, &tiers) {
+            Ok(metainfo) => metainfo,
+            Err(error) => {
+                log::warn!("Torrent {} metadata is invalid: {}", id, error);
+                self.alert_tx
+                    .send(Alert::Error(Error::InvalidMetadata { id, error }))?;
+                self.alert_tx.send(Alert::TorrentRemoved(id))?;
+                return Ok(());
+            }
+        };
+        log::info!("Torrent {} metadata received", id);
+        self.alert_tx.send(Alert::MetadataReceived {
+            id,
+            metainfo: metainfo.bytes.clone(),
+        })?;
+        self.add_torrent(id, metainfo, magnet.params, peers).await
+    }
+
+    /// Creates the torrent added with [`EngineHandle::add_torrent`], adding
+    /// the trackers it was added with to those in its metainfo.
+    ///
+    /// Unless the torrent is seeded, it's downloaded from the given peers in
+    /// addition to those it finds.
+    async fn add_torrent(
+        &mut self,
+        id: TorrentId,
+        mut metainfo: Metainfo,
+        mut params: AddParams,
+        peers: Vec<SocketAddr>,
+    ) -> Result<()> {
+        let mut new_tier = Vec::new();
+        for url in params.trackers {
+            let is_known =
+                metainfo.trackers.iter().flatten().any(|u| *u == url);
+            if !is_known && !new_tier.contains(&url) {
+                new_tier.push(url);
+            }
+        }
+        if !new_tier.is_empty() {
+            metainfo.trackers.push(new_tier);
+        }
+        let mode = if params.is_seed {
+            Mode::Seed
+        } else {
+            Mode::Download { seeds: peers }
+        };
+
+        // the defaults of the torrent's labels fill in what was not set for
+        // the torrent itself
+        let labels = &params.options.labels;
+        let conf = match params.conf {
+            Some(conf) => conf,
+            None if labels.is_empty() => self.conf.torrent.clone(),
+            None => TorrentConf {
+                rate_limit: self
+                    .label_default(labels, |l| l.rate_limit)
+                    .unwrap_or(self.conf.torrent.rate_limit),
+                seed_goals: self
+                    .label_default(labels, |l| l.seed_goals)
+                    .unwrap_or(self.conf.torrent.seed_goals),
+                ..self.conf.torrent.clone()
+            },
+        };
+        if params.options.download_dir.is_none() {
+            params.options.download_dir =
+                self.label_default(labels, |l| l.download_dir.clone());
+        }
+
+        self.create_torrent(
+            id,
+            TorrentParams {
+                metainfo,
+                conf: Some(conf),
+                mode,
+                listen_addr: None,
+                piece_picker: None,
+            },
+            params.options,
+            SavedStats::default(),
+        )
+        .await
+    }
+
+    /// Removes the torrent from the engine and shuts it down.
+    ///
+    /// The torrent is joined on a separate task so that the engine is not
+    /// blocked while the torrent announces its leave to its trackers. Its
+    /// storage is only released once it's done, so that the disk task has
+    /// processed all its reads and writes by then, and only then are its
+    /// files deleted, if requested.
+    fn remove_torrent(&mut self, id: TorrentId, delete_files: DeleteFiles) {
+        if let Some(mut magnet) = self.magnets.remove(&id) {
+            log::info!("Removing torrent {} before its metadata", id);
+            // the fetch task may have finished already
+            magnet.tx.send(torrent::Command::Shutdown).ok();
+            let join_handle = magnet
+                .join_handle
+                .take()
+                .expect("metadata fetch join handle missing");
+            let alert_tx = self.alert_tx.clone();
+            rt::spawn(async move {
+                join_handle.await.expect("task error");
+                alert_tx.send(Alert::TorrentRemoved(id)).ok();
+            });
+            return;
+        }
+        let mut torrent = match self.torrents.remove(&id) {
+            Some(torrent) => torrent,
+            None => {
+                log::warn!("Torrent {} not found", id);
+                return;
+            }
+        };
+        log::info!("Removing torrent {}", id);
+        // the torrent task may no longer be running
+        torrent.tx.send(torrent::Command::Shutdown).ok();
+        // its slot, if it had one, goes to the next torrent in the queue
+        self.queue.remove(id);
+        self.update_queue();
+        if let Some(dir) = &self.conf.engine.state_dir {
+            if let Err(e) = state::remove_torrent(dir, &torrent.info_hash) {
+                log::warn!("Error removing torrent {} state: {}", id, e);
+            }
+        }
+        self.save_session();
+        let join_handle = torrent
+            .join_handle
+            .take()
+            .expect("torrent join handle missing");
+        let disk_tx = self.disk_tx.clone();
+        let alert_tx = self.alert_tx.clone();
+        rt::spawn(async move {
+            if let Err(e) = join_handle.await.expect("task error") {
+                log::error!("Torrent error: {}", e);
+            }
+            // the disk task may no longer be running if the engine was shut
+            // down in the meantime
+            disk_tx
+                .send(disk::Command::RemoveTorrent {
+                    id,
+                    delete_files: delete_files == DeleteFiles::Yes,
+                })
+                .ok();
+            alert_tx.send(Alert::TorrentRemoved(id)).ok();
+        });
+    }
+
     /// Gracefully shuts down the engine and all its components.
+    ///
+    /// The order matters: the watch task stops first, so that no torrents are
+    /// added in the meantime. Then the torrents stop, each of which stops
+    /// accepting peers, tells its trackers it's leaving, and closes its peer
+    /// connections. Then the DHT, LSD and port mapping tasks are stopped, and
+    /// only then the disk task, so that it has all the torrents' writes by
+    /// the time it syncs the files. The session state is saved last.
     async fn shutdown(&mut self) -> Result<()> {
         log::info!("Shutting down engine");
 
+        // no more torrents are added from the watched directory
+        if let Some(watch_tx) = &self.watch_tx {
+            // the watch task may no longer be running
+            watch_tx.send(watch::Command::Shutdown).ok();
+        }
+        if let Some(join_handle) = self.watch_join_handle.take() {
+            join_handle.await.expect("watch task has panicked");
+        }
+
+        // magnets whose metadata is still being fetched are dropped, as they
+        // are not saved with the session
+        for magnet in self.magnets.values() {
+            magnet.tx.send(torrent::Command::Shutdown).ok();
+        }
+        for magnet in self.magnets.values_mut() {
+            if let Some(join_handle) = magnet.join_handle.take() {
+                join_handle.await.expect("task error");
+            }
+        }
+
         // tell all torrents to shut down and join their tasks
         for torrent in self.torrents.values_mut() {
             // the torrent task may no longer be running, so don't panic here
@@ -344,7 +3044,42 @@ impl Engine {
             }
         }
 
-        // send a shutdown command to disk
+        if let Some(dht_tx) = &self.dht_tx {
+            // the DHT task may no longer be running
+            dht_tx.send(dht::Command::Shutdown).ok();
+        }
+        if let Some(join_handle) = self.dht_join_handle.take() {
+            if let Err(e) = join_handle.await.expect("DHT task has panicked") {
+                log::error!("DHT error: {}", e);
+            }
+        }
+
+        if let Some(lsd_tx) = &self.lsd_tx {
+            // the LSD task may no longer be running
+            lsd_tx.send(lsd::Command::Shutdown).ok();
+        }
+        if let Some(join_handle) = self.lsd_join_handle.take() {
+            if let Err(e) = join_handle.await.expect("LSD task has panicked") {
+                log::error!("LSD error: {}", e);
+            }
+        }
+
+        // torrents have already asked for their ports to be unmapped, and the
+        // port mapper unmaps the rest before it stops
+        if let Some(port_mapping_tx) = &self.port_mapping_tx {
+            // the port mapping task may no longer be running
+            port_mapping_tx.send(port_mapping::Command::Shutdown).ok();
+        }
+        if let Some(join_handle) = self.port_mapping_join_handle.take() {
+            if let Err(e) =
+                join_handle.await.expect("port mapping task has panicked")
+            {
+                log::error!("Port mapping error: {}", e);
+            }
+        }
+
+        // send a shutdown command to disk, which finishes the pending writes
+        // and syncs all files
         self.disk_tx.send(disk::Command::Shutdown)?;
         // and join on its handle
         self.disk_join_handle
@@ -354,6 +3089,18 @@ impl Engine {
             .expect("Disk task has panicked")
             .map_err(Error::from)?;
 
+        // The torrents sent their state before stopping, which is saved along
+        // with the session only now that their pieces are synced to disk, so
+        // that the resume data doesn't claim pieces that could still be lost.
+        if self.conf.engine.state_dir.is_some() {
+            while let Ok(cmd) = self.cmd_rx.get_mut().try_recv() {
+                if let Command::TorrentState { id, state } = cmd {
+                    self.save_resume_data(id, state);
+                }
+            }
+            self.save_session();
+        }
+
         return Ok(());
     }
 }
@@ -363,12 +3110,24 @@ impl Mode {
         match self {
             Self::Download { .. } => Bitfield::repeat(false, piece_count),
             Self::Seed => Bitfield::repeat(true, piece_count),
+            Self::Resume { own_pieces, .. } => {
+                let mut own_pieces = own_pieces.clone();
+                if own_pieces.len() != piece_count {
+                    log::warn!(
+                        "Resume bitfield has {} pieces instead of {}",
+                        own_pieces.len(),
+                        piece_count
+                    );
+                    own_pieces.resize(piece_count, false);
+                }
+                own_pieces
+            }
         }
     }
 
     fn seeds(self) -> Vec<SocketAddr> {
         match self {
-            Self::Download { seeds } => seeds,
+            Self::Download { seeds } | Self::Resume { seeds, .. } => seeds,
             _ => Vec::new(),
         }
     }
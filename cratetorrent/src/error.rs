@@ -2,12 +2,18 @@
 //! reported via the [alert system](crate::alert), as most operations via the
 //! engine happen asynchronously.
 
-use std::{fmt, net::SocketAddr};
+use std::fmt;
 
-use crate::TorrentId;
+use reqwest::Url;
+
+use crate::{
+    conf::BindAddr, magnet::MagnetError, metainfo::MetainfoError, TorrentId,
+};
 
 pub use crate::{
-    peer::error::PeerError, torrent::error::TorrentError, tracker::TrackerError,
+    peer::error::PeerError,
+    torrent::error::TorrentError,
+    tracker::{HttpError, TlsError, TrackerError},
 };
 pub use tokio::{io::Error as IoError, sync::mpsc::error::SendError};
 
@@ -26,17 +32,53 @@ pub enum Error {
     /// The torrent ID did not correspond to any entry. This is returned when
     /// the user specified a torrent that does not exist.
     InvalidTorrentId,
+    /// The file index did not correspond to any file in the torrent.
+    InvalidFileIndex,
+    /// The metainfo of the torrent passed to
+    /// [`EngineHandle::add_torrent`](crate::engine::EngineHandle::add_torrent)
+    /// could not be parsed.
+    InvalidMetainfo(MetainfoError),
+    /// The tracker URL's protocol is not supported, or the tracker can't be
+    /// reached through the configured proxy.
+    InvalidTrackerUrl(Url),
+    /// The magnet link passed to
+    /// [`EngineHandle::add_magnet`](crate::engine::EngineHandle::add_magnet)
+    /// could not be parsed.
+    InvalidMagnet(MagnetError),
+    /// The metadata downloaded for a torrent added from a magnet link matches
+    /// its info hash but is not a valid info dictionary, so the torrent is
+    /// removed.
+    InvalidMetadata { id: TorrentId, error: MetainfoError },
+    /// The item to be stored in the DHT is not valid bencode, or its value or
+    /// salt is too long.
+    InvalidDhtItem,
     /// Holds global IO related errors.
     Io(IoError),
+    /// The configured network interface has no address, e.g. because it's
+    /// down, so no peers can be connected until it's back.
+    ///
+    /// See [`EngineConf::bind_addr`](crate::conf::EngineConf::bind_addr).
+    BindAddrUnavailable(BindAddr),
+    /// The engine's HTTP client could not be set up, e.g. due to an invalid
+    /// proxy configuration.
+    Http(HttpError),
+    /// The TLS settings of HTTPS trackers could not be applied, e.g. due to
+    /// an invalid root certificate.
+    Tls(TlsError),
     /// An error specific to a torrent.
     Torrent { id: TorrentId, error: TorrentError },
+    /// An error that occurred while allocating, writing, or reading
+    /// a torrent's files.
+    Disk { id: TorrentId, error: DiskError },
     /// An error that occurred while a torrent was announcing to tracker.
-    Tracker { id: TorrentId, error: TrackerError },
-    /// An error that occurred in a torrent's session with a peer.
-    Peer {
+    ///
+    /// If the tracker rejected the announce, e.g. because the torrent is not
+    /// registered with it, the error is [`TrackerError::Failure`] with the
+    /// tracker's message.
+    Tracker {
         id: TorrentId,
-        addr: SocketAddr,
-        error: PeerError,
+        url: Url,
+        error: TrackerError,
     },
 }
 
@@ -47,15 +89,30 @@ impl fmt::Display for Error {
             Channel => write!(fmt, "channel error"),
             InvalidDownloadPath => write!(fmt, "invalid download path"),
             InvalidTorrentId => write!(fmt, "invalid torrent id"),
+            InvalidFileIndex => write!(fmt, "invalid file index"),
+            InvalidMetainfo(e) => write!(fmt, "invalid metainfo: {}", e),
+            InvalidTrackerUrl(url) => {
+                write!(fmt, "invalid tracker url {}", url)
+            }
+            InvalidMagnet(e) => write!(fmt, "{}", e),
+            InvalidMetadata { id, error } => {
+                write!(fmt, "torrent {} invalid metadata: {}", id, error)
+            }
+            InvalidDhtItem => write!(fmt, "invalid DHT item"),
             Io(e) => e.fmt(fmt),
+            BindAddrUnavailable(addr) => {
+                write!(fmt, "{} has no address", addr)
+            }
+            Http(e) => e.fmt(fmt),
+            Tls(e) => e.fmt(fmt),
             Torrent { id, error } => {
                 write!(fmt, "torrent {} error: {}", id, error)
             }
-            Tracker { id, error } => {
-                write!(fmt, "torrent {} tracker error: {}", id, error)
+            Disk { id, error } => {
+                write!(fmt, "torrent {} disk error: {}", id, error)
             }
-            Peer { id, addr, error } => {
-                write!(fmt, "torrent {} peer {} error: {}", id, addr, error)
+            Tracker { id, url, error } => {
+                write!(fmt, "torrent {} tracker {} error: {}", id, url, error)
             }
         }
     }
@@ -65,12 +122,39 @@ impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         use Error::*;
         match self {
+            InvalidMetainfo(e) => Some(e),
+            InvalidMagnet(e) => Some(e),
+            InvalidMetadata { error, .. } => Some(error),
             Io(e) => Some(e),
+            Http(e) => Some(e),
+            Tls(e) => Some(e),
+            Disk { error, .. } => Some(error),
             _ => None,
         }
     }
 }
 
+/// Errors are serialized as their messages, as the IO and HTTP errors they
+/// may wrap can't be serialized, and so alerts can't be deserialized either.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Error {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serialize_message(self, serializer)
+    }
+}
+
+/// Serializes the error as its message.
+#[cfg(feature = "serde")]
+pub(crate) fn serialize_message<S: serde::Serializer>(
+    error: &impl fmt::Display,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(error)
+}
+
 impl From<IoError> for Error {
     fn from(e: IoError) -> Self {
         // the pieces field is a concatenation of 20 byte SHA-1 hashes, so it
@@ -79,8 +163,58 @@ impl From<IoError> for Error {
     }
 }
 
+impl From<HttpError> for Error {
+    fn from(e: HttpError) -> Self {
+        Self::Http(e)
+    }
+}
+
 impl<T> From<SendError<T>> for Error {
     fn from(_: SendError<T>) -> Self {
         Self::Channel
     }
 }
+
+/// An IO error that occurred while accessing a torrent's files.
+///
+/// Unlike [`TorrentError`], these are not fatal to the torrent.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum DiskError {
+    /// The torrent's files could not be created.
+    Allocation(IoError),
+    /// A downloaded piece could not be written, so it has to be downloaded
+    /// again.
+    Write(IoError),
+    /// A block requested by a peer could not be read, so it was not sent.
+    Read(IoError),
+    /// The files of a removed torrent could not be deleted.
+    Deletion(IoError),
+    /// A file or the root of a torrent could not be renamed, so it was left
+    /// where it was.
+    Rename(IoError),
+}
+
+impl fmt::Display for DiskError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use DiskError::*;
+        match self {
+            Allocation(e) => write!(fmt, "allocation error: {}", e),
+            Write(e) => write!(fmt, "write error: {}", e),
+            Read(e) => write!(fmt, "read error: {}", e),
+            Deletion(e) => write!(fmt, "deletion error: {}", e),
+            Rename(e) => write!(fmt, "rename error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DiskError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use DiskError::*;
+        match self {
+            Allocation(e) | Write(e) | Read(e) | Deletion(e) | Rename(e) => {
+                Some(e)
+            }
+        }
+    }
+}
@@ -0,0 +1,290 @@
+//! Detection of our public IP address.
+//!
+//! We can't reliably tell our public address from the local network
+//! interfaces, e.g. if we're behind a NAT. However, trackers may report the
+//! address they see us connecting from in their announce responses, and peers
+//! that support the extension protocol do the same in their extended
+//! handshake, and DHT nodes in their responses. Since any single tracker or peer may lie or be mistaken, each of
+//! them gets a vote and the address with the most votes wins.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Mutex,
+};
+
+use reqwest::Url;
+
+use crate::alert::{Alert, AlertSender};
+
+use crate::logging;
+
+/// The maximum number of votes we keep. When exceeded, voting starts afresh,
+/// so that a change of our address is eventually picked up, but the current
+/// results are kept until a new majority emerges.
+const MAX_VOTER_COUNT: usize = 1000;
+
+/// Who reported our external address.
+///
+/// Each voter only has a single vote, so that a single tracker or peer can't
+/// sway the outcome by reporting the same address repeatedly.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Voter {
+    Tracker(Url),
+    Peer(IpAddr),
+    DhtNode(IpAddr),
+}
+
+/// Collects the external address reports of trackers and peers in the engine,
+/// and determines our external IPv4 and IPv6 addresses by majority.
+#[derive(Debug)]
+pub(crate) struct ExternalIp {
+    votes: Mutex<Votes>,
+    /// The detected addresses are posted to the user each time they change.
+    alert_tx: AlertSender,
+}
+
+#[derive(Debug, Default)]
+struct Votes {
+    /// The address each voter reported last.
+    by_voter: HashMap<Voter, IpAddr>,
+    ipv4: Option<Ipv4Addr>,
+    ipv6: Option<Ipv6Addr>,
+}
+
+impl ExternalIp {
+    pub fn new(alert_tx: AlertSender) -> Self {
+        Self {
+            votes: Mutex::default(),
+            alert_tx,
+        }
+    }
+
+    /// Returns the detected external IPv4 address, if any.
+    pub fn ipv4(&self) -> Option<Ipv4Addr> {
+        self.votes.lock().unwrap().ipv4
+    }
+
+    /// Returns the detected external IPv6 address, if any.
+    pub fn ipv6(&self) -> Option<Ipv6Addr> {
+        self.votes.lock().unwrap().ipv6
+    }
+
+    /// Returns whether the peer with the address is on our local network,
+    /// either because its address is not publicly routable, or because it's
+    /// behind the same NAT as us and so shares our external address.
+    pub fn is_local_peer(&self, ip: IpAddr) -> bool {
+        if !is_global(ip) {
+            return true;
+        }
+        let votes = self.votes.lock().unwrap();
+        match ip {
+            IpAddr::V4(ip) => votes.ipv4 == Some(ip),
+            IpAddr::V6(ip) => votes.ipv6 == Some(ip),
+        }
+    }
+
+    /// Records the address reported by the voter, replacing its previous vote,
+    /// and updates the detected address of its address family if a new
+    /// majority emerges.
+    ///
+    /// Addresses that aren't publicly routable, such as those reported by
+    /// peers on the local network, are ignored.
+    pub fn vote(&self, voter: Voter, ip: IpAddr) {
+        if !is_global(ip) {
+            return;
+        }
+
+        let mut votes = self.votes.lock().unwrap();
+        if votes.by_voter.len() >= MAX_VOTER_COUNT
+            && !votes.by_voter.contains_key(&voter)
+        {
+            votes.by_voter.clear();
+        }
+        votes.by_voter.insert(voter, ip);
+
+        let is_changed = match ip {
+            IpAddr::V4(_) => {
+                let ipv4 = votes.majority(votes.ipv4.map(IpAddr::V4), ip);
+                let is_changed = votes.ipv4.map(IpAddr::V4) != Some(ipv4);
+                if let IpAddr::V4(ipv4) = ipv4 {
+                    votes.ipv4 = Some(ipv4);
+                }
+                is_changed
+            }
+            IpAddr::V6(_) => {
+                let ipv6 = votes.majority(votes.ipv6.map(IpAddr::V6), ip);
+                let is_changed = votes.ipv6.map(IpAddr::V6) != Some(ipv6);
+                if let IpAddr::V6(ipv6) = ipv6 {
+                    votes.ipv6 = Some(ipv6);
+                }
+                is_changed
+            }
+        };
+
+        if is_changed {
+            log::info!(
+                target: logging::ENGINE,
+                "Detected external IPv4: {:?}, IPv6: {:?}",
+                votes.ipv4,
+                votes.ipv6
+            );
+            self.alert_tx
+                .send(Alert::ExternalIp {
+                    ipv4: votes.ipv4,
+                    ipv6: votes.ipv6,
+                })
+                .ok();
+        }
+    }
+}
+
+impl Votes {
+    /// Returns the address with the most votes in the address family of the
+    /// given address, which must have at least one vote.
+    ///
+    /// On a tie, the current address is kept.
+    fn majority(&self, current: Option<IpAddr>, ip: IpAddr) -> IpAddr {
+        let mut counts = HashMap::new();
+        for vote in self.by_voter.values() {
+            if vote.is_ipv4() == ip.is_ipv4() {
+                *counts.entry(*vote).or_insert(0) += 1;
+            }
+        }
+        let (leader, leader_count) = counts
+            .iter()
+            .max_by_key(|(_, &count)| count)
+            .map(|(&ip, &count)| (ip, count))
+            .expect("voted address must have a vote");
+        match current {
+            Some(current)
+                if counts.get(&current).copied().unwrap_or(0)
+                    >= leader_count =>
+            {
+                current
+            }
+            _ => leader,
+        }
+    }
+}
+
+/// Parses an IPv4 or IPv6 address from its 4 or 16 byte network order
+/// representation, as sent by trackers and peers.
+pub(crate) fn parse_ip(b: &[u8]) -> Option<IpAddr> {
+    match b.len() {
+        4 => {
+            let mut octets = [0; 4];
+            octets.copy_from_slice(b);
+            Some(Ipv4Addr::from(octets).into())
+        }
+        16 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(b);
+            Some(Ipv6Addr::from(octets).into())
+        }
+        _ => None,
+    }
+}
+
+/// Returns whether the address is publicly routable.
+pub(crate) fn is_global(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation())
+        }
+        IpAddr::V6(ip) => {
+            let first_segment = ip.segments()[0];
+            // unique local (fc00::/7) and link local (fe80::/10) addresses
+            // are not routable
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || first_segment & 0xfe00 == 0xfc00
+                || first_segment & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn should_detect_ip_by_majority() {
+        let (alert_tx, mut alert_rx) = mpsc::unbounded_channel();
+        let external_ip = ExternalIp::new(alert_tx);
+        let ip1 = Ipv4Addr::new(1, 2, 3, 4);
+        let ip2 = Ipv4Addr::new(5, 6, 7, 8);
+        let peer = |i| Voter::Peer(Ipv4Addr::new(9, 9, 9, i).into());
+
+        external_ip.vote(peer(1), ip1.into());
+        assert_eq!(external_ip.ipv4(), Some(ip1));
+        assert!(matches!(
+            alert_rx.try_recv(),
+            Ok(Alert::ExternalIp { ipv4: Some(ip), ipv6: None }) if ip == ip1
+        ));
+
+        // a tie doesn't change the detected address
+        external_ip.vote(peer(2), ip2.into());
+        assert_eq!(external_ip.ipv4(), Some(ip1));
+
+        // a voter may only vote once
+        external_ip.vote(peer(2), ip2.into());
+        assert_eq!(external_ip.ipv4(), Some(ip1));
+
+        external_ip.vote(peer(3), ip2.into());
+        assert_eq!(external_ip.ipv4(), Some(ip2));
+
+        // IPv6 addresses are tallied separately
+        let ip6 = Ipv6Addr::new(0x2a00, 0, 0, 0, 0, 0, 0, 1);
+        let url: Url = "http://tracker.example.com/announce".parse().unwrap();
+        external_ip.vote(Voter::Tracker(url), ip6.into());
+        assert_eq!(external_ip.ipv4(), Some(ip2));
+        assert_eq!(external_ip.ipv6(), Some(ip6));
+    }
+
+    #[test]
+    fn should_ignore_non_global_ip() {
+        let (alert_tx, _alert_rx) = mpsc::unbounded_channel();
+        let external_ip = ExternalIp::new(alert_tx);
+        let peer = Voter::Peer(Ipv4Addr::new(9, 9, 9, 9).into());
+
+        external_ip.vote(peer.clone(), Ipv4Addr::new(192, 168, 0, 2).into());
+        external_ip.vote(peer.clone(), Ipv4Addr::LOCALHOST.into());
+        external_ip.vote(peer, "fe80::1".parse().unwrap());
+        assert_eq!(external_ip.ipv4(), None);
+        assert_eq!(external_ip.ipv6(), None);
+    }
+
+    #[test]
+    fn should_detect_local_peer() {
+        let (alert_tx, _alert_rx) = mpsc::unbounded_channel();
+        let external_ip = ExternalIp::new(alert_tx);
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+
+        assert!(external_ip.is_local_peer([192, 168, 0, 2].into()));
+        assert!(external_ip.is_local_peer("fe80::1".parse().unwrap()));
+        assert!(!external_ip.is_local_peer(ip.into()));
+
+        // peers behind the same NAT share our external address
+        external_ip.vote(Voter::Peer([9, 9, 9, 9].into()), ip.into());
+        assert!(external_ip.is_local_peer(ip.into()));
+        assert!(!external_ip.is_local_peer([5, 6, 7, 8].into()));
+    }
+
+    #[test]
+    fn should_parse_ip() {
+        assert_eq!(parse_ip(&[1, 2, 3, 4]), Some([1, 2, 3, 4].into()));
+        assert_eq!(
+            parse_ip(&Ipv6Addr::LOCALHOST.octets()),
+            Some(Ipv6Addr::LOCALHOST.into())
+        );
+        assert_eq!(parse_ip(&[1, 2, 3]), None);
+    }
+}
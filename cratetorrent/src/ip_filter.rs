@@ -0,0 +1,487 @@
+//! Filtering of the IP addresses of peers.
+//!
+//! An [`IpFilter`] is a set of blocked IP address ranges, which may be built
+//! from CIDR ranges or loaded from the blocklists published for other clients,
+//! in the eMule (`.dat`) or PeerGuardian (`.p2p`) text formats. The engine
+//! applies it to all its torrents: peers in a blocked range are neither
+//! connected to, whichever source they came from, nor allowed to connect to
+//! us.
+
+use std::{
+    fmt, fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+};
+
+use crate::logging;
+
+/// A set of blocked IP address ranges.
+///
+/// Lookups take logarithmic time in the number of ranges, so large blocklists
+/// with hundreds of thousands of entries are fine.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IpFilter {
+    /// The sorted, non-overlapping, inclusive IPv4 ranges.
+    v4: Vec<(u128, u128)>,
+    /// The sorted, non-overlapping, inclusive IPv6 ranges.
+    v6: Vec<(u128, u128)>,
+}
+
+impl IpFilter {
+    /// Creates a filter that doesn't block any address.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses a blocklist, in which each line is one of:
+    /// - an eMule style range: `1.2.3.0 - 1.2.3.255 , 000 , description`,
+    ///   where ranges with an access level above 127 are allowed rather than
+    ///   blocked,
+    /// - a PeerGuardian style range: `description:1.2.3.0-1.2.3.255`,
+    /// - a CIDR range, such as `1.2.3.0/24` or `2001:db8::/32`,
+    /// - or a single address.
+    ///
+    /// Empty lines and lines starting with `#` or `//` are skipped, as are
+    /// malformed lines, which are common in published lists.
+    pub fn parse(s: &str) -> Self {
+        let mut filter = Self::default();
+        for line in s.lines() {
+            let line = line.trim();
+            if line.is_empty()
+                || line.starts_with('#')
+                || line.starts_with("//")
+            {
+                continue;
+            }
+            match parse_line(line) {
+                Some(Some((start, end))) => filter.push(start, end),
+                // a range that is explicitly allowed
+                Some(None) => {}
+                None => {
+                    log::debug!(
+                        target: logging::ENGINE,
+                        "Skipping invalid IP filter line {}",
+                        line
+                    )
+                }
+            }
+        }
+        filter.normalize();
+        filter
+    }
+
+    /// Loads the blocklist at the path. See [`Self::parse`] for the supported
+    /// formats.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let buf = fs::read(path)?;
+        Ok(Self::parse(&String::from_utf8_lossy(&buf)))
+    }
+
+    /// Blocks the addresses from `start` to `end`, inclusive. Ranges whose
+    /// ends are of different address families are ignored.
+    pub fn block(&mut self, start: IpAddr, end: IpAddr) {
+        if start.is_ipv4() != end.is_ipv4() {
+            return;
+        }
+        self.push(start, end);
+        self.normalize();
+    }
+
+    /// Blocks the CIDR range, such as `10.0.0.0/8`.
+    pub fn block_cidr(&mut self, cidr: &str) -> Result<(), InvalidRange> {
+        let (start, end) =
+            parse_cidr(cidr).ok_or_else(|| InvalidRange(cidr.to_string()))?;
+        self.block(start, end);
+        Ok(())
+    }
+
+    /// Returns whether the address is in a blocked range.
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        let (ranges, ip) = match to_canonical(ip) {
+            IpAddr::V4(ip) => (&self.v4, u32::from(ip) as u128),
+            IpAddr::V6(ip) => (&self.v6, u128::from(ip)),
+        };
+        // the last range that starts at or before the address is the only
+        // one that may contain it
+        match ranges.binary_search_by(|(start, _)| start.cmp(&ip)) {
+            Ok(_) => true,
+            Err(0) => false,
+            Err(index) => ranges[index - 1].1 >= ip,
+        }
+    }
+
+    /// Returns the number of blocked ranges, after merging overlapping ones.
+    pub fn range_count(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    /// Returns whether the filter blocks no addresses.
+    pub fn is_empty(&self) -> bool {
+        self.range_count() == 0
+    }
+
+    /// Adds the range without restoring the order of the ranges.
+    fn push(&mut self, start: IpAddr, end: IpAddr) {
+        let (ranges, start, end) =
+            match (to_canonical(start), to_canonical(end)) {
+                (IpAddr::V4(start), IpAddr::V4(end)) => (
+                    &mut self.v4,
+                    u32::from(start) as u128,
+                    u32::from(end) as u128,
+                ),
+                (IpAddr::V6(start), IpAddr::V6(end)) => {
+                    (&mut self.v6, u128::from(start), u128::from(end))
+                }
+                _ => return,
+            };
+        ranges.push((start.min(end), start.max(end)));
+    }
+
+    /// Sorts the ranges and merges the overlapping and adjacent ones.
+    fn normalize(&mut self) {
+        for ranges in [&mut self.v4, &mut self.v6].iter_mut() {
+            ranges.sort_unstable();
+            let mut merged: Vec<(u128, u128)> =
+                Vec::with_capacity(ranges.len());
+            for &(start, end) in ranges.iter() {
+                match merged.last_mut() {
+                    Some(last) if start <= last.1.saturating_add(1) => {
+                        last.1 = last.1.max(end);
+                    }
+                    _ => merged.push((start, end)),
+                }
+            }
+            **ranges = merged;
+        }
+    }
+}
+
+/// The filter is serialized as the list of its blocked ranges, as pairs of
+/// their first and last addresses, which are blocked again when deserialized.
+#[cfg(feature = "serde")]
+impl serde::Serialize for IpFilter {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let v4 = self.v4.iter().map(|&(start, end)| {
+            (
+                IpAddr::from(Ipv4Addr::from(start as u32)),
+                IpAddr::from(Ipv4Addr::from(end as u32)),
+            )
+        });
+        let v6 = self.v6.iter().map(|&(start, end)| {
+            (
+                IpAddr::from(Ipv6Addr::from(start)),
+                IpAddr::from(Ipv6Addr::from(end)),
+            )
+        });
+        serializer.collect_seq(v4.chain(v6))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for IpFilter {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        let ranges: Vec<(IpAddr, IpAddr)> =
+            serde::Deserialize::deserialize(deserializer)?;
+        let mut filter = Self::default();
+        for (start, end) in ranges {
+            filter.push(start, end);
+        }
+        filter.normalize();
+        Ok(filter)
+    }
+}
+
+/// The error returned for an invalid CIDR range.
+#[derive(Debug)]
+pub struct InvalidRange(String);
+
+impl fmt::Display for InvalidRange {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "invalid IP range {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidRange {}
+
+/// The number of peers blocked by the engine's IP filter.
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct IpFilterStats {
+    /// The number of peers returned by trackers, the DHT, or other sources
+    /// that were not connected to.
+    pub blocked_peer_count: u64,
+    /// The number of incoming connections that were rejected.
+    pub blocked_connection_count: u64,
+}
+
+/// The engine's IP filter, shared by its torrents, which counts the peers it
+/// blocks.
+#[derive(Debug, Default)]
+pub(crate) struct SharedIpFilter {
+    filter: RwLock<IpFilter>,
+    blocked_peer_count: AtomicU64,
+    blocked_connection_count: AtomicU64,
+}
+
+impl SharedIpFilter {
+    pub fn new(filter: IpFilter) -> Self {
+        Self {
+            filter: RwLock::new(filter),
+            ..Default::default()
+        }
+    }
+
+    /// Replaces the filter. Peers that are already connected are not
+    /// affected.
+    pub fn set_filter(&self, filter: IpFilter) {
+        *self.filter.write().unwrap() = filter;
+    }
+
+    /// Returns whether we may connect to the peer.
+    pub fn allows_peer(&self, addr: &SocketAddr) -> bool {
+        let is_blocked = self.filter.read().unwrap().is_blocked(addr.ip());
+        if is_blocked {
+            log::debug!(
+                target: logging::ENGINE,
+                "Peer {} blocked by IP filter",
+                addr
+            );
+            self.blocked_peer_count.fetch_add(1, Ordering::Relaxed);
+        }
+        !is_blocked
+    }
+
+    /// Returns whether we may accept the peer's connection.
+    pub fn allows_connection(&self, addr: &SocketAddr) -> bool {
+        let is_blocked = self.filter.read().unwrap().is_blocked(addr.ip());
+        if is_blocked {
+            log::debug!(
+                target: logging::ENGINE,
+                "Connection from {} blocked by IP filter",
+                addr
+            );
+            self.blocked_connection_count
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        !is_blocked
+    }
+
+    pub fn stats(&self) -> IpFilterStats {
+        IpFilterStats {
+            blocked_peer_count: self.blocked_peer_count.load(Ordering::Relaxed),
+            blocked_connection_count: self
+                .blocked_connection_count
+                .load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Parses a blocklist line, returning `Some(None)` if it's a range that is
+/// allowed rather than blocked.
+fn parse_line(line: &str) -> Option<Option<(IpAddr, IpAddr)>> {
+    // eMule: start - end , access level , description
+    if line.contains(',') {
+        let mut fields = line.split(',');
+        let range = parse_range(fields.next()?)?;
+        let access_level: u32 = fields.next()?.trim().parse().ok()?;
+        return Some(if access_level <= 127 {
+            Some(range)
+        } else {
+            None
+        });
+    }
+    if let Some(range) = parse_cidr(line) {
+        return Some(Some(range));
+    }
+    // PeerGuardian: description:start-end, where the description may contain
+    // colons, so the range is after the last one
+    let range = &line[line.rfind(':')? + 1..];
+    parse_range(range).map(Some)
+}
+
+/// Parses a range of the form `start - end`.
+fn parse_range(s: &str) -> Option<(IpAddr, IpAddr)> {
+    let mut ends = s.splitn(2, '-');
+    let start = parse_ip(ends.next()?)?;
+    let end = parse_ip(ends.next()?)?;
+    if start.is_ipv4() == end.is_ipv4() {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+/// Parses a CIDR range, or a single address, into its first and last
+/// addresses.
+fn parse_cidr(s: &str) -> Option<(IpAddr, IpAddr)> {
+    let mut parts = s.trim().splitn(2, '/');
+    let ip = parse_ip(parts.next()?)?;
+    let bits = match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    };
+    let prefix_len: u32 = match parts.next() {
+        Some(prefix_len) => prefix_len.parse().ok()?,
+        None => bits,
+    };
+    if prefix_len > bits {
+        return None;
+    }
+    let host_mask = if prefix_len == 0 {
+        u128::MAX
+    } else {
+        (1u128 << (bits - prefix_len)) - 1
+    };
+    Some(match ip {
+        IpAddr::V4(ip) => {
+            let ip = u32::from(ip) as u128;
+            let start = ip & !host_mask;
+            let end = (start | host_mask) & u32::MAX as u128;
+            (
+                Ipv4Addr::from(start as u32).into(),
+                Ipv4Addr::from(end as u32).into(),
+            )
+        }
+        IpAddr::V6(ip) => {
+            let start = u128::from(ip) & !host_mask;
+            let end = start | host_mask;
+            (Ipv6Addr::from(start).into(), Ipv6Addr::from(end).into())
+        }
+    })
+}
+
+/// Parses an address, allowing the zero padded octets of eMule lists, such as
+/// `001.002.003.004`.
+fn parse_ip(s: &str) -> Option<IpAddr> {
+    let s = s.trim();
+    if s.contains(':') {
+        return s.parse::<Ipv6Addr>().ok().map(IpAddr::V6);
+    }
+    let mut octets = [0; 4];
+    let mut parts = s.split('.');
+    for octet in octets.iter_mut() {
+        *octet = parts.next()?.parse().ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(Ipv4Addr::from(octets).into())
+}
+
+/// Maps IPv4-mapped IPv6 addresses to IPv4, so that they are filtered by the
+/// IPv4 ranges.
+fn to_canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.octets() {
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => {
+                Ipv4Addr::new(a, b, c, d).into()
+            }
+            _ => ip,
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn should_block_cidr_ranges() {
+        let mut filter = IpFilter::new();
+        filter.block_cidr("10.0.0.0/8").unwrap();
+        filter.block_cidr("2001:db8::/32").unwrap();
+        filter.block_cidr("192.168.1.7").unwrap();
+        assert!(filter.block_cidr("10.0.0.0/33").is_err());
+        assert!(filter.block_cidr("not an ip").is_err());
+
+        assert!(filter.is_blocked(ip("10.0.0.0")));
+        assert!(filter.is_blocked(ip("10.255.255.255")));
+        assert!(!filter.is_blocked(ip("11.0.0.0")));
+        assert!(!filter.is_blocked(ip("9.255.255.255")));
+        assert!(filter.is_blocked(ip("192.168.1.7")));
+        assert!(!filter.is_blocked(ip("192.168.1.8")));
+        assert!(filter.is_blocked(ip("2001:db8:ffff::1")));
+        assert!(!filter.is_blocked(ip("2001:db9::1")));
+        // IPv4-mapped addresses are filtered as IPv4
+        assert!(filter.is_blocked(ip("::ffff:10.1.2.3")));
+    }
+
+    #[test]
+    fn should_merge_ranges() {
+        let mut filter = IpFilter::new();
+        filter.block(ip("1.0.0.0"), ip("1.0.0.255"));
+        filter.block(ip("1.0.1.0"), ip("1.0.1.255"));
+        filter.block(ip("1.0.0.128"), ip("1.0.0.130"));
+        filter.block(ip("3.0.0.0"), ip("2.0.0.0"));
+        assert_eq!(filter.range_count(), 2);
+        assert!(filter.is_blocked(ip("1.0.1.10")));
+        assert!(filter.is_blocked(ip("2.5.0.0")));
+
+        filter.block(ip("1.0.0.0"), ip("::1"));
+        assert_eq!(filter.range_count(), 2);
+    }
+
+    #[test]
+    fn should_parse_blocklists() {
+        let filter = IpFilter::parse(
+            "# a comment\n\
+            001.002.004.000 - 001.002.004.255 , 000 , Bogon\n\
+            005.006.007.000 - 005.006.007.255 , 200 , Allowed\n\
+            Some Org: Inc.:8.8.4.0-8.8.4.255\n\
+            172.16.0.0/12\n\
+            this line is garbage\n",
+        );
+        assert_eq!(filter.range_count(), 3);
+        assert!(filter.is_blocked(ip("1.2.4.100")));
+        assert!(!filter.is_blocked(ip("5.6.7.8")));
+        assert!(filter.is_blocked(ip("8.8.4.4")));
+        assert!(!filter.is_blocked(ip("8.8.8.8")));
+        assert!(filter.is_blocked(ip("172.20.0.1")));
+    }
+
+    #[test]
+    fn should_count_blocked_peers() {
+        let mut filter = IpFilter::new();
+        filter.block_cidr("10.0.0.0/8").unwrap();
+        let filter = SharedIpFilter::new(filter);
+        let blocked = SocketAddr::new(ip("10.0.0.1"), 6881);
+        let allowed = SocketAddr::new(ip("11.0.0.1"), 6881);
+        assert!(!filter.allows_peer(&blocked));
+        assert!(filter.allows_peer(&allowed));
+        assert!(!filter.allows_connection(&blocked));
+        assert!(!filter.allows_connection(&blocked));
+        let stats = filter.stats();
+        assert_eq!(stats.blocked_peer_count, 1);
+        assert_eq!(stats.blocked_connection_count, 2);
+    }
+
+    #[test]
+    fn should_replace_filter() {
+        let filter = SharedIpFilter::default();
+        let addr = SocketAddr::new(ip("10.0.0.1"), 6881);
+        assert!(filter.allows_peer(&addr));
+
+        let mut new_filter = IpFilter::new();
+        new_filter.block_cidr("10.0.0.0/8").unwrap();
+        filter.set_filter(new_filter);
+        assert!(!filter.allows_peer(&addr));
+    }
+}
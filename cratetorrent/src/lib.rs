@@ -61,6 +61,13 @@
 //! configuration is used for all new torrents, but this way it is possible to
 //! configure a torrent on a case-by-case basis.
 //!
+//! Alternatively, a torrent can be added with
+//! [`EngineHandle::add_torrent`](crate::engine::EngineHandle::add_torrent),
+//! which takes an [`AddTorrent`](crate::engine::AddTorrent) builder. It reads
+//! the metainfo from a file, or from a magnet link, and has further options,
+//! such as the torrent's own download directory and its initial file
+//! priorities.
+//!
 //! For now, the torrent's download mode has to be specified via `Mode`:
 //! whether to download or seed (upload) the torrent. If the latter is chosen,
 //! the torrent's contents _have_ to exist in the directory specified as the
@@ -87,6 +94,7 @@
 //!         metainfo,
 //!         // tell the engine to assign a randomly chosen free port
 //!         listen_addr: None,
+//!         piece_picker: None,
 //!         mode: Mode::Download { seeds: Vec::new() },
 //!         conf: None,
 //!     })?;
@@ -129,13 +137,51 @@
 //! seeding--it's indefinite until the user stops it.
 //!
 //! Therefore the application must make sure to provide its own way of stopping
-//! the download.
+//! the upload, e.g. by removing the torrent as described below.
+//!
+//! # Resuming
+//!
+//! A partially complete download may be continued with
+//! [`Mode::Resume`](crate::engine::Mode::Resume), given the bitfield of the
+//! pieces that are already on disk and verified. Only the missing pieces are
+//! then downloaded.
+//!
+//! # Managing torrents
+//!
+//! A torrent is stopped and removed from the engine with
+//! [`EngineHandle::remove_torrent`](crate::engine::EngineHandle::remove_torrent),
+//! which also deletes its downloaded files if passed
+//! [`DeleteFiles::Yes`](crate::engine::DeleteFiles::Yes). The ids of the
+//! torrents in the engine can be queried with
+//! [`EngineHandle::query_torrents`](crate::engine::EngineHandle::query_torrents).
+//!
+//! # Logging and tracing
+//!
+//! The engine logs through the [`log`](https://docs.rs/log) facade. Its tasks
+//! also run within [`tracing`](https://docs.rs/tracing) spans: one for each
+//! torrent, nested in it one for each peer connection, and one for each
+//! command executed by the disk task, recording the torrent and block it
+//! concerns. Installing a `tracing` subscriber along with
+//! [`tracing_log::LogTracer`](https://docs.rs/tracing-log) attaches the log
+//! records to these spans, so that the events of a torrent or a connection can
+//! be correlated.
+//!
+//! Each subsystem of the engine, such as the disk task or the DHT, logs with
+//! its own target, and the level of each can be changed at runtime, so that
+//! e.g. only one of them logs trace records. See the [`logging`] module.
+//!
+//! # Runtime
+//!
+//! The engine's tasks and timers run on tokio by default. To run them on
+//! another executor, the application implements [`rt::Runtime`] for it and
+//! sets it with [`rt::set_runtime`] before spawning the engine.
 
 // needed by the `select!` macro reaching the default recursion limit
 #![recursion_limit = "256"]
 
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_crate as serde;
 
 use std::{
     fmt,
@@ -152,22 +198,45 @@ pub mod alert;
 mod avg;
 pub mod conf;
 mod counter;
+#[cfg(feature = "daemon")]
+pub mod daemon;
+pub mod dht;
 mod disk;
 mod download;
 pub mod engine;
 pub mod error;
+mod external_ip;
 pub mod iovecs;
+pub mod ip_filter;
+mod local_addr;
+pub mod logging;
+mod lsd;
+pub mod magnet;
 pub mod metainfo;
+pub mod metrics;
 pub mod peer;
-mod piece_picker;
+pub mod piece_picker;
+pub mod port_mapping;
 pub mod prelude;
+mod proxy;
+mod queue;
+mod rate_limit;
+pub mod rt;
+mod state;
 pub mod storage_info;
+pub mod stream;
 pub mod torrent;
-mod tracker;
+pub mod tracker;
+mod watch;
 
 /// Each torrent gets a randomly assigned ID that is globally unique.
 /// This id is used in engine APIs to interact with torrents.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Ord, PartialOrd, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 pub struct TorrentId(u32);
 
 impl TorrentId {
@@ -182,6 +251,23 @@ impl TorrentId {
     }
 }
 
+/// The id as an integer, e.g. to pass it to applications written in other
+/// languages.
+impl From<TorrentId> for u32 {
+    fn from(id: TorrentId) -> Self {
+        id.0
+    }
+}
+
+/// The id of a torrent that was passed around as an integer. Engine methods
+/// called with an id that doesn't belong to a torrent treat it as they do
+/// any other invalid id.
+impl From<u32> for TorrentId {
+    fn from(id: u32) -> Self {
+        Self(id)
+    }
+}
+
 impl fmt::Display for TorrentId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "t#{}", self.0)
@@ -228,10 +314,42 @@ impl Default for Side {
     }
 }
 
-/// This is the only block length we're dealing with (except for possibly the
-/// last block).  It is the widely used and accepted 16 KiB.
+/// The download priority of a file.
+///
+/// Each piece is downloaded with the priority of the highest priority file it
+/// intersects: higher priority pieces are downloaded before lower priority
+/// ones, and pieces that only intersect skipped files are not downloaded at
+/// all.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub enum FilePriority {
+    /// The file is not downloaded.
+    Skip,
+    Low,
+    Normal,
+    High,
+}
+
+impl Default for FilePriority {
+    fn default() -> Self {
+        Self::Normal
+    }
+}
+
+/// This is the default block length in which we download pieces (except for
+/// possibly the last block). It is the widely used and accepted 16 KiB.
 pub(crate) const BLOCK_LEN: u32 = 0x4000;
 
+/// The longest block that we accept from peers, either as a request or as
+/// block data. While we request blocks of [`BLOCK_LEN`] by default, other
+/// clients occasionally use larger blocks, 128 KiB being the most common
+/// upper bound.
+pub(crate) const MAX_BLOCK_LEN: u32 = 0x20000;
+
 /// A block is a fixed size chunk of a piece, which in turn is a fixed size
 /// chunk of a torrent. Downloading torrents happen at this block level
 /// granularity.
@@ -241,23 +359,11 @@ pub(crate) struct BlockInfo {
     pub piece_index: PieceIndex,
     /// The zero-based byte offset into the piece.
     pub offset: u32,
-    /// The block's length in bytes. Always 16 KiB (0x4000 bytes) or less, for
-    /// now.
+    /// The block's length in bytes. Usually 16 KiB (0x4000 bytes) or less,
+    /// but never more than [`MAX_BLOCK_LEN`].
     pub len: u32,
 }
 
-impl BlockInfo {
-    /// Returns the index of the block within its piece, assuming the default
-    /// block length of 16 KiB.
-    pub fn index_in_piece(&self) -> usize {
-        // we need to use "lower than or equal" as this may be the last block in
-        // which case it may be shorter than the default block length
-        debug_assert!(self.len <= BLOCK_LEN);
-        debug_assert!(self.len > 0);
-        (self.offset / BLOCK_LEN) as usize
-    }
-}
-
 impl fmt::Display for BlockInfo {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
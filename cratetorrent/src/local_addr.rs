@@ -0,0 +1,218 @@
+//! The local address to which peer connections are bound, if the user
+//! configured one.
+//!
+//! The address may be given directly, or as the name of a network interface,
+//! such as a VPN's, in which case its address is looked up. The interface may
+//! go down or change its address while the engine is running, so the engine
+//! looks it up again on each tick.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    os::unix::io::FromRawFd,
+    sync::RwLock,
+};
+
+use nix::{
+    ifaddrs,
+    sys::socket::{
+        self, AddressFamily, InetAddr, SockAddr, SockFlag, SockType,
+    },
+};
+use tokio::net::TcpStream;
+
+use crate::{conf::BindAddr, logging, lsd::nix_to_io_error, rt};
+
+/// The local address of peer connections, shared by the engine, which keeps
+/// it up to date, and the torrents and peer sessions, which bind their
+/// sockets to it.
+#[derive(Debug, Default)]
+pub(crate) struct LocalAddr {
+    /// The address or interface the user configured, if any.
+    conf: Option<BindAddr>,
+    /// The current address of the configured interface, or none if it has no
+    /// address, e.g. because it's down.
+    ip: RwLock<Option<IpAddr>>,
+}
+
+impl LocalAddr {
+    pub fn new(conf: Option<BindAddr>) -> Self {
+        let ip = conf.as_ref().and_then(resolve);
+        Self {
+            conf,
+            ip: RwLock::new(ip),
+        }
+    }
+
+    /// Returns the address to bind sockets to, or none if no address is
+    /// configured, in which case the OS picks it.
+    ///
+    /// If the configured interface has no address, an error is returned, as
+    /// the connection must not go through another interface.
+    pub fn ip(&self) -> io::Result<Option<IpAddr>> {
+        match &self.conf {
+            None => Ok(None),
+            Some(conf) => match *self.ip.read().unwrap() {
+                Some(ip) => Ok(Some(ip)),
+                None => Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    format!("{} has no address", conf),
+                )),
+            },
+        }
+    }
+
+    /// Looks up the configured interface's address again.
+    ///
+    /// Returns whether the interface has an address, if that changed since
+    /// the last lookup.
+    pub fn update(&self) -> Option<bool> {
+        let conf = self.conf.as_ref()?;
+        let ip = resolve(conf);
+        let mut curr_ip = self.ip.write().unwrap();
+        if ip == *curr_ip {
+            return None;
+        }
+        match ip {
+            Some(ip) => {
+                log::info!(
+                    target: logging::ENGINE,
+                    "{} has address {}",
+                    conf,
+                    ip
+                )
+            }
+            None => {
+                log::warn!(target: logging::ENGINE, "{} has no address", conf)
+            }
+        }
+        let was_up = curr_ip.is_some();
+        *curr_ip = ip;
+        if was_up != ip.is_some() {
+            Some(ip.is_some())
+        } else {
+            None
+        }
+    }
+
+    /// Connects to the peer from the local address, if one is configured.
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        match self.ip()? {
+            None => TcpStream::connect(addr).await,
+            Some(ip) => {
+                // the standard library can't bind a socket before connecting
+                // it, so the socket is set up by hand and connected on
+                // a blocking thread
+                let socket = rt::spawn_blocking(move || connect_from(ip, addr))
+                    .await
+                    .expect("connect task has panicked")?;
+                TcpStream::from_std(socket)
+            }
+        }
+    }
+}
+
+/// Returns the address of the configured interface: its first IPv4 address,
+/// or if it has none, its first IPv6 address.
+fn resolve(conf: &BindAddr) -> Option<IpAddr> {
+    let name = match conf {
+        BindAddr::Ip(ip) => return Some(*ip),
+        BindAddr::Interface(name) => name,
+    };
+    let addrs = match ifaddrs::getifaddrs() {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            log::warn!(
+                target: logging::ENGINE,
+                "Error listing network interfaces: {}",
+                e
+            );
+            return None;
+        }
+    };
+    let mut ips: Vec<_> = addrs
+        .filter(|ifaddr| &ifaddr.interface_name == name)
+        .filter_map(|ifaddr| match ifaddr.address {
+            Some(SockAddr::Inet(addr)) => Some(addr.to_std().ip()),
+            _ => None,
+        })
+        .collect();
+    ips.sort_by_key(IpAddr::is_ipv6);
+    ips.first().copied()
+}
+
+/// Connects a socket bound to the local IP to the address, blocking until
+/// the connection is established or fails.
+fn connect_from(
+    ip: IpAddr,
+    addr: SocketAddr,
+) -> io::Result<std::net::TcpStream> {
+    let family = if addr.is_ipv4() {
+        AddressFamily::Inet
+    } else {
+        AddressFamily::Inet6
+    };
+    let fd = socket::socket(family, SockType::Stream, SockFlag::empty(), None)
+        .map_err(nix_to_io_error)?;
+    // SAFETY: the file descriptor was just created and is not owned by
+    // anything else, so the stream takes sole ownership of it and closes it
+    // even if a later step fails
+    let socket = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+    let local_addr = SocketAddr::new(ip, 0);
+    socket::bind(fd, &SockAddr::new_inet(InetAddr::from_std(&local_addr)))
+        .map_err(nix_to_io_error)?;
+    socket::connect(fd, &SockAddr::new_inet(InetAddr::from_std(&addr)))
+        .map_err(nix_to_io_error)?;
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_not_bind_without_conf() {
+        let local_addr = LocalAddr::new(None);
+        assert_eq!(local_addr.ip().unwrap(), None);
+        assert_eq!(local_addr.update(), None);
+    }
+
+    #[test]
+    fn should_bind_to_ip() {
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let local_addr = LocalAddr::new(Some(BindAddr::Ip(ip)));
+        assert_eq!(local_addr.ip().unwrap(), Some(ip));
+        assert_eq!(local_addr.update(), None);
+    }
+
+    #[test]
+    fn should_resolve_loopback_interface() {
+        let local_addr = LocalAddr::new(Some(BindAddr::Interface("lo".into())));
+        assert_eq!(
+            local_addr.ip().unwrap(),
+            Some("127.0.0.1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn should_fail_on_missing_interface() {
+        let local_addr = LocalAddr::new(Some(BindAddr::Interface(
+            "cratetorrent-missing".into(),
+        )));
+        assert!(local_addr.ip().is_err());
+        assert_eq!(local_addr.update(), None);
+    }
+
+    #[tokio::test]
+    async fn should_connect_from_ip() {
+        let mut listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let ip = addr.ip();
+        let local_addr = LocalAddr::new(Some(BindAddr::Ip(ip)));
+        let socket = local_addr.connect(addr).await.unwrap();
+        let (_, peer_addr) = listener.accept().await.unwrap();
+        assert_eq!(socket.local_addr().unwrap(), peer_addr);
+        assert_eq!(peer_addr.ip(), ip);
+    }
+}
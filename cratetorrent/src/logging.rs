@@ -0,0 +1,266 @@
+//! Per-subsystem log levels.
+//!
+//! The engine's log records have the target of the subsystem they come from,
+//! or a target nested in it: `cratetorrent::engine`, `cratetorrent::torrent`,
+//! `cratetorrent::peer`, `cratetorrent::tracker`, `cratetorrent::dht`, or
+//! `cratetorrent::disk`. Loggers that filter by target prefix, such as
+//! [`env_logger`](https://docs.rs/env_logger), can be told to show only those
+//! of one subsystem, e.g. with `RUST_LOG=cratetorrent::dht=trace`.
+//!
+//! To change the levels while the engine is running, the application's logger
+//! is wrapped in a [`Filter`], which drops the records above the level of
+//! their subsystem. The levels are set initially from
+//! [`EngineConf::log_levels`](crate::conf::EngineConf::log_levels), and may be
+//! changed at any time with [`set_level`]. As the `log` facade is global, so
+//! are the levels. For the trace records of a subsystem to reach the filter,
+//! the maximum level of the facade and of the wrapped logger must allow them.
+//!
+//! ```no_run
+//! use cratetorrent::logging::{self, Filter, Subsystem};
+//! use log::LevelFilter;
+//! # struct MyLogger;
+//! # impl log::Log for MyLogger {
+//! #     fn enabled(&self, _: &log::Metadata) -> bool { true }
+//! #     fn log(&self, _: &log::Record) {}
+//! #     fn flush(&self) {}
+//! # }
+//!
+//! log::set_logger(Box::leak(Box::new(Filter::new(MyLogger)))).unwrap();
+//! log::set_max_level(LevelFilter::Trace);
+//!
+//! // only warnings, except for the DHT, which is being debugged
+//! for subsystem in Subsystem::ALL.iter() {
+//!     logging::set_level(*subsystem, LevelFilter::Warn);
+//! }
+//! logging::set_level(Subsystem::Dht, LevelFilter::Trace);
+//! ```
+
+use std::{
+    fmt,
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+pub(crate) const ENGINE: &str = "cratetorrent::engine";
+pub(crate) const TORRENT: &str = "cratetorrent::torrent";
+pub(crate) const DISK: &str = "cratetorrent::disk";
+
+/// A part of the engine whose records are logged with its own target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub enum Subsystem {
+    /// The engine and its engine-wide services, such as local service
+    /// discovery, port mapping, and the watch directory.
+    Engine,
+    /// The torrents, including their piece picking and downloads.
+    Torrent,
+    /// The peer sessions.
+    Peer,
+    /// The tracker announces.
+    Tracker,
+    /// The DHT node.
+    Dht,
+    /// The disk task.
+    Disk,
+}
+
+impl Subsystem {
+    /// All subsystems.
+    pub const ALL: [Subsystem; 6] = [
+        Self::Engine,
+        Self::Torrent,
+        Self::Peer,
+        Self::Tracker,
+        Self::Dht,
+        Self::Disk,
+    ];
+
+    /// Returns the name of the subsystem, as used in its target.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Engine => "engine",
+            Self::Torrent => "torrent",
+            Self::Peer => "peer",
+            Self::Tracker => "tracker",
+            Self::Dht => "dht",
+            Self::Disk => "disk",
+        }
+    }
+
+    /// Returns the subsystem whose target is or contains the given target,
+    /// if any.
+    pub fn of_target(target: &str) -> Option<Self> {
+        let target = target.strip_prefix("cratetorrent::")?;
+        Self::ALL.iter().copied().find(|subsystem| {
+            let rest = match target.strip_prefix(subsystem.name()) {
+                Some(rest) => rest,
+                None => return false,
+            };
+            // the peer sessions' targets are followed by their torrent
+            // and address
+            rest.is_empty() || rest.starts_with("::") || rest.starts_with(' ')
+        })
+    }
+}
+
+impl fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Subsystem {
+    type Err = InvalidSubsystem;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|subsystem| subsystem.name() == s)
+            .ok_or_else(|| InvalidSubsystem(s.to_string()))
+    }
+}
+
+/// The error returned when parsing an unknown subsystem name.
+#[derive(Debug)]
+pub struct InvalidSubsystem(String);
+
+impl fmt::Display for InvalidSubsystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid log subsystem {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidSubsystem {}
+
+/// The level of each subsystem, indexed by its position in
+/// [`Subsystem::ALL`]. All records are let through by default.
+static LEVELS: [AtomicUsize; 6] = [
+    AtomicUsize::new(LevelFilter::Trace as usize),
+    AtomicUsize::new(LevelFilter::Trace as usize),
+    AtomicUsize::new(LevelFilter::Trace as usize),
+    AtomicUsize::new(LevelFilter::Trace as usize),
+    AtomicUsize::new(LevelFilter::Trace as usize),
+    AtomicUsize::new(LevelFilter::Trace as usize),
+];
+
+/// Sets the most verbose level of the subsystem's records that a [`Filter`]
+/// lets through.
+pub fn set_level(subsystem: Subsystem, level: LevelFilter) {
+    LEVELS[subsystem as usize].store(level as usize, Ordering::Relaxed);
+}
+
+/// Returns the most verbose level of the subsystem's records that a
+/// [`Filter`] lets through.
+pub fn level(subsystem: Subsystem) -> LevelFilter {
+    match LEVELS[subsystem as usize].load(Ordering::Relaxed) {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// A logger that drops the engine's records above the level of their
+/// subsystem, and passes all other records to the logger it wraps.
+pub struct Filter<L> {
+    inner: L,
+}
+
+impl<L: Log> Filter<L> {
+    /// Wraps the logger.
+    pub fn new(inner: L) -> Self {
+        Self { inner }
+    }
+
+    fn allows(&self, metadata: &Metadata) -> bool {
+        match Subsystem::of_target(metadata.target()) {
+            Some(subsystem) => metadata.level() <= level(subsystem),
+            None => true,
+        }
+    }
+}
+
+impl<L: Log> Log for Filter<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.allows(metadata) && self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.allows(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+
+    use super::*;
+
+    #[test]
+    fn should_find_subsystem_of_target() {
+        assert_eq!(
+            Subsystem::of_target("cratetorrent::dht"),
+            Some(Subsystem::Dht)
+        );
+        assert_eq!(
+            Subsystem::of_target("cratetorrent::disk::io::torrent"),
+            Some(Subsystem::Disk)
+        );
+        assert_eq!(
+            Subsystem::of_target("cratetorrent::peer [0][127.0.0.1:6881]"),
+            Some(Subsystem::Peer)
+        );
+        assert_eq!(Subsystem::of_target("cratetorrent::dhtx"), None);
+        assert_eq!(Subsystem::of_target("cratetorrent"), None);
+        assert_eq!(Subsystem::of_target("other::dht"), None);
+        assert_eq!("tracker".parse::<Subsystem>().unwrap(), Subsystem::Tracker);
+        assert!("trackers".parse::<Subsystem>().is_err());
+    }
+
+    /// Tests that the filter drops the records above their subsystem's level
+    /// and lets through those of other crates.
+    #[test]
+    fn should_filter_records_by_subsystem_level() {
+        struct Inner;
+        impl Log for Inner {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+            fn log(&self, _: &Record) {}
+            fn flush(&self) {}
+        }
+
+        let filter = Filter::new(Inner);
+        let is_enabled = |target, level| {
+            let metadata =
+                Metadata::builder().target(target).level(level).build();
+            filter.enabled(&metadata)
+        };
+
+        set_level(Subsystem::Tracker, LevelFilter::Info);
+        assert_eq!(level(Subsystem::Tracker), LevelFilter::Info);
+        assert!(is_enabled("cratetorrent::tracker", Level::Info));
+        assert!(!is_enabled("cratetorrent::tracker::udp", Level::Debug));
+        // other subsystems and crates are not affected
+        assert!(is_enabled("cratetorrent::dht", Level::Trace));
+        assert!(is_enabled("reqwest", Level::Trace));
+
+        set_level(Subsystem::Tracker, LevelFilter::Trace);
+        assert!(is_enabled("cratetorrent::tracker::udp", Level::Trace));
+    }
+}
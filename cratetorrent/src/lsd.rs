@@ -0,0 +1,449 @@
+//! Local Service Discovery, as specified in
+//! [BEP 14](https://www.bittorrent.org/beps/bep_0014.html).
+//!
+//! Peers on the same local network find each other by announcing the info
+//! hashes of their torrents to a well-known multicast group, in HTTP-like
+//! messages. This lets them connect directly over the LAN, even if they are
+//! behind the same NAT and so can't reach each other via their public
+//! addresses returned by trackers or the DHT.
+//!
+//! The engine runs a single LSD service on its own task, which periodically
+//! announces the engine's public torrents and sends the peers announcing the
+//! same torrents to them.
+//!
+//! Only IPv4 is supported.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    os::unix::io::FromRawFd,
+    str,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use futures::{
+    select,
+    stream::{Fuse, SplitSink, SplitStream, StreamExt},
+    SinkExt,
+};
+use nix::sys::socket::{
+    self, sockopt::ReuseAddr, AddressFamily, InetAddr, SockAddr, SockFlag,
+    SockType,
+};
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+};
+use tokio_util::{codec::BytesCodec, udp::UdpFramed};
+use tracing::Instrument;
+
+use crate::{conf::LsdConf, error::*, logging, rt, torrent, Sha1Hash};
+
+/// The multicast group to which announcements are sent.
+const LSD_IP: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+/// The port on which announcements are sent and received.
+const LSD_PORT: u16 = 6771;
+
+/// A torrent is announced at most this often, as recommended by the BEP, even
+/// if the configured announce interval is shorter.
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Returns the address to which announcements are sent.
+fn lsd_addr() -> SocketAddr {
+    SocketAddr::new(LSD_IP.into(), LSD_PORT)
+}
+
+/// Spawns the LSD service on a new task, joining the multicast group on the
+/// configured interface.
+pub(crate) fn spawn(conf: LsdConf) -> Result<(JoinHandle, Sender)> {
+    log::info!(
+        target: logging::ENGINE,
+        "Spawning LSD task on interface {}",
+        conf.interface
+    );
+    let socket = bind_socket(conf.interface)?;
+    let socket = UdpSocket::from_std(socket)?;
+    let (socket_tx, socket_rx) =
+        UdpFramed::new(socket, BytesCodec::new()).split();
+
+    let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+    let mut lsd = Lsd::new(conf, socket_tx, cmd_rx);
+    let join_handle = rt::spawn(
+        async move { lsd.run(socket_rx).await }
+            .instrument(tracing::info_span!("lsd")),
+    );
+    log::info!(target: logging::ENGINE, "Spawned LSD task");
+
+    Ok((join_handle, cmd_tx))
+}
+
+/// Binds a socket to the LSD port and joins the multicast group on the given
+/// interface.
+///
+/// Other clients on the same host listen on the same port, so the address
+/// must be set to be reusable before binding, which the standard library
+/// doesn't allow.
+fn bind_socket(interface: Ipv4Addr) -> io::Result<std::net::UdpSocket> {
+    let fd = socket::socket(
+        AddressFamily::Inet,
+        SockType::Datagram,
+        SockFlag::empty(),
+        None,
+    )
+    .map_err(nix_to_io_error)?;
+    // SAFETY: the file descriptor was just created and is not owned by
+    // anything else, so the socket takes sole ownership of it and closes it
+    // even if a later step fails
+    let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+    socket::setsockopt(fd, ReuseAddr, &true).map_err(nix_to_io_error)?;
+    let addr = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), LSD_PORT);
+    socket::bind(fd, &SockAddr::new_inet(InetAddr::from_std(&addr)))
+        .map_err(nix_to_io_error)?;
+    socket.join_multicast_v4(&LSD_IP, &interface)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+pub(crate) fn nix_to_io_error(e: nix::Error) -> io::Error {
+    match e.as_errno() {
+        Some(errno) => io::Error::from_raw_os_error(errno as i32),
+        None => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
+
+pub(crate) type JoinHandle = rt::JoinHandle<Result<()>>;
+
+/// The channel for sending commands to the LSD task.
+pub(crate) type Sender = UnboundedSender<Command>;
+/// The channel on which the LSD task listens for commands.
+type Receiver = UnboundedReceiver<Command>;
+
+/// The commands the LSD task can receive.
+#[derive(Debug)]
+pub(crate) enum Command {
+    /// Starts announcing the torrent periodically with the given port, and
+    /// sending the local peers announcing it to the torrent.
+    AddTorrent {
+        info_hash: Sha1Hash,
+        port: u16,
+        torrent_tx: torrent::Sender,
+    },
+    /// Stops announcing the torrent.
+    RemoveTorrent { info_hash: Sha1Hash },
+    /// Shuts down the LSD task.
+    Shutdown,
+}
+
+/// The sending half of the service's socket.
+type SocketSender = SplitSink<UdpFramed<BytesCodec>, (Bytes, SocketAddr)>;
+/// The receiving half of the service's socket.
+type SocketReceiver = SplitStream<UdpFramed<BytesCodec>>;
+
+/// A torrent that is announced on the local network.
+struct TorrentEntry {
+    /// The port announced for the torrent.
+    port: u16,
+    /// The local peers found are sent to the torrent on this channel.
+    torrent_tx: torrent::Sender,
+    /// The time the torrent was last announced.
+    last_announce_time: Option<Instant>,
+}
+
+struct Lsd {
+    socket_tx: SocketSender,
+    /// The torrents of the engine, by info hash.
+    torrents: HashMap<Sha1Hash, TorrentEntry>,
+    /// A random value sent with our announcements, so that we can recognize
+    /// and ignore them when the multicast group loops them back to us.
+    cookie: String,
+    cmd_rx: Fuse<Receiver>,
+    conf: LsdConf,
+}
+
+impl Lsd {
+    fn new(conf: LsdConf, socket_tx: SocketSender, cmd_rx: Receiver) -> Self {
+        Self {
+            socket_tx,
+            torrents: HashMap::new(),
+            cookie: hex::encode(rand::random::<[u8; 4]>()),
+            cmd_rx: cmd_rx.fuse(),
+            conf,
+        }
+    }
+
+    /// Runs the service until it's shut down.
+    async fn run(&mut self, socket_rx: SocketReceiver) -> Result<()> {
+        let mut tick_timer = rt::interval(Duration::from_secs(1)).fuse();
+        let mut socket_rx = socket_rx.fuse();
+
+        loop {
+            select! {
+                tick_time = tick_timer.select_next_some() => {
+                    self.announce_torrents(tick_time).await;
+                }
+                msg = socket_rx.select_next_some() => match msg {
+                    Ok((buf, addr)) => self.handle_message(&buf, addr),
+                    Err(e) => {
+                        log::debug!(
+                            target: logging::ENGINE,
+                            "Error receiving LSD message: {}",
+                            e
+                        );
+                    }
+                },
+                cmd = self.cmd_rx.select_next_some() => match cmd {
+                    Command::AddTorrent {
+                        info_hash,
+                        port,
+                        torrent_tx,
+                    } => {
+                        log::info!(
+                            target: logging::ENGINE,
+                            "Adding torrent {} to LSD",
+                            hex::encode(&info_hash)
+                        );
+                        self.torrents.insert(
+                            info_hash,
+                            TorrentEntry {
+                                port,
+                                torrent_tx,
+                                last_announce_time: None,
+                            },
+                        );
+                    }
+                    Command::RemoveTorrent { info_hash } => {
+                        log::info!(
+                            target: logging::ENGINE,
+                            "Removing torrent {} from LSD",
+                            hex::encode(&info_hash)
+                        );
+                        self.torrents.remove(&info_hash);
+                    }
+                    Command::Shutdown => {
+                        log::info!(
+                            target: logging::ENGINE,
+                            "Shutting down LSD"
+                        );
+                        break;
+                    }
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Announces the torrents that are due. Torrents announced with the same
+    /// port are announced in a single message.
+    async fn announce_torrents(&mut self, now: Instant) {
+        let announce_interval =
+            self.conf.announce_interval.max(MIN_ANNOUNCE_INTERVAL);
+        let mut due: HashMap<u16, Vec<Sha1Hash>> = HashMap::new();
+        for (info_hash, torrent) in self.torrents.iter_mut() {
+            let is_due = torrent.last_announce_time.map_or(true, |t| {
+                now.saturating_duration_since(t) >= announce_interval
+            });
+            if is_due {
+                torrent.last_announce_time = Some(now);
+                due.entry(torrent.port).or_default().push(*info_hash);
+            }
+        }
+
+        for (port, info_hashes) in due.into_iter() {
+            log::debug!(
+                target: logging::ENGINE,
+                "Announcing {} torrent(s) on port {} via LSD",
+                info_hashes.len(),
+                port
+            );
+            let msg = encode_announce(port, &info_hashes, &self.cookie);
+            if let Err(e) =
+                self.socket_tx.send((Bytes::from(msg), lsd_addr())).await
+            {
+                log::warn!(
+                    target: logging::ENGINE,
+                    "Error sending LSD announce: {}",
+                    e
+                );
+            }
+        }
+    }
+
+    /// Sends the peer announcing in the message to the torrents it
+    /// announces that are ours.
+    fn handle_message(&mut self, buf: &[u8], addr: SocketAddr) {
+        let announce = match Announce::decode(buf) {
+            Some(announce) => announce,
+            None => {
+                log::trace!(
+                    target: logging::ENGINE,
+                    "Invalid LSD message from {}",
+                    addr
+                );
+                return;
+            }
+        };
+        if announce.cookie.as_deref() == Some(self.cookie.as_str()) {
+            return;
+        }
+
+        let peer_addr = SocketAddr::new(addr.ip(), announce.port);
+        for info_hash in announce.info_hashes.iter() {
+            if let Some(torrent) = self.torrents.get(info_hash) {
+                log::debug!(
+                    target: logging::ENGINE,
+                    "Local peer {} announced torrent {}",
+                    peer_addr,
+                    hex::encode(info_hash)
+                );
+                // the torrent may have stopped in the meantime
+                torrent
+                    .torrent_tx
+                    .send(torrent::Command::LsdPeers(vec![peer_addr]))
+                    .ok();
+            }
+        }
+    }
+}
+
+/// An announcement received from a local peer.
+#[derive(Debug, PartialEq)]
+struct Announce {
+    /// The port on which the peer listens.
+    port: u16,
+    /// The info hashes of the torrents the peer announces.
+    info_hashes: Vec<Sha1Hash>,
+    /// The value by which the peer recognizes its own announcements.
+    cookie: Option<String>,
+}
+
+impl Announce {
+    /// Parses an announcement, returning `None` if it's malformed.
+    ///
+    /// Header names are case insensitive and unknown headers are ignored, as
+    /// in HTTP.
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let msg = str::from_utf8(buf).ok()?;
+        let mut lines = msg.split("\r\n");
+        if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let mut parts = line.splitn(2, ':');
+            let name = parts.next()?.trim();
+            let value = parts.next()?.trim();
+            if name.eq_ignore_ascii_case("port") {
+                port = Some(value.parse().ok()?);
+            } else if name.eq_ignore_ascii_case("infohash") {
+                let mut info_hash = [0; 20];
+                hex::decode_to_slice(value, &mut info_hash).ok()?;
+                info_hashes.push(info_hash);
+            } else if name.eq_ignore_ascii_case("cookie") {
+                cookie = Some(value.to_string());
+            }
+        }
+
+        let port = port.filter(|&port| port != 0)?;
+        if info_hashes.is_empty() {
+            return None;
+        }
+        Some(Self {
+            port,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+/// Encodes the announcement of the torrents with the given port.
+fn encode_announce(
+    port: u16,
+    info_hashes: &[Sha1Hash],
+    cookie: &str,
+) -> String {
+    let mut msg = format!(
+        "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+        lsd_addr(),
+        port
+    );
+    for info_hash in info_hashes.iter() {
+        msg.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+    }
+    msg.push_str(&format!("cookie: {}\r\n\r\n\r\n", cookie));
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_encode_and_decode_announce() {
+        let info_hashes = [[0xab; 20], [0x01; 20]];
+        let msg = encode_announce(6881, &info_hashes, "c00k1e");
+        assert_eq!(
+            msg,
+            "BT-SEARCH * HTTP/1.1\r\n\
+             Host: 239.192.152.143:6771\r\n\
+             Port: 6881\r\n\
+             Infohash: abababababababababababababababababababab\r\n\
+             Infohash: 0101010101010101010101010101010101010101\r\n\
+             cookie: c00k1e\r\n\r\n\r\n"
+        );
+        assert_eq!(
+            Announce::decode(msg.as_bytes()),
+            Some(Announce {
+                port: 6881,
+                info_hashes: info_hashes.to_vec(),
+                cookie: Some("c00k1e".into()),
+            })
+        );
+    }
+
+    #[test]
+    fn should_decode_announce_case_insensitively() {
+        let msg = "BT-SEARCH * HTTP/1.1\r\n\
+                   host: 239.192.152.143:6771\r\n\
+                   PORT:51413\r\n\
+                   infohash: ABABABABABABABABABABABABABABABABABABABAB\r\n\
+                   \r\n\r\n";
+        assert_eq!(
+            Announce::decode(msg.as_bytes()),
+            Some(Announce {
+                port: 51413,
+                info_hashes: vec![[0xab; 20]],
+                cookie: None,
+            })
+        );
+    }
+
+    #[test]
+    fn should_reject_invalid_announce() {
+        // wrong request line
+        assert!(Announce::decode(
+            b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\nInfohash: \
+              abababababababababababababababababababab\r\n\r\n"
+        )
+        .is_none());
+        // missing port
+        assert!(Announce::decode(
+            b"BT-SEARCH * HTTP/1.1\r\nInfohash: \
+              abababababababababababababababababababab\r\n\r\n"
+        )
+        .is_none());
+        // invalid info hash
+        assert!(Announce::decode(
+            b"BT-SEARCH * HTTP/1.1\r\nPort: 1\r\nInfohash: abab\r\n\r\n"
+        )
+        .is_none());
+        // no info hash
+        assert!(Announce::decode(b"BT-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n")
+            .is_none());
+    }
+}
@@ -0,0 +1,225 @@
+//! Parsing of magnet links, which identify a torrent by its info hash alone.
+//!
+//! The torrent's metainfo is then downloaded from its peers, as defined in
+//! [BEP 9](https://www.bittorrent.org/beps/bep_0009.html), which the engine
+//! does when a magnet link is added with
+//! [`EngineHandle::add_magnet`](crate::engine::EngineHandle::add_magnet).
+
+use std::{fmt, net::SocketAddr};
+
+use reqwest::Url;
+
+use crate::{logging, metainfo::is_supported_tracker, Sha1Hash};
+
+/// The prefix of the exact topic (`xt`) of BitTorrent v1 magnet links, which
+/// is followed by the info hash.
+const BTIH_PREFIX: &str = "urn:btih:";
+
+#[derive(Debug)]
+pub enum MagnetError {
+    /// The link is not a valid `magnet:` URI.
+    InvalidUri,
+    /// The link has no BitTorrent v1 info hash.
+    MissingInfoHash,
+    /// The info hash is neither 40 hex nor 32 base32 characters long.
+    InvalidInfoHash,
+}
+
+impl fmt::Display for MagnetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use MagnetError::*;
+        match self {
+            InvalidUri => write!(f, "invalid magnet URI"),
+            MissingInfoHash => write!(f, "magnet URI has no info hash"),
+            InvalidInfoHash => write!(f, "invalid info hash in magnet URI"),
+        }
+    }
+}
+
+impl std::error::Error for MagnetError {}
+
+/// A parsed magnet link.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Magnet {
+    /// The info hash of the torrent, from the link's exact topic (`xt`).
+    pub info_hash: Sha1Hash,
+    /// The display name (`dn`) of the torrent, if given. The name in the
+    /// metainfo is used once it's downloaded.
+    pub name: Option<String>,
+    /// The trackers (`tr`) of the torrent. Unsupported trackers are omitted.
+    pub trackers: Vec<Url>,
+    /// The addresses of peers (`x.pe`) of the torrent.
+    pub peers: Vec<SocketAddr>,
+}
+
+impl Magnet {
+    /// Parses a magnet link of the form
+    /// `magnet:?xt=urn:btih:<info hash>&dn=<name>&tr=<tracker>`, where the
+    /// info hash is hex or base32 encoded.
+    ///
+    /// Parameters other than the above and the `x.pe` peer addresses are
+    /// ignored.
+    pub fn parse(uri: &str) -> Result<Self, MagnetError> {
+        let url = Url::parse(uri).map_err(|_| MagnetError::InvalidUri)?;
+        if url.scheme() != "magnet" {
+            return Err(MagnetError::InvalidUri);
+        }
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => {
+                    // other topics, such as BitTorrent v2 hashes, are skipped
+                    if let Some(hash) = value.strip_prefix(BTIH_PREFIX) {
+                        info_hash = Some(
+                            decode_info_hash(hash)
+                                .ok_or(MagnetError::InvalidInfoHash)?,
+                        );
+                    }
+                }
+                "dn" => name = Some(value.into_owned()),
+                "tr" => match Url::parse(&value) {
+                    Ok(url) if is_supported_tracker(&url) => {
+                        if !trackers.contains(&url) {
+                            trackers.push(url);
+                        }
+                    }
+                    _ => {
+                        log::warn!(
+                            target: logging::ENGINE,
+                            "Skipping magnet tracker {}",
+                            value
+                        )
+                    }
+                },
+                "x.pe" => match value.parse() {
+                    Ok(addr) => peers.push(addr),
+                    Err(_) => {
+                        log::warn!(
+                            target: logging::ENGINE,
+                            "Skipping magnet peer {}",
+                            value
+                        )
+                    }
+                },
+                _ => (),
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash.ok_or(MagnetError::MissingInfoHash)?,
+            name,
+            trackers,
+            peers,
+        })
+    }
+}
+
+/// Decodes the 40 character hex or 32 character base32 info hash.
+fn decode_info_hash(hash: &str) -> Option<Sha1Hash> {
+    let mut info_hash = [0; 20];
+    match hash.len() {
+        40 => hex::decode_to_slice(hash, &mut info_hash).ok()?,
+        32 => {
+            // each character encodes 5 bits, which are collected until they
+            // make up a byte
+            let mut bits = 0u32;
+            let mut bit_count = 0;
+            let mut i = 0;
+            for c in hash.bytes() {
+                let value = match c.to_ascii_uppercase() {
+                    c @ b'A'..=b'Z' => c - b'A',
+                    c @ b'2'..=b'7' => c - b'2' + 26,
+                    _ => return None,
+                };
+                bits = (bits << 5) | value as u32;
+                bit_count += 5;
+                if bit_count >= 8 {
+                    bit_count -= 8;
+                    info_hash[i] = (bits >> bit_count) as u8;
+                    i += 1;
+                }
+            }
+        }
+        _ => return None,
+    }
+    Some(info_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO_HASH_HEX: &str = "c9e15763f722f23e98a29decdfae341b98d53056";
+
+    fn info_hash() -> Sha1Hash {
+        let mut info_hash = [0; 20];
+        hex::decode_to_slice(INFO_HASH_HEX, &mut info_hash).unwrap();
+        info_hash
+    }
+
+    #[test]
+    fn should_parse_magnet() {
+        let uri = format!(
+            "magnet:?xt=urn:btih:{}&dn=Some+Name\
+            &tr=udp%3A%2F%2Ftracker.example.com%3A6969\
+            &tr=http%3A%2F%2Ftracker.example.com%2Fannounce\
+            &tr=udp%3A%2F%2Fno-port.example.com\
+            &x.pe=1.2.3.4%3A6881&x.pe=not-an-address",
+            INFO_HASH_HEX
+        );
+        let magnet = Magnet::parse(&uri).unwrap();
+        assert_eq!(magnet.info_hash, info_hash());
+        assert_eq!(magnet.name.as_deref(), Some("Some Name"));
+        assert_eq!(
+            magnet.trackers,
+            vec![
+                "udp://tracker.example.com:6969".parse().unwrap(),
+                "http://tracker.example.com/announce".parse().unwrap(),
+            ]
+        );
+        assert_eq!(magnet.peers, vec!["1.2.3.4:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn should_parse_base32_info_hash() {
+        let magnet = Magnet::parse(
+            "magnet:?xt=urn:btih:ZHQVOY7XELZD5GFCTXWN7LRUDOMNKMCW",
+        )
+        .unwrap();
+        assert_eq!(magnet.info_hash, info_hash());
+        assert_eq!(magnet.name, None);
+        assert!(magnet.trackers.is_empty());
+
+        let magnet = Magnet::parse(
+            "magnet:?xt=urn:btih:zhqvoy7xelzd5gfctxwn7lrudomnkmcw",
+        )
+        .unwrap();
+        assert_eq!(magnet.info_hash, info_hash());
+    }
+
+    #[test]
+    fn should_reject_invalid_magnet() {
+        assert!(matches!(
+            Magnet::parse("http://example.com/?xt=urn:btih:abc"),
+            Err(MagnetError::InvalidUri)
+        ));
+        assert!(matches!(
+            Magnet::parse("magnet:?dn=name"),
+            Err(MagnetError::MissingInfoHash)
+        ));
+        assert!(matches!(
+            Magnet::parse("magnet:?xt=urn:btih:c9e15763"),
+            Err(MagnetError::InvalidInfoHash)
+        ));
+        assert!(matches!(
+            Magnet::parse(
+                "magnet:?xt=urn:btih:ZHQVOY7XELZD5GFCTXWN7LRUDOMNKMC1"
+            ),
+            Err(MagnetError::InvalidInfoHash)
+        ));
+    }
+}
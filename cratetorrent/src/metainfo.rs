@@ -7,8 +7,9 @@ use std::{
 };
 
 use reqwest::Url;
+use sha1::{Digest, Sha1};
 
-use crate::{FileInfo, Sha1Hash};
+use crate::{logging, FileInfo, Sha1Hash};
 
 pub use serde_bencode::Error as BencodeError;
 
@@ -78,10 +79,27 @@ pub struct Metainfo {
     pub piece_len: u32,
     /// The paths and lenths of the files in torrent.
     pub files: Vec<FileInfo>,
-    /// The trackers that we can announce to.
-    /// The tier information is not currently present in this field as
-    /// cratetorrent doesn't use it. In the future it may be added.
-    pub trackers: Vec<Url>,
+    /// The tiers of trackers that we can announce to, in order of preference,
+    /// as defined in [BEP 12](https://www.bittorrent.org/beps/bep_0012.html).
+    /// If the metainfo has no announce list, the single announce URL makes up
+    /// the only tier. Unsupported trackers and empty tiers are omitted.
+    pub trackers: Vec<Vec<Url>>,
+    /// Whether the torrent is private, in which case its peers may only be
+    /// obtained from its trackers, as per
+    /// [BEP 27](https://www.bittorrent.org/beps/bep_0027.html).
+    pub is_private: bool,
+    /// The DHT nodes suggested by the torrent's creator, as `host:port`
+    /// pairs, through which the torrent can be found in the DHT even without
+    /// bootstrap nodes, as defined in
+    /// [BEP 5](https://www.bittorrent.org/beps/bep_0005.html).
+    pub dht_nodes: Vec<String>,
+    /// The bencoded metainfo file the above was parsed from, kept so that the
+    /// torrent can be saved with the session state and added again on
+    /// restart.
+    pub bytes: Vec<u8>,
+    /// Whether the metainfo lists the torrent's files, rather than describing
+    /// a single file.
+    is_archive: bool,
 }
 
 impl Metainfo {
@@ -104,13 +122,17 @@ impl Metainfo {
 
         // verify download structure and build up files metadata
         let mut files = Vec::new();
+        let is_archive = metainfo.info.files.is_some();
         if let Some(len) = metainfo.info.len {
             if metainfo.info.files.is_some() {
-                log::warn!("Metainfo cannot contain both `length` and `files`");
+                log::warn!(
+                    target: logging::ENGINE,
+                    "Metainfo cannot contain both `length` and `files`"
+                );
                 return Err(MetainfoError::InvalidMetainfo);
             }
             if len == 0 {
-                log::warn!("File length is 0");
+                log::warn!(target: logging::ENGINE, "File length is 0");
                 return Err(MetainfoError::InvalidMetainfo);
             }
 
@@ -122,7 +144,10 @@ impl Metainfo {
             });
         } else if let Some(raw_files) = &metainfo.info.files {
             if raw_files.is_empty() {
-                log::warn!("Metainfo files must not be empty");
+                log::warn!(
+                    target: logging::ENGINE,
+                    "Metainfo files must not be empty"
+                );
                 return Err(MetainfoError::InvalidMetainfo);
             }
 
@@ -133,26 +158,41 @@ impl Metainfo {
             for file in raw_files.iter() {
                 // verify that the file length is non-zero
                 if file.len == 0 {
-                    log::warn!("File {:?} length is 0", file.path);
+                    log::warn!(
+                        target: logging::ENGINE,
+                        "File {:?} length is 0",
+                        file.path
+                    );
                     return Err(MetainfoError::InvalidMetainfo);
                 }
 
                 // verify that the path is not empty
                 let path: PathBuf = file.path.iter().collect();
                 if path == PathBuf::new() {
-                    log::warn!("Path in metainfo is empty");
+                    log::warn!(
+                        target: logging::ENGINE,
+                        "Path in metainfo is empty"
+                    );
                     return Err(MetainfoError::InvalidMetainfo);
                 }
 
                 // verify that the path is not absolute
                 if path.is_absolute() {
-                    log::warn!("Path {:?} is absolute", path);
+                    log::warn!(
+                        target: logging::ENGINE,
+                        "Path {:?} is absolute",
+                        path
+                    );
                     return Err(MetainfoError::InvalidMetainfo);
                 }
 
                 // verify that the path is not the root
                 if path == Path::new("/") {
-                    log::warn!("Path {:?} is root", path);
+                    log::warn!(
+                        target: logging::ENGINE,
+                        "Path {:?} is root",
+                        path
+                    );
                     return Err(MetainfoError::InvalidMetainfo);
                 }
 
@@ -167,42 +207,62 @@ impl Metainfo {
                 torrent_offset += file.len;
             }
         } else {
-            log::warn!("No `length` or `files` key present in metainfo");
+            log::warn!(
+                target: logging::ENGINE,
+                "No `length` or `files` key present in metainfo"
+            );
             return Err(MetainfoError::InvalidMetainfo);
         }
 
         let mut trackers = Vec::new();
         if !metainfo.announce_list.is_empty() {
-            let tracker_count = metainfo
-                .announce_list
-                .iter()
-                .map(|t| t.len())
-                .sum::<usize>()
-                + metainfo.announce.as_ref().map(|_| 1).unwrap_or_default();
-            trackers.reserve(tracker_count);
-
+            trackers.reserve(metainfo.announce_list.len());
             for tier in metainfo.announce_list.iter() {
+                let mut urls = Vec::with_capacity(tier.len());
                 for tracker in tier.iter() {
                     let url = Url::parse(&tracker)?;
-                    // the tracker may be over UDP, which we don't support (yet)
-                    if url.scheme() == "http" || url.scheme() == "https" {
-                        trackers.push(url);
+                    if is_supported_tracker(&url) {
+                        urls.push(url);
                     }
                 }
+                if !urls.is_empty() {
+                    trackers.push(urls);
+                }
             }
         } else if let Some(tracker) = &metainfo.announce {
             let url = Url::parse(&tracker)?;
-            if url.scheme() == "http" || url.scheme() == "https" {
-                trackers.push(url);
+            if is_supported_tracker(&url) {
+                trackers.push(vec![url]);
             }
         }
 
         if trackers.is_empty() {
-            log::warn!("No HTTP trackers in metainfo");
+            log::warn!(
+                target: logging::ENGINE,
+                "No supported trackers in metainfo"
+            );
         }
 
-        // create info hash as a last step
-        let info_hash = metainfo.create_info_hash()?;
+        let dht_nodes = metainfo
+            .nodes
+            .iter()
+            .map(|(host, port)| {
+                // IPv6 addresses must be enclosed in brackets to be followed
+                // by a port
+                if host.contains(':') {
+                    format!("[{}]:{}", host, port)
+                } else {
+                    format!("{}:{}", host, port)
+                }
+            })
+            .collect();
+
+        // create info hash as a last step, from the info dictionary as it's
+        // encoded in the file, as re-encoding the parsed dictionary would drop
+        // the keys we don't know and change the hash
+        let info = raw_info(buf).ok_or(MetainfoError::InvalidMetainfo)?;
+        let mut info_hash = [0; 20];
+        info_hash.copy_from_slice(&Sha1::digest(info));
 
         Ok(Self {
             name: metainfo.info.name,
@@ -211,12 +271,59 @@ impl Metainfo {
             piece_len: metainfo.info.piece_len,
             files,
             trackers,
+            is_private: metainfo.info.private == Some(1),
+            dht_nodes,
+            bytes: buf.to_vec(),
+            is_archive,
         })
     }
 
-    /// Returns true if the download is for an archive.
+    /// Creates the metainfo of a torrent from its bencoded info dictionary,
+    /// e.g. one downloaded from peers for a magnet link, and the tiers of
+    /// trackers to announce to.
+    ///
+    /// The metainfo file is reconstructed from the two, so that the torrent
+    /// can be saved with the session state like any other.
+    pub fn from_info_bytes(info: &[u8], trackers: &[Vec<Url>]) -> Result<Self> {
+        // the keys of a bencoded dictionary must be sorted
+        let mut buf = Vec::with_capacity(info.len() + 256);
+        buf.push(b'd');
+        let push_str = |buf: &mut Vec<u8>, s: &[u8]| {
+            buf.extend_from_slice(s.len().to_string().as_bytes());
+            buf.push(b':');
+            buf.extend_from_slice(s);
+        };
+        if let Some(url) = trackers.iter().flatten().next() {
+            push_str(&mut buf, b"announce");
+            push_str(&mut buf, url.as_str().as_bytes());
+            push_str(&mut buf, b"announce-list");
+            buf.push(b'l');
+            for tier in trackers.iter().filter(|tier| !tier.is_empty()) {
+                buf.push(b'l');
+                for url in tier.iter() {
+                    push_str(&mut buf, url.as_str().as_bytes());
+                }
+                buf.push(b'e');
+            }
+            buf.push(b'e');
+        }
+        push_str(&mut buf, b"info");
+        buf.extend_from_slice(info);
+        buf.push(b'e');
+        Self::from_bytes(&buf)
+    }
+
+    /// Returns the bencoded info dictionary, as it's encoded in the metainfo
+    /// file. This is what peers download for magnet links.
+    pub fn info_bytes(&self) -> Option<&[u8]> {
+        raw_info(&self.bytes)
+    }
+
+    /// Returns true if the download is for an archive, i.e. the metainfo
+    /// lists the torrent's files, even if it only has one, in which case they
+    /// are downloaded into the torrent's own directory.
     pub fn is_archive(&self) -> bool {
-        self.files.len() > 1
+        self.is_archive
     }
 
     /// Returns the total download size in bytes.
@@ -233,6 +340,102 @@ impl Metainfo {
     }
 }
 
+/// Metainfo is serialized as the bencoded metainfo file it was parsed from,
+/// which is parsed and validated again when deserialized.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Metainfo {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.bytes)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Metainfo {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        let buf: serde_bytes::ByteBuf =
+            serde::Deserialize::deserialize(deserializer)?;
+        Self::from_bytes(&buf).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Returns whether we can announce to the tracker at the given URL. UDP
+/// trackers must specify a port as they have no default one.
+pub(crate) fn is_supported_tracker(url: &Url) -> bool {
+    match url.scheme() {
+        "http" | "https" => true,
+        "udp" => url.host().is_some() && url.port().is_some(),
+        #[cfg(feature = "websocket-trackers")]
+        "ws" | "wss" => true,
+        _ => false,
+    }
+}
+
+/// Returns the value of the `info` key of the bencoded metainfo dictionary.
+fn raw_info(buf: &[u8]) -> Option<&[u8]> {
+    if buf.first() != Some(&b'd') {
+        return None;
+    }
+    let mut pos = 1;
+    while *buf.get(pos)? != b'e' {
+        let key_len = bencode_len(&buf[pos..])?;
+        let key = &buf[pos..pos + key_len];
+        pos += key_len;
+        let value_len = bencode_len(&buf[pos..])?;
+        if key == b"4:info" {
+            return Some(&buf[pos..pos + value_len]);
+        }
+        pos += value_len;
+    }
+    None
+}
+
+/// Returns the length of the bencoded value at the start of the buffer, or
+/// `None` if it's not valid bencode.
+///
+/// The value is only scanned, not parsed, so this can be used to find where
+/// a value ends, e.g. when it's followed by other data.
+pub(crate) fn bencode_len(buf: &[u8]) -> Option<usize> {
+    // the nesting is tracked in a counter rather than by recursion, so that
+    // deeply nested input can't overflow the stack
+    let mut depth = 0usize;
+    let mut pos = 0;
+    loop {
+        match *buf.get(pos)? {
+            b'i' => {
+                pos += buf[pos..].iter().position(|&b| b == b'e')? + 1;
+            }
+            b'l' | b'd' => {
+                depth += 1;
+                pos += 1;
+            }
+            b'e' if depth > 0 => {
+                depth -= 1;
+                pos += 1;
+            }
+            b'0'..=b'9' => {
+                let colon = buf[pos..].iter().position(|&b| b == b':')?;
+                let len: usize = std::str::from_utf8(&buf[pos..pos + colon])
+                    .ok()?
+                    .parse()
+                    .ok()?;
+                pos = (pos + colon + 1).checked_add(len)?;
+                if pos > buf.len() {
+                    return None;
+                }
+            }
+            _ => return None,
+        }
+        if depth == 0 {
+            return Some(pos);
+        }
+    }
+}
+
 impl fmt::Debug for Metainfo {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Metainfo")
@@ -241,6 +444,8 @@ impl fmt::Debug for Metainfo {
             .field("pieces", &"<pieces...>")
             .field("piece_len", &self.piece_len)
             .field("structure", &self.files)
+            .field("is_private", &self.is_private)
+            .field("dht_nodes", &self.dht_nodes)
             .finish()
     }
 }
@@ -253,31 +458,21 @@ mod raw {
     //! [`Metainfo`], but with semantic requirements encoded in the type
     //! system.
 
-    use sha1::{Digest, Sha1};
-
-    use super::{Result, Sha1Hash};
-
     #[derive(Debug, Deserialize)]
+    #[serde(crate = "serde_crate")]
     pub struct Metainfo {
         pub info: Info,
         pub announce: Option<String>,
         #[serde(default)]
         #[serde(rename = "announce-list")]
         pub announce_list: Vec<Vec<String>>,
+        /// The DHT nodes as host and port pairs.
+        #[serde(default)]
+        pub nodes: Vec<(String, u16)>,
     }
 
-    impl Metainfo {
-        /// Creates a SHA-1 hash of the encoded `info` field's value.
-        pub fn create_info_hash(&self) -> Result<Sha1Hash> {
-            let info = serde_bencode::to_bytes(&self.info)?;
-            let digest = Sha1::digest(&info);
-            let mut info_hash = [0; 20];
-            info_hash.copy_from_slice(&digest);
-            Ok(info_hash)
-        }
-    }
-
-    #[derive(Debug, Serialize, Deserialize)]
+    #[derive(Debug, Deserialize)]
+    #[serde(crate = "serde_crate")]
     pub struct Info {
         pub name: String,
         #[serde(with = "serde_bytes")]
@@ -287,12 +482,12 @@ mod raw {
         #[serde(rename = "length")]
         pub len: Option<u64>,
         pub files: Option<Vec<File>>,
-        /// This is not currently used but needs to be kept in here so that we
-        /// can encode back a valid info hash for hashing.
+        /// Set to 1 if the torrent is private.
         pub private: Option<u8>,
     }
 
-    #[derive(Debug, Serialize, Deserialize)]
+    #[derive(Debug, Deserialize)]
+    #[serde(crate = "serde_crate")]
     pub struct File {
         pub path: Vec<String>,
         #[serde(rename = "length")]
@@ -302,3 +497,60 @@ mod raw {
 
 // TODO(https://github.com/mandreyel/cratetorrent/issues/8): add metainfo
 // parsing tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO: &[u8] =
+        b"d6:lengthi100e4:name4:test12:piece lengthi16384e6:pieces20:\
+        aaaaaaaaaaaaaaaaaaaa7:privatei0e6:sourcei1ee";
+
+    #[test]
+    fn should_find_bencode_value_len() {
+        assert_eq!(bencode_len(b"i-42e"), Some(5));
+        assert_eq!(bencode_len(b"4:spamtrailing"), Some(6));
+        assert_eq!(bencode_len(b"l4:spami1eed"), Some(11));
+        assert_eq!(bencode_len(b"d1:ad1:bleee1:c"), Some(12));
+        assert_eq!(bencode_len(b"5:spam"), None);
+        assert_eq!(bencode_len(b"l4:spam"), None);
+        assert_eq!(bencode_len(b"x"), None);
+    }
+
+    #[test]
+    fn should_create_metainfo_from_info_bytes() {
+        let url: Url = "http://tracker.example.com/announce".parse().unwrap();
+        let metainfo =
+            Metainfo::from_info_bytes(INFO, &[vec![url.clone()]]).unwrap();
+        assert_eq!(metainfo.name, "test");
+        assert_eq!(metainfo.download_len(), 100);
+        assert_eq!(metainfo.trackers, vec![vec![url]]);
+        // the info hash covers the keys we don't parse too
+        let mut info_hash = [0; 20];
+        info_hash.copy_from_slice(&Sha1::digest(INFO));
+        assert_eq!(metainfo.info_hash, info_hash);
+        assert_eq!(metainfo.info_bytes(), Some(INFO));
+
+        let metainfo = Metainfo::from_info_bytes(INFO, &[]).unwrap();
+        assert!(metainfo.trackers.is_empty());
+        assert_eq!(metainfo.info_hash, info_hash);
+    }
+
+    /// Tests that metainfo is serialized as its bencoded metainfo file, and
+    /// parsed from it when deserialized.
+    #[cfg(feature = "serde")]
+    #[test]
+    fn should_serialize_metainfo_as_file() {
+        let metainfo = Metainfo::from_info_bytes(INFO, &[]).unwrap();
+        let buf = serde_bencode::to_bytes(&metainfo).unwrap();
+        let deserialized: Metainfo = serde_bencode::from_bytes(&buf).unwrap();
+        assert_eq!(deserialized.bytes, metainfo.bytes);
+        assert_eq!(deserialized.info_hash, metainfo.info_hash);
+
+        // invalid metainfo is rejected
+        let buf = serde_bencode::to_bytes(&serde_bytes::Bytes::new(INFO));
+        let result: std::result::Result<Metainfo, _> =
+            serde_bencode::from_bytes(&buf.unwrap());
+        assert!(result.is_err());
+    }
+}
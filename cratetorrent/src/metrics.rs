@@ -0,0 +1,110 @@
+//! Exports engine-wide counters and gauges through the
+//! [`metrics`](https://docs.rs/metrics) crate facade.
+//!
+//! This is only done with the `metrics` feature enabled, without which the
+//! functions here are no-ops. The application is expected to install
+//! a recorder (e.g. `metrics-exporter-prometheus`) to collect the metrics,
+//! otherwise they are discarded.
+//!
+//! The metrics are aggregated over all torrents, so that the number of series
+//! doesn't grow with the number of torrents in the engine. Per torrent
+//! statistics are available in [`Alert::TorrentStats`](crate::alert::Alert).
+
+#![cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+
+#[cfg(feature = "metrics")]
+use ::metrics::{counter, describe_counter, describe_gauge, gauge, Unit};
+
+/// The total number of payload bytes downloaded.
+pub const DOWNLOADED_BYTES: &str = "cratetorrent_downloaded_bytes_total";
+/// The total number of payload bytes uploaded.
+pub const UPLOADED_BYTES: &str = "cratetorrent_uploaded_bytes_total";
+/// The total number of pieces that passed the hash check.
+pub const PIECES_PASSED: &str = "cratetorrent_pieces_passed_total";
+/// The total number of pieces that failed the hash check. Together with
+/// [`PIECES_PASSED`] this gives the piece fail rate.
+pub const PIECES_FAILED: &str = "cratetorrent_pieces_failed_total";
+/// The number of peers of all torrents.
+pub const CONNECTED_PEERS: &str = "cratetorrent_connected_peers";
+/// The number of complete pieces waiting to be hashed and written to disk.
+pub const DISK_QUEUE_DEPTH: &str = "cratetorrent_disk_queue_depth";
+/// The number of nodes in the DHT routing table.
+pub const DHT_NODES: &str = "cratetorrent_dht_nodes";
+
+/// Registers the descriptions and units of the metrics with the installed
+/// recorder.
+pub(crate) fn describe() {
+    #[cfg(feature = "metrics")]
+    {
+        describe_counter!(
+            DOWNLOADED_BYTES,
+            Unit::Bytes,
+            "Payload bytes downloaded"
+        );
+        describe_counter!(
+            UPLOADED_BYTES,
+            Unit::Bytes,
+            "Payload bytes uploaded"
+        );
+        describe_counter!(PIECES_PASSED, "Pieces that passed the hash check");
+        describe_counter!(PIECES_FAILED, "Pieces that failed the hash check");
+        describe_gauge!(CONNECTED_PEERS, "Peers of all torrents");
+        describe_gauge!(
+            DISK_QUEUE_DEPTH,
+            "Complete pieces waiting to be hashed and written to disk"
+        );
+        describe_gauge!(DHT_NODES, "Nodes in the DHT routing table");
+    }
+}
+
+/// Records the payload transferred by a torrent in the last round.
+pub(crate) fn add_payload(down: u64, up: u64) {
+    #[cfg(feature = "metrics")]
+    {
+        counter!(DOWNLOADED_BYTES).increment(down);
+        counter!(UPLOADED_BYTES).increment(up);
+    }
+}
+
+/// Records the result of a piece's hash check.
+pub(crate) fn add_piece(is_valid: bool) {
+    #[cfg(feature = "metrics")]
+    {
+        if is_valid {
+            counter!(PIECES_PASSED).increment(1);
+        } else {
+            counter!(PIECES_FAILED).increment(1);
+        }
+    }
+}
+
+/// Updates the peer count of all torrents with the change in a torrent's
+/// peer count, from its previously reported count.
+pub(crate) fn update_peer_count(prev: usize, curr: usize) {
+    #[cfg(feature = "metrics")]
+    {
+        if curr > prev {
+            gauge!(CONNECTED_PEERS).increment((curr - prev) as f64);
+        } else if curr < prev {
+            gauge!(CONNECTED_PEERS).decrement((prev - curr) as f64);
+        }
+    }
+}
+
+/// Records that a complete piece was queued for hashing and writing.
+pub(crate) fn disk_write_queued() {
+    #[cfg(feature = "metrics")]
+    gauge!(DISK_QUEUE_DEPTH).increment(1.0);
+}
+
+/// Records that a queued piece was hashed and written (or failed to be).
+pub(crate) fn disk_write_done() {
+    #[cfg(feature = "metrics")]
+    gauge!(DISK_QUEUE_DEPTH).decrement(1.0);
+}
+
+/// Records the number of nodes in the DHT routing table.
+pub(crate) fn set_dht_node_count(count: usize) {
+    #[cfg(feature = "metrics")]
+    gauge!(DHT_NODES).set(count as f64);
+}
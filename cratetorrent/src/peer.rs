@@ -9,8 +9,9 @@
 //! one, due to making use of shared data in torrent.
 
 use std::{
-    collections::HashSet,
-    net::SocketAddr,
+    collections::{HashMap, HashSet, VecDeque},
+    io,
+    net::{IpAddr, SocketAddr},
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -26,27 +27,35 @@ use tokio::{
         mpsc::{self, UnboundedReceiver, UnboundedSender},
         RwLock,
     },
-    time,
 };
 use tokio_util::codec::{Framed, FramedParts};
+use tracing::Instrument;
 
 use crate::{
     alert::Alert,
+    conf::SocketConf,
     counter::ThruputCounters,
     disk,
     download::{BlockStatus, PieceDownload},
-    error::Error,
-    torrent::{self, TorrentContext},
-    Bitfield, Block, BlockInfo, PeerId, PieceIndex,
+    external_ip::Voter,
+    proxy,
+    rate_limit::TokenBucket,
+    rt,
+    torrent::{self, stats::MessageStats, TorrentContext},
+    Bitfield, Block, BlockInfo, PeerId, PieceIndex, MAX_BLOCK_LEN,
 };
 use codec::*;
 use error::*;
+use extension::{ExtendedHandshake, MetadataMsg, METADATA_PIECE_LEN};
 use state::*;
 
 pub use state::{ConnectionState, SessionState};
 
 mod codec;
 pub mod error;
+mod extension;
+mod fast;
+pub(crate) mod metadata;
 mod state;
 
 /// The most essential information of a peer session that is sent to torrent
@@ -57,6 +66,8 @@ pub(crate) struct SessionTick {
     pub state: SessionState,
     /// Various transfer statistics.
     pub counters: ThruputCounters,
+    /// The messages exchanged since the last tick, by message type.
+    pub messages: MessageStats,
     /// The number of pieces the peer has available.
     pub piece_count: usize,
 }
@@ -76,12 +87,18 @@ pub(crate) enum Command {
         /// Tell the session to enter endgame mode.
         in_endgame: bool,
     },
+    /// Puts the peer on parole, as it sent blocks of a piece that turned out
+    /// to be corrupt.
+    EnterParole,
+    /// Cancels the request of a block, if still pending, because it was
+    /// received from another peer or because the request timed out.
+    CancelRequest(BlockInfo),
     /// Eventually shut down the peer session.
     Shutdown,
 }
 
 /// Determines who initiated the connection.
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 enum Direction {
     Outbound,
     Inbound,
@@ -162,13 +179,39 @@ pub(crate) struct PeerSession {
     // this information in just PieceDownload so that we don't have to enforce
     // this invariant (keeping in mind that later PieceDownloads will be shared
     // among PeerSessions)?
-    outgoing_requests: HashSet<BlockInfo>,
+    //
+    // Each request is mapped to the time it was sent, from which the request
+    // round-trip-time is measured and the request timeout is checked.
+    outgoing_requests: HashMap<BlockInfo, Instant>,
     /// The requests we got from peer.
     ///
     /// The request's entry is removed from here when the block is transmitted
     /// or when the peer cancels it. If a peer sends a request and cancels it
     /// before the disk read is done, the read block is dropped.
     incoming_requests: HashSet<BlockInfo>,
+
+    /// The blocks read from disk that are waiting to be sent to peer, due to
+    /// the upload rate limits.
+    upload_queue: VecDeque<Block>,
+    /// If set, limits the rate at which we send blocks to peer.
+    upload_limit: Option<TokenBucket>,
+    /// If set, limits the rate at which we request blocks from peer.
+    download_limit: Option<TokenBucket>,
+
+    /// The pieces peer may request from us even while choked. This is only
+    /// set if peer supports the Fast extension.
+    allowed_fast: HashSet<PieceIndex>,
+
+    /// Whether the peer is on parole.
+    ///
+    /// A peer is put on parole if it sent blocks of a piece that failed the
+    /// hash check, along with other peers, so we can't tell which of them sent
+    /// the corrupt data. While on parole, the peer only downloads pieces that
+    /// no other peer may download, one at a time, so that if such a piece is
+    /// corrupt again, the peer can be banned.
+    on_parole: bool,
+    /// The piece we're downloading exclusively from the peer while on parole.
+    parole_piece: Option<PieceIndex>,
 }
 
 /// Information about the peer we're connected to.
@@ -176,6 +219,8 @@ pub(crate) struct PeerSession {
 pub(super) struct PeerInfo {
     /// The IP-port pair of the peer.
     pub addr: SocketAddr,
+    /// Whether the peer is on our local network.
+    pub is_local: bool,
     /// Peer's 20 byte BitTorrent id. Updated when the peer sends us its peer
     /// id, in the handshake.
     pub id: Option<PeerId>,
@@ -188,6 +233,16 @@ pub(super) struct PeerInfo {
     /// This is equivalent to `self.pieces.count_ones()` and is updated every
     /// time the peer sends us an announcement of a new piece.
     pub piece_count: usize,
+    /// Whether the peer advertised support for the Fast extension in its
+    /// handshake.
+    pub supports_fast: bool,
+    /// Whether the peer advertised support for the extension protocol in its
+    /// handshake.
+    pub supports_extensions: bool,
+    /// The extended message id with which metadata messages must be sent to
+    /// the peer, if it advertised support for the metadata exchange
+    /// extension in its extended handshake.
+    pub ut_metadata_id: Option<u8>,
 }
 
 impl PeerSession {
@@ -200,11 +255,21 @@ impl PeerSession {
     pub fn new(
         torrent: Arc<TorrentContext>,
         addr: SocketAddr,
+        is_local: bool,
     ) -> (Self, Sender) {
         let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
         let piece_count = torrent.storage.piece_count;
         let log_target =
             format!("cratetorrent::peer [{}][{}]", torrent.id, addr);
+        let now = Instant::now();
+        let upload_limit = torrent
+            .peer_rate_limit
+            .upload
+            .map(|rate| TokenBucket::new(rate, now));
+        let download_limit = torrent
+            .peer_rate_limit
+            .download
+            .map(|rate| TokenBucket::new(rate, now));
         (
             Self {
                 torrent,
@@ -212,16 +277,26 @@ impl PeerSession {
                 cmd_rx: cmd_rx.fuse(),
                 peer: PeerInfo {
                     addr,
+                    is_local,
                     pieces: Bitfield::repeat(false, piece_count),
                     piece_count: 0,
                     id: Default::default(),
+                    supports_fast: false,
+                    supports_extensions: false,
+                    ut_metadata_id: None,
                 },
                 ctx: SessionContext {
                     log_target,
                     ..SessionContext::default()
                 },
-                outgoing_requests: HashSet::new(),
+                outgoing_requests: HashMap::new(),
                 incoming_requests: HashSet::new(),
+                upload_queue: VecDeque::new(),
+                upload_limit,
+                download_limit,
+                allowed_fast: HashSet::new(),
+                on_parole: false,
+                parole_piece: None,
             },
             cmd_tx,
         )
@@ -233,13 +308,32 @@ impl PeerSession {
     /// constructor, send a handshake, and start the session.
     /// It returns if the connection is closed or an error occurs.
     pub async fn start_outbound(&mut self) -> Result<()> {
-        log::info!(target: &self.ctx.log_target, "Starting outbound session");
+        let span = self.span(Direction::Outbound);
+        async {
+            log::info!(
+                target: &self.ctx.log_target,
+                "Starting outbound session"
+            );
+            let result = self.connect_and_start().await;
+            self.disconnect(result).await
+        }
+        .instrument(span)
+        .await
+    }
 
-        // establish the TCP connection
+    /// Establishes the TCP connection with the peer and starts the session.
+    async fn connect_and_start(&mut self) -> Result<()> {
         log::info!(target: &self.ctx.log_target, "Connecting to peer");
         self.ctx.set_connection_state(ConnectionState::Connecting);
-        let socket = TcpStream::connect(self.peer.addr).await?;
+        let socket = match &self.torrent.proxy {
+            Some(proxy) => {
+                proxy::connect(proxy, &self.torrent.local_addr, self.peer.addr)
+                    .await?
+            }
+            None => self.torrent.local_addr.connect(self.peer.addr).await?,
+        };
         log::info!(target: &self.ctx.log_target, "Connected to peer");
+        self.configure_socket(&socket);
 
         let socket = Framed::new(socket, HandshakeCodec);
         self.start(socket, Direction::Outbound).await
@@ -251,10 +345,48 @@ impl PeerSession {
     /// with a handshake, and starts the session.
     /// It returns if the connection is closed or an error occurs.
     pub async fn start_inbound(&mut self, socket: TcpStream) -> Result<()> {
-        log::info!(target: &self.ctx.log_target, "Starting inbound session");
-        self.ctx.set_connection_state(ConnectionState::Connecting);
-        let socket = Framed::new(socket, HandshakeCodec);
-        self.start(socket, Direction::Inbound).await
+        let span = self.span(Direction::Inbound);
+        async {
+            log::info!(
+                target: &self.ctx.log_target,
+                "Starting inbound session"
+            );
+            self.ctx.set_connection_state(ConnectionState::Connecting);
+            self.configure_socket(&socket);
+            let socket = Framed::new(socket, HandshakeCodec);
+            let result = self.start(socket, Direction::Inbound).await;
+            self.disconnect(result).await
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Returns the span in which the session's events are recorded, so that
+    /// they can be told apart from other sessions' events.
+    fn span(&self, direction: Direction) -> tracing::Span {
+        tracing::info_span!(
+            "peer",
+            addr = %self.peer.addr,
+            ?direction,
+            is_local = self.peer.is_local,
+        )
+    }
+
+    /// Applies the socket options from the engine configuration to the
+    /// connection's socket.
+    ///
+    /// Failing to set an option is not fatal, as the connection is still
+    /// usable with the OS defaults, so errors are only logged.
+    fn configure_socket(&self, socket: &TcpStream) {
+        let conf = &self.torrent.socket_conf;
+        if let Err(e) = set_socket_options(socket, conf) {
+            log::warn!(
+                target: &self.ctx.log_target,
+                "Error setting socket options {:?}: {}",
+                conf,
+                e
+            );
+        }
     }
 
     /// Helper method for the common steps of setting up a session.
@@ -275,91 +407,86 @@ impl PeerSession {
             socket.send(handshake).await?;
         }
 
-        // receive peer's handshake
+        // receive peer's handshake, but don't wait for it forever
         log::info!(target: &self.ctx.log_target, "Waiting for peer handshake");
-        if let Some(peer_handshake) = socket.next().await {
-            let peer_handshake = peer_handshake?;
-            log::info!(target: &self.ctx.log_target, "Peer sent handshake");
-            log::trace!(target: &self.ctx.log_target, "Peer handshake: {:?}", peer_handshake);
-            // codec should only return handshake if the protocol string in it
-            // is valid
-            debug_assert_eq!(peer_handshake.prot, PROTOCOL_STRING.as_bytes());
-
-            self.ctx.counters.protocol.down += peer_handshake.len();
-
-            // verify that the advertised torrent info hash is the same as ours
-            if peer_handshake.info_hash != self.torrent.info_hash {
-                log::info!(target: &self.ctx.log_target, "Peer handshake invalid info hash");
-                // abort session, info hash is invalid
-                return Err(PeerError::InvalidInfoHash);
+        let peer_handshake = rt::timeout(HANDSHAKE_TIMEOUT, socket.next())
+            .await
+            .map_err(|_| {
+                log::info!(target: &self.ctx.log_target, "Peer handshake timed out");
+                PeerError::HandshakeTimeout
+            })?;
+        let peer_handshake = match peer_handshake {
+            Some(peer_handshake) => peer_handshake?,
+            None => {
+                log::info!(target: &self.ctx.log_target, "No handshake received");
+                return Ok(());
             }
+        };
+        log::info!(target: &self.ctx.log_target, "Peer sent handshake");
+        log::trace!(target: &self.ctx.log_target, "Peer handshake: {:?}", peer_handshake);
+        // codec should only return handshake if the protocol string in it is
+        // valid
+        debug_assert_eq!(peer_handshake.prot, PROTOCOL_STRING.as_bytes());
+
+        self.ctx.counters.protocol.down += peer_handshake.len();
+
+        // verify that the advertised torrent info hash is the same as ours
+        if peer_handshake.info_hash != self.torrent.info_hash {
+            log::info!(target: &self.ctx.log_target, "Peer handshake invalid info hash");
+            // abort session, info hash is invalid
+            return Err(PeerError::InvalidInfoHash);
+        }
 
-            // set the peer's id
-            self.peer.id = Some(peer_handshake.peer_id);
+        // set the peer's id
+        self.peer.id = Some(peer_handshake.peer_id);
+        self.peer.supports_fast = peer_handshake.supports_fast();
+        self.peer.supports_extensions = peer_handshake.supports_extensions();
 
-            // if this is an inbound connection, we reply with the handshake
-            if direction == Direction::Inbound {
-                let handshake = Handshake::new(
-                    self.torrent.info_hash,
-                    self.torrent.client_id,
-                );
-                log::info!(target: &self.ctx.log_target, "Sending handshake");
-                self.ctx.counters.protocol.up += handshake.len();
-                socket.send(handshake).await?;
-            }
+        // if this is an inbound connection, we reply with the handshake
+        if direction == Direction::Inbound {
+            let handshake =
+                Handshake::new(self.torrent.info_hash, self.torrent.client_id);
+            log::info!(target: &self.ctx.log_target, "Sending handshake");
+            self.ctx.counters.protocol.up += handshake.len();
+            socket.send(handshake).await?;
+        }
 
-            // now that we have the handshake, we need to switch to the peer
-            // message codec and save the socket in self (note that we need to
-            // keep the buffer from the original codec as it may contain bytes
-            // of any potential message the peer may have sent after the
-            // handshake)
-            let old_parts = socket.into_parts();
-            let mut new_parts = FramedParts::new(old_parts.io, PeerCodec);
-            // reuse buffers of previous codec
-            new_parts.read_buf = old_parts.read_buf;
-            new_parts.write_buf = old_parts.write_buf;
-            let socket = Framed::from_parts(new_parts);
-
-            // update torrent of connection
-            self.torrent.cmd_tx.send(torrent::Command::PeerConnected {
-                addr: self.peer.addr,
-                id: peer_handshake.peer_id,
-            })?;
+        // now that we have the handshake, we need to switch to the peer
+        // message codec and save the socket in self (note that we need to
+        // keep the buffer from the original codec as it may contain bytes of
+        // any potential message the peer may have sent after the handshake)
+        let old_parts = socket.into_parts();
+        let mut new_parts = FramedParts::new(old_parts.io, PeerCodec);
+        // reuse buffers of previous codec
+        new_parts.read_buf = old_parts.read_buf;
+        new_parts.write_buf = old_parts.write_buf;
+        let socket = Framed::from_parts(new_parts);
+
+        // update torrent of connection
+        self.torrent.cmd_tx.send(torrent::Command::PeerConnected {
+            addr: self.peer.addr,
+            id: peer_handshake.peer_id,
+        })?;
 
-            // enter the piece availability exchange state
-            self.ctx
-                .set_connection_state(ConnectionState::AvailabilityExchange);
-            log::info!(target: &self.ctx.log_target, "Session state: {:?}", self.ctx.state.connection);
+        // enter the piece availability exchange state
+        self.ctx.set_connection_state(ConnectionState::AvailabilityExchange);
+        log::info!(target: &self.ctx.log_target, "Session state: {:?}", self.ctx.state.connection);
 
-            // run the session
-            if let Err(e) = self.run(socket).await {
-                log::error!(
-                    target: &self.ctx.log_target,
-                    "Session stopped due to an error: {}",
-                    e
-                );
-                self.ctx.set_connection_state(ConnectionState::Disconnected);
-                self.torrent.cmd_tx.send(torrent::Command::PeerState {
-                    addr: self.peer.addr,
-                    info: self.session_info(),
-                })?;
-                self.torrent.alert_tx.send(Alert::Error(Error::Peer {
-                    id: self.torrent.id,
-                    addr: self.peer.addr,
-                    error: e,
-                }))?;
-            }
-        } else {
-            log::error!(target: &self.ctx.log_target, "No handshake received");
-            self.ctx.set_connection_state(ConnectionState::Disconnected);
-            self.torrent.cmd_tx.send(torrent::Command::PeerState {
-                addr: self.peer.addr,
-                info: self.session_info(),
-            })?;
-        }
+        // run the session
+        self.run(socket).await
+    }
 
-        // session exited as a result of a clean shutdown or an error, perform
-        // some cleanup before exiting
+    /// Performs cleanup after the session exited as a result of a clean
+    /// shutdown or an error, and notifies torrent and the user of the
+    /// disconnect.
+    async fn disconnect(&mut self, result: Result<()>) -> Result<()> {
+        if let Err(e) = &result {
+            log::error!(
+                target: &self.ctx.log_target,
+                "Session stopped due to an error: {}",
+                e
+            );
+        }
 
         // cancel any pending requests to not block other peers from completing
         // the piece
@@ -372,6 +499,25 @@ impl PeerSession {
             self.free_pending_blocks().await;
         }
 
+        // let other peers finish the piece we were downloading exclusively from
+        // this peer
+        if let Some(index) = self.parole_piece.take() {
+            if let Some(download) =
+                self.torrent.downloads.read().await.get(&index)
+            {
+                download.write().await.set_exclusive_peer(None);
+            }
+        }
+
+        // the peer's pieces are no longer available to us
+        if self.peer.pieces.any() {
+            self.torrent
+                .piece_picker
+                .write()
+                .await
+                .unregister_peer_pieces(&self.peer.pieces);
+        }
+
         // send a state update message to torrent to actualize possible download
         // stats changes
         self.ctx.set_connection_state(ConnectionState::Disconnected);
@@ -379,6 +525,11 @@ impl PeerSession {
             addr: self.peer.addr,
             info: self.session_info(),
         })?;
+        self.torrent.alert_tx.send(Alert::PeerDisconnected {
+            id: self.torrent.id,
+            addr: self.peer.addr,
+            error: result.err(),
+        })?;
 
         Ok(())
     }
@@ -400,26 +551,73 @@ impl PeerSession {
 
         // This is the beginning of the session, which is the only time
         // a peer is allowed to advertise their pieces. If we have pieces
-        // available, send a bitfield message.
+        // available, send a bitfield message. With the Fast extension, we
+        // must always advertise our pieces, but we can do so more compactly
+        // if we have all or none of the pieces.
         {
             let piece_picker_guard = self.torrent.piece_picker.read().await;
             let own_pieces = piece_picker_guard.own_pieces();
-            if own_pieces.any() {
+            let msg = if self.peer.supports_fast && own_pieces.all() {
+                Some(Message::HaveAll)
+            } else if self.peer.supports_fast && own_pieces.not_any() {
+                Some(Message::HaveNone)
+            } else if own_pieces.any() {
+                Some(Message::Bitfield(own_pieces.clone()))
+            } else {
+                None
+            };
+            if let Some(msg) = msg {
                 log::info!(target: &self.ctx.log_target, "Sending piece availability");
-                sink.send(Message::Bitfield(own_pieces.clone())).await?;
+                self.ctx.record_outgoing_msg(&msg);
+                sink.send(msg).await?;
                 log::info!(target: &self.ctx.log_target, "Sent piece availability");
             }
+
+            // let peer know which of our pieces it may download while choked
+            if self.peer.supports_fast {
+                if let IpAddr::V4(ip) = self.peer.addr.ip() {
+                    self.allowed_fast = fast::allowed_fast_set(
+                        ip,
+                        &self.torrent.info_hash,
+                        self.torrent.storage.piece_count,
+                        ALLOWED_FAST_SET_LEN,
+                    );
+                    for &piece_index in self.allowed_fast.iter() {
+                        if own_pieces[piece_index] {
+                            log::debug!(target: &self.ctx.log_target, "Allowing fast piece {}", piece_index);
+                            let msg = Message::AllowedFast { piece_index };
+                            self.ctx.record_outgoing_msg(&msg);
+                            sink.send(msg).await?;
+                        }
+                    }
+                }
+            }
+        }
+
+        // tell peer the extensions we support and its IP address as we see it
+        if self.peer.supports_extensions {
+            let handshake = ExtendedHandshake::new(
+                self.peer.addr.ip(),
+                self.torrent.metadata.as_ref().map(Vec::len),
+            );
+            let msg = Message::Extended {
+                id: extension::HANDSHAKE_ID,
+                payload: handshake.encode(),
+            };
+            log::info!(target: &self.ctx.log_target, "Sending extended handshake");
+            self.ctx.record_outgoing_msg(&msg);
+            sink.send(msg).await?;
         }
 
         // used for collecting session stats every second
-        let mut tick_timer = time::interval(Duration::from_secs(1)).fuse();
+        let mut tick_timer = rt::interval(Duration::from_secs(1)).fuse();
 
         // start the loop for receiving messages from peer and commands from
         // other parts of the engine
         loop {
             select! {
                 now = tick_timer.select_next_some() => {
-                    self.tick(&mut sink, now.into_std()).await?;
+                    self.tick(&mut sink, now).await?;
                 }
                 msg = stream.select_next_some() => {
                     let msg = msg?;
@@ -428,7 +626,11 @@ impl PeerSession {
                     // received directly after the handshake (later once we
                     // implement the FAST extension, there will be other piece
                     // availability related messages to handle)
-                    if self.ctx.state.connection == ConnectionState::AvailabilityExchange {
+                    if let Message::Extended { .. } = msg {
+                        // the extended handshake may be sent before the piece
+                        // availability, so it doesn't affect the session state
+                        self.handle_msg(&mut sink, msg).await?;
+                    } else if self.ctx.state.connection == ConnectionState::AvailabilityExchange {
                         if let Message::Bitfield(bitfield) = msg {
                             self.handle_bitfield_msg(&mut sink, bitfield).await?;
                         } else {
@@ -468,12 +670,25 @@ impl PeerSession {
                 cmd = self.cmd_rx.select_next_some() => {
                     match cmd {
                         Command::Block(block)=> {
-                            self.send_block(&mut sink, block).await?;
+                            self.upload_queue.push_back(block);
+                            self.send_blocks(&mut sink).await?;
                         }
                         Command::PieceCompletion { index, in_endgame } => {
                             self.ctx.in_endgame = in_endgame;
                             self.handle_piece_completion(&mut sink, index).await?;
                         }
+                        Command::EnterParole => {
+                            if !self.on_parole {
+                                log::warn!(
+                                    target: &self.ctx.log_target,
+                                    "Peer put on parole"
+                                );
+                                self.on_parole = true;
+                            }
+                        }
+                        Command::CancelRequest(block) => {
+                            self.cancel_request(&mut sink, block).await?;
+                        }
                         Command::Shutdown => {
                             log::info!(
                                 target: &self.ctx.log_target,
@@ -513,12 +728,39 @@ impl PeerSession {
             return Err(PeerError::InactivityTimeout);
         }
 
+        // disconnect peer if it is sending us more messages than what is
+        // reasonable (blocks and requests are not counted as these are bounded
+        // by our own requests and the incoming request limit, respectively)
+        let msgs = &self.ctx.messages.down;
+        let msg_count =
+            msgs.total().count - msgs.block.count - msgs.request.count;
+        if msg_count > MAX_MSG_RATE {
+            log::warn!(
+                target: &self.ctx.log_target,
+                "Peer sent {} messages in a second, disconnecting",
+                msg_count
+            );
+            return Err(PeerError::Flooding);
+        }
+
         // resent requests if we have pending requests and more time has elapsed
         // since the last request than the current timeout value
         if !self.outgoing_requests.is_empty() {
             self.check_request_timeout(sink).await?;
         }
 
+        // if we were held back by the rate limits, the buckets may have been
+        // replenished since, so continue the transfers
+        if !self.upload_queue.is_empty() {
+            self.send_blocks(sink).await?;
+        }
+        if self.download_limit.is_some()
+            || (self.uses_bandwidth_share()
+                && self.torrent.download_bandwidth.rate().is_some())
+        {
+            self.make_requests(sink).await?;
+        }
+
         // TODO(https://github.com/mandreyel/cratetorrent/issues/42): send
         // keep-alive
 
@@ -534,9 +776,15 @@ impl PeerSession {
             })?;
         }
 
+        // the protocol chatter of this round is only known now, and must be
+        // accounted for before the tick resets the round's counters
+        if self.torrent.rate_limit_protocol_overhead {
+            self.consume_protocol_overhead(now);
+        }
+
         // update session context
         let prev_queue_len = self.ctx.target_request_queue_len;
-        self.ctx.tick();
+        self.ctx.tick(self.torrent.block_len);
         if let (Some(prev_queue_len), Some(curr_queue_len)) =
             (prev_queue_len, self.ctx.target_request_queue_len)
         {
@@ -575,31 +823,33 @@ impl PeerSession {
         Ok(())
     }
 
-    /// Times out the peer if it hasn't sent a request in too long.
+    /// Times out the peer if it hasn't served its oldest pending request
+    /// within the request timeout, which is derived from the peer's request
+    /// round-trip-times.
     async fn check_request_timeout(
         &mut self,
         sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
     ) -> Result<()> {
-        if let Some(last_outgoing_request_time) =
-            self.ctx.last_outgoing_request_time
+        if let Some(oldest_request_time) =
+            self.outgoing_requests.values().min().copied()
         {
-            let elapsed_since_last_request = Instant::now()
-                .saturating_duration_since(last_outgoing_request_time);
+            let elapsed_since_oldest_request =
+                Instant::now().saturating_duration_since(oldest_request_time);
             let request_timeout = self.ctx.request_timeout();
 
             log::debug!(
                 target: &self.ctx.log_target,
                 "Checking request timeout \
-                (last {} ms ago, timeout: {} ms)",
-                elapsed_since_last_request.as_millis(),
+                (oldest {} ms ago, timeout: {} ms)",
+                elapsed_since_oldest_request.as_millis(),
                 request_timeout.as_millis()
             );
 
-            if elapsed_since_last_request > request_timeout {
+            if elapsed_since_oldest_request > request_timeout {
                 log::warn!(
                     target: &self.ctx.log_target,
                     "Timeout after {} ms, cancelling {} request(s) (timeouts: {})",
-                    elapsed_since_last_request.as_millis(),
+                    elapsed_since_oldest_request.as_millis(),
                     self.outgoing_requests.len(),
                     self.ctx.timed_out_request_count + 1
                 );
@@ -623,7 +873,14 @@ impl PeerSession {
     /// other peer sessions may download them.
     async fn free_pending_blocks(&mut self) {
         let downloads_guard = self.torrent.downloads.read().await;
-        for block in self.outgoing_requests.drain() {
+        let mut in_flight_requests =
+            self.torrent.in_flight_requests.write().await;
+        for (block, _) in self.outgoing_requests.drain() {
+            // in endgame the block may still be requested from other peers,
+            // in which case it must not be freed
+            if in_flight_requests.remove(&block, self.peer.addr) {
+                continue;
+            }
             // The piece may no longer be present if it was compoleted by
             // another peer in the meantime and torrent removed it from the
             // shared download store. This is fine, in this case we don't have
@@ -639,12 +896,32 @@ impl PeerSession {
         }
     }
 
+    /// Cancels the pending request of the block, if any.
+    ///
+    /// The block is not freed in its download, as this is done by whoever
+    /// cancels the request. If the block still arrives, it's accepted as long
+    /// as it hasn't been received from another peer.
+    async fn cancel_request(
+        &mut self,
+        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        block: BlockInfo,
+    ) -> Result<()> {
+        if self.outgoing_requests.remove(&block).is_some() {
+            log::debug!(target: &self.ctx.log_target, "Cancelling request {}", block);
+            let msg = Message::Cancel(block);
+            self.ctx.record_outgoing_msg(&msg);
+            sink.send(msg).await?;
+        }
+        Ok(())
+    }
+
     /// Returns a summary of the most important information of the session
     /// state to send to torrent.
     fn session_info(&self) -> SessionTick {
         SessionTick {
             state: self.ctx.state,
             counters: self.ctx.counters,
+            messages: self.ctx.messages,
             piece_count: self.peer.piece_count,
         }
     }
@@ -664,6 +941,17 @@ impl PeerSession {
             ConnectionState::AvailabilityExchange
         );
 
+        // record protocol message size (before the bitfield is resized below,
+        // as we need to record the length of the raw message)
+        let header_len = MessageId::Bitfield.header_len();
+        self.ctx.counters.protocol.down += header_len;
+        self.ctx
+            .messages
+            .down
+            .bitfield
+            .record(header_len + bitfield.as_slice().len() as u64);
+        self.ctx.changed = true;
+
         // The bitfield raw data that is sent over the wire may be longer than
         // the logical pieces it represents, if there the number of pieces in
         // torrent is not a multiple of 8. Therefore, we need to slice off the
@@ -672,8 +960,54 @@ impl PeerSession {
         // According to the spec if the remainder contains any non-zero
         // bits, we need to abort the connection. Not sure if this is too
         // strict, there doesn't seem much harm in it so we skip the check.
-        bitfield.resize(self.torrent.storage.piece_count, false);
+        //
+        // However, the bitfield must have exactly as many bytes as needed to
+        // represent all pieces.
+        let piece_count = self.torrent.storage.piece_count;
+        let bitfield_len = bitfield.as_slice().len();
+        if bitfield_len != (piece_count + 7) / 8 {
+            log::warn!(
+                target: &self.ctx.log_target,
+                "Peer sent bitfield of {} bytes for {} pieces",
+                bitfield_len,
+                piece_count
+            );
+            return Err(PeerError::InvalidMessageLength {
+                id: MessageId::Bitfield as u8,
+                len: 1 + bitfield_len,
+            });
+        }
+        bitfield.resize(piece_count, false);
+
+        self.register_peer_pieces(sink, bitfield).await
+    }
+
+    /// Handles the Fast extension's 'have all' and 'have none' messages, which
+    /// may be sent instead of the bitfield.
+    async fn handle_have_all_or_none_msg(
+        &mut self,
+        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        has_all: bool,
+    ) -> Result<()> {
+        if self.ctx.state.connection != ConnectionState::AvailabilityExchange {
+            log::info!(
+                target: &self.ctx.log_target,
+                "Peer sent have all or none message not after handshake"
+            );
+            return Err(PeerError::BitfieldNotAfterHandshake);
+        }
+        let bitfield =
+            Bitfield::repeat(has_all, self.torrent.storage.piece_count);
+        self.register_peer_pieces(sink, bitfield).await
+    }
 
+    /// Registers the peer's piece availability, as advertised at the beginning
+    /// of the session, and determines our interest in it.
+    async fn register_peer_pieces(
+        &mut self,
+        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        bitfield: Bitfield,
+    ) -> Result<()> {
         // register peer's pieces with piece picker and determine interest in it
         let is_interested = self
             .torrent
@@ -707,6 +1041,7 @@ impl PeerSession {
     ) -> Result<()> {
         // record protocol message size
         self.ctx.counters.protocol.down += msg.protocol_len();
+        self.ctx.record_incoming_msg(&msg);
         match msg {
             Message::Bitfield(_) => {
                 log::info!(
@@ -753,6 +1088,7 @@ impl PeerSession {
                         state.is_peer_interested = true;
                         state.is_peer_choked = false;
                     });
+                    self.ctx.record_outgoing_msg(&Message::Unchoke);
                     sink.send(Message::Unchoke).await?;
                 }
             }
@@ -781,7 +1117,7 @@ impl PeerSession {
                 self.make_requests(sink).await?;
             }
             Message::Request(block_info) => {
-                self.handle_request_msg(block_info).await?;
+                self.handle_request_msg(sink, block_info).await?;
             }
             Message::Have { piece_index } => {
                 self.handle_have_msg(sink, piece_index).await?;
@@ -792,11 +1128,153 @@ impl PeerSession {
                 log::info!(target: &self.ctx.log_target, "Peer cancelled block {}", block_info);
                 self.incoming_requests.remove(&block_info);
             }
+            Message::HaveAll => {
+                self.handle_have_all_or_none_msg(sink, true).await?;
+            }
+            Message::HaveNone => {
+                self.handle_have_all_or_none_msg(sink, false).await?;
+            }
+            Message::RejectRequest(block_info) => {
+                self.validate_block_info(&block_info)?;
+                log::info!(target: &self.ctx.log_target, "Peer rejected request {}", block_info);
+                // free the block so that other peers may download it
+                if self.outgoing_requests.remove(&block_info).is_some() {
+                    if let Some(download) = self
+                        .torrent
+                        .downloads
+                        .read()
+                        .await
+                        .get(&block_info.piece_index)
+                    {
+                        download.write().await.free_block(&block_info);
+                    }
+                }
+            }
+            Message::SuggestPiece { piece_index } => {
+                // we don't act on suggestions (yet)
+                self.validate_piece_index(piece_index)?;
+                log::debug!(target: &self.ctx.log_target, "Peer suggested piece {}", piece_index);
+            }
+            Message::AllowedFast { piece_index } => {
+                // we don't download while choked (yet)
+                self.validate_piece_index(piece_index)?;
+                log::debug!(target: &self.ctx.log_target, "Peer allowed fast piece {}", piece_index);
+            }
+            Message::Extended { id, payload } => {
+                if id == extension::HANDSHAKE_ID {
+                    self.handle_extended_handshake(&payload);
+                } else if id == extension::UT_METADATA_ID {
+                    self.handle_metadata_msg(sink, &payload).await?;
+                } else {
+                    // we don't advertise other extensions so the peer
+                    // shouldn't send us other extended messages, but this is
+                    // harmless
+                    log::debug!(target: &self.ctx.log_target, "Peer sent unsupported extended message {}", id);
+                }
+            }
         }
 
         Ok(())
     }
 
+    /// Handles the peer's extended handshake.
+    ///
+    /// If the peer tells us our IP address, it is counted as a vote towards
+    /// our external IP address. An invalid handshake is ignored, as we don't
+    /// use any extensions yet.
+    fn handle_extended_handshake(&mut self, payload: &[u8]) {
+        let handshake = match ExtendedHandshake::decode(payload) {
+            Ok(handshake) => handshake,
+            Err(e) => {
+                log::info!(target: &self.ctx.log_target, "Peer sent invalid extended handshake: {}", e);
+                return;
+            }
+        };
+        log::debug!(target: &self.ctx.log_target, "Peer extended handshake: {:?}", handshake);
+        if let Some(ip) = handshake.your_ip() {
+            self.torrent
+                .external_ip
+                .vote(Voter::Peer(self.peer.addr.ip()), ip);
+        }
+        self.peer.ut_metadata_id = handshake.ut_metadata_id();
+    }
+
+    /// Handles a message of the metadata exchange extension, with which the
+    /// peer downloads the torrent's info dictionary, e.g. because it was
+    /// added from a magnet link.
+    ///
+    /// Requests for pieces of the metadata we don't have are rejected, while
+    /// other messages are ignored, as we already have the metadata.
+    async fn handle_metadata_msg(
+        &mut self,
+        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        payload: &[u8],
+    ) -> Result<()> {
+        let id = match self.peer.ut_metadata_id {
+            Some(id) => id,
+            None => {
+                log::debug!(target: &self.ctx.log_target, "Peer sent metadata message without advertising support");
+                return Ok(());
+            }
+        };
+        let piece = match MetadataMsg::decode(payload) {
+            Some(MetadataMsg::Request { piece }) => piece,
+            Some(_) => return Ok(()),
+            None => {
+                log::info!(target: &self.ctx.log_target, "Peer sent invalid metadata message");
+                return Ok(());
+            }
+        };
+        log::debug!(target: &self.ctx.log_target, "Peer requested metadata piece {}", piece);
+
+        let metadata = self.torrent.metadata.as_deref().unwrap_or_default();
+        let msg = match piece.checked_mul(METADATA_PIECE_LEN) {
+            Some(start) if start < metadata.len() => {
+                let end = metadata.len().min(start + METADATA_PIECE_LEN);
+                MetadataMsg::Data {
+                    piece,
+                    total_size: metadata.len(),
+                    data: metadata[start..end].to_vec(),
+                }
+            }
+            _ => MetadataMsg::Reject { piece },
+        };
+        let msg = Message::Extended {
+            id,
+            payload: msg.encode(),
+        };
+        self.ctx.record_outgoing_msg(&msg);
+        sink.send(msg).await?;
+        Ok(())
+    }
+
+    /// Returns whether transfers with the peer draw from the torrent's shares
+    /// of the engine-wide rate limits, which peers on the local network may be
+    /// exempt from.
+    fn uses_bandwidth_share(&self) -> bool {
+        !(self.peer.is_local && self.torrent.exempt_local_peers_from_rate_limit)
+    }
+
+    /// Draws the protocol bytes exchanged this round from the rate limits, in
+    /// addition to the block payloads, which are drawn as they're transferred.
+    ///
+    /// This may put the buckets in debt, which holds back the next transfers
+    /// until it's paid off.
+    fn consume_protocol_overhead(&mut self, now: Instant) {
+        let up = self.ctx.counters.protocol.up.round();
+        let down = self.ctx.counters.protocol.down.round();
+        if let Some(limit) = &mut self.upload_limit {
+            limit.consume(up, now);
+        }
+        if let Some(limit) = &mut self.download_limit {
+            limit.consume(down, now);
+        }
+        if self.uses_bandwidth_share() {
+            self.torrent.upload_bandwidth.consume(up, now);
+            self.torrent.download_bandwidth.consume(down, now);
+        }
+    }
+
     /// Fills the session's download pipeline with the optimal number of
     /// requests.
     ///
@@ -818,37 +1296,121 @@ impl PeerSession {
             return Ok(());
         }
 
+        if let Some(limit) = &mut self.download_limit {
+            if !limit.has_tokens(Instant::now()) {
+                log::debug!(target: &self.ctx.log_target, "Download rate limit reached");
+                return Ok(());
+            }
+        }
+        if self.uses_bandwidth_share()
+            && !self.torrent.download_bandwidth.has_tokens(Instant::now())
+        {
+            log::debug!(
+                target: &self.ctx.log_target,
+                "Torrent download rate limit reached"
+            );
+            return Ok(());
+        }
+
         // TODO: optimize this by using the preallocated hashset in self
         let mut requests = Vec::new();
         let target_request_queue_len =
             self.ctx.target_request_queue_len.unwrap_or_default();
 
+        // Pieces whose deadline would be missed if the peer downloading them
+        // timed out are requested first, even if their blocks have already
+        // been requested from other peers, as in endgame mode. Whichever peer
+        // delivers a block first wins.
+        let at_risk = self
+            .torrent
+            .piece_picker
+            .read()
+            .await
+            .pieces_at_risk(Instant::now() + self.ctx.request_timeout());
+        // peers on parole may only download their own piece
+        if !at_risk.is_empty() && !self.on_parole {
+            let downloads = self.torrent.downloads.read().await;
+            for index in at_risk.iter().copied() {
+                let outgoing_request_count =
+                    requests.len() + self.outgoing_requests.len();
+                if outgoing_request_count >= target_request_queue_len {
+                    break;
+                }
+                if !self.peer.pieces[index] {
+                    continue;
+                }
+                if let Some(download) = downloads.get(&index) {
+                    let mut download = download.write().await;
+                    if !download.is_available_to(self.peer.addr) {
+                        continue;
+                    }
+                    log::debug!(target: &self.ctx.log_target, "Piece {} deadline at risk", index);
+                    download.pick_blocks(
+                        target_request_queue_len - outgoing_request_count,
+                        &mut requests,
+                        true,
+                        &self.outgoing_requests,
+                    );
+                }
+            }
+        }
+        // these may be requested from multiple peers even outside endgame
+        let at_risk_request_count = requests.len();
+
         // If we have active downloads, prefer to continue those. This will
-        // result in less in-progress pieces.
-        for download in self.torrent.downloads.write().await.values_mut() {
-            // check and calculate the number of requests we can make now
-            let outgoing_request_count =
-                requests.len() + self.outgoing_requests.len();
-            // our outgoing request queue shouldn't exceed the allowed request
-            // queue size
-            if outgoing_request_count >= target_request_queue_len {
-                break;
+        // result in less in-progress pieces. The downloads closest to
+        // completion are continued first, so that they free up their write
+        // buffers sooner.
+        {
+            let piece_picker = self.torrent.piece_picker.read().await;
+            let downloads = self.torrent.downloads.read().await;
+            let mut partial_pieces = Vec::with_capacity(downloads.len());
+            for (index, download) in downloads.iter() {
+                // we can only download pieces the peer has, and pieces at risk
+                // were already handled above, while pieces that were skipped
+                // since they were started are not continued
+                if !self.peer.pieces[*index]
+                    || at_risk.contains(index)
+                    || !piece_picker.is_wanted(*index)
+                {
+                    continue;
+                }
+                let download = download.read().await;
+                // pieces exclusive to another peer on parole are not shared,
+                // while a peer on parole only continues its own piece
+                if !download.is_available_to(self.peer.addr)
+                    || (self.on_parole && self.parole_piece != Some(*index))
+                {
+                    continue;
+                }
+                partial_pieces.push((download.count_free_blocks(), *index));
             }
-            let to_request_count =
-                target_request_queue_len - outgoing_request_count;
+            partial_pieces.sort_unstable();
+
+            for (_, index) in partial_pieces {
+                // check and calculate the number of requests we can make now
+                let outgoing_request_count =
+                    requests.len() + self.outgoing_requests.len();
+                // our outgoing request queue shouldn't exceed the allowed
+                // request queue size
+                if outgoing_request_count >= target_request_queue_len {
+                    break;
+                }
+                let to_request_count =
+                    target_request_queue_len - outgoing_request_count;
 
-            let mut download_write_guard = download.write().await;
-            log::trace!(
-                target: &self.ctx.log_target,
-                "Trying to continue download {}",
-                download_write_guard.piece_index()
-            );
-            download_write_guard.pick_blocks(
-                to_request_count,
-                &mut requests,
-                self.ctx.in_endgame,
-                &self.outgoing_requests,
-            );
+                log::trace!(
+                    target: &self.ctx.log_target,
+                    "Trying to continue download {}",
+                    index
+                );
+                downloads[&index].write().await.pick_blocks(
+                    to_request_count,
+                    &mut requests,
+                    self.ctx.in_endgame,
+                    &self.outgoing_requests,
+                );
+            }
         }
 
         // while we can make more requests we start new download(s)
@@ -863,17 +1425,50 @@ impl PeerSession {
             let to_request_count =
                 target_request_queue_len - outgoing_request_count;
 
+            // bound the number of pieces in progress, as each partial piece's
+            // blocks are buffered in memory until the piece is complete
+            let partial_piece_count = self.torrent.downloads.read().await.len();
+            if !can_start_piece(
+                partial_piece_count,
+                self.torrent.max_partial_piece_count,
+                self.is_likely_super_seeder(),
+            ) {
+                log::debug!(
+                    target: &self.ctx.log_target,
+                    "Cannot start new piece, {} pieces in progress",
+                    partial_piece_count
+                );
+                break;
+            }
+
+            // a peer on parole downloads one piece at a time
+            if self.on_parole && self.parole_piece.is_some() {
+                break;
+            }
+
             log::debug!(target: &self.ctx.log_target, "Trying to pick new piece");
 
-            if let Some(index) =
-                self.torrent.piece_picker.write().await.pick_piece()
+            if let Some(index) = self
+                .torrent
+                .piece_picker
+                .write()
+                .await
+                .pick_piece(&self.peer.pieces)
             {
                 log::info!(target: &self.ctx.log_target, "Picked piece {}", index);
 
                 let mut download = PieceDownload::new(
                     index,
                     self.torrent.storage.piece_len(index),
+                    self.torrent.block_len,
                 );
+                // no other peer may download the piece, so that if it's
+                // corrupt, we know that this peer sent the corrupt data
+                if self.on_parole {
+                    log::info!(target: &self.ctx.log_target, "Downloading piece {} on parole", index);
+                    download.set_exclusive_peer(Some(self.peer.addr));
+                    self.parole_piece = Some(index);
+                }
 
                 download.pick_blocks(
                     to_request_count,
@@ -900,6 +1495,29 @@ impl PeerSession {
             }
         }
 
+        // register the requests torrent-wide, dropping those that are already
+        // in flight to other peers, since outside endgame (or deadline risk)
+        // a block is only requested from a single peer
+        {
+            let mut in_flight_requests =
+                self.torrent.in_flight_requests.write().await;
+            let mut i = 0;
+            requests.retain(|block| {
+                let may_duplicate =
+                    i < at_risk_request_count || self.ctx.in_endgame;
+                i += 1;
+                !in_flight_requests.is_requested_by_other(block, self.peer.addr)
+                    || may_duplicate
+            });
+            for block in requests.iter() {
+                in_flight_requests.insert(
+                    *block,
+                    self.peer.addr,
+                    self.cmd_tx.clone(),
+                );
+            }
+        }
+
         if !requests.is_empty() {
             log::info!(
                 target: &self.ctx.log_target,
@@ -907,14 +1525,16 @@ impl PeerSession {
                 requests.len(),
                 self.outgoing_requests.len()
             );
-            self.ctx.last_outgoing_request_time = Some(Instant::now());
+            let now = Instant::now();
             // make the actual requests
             for req in requests.into_iter() {
                 log::debug!(target: &self.ctx.log_target, "Requesting block {}", req);
-                self.outgoing_requests.insert(req);
+                self.outgoing_requests.insert(req, now);
+                let msg = Message::Request(req);
+                self.ctx.record_outgoing_msg(&msg);
                 // TODO: batch these in a single syscall, or is this already
                 // being done by the tokio codec type?
-                sink.send(Message::Request(req)).await?;
+                sink.send(msg).await?;
                 self.ctx.counters.protocol.up +=
                     MessageId::Request.header_len();
             }
@@ -932,8 +1552,21 @@ impl PeerSession {
         block_info: BlockInfo,
         data: Vec<u8>,
     ) -> Result<()> {
-        // remove pending block request
-        self.outgoing_requests.remove(&block_info);
+        // remove pending block request, if it's still pending (it may have
+        // timed out)
+        let request_time = self.outgoing_requests.remove(&block_info);
+
+        // all received blocks count towards the rate limit, even if they are
+        // later discarded
+        let now = Instant::now();
+        if let Some(limit) = &mut self.download_limit {
+            limit.consume(block_info.len as u64, now);
+        }
+        if self.uses_bandwidth_share() {
+            self.torrent
+                .download_bandwidth
+                .consume(block_info.len as u64, now);
+        }
 
         // try to find the piece to which this block corresponds
         // and mark the block in piece as downloaded
@@ -945,7 +1578,23 @@ impl PeerSession {
             .get(&block_info.piece_index)
         {
             Some(download) => {
-                download.write().await.received_block(&block_info)
+                let mut download = download.write().await;
+                // the block must be one of the blocks in which we download
+                // the piece, otherwise we couldn't have requested it
+                if !download.contains_block(&block_info) {
+                    log::warn!(
+                        target: &self.ctx.log_target,
+                        "Discarding block {} not matching piece's blocks",
+                        block_info,
+                    );
+                    self.ctx.record_waste(block_info.len);
+                    return Ok(());
+                }
+                let prev_status = download.received_block(&block_info);
+                if prev_status != BlockStatus::Received {
+                    download.add_contributor(self.peer.addr);
+                }
+                prev_status
             }
             None => {
                 // silently ignore this block if we didn't expected it
@@ -969,7 +1618,25 @@ impl PeerSession {
             }
         };
 
-        // don't process the block if already downloaded
+        // The block is no longer in flight. If it's the first copy to arrive,
+        // cancel its requests to other peers, so they don't send it in vain.
+        {
+            let mut in_flight_requests =
+                self.torrent.in_flight_requests.write().await;
+            if prev_status == BlockStatus::Received {
+                in_flight_requests.remove(&block_info, self.peer.addr);
+            } else {
+                for (addr, tx) in in_flight_requests.take(&block_info) {
+                    if addr != self.peer.addr {
+                        // the peer session may no longer be running
+                        tx.send(Command::CancelRequest(block_info)).ok();
+                    }
+                }
+            }
+        }
+
+        // don't process the block if already downloaded, so that duplicates
+        // never reach the disk task
         if prev_status == BlockStatus::Received {
             self.ctx.record_waste(block_info.len);
             log::info!(
@@ -992,7 +1659,7 @@ impl PeerSession {
             );
 
             // update download stats
-            self.ctx.update_download_stats(block_info.len);
+            self.ctx.update_download_stats(block_info.len, request_time);
 
             // validate and save the block to disk by sending a write command to the
             // disk task
@@ -1015,6 +1682,7 @@ impl PeerSession {
     /// the request is not cancelled by then.
     async fn handle_request_msg(
         &mut self,
+        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
         block_info: BlockInfo,
     ) -> Result<()> {
         log::info!(target: &self.ctx.log_target, "Got request: {:?}", block_info);
@@ -1022,12 +1690,30 @@ impl PeerSession {
         // before processing request validate block info
         self.validate_block_info(&block_info)?;
 
-        // check if peer is not choked: if they are, they can't request blocks
-        if self.ctx.state.is_peer_choked {
+        // check if peer is not choked: if they are, they can't request blocks,
+        // unless the block is in their allowed fast set
+        if self.ctx.state.is_peer_choked
+            && !self.allowed_fast.contains(&block_info.piece_index)
+        {
             log::warn!(target: &self.ctx.log_target, "Choked peer sent request");
+            // with the Fast extension, requests are explicitly rejected
+            if self.peer.supports_fast {
+                let msg = Message::RejectRequest(block_info);
+                self.ctx.record_outgoing_msg(&msg);
+                sink.send(msg).await?;
+                return Ok(());
+            }
             return Err(PeerError::RequestWhileChoked);
         }
 
+        // peer may only request pieces we have
+        if !self.torrent.piece_picker.read().await.own_pieces()
+            [block_info.piece_index]
+        {
+            log::warn!(target: &self.ctx.log_target, "Peer requested missing piece");
+            return Err(PeerError::RequestForMissingPiece);
+        }
+
         // check if peer is not already requesting this block
         if self.incoming_requests.contains(&block_info) {
             // TODO: if peer keeps spamming us, close connection
@@ -1035,6 +1721,17 @@ impl PeerSession {
             return Ok(());
         }
 
+        // each pending request takes up memory and disk IO, so don't let peer
+        // queue up requests indefinitely
+        if self.incoming_requests.len() >= MAX_INCOMING_REQUEST_COUNT {
+            log::warn!(
+                target: &self.ctx.log_target,
+                "Peer exceeded {} pending requests",
+                MAX_INCOMING_REQUEST_COUNT
+            );
+            return Err(PeerError::Flooding);
+        }
+
         log::info!(target: &self.ctx.log_target, "Issuing disk IO read for block {}", block_info);
         self.incoming_requests.insert(block_info);
 
@@ -1049,6 +1746,40 @@ impl PeerSession {
         Ok(())
     }
 
+    /// Sends the blocks read from disk to peer, for as long as the upload rate
+    /// limit allows it.
+    async fn send_blocks(
+        &mut self,
+        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+    ) -> Result<()> {
+        while !self.upload_queue.is_empty() {
+            if let Some(limit) = &mut self.upload_limit {
+                if !limit.has_tokens(Instant::now()) {
+                    log::debug!(
+                        target: &self.ctx.log_target,
+                        "Upload rate limit reached, {} block(s) queued",
+                        self.upload_queue.len()
+                    );
+                    break;
+                }
+            }
+            if self.uses_bandwidth_share()
+                && !self.torrent.upload_bandwidth.has_tokens(Instant::now())
+            {
+                log::debug!(
+                    target: &self.ctx.log_target,
+                    "Torrent upload rate limit reached, {} block(s) queued",
+                    self.upload_queue.len()
+                );
+                break;
+            }
+            if let Some(block) = self.upload_queue.pop_front() {
+                self.send_block(sink, block).await?;
+            }
+        }
+        Ok(())
+    }
+
     /// Sends the block to peer if the peer still wants it (hasn't canceled the
     /// request).
     async fn send_block(
@@ -1070,16 +1801,24 @@ impl PeerSession {
 
         // if it hasn't, send the data to peer
         log::info!(target: &self.ctx.log_target, "Sending {}", info);
-        sink.send(Message::Block {
+        let msg = Message::Block {
             piece_index: block.piece_index,
             offset: block.offset,
             data: block.data,
-        })
-        .await?;
+        };
+        self.ctx.record_outgoing_msg(&msg);
+        sink.send(msg).await?;
         log::info!(target: &self.ctx.log_target, "Sent {}", info);
 
         // update download stats
         self.ctx.update_upload_stats(info.len);
+        let now = Instant::now();
+        if let Some(limit) = &mut self.upload_limit {
+            limit.consume(info.len as u64, now);
+        }
+        if self.uses_bandwidth_share() {
+            self.torrent.upload_bandwidth.consume(info.len as u64, now);
+        }
 
         Ok(())
     }
@@ -1114,8 +1853,31 @@ impl PeerSession {
             .await
             .register_peer_piece(piece_index);
 
-        // we may have become interested in peer
-        self.update_interest(sink, is_interested).await
+        // we may have become interested in peer, but a piece we already have
+        // doesn't make us lose interest in its other pieces
+        if is_interested {
+            self.update_interest(sink, is_interested).await?;
+
+            // A super-seeder only reveals a piece to us once the previous one
+            // it revealed has spread, so request the new piece right away
+            // rather than at the next block arrival. The same goes for any
+            // peer from which we are not downloading anything at the moment.
+            if self.is_likely_super_seeder()
+                || self.outgoing_requests.is_empty()
+            {
+                self.make_requests(sink).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether the peer may be a super-seeder.
+    fn is_likely_super_seeder(&self) -> bool {
+        is_likely_super_seeder(
+            self.torrent.storage.piece_count,
+            self.peer.piece_count,
+        )
     }
 
     /// Checks whether we have become or stopped being interested in the peer.
@@ -1132,6 +1894,7 @@ impl PeerSession {
                 state.is_interested = is_interested;
             });
             // send interested message to peer
+            self.ctx.record_outgoing_msg(&Message::Interested);
             sink.send(Message::Interested).await?;
         } else if self.ctx.state.is_interested && !is_interested {
             log::info!(target: &self.ctx.log_target, "No longer interested in peer");
@@ -1150,7 +1913,15 @@ impl PeerSession {
         log::trace!(target: &self.ctx.log_target, "Validating {}", info);
         self.validate_piece_index(info.piece_index)?;
         let piece_len = self.torrent.storage.piece_len(info.piece_index);
-        if info.len > 0 && info.offset + info.len <= piece_len {
+        // we accept blocks longer than the default block length (as some
+        // clients use them) up to a cap, and the offset is checked for
+        // overflow as it comes from the peer
+        let is_in_piece = info
+            .offset
+            .checked_add(info.len)
+            .map(|end| end <= piece_len)
+            .unwrap_or(false);
+        if info.len > 0 && info.len <= MAX_BLOCK_LEN && is_in_piece {
             Ok(())
         } else {
             log::warn!(target: &self.ctx.log_target, "Peer sent invalid {}", info);
@@ -1182,6 +1953,20 @@ impl PeerSession {
         sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
         piece_index: PieceIndex,
     ) -> Result<()> {
+        // if the piece we downloaded exclusively from this peer is valid, the
+        // peer is no longer suspected of sending corrupt data
+        if self.parole_piece == Some(piece_index) {
+            log::info!(
+                target: &self.ctx.log_target,
+                "Peer sent valid piece {} on parole, ending parole",
+                piece_index
+            );
+            self.parole_piece = None;
+            self.on_parole = false;
+            // we may now download other pieces
+            self.make_requests(sink).await?;
+        }
+
         // if peer doesn't have the piece, announce it
         if !self.peer.pieces[piece_index] {
             log::debug!(
@@ -1189,25 +1974,24 @@ impl PeerSession {
                 "Announcing piece {}",
                 piece_index
             );
-            sink.send(Message::Have { piece_index }).await?;
+            let msg = Message::Have { piece_index };
+            self.ctx.record_outgoing_msg(&msg);
+            sink.send(msg).await?;
         } else {
-            // Otherwise peer has it and we may have requested it. Check if
-            // there are any pending requests for blocks in this piece, and if
-            // so, cancel them.
-            // TODO: We could actually send the cancel messages much sooner,
-            // when we first receive the block (rather then waiting for the
-            // piece completion). However, it would require an mpsc roundtrip to
-            // torrent and all other peers, for each of these blocks received in
-            // endgame, so it is questionable whether it's worth it at the cost
-            // of slowing down the engine.
-            for block in self.outgoing_requests.iter() {
+            // Otherwise peer has it and we may have requested it. Requests
+            // of blocks received from other peers are cancelled as soon as
+            // the blocks arrive, but check if there are any pending requests
+            // left for blocks in this piece, and if so, cancel them.
+            for block in self.outgoing_requests.keys() {
                 if block.piece_index == piece_index {
                     log::info!(
                         target: &self.ctx.log_target,
                         "Already have block {}, cancelling",
                         block
                     );
-                    sink.send(Message::Cancel(*block)).await?;
+                    let msg = Message::Cancel(*block);
+                    self.ctx.record_outgoing_msg(&msg);
+                    sink.send(msg).await?;
                 }
             }
         }
@@ -1219,3 +2003,140 @@ impl PeerSession {
 /// After this timeout if the peers haven't become intereseted in each other,
 /// the connection is severed.
 const INACTIVITY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// If the peer doesn't send its handshake within this timeout after the
+/// connection is established, the connection is severed.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A peer with at most the number of pieces in the torrent divided by this
+/// many pieces (but at least one) is treated as a possible super-seeder.
+const SUPER_SEED_PIECE_COUNT_DIVISOR: usize = 20;
+
+/// The number of pieces that may be started from a possible super-seeder
+/// beyond the torrent's maximum number of pieces in progress.
+const SUPER_SEED_EXTRA_PARTIAL_PIECE_COUNT: usize = 2;
+
+/// The number of pieces in a peer's allowed fast set.
+const ALLOWED_FAST_SET_LEN: usize = 10;
+
+/// Peer may not have more than this many pending requests. Pending requests
+/// take up memory and disk IO, so letting a peer queue up any number of
+/// requests could be used to exhaust our resources.
+const MAX_INCOMING_REQUEST_COUNT: usize = 2000;
+
+/// The maximum number of messages per second, excluding blocks and requests,
+/// that we accept from a peer before disconnecting it.
+const MAX_MSG_RATE: u64 = 5000;
+
+/// Returns whether a peer with the given number of pieces of the torrent has
+/// so few that it may be a super-seeder (BEP 16), which advertises only
+/// a piece or two at a time so that the pieces spread through the swarm as
+/// fast as possible.
+///
+/// Peers that just joined the swarm look the same, but their pieces are rare
+/// too, so treating them the same way doesn't hurt.
+fn is_likely_super_seeder(piece_count: usize, peer_piece_count: usize) -> bool {
+    let max_piece_count = (piece_count / SUPER_SEED_PIECE_COUNT_DIVISOR).max(1);
+    peer_piece_count > 0 && peer_piece_count <= max_piece_count
+}
+
+/// Returns whether a new piece may be started while the given number of
+/// pieces is in progress.
+///
+/// A possible super-seeder may start a few pieces beyond the limit: its
+/// pieces are not available from other peers yet, and the sooner we get them
+/// the sooner we can advertise them to the rest of the swarm. The allowance is
+/// small, so that the write buffers of pieces in progress stay bounded even if
+/// many peers look like super-seeders.
+fn can_start_piece(
+    partial_piece_count: usize,
+    max_partial_piece_count: usize,
+    is_likely_super_seeder: bool,
+) -> bool {
+    let max_partial_piece_count = if is_likely_super_seeder {
+        max_partial_piece_count + SUPER_SEED_EXTRA_PARTIAL_PIECE_COUNT
+    } else {
+        max_partial_piece_count
+    };
+    partial_piece_count < max_partial_piece_count
+}
+
+/// Sets the socket options in `conf` on the TCP socket.
+fn set_socket_options(socket: &TcpStream, conf: &SocketConf) -> io::Result<()> {
+    if conf.nodelay {
+        socket.set_nodelay(true)?;
+    }
+    if let Some(size) = conf.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = conf.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(tos) = conf.tos {
+        set_tos(socket, tos)?;
+    }
+    Ok(())
+}
+
+/// Sets the type of service (IPv4) or traffic class (IPv6) field of the
+/// socket's outgoing packets.
+fn set_tos(socket: &TcpStream, tos: u8) -> io::Result<()> {
+    use nix::libc;
+    use std::os::unix::io::AsRawFd;
+
+    let (level, name) = if socket.local_addr()?.is_ipv6() {
+        (libc::IPPROTO_IPV6, libc::IPV6_TCLASS)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_TOS)
+    };
+    let tos = tos as libc::c_int;
+    // SAFETY: the file descriptor is owned by the live socket and the option
+    // value is a valid pointer to an int of the given size
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &tos as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_detect_likely_super_seeder() {
+        // up to a twentieth of the pieces
+        assert!(is_likely_super_seeder(100, 1));
+        assert!(is_likely_super_seeder(100, 5));
+        assert!(!is_likely_super_seeder(100, 6));
+        // but always at least one piece
+        assert!(is_likely_super_seeder(10, 1));
+        assert!(!is_likely_super_seeder(10, 2));
+        // a peer with no pieces is not seeding anything
+        assert!(!is_likely_super_seeder(100, 0));
+    }
+
+    /// Tests that a possible super-seeder may only start a few pieces beyond
+    /// the limit of pieces in progress.
+    #[test]
+    fn should_cap_partial_pieces_of_super_seeder() {
+        let max = 4;
+        assert!(can_start_piece(3, max, false));
+        assert!(!can_start_piece(4, max, false));
+
+        assert!(can_start_piece(4, max, true));
+        let cap = max + SUPER_SEED_EXTRA_PARTIAL_PIECE_COUNT;
+        assert!(can_start_piece(cap - 1, max, true));
+        assert!(!can_start_piece(cap, max, true));
+        assert!(!can_start_piece(cap + 10, max, true));
+    }
+}
@@ -6,7 +6,11 @@ use std::{
 use bytes::{Buf, BufMut, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 
-use crate::{Bitfield, BlockData, BlockInfo};
+use super::error::PeerError;
+use crate::{
+    torrent::stats::{MessageCount, MessageTypeStats},
+    Bitfield, BlockData, BlockInfo, MAX_BLOCK_LEN,
+};
 
 /// The message sent at the beginning of a peer session by both sides of the
 /// connection.
@@ -28,23 +32,61 @@ pub(crate) struct Handshake {
 impl Handshake {
     /// Creates a new protocol version 1 handshake with the given info hash and
     /// peer id.
+    ///
+    /// The handshake advertises support for the Fast extension and the
+    /// extension protocol.
     pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
         let mut prot = [0; 19];
         prot.copy_from_slice(PROTOCOL_STRING.as_bytes());
+        let mut reserved = [0; 8];
+        reserved[5] |= EXTENSION_PROTOCOL_BIT;
+        reserved[7] |= FAST_EXTENSION_BIT;
         Self {
             prot,
-            reserved: [0; 8],
+            reserved,
             info_hash,
             peer_id,
         }
     }
 
+    /// Returns whether the handshake advertises support for the Fast extension
+    /// ([BEP 6](http://bittorrent.org/beps/bep_0006.html)).
+    pub fn supports_fast(&self) -> bool {
+        self.reserved[7] & FAST_EXTENSION_BIT != 0
+    }
+
+    /// Returns whether the handshake advertises support for the extension
+    /// protocol ([BEP 10](http://bittorrent.org/beps/bep_0010.html)).
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[5] & EXTENSION_PROTOCOL_BIT != 0
+    }
+
     /// Returns the length of the handshake, in bytes.
     pub const fn len(&self) -> u64 {
         19 + 8 + 20 + 20
     }
 }
 
+/// The maximum length of a message we accept from peers, excluding the length
+/// prefix.
+///
+/// The longest messages are blocks, the data of which we request in chunks of
+/// 16 KiB by default. However, other clients may send larger blocks (up to
+/// 128 KiB is widespread), which we allow. Bitfields of torrents with fewer
+/// than a million pieces also fit in this limit.
+///
+/// Without this limit, a peer could make us buffer up to 4 GiB for a single
+/// message.
+pub(crate) const MAX_MSG_LEN: usize = 1 + 4 + 4 + MAX_BLOCK_LEN as usize;
+
+/// The bit in the last byte of the handshake's reserved field that signals
+/// support for the Fast extension.
+const FAST_EXTENSION_BIT: u8 = 0x04;
+
+/// The bit in the sixth byte of the handshake's reserved field that signals
+/// support for the extension protocol.
+const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+
 /// The protocol version 1 string included in the handshake.
 pub(crate) const PROTOCOL_STRING: &str = "BitTorrent protocol";
 
@@ -90,9 +132,12 @@ impl Encoder<Handshake> for HandshakeCodec {
 
 impl Decoder for HandshakeCodec {
     type Item = Handshake;
-    type Error = io::Error;
+    type Error = PeerError;
 
-    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Handshake>> {
+    fn decode(
+        &mut self,
+        buf: &mut BytesMut,
+    ) -> Result<Option<Handshake>, PeerError> {
         if buf.is_empty() {
             return Ok(None);
         }
@@ -111,10 +156,7 @@ impl Decoder for HandshakeCodec {
         let mut tmp_buf = buf.bytes();
         let prot_len = tmp_buf.get_u8() as usize;
         if prot_len != PROTOCOL_STRING.as_bytes().len() {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Handshake must have the string \"BitTorrent protocol\"",
-            ));
+            return Err(PeerError::InvalidHandshake);
         }
 
         // check that we got the full payload in the buffer (NOTE: we need to
@@ -133,6 +175,9 @@ impl Decoder for HandshakeCodec {
         // protocol string
         let mut prot = [0; 19];
         buf.copy_to_slice(&mut prot);
+        if prot != PROTOCOL_STRING.as_bytes() {
+            return Err(PeerError::InvalidHandshake);
+        }
         // reserved field
         let mut reserved = [0; 8];
         buf.copy_to_slice(&mut reserved);
@@ -172,6 +217,23 @@ pub(crate) enum Message {
         data: BlockData,
     },
     Cancel(BlockInfo),
+    // the below messages are part of the Fast extension
+    SuggestPiece {
+        piece_index: usize,
+    },
+    HaveAll,
+    HaveNone,
+    RejectRequest(BlockInfo),
+    AllowedFast {
+        piece_index: usize,
+    },
+    /// A message of the extension protocol. The id is 0 for the extended
+    /// handshake, and otherwise the id the receiver assigned to the
+    /// extension in its extended handshake.
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
 }
 
 impl Message {
@@ -188,6 +250,12 @@ impl Message {
             Self::Request(_) => Some(MessageId::Request),
             Self::Block { .. } => Some(MessageId::Block),
             Self::Cancel(_) => Some(MessageId::Cancel),
+            Self::SuggestPiece { .. } => Some(MessageId::SuggestPiece),
+            Self::HaveAll => Some(MessageId::HaveAll),
+            Self::HaveNone => Some(MessageId::HaveNone),
+            Self::RejectRequest(_) => Some(MessageId::RejectRequest),
+            Self::AllowedFast { .. } => Some(MessageId::AllowedFast),
+            Self::Extended { .. } => Some(MessageId::Extended),
         }
     }
 
@@ -195,13 +263,59 @@ impl Message {
     /// message header. For all but the block message this is simply the size of
     /// the message. For the block message this is the message header.
     pub fn protocol_len(&self) -> u64 {
-        if let Some(id) = self.id() {
+        if let Self::Extended { .. } = self {
+            // all of the extended message is protocol chatter
+            self.encoded_len()
+        } else if let Some(id) = self.id() {
             id.header_len()
         } else {
             assert_eq!(*self, Self::KeepAlive);
             1
         }
     }
+
+    /// Returns the length of the whole message on the wire, including the
+    /// message length prefix and the payload.
+    pub fn encoded_len(&self) -> u64 {
+        match self {
+            Self::KeepAlive => 4,
+            Self::Bitfield(bitfield) => {
+                MessageId::Bitfield.header_len()
+                    + bitfield.as_slice().len() as u64
+            }
+            Self::Block { data, .. } => {
+                MessageId::Block.header_len() + data.len() as u64
+            }
+            Self::Extended { payload, .. } => {
+                MessageId::Extended.header_len() + payload.len() as u64
+            }
+            // all other messages have a fix size header and no payload
+            _ => self.protocol_len(),
+        }
+    }
+
+    /// Records this message in the given per message type statistics.
+    pub fn record(&self, stats: &mut MessageTypeStats) {
+        let count: &mut MessageCount = match self {
+            Self::KeepAlive => &mut stats.keep_alive,
+            Self::Bitfield(_) => &mut stats.bitfield,
+            Self::Choke => &mut stats.choke,
+            Self::Unchoke => &mut stats.unchoke,
+            Self::Interested => &mut stats.interested,
+            Self::NotInterested => &mut stats.not_interested,
+            Self::Have { .. } => &mut stats.have,
+            Self::Request(_) => &mut stats.request,
+            Self::Block { .. } => &mut stats.block,
+            Self::Cancel(_) => &mut stats.cancel,
+            Self::SuggestPiece { .. } => &mut stats.suggest_piece,
+            Self::HaveAll => &mut stats.have_all,
+            Self::HaveNone => &mut stats.have_none,
+            Self::RejectRequest(_) => &mut stats.reject_request,
+            Self::AllowedFast { .. } => &mut stats.allowed_fast,
+            Self::Extended { .. } => &mut stats.extended,
+        };
+        count.record(self.encoded_len());
+    }
 }
 
 /// The ID of a message, which is included as a prefix in most messages.
@@ -219,6 +333,12 @@ pub(crate) enum MessageId {
     Request = 6,
     Block = 7,
     Cancel = 8,
+    SuggestPiece = 0x0d,
+    HaveAll = 0x0e,
+    HaveNone = 0x0f,
+    RejectRequest = 0x10,
+    AllowedFast = 0x11,
+    Extended = 20,
 }
 
 impl MessageId {
@@ -237,12 +357,36 @@ impl MessageId {
             Self::Request => 4 + 1 + 3 * 4,
             Self::Block => 4 + 1 + 2 * 4,
             Self::Cancel => 4 + 1 + 3 * 4,
+            Self::SuggestPiece => 4 + 1 + 4,
+            Self::HaveAll => 4 + 1,
+            Self::HaveNone => 4 + 1,
+            Self::RejectRequest => 4 + 1 + 3 * 4,
+            Self::AllowedFast => 4 + 1 + 4,
+            Self::Extended => 4 + 1 + 1,
+        }
+    }
+
+    /// Returns whether the message length, as declared in the message's length
+    /// prefix, is valid for the message type.
+    ///
+    /// Messages with a fixed size must match their header length exactly,
+    /// while the bitfield, block, and extended messages must be at least as
+    /// long as their header (a block must also contain at least one byte of
+    /// data).
+    fn is_valid_len(&self, msg_len: usize) -> bool {
+        // the header length includes the 4 byte length prefix, which is not
+        // counted in the message length
+        let min_len = self.header_len() as usize - 4;
+        match self {
+            Self::Bitfield | Self::Extended => msg_len >= min_len,
+            Self::Block => msg_len > min_len,
+            _ => msg_len == min_len,
         }
     }
 }
 
 impl TryFrom<u8> for MessageId {
-    type Error = io::Error;
+    type Error = PeerError;
 
     fn try_from(k: u8) -> Result<Self, Self::Error> {
         use MessageId::*;
@@ -256,10 +400,13 @@ impl TryFrom<u8> for MessageId {
             k if k == Request as u8 => Ok(Request),
             k if k == Block as u8 => Ok(Block),
             k if k == Cancel as u8 => Ok(Cancel),
-            _ => Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Unknown message id",
-            )),
+            k if k == SuggestPiece as u8 => Ok(SuggestPiece),
+            k if k == HaveAll as u8 => Ok(HaveAll),
+            k if k == HaveNone as u8 => Ok(HaveNone),
+            k if k == RejectRequest as u8 => Ok(RejectRequest),
+            k if k == AllowedFast as u8 => Ok(AllowedFast),
+            k if k == Extended as u8 => Ok(Extended),
+            _ => Err(PeerError::UnknownMessageId(k)),
         }
     }
 }
@@ -344,17 +491,7 @@ impl Encoder<Message> for PeerCodec {
                 // no payload
             }
             Have { piece_index } => {
-                // message length prefix:
-                // 1 byte message id and 4 byte piece index
-                let msg_len = 1 + 4;
-                buf.put_u32(msg_len);
-                // message id
-                buf.put_u8(MessageId::Have as u8);
-                // payload
-                let piece_index = piece_index.try_into().map_err(|e| {
-                    io::Error::new(io::ErrorKind::InvalidInput, e)
-                })?;
-                buf.put_u32(piece_index);
+                encode_piece_index_msg(MessageId::Have, piece_index, buf)?;
             }
             Request(block) => {
                 // message length prefix:
@@ -398,17 +535,93 @@ impl Encoder<Message> for PeerCodec {
                 // payload
                 block.encode(buf)?;
             }
+            SuggestPiece { piece_index } => {
+                encode_piece_index_msg(
+                    MessageId::SuggestPiece,
+                    piece_index,
+                    buf,
+                )?;
+            }
+            HaveAll => {
+                // message length prefix: 1 byte message id
+                let msg_len = 1;
+                buf.put_u32(msg_len);
+                // message id
+                buf.put_u8(MessageId::HaveAll as u8);
+                // no payload
+            }
+            HaveNone => {
+                // message length prefix: 1 byte message id
+                let msg_len = 1;
+                buf.put_u32(msg_len);
+                // message id
+                buf.put_u8(MessageId::HaveNone as u8);
+                // no payload
+            }
+            RejectRequest(block) => {
+                // message length prefix:
+                // 1 byte message id, 4 byte piece index, 4 byte offset, 4 byte
+                // length
+                let msg_len = 1 + 4 + 4 + 4;
+                buf.put_u32(msg_len);
+                // message id
+                buf.put_u8(MessageId::RejectRequest as u8);
+                // payload
+                block.encode(buf)?;
+            }
+            AllowedFast { piece_index } => {
+                encode_piece_index_msg(
+                    MessageId::AllowedFast,
+                    piece_index,
+                    buf,
+                )?;
+            }
+            Extended { id, payload } => {
+                // message length prefix:
+                // 1 byte message id, 1 byte extended message id, and n byte
+                // payload
+                let msg_len = 1 + 1 + payload.len() as u32;
+                buf.put_u32(msg_len);
+                // message id
+                buf.put_u8(MessageId::Extended as u8);
+                // payload
+                buf.put_u8(id);
+                buf.extend_from_slice(&payload);
+            }
         }
 
         Ok(())
     }
 }
 
+/// Encodes a message whose only payload is a piece index (such as 'have').
+fn encode_piece_index_msg(
+    id: MessageId,
+    piece_index: usize,
+    buf: &mut BytesMut,
+) -> io::Result<()> {
+    // message length prefix:
+    // 1 byte message id and 4 byte piece index
+    let msg_len = 1 + 4;
+    buf.put_u32(msg_len);
+    // message id
+    buf.put_u8(id as u8);
+    // payload
+    let piece_index = piece_index
+        .try_into()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    buf.put_u32(piece_index);
+    Ok(())
+}
+
 impl Decoder for PeerCodec {
     type Item = Message;
-    type Error = io::Error;
+    type Error = PeerError;
 
-    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+    fn decode(
+        &mut self,
+        buf: &mut BytesMut,
+    ) -> Result<Option<Self::Item>, PeerError> {
         log::trace!("Decoder has {} byte(s) remaining", buf.remaining());
 
         // the message length header must be present at the minimum, otherwise
@@ -431,6 +644,11 @@ impl Decoder for PeerCodec {
         let mut tmp_buf = buf.bytes();
         let msg_len = tmp_buf.get_u32() as usize;
 
+        // reject overly long messages before buffering them
+        if msg_len > MAX_MSG_LEN {
+            return Err(PeerError::MessageTooLong(msg_len));
+        }
+
         // check that we got the full payload in the buffer (NOTE: we need to
         // add the message length prefix's byte count to msg_len since the
         // buffer cursor was not advanced and thus we need to consider the
@@ -453,7 +671,13 @@ impl Decoder for PeerCodec {
             return Ok(None);
         }
 
-        let msg_id = MessageId::try_from(buf.get_u8())?;
+        let id = buf.get_u8();
+        let msg_id = MessageId::try_from(id)?;
+        // the peer may send us a message whose length prefix doesn't match its
+        // type, in which case we can't safely parse it
+        if !msg_id.is_valid_len(msg_len) {
+            return Err(PeerError::InvalidMessageLength { id, len: msg_len });
+        }
         let msg = match msg_id {
             MessageId::Choke => Message::Choke,
             MessageId::Unchoke => Message::Unchoke,
@@ -488,9 +712,6 @@ impl Decoder for PeerCodec {
                 })
             }
             MessageId::Block => {
-                // TODO: this shouldn't be a debug asset as we're dealing with
-                // unknown input
-                debug_assert!(msg_len > 9);
                 let piece_index = buf.get_u32();
                 let piece_index = piece_index.try_into().map_err(|e| {
                     io::Error::new(io::ErrorKind::InvalidInput, e)
@@ -524,6 +745,42 @@ impl Decoder for PeerCodec {
                     len,
                 })
             }
+            MessageId::SuggestPiece => {
+                let piece_index = buf.get_u32();
+                let piece_index = piece_index.try_into().map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidInput, e)
+                })?;
+                Message::SuggestPiece { piece_index }
+            }
+            MessageId::HaveAll => Message::HaveAll,
+            MessageId::HaveNone => Message::HaveNone,
+            MessageId::RejectRequest => {
+                let piece_index = buf.get_u32();
+                let piece_index = piece_index.try_into().map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidInput, e)
+                })?;
+                let offset = buf.get_u32();
+                let len = buf.get_u32();
+                Message::RejectRequest(BlockInfo {
+                    piece_index,
+                    offset,
+                    len,
+                })
+            }
+            MessageId::AllowedFast => {
+                let piece_index = buf.get_u32();
+                let piece_index = piece_index.try_into().map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidInput, e)
+                })?;
+                Message::AllowedFast { piece_index }
+            }
+            MessageId::Extended => {
+                let id = buf.get_u8();
+                // the payload is what remains after the id and extended id
+                let mut payload = vec![0; msg_len - 2];
+                buf.copy_to_slice(&mut payload);
+                Message::Extended { id, payload }
+            }
         };
 
         Ok(Some(msg))
@@ -688,7 +945,7 @@ mod tests {
             buf
         };
         let result = HandshakeCodec.decode(&mut invalid_encoded);
-        assert!(result.is_err());
+        assert!(matches!(result, Err(PeerError::InvalidHandshake)));
     }
 
     // Returns a `Handshake` and its expected encoded variant.
@@ -809,6 +1066,158 @@ mod tests {
         assert_message_codec(msg, expected_encoded);
     }
 
+    /// Tests the encoding and subsequent decoding of the Fast extension
+    /// messages.
+    #[test]
+    fn test_fast_extension_codec() {
+        let msgs = [
+            make_suggest_piece(),
+            make_have_all(),
+            make_have_none(),
+            make_reject_request(),
+            make_allowed_fast(),
+        ];
+        for (msg, expected_encoded) in msgs.iter().cloned() {
+            assert_message_codec(msg, expected_encoded);
+        }
+    }
+
+    /// Tests that our handshake advertises the Fast extension and that it is
+    /// detected in a peer's handshake.
+    #[test]
+    fn test_handshake_fast_extension() {
+        let handshake = Handshake::new([0; 20], [0; 20]);
+        assert!(handshake.supports_fast());
+        let (handshake, _) = make_handshake();
+        assert!(!handshake.supports_fast());
+    }
+
+    /// Tests that our handshake advertises the extension protocol and that it
+    /// is detected in a peer's handshake.
+    #[test]
+    fn test_handshake_extension_protocol() {
+        let handshake = Handshake::new([0; 20], [0; 20]);
+        assert!(handshake.supports_extensions());
+        let (handshake, _) = make_handshake();
+        assert!(!handshake.supports_extensions());
+    }
+
+    /// Tests the encoding and subsequent decoding of a valid extended message.
+    #[test]
+    fn test_extended_codec() {
+        let (msg, expected_encoded) = make_extended();
+        assert_message_codec(msg, expected_encoded);
+    }
+
+    /// Tests that the reported encoded length of messages is the same as the
+    /// number of bytes that is actually encoded.
+    #[test]
+    fn test_message_encoded_len() {
+        let msgs = [
+            make_keep_alive(),
+            make_choke(),
+            make_unchoke(),
+            make_interested(),
+            make_not_interested(),
+            make_bitfield(),
+            make_have(),
+            make_request(),
+            make_block(),
+            make_cancel(),
+            make_suggest_piece(),
+            make_have_all(),
+            make_have_none(),
+            make_reject_request(),
+            make_allowed_fast(),
+            make_extended(),
+        ];
+        for (msg, encoded) in &msgs {
+            assert_eq!(msg.encoded_len(), encoded.len() as u64);
+        }
+    }
+
+    /// Tests that recording messages updates only the count of the message's
+    /// type.
+    #[test]
+    fn test_message_record() {
+        let mut stats = MessageTypeStats::default();
+        let (have, _) = make_have();
+        let (block, _) = make_block();
+
+        have.record(&mut stats);
+        have.record(&mut stats);
+        block.record(&mut stats);
+
+        assert_eq!(stats.have.count, 2);
+        assert_eq!(stats.have.bytes, 2 * have.encoded_len());
+        assert_eq!(stats.block.count, 1);
+        assert_eq!(stats.block.bytes, block.encoded_len());
+        assert_eq!(stats.request, MessageCount::default());
+        assert_eq!(stats.total().count, 3);
+    }
+
+    /// Tests that decoding a message with an unknown id results in an error
+    /// that includes the id.
+    #[test]
+    fn test_unknown_message_id_decoding() {
+        let mut encoded = BytesMut::new();
+        encoded.put_u32(1);
+        encoded.put_u8(42);
+        let result = PeerCodec.decode(&mut encoded);
+        assert!(matches!(result, Err(PeerError::UnknownMessageId(42))));
+    }
+
+    /// Tests that a message exceeding the maximum message length is rejected
+    /// as soon as its length prefix is received.
+    #[test]
+    fn test_too_long_message_decoding() {
+        let mut encoded = BytesMut::new();
+        encoded.put_u32(MAX_MSG_LEN as u32 + 1);
+        encoded.put_u8(MessageId::Block as u8);
+        let result = PeerCodec.decode(&mut encoded);
+        assert!(matches!(result, Err(PeerError::MessageTooLong(_))));
+    }
+
+    /// Tests that decoding messages whose length prefix doesn't match their
+    /// type results in an error, rather than reading past the message.
+    #[test]
+    fn test_invalid_message_length_decoding() {
+        // a 'have' message must contain the 4 byte piece index
+        let mut encoded = BytesMut::new();
+        encoded.put_u32(1 + 2);
+        encoded.put_u8(MessageId::Have as u8);
+        encoded.put_u16(0);
+        let result = PeerCodec.decode(&mut encoded);
+        assert!(matches!(
+            result,
+            Err(PeerError::InvalidMessageLength { id, len: 3 })
+                if id == MessageId::Have as u8
+        ));
+
+        // a 'choke' message has no payload
+        let mut encoded = BytesMut::new();
+        encoded.put_u32(1 + 4);
+        encoded.put_u8(MessageId::Choke as u8);
+        encoded.put_u32(0);
+        let result = PeerCodec.decode(&mut encoded);
+        assert!(matches!(
+            result,
+            Err(PeerError::InvalidMessageLength { len: 5, .. })
+        ));
+
+        // a 'block' message must contain at least one byte of data
+        let mut encoded = BytesMut::new();
+        encoded.put_u32(1 + 4 + 4);
+        encoded.put_u8(MessageId::Block as u8);
+        encoded.put_u32(0);
+        encoded.put_u32(0);
+        let result = PeerCodec.decode(&mut encoded);
+        assert!(matches!(
+            result,
+            Err(PeerError::InvalidMessageLength { len: 9, .. })
+        ));
+    }
+
     /// Helper function that asserts that a message is encoded and subsequently
     /// decoded correctly.
     fn assert_message_codec(msg: Message, expected_encoded: Bytes) {
@@ -902,21 +1311,106 @@ mod tests {
     fn make_have() -> (Message, Bytes) {
         let piece_index = 42;
         let msg = Message::Have { piece_index };
+        let encoded =
+            make_piece_index_encoded_msg_payload(MessageId::Have, piece_index);
+        (msg, encoded)
+    }
+
+    /// Returns `SuggestPiece` and its expected encoded variant.
+    fn make_suggest_piece() -> (Message, Bytes) {
+        let piece_index = 42;
+        let msg = Message::SuggestPiece { piece_index };
+        let encoded = make_piece_index_encoded_msg_payload(
+            MessageId::SuggestPiece,
+            piece_index,
+        );
+        (msg, encoded)
+    }
+
+    /// Returns `AllowedFast` and its expected encoded variant.
+    fn make_allowed_fast() -> (Message, Bytes) {
+        let piece_index = 42;
+        let msg = Message::AllowedFast { piece_index };
+        let encoded = make_piece_index_encoded_msg_payload(
+            MessageId::AllowedFast,
+            piece_index,
+        );
+        (msg, encoded)
+    }
+
+    /// Returns `Extended` and its expected encoded variant.
+    fn make_extended() -> (Message, Bytes) {
+        let payload = b"d1:md6:ut_pexi1eee".to_vec();
         let encoded = {
-            // 1 byte message id and 4 byte piece index
-            let msg_len = 1 + 4;
+            // 1 byte message id, 1 byte extended message id, and n byte
+            // payload
+            let msg_len = 1 + 1 + payload.len();
             // 4 byte message length prefix and message length
             let buf_len = 4 + msg_len;
             let mut buf = BytesMut::with_capacity(buf_len);
             buf.put_u32(msg_len as u32);
-            buf.put_u8(MessageId::Have as u8);
-            // ok to unwrap, only used in tests
-            buf.put_u32(piece_index.try_into().unwrap());
+            buf.put_u8(MessageId::Extended as u8);
+            buf.put_u8(0);
+            buf.extend_from_slice(&payload);
             buf
         };
+        let msg = Message::Extended { id: 0, payload };
         (msg, encoded.into())
     }
 
+    /// Helper used to create 'have', 'suggest piece', and 'allowed fast'
+    /// encoded messages that all have the same format.
+    fn make_piece_index_encoded_msg_payload(
+        id: MessageId,
+        piece_index: usize,
+    ) -> Bytes {
+        // 1 byte message id and 4 byte piece index
+        let msg_len = 1 + 4;
+        // 4 byte message length prefix and message length
+        let buf_len = 4 + msg_len;
+        let mut buf = BytesMut::with_capacity(buf_len);
+        buf.put_u32(msg_len as u32);
+        buf.put_u8(id as u8);
+        // ok to unwrap, only used in tests
+        buf.put_u32(piece_index.try_into().unwrap());
+        buf.into()
+    }
+
+    /// Returns `HaveAll` and its expected encoded variant.
+    fn make_have_all() -> (Message, Bytes) {
+        (
+            Message::HaveAll,
+            make_empty_msg_encoded_payload(MessageId::HaveAll),
+        )
+    }
+
+    /// Returns `HaveNone` and its expected encoded variant.
+    fn make_have_none() -> (Message, Bytes) {
+        (
+            Message::HaveNone,
+            make_empty_msg_encoded_payload(MessageId::HaveNone),
+        )
+    }
+
+    /// Returns `RejectRequest` and its expected encoded variant.
+    fn make_reject_request() -> (Message, Bytes) {
+        let piece_index = 42;
+        let offset = 0x4000;
+        let len = BLOCK_LEN;
+        let msg = Message::RejectRequest(BlockInfo {
+            piece_index,
+            offset,
+            len,
+        });
+        let encoded = make_block_info_encoded_msg_payload(
+            MessageId::RejectRequest,
+            piece_index,
+            offset,
+            len,
+        );
+        (msg, encoded)
+    }
+
     /// Returns `Request` and its expected encoded variant.
     fn make_request() -> (Message, Bytes) {
         let piece_index = 42;
@@ -8,12 +8,20 @@ pub(crate) type Result<T, E = PeerError> = std::result::Result<T, E>;
 ///
 /// This error is non-fatal so it should not be grouped with the global `Error`
 /// type as it may be recovered from.
+///
+/// The errors can broadly be categorized into network or local issues (such as
+/// IO errors or timeouts) and protocol violations by the peer (such as sending
+/// malformed messages). To tell these apart, see
+/// [`PeerError::is_protocol_violation`].
 #[derive(Debug)]
 #[non_exhaustive]
 pub enum PeerError {
     /// The bitfield message was not sent after the handshake. According to the
     /// protocol, it should only be accepted after the handshake and when
     /// received at any other time, connection is severed.
+    ///
+    /// The same applies to the Fast extension's 'have all' and 'have none'
+    /// messages.
     BitfieldNotAfterHandshake,
     /// The channel on which some component in engine was listening or sending
     /// died.
@@ -24,16 +32,67 @@ pub enum PeerError {
     /// A peer session timed out because neither side of the connection became
     /// interested in each other.
     InactivityTimeout,
+    /// The peer did not send its handshake in time.
+    HandshakeTimeout,
+    /// The peer's handshake did not contain the BitTorrent protocol string.
+    InvalidHandshake,
     /// The block information the peer sent is invalid.
     InvalidBlockInfo,
     /// The block's piece index is invalid.
     InvalidPieceIndex,
     /// Peer's torrent info hash did not match ours.
     InvalidInfoHash,
+    /// The length of a message did not match the length expected for its
+    /// type.
+    InvalidMessageLength {
+        /// The message's id.
+        id: u8,
+        /// The length of the message as sent by peer.
+        len: usize,
+    },
+    /// The peer sent a message longer than the maximum we accept.
+    MessageTooLong(usize),
+    /// The peer sent a message with an id we don't recognize.
+    UnknownMessageId(u8),
+    /// The peer requested a block of a piece we don't have.
+    RequestForMissingPiece,
+    /// The peer sent us more messages than we are willing to process.
+    Flooding,
+    /// The peer doesn't support the metadata exchange extension, or doesn't
+    /// have the torrent's metadata.
+    MetadataUnavailable,
+    /// The metadata the peer sent is invalid, or doesn't match the torrent's
+    /// info hash.
+    InvalidMetadata,
     /// An IO error ocurred.
     Io(std::io::Error),
 }
 
+impl PeerError {
+    /// Returns true if the error is the result of the peer violating the
+    /// protocol, i.e. if the peer is misbehaving, as opposed to network or
+    /// local issues.
+    pub fn is_protocol_violation(&self) -> bool {
+        use PeerError::*;
+        match self {
+            BitfieldNotAfterHandshake
+            | RequestWhileChoked
+            | InvalidHandshake
+            | InvalidBlockInfo
+            | InvalidPieceIndex
+            | InvalidInfoHash
+            | InvalidMessageLength { .. }
+            | MessageTooLong(_)
+            | UnknownMessageId(_)
+            | RequestForMissingPiece
+            | Flooding
+            | InvalidMetadata => true,
+            Channel | InactivityTimeout | HandshakeTimeout
+            | MetadataUnavailable | Io(_) => false,
+        }
+    }
+}
+
 impl fmt::Display for PeerError {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         use PeerError::*;
@@ -46,14 +105,37 @@ impl fmt::Display for PeerError {
                 write!(fmt, "choked peer sent request")
             }
             InactivityTimeout => write!(fmt, "inactivity timeout"),
+            HandshakeTimeout => write!(fmt, "handshake timeout"),
+            InvalidHandshake => write!(fmt, "invalid handshake"),
             InvalidBlockInfo => write!(fmt, "invalid block info"),
             InvalidPieceIndex => write!(fmt, "invalid piece index"),
             InvalidInfoHash => write!(fmt, "invalid info hash"),
+            InvalidMessageLength { id, len } => {
+                write!(fmt, "invalid message {} length {}", id, len)
+            }
+            MessageTooLong(len) => write!(fmt, "message too long: {}", len),
+            UnknownMessageId(id) => write!(fmt, "unknown message id {}", id),
+            RequestForMissingPiece => {
+                write!(fmt, "peer requested missing piece")
+            }
+            Flooding => write!(fmt, "peer is flooding"),
+            MetadataUnavailable => write!(fmt, "peer has no metadata"),
+            InvalidMetadata => write!(fmt, "invalid metadata"),
             Io(e) => write!(fmt, "{}", e),
         }
     }
 }
 
+#[cfg(feature = "serde")]
+impl serde::Serialize for PeerError {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        crate::error::serialize_message(self, serializer)
+    }
+}
+
 impl From<IoError> for PeerError {
     fn from(e: IoError) -> Self {
         // the pieces field is a concatenation of 20 byte SHA-1 hashes, so it
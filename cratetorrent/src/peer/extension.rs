@@ -0,0 +1,263 @@
+//! This module implements parts of the extension protocol
+//! ([BEP 10](http://bittorrent.org/beps/bep_0010.html)) that are independent
+//! of the peer session.
+//!
+//! Besides the extended handshake, from which we learn our external IP
+//! address, the metadata exchange extension
+//! ([BEP 9](http://bittorrent.org/beps/bep_0009.html)) is supported, with
+//! which the info dictionary of torrents added from magnet links is
+//! downloaded.
+
+use std::{collections::BTreeMap, net::IpAddr};
+
+use serde_bytes::ByteBuf;
+
+use crate::{external_ip::parse_ip, metainfo::bencode_len};
+
+/// The extended message id of the extended handshake.
+pub(crate) const HANDSHAKE_ID: u8 = 0;
+
+/// The name of the metadata exchange extension in the extended handshake.
+pub(crate) const UT_METADATA: &str = "ut_metadata";
+/// The extended message id with which peers must send us metadata messages.
+pub(crate) const UT_METADATA_ID: u8 = 1;
+/// The metadata is exchanged in pieces of this length, except for the last
+/// piece, which may be shorter.
+pub(crate) const METADATA_PIECE_LEN: usize = 16 * 1024;
+
+/// The client name and version we advertise to peers.
+const CLIENT_VERSION: &str =
+    concat!("cratetorrent ", env!("CARGO_PKG_VERSION"));
+
+/// The extended handshake, sent by both sides of the connection after the
+/// BitTorrent handshake if both advertise support for the extension protocol.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "serde_crate")]
+pub(crate) struct ExtendedHandshake {
+    /// Maps the names of the extensions the sender supports to the extended
+    /// message ids with which they must be sent to the sender.
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+    /// The sender's client name and version.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    /// The IP address of the receiver, as seen by the sender, in its 4 or 16
+    /// byte network order representation.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub yourip: Option<ByteBuf>,
+    /// The length of the torrent's info dictionary, if the sender has it and
+    /// supports the metadata exchange extension.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtendedHandshake {
+    /// Creates our extended handshake for the peer at the given address,
+    /// with the length of the torrent's info dictionary, if we have it.
+    pub fn new(peer_ip: IpAddr, metadata_size: Option<usize>) -> Self {
+        let yourip = match peer_ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        let mut m = BTreeMap::new();
+        m.insert(UT_METADATA.into(), UT_METADATA_ID);
+        Self {
+            m,
+            v: Some(CLIENT_VERSION.into()),
+            yourip: Some(ByteBuf::from(yourip)),
+            metadata_size,
+        }
+    }
+
+    /// Encodes the handshake as the payload of an extended message.
+    pub fn encode(&self) -> Vec<u8> {
+        serde_bencode::to_bytes(self)
+            .expect("extended handshake should serialize")
+    }
+
+    /// Decodes the handshake from the payload of an extended message.
+    pub fn decode(payload: &[u8]) -> Result<Self, serde_bencode::Error> {
+        serde_bencode::from_bytes(payload)
+    }
+
+    /// Returns our IP address as seen by the sender, if included and valid.
+    pub fn your_ip(&self) -> Option<IpAddr> {
+        self.yourip.as_ref().and_then(|ip| parse_ip(ip))
+    }
+
+    /// Returns the extended message id with which metadata messages must be
+    /// sent to the sender, if it supports the metadata exchange extension.
+    pub fn ut_metadata_id(&self) -> Option<u8> {
+        // an id of 0 means that the extension was disabled
+        self.m
+            .get(UT_METADATA)
+            .copied()
+            .filter(|id| *id != HANDSHAKE_ID)
+    }
+}
+
+/// A message of the metadata exchange extension.
+#[derive(Debug, PartialEq)]
+pub(crate) enum MetadataMsg {
+    /// Requests a piece of the metadata.
+    Request { piece: usize },
+    /// A piece of the metadata, along with the length of the whole metadata.
+    Data {
+        piece: usize,
+        total_size: usize,
+        data: Vec<u8>,
+    },
+    /// The sender doesn't have the requested piece.
+    Reject { piece: usize },
+}
+
+/// The bencoded dictionary at the start of each metadata message. In data
+/// messages, it's followed by the piece's data.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "serde_crate")]
+struct MetadataMsgHeader {
+    msg_type: u8,
+    piece: usize,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+const METADATA_REQUEST: u8 = 0;
+const METADATA_DATA: u8 = 1;
+const METADATA_REJECT: u8 = 2;
+
+impl MetadataMsg {
+    /// Encodes the message as the payload of an extended message.
+    pub fn encode(&self) -> Vec<u8> {
+        let (header, data) = match self {
+            Self::Request { piece } => (
+                MetadataMsgHeader {
+                    msg_type: METADATA_REQUEST,
+                    piece: *piece,
+                    total_size: None,
+                },
+                None,
+            ),
+            Self::Data {
+                piece,
+                total_size,
+                data,
+            } => (
+                MetadataMsgHeader {
+                    msg_type: METADATA_DATA,
+                    piece: *piece,
+                    total_size: Some(*total_size),
+                },
+                Some(data),
+            ),
+            Self::Reject { piece } => (
+                MetadataMsgHeader {
+                    msg_type: METADATA_REJECT,
+                    piece: *piece,
+                    total_size: None,
+                },
+                None,
+            ),
+        };
+        let mut payload = serde_bencode::to_bytes(&header)
+            .expect("metadata message should serialize");
+        if let Some(data) = data {
+            payload.extend_from_slice(data);
+        }
+        payload
+    }
+
+    /// Decodes the message from the payload of an extended message, returning
+    /// `None` if it's invalid or of an unknown type.
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        // the header's length is only known by scanning it, as the data that
+        // follows it is not bencoded
+        let header_len = bencode_len(payload)?;
+        let header: MetadataMsgHeader =
+            serde_bencode::from_bytes(&payload[..header_len]).ok()?;
+        match header.msg_type {
+            METADATA_REQUEST => Some(Self::Request {
+                piece: header.piece,
+            }),
+            METADATA_DATA => Some(Self::Data {
+                piece: header.piece,
+                total_size: header.total_size?,
+                data: payload[header_len..].to_vec(),
+            }),
+            METADATA_REJECT => Some(Self::Reject {
+                piece: header.piece,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the number of pieces in which metadata of the given length is
+/// exchanged.
+pub(crate) fn metadata_piece_count(metadata_len: usize) -> usize {
+    (metadata_len + METADATA_PIECE_LEN - 1) / METADATA_PIECE_LEN
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn should_encode_and_decode_handshake() {
+        let ip = Ipv4Addr::new(1, 2, 3, 4).into();
+        let handshake = ExtendedHandshake::new(ip, Some(1234));
+        let decoded = ExtendedHandshake::decode(&handshake.encode()).unwrap();
+        assert_eq!(decoded, handshake);
+        assert_eq!(decoded.your_ip(), Some(ip));
+        assert_eq!(decoded.ut_metadata_id(), Some(UT_METADATA_ID));
+        assert_eq!(decoded.metadata_size, Some(1234));
+    }
+
+    #[test]
+    fn should_decode_handshake_with_unknown_fields() {
+        let payload = b"d1:md6:ut_pexi1ee1:pi6881e4:reqqi500e\
+            6:yourip4:\x01\x02\x03\x04e";
+        let handshake = ExtendedHandshake::decode(payload).unwrap();
+        assert_eq!(handshake.m.get("ut_pex"), Some(&1));
+        assert_eq!(handshake.v, None);
+        assert_eq!(handshake.your_ip(), Some(Ipv4Addr::new(1, 2, 3, 4).into()));
+        assert_eq!(handshake.ut_metadata_id(), None);
+    }
+
+    #[test]
+    fn should_encode_and_decode_metadata_msgs() {
+        let msgs = vec![
+            MetadataMsg::Request { piece: 1 },
+            MetadataMsg::Data {
+                piece: 2,
+                total_size: 40000,
+                data: b"d4:spami42ee".to_vec(),
+            },
+            MetadataMsg::Reject { piece: 3 },
+        ];
+        for msg in msgs {
+            assert_eq!(MetadataMsg::decode(&msg.encode()), Some(msg));
+        }
+    }
+
+    #[test]
+    fn should_decode_metadata_msg_with_unknown_fields() {
+        let payload = b"d8:msg_typei1e5:piecei0e10:total_sizei3e1:xi0eeabc";
+        assert_eq!(
+            MetadataMsg::decode(payload),
+            Some(MetadataMsg::Data {
+                piece: 0,
+                total_size: 3,
+                data: b"abc".to_vec(),
+            })
+        );
+        assert_eq!(MetadataMsg::decode(b"d8:msg_typei9e5:piecei0ee"), None);
+        assert_eq!(MetadataMsg::decode(b"d8:msg_typei1e5:piecei0ee"), None);
+    }
+}
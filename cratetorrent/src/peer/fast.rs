@@ -0,0 +1,89 @@
+//! This module implements parts of the Fast extension
+//! ([BEP 6](http://bittorrent.org/beps/bep_0006.html)) that are independent of
+//! the peer session.
+
+use std::{collections::HashSet, net::Ipv4Addr};
+
+use sha1::{Digest, Sha1};
+
+use crate::{PieceIndex, Sha1Hash};
+
+/// Computes the allowed fast set of at most `k` pieces for the peer at the
+/// given IP address.
+///
+/// These are the pieces that the peer may download from us even while it is
+/// choked. The set is generated canonically, as described in BEP 6, so that
+/// a peer gets the same set from all peers and it can't obtain a different
+/// set by reconnecting.
+///
+/// The set is only defined for IPv4 addresses.
+pub(super) fn allowed_fast_set(
+    ip: Ipv4Addr,
+    info_hash: &Sha1Hash,
+    piece_count: usize,
+    k: usize,
+) -> HashSet<PieceIndex> {
+    // we can't allow more pieces than there are in the torrent
+    let k = k.min(piece_count);
+    let mut set = HashSet::with_capacity(k);
+
+    // the last byte of the IP is masked out so that all peers in the same /24
+    // subnet receive the same set
+    let ip = u32::from(ip) & 0xffff_ff00;
+    let mut x = Vec::with_capacity(4 + info_hash.len());
+    x.extend_from_slice(&ip.to_be_bytes());
+    x.extend_from_slice(info_hash);
+
+    while set.len() < k {
+        x = Sha1::digest(&x).to_vec();
+        // each hash yields 5 piece indices
+        for chunk in x.chunks_exact(4) {
+            if set.len() >= k {
+                break;
+            }
+            let mut y = [0; 4];
+            y.copy_from_slice(chunk);
+            let index = u32::from_be_bytes(y) as usize % piece_count;
+            set.insert(index);
+        }
+    }
+
+    set
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests the allowed fast set generation using the example from BEP 6.
+    #[test]
+    fn test_allowed_fast_set() {
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        let info_hash = [0xaa; 20];
+        let piece_count = 1313;
+
+        let set = allowed_fast_set(ip, &info_hash, piece_count, 7);
+        let expected: HashSet<_> = [1059, 431, 808, 1217, 287, 376, 1188]
+            .iter()
+            .copied()
+            .collect();
+        assert_eq!(set, expected);
+
+        let set = allowed_fast_set(ip, &info_hash, piece_count, 9);
+        let expected: HashSet<_> =
+            [1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+                .iter()
+                .copied()
+                .collect();
+        assert_eq!(set, expected);
+    }
+
+    /// Tests that the set is capped at the number of pieces in the torrent.
+    #[test]
+    fn test_allowed_fast_set_small_torrent() {
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        let set = allowed_fast_set(ip, &[0xaa; 20], 3, 10);
+        let expected: HashSet<_> = [0, 1, 2].iter().copied().collect();
+        assert_eq!(set, expected);
+    }
+}
@@ -0,0 +1,216 @@
+//! Downloads a torrent's metadata, i.e. its info dictionary, from a peer with
+//! the metadata exchange extension
+//! ([BEP 9](http://bittorrent.org/beps/bep_0009.html)).
+//!
+//! This is a minimal session for torrents added from magnet links, which
+//! can't run a regular [`PeerSession`](super::PeerSession) as long as they
+//! don't know their pieces. After the handshakes all pieces of the metadata
+//! are requested, and all other messages are ignored. Once the metadata is
+//! downloaded and verified against the info hash, the connection is closed.
+
+use std::{io, net::SocketAddr};
+
+use futures::{SinkExt, StreamExt};
+use sha1::{Digest, Sha1};
+use tokio::net::TcpStream;
+use tokio_util::codec::{Framed, FramedParts};
+
+use super::{
+    codec::*,
+    error::*,
+    extension::{self, ExtendedHandshake, MetadataMsg, METADATA_PIECE_LEN},
+    HANDSHAKE_TIMEOUT, INACTIVITY_TIMEOUT,
+};
+use crate::{
+    conf::ProxyConf, local_addr::LocalAddr, proxy, rt, PeerId, Sha1Hash,
+};
+
+/// The longest metadata we accept, as otherwise a peer could make us buffer
+/// arbitrarily much of it.
+pub(crate) const MAX_METADATA_LEN: usize = 16 * 1024 * 1024;
+
+/// Connects to the peer and downloads the metadata of the torrent with the
+/// info hash from it.
+///
+/// The download is aborted if it takes longer than the inactivity timeout,
+/// e.g. because the peer stopped responding.
+pub(crate) async fn download(
+    addr: SocketAddr,
+    info_hash: Sha1Hash,
+    client_id: PeerId,
+    proxy: Option<&ProxyConf>,
+    local_addr: &LocalAddr,
+) -> Result<Vec<u8>> {
+    log::debug!("Downloading metadata from peer {}", addr);
+    let socket = match proxy {
+        Some(proxy) => proxy::connect(proxy, local_addr, addr).await?,
+        None => local_addr.connect(addr).await?,
+    };
+    let socket = handshake(socket, info_hash, client_id).await?;
+    let metadata = rt::timeout(
+        INACTIVITY_TIMEOUT,
+        download_metadata(socket, addr, info_hash),
+    )
+    .await
+    .map_err(|_| PeerError::InactivityTimeout)??;
+    log::info!(
+        "Downloaded {} bytes of metadata from peer {}",
+        metadata.len(),
+        addr
+    );
+    Ok(metadata)
+}
+
+/// Exchanges the BitTorrent handshakes with the peer, returning the socket
+/// with which peer messages can be exchanged.
+async fn handshake(
+    socket: TcpStream,
+    info_hash: Sha1Hash,
+    client_id: PeerId,
+) -> Result<Framed<TcpStream, PeerCodec>> {
+    let mut socket = Framed::new(socket, HandshakeCodec);
+    socket.send(Handshake::new(info_hash, client_id)).await?;
+    let peer_handshake = rt::timeout(HANDSHAKE_TIMEOUT, socket.next())
+        .await
+        .map_err(|_| PeerError::HandshakeTimeout)?
+        .ok_or_else(|| {
+            PeerError::Io(io::Error::from(io::ErrorKind::UnexpectedEof))
+        })??;
+    if peer_handshake.info_hash != info_hash {
+        return Err(PeerError::InvalidInfoHash);
+    }
+    if !peer_handshake.supports_extensions() {
+        return Err(PeerError::MetadataUnavailable);
+    }
+
+    // the peer may have sent messages right after its handshake, so the
+    // buffers of the handshake codec are kept
+    let old_parts = socket.into_parts();
+    let mut new_parts = FramedParts::new(old_parts.io, PeerCodec);
+    new_parts.read_buf = old_parts.read_buf;
+    new_parts.write_buf = old_parts.write_buf;
+    Ok(Framed::from_parts(new_parts))
+}
+
+/// Requests all pieces of the metadata once the peer told us its length in
+/// its extended handshake, and collects them until the metadata is complete.
+async fn download_metadata(
+    mut socket: Framed<TcpStream, PeerCodec>,
+    addr: SocketAddr,
+    info_hash: Sha1Hash,
+) -> Result<Vec<u8>> {
+    let handshake = ExtendedHandshake::new(addr.ip(), None);
+    socket
+        .send(Message::Extended {
+            id: extension::HANDSHAKE_ID,
+            payload: handshake.encode(),
+        })
+        .await?;
+
+    // the peer's metadata message id, and the metadata with the pieces
+    // received so far, are known once the peer sent its extended handshake
+    let mut peer_id = None;
+    let mut metadata = Vec::new();
+    let mut received = Vec::new();
+    let mut missing_count = 0;
+    while let Some(msg) = socket.next().await {
+        let payload = match msg? {
+            Message::Extended { id, payload }
+                if id == extension::HANDSHAKE_ID && peer_id.is_none() =>
+            {
+                let handshake = ExtendedHandshake::decode(&payload)
+                    .map_err(|_| PeerError::InvalidHandshake)?;
+                let id = handshake
+                    .ut_metadata_id()
+                    .ok_or(PeerError::MetadataUnavailable)?;
+                let len = handshake
+                    .metadata_size
+                    .ok_or(PeerError::MetadataUnavailable)?;
+                if len == 0 || len > MAX_METADATA_LEN {
+                    return Err(PeerError::InvalidMetadata);
+                }
+                log::debug!("Peer {} has {} bytes of metadata", addr, len);
+                metadata = vec![0; len];
+                missing_count = extension::metadata_piece_count(len);
+                received = vec![false; missing_count];
+                for piece in 0..missing_count {
+                    let msg = MetadataMsg::Request { piece };
+                    socket
+                        .feed(Message::Extended {
+                            id,
+                            payload: msg.encode(),
+                        })
+                        .await?;
+                }
+                socket.flush().await?;
+                peer_id = Some(id);
+                continue;
+            }
+            Message::Extended { id, payload }
+                if id == extension::UT_METADATA_ID =>
+            {
+                payload
+            }
+            // piece availability and all other messages are irrelevant, as
+            // we don't download pieces
+            _ => continue,
+        };
+        let peer_id = match peer_id {
+            Some(peer_id) => peer_id,
+            // we can't reply to the peer until its extended handshake tells
+            // us its message id, nor have we requested anything yet
+            None => continue,
+        };
+
+        match MetadataMsg::decode(&payload) {
+            Some(MetadataMsg::Data {
+                piece,
+                total_size,
+                data,
+            }) => {
+                if total_size != metadata.len() || piece >= received.len() {
+                    return Err(PeerError::InvalidMetadata);
+                }
+                let start = piece * METADATA_PIECE_LEN;
+                let end = metadata.len().min(start + METADATA_PIECE_LEN);
+                if data.len() != end - start {
+                    return Err(PeerError::InvalidMetadata);
+                }
+                if received[piece] {
+                    continue;
+                }
+                metadata[start..end].copy_from_slice(&data);
+                received[piece] = true;
+                missing_count -= 1;
+                if missing_count == 0 {
+                    if Sha1::digest(&metadata)[..] != info_hash[..] {
+                        log::info!("Peer {} sent invalid metadata", addr);
+                        return Err(PeerError::InvalidMetadata);
+                    }
+                    return Ok(metadata);
+                }
+            }
+            Some(MetadataMsg::Reject { piece }) => {
+                log::debug!(
+                    "Peer {} rejected metadata piece {} request",
+                    addr,
+                    piece
+                );
+                return Err(PeerError::MetadataUnavailable);
+            }
+            Some(MetadataMsg::Request { piece }) => {
+                // we don't have the metadata either
+                let msg = MetadataMsg::Reject { piece };
+                socket
+                    .send(Message::Extended {
+                        id: peer_id,
+                        payload: msg.encode(),
+                    })
+                    .await?;
+            }
+            None => return Err(PeerError::InvalidMetadata),
+        }
+    }
+
+    Err(PeerError::Io(io::Error::from(io::ErrorKind::UnexpectedEof)))
+}
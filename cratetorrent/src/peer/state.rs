@@ -1,9 +1,19 @@
 use std::time::{Duration, Instant};
 
-use crate::{avg::SlidingDurationAvg, counter::ThruputCounters, BLOCK_LEN};
+use crate::{
+    avg::SlidingDurationAvg, counter::ThruputCounters,
+    torrent::stats::MessageStats,
+};
+
+use super::codec::Message;
 
 /// Contains the state of both sides of the connection.
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 pub struct SessionState {
     /// The current state of the connection.
     pub connection: ConnectionState,
@@ -33,6 +43,11 @@ impl Default for SessionState {
 
 /// At any given time, a connection with a peer is in one of the below states.
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 pub enum ConnectionState {
     /// The peer connection has not yet been connected or it had been connected
     /// before but has been stopped.
@@ -69,6 +84,12 @@ pub(super) struct SessionContext {
     /// Measures various transfer statistics.
     pub counters: ThruputCounters,
 
+    /// The number and length of the messages exchanged since the last session
+    /// tick, by message type.
+    ///
+    /// This is reset every tick, so it is up to torrent to aggregate it.
+    pub messages: MessageStats,
+
     /// A flag to indicate whether since the previous session tick the state has
     /// changed in a way that requires sending a new message to the torrent
     /// task. If this is true, the peer session needs to send a state update
@@ -78,6 +99,7 @@ pub(super) struct SessionContext {
     /// before this field, that is:
     /// - [`Self::state`]
     /// - [`Self::counters`]
+    /// - [`Self::messages`]
     pub changed: bool,
 
     /// Whether the session is in slow start.
@@ -119,19 +141,18 @@ pub(super) struct SessionContext {
     // to match on it all the time)
     pub target_request_queue_len: Option<usize>,
 
-    /// The last time some requests were sent to the peer.
-    pub last_outgoing_request_time: Option<Instant>,
     /// Updated with the time of receipt of the most recently received requested
     /// block.
     pub last_incoming_block_time: Option<Instant>,
     /// Updated with the time of receipt of the most recently uploaded block.
     pub last_outgoing_block_time: Option<Instant>,
-    /// This is the average network round-trip-time between the last issued
-    /// a request and receiving the next block.
+    /// This is the smoothed round-trip-time between sending a request and
+    /// receiving the requested block, along with its average deviation.
     ///
-    /// Note that it doesn't have to be the same block since peers are not
-    /// required to serve our requests in order, so this is more of a general
-    /// approximation.
+    /// Since requests are pipelined, this includes the time the request spends
+    /// in the peer's queue, which is exactly what we want for deriving the
+    /// request timeout: a slow peer with a deep queue will have a longer
+    /// timeout than a fast peer.
     pub avg_request_rtt: SlidingDurationAvg,
     pub request_timed_out: bool,
     pub timed_out_request_count: usize,
@@ -161,13 +182,24 @@ impl SessionContext {
     /// timeouts.
     const MIN_TIMEOUT: Duration = Duration::from_secs(2);
 
+    /// The largest timeout value we can give a peer, so that the blocks of
+    /// a peer that stopped responding are eventually freed for other peers.
+    const MAX_TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// Until we have a round-trip-time sample we don't know anything about the
+    /// peer's latency, so we use a generous timeout.
+    const INITIAL_TIMEOUT: Duration = Duration::from_secs(10);
+
     /// Returns the current request timeout value, based on the running average
     /// of past request round trip times.
     pub fn request_timeout(&self) -> Duration {
+        if self.avg_request_rtt.sample_count() == 0 {
+            return Self::INITIAL_TIMEOUT;
+        }
         // we allow up to four times the average deviation from the mean
         let t =
             self.avg_request_rtt.mean() + 4 * self.avg_request_rtt.deviation();
-        t.max(Self::MIN_TIMEOUT)
+        t.max(Self::MIN_TIMEOUT).min(Self::MAX_TIMEOUT)
     }
 
     /// Updates state to reflect that peer was timed out.
@@ -213,33 +245,36 @@ impl SessionContext {
 
     /// Updates various statistics around a block download.
     ///
-    /// This should be called every time a block is received.
-    pub fn update_download_stats(&mut self, block_len: u32) {
+    /// This should be called every time a block is received. If the block was
+    /// requested by us and the request is still pending (i.e. not timed out),
+    /// the time the request was sent should be passed in, from which the
+    /// request round-trip-time is sampled.
+    pub fn update_download_stats(
+        &mut self,
+        block_len: u32,
+        request_time: Option<Instant>,
+    ) {
         let now = Instant::now();
 
-        // update request time
-        if let Some(last_outgoing_request_time) =
-            &mut self.last_outgoing_request_time
-        {
+        // update request round-trip-time (requests that timed out are not
+        // sampled as we can't tell whether the block arrived in response to
+        // the original request or a later one)
+        if let Some(request_time) = request_time {
             // Due to what is presumed to be inconsistencies with the
             // `Instant::now()` API, it happens in rare circumstances that using
             // the regular `duration_since` here panics (#48). I suspect this
             // happens when requests are made a very short interval before this
             // function is called, which is likely in very fast downloads.
             // Either way, we guard against this by defaulting to 0.
-            let elapsed_since_last_request =
-                now.saturating_duration_since(*last_outgoing_request_time);
+            let request_rtt = now.saturating_duration_since(request_time);
 
             // If we timed out before, check if this request arrived within the
             // timeout window, or outside of it. If it arrived within the
             // window, we can mark peer as having recovered from the timeout.
-            if self.request_timed_out
-                && elapsed_since_last_request <= self.request_timeout()
-            {
+            if self.request_timed_out && request_rtt <= self.request_timeout() {
                 self.request_timed_out = false;
             }
 
-            let request_rtt = elapsed_since_last_request;
             self.avg_request_rtt.update(request_rtt);
         }
 
@@ -271,10 +306,23 @@ impl SessionContext {
         self.changed = true;
     }
 
+    /// Records a message received from peer in the message statistics.
+    pub fn record_incoming_msg(&mut self, msg: &Message) {
+        msg.record(&mut self.messages.down);
+        self.changed = true;
+    }
+
+    /// Records a message sent to peer in the message statistics.
+    pub fn record_outgoing_msg(&mut self, msg: &Message) {
+        msg.record(&mut self.messages.up);
+        self.changed = true;
+    }
+
     /// Updates various statistics and session state.
     ///
-    /// This should be called every second.
-    pub fn tick(&mut self) {
+    /// This should be called every second. The block length is the length of
+    /// the blocks we request, used to size the request queue.
+    pub fn tick(&mut self, block_len: u32) {
         self.maybe_exit_slow_start();
 
         // NOTE: This has to be *after* `maybe_exit_slow_start` and *before*
@@ -284,11 +332,12 @@ impl SessionContext {
         // rate).
         // TODO: can we statically ensure this rather than rely on the comment?
         self.counters.reset();
+        self.messages = MessageStats::default();
 
         // if we're still in the timeout, we don't want to increase
         // the target request queue size
         if !self.request_timed_out {
-            self.update_target_request_queue_len();
+            self.update_target_request_queue_len(block_len);
         }
 
         // reset the dirty flag
@@ -315,7 +364,7 @@ impl SessionContext {
 
     /// Adjusts the target request queue size based on the current download
     /// statistics.
-    fn update_target_request_queue_len(&mut self) {
+    fn update_target_request_queue_len(&mut self, block_len: u32) {
         if let Some(target_request_queue_len) =
             &mut self.target_request_queue_len
         {
@@ -330,8 +379,8 @@ impl SessionContext {
                 // overestimating the link capacity is cheaper than
                 // underestimating it
                 *target_request_queue_len =
-                    ((download_rate + (BLOCK_LEN - 1) as u64)
-                        / BLOCK_LEN as u64) as usize;
+                    ((download_rate + (block_len - 1) as u64)
+                        / block_len as u64) as usize;
             }
 
             // make sure the target doesn't go below 1
@@ -354,6 +403,7 @@ impl SessionContext {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::BLOCK_LEN;
 
     #[test]
     fn should_prepare_for_download() {
@@ -423,7 +473,7 @@ mod tests {
         s.counters.payload.down.reset();
 
         // this should be a noop
-        s.update_target_request_queue_len();
+        s.update_target_request_queue_len(BLOCK_LEN);
         assert_eq!(s.target_request_queue_len, Some(1));
     }
 
@@ -447,7 +497,7 @@ mod tests {
         // 0 + (10 * 16384 + 5000) / 5 = 33768
         // queue size based on bandwidth-delay product:
         // (33768 + (16384 - 1)) / 16384 = 3.06 ~ 3
-        s.update_target_request_queue_len();
+        s.update_target_request_queue_len(BLOCK_LEN);
         assert_eq!(s.target_request_queue_len, Some(3));
     }
 
@@ -460,7 +510,8 @@ mod tests {
         s.in_slow_start = true;
         s.target_request_queue_len = Some(1);
 
-        s.update_download_stats(BLOCK_LEN);
+        let request_time = Instant::now() - Duration::from_millis(500);
+        s.update_download_stats(BLOCK_LEN, Some(request_time));
 
         // request queue length should be increased by one in slow start
         assert_eq!(s.target_request_queue_len, Some(2));
@@ -468,5 +519,31 @@ mod tests {
         assert!(s.last_incoming_block_time.is_some());
         // download stat should be increased
         assert_eq!(s.counters.payload.down.round(), BLOCK_LEN as u64);
+        // request round-trip-time should be sampled
+        assert!(s.avg_request_rtt.mean() >= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn should_derive_request_timeout_from_rtt() {
+        let mut s = SessionContext::default();
+
+        // without samples the initial timeout is used
+        assert_eq!(s.request_timeout(), SessionContext::INITIAL_TIMEOUT);
+
+        // a fast peer gets the minimum timeout
+        s.avg_request_rtt.update(Duration::from_millis(100));
+        assert_eq!(s.request_timeout(), SessionContext::MIN_TIMEOUT);
+
+        // a slow but steady peer gets a timeout longer than its round trip
+        let mut s = SessionContext::default();
+        for _ in 0..10 {
+            s.avg_request_rtt.update(Duration::from_secs(5));
+        }
+        assert!(s.request_timeout() >= Duration::from_secs(5));
+        assert!(s.request_timeout() < SessionContext::INITIAL_TIMEOUT);
+
+        // but the timeout is capped
+        s.avg_request_rtt.update(Duration::from_secs(600));
+        assert_eq!(s.request_timeout(), SessionContext::MAX_TIMEOUT);
     }
 }
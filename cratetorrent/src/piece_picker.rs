@@ -1,108 +1,710 @@
-use crate::{Bitfield, PieceIndex};
+//! This module defines the [`PiecePicker`] trait, through which a torrent
+//! decides which pieces to download from its peers, and the built-in
+//! rarest-first implementation.
+//!
+//! Applications with special needs may plug in their own piece selection
+//! strategy by implementing the trait and passing a [`PiecePickerFactory`] in
+//! [`TorrentParams::piece_picker`](crate::engine::TorrentParams::piece_picker).
 
-pub(crate) struct PiecePicker {
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    time::Instant,
+};
+
+use rand::Rng;
+
+use crate::{logging, Bitfield, FilePriority, PieceIndex};
+
+/// Chooses the pieces a torrent downloads and keeps track of the pieces we
+/// have and of their availability among connected peers.
+///
+/// The torrent feeds the picker the pieces that its peers have (their
+/// bitfields and `have` messages) and the pieces it completes, and its peer
+/// sessions ask it for the next piece to download from their peer, whose
+/// blocks are then requested from the peer.
+///
+/// A picked piece must not be picked again until it is received, so that
+/// multiple pieces may be downloaded in parallel. The picker is shared by all
+/// peer sessions of a torrent, so it should not block.
+pub trait PiecePicker: Send + Sync {
+    /// Returns the pieces we have.
+    fn own_pieces(&self) -> &Bitfield;
+
+    /// Returns the number of pieces we don't have yet.
+    fn missing_piece_count(&self) -> usize;
+
+    /// Returns the number of pieces that are not skipped.
+    fn wanted_piece_count(&self) -> usize;
+
+    /// Returns the number of pieces that are not skipped and that we don't
+    /// have yet.
+    fn wanted_missing_piece_count(&self) -> usize;
+
+    /// Returns whether the piece is not skipped.
+    fn is_wanted(&self, index: PieceIndex) -> bool;
+
+    /// Returns true if all wanted pieces have been picked (whether they are
+    /// being downloaded or were received).
+    fn all_pieces_picked(&self) -> bool;
+
+    /// Sets the time by which the piece should be downloaded, or clears it if
+    /// `deadline` is `None`.
+    fn set_piece_deadline(
+        &mut self,
+        index: PieceIndex,
+        deadline: Option<Instant>,
+    );
+
+    /// Returns the pieces that are being downloaded and whose deadline is
+    /// before `threshold`, in the order of their deadlines.
+    ///
+    /// The threshold is the latest time by which a piece requested now can be
+    /// expected to arrive. Such pieces are requested from more than one peer.
+    fn pieces_at_risk(&self, threshold: Instant) -> Vec<PieceIndex>;
+
+    /// Sets the priority of a piece, which is the highest priority of the
+    /// files it intersects. Skipped pieces must not be picked.
+    fn set_piece_priority(&mut self, index: PieceIndex, priority: FilePriority);
+
+    /// Sets whether the piece should be picked before other pieces of the
+    /// same priority.
+    fn set_piece_boost(&mut self, index: PieceIndex, is_boosted: bool);
+
+    /// Returns the piece that we should download next from a peer that has
+    /// `peer_pieces`, or None, if no piece can be picked at this time.
+    ///
+    /// The returned piece is considered to be downloading and it must not be
+    /// picked again.
+    fn pick_piece(&mut self, peer_pieces: &Bitfield) -> Option<PieceIndex>;
+
+    /// Registers the pieces of a newly connected peer and returns whether we
+    /// are interested in any of them.
+    fn register_peer_pieces(&mut self, pieces: &Bitfield) -> bool;
+
+    /// Registers a piece a peer announced with a `have` message and returns
+    /// whether we are interested in it.
+    fn register_peer_piece(&mut self, index: PieceIndex) -> bool;
+
+    /// Unregisters the pieces of a disconnected peer.
+    fn unregister_peer_pieces(&mut self, pieces: &Bitfield);
+
+    /// Registers that we have downloaded and verified the piece.
+    fn received_piece(&mut self, index: PieceIndex);
+
+    /// Registers that a piece we had is no longer valid, e.g. because its
+    /// data on disk was found to be corrupt by a recheck, so it's to be
+    /// downloaded again.
+    fn lost_piece(&mut self, index: PieceIndex);
+
+    /// Returns the number of connected peers that have the piece.
+    fn availability(&self, index: PieceIndex) -> u32;
+
+    /// Returns the number of distributed copies of the torrent among
+    /// connected peers.
+    ///
+    /// The integer part is the lowest availability of any piece, and the
+    /// fractional part is the fraction of pieces that are more available than
+    /// that.
+    fn distributed_copies(&self) -> f64 {
+        let piece_count = self.own_pieces().len();
+        let min_availability =
+            match (0..piece_count).map(|i| self.availability(i)).min() {
+                Some(min) => min,
+                None => return 0.0,
+            };
+        let more_available_count = (0..piece_count)
+            .filter(|&i| self.availability(i) > min_availability)
+            .count();
+        min_availability as f64
+            + more_available_count as f64 / piece_count as f64
+    }
+}
+
+/// A boxed picker, such as the one returned by a [`PiecePickerFactory`], is
+/// used as the picker it boxes.
+impl<P: PiecePicker + ?Sized> PiecePicker for Box<P> {
+    fn own_pieces(&self) -> &Bitfield {
+        (**self).own_pieces()
+    }
+
+    fn missing_piece_count(&self) -> usize {
+        (**self).missing_piece_count()
+    }
+
+    fn wanted_piece_count(&self) -> usize {
+        (**self).wanted_piece_count()
+    }
+
+    fn wanted_missing_piece_count(&self) -> usize {
+        (**self).wanted_missing_piece_count()
+    }
+
+    fn is_wanted(&self, index: PieceIndex) -> bool {
+        (**self).is_wanted(index)
+    }
+
+    fn all_pieces_picked(&self) -> bool {
+        (**self).all_pieces_picked()
+    }
+
+    fn set_piece_deadline(
+        &mut self,
+        index: PieceIndex,
+        deadline: Option<Instant>,
+    ) {
+        (**self).set_piece_deadline(index, deadline)
+    }
+
+    fn pieces_at_risk(&self, threshold: Instant) -> Vec<PieceIndex> {
+        (**self).pieces_at_risk(threshold)
+    }
+
+    fn set_piece_priority(
+        &mut self,
+        index: PieceIndex,
+        priority: FilePriority,
+    ) {
+        (**self).set_piece_priority(index, priority)
+    }
+
+    fn set_piece_boost(&mut self, index: PieceIndex, is_boosted: bool) {
+        (**self).set_piece_boost(index, is_boosted)
+    }
+
+    fn pick_piece(&mut self, peer_pieces: &Bitfield) -> Option<PieceIndex> {
+        (**self).pick_piece(peer_pieces)
+    }
+
+    fn register_peer_pieces(&mut self, pieces: &Bitfield) -> bool {
+        (**self).register_peer_pieces(pieces)
+    }
+
+    fn register_peer_piece(&mut self, index: PieceIndex) -> bool {
+        (**self).register_peer_piece(index)
+    }
+
+    fn unregister_peer_pieces(&mut self, pieces: &Bitfield) {
+        (**self).unregister_peer_pieces(pieces)
+    }
+
+    fn received_piece(&mut self, index: PieceIndex) {
+        (**self).received_piece(index)
+    }
+
+    fn lost_piece(&mut self, index: PieceIndex) {
+        (**self).lost_piece(index)
+    }
+
+    fn availability(&self, index: PieceIndex) -> u32 {
+        (**self).availability(index)
+    }
+
+    fn distributed_copies(&self) -> f64 {
+        (**self).distributed_copies()
+    }
+}
+
+/// Creates the piece picker of a torrent, given the pieces we already have.
+pub type PiecePickerFactory =
+    Box<dyn FnOnce(Bitfield) -> Box<dyn PiecePicker> + Send>;
+
+/// Picks the pieces to download in rarest-first order.
+///
+/// The picker tracks the availability of each piece in the swarm, based on the
+/// bitfields and `have` messages of connected peers, and always picks one of
+/// the least available pieces we don't yet have. This maximizes the
+/// availability of pieces in the swarm, so that we are less likely to end up
+/// needing a piece that no connected peer has, and it gives us pieces that are
+/// in demand by other peers.
+///
+/// Ties between equally rare pieces are broken randomly, so that peers
+/// downloading from the same swarm don't all pick the same pieces.
+///
+/// As an exception, the first few pieces are picked at random: rare pieces are
+/// by definition only available from a few peers, so they'd take longer to
+/// download, while we need some pieces as soon as possible to have something
+/// to trade with other peers in return for being unchoked.
+///
+/// Each piece has the priority of the highest priority file it intersects.
+/// Higher priority pieces are always picked before lower priority ones,
+/// regardless of their availability, and skipped pieces are never picked.
+/// Within the same priority, boosted pieces (such as the first and last pieces
+/// of files, which media players need to start playback) are picked first.
+///
+/// Alternatively, the picker may be switched to sequential mode, in which
+/// pieces are picked in order.
+///
+/// Pieces with a deadline take precedence over both modes and are picked in
+/// the order of their deadlines.
+///
+/// # Complexity
+///
+/// To scale to torrents with hundreds of thousands of pieces, the free pieces
+/// (the wanted pieces that we neither have nor are downloading) are kept in
+/// buckets, by their priority and availability. A rarest-first pick visits
+/// the buckets in order and returns the first piece that the peer has, so
+/// when picking from a peer that has most pieces (the common case), a pick
+/// takes logarithmic time in the number of buckets, rather than the linear
+/// scan over all pieces of a naive implementation. Changes in a piece's
+/// availability move the piece between buckets in constant time (plus the
+/// logarithmic bucket lookup).
+pub struct RarestFirstPicker {
     /// Represents the pieces that we have downloaded.
     ///
     /// The bitfield is pre-allocated to the number of pieces in the torrent and
     /// each field that we have is set to true.
     own_pieces: Bitfield,
+    /// The pieces that we have already picked and are currently downloading.
+    ///
+    /// This is to prevent picking the same piece we are already downloading in
+    /// the scenario in which we want to pick a new piece before the already
+    /// downloadng piece finishes. Not having this check would lead us to always
+    /// pick this piece until we tell the piece picker that we have it and thus
+    /// wouldn't be able to download multiple pieces simultaneously (an
+    /// important optimizaiton step).
+    pending_pieces: Bitfield,
     /// We collect metadata about pieces in the torrent swarm in this vector.
     ///
     /// The vector is pre-allocated to the number of pieces in the torrent.
     pieces: Vec<Piece>,
+    /// The free pieces, that is, the wanted pieces that we don't have and are
+    /// not downloading, grouped by their pick order and availability.
+    ///
+    /// Iterating the map yields the buckets in the order in which their pieces
+    /// should be picked. Empty buckets are removed.
+    buckets: BTreeMap<BucketKey, Vec<PieceIndex>>,
+    /// The position of each free piece in its bucket, or [`NOT_FREE`] if the
+    /// piece is not free. This makes removing a piece from its bucket
+    /// a constant time operation.
+    bucket_positions: Vec<u32>,
+    /// A cache for the number of free pieces in all buckets.
+    free_count: usize,
     /// A cache for the number of pieces we haven't received yet (but may have
     /// picked).
     missing_count: usize,
-    /// A cache for the number of pieces that can be picked.
-    free_count: usize,
+    /// A cache for the number of pieces that are not skipped.
+    wanted_count: usize,
+    /// A cache for the number of pieces that are not skipped and that we
+    /// haven't received yet (but may have picked).
+    wanted_missing_count: usize,
+    /// The first piece that we want and don't have, or the number of pieces
+    /// if there is none. This is where the sequential lookahead window starts.
+    first_wanted_missing: PieceIndex,
+    /// If set, pieces are picked in order, and only from the window of this
+    /// many pieces starting at the first piece we don't have.
+    sequential_lookahead: Option<usize>,
+    /// Until we have this many pieces, pieces are picked at random rather than
+    /// rarest-first.
+    random_first_count: usize,
+    /// The pieces that are needed by a certain time, e.g. by a media player
+    /// streaming the torrent. These are removed once the piece is received.
+    deadlines: HashMap<PieceIndex, Instant>,
 }
 
+/// The position of pieces that are not in any bucket.
+const NOT_FREE: u32 = u32::MAX;
+
+/// Identifies the bucket of free pieces with the same pick order and
+/// availability. Buckets are ordered such that the pieces of the first bucket
+/// should be picked first: higher pick orders first, then lower availability.
+type BucketKey = (Reverse<(FilePriority, bool)>, u32);
+
 /// Metadata about a piece relevant for the piece picker.
 #[derive(Clone, Copy, Default)]
 pub(crate) struct Piece {
     /// The frequency of this piece in the torrent swarm.
-    pub frequency: usize,
-    /// Whether we have already picked this piece and are currently downloading
-    /// it. This flag is set to true when the piece is picked.
-    ///
-    /// This is to prevent picking the same piece we are already downloading in
-    /// the scenario in which we want to pick a new piece before the already
-    /// downloadng piece finishes. Not having this check would lead us to always
-    /// pick this piece until we tell the piece picker that we have it and thus
-    /// wouldn't be able to download multiple pieces simultaneously (an
-    /// important optimizaiton step).
-    pub is_pending: bool,
+    pub frequency: u32,
+    /// The priority of the highest priority file the piece intersects.
+    pub priority: FilePriority,
+    /// Whether the piece is picked before other pieces of the same priority.
+    pub is_boosted: bool,
 }
 
-impl PiecePicker {
+impl Piece {
+    /// Returns the key by which pieces are ordered for picking, regardless of
+    /// their availability: the higher the key, the sooner it's picked.
+    fn pick_order(&self) -> (FilePriority, bool) {
+        (self.priority, self.is_boosted)
+    }
+
+    /// Returns the key of the bucket in which the piece is kept while free.
+    fn bucket_key(&self) -> BucketKey {
+        (Reverse(self.pick_order()), self.frequency)
+    }
+}
+
+impl RarestFirstPicker {
     /// Creates a new piece picker with the given own_pieces we already have.
     pub fn new(own_pieces: Bitfield) -> Self {
+        let piece_count = own_pieces.len();
+        assert!(
+            piece_count < NOT_FREE as usize,
+            "too many pieces in torrent"
+        );
         let mut pieces = Vec::new();
-        pieces.resize_with(own_pieces.len(), Piece::default);
+        pieces.resize_with(piece_count, Piece::default);
         let missing_count = own_pieces.count_zeros();
+        let first_wanted_missing = own_pieces
+            .iter()
+            .position(|have| !*have)
+            .unwrap_or(piece_count);
+
+        // all missing pieces are initially free and equally available
+        let mut bucket_positions = vec![NOT_FREE; piece_count];
+        let mut free_pieces = Vec::with_capacity(missing_count);
+        for (index, have) in own_pieces.iter().enumerate() {
+            if !*have {
+                bucket_positions[index] = free_pieces.len() as u32;
+                free_pieces.push(index);
+            }
+        }
+        let mut buckets = BTreeMap::new();
+        if !free_pieces.is_empty() {
+            buckets.insert(Piece::default().bucket_key(), free_pieces);
+        }
+
         Self {
+            pending_pieces: Bitfield::repeat(false, piece_count),
             own_pieces,
             pieces,
-            missing_count,
+            buckets,
+            bucket_positions,
             free_count: missing_count,
+            missing_count,
+            wanted_count: piece_count,
+            wanted_missing_count: missing_count,
+            first_wanted_missing,
+            sequential_lookahead: None,
+            random_first_count: 0,
+            deadlines: HashMap::new(),
         }
     }
 
-    /// Returns an immutable reference to a bitfield of the pieces we own.
-    pub fn own_pieces(&self) -> &Bitfield {
-        &self.own_pieces
+    /// Switches between sequential (if a lookahead window is given) and
+    /// rarest-first piece picking.
+    ///
+    /// The lookahead is the number of pieces, starting at the first missing
+    /// piece, from which the picker may pick. It is at least 1.
+    pub fn set_sequential(&mut self, lookahead: Option<usize>) {
+        self.sequential_lookahead = lookahead.map(|n| n.max(1));
     }
 
-    /// Returns the number of missing pieces that are needed to complete the
-    /// download.
-    pub fn missing_piece_count(&self) -> usize {
-        self.missing_count
+    /// Sets the number of pieces to pick at random, before switching to
+    /// rarest-first picking.
+    pub fn set_random_first_count(&mut self, count: usize) {
+        self.random_first_count = count;
     }
 
-    /// Returns true if all pieces have been picked (whether pending or
-    /// recieved).
-    pub fn all_pieces_picked(&self) -> bool {
-        self.free_count == 0
+    /// Returns the piece with the earliest deadline that can be picked.
+    fn pick_deadline(&self, peer_pieces: &Bitfield) -> Option<PieceIndex> {
+        self.deadlines
+            .iter()
+            .filter(|(index, _)| self.is_pickable(**index, peer_pieces))
+            .min_by_key(|(index, deadline)| (**deadline, **index))
+            .map(|(index, _)| *index)
     }
 
-    /// Returns the first piece that we don't yet have and isn't already being
-    /// downloaded, or None, if no piece can be picked at this time.
-    pub fn pick_piece(&mut self) -> Option<PieceIndex> {
-        log::trace!("Picking next piece");
+    /// Returns the first of the highest priority (and boost) pieces in the
+    /// lookahead window that can be picked.
+    ///
+    /// The window starts at the first piece we don't have and want.
+    fn pick_sequential(
+        &self,
+        peer_pieces: &Bitfield,
+        lookahead: usize,
+    ) -> Option<PieceIndex> {
+        let first_missing = self.first_wanted_missing;
+        let window_end = (first_missing + lookahead).min(self.pieces.len());
+        (first_missing..window_end)
+            .filter(|&index| {
+                self.pieces[index].priority != FilePriority::Skip
+                    && self.is_pickable(index, peer_pieces)
+            })
+            .max_by_key(|&index| {
+                (self.pieces[index].pick_order(), Reverse(index))
+            })
+    }
 
-        for index in 0..self.own_pieces.len() {
-            // only consider this piece if we don't have it and if we are not
-            // already downloading it (whether it's not pending)
-            debug_assert!(index < self.pieces.len());
-            let piece = &mut self.pieces[index];
-            if !self.own_pieces[index]
-                && piece.frequency > 0
-                && !piece.is_pending
+    /// Returns one of the rarest pieces of the highest priority (and boost)
+    /// that can be picked, chosen at random.
+    ///
+    /// If we don't yet have the configured number of pieces to pick at random,
+    /// availability is disregarded and any of the highest priority pieces may
+    /// be picked.
+    fn pick_rarest(&self, peer_pieces: &Bitfield) -> Option<PieceIndex> {
+        let have_count = self.own_pieces.len() - self.missing_count;
+        if have_count < self.random_first_count {
+            return self.pick_random(peer_pieces);
+        }
+
+        // The first bucket with a piece that the peer has contains the rarest
+        // pieces of the highest priority we can pick. Within the bucket, the
+        // search starts at a random position so that ties are broken randomly.
+        let mut rng = rand::thread_rng();
+        for bucket in self.buckets.values() {
+            let start = rng.gen_range(0, bucket.len());
+            let (tail, head) = bucket.split_at(start);
+            if let Some(index) = head
+                .iter()
+                .chain(tail.iter())
+                .find(|index| peer_pieces[**index])
             {
-                // set pending flag on piece so that this piece is not picked
-                // again (see note on field)
-                piece.is_pending = true;
-                self.free_count -= 1;
-                log::trace!("Picked piece {}", index);
-                return Some(index);
+                return Some(*index);
             }
         }
 
-        // no piece could be picked
-        log::trace!("Could not pick piece");
         None
     }
 
-    /// Registers the avilability of a peer's pieces and returns whether we're
-    /// interested in peer's pieces.
+    /// Returns one of the free pieces of the highest priority (and boost) that
+    /// the peer has, chosen at random regardless of availability.
     ///
-    /// # Panics
+    /// Unlike a rarest-first pick, this visits all free pieces of the highest
+    /// priority, but it's only done for the first few pieces.
+    fn pick_random(&self, peer_pieces: &Bitfield) -> Option<PieceIndex> {
+        let mut rng = rand::thread_rng();
+        let mut pick = None;
+        let mut pick_order = None;
+        // the number of pickable pieces seen so far in the pick order
+        let mut count = 0;
+        for ((Reverse(order), _), bucket) in self.buckets.iter() {
+            // buckets are ordered by pick order first, so once we have a pick,
+            // the buckets of lower orders can be skipped
+            if pick_order.map(|o| o != *order).unwrap_or(false) {
+                break;
+            }
+            for index in bucket.iter().filter(|index| peer_pieces[**index]) {
+                // Pick uniformly without collecting the pieces: the n-th
+                // piece replaces the current pick with a probability of 1/n.
+                count += 1;
+                if rng.gen_range(0, count) == 0 {
+                    pick = Some(*index);
+                }
+                pick_order = Some(*order);
+            }
+        }
+        pick
+    }
+
+    /// Returns whether the piece can be picked: we only consider a piece if we
+    /// don't have it, if the peer has it, and if we are not already
+    /// downloading it (whether it's not pending).
+    fn is_pickable(&self, index: PieceIndex, peer_pieces: &Bitfield) -> bool {
+        !self.own_pieces[index]
+            && peer_pieces[index]
+            && !self.pending_pieces[index]
+    }
+
+    /// Sets or clears the piece's pending flag, which also removes the piece
+    /// from or returns it to the free pieces.
+    fn set_pending(&mut self, index: PieceIndex, is_pending: bool) {
+        self.remove_free(index);
+        self.pending_pieces.set(index, is_pending);
+        self.insert_free(index);
+    }
+
+    /// Updates the piece's metadata, moving it to the bucket matching its new
+    /// availability and priority, if it's free (or became free by the update).
+    fn update_piece(&mut self, index: PieceIndex, f: impl FnOnce(&mut Piece)) {
+        self.remove_free(index);
+        f(&mut self.pieces[index]);
+        self.insert_free(index);
+    }
+
+    /// Removes the piece from its bucket and returns whether it was free.
+    fn remove_free(&mut self, index: PieceIndex) -> bool {
+        let pos = self.bucket_positions[index];
+        if pos == NOT_FREE {
+            return false;
+        }
+        let key = self.pieces[index].bucket_key();
+        let bucket = self.buckets.get_mut(&key).expect("piece bucket missing");
+        bucket.swap_remove(pos as usize);
+        // the last piece in the bucket was moved into the removed piece's place
+        if let Some(moved) = bucket.get(pos as usize) {
+            self.bucket_positions[*moved] = pos;
+        }
+        if bucket.is_empty() {
+            self.buckets.remove(&key);
+        }
+        self.bucket_positions[index] = NOT_FREE;
+        self.free_count -= 1;
+        true
+    }
+
+    /// Adds the piece to its bucket if it's free, i.e. if it's wanted and we
+    /// neither have it nor are downloading it.
+    fn insert_free(&mut self, index: PieceIndex) {
+        debug_assert_eq!(self.bucket_positions[index], NOT_FREE);
+        if self.own_pieces[index]
+            || self.pending_pieces[index]
+            || !self.is_wanted(index)
+        {
+            return;
+        }
+        let bucket = self
+            .buckets
+            .entry(self.pieces[index].bucket_key())
+            .or_insert_with(Vec::new);
+        self.bucket_positions[index] = bucket.len() as u32;
+        bucket.push(index);
+        self.free_count += 1;
+    }
+
+    /// Moves the start of the sequential window to the next piece we want and
+    /// don't have.
+    fn advance_first_wanted_missing(&mut self) {
+        let start = self.first_wanted_missing;
+        self.first_wanted_missing = (start..self.pieces.len())
+            .find(|&index| !self.own_pieces[index] && self.is_wanted(index))
+            .unwrap_or_else(|| self.pieces.len());
+    }
+}
+
+impl PiecePicker for RarestFirstPicker {
+    fn own_pieces(&self) -> &Bitfield {
+        &self.own_pieces
+    }
+
+    fn missing_piece_count(&self) -> usize {
+        self.missing_count
+    }
+
+    fn wanted_piece_count(&self) -> usize {
+        self.wanted_count
+    }
+
+    fn wanted_missing_piece_count(&self) -> usize {
+        self.wanted_missing_count
+    }
+
+    fn is_wanted(&self, index: PieceIndex) -> bool {
+        self.pieces[index].priority != FilePriority::Skip
+    }
+
+    fn all_pieces_picked(&self) -> bool {
+        self.free_count == 0
+    }
+
+    fn set_piece_deadline(
+        &mut self,
+        index: PieceIndex,
+        deadline: Option<Instant>,
+    ) {
+        log::trace!(
+            target: logging::TORRENT,
+            "Setting piece {} deadline: {:?}",
+            index,
+            deadline
+        );
+        let have_piece =
+            self.own_pieces.get(index).expect("invalid piece index");
+        match deadline {
+            Some(deadline) if !*have_piece => {
+                self.deadlines.insert(index, deadline);
+            }
+            _ => {
+                self.deadlines.remove(&index);
+            }
+        }
+    }
+
+    fn pieces_at_risk(&self, threshold: Instant) -> Vec<PieceIndex> {
+        let mut pieces: Vec<_> = self
+            .deadlines
+            .iter()
+            .filter(|(index, deadline)| {
+                self.pending_pieces[**index] && **deadline <= threshold
+            })
+            .map(|(index, deadline)| (*deadline, *index))
+            .collect();
+        pieces.sort_unstable();
+        pieces.into_iter().map(|(_, index)| index).collect()
+    }
+
+    fn set_piece_priority(
+        &mut self,
+        index: PieceIndex,
+        priority: FilePriority,
+    ) {
+        let was_wanted = self.is_wanted(index);
+        self.update_piece(index, |piece| piece.priority = priority);
+        let is_wanted = self.is_wanted(index);
+        if was_wanted == is_wanted {
+            return;
+        }
+
+        // update the caches of wanted pieces
+        let is_missing = !self.own_pieces[index];
+        if is_wanted {
+            self.wanted_count += 1;
+            self.wanted_missing_count += is_missing as usize;
+            if is_missing && index < self.first_wanted_missing {
+                self.first_wanted_missing = index;
+            }
+        } else {
+            self.wanted_count -= 1;
+            self.wanted_missing_count -= is_missing as usize;
+            if index == self.first_wanted_missing {
+                self.advance_first_wanted_missing();
+            }
+        }
+    }
+
+    fn set_piece_boost(&mut self, index: PieceIndex, is_boosted: bool) {
+        if self.pieces[index].is_boosted != is_boosted {
+            self.update_piece(index, |piece| piece.is_boosted = is_boosted);
+        }
+    }
+
+    /// Returns the piece that we should download next from a peer that has
+    /// `peer_pieces`, or None, if no piece can be picked at this time.
     ///
-    /// Panics if the peer sent us pieces with a different count than ours.
-    /// The validity of the pieces must be ensured at the protocol level (in
-    /// [`crate::peer::PeerSession`]).
-    pub fn register_peer_pieces(&mut self, pieces: &Bitfield) -> bool {
-        log::trace!("Registering piece availability: {}", pieces);
+    /// A piece is only picked if we don't yet have it, if it isn't already
+    /// being downloaded, and if the peer has it. Of these, the piece with the
+    /// earliest deadline is picked, if any. Otherwise the highest priority
+    /// piece in the lookahead window is picked in sequential mode, and the
+    /// rarest of the highest priority pieces is picked in rarest-first mode.
+    /// If there are multiple such pieces with the same lowest availability,
+    /// one of them is picked at random. Skipped pieces are only picked if they
+    /// have a deadline.
+    fn pick_piece(&mut self, peer_pieces: &Bitfield) -> Option<PieceIndex> {
+        log::trace!(target: logging::TORRENT, "Picking next piece");
+
+        debug_assert_eq!(peer_pieces.len(), self.own_pieces.len());
+
+        let pick = self.pick_deadline(peer_pieces).or_else(|| {
+            if let Some(lookahead) = self.sequential_lookahead {
+                self.pick_sequential(peer_pieces, lookahead)
+            } else {
+                self.pick_rarest(peer_pieces)
+            }
+        });
+
+        if let Some(index) = pick {
+            // set pending flag on piece so that this piece is not picked
+            // again (see note on field)
+            self.set_pending(index, true);
+            log::trace!(
+                target: logging::TORRENT,
+                "Picked piece {} (availability: {})",
+                index,
+                self.pieces[index].frequency
+            );
+        } else {
+            log::trace!(target: logging::TORRENT, "Could not pick piece");
+        }
+
+        pick
+    }
+
+    fn register_peer_pieces(&mut self, pieces: &Bitfield) -> bool {
+        log::trace!(
+            target: logging::TORRENT,
+            "Registering piece availability: {}",
+            pieces
+        );
 
         assert_eq!(
             pieces.len(),
@@ -111,87 +713,124 @@ impl PiecePicker {
         );
 
         let mut interested = false;
-        for (index, (have_piece, peer_has_piece)) in
-            self.own_pieces.iter().zip(pieces.iter()).enumerate()
-        {
+        for index in peer_piece_indices(pieces) {
             // increase frequency count for this piece if peer has it
-            if *peer_has_piece {
-                self.pieces[index].frequency += 1;
-                // if we don't have at least one piece peer has, we're
-                // interested
-                if !have_piece {
-                    interested = true;
-                }
+            self.update_piece(index, |piece| piece.frequency += 1);
+            // if we don't have at least one wanted piece peer has, we're
+            // interested
+            if !self.own_pieces[index] && self.is_wanted(index) {
+                interested = true;
             }
         }
 
         interested
     }
 
-    /// Increments the availability of a piece.
-    ///
-    /// This should be called when a peer sends us a `have` message of a new
-    /// piece.
-    ///
-    /// # Panics
-    ///
-    /// Panics if the piece index is out of range. The index validity must be
-    /// ensured at the protocol level (in [`crate::peer::PeerSession`]).
-    pub fn register_peer_piece(&mut self, index: PieceIndex) -> bool {
-        log::trace!("Registering newly available piece {}", index);
-        let is_interested =
-            self.own_pieces.get(index).expect("invalid piece index");
-        self.pieces[index].frequency += 1;
-        *is_interested
+    fn register_peer_piece(&mut self, index: PieceIndex) -> bool {
+        log::trace!(
+            target: logging::TORRENT,
+            "Registering newly available piece {}",
+            index
+        );
+        let have_piece =
+            *self.own_pieces.get(index).expect("invalid piece index");
+        self.update_piece(index, |piece| piece.frequency += 1);
+        !have_piece && self.is_wanted(index)
     }
 
-    /// Tells the piece picker that we have downloaded the piece at the given
-    /// index that we had picked before.
-    ///
-    /// # Panics
-    ///
-    /// Panics if the piece was already received.
-    pub fn received_piece(&mut self, index: PieceIndex) {
-        log::trace!("Registering received piece {}", index);
+    fn unregister_peer_pieces(&mut self, pieces: &Bitfield) {
+        log::trace!(
+            target: logging::TORRENT,
+            "Unregistering piece availability: {}",
+            pieces
+        );
+
+        assert_eq!(
+            pieces.len(),
+            self.own_pieces.len(),
+            "peer's bitfield must be the same length as ours"
+        );
+
+        for index in peer_piece_indices(pieces) {
+            debug_assert!(self.pieces[index].frequency > 0);
+            self.update_piece(index, |piece| {
+                piece.frequency = piece.frequency.saturating_sub(1)
+            });
+        }
+    }
+
+    fn received_piece(&mut self, index: PieceIndex) {
+        log::trace!(
+            target: logging::TORRENT,
+            "Registering received piece {}",
+            index
+        );
 
         // we assert here as this method is only called by internal methods on
         // piece completion, meaning the piece must exist (we can't download an
         // invalid piece)
-        let mut have_piece =
-            self.own_pieces.get_mut(index).expect("invalid piece index");
+        let have_piece =
+            *self.own_pieces.get(index).expect("invalid piece index");
         // we must not already have this piece as otherwise the free/missing
         // count logic is thrown off
-        assert!(!*have_piece);
+        assert!(!have_piece);
 
-        // register owned piece
-        *have_piece = true;
+        // Register owned piece. The piece is usually pending, but in case it
+        // was received without having been picked, it's removed from the free
+        // pieces here.
+        self.remove_free(index);
+        self.own_pieces.set(index, true);
+        self.pending_pieces.set(index, false);
         self.missing_count -= 1;
+        self.deadlines.remove(&index);
+        if self.is_wanted(index) {
+            self.wanted_missing_count -= 1;
+        }
+        if index == self.first_wanted_missing {
+            self.advance_first_wanted_missing();
+        }
+    }
+
+    fn lost_piece(&mut self, index: PieceIndex) {
+        log::trace!(
+            target: logging::TORRENT,
+            "Registering lost piece {}",
+            index
+        );
 
-        // This is an edge-case and shouldn't normally happen, but we guard
-        // against it anyway in case there are changes in other parts of the
-        // code.
-        // If the piece was received without it having previously been picked,
-        // we need to decrease the free piece count here, as it is normally done
-        // in the `pick_piece` method.
-        let piece = &mut self.pieces[index];
-        if !piece.is_pending {
-            self.free_count -= 1;
-            // also set that this piece is no longer pending (even though we
-            // won't be downloading it anymore, later we may re-download a piece
-            // in which case not resetting the flag would cause us to never pick
-            // the piece again)
-            piece.is_pending = false;
+        let have_piece =
+            *self.own_pieces.get(index).expect("invalid piece index");
+        // as with received pieces, the counts would be thrown off otherwise
+        assert!(have_piece);
+
+        self.own_pieces.set(index, false);
+        self.missing_count += 1;
+        if self.is_wanted(index) {
+            self.wanted_missing_count += 1;
+            self.first_wanted_missing = self.first_wanted_missing.min(index);
         }
+        self.insert_free(index);
     }
 
-    pub fn pieces(&self) -> &[Piece] {
-        &self.pieces
+    fn availability(&self, index: PieceIndex) -> u32 {
+        self.pieces[index].frequency
     }
 }
 
+/// Returns the indices of the pieces in the bitfield.
+fn peer_piece_indices(
+    pieces: &Bitfield,
+) -> impl Iterator<Item = PieceIndex> + '_ {
+    pieces
+        .iter()
+        .enumerate()
+        .filter(|(_, has_piece)| **has_piece)
+        .map(|(index, _)| index)
+}
+
 #[cfg(test)]
 mod tests {
-    use std::collections::HashSet;
+    use std::{collections::HashSet, time::Duration};
 
     use super::*;
 
@@ -200,7 +839,8 @@ mod tests {
     #[test]
     fn should_pick_all_pieces() {
         let piece_count = 15;
-        let mut piece_picker = PiecePicker::empty(piece_count);
+        let mut piece_picker = RarestFirstPicker::empty(piece_count);
+        let all = Bitfield::repeat(true, piece_count);
         let available_pieces = Bitfield::repeat(true, piece_count);
         piece_picker.register_peer_pieces(&available_pieces);
 
@@ -208,11 +848,9 @@ mod tests {
         let mut picked = HashSet::with_capacity(piece_count);
 
         // pick all pieces one by one
-        for index in 0..piece_count {
-            let pick = piece_picker.pick_piece();
-            // for now we assert that we pick pieces in sequential order, but
-            // later, when we add different algorithms, this line has to change
-            assert_eq!(pick, Some(index));
+        for _ in 0..piece_count {
+            let pick = piece_picker.pick_piece(&all);
+            assert!(pick.is_some());
             let pick = pick.unwrap();
             // assert that this piece hasn't been picked before
             assert!(!picked.contains(&pick));
@@ -229,7 +867,8 @@ mod tests {
     #[test]
     fn should_mark_piece_as_received() {
         let piece_count = 15;
-        let mut piece_picker = PiecePicker::empty(piece_count);
+        let mut piece_picker = RarestFirstPicker::empty(piece_count);
+        let all = Bitfield::repeat(true, piece_count);
         let available_pieces = Bitfield::repeat(true, piece_count);
         piece_picker.register_peer_pieces(&available_pieces);
         assert!(piece_picker.own_pieces.not_any());
@@ -245,17 +884,69 @@ mod tests {
         // request pieces to pick next and make sure the ones we already have
         // are not picked
         for _ in 0..piece_count - owned_pieces.len() {
-            let pick = piece_picker.pick_piece().unwrap();
+            let pick = piece_picker.pick_piece(&all).unwrap();
             // assert that it's not a piece we already have
             assert!(owned_pieces.iter().all(|owned| *owned != pick));
         }
     }
 
+    /// Tests that a lost piece is counted as missing and is picked again.
+    #[test]
+    fn should_pick_lost_piece_again() {
+        let piece_count = 15;
+        let mut piece_picker =
+            RarestFirstPicker::new(Bitfield::repeat(true, piece_count));
+        let all = Bitfield::repeat(true, piece_count);
+        piece_picker.register_peer_pieces(&all);
+        assert_eq!(piece_picker.missing_piece_count(), 0);
+        assert_eq!(piece_picker.pick_piece(&all), None);
+
+        piece_picker.lost_piece(7);
+        assert!(!piece_picker.own_pieces[7]);
+        assert_eq!(piece_picker.missing_piece_count(), 1);
+        assert_eq!(piece_picker.wanted_missing_piece_count(), 1);
+        assert_eq!(piece_picker.first_wanted_missing, 7);
+        assert_eq!(piece_picker.pick_piece(&all), Some(7));
+        assert!(piece_picker.all_pieces_picked());
+
+        piece_picker.received_piece(7);
+        assert_eq!(piece_picker.missing_piece_count(), 0);
+    }
+
+    /// Tests that a piece picker created from a partially complete bitfield,
+    /// such as after resuming a download, only picks the missing pieces.
+    #[test]
+    fn should_only_pick_missing_pieces_when_resuming() {
+        let piece_count = 15;
+        let mut own_pieces = Bitfield::repeat(false, piece_count);
+        let owned_pieces = [0, 4, 5, 14];
+        for index in owned_pieces.iter() {
+            own_pieces.set(*index, true);
+        }
+        let mut piece_picker = RarestFirstPicker::new(own_pieces);
+        let all = Bitfield::repeat(true, piece_count);
+        piece_picker.register_peer_pieces(&all);
+
+        assert_eq!(
+            piece_picker.missing_piece_count(),
+            piece_count - owned_pieces.len()
+        );
+        assert_eq!(piece_picker.free_count, piece_count - owned_pieces.len());
+
+        let mut picked = HashSet::new();
+        while let Some(pick) = piece_picker.pick_piece(&all) {
+            assert!(!owned_pieces.contains(&pick));
+            assert!(picked.insert(pick));
+        }
+        assert_eq!(picked.len(), piece_count - owned_pieces.len());
+        assert!(piece_picker.all_pieces_picked());
+    }
+
     #[test]
     fn should_count_missing_pieces() {
         // empty piece picker
         let piece_count = 15;
-        let mut piece_picker = PiecePicker::empty(piece_count);
+        let mut piece_picker = RarestFirstPicker::empty(piece_count);
 
         assert_eq!(piece_picker.missing_piece_count(), piece_count);
 
@@ -282,36 +973,343 @@ mod tests {
     fn should_count_free_pieces() {
         // empty piece picker
         let piece_count = 15;
-        let mut piece_picker = PiecePicker::empty(piece_count);
+        let mut piece_picker = RarestFirstPicker::empty(piece_count);
+        let all = Bitfield::repeat(true, piece_count);
         // NOTE: need to register frequency before we pick any pieces
         piece_picker.register_peer_pieces(&Bitfield::repeat(true, piece_count));
 
         assert_eq!(piece_picker.free_count, piece_count);
 
         // picked and received 2 pieces
-        for i in 0..2 {
-            assert!(piece_picker.pick_piece().is_some());
-            piece_picker.received_piece(i);
+        for _ in 0..2 {
+            let pick = piece_picker.pick_piece(&all).unwrap();
+            piece_picker.received_piece(pick);
         }
         assert_eq!(piece_picker.free_count, 13);
 
         // pick 3 pieces
+        let mut picks = Vec::new();
         for _ in 0..3 {
-            assert!(piece_picker.pick_piece().is_some());
+            picks.push(piece_picker.pick_piece(&all).unwrap());
         }
         assert_eq!(piece_picker.free_count, 10);
 
         // received 1 of the above picked pieces: shouldn't change outcome
-        piece_picker.received_piece(2);
+        piece_picker.received_piece(picks[0]);
         assert_eq!(piece_picker.free_count, 10);
 
         // pick rest of the pieces
         for _ in 0..10 {
-            assert!(piece_picker.pick_piece().is_some());
+            assert!(piece_picker.pick_piece(&all).is_some());
         }
         assert!(piece_picker.all_pieces_picked());
     }
 
+    /// Tests that the rarest pieces are picked first and that picks follow
+    /// changes in availability.
+    #[test]
+    fn should_pick_rarest_pieces_first() {
+        let piece_count = 6;
+        let mut piece_picker = RarestFirstPicker::empty(piece_count);
+        let all = Bitfield::repeat(true, piece_count);
+
+        // all peers have all pieces but pieces 2 and 4, which only one peer
+        // has
+        let mut pieces = Bitfield::repeat(true, piece_count);
+        piece_picker.register_peer_pieces(&pieces);
+        pieces.set(2, false);
+        pieces.set(4, false);
+        piece_picker.register_peer_pieces(&pieces);
+        piece_picker.register_peer_pieces(&pieces);
+
+        let mut picks = HashSet::new();
+        picks.insert(piece_picker.pick_piece(&all).unwrap());
+        picks.insert(piece_picker.pick_piece(&all).unwrap());
+        let expected: HashSet<_> = [2, 4].iter().copied().collect();
+        assert_eq!(picks, expected);
+
+        // piece 5 becomes more available while piece 0 loses peers
+        piece_picker.register_peer_piece(5);
+        piece_picker.unregister_peer_pieces(&pieces);
+        let mut pieces = Bitfield::repeat(false, piece_count);
+        pieces.set(0, true);
+        piece_picker.unregister_peer_pieces(&pieces);
+        assert_eq!(piece_picker.availability(0), 1);
+        assert_eq!(piece_picker.pick_piece(&all), Some(0));
+
+        // the remaining pieces 1 and 3 are equally rare and piece 5 is picked
+        // last
+        let mut picks = HashSet::new();
+        picks.insert(piece_picker.pick_piece(&all).unwrap());
+        picks.insert(piece_picker.pick_piece(&all).unwrap());
+        let expected: HashSet<_> = [1, 3].iter().copied().collect();
+        assert_eq!(picks, expected);
+        assert_eq!(piece_picker.pick_piece(&all), Some(5));
+        assert_eq!(piece_picker.pick_piece(&all), None);
+    }
+
+    /// Tests that the first pieces are picked regardless of their
+    /// availability, after which picking switches to rarest-first.
+    #[test]
+    fn should_pick_random_pieces_first() {
+        let piece_count = 20;
+        let mut piece_picker = RarestFirstPicker::empty(piece_count);
+        let all = Bitfield::repeat(true, piece_count);
+        piece_picker.set_random_first_count(2);
+
+        // only the last piece is rare
+        piece_picker.register_peer_pieces(&all);
+        let mut common = Bitfield::repeat(true, piece_count);
+        common.set(piece_count - 1, false);
+        piece_picker.register_peer_pieces(&common);
+
+        // A common piece is picked with a 19 in 20 chance when picking at
+        // random, and never when picking rarest-first, so retry the pick
+        // a few times to rule out bad luck.
+        let mut picked_common = false;
+        for _ in 0..100 {
+            let pick = piece_picker.pick_piece(&all).unwrap();
+            if pick != piece_count - 1 {
+                picked_common = true;
+                break;
+            }
+            // reset pick
+            piece_picker.set_pending(pick, false);
+        }
+        assert!(picked_common);
+
+        // once we have enough pieces, the rarest piece is picked
+        piece_picker.received_piece(0);
+        piece_picker.received_piece(1);
+        assert_eq!(piece_picker.pick_piece(&all), Some(piece_count - 1));
+    }
+
+    /// Tests that in sequential mode pieces are picked in order, within the
+    /// lookahead window.
+    #[test]
+    fn should_pick_pieces_sequentially() {
+        let piece_count = 10;
+        let mut piece_picker = RarestFirstPicker::empty(piece_count);
+        let all = Bitfield::repeat(true, piece_count);
+        piece_picker.set_sequential(Some(3));
+
+        // make the last pieces the rarest to make sure availability is not
+        // taken into account
+        let pieces = Bitfield::repeat(true, piece_count);
+        piece_picker.register_peer_pieces(&pieces);
+        piece_picker.register_peer_pieces(&pieces);
+        piece_picker.unregister_peer_pieces(&{
+            let mut pieces = Bitfield::repeat(false, piece_count);
+            pieces.set(8, true);
+            pieces.set(9, true);
+            pieces
+        });
+
+        assert_eq!(piece_picker.pick_piece(&all), Some(0));
+        assert_eq!(piece_picker.pick_piece(&all), Some(1));
+        assert_eq!(piece_picker.pick_piece(&all), Some(2));
+        // the window is exhausted until the first piece is received
+        assert_eq!(piece_picker.pick_piece(&all), None);
+
+        // receiving a piece other than the first doesn't move the window
+        piece_picker.received_piece(1);
+        assert_eq!(piece_picker.pick_piece(&all), None);
+
+        // but receiving the first piece does
+        piece_picker.received_piece(0);
+        assert_eq!(piece_picker.pick_piece(&all), Some(3));
+        assert_eq!(piece_picker.pick_piece(&all), Some(4));
+        assert_eq!(piece_picker.pick_piece(&all), None);
+
+        // switching back to rarest-first picks the rarest pieces
+        piece_picker.set_sequential(None);
+        let mut picks = HashSet::new();
+        picks.insert(piece_picker.pick_piece(&all).unwrap());
+        picks.insert(piece_picker.pick_piece(&all).unwrap());
+        let expected: HashSet<_> = [8, 9].iter().copied().collect();
+        assert_eq!(picks, expected);
+    }
+
+    /// Tests that only pieces the peer has are picked.
+    #[test]
+    fn should_only_pick_peer_pieces() {
+        let piece_count = 4;
+        let mut piece_picker = RarestFirstPicker::empty(piece_count);
+        let mut pieces = Bitfield::repeat(false, piece_count);
+        pieces.set(3, true);
+        piece_picker.register_peer_pieces(&pieces);
+        assert_eq!(piece_picker.pick_piece(&pieces), Some(3));
+        assert_eq!(piece_picker.pick_piece(&pieces), None);
+    }
+
+    /// Tests that higher priority pieces are picked first, and that skipped
+    /// pieces are not picked, in both picking modes.
+    #[test]
+    fn should_pick_pieces_by_priority() {
+        let piece_count = 6;
+        for sequential in [false, true].iter() {
+            let mut piece_picker = RarestFirstPicker::empty(piece_count);
+            let all = Bitfield::repeat(true, piece_count);
+            piece_picker.register_peer_pieces(&all);
+            if *sequential {
+                piece_picker.set_sequential(Some(piece_count));
+            }
+            piece_picker.set_piece_priority(0, FilePriority::Skip);
+            piece_picker.set_piece_priority(1, FilePriority::Low);
+            piece_picker.set_piece_priority(4, FilePriority::High);
+            piece_picker.set_piece_priority(5, FilePriority::Skip);
+
+            assert_eq!(piece_picker.pick_piece(&all), Some(4));
+            let mut picks = HashSet::new();
+            picks.insert(piece_picker.pick_piece(&all).unwrap());
+            picks.insert(piece_picker.pick_piece(&all).unwrap());
+            let expected: HashSet<_> = [2, 3].iter().copied().collect();
+            assert_eq!(picks, expected);
+            assert_eq!(piece_picker.pick_piece(&all), Some(1));
+            assert_eq!(piece_picker.pick_piece(&all), None);
+        }
+    }
+
+    /// Tests that boosted pieces are picked before other pieces of the same
+    /// priority, but not before higher priority pieces.
+    #[test]
+    fn should_pick_boosted_pieces_first() {
+        let piece_count = 6;
+        for sequential in [false, true].iter() {
+            let mut piece_picker = RarestFirstPicker::empty(piece_count);
+            let all = Bitfield::repeat(true, piece_count);
+            piece_picker.register_peer_pieces(&all);
+            if *sequential {
+                piece_picker.set_sequential(Some(piece_count));
+            }
+            piece_picker.set_piece_priority(1, FilePriority::High);
+            piece_picker.set_piece_boost(0, true);
+            piece_picker.set_piece_boost(5, true);
+            // boosting a skipped piece doesn't make it pickable
+            piece_picker.set_piece_priority(3, FilePriority::Skip);
+            piece_picker.set_piece_boost(3, true);
+
+            assert_eq!(piece_picker.pick_piece(&all), Some(1));
+            let mut picks = HashSet::new();
+            picks.insert(piece_picker.pick_piece(&all).unwrap());
+            picks.insert(piece_picker.pick_piece(&all).unwrap());
+            let expected: HashSet<_> = [0, 5].iter().copied().collect();
+            assert_eq!(picks, expected);
+            let mut picks = HashSet::new();
+            picks.insert(piece_picker.pick_piece(&all).unwrap());
+            picks.insert(piece_picker.pick_piece(&all).unwrap());
+            let expected: HashSet<_> = [2, 4].iter().copied().collect();
+            assert_eq!(picks, expected);
+            assert_eq!(piece_picker.pick_piece(&all), None);
+        }
+    }
+
+    /// Tests that pieces with deadlines are picked first, in the order of
+    /// their deadlines, and that they are reported at risk once they are being
+    /// downloaded and their deadline approaches.
+    #[test]
+    fn should_pick_pieces_with_deadlines_first() {
+        let piece_count = 10;
+        let mut piece_picker = RarestFirstPicker::empty(piece_count);
+        let all = Bitfield::repeat(true, piece_count);
+        piece_picker.register_peer_pieces(&all);
+        piece_picker.set_sequential(Some(piece_count));
+
+        let now = Instant::now();
+        let soon = now + Duration::from_secs(1);
+        let later = now + Duration::from_secs(10);
+        piece_picker.set_piece_deadline(7, Some(later));
+        piece_picker.set_piece_deadline(5, Some(soon));
+        piece_picker.set_piece_deadline(9, Some(later));
+        piece_picker.set_piece_deadline(9, None);
+
+        assert_eq!(piece_picker.pick_piece(&all), Some(5));
+        assert_eq!(piece_picker.pick_piece(&all), Some(7));
+        // continue with the regular order
+        assert_eq!(piece_picker.pick_piece(&all), Some(0));
+
+        // only the piece due soon is at risk
+        let threshold = now + Duration::from_secs(2);
+        assert_eq!(piece_picker.pieces_at_risk(threshold), vec![5]);
+        let threshold = now + Duration::from_secs(20);
+        assert_eq!(piece_picker.pieces_at_risk(threshold), vec![5, 7]);
+
+        // the deadline is cleared when the piece is received
+        piece_picker.received_piece(5);
+        assert_eq!(piece_picker.pieces_at_risk(threshold), vec![7]);
+
+        // deadlines of pieces we have are ignored
+        piece_picker.set_piece_deadline(5, Some(soon));
+        assert_eq!(piece_picker.pieces_at_risk(threshold), vec![7]);
+    }
+
+    /// Tests that skipped pieces are not counted as wanted and that they don't
+    /// make us interested in peers.
+    #[test]
+    fn should_count_wanted_pieces() {
+        let piece_count = 4;
+        let mut piece_picker = RarestFirstPicker::empty(piece_count);
+        let all = Bitfield::repeat(true, piece_count);
+        piece_picker.register_peer_pieces(&all);
+
+        piece_picker.set_piece_priority(0, FilePriority::Skip);
+        piece_picker.set_piece_priority(1, FilePriority::Skip);
+        assert_eq!(piece_picker.wanted_piece_count(), 2);
+        assert_eq!(piece_picker.wanted_missing_piece_count(), 2);
+        assert!(!piece_picker.register_peer_piece(0));
+        assert!(piece_picker.register_peer_piece(2));
+
+        // all wanted pieces are picked and downloaded
+        for _ in 0..2 {
+            let pick = piece_picker.pick_piece(&all).unwrap();
+            piece_picker.received_piece(pick);
+        }
+        assert_eq!(piece_picker.wanted_missing_piece_count(), 0);
+        assert_eq!(piece_picker.missing_piece_count(), 2);
+        assert!(piece_picker.all_pieces_picked());
+        assert_eq!(piece_picker.pick_piece(&all), None);
+
+        // a peer that only has skipped pieces is not interesting
+        let mut skipped = Bitfield::repeat(false, piece_count);
+        skipped.set(0, true);
+        assert!(!piece_picker.register_peer_pieces(&skipped));
+
+        // unskipping a piece makes it wanted again
+        piece_picker.set_piece_priority(1, FilePriority::Low);
+        assert_eq!(piece_picker.wanted_piece_count(), 3);
+        assert_eq!(piece_picker.wanted_missing_piece_count(), 1);
+        assert!(!piece_picker.all_pieces_picked());
+        assert_eq!(piece_picker.pick_piece(&all), Some(1));
+    }
+
+    /// Tests the distributed copies calculation as peers join and leave.
+    #[test]
+    fn should_count_distributed_copies() {
+        let piece_count = 4;
+        let mut piece_picker = RarestFirstPicker::empty(piece_count);
+        assert_eq!(piece_picker.distributed_copies(), 0.0);
+
+        // a peer with half the pieces
+        let mut half = Bitfield::repeat(false, piece_count);
+        half.set(0, true);
+        half.set(1, true);
+        piece_picker.register_peer_pieces(&half);
+        assert_eq!(piece_picker.distributed_copies(), 0.5);
+
+        // a seed
+        let all = Bitfield::repeat(true, piece_count);
+        piece_picker.register_peer_pieces(&all);
+        assert_eq!(piece_picker.distributed_copies(), 1.5);
+
+        // and another peer with a single piece
+        piece_picker.register_peer_piece(3);
+        assert_eq!(piece_picker.distributed_copies(), 1.75);
+
+        // the seed leaves
+        piece_picker.unregister_peer_pieces(&all);
+        assert_eq!(piece_picker.distributed_copies(), 0.75);
+    }
+
     /// Tests that the piece picker correctly determines whether we are
     /// interested in a variety of piece sets.
     // TODO: break this up into smaller tests
@@ -319,7 +1317,7 @@ mod tests {
     fn should_determine_interest() {
         // empty piece picker
         let piece_count = 15;
-        let mut piece_picker = PiecePicker::empty(piece_count);
+        let mut piece_picker = RarestFirstPicker::empty(piece_count);
 
         // we are interested if peer has all pieces
         let available_pieces = Bitfield::repeat(true, piece_count);
@@ -332,7 +1330,7 @@ mod tests {
 
         // half full piece picker
         let piece_count = 15;
-        let mut piece_picker = PiecePicker::empty(piece_count);
+        let mut piece_picker = RarestFirstPicker::empty(piece_count);
         for index in 0..8 {
             piece_picker.received_piece(index);
         }
@@ -353,7 +1351,7 @@ mod tests {
 
         // full piece picker
         let piece_count = 15;
-        let mut piece_picker = PiecePicker::empty(piece_count);
+        let mut piece_picker = RarestFirstPicker::empty(piece_count);
         for index in 0..piece_count {
             piece_picker.received_piece(index);
         }
@@ -362,7 +1360,76 @@ mod tests {
         assert!(!piece_picker.register_peer_pieces(&available_pieces));
     }
 
-    impl PiecePicker {
+    /// Tests that the free piece buckets stay consistent with the pieces'
+    /// state through availability, priority, and pick changes.
+    #[test]
+    fn should_keep_buckets_consistent() {
+        let piece_count = 50;
+        let mut piece_picker = RarestFirstPicker::empty(piece_count);
+        let mut rng = rand::thread_rng();
+        let mut peers = Vec::new();
+        for i in 0..200 {
+            let mut pieces = Bitfield::repeat(false, piece_count);
+            for index in 0..piece_count {
+                pieces.set(index, rng.gen_range(0, 2) == 0);
+            }
+            match i % 5 {
+                0 if !peers.is_empty() => {
+                    let pieces = peers.swap_remove(0);
+                    piece_picker.unregister_peer_pieces(&pieces);
+                }
+                1 => {
+                    let index = rng.gen_range(0, piece_count);
+                    let priority = match rng.gen_range(0, 4) {
+                        0 => FilePriority::Skip,
+                        1 => FilePriority::Low,
+                        2 => FilePriority::Normal,
+                        _ => FilePriority::High,
+                    };
+                    piece_picker.set_piece_priority(index, priority);
+                    piece_picker
+                        .set_piece_boost(index, rng.gen_range(0, 2) == 0);
+                }
+                2 => {
+                    if let Some(index) = piece_picker.pick_piece(&pieces) {
+                        if rng.gen_range(0, 2) == 0 {
+                            piece_picker.received_piece(index);
+                        }
+                    }
+                }
+                _ => {
+                    piece_picker.register_peer_pieces(&pieces);
+                    peers.push(pieces);
+                }
+            }
+
+            // every free piece is in the bucket matching its state, at the
+            // recorded position
+            let mut free_count = 0;
+            for (key, bucket) in piece_picker.buckets.iter() {
+                assert!(!bucket.is_empty());
+                for (pos, index) in bucket.iter().enumerate() {
+                    assert_eq!(piece_picker.pieces[*index].bucket_key(), *key);
+                    assert_eq!(
+                        piece_picker.bucket_positions[*index],
+                        pos as u32
+                    );
+                }
+                free_count += bucket.len();
+            }
+            let expected_free_count = (0..piece_count)
+                .filter(|index| {
+                    !piece_picker.own_pieces[*index]
+                        && !piece_picker.pending_pieces[*index]
+                        && piece_picker.is_wanted(*index)
+                })
+                .count();
+            assert_eq!(free_count, expected_free_count);
+            assert_eq!(piece_picker.free_count, expected_free_count);
+        }
+    }
+
+    impl RarestFirstPicker {
         fn empty(piece_count: usize) -> Self {
             Self::new(Bitfield::repeat(false, piece_count))
         }
@@ -0,0 +1,507 @@
+//! Port mapping on the local network's gateway.
+//!
+//! When the engine is behind a NAT, peers and DHT nodes on the internet can't
+//! connect to it unless the gateway forwards our listening ports to us. Most
+//! consumer gateways let applications set up such forwarding themselves, via
+//! UPnP IGD or via NAT-PMP and its successor PCP, which the engine tries in
+//! parallel.
+//!
+//! The engine runs a single port mapper on its own task, to which torrents
+//! add their listening port once they're bound, and from which they remove it
+//! when they stop. Mappings are leased for a limited time and renewed before
+//! they expire, and are deleted when the engine shuts down. The outcome of
+//! each mapping is posted as an alert.
+//!
+//! Only IPv4 is supported.
+
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use futures::{select, stream::StreamExt};
+use reqwest::Client;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tracing::Instrument;
+
+use crate::{
+    alert::{Alert, AlertSender},
+    conf::PortMappingConf,
+    error::*,
+    logging, rt,
+};
+
+mod natpmp;
+mod upnp;
+
+/// A mapping that failed, or a gateway that wasn't found, is retried this
+/// often.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Mappings are renewed at most this often, even if the gateway grants
+/// shorter, or permanent, leases.
+const MIN_RENEWAL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The timeout of HTTP requests to UPnP gateways, which are on the local
+/// network and so should answer quickly.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The transport protocol of a mapped port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    /// Returns the protocol's number, as used by PCP.
+    fn iana_number(self) -> u8 {
+        match self {
+            Self::Tcp => 6,
+            Self::Udp => 17,
+        }
+    }
+
+    /// Returns the opcode of NAT-PMP mapping requests of the protocol.
+    fn natpmp_opcode(self) -> u8 {
+        match self {
+            Self::Tcp => 2,
+            Self::Udp => 1,
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp => write!(fmt, "TCP"),
+            Self::Udp => write!(fmt, "UDP"),
+        }
+    }
+}
+
+/// The protocol with which a port is mapped on the gateway.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub enum Method {
+    /// UPnP Internet Gateway Device.
+    Upnp,
+    /// NAT-PMP, or PCP if the gateway supports it.
+    NatPmp,
+}
+
+/// Spawns the port mapper on a new task.
+pub(crate) fn spawn(
+    conf: PortMappingConf,
+    alert_tx: AlertSender,
+) -> Result<(JoinHandle, Sender)> {
+    log::info!(target: logging::ENGINE, "Spawning port mapping task");
+    // requests to the gateway must not go through any proxy configured in
+    // the environment
+    let http_client =
+        Client::builder().no_proxy().timeout(HTTP_TIMEOUT).build()?;
+    let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+    let mut port_mapper = PortMapper::new(conf, http_client, alert_tx);
+    let join_handle = rt::spawn(
+        async move { port_mapper.run(cmd_rx).await }
+            .instrument(tracing::info_span!("port_mapping")),
+    );
+    log::info!(target: logging::ENGINE, "Spawned port mapping task");
+
+    Ok((join_handle, cmd_tx))
+}
+
+pub(crate) type JoinHandle = rt::JoinHandle<Result<()>>;
+
+/// The channel for sending commands to the port mapping task.
+pub(crate) type Sender = UnboundedSender<Command>;
+/// The channel on which the port mapping task listens for commands.
+type Receiver = UnboundedReceiver<Command>;
+
+/// The commands the port mapping task can receive.
+#[derive(Debug)]
+pub(crate) enum Command {
+    /// Maps the port with each enabled method, and keeps it mapped until it's
+    /// removed.
+    AddPort { protocol: Protocol, port: u16 },
+    /// Deletes the mappings of the port.
+    RemovePort { protocol: Protocol, port: u16 },
+    /// Deletes all mappings and shuts down the task.
+    Shutdown,
+}
+
+/// The state of a port's mapping with one method.
+struct Mapping {
+    /// The external port and address the gateway reported, if the port is
+    /// mapped.
+    external: Option<(u16, Option<IpAddr>)>,
+    /// The port is mapped, or its mapping renewed, at this time.
+    next_attempt_time: Instant,
+}
+
+/// The gateway of a method, which has to be found before mapping ports.
+enum Gateway<T> {
+    /// Not looked for yet, or not found at the given time.
+    Unknown {
+        last_attempt_time: Option<Instant>,
+    },
+    Found(T),
+}
+
+impl<T> Gateway<T> {
+    fn is_lookup_due(&self, now: Instant) -> bool {
+        match self {
+            Self::Unknown { last_attempt_time } => last_attempt_time
+                .map_or(true, |t| {
+                    now.saturating_duration_since(t) >= RETRY_INTERVAL
+                }),
+            Self::Found(_) => false,
+        }
+    }
+}
+
+struct PortMapper {
+    /// The ports to map, by method.
+    mappings: HashMap<(Method, Protocol, u16), Mapping>,
+    upnp: Gateway<upnp::Gateway>,
+    natpmp: Gateway<natpmp::Client>,
+    http_client: Client,
+    alert_tx: AlertSender,
+    conf: PortMappingConf,
+}
+
+impl PortMapper {
+    fn new(
+        conf: PortMappingConf,
+        http_client: Client,
+        alert_tx: AlertSender,
+    ) -> Self {
+        Self {
+            mappings: HashMap::new(),
+            upnp: Gateway::Unknown {
+                last_attempt_time: None,
+            },
+            natpmp: Gateway::Unknown {
+                last_attempt_time: None,
+            },
+            http_client,
+            alert_tx,
+            conf,
+        }
+    }
+
+    /// Runs the port mapper until it's shut down.
+    async fn run(&mut self, cmd_rx: Receiver) -> Result<()> {
+        let mut tick_timer = rt::interval(Duration::from_secs(1)).fuse();
+        let mut cmd_rx = cmd_rx.fuse();
+
+        loop {
+            select! {
+                tick_time = tick_timer.select_next_some() => {
+                    self.tick(tick_time).await;
+                }
+                cmd = cmd_rx.select_next_some() => match cmd {
+                    Command::AddPort { protocol, port } => {
+                        self.add_port(protocol, port, Instant::now());
+                    }
+                    Command::RemovePort { protocol, port } => {
+                        self.remove_port(protocol, port).await;
+                    }
+                    Command::Shutdown => {
+                        log::info!(
+                            target: logging::ENGINE,
+                            "Shutting down port mapper"
+                        );
+                        break;
+                    }
+                },
+            }
+        }
+
+        // the mappings would expire on their own, but the ports may be
+        // reused by other applications in the meantime
+        let mapped: Vec<_> = self
+            .mappings
+            .iter()
+            .filter(|(_, mapping)| mapping.external.is_some())
+            .map(|(key, _)| *key)
+            .collect();
+        for (method, protocol, port) in mapped {
+            self.unmap(method, protocol, port).await;
+        }
+
+        Ok(())
+    }
+
+    /// Starts mapping the port with the enabled methods. The port is mapped
+    /// on the next tick, as the gateways may have to be found first.
+    fn add_port(&mut self, protocol: Protocol, port: u16, now: Instant) {
+        log::info!(
+            target: logging::ENGINE,
+            "Mapping {} port {}",
+            protocol,
+            port
+        );
+        let methods = [
+            (Method::Upnp, self.conf.upnp),
+            (Method::NatPmp, self.conf.natpmp),
+        ];
+        for &(method, is_enabled) in methods.iter() {
+            if is_enabled {
+                self.mappings.insert(
+                    (method, protocol, port),
+                    Mapping {
+                        external: None,
+                        next_attempt_time: now,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Stops mapping the port, deleting its mappings from the gateways.
+    async fn remove_port(&mut self, protocol: Protocol, port: u16) {
+        log::info!(
+            target: logging::ENGINE,
+            "Unmapping {} port {}",
+            protocol,
+            port
+        );
+        for &method in [Method::Upnp, Method::NatPmp].iter() {
+            if let Some(mapping) =
+                self.mappings.remove(&(method, protocol, port))
+            {
+                if mapping.external.is_some() {
+                    self.unmap(method, protocol, port).await;
+                }
+            }
+        }
+    }
+
+    /// Looks for the gateways that are needed and not yet found, and maps
+    /// the ports that are due.
+    async fn tick(&mut self, now: Instant) {
+        let has_method = |method| self.mappings.keys().any(|k| k.0 == method);
+        let is_upnp_lookup_due =
+            has_method(Method::Upnp) && self.upnp.is_lookup_due(now);
+        let is_natpmp_lookup_due =
+            has_method(Method::NatPmp) && self.natpmp.is_lookup_due(now);
+        if is_upnp_lookup_due {
+            self.find_upnp_gateway(now).await;
+        }
+        if is_natpmp_lookup_due {
+            self.find_natpmp_gateway(now);
+        }
+
+        let due: Vec<_> = self
+            .mappings
+            .iter()
+            .filter(|(_, mapping)| mapping.next_attempt_time <= now)
+            .map(|(key, _)| *key)
+            .collect();
+        for (method, protocol, port) in due {
+            self.map(method, protocol, port, now).await;
+        }
+    }
+
+    async fn find_upnp_gateway(&mut self, now: Instant) {
+        log::debug!(target: logging::ENGINE, "Looking for UPnP gateway");
+        match upnp::discover(self.http_client.clone()).await {
+            Ok(gateway) => {
+                log::info!(target: logging::ENGINE, "Found UPnP gateway");
+                self.upnp = Gateway::Found(gateway);
+            }
+            Err(e) => {
+                log::warn!(
+                    target: logging::ENGINE,
+                    "Error looking for UPnP gateway: {}",
+                    e
+                );
+                self.upnp = Gateway::Unknown {
+                    last_attempt_time: Some(now),
+                };
+                self.post_gateway_error(Method::Upnp, &e, now);
+            }
+        }
+    }
+
+    fn find_natpmp_gateway(&mut self, now: Instant) {
+        let gateway = match self.conf.gateway {
+            Some(gateway) => Ok(gateway),
+            None => natpmp::default_gateway(),
+        };
+        match gateway {
+            Ok(gateway) => {
+                log::info!(
+                    target: logging::ENGINE,
+                    "Using NAT-PMP gateway {}",
+                    gateway
+                );
+                self.natpmp = Gateway::Found(natpmp::Client::new(gateway));
+            }
+            Err(e) => {
+                log::warn!(
+                    target: logging::ENGINE,
+                    "Error looking up default gateway: {}",
+                    e
+                );
+                self.natpmp = Gateway::Unknown {
+                    last_attempt_time: Some(now),
+                };
+                self.post_gateway_error(Method::NatPmp, &e, now);
+            }
+        }
+    }
+
+    /// Posts the error of finding the method's gateway for each port to be
+    /// mapped with it, postponing their mapping until the gateway is looked
+    /// for again.
+    fn post_gateway_error(
+        &mut self,
+        method: Method,
+        e: &io::Error,
+        now: Instant,
+    ) {
+        for ((m, protocol, port), mapping) in self.mappings.iter_mut() {
+            if *m != method {
+                continue;
+            }
+            mapping.next_attempt_time = now + RETRY_INTERVAL;
+            self.alert_tx
+                .send(Alert::PortMappingError {
+                    method,
+                    protocol: *protocol,
+                    port: *port,
+                    error: io::Error::new(e.kind(), e.to_string()),
+                })
+                .ok();
+        }
+    }
+
+    /// Maps or renews the mapping of the port, if the method's gateway is
+    /// known.
+    async fn map(
+        &mut self,
+        method: Method,
+        protocol: Protocol,
+        port: u16,
+        now: Instant,
+    ) {
+        let lease_duration = self.conf.lease_duration;
+        let result = match (method, &mut self.upnp, &mut self.natpmp) {
+            (Method::Upnp, Gateway::Found(gateway), _) => {
+                let result =
+                    gateway.add_mapping(protocol, port, lease_duration).await;
+                match result {
+                    // the external address is not returned with the mapping
+                    Ok(()) => match gateway.external_ip().await {
+                        Ok(ip) => Ok((port, Some(ip), lease_duration)),
+                        Err(e) => {
+                            log::debug!(
+                                target: logging::ENGINE,
+                                "Error getting external address: {}",
+                                e
+                            );
+                            Ok((port, None, lease_duration))
+                        }
+                    },
+                    Err(e) => Err(e),
+                }
+            }
+            (Method::NatPmp, _, Gateway::Found(client)) => client
+                .map(protocol, port, lease_duration)
+                .await
+                .map(|m| (m.external_port, m.external_ip, m.lifetime)),
+            // the gateway is still being looked for
+            _ => return,
+        };
+
+        let mapping = match self.mappings.get_mut(&(method, protocol, port)) {
+            Some(mapping) => mapping,
+            None => return,
+        };
+        match result {
+            Ok((external_port, external_ip, lifetime)) => {
+                // the mapping is renewed at half its lifetime, so that it
+                // doesn't expire if a renewal is lost
+                mapping.next_attempt_time =
+                    now + (lifetime / 2).max(MIN_RENEWAL_INTERVAL);
+                let external = Some((external_port, external_ip));
+                if mapping.external != external {
+                    log::info!(
+                        target: logging::ENGINE,
+                        "Mapped {} port {} to {} with {:?}",
+                        protocol,
+                        port,
+                        external_port,
+                        method
+                    );
+                    mapping.external = external;
+                    self.alert_tx
+                        .send(Alert::PortMapped {
+                            method,
+                            protocol,
+                            port,
+                            external_port,
+                            external_ip,
+                        })
+                        .ok();
+                }
+            }
+            Err(e) => {
+                log::warn!(
+                    target: logging::ENGINE,
+                    "Error mapping {} port {} with {:?}: {}",
+                    protocol,
+                    port,
+                    method,
+                    e
+                );
+                mapping.next_attempt_time = now + RETRY_INTERVAL;
+                mapping.external = None;
+                self.alert_tx
+                    .send(Alert::PortMappingError {
+                        method,
+                        protocol,
+                        port,
+                        error: e,
+                    })
+                    .ok();
+            }
+        }
+    }
+
+    /// Deletes the mapping of the port from the method's gateway.
+    async fn unmap(&mut self, method: Method, protocol: Protocol, port: u16) {
+        let result = match (method, &mut self.upnp, &mut self.natpmp) {
+            (Method::Upnp, Gateway::Found(gateway), _) => {
+                gateway.delete_mapping(protocol, port).await
+            }
+            (Method::NatPmp, _, Gateway::Found(client)) => {
+                client.unmap(protocol, port).await
+            }
+            _ => return,
+        };
+        if let Err(e) = result {
+            log::warn!(
+                target: logging::ENGINE,
+                "Error unmapping {} port {} with {:?}: {}",
+                protocol,
+                port,
+                method,
+                e
+            );
+        }
+    }
+}
@@ -0,0 +1,484 @@
+//! Port mapping with NAT-PMP, as specified in
+//! [RFC 6886](https://tools.ietf.org/html/rfc6886), and its successor PCP, as
+//! specified in [RFC 6887](https://tools.ietf.org/html/rfc6887).
+//!
+//! Both protocols send requests to the gateway's port 5351 over UDP, which
+//! the gateway answers with the mapped external port and the lifetime of the
+//! mapping. Gateways that support PCP also answer NAT-PMP requests, but older
+//! gateways only support NAT-PMP, and answer PCP requests with an unsupported
+//! version error in the NAT-PMP format. So a PCP request is sent first, and if
+//! the gateway responds that way, NAT-PMP is used from then on.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut};
+use tokio::net::UdpSocket;
+
+use super::Protocol;
+use crate::{logging, rt};
+
+/// The port on which the gateway listens for requests.
+const SERVER_PORT: u16 = 5351;
+
+const NATPMP_VERSION: u8 = 0;
+const PCP_VERSION: u8 = 2;
+
+/// The opcode of NAT-PMP requests for the gateway's external address.
+const NATPMP_OP_EXTERNAL_ADDR: u8 = 0;
+/// The opcode of PCP mapping requests.
+const PCP_OP_MAP: u8 = 1;
+/// Set in the opcode of responses.
+const RESPONSE_BIT: u8 = 0x80;
+
+/// The result code with which NAT-PMP gateways answer PCP requests.
+const UNSUPPORTED_VERSION: u16 = 1;
+
+/// The length of the nonce that identifies a PCP mapping.
+const NONCE_LEN: usize = 12;
+
+/// A request that isn't answered in this time is resent, and the timeout is
+/// doubled each time, as recommended by the RFCs.
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// The number of times a request is sent before giving up. The RFCs allow for
+/// more attempts, but a gateway that doesn't answer within a few seconds is
+/// unlikely to support the protocol.
+const MAX_ATTEMPT_COUNT: u32 = 4;
+
+/// A port mapped on the gateway.
+#[derive(Debug, PartialEq)]
+pub(super) struct Mapping {
+    /// The port the gateway forwards to our port, which may differ from it.
+    pub external_port: u16,
+    /// The gateway's external address, if it reported it.
+    pub external_ip: Option<IpAddr>,
+    /// The mapping expires after this long unless it's renewed.
+    pub lifetime: Duration,
+}
+
+/// The client that maps ports on a single gateway.
+pub(super) struct Client {
+    gateway: Ipv4Addr,
+    /// Set once the gateway answered a PCP request with an unsupported
+    /// version error.
+    is_natpmp_only: bool,
+    /// The nonces of our PCP mappings, which must be sent again to renew or
+    /// delete them.
+    nonces: HashMap<(Protocol, u16), [u8; NONCE_LEN]>,
+}
+
+impl Client {
+    pub fn new(gateway: Ipv4Addr) -> Self {
+        Self {
+            gateway,
+            is_natpmp_only: false,
+            nonces: HashMap::new(),
+        }
+    }
+
+    /// Maps the port on the gateway for the requested lifetime, asking for
+    /// the same external port.
+    pub async fn map(
+        &mut self,
+        protocol: Protocol,
+        port: u16,
+        lifetime: Duration,
+    ) -> io::Result<Mapping> {
+        let lifetime = lifetime.as_secs().min(u32::MAX as u64) as u32;
+        if !self.is_natpmp_only {
+            match self.map_pcp(protocol, port, lifetime).await? {
+                Some(mapping) => return Ok(mapping),
+                None => {
+                    log::info!(
+                        target: logging::ENGINE,
+                        "Gateway {} doesn't support PCP, using NAT-PMP",
+                        self.gateway
+                    );
+                    self.is_natpmp_only = true;
+                }
+            }
+        }
+        self.map_natpmp(protocol, port, lifetime).await
+    }
+
+    /// Deletes the mapping of the port from the gateway.
+    pub async fn unmap(
+        &mut self,
+        protocol: Protocol,
+        port: u16,
+    ) -> io::Result<()> {
+        // a mapping is deleted by requesting a lifetime of 0
+        if self.is_natpmp_only {
+            self.map_natpmp(protocol, port, 0).await?;
+        } else {
+            self.map_pcp(protocol, port, 0).await?;
+        }
+        self.nonces.remove(&(protocol, port));
+        Ok(())
+    }
+
+    /// Sends a PCP mapping request, returning `None` if the gateway only
+    /// supports NAT-PMP.
+    async fn map_pcp(
+        &mut self,
+        protocol: Protocol,
+        port: u16,
+        lifetime: u32,
+    ) -> io::Result<Option<Mapping>> {
+        let nonce = *self
+            .nonces
+            .entry((protocol, port))
+            .or_insert_with(rand::random);
+        let mut socket = self.connect().await?;
+        // the gateway checks that the address in the request is the source
+        // address of the request, so it must be that of the interface
+        // through which the gateway is reached
+        let client_ip = match socket.local_addr()?.ip() {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(_) => unreachable!("socket should be bound to IPv4"),
+        };
+        let req = encode_pcp_map(client_ip, &nonce, protocol, port, lifetime);
+        let resp = exchange(&mut socket, &req).await?;
+        decode_pcp_map(&resp, &nonce)
+    }
+
+    /// Sends a NAT-PMP mapping request, followed by a request for the
+    /// gateway's external address if the port was mapped.
+    async fn map_natpmp(
+        &mut self,
+        protocol: Protocol,
+        port: u16,
+        lifetime: u32,
+    ) -> io::Result<Mapping> {
+        let mut socket = self.connect().await?;
+        let req = encode_natpmp_map(protocol, port, lifetime);
+        let resp = exchange(&mut socket, &req).await?;
+        let mut mapping = decode_natpmp_map(&resp, protocol)?;
+        if lifetime > 0 {
+            let req = [NATPMP_VERSION, NATPMP_OP_EXTERNAL_ADDR];
+            let resp = exchange(&mut socket, &req).await?;
+            match decode_natpmp_external_addr(&resp) {
+                Ok(ip) => mapping.external_ip = Some(ip.into()),
+                Err(e) => {
+                    log::debug!(
+                        target: logging::ENGINE,
+                        "Error getting external address: {}",
+                        e
+                    )
+                }
+            }
+        }
+        Ok(mapping)
+    }
+
+    /// Creates a socket connected to the gateway, so that only its datagrams
+    /// are received.
+    async fn connect(&self) -> io::Result<UdpSocket> {
+        let socket =
+            UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+                .await?;
+        socket
+            .connect(SocketAddr::from((self.gateway, SERVER_PORT)))
+            .await?;
+        Ok(socket)
+    }
+}
+
+/// Sends the request to the gateway until it's answered, retransmitting it
+/// with a growing timeout, and returns the response.
+async fn exchange(socket: &mut UdpSocket, req: &[u8]) -> io::Result<Vec<u8>> {
+    let mut buf = [0; 1100];
+    for n in 0..MAX_ATTEMPT_COUNT {
+        socket.send(req).await?;
+        let timeout = INITIAL_TIMEOUT * 2u32.pow(n);
+        if let Ok(len) = rt::timeout(timeout, socket.recv(&mut buf)).await {
+            return Ok(buf[..len?].to_vec());
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "gateway didn't respond",
+    ))
+}
+
+fn encode_pcp_map(
+    client_ip: Ipv4Addr,
+    nonce: &[u8; NONCE_LEN],
+    protocol: Protocol,
+    port: u16,
+    lifetime: u32,
+) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(60);
+    // the common request header
+    buf.put_u8(PCP_VERSION);
+    buf.put_u8(PCP_OP_MAP);
+    buf.put_u16(0);
+    buf.put_u32(lifetime);
+    buf.put_slice(&client_ip.to_ipv6_mapped().octets());
+    // the MAP opcode's fields
+    buf.put_slice(nonce);
+    buf.put_u8(protocol.iana_number());
+    buf.put_slice(&[0; 3]);
+    buf.put_u16(port);
+    // we suggest the same external port, but any external address
+    buf.put_u16(port);
+    buf.put_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+    buf
+}
+
+/// Decodes the response to a PCP mapping request, returning `None` if it's
+/// a NAT-PMP unsupported version error.
+fn decode_pcp_map(
+    mut buf: &[u8],
+    nonce: &[u8; NONCE_LEN],
+) -> io::Result<Option<Mapping>> {
+    if buf.len() >= 4 && buf[0] == NATPMP_VERSION {
+        let result_code = (&buf[2..4]).get_u16();
+        if result_code == UNSUPPORTED_VERSION {
+            return Ok(None);
+        }
+    }
+    if buf.len() < 60 {
+        return Err(invalid_response("PCP response too short"));
+    }
+    let version = buf.get_u8();
+    let opcode = buf.get_u8();
+    if version != PCP_VERSION || opcode != PCP_OP_MAP | RESPONSE_BIT {
+        return Err(invalid_response("unexpected PCP response"));
+    }
+    buf.advance(1);
+    let result_code = buf.get_u8();
+    let lifetime = buf.get_u32();
+    // skip the epoch and the reserved bytes
+    buf.advance(16);
+    if result_code != 0 {
+        return Err(gateway_error("PCP", result_code.into()));
+    }
+    if &buf[..NONCE_LEN] != nonce {
+        return Err(invalid_response("PCP response nonce mismatch"));
+    }
+    // skip the nonce, the protocol, reserved bytes and the internal port
+    buf.advance(NONCE_LEN + 6);
+    let external_port = buf.get_u16();
+    let mut external_ip = [0; 16];
+    buf.copy_to_slice(&mut external_ip);
+    let external_ip = Ipv6Addr::from(external_ip);
+    Ok(Some(Mapping {
+        external_port,
+        external_ip: Some(match external_ip.to_ipv4() {
+            Some(ip) => ip.into(),
+            None => external_ip.into(),
+        }),
+        lifetime: Duration::from_secs(lifetime.into()),
+    }))
+}
+
+fn encode_natpmp_map(protocol: Protocol, port: u16, lifetime: u32) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(12);
+    buf.put_u8(NATPMP_VERSION);
+    buf.put_u8(protocol.natpmp_opcode());
+    buf.put_u16(0);
+    buf.put_u16(port);
+    // a deletion must suggest the external port 0
+    buf.put_u16(if lifetime > 0 { port } else { 0 });
+    buf.put_u32(lifetime);
+    buf
+}
+
+fn decode_natpmp_map(
+    mut buf: &[u8],
+    protocol: Protocol,
+) -> io::Result<Mapping> {
+    if buf.len() < 16 {
+        return Err(invalid_response("NAT-PMP response too short"));
+    }
+    let version = buf.get_u8();
+    let opcode = buf.get_u8();
+    if version != NATPMP_VERSION
+        || opcode != protocol.natpmp_opcode() | RESPONSE_BIT
+    {
+        return Err(invalid_response("unexpected NAT-PMP response"));
+    }
+    let result_code = buf.get_u16();
+    if result_code != 0 {
+        return Err(gateway_error("NAT-PMP", result_code));
+    }
+    // skip the epoch and the internal port
+    buf.advance(6);
+    let external_port = buf.get_u16();
+    let lifetime = buf.get_u32();
+    Ok(Mapping {
+        external_port,
+        external_ip: None,
+        lifetime: Duration::from_secs(lifetime.into()),
+    })
+}
+
+fn decode_natpmp_external_addr(mut buf: &[u8]) -> io::Result<Ipv4Addr> {
+    if buf.len() < 12 {
+        return Err(invalid_response("NAT-PMP response too short"));
+    }
+    let version = buf.get_u8();
+    let opcode = buf.get_u8();
+    if version != NATPMP_VERSION
+        || opcode != NATPMP_OP_EXTERNAL_ADDR | RESPONSE_BIT
+    {
+        return Err(invalid_response("unexpected NAT-PMP response"));
+    }
+    let result_code = buf.get_u16();
+    if result_code != 0 {
+        return Err(gateway_error("NAT-PMP", result_code));
+    }
+    buf.advance(4);
+    Ok(Ipv4Addr::from(buf.get_u32()))
+}
+
+fn invalid_response(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Returns the error for the result code with which the gateway rejected
+/// a request. The codes of the two protocols overlap, so only the common
+/// reasons are named.
+fn gateway_error(protocol: &str, result_code: u16) -> io::Error {
+    let reason = match result_code {
+        2 => "not authorized",
+        3 => "network failure",
+        4 | 8 => "out of resources",
+        _ => "request failed",
+    };
+    io::Error::new(
+        io::ErrorKind::Other,
+        format!("{} error {}: {}", protocol, result_code, reason),
+    )
+}
+
+/// Returns the IPv4 address of the default gateway, from the kernel's routing
+/// table. This is only supported on Linux.
+pub(super) fn default_gateway() -> io::Result<Ipv4Addr> {
+    let routes = fs::read_to_string("/proc/net/route")?;
+    parse_default_gateway(&routes).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, "no default gateway")
+    })
+}
+
+/// Parses the gateway of the default route from the contents of
+/// `/proc/net/route`, in which addresses are hex numbers in host byte order.
+fn parse_default_gateway(routes: &str) -> Option<Ipv4Addr> {
+    /// The flag of routes that go through a gateway.
+    const RTF_GATEWAY: u16 = 0x2;
+
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if fields.len() < 4 || fields[1] != "00000000" {
+            return None;
+        }
+        let flags = u16::from_str_radix(fields[3], 16).ok()?;
+        if flags & RTF_GATEWAY == 0 {
+            return None;
+        }
+        let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_ne_bytes()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_encode_and_decode_pcp_map() {
+        let nonce = [7; NONCE_LEN];
+        let req = encode_pcp_map(
+            Ipv4Addr::new(192, 168, 1, 2),
+            &nonce,
+            Protocol::Tcp,
+            6881,
+            3600,
+        );
+        assert_eq!(req.len(), 60);
+        assert_eq!(&req[..4], &[2, 1, 0, 0]);
+        assert_eq!(&req[20..24], &[192, 168, 1, 2]);
+        assert_eq!(req[36], 6);
+
+        // the gateway echoes the request's MAP fields, with the assigned
+        // external port and address
+        let mut resp = vec![2, 0x81, 0, 0, 0, 0, 0x0e, 0x10];
+        resp.extend_from_slice(&[0; 16]);
+        resp.extend_from_slice(&req[24..]);
+        resp[42..44].copy_from_slice(&6882u16.to_be_bytes());
+        resp[56..60].copy_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(
+            decode_pcp_map(&resp, &nonce).unwrap(),
+            Some(Mapping {
+                external_port: 6882,
+                external_ip: Some(Ipv4Addr::new(1, 2, 3, 4).into()),
+                lifetime: Duration::from_secs(3600),
+            })
+        );
+        assert!(decode_pcp_map(&resp, &[8; NONCE_LEN]).is_err());
+
+        // an error result code
+        resp[3] = 8;
+        assert!(decode_pcp_map(&resp, &nonce).is_err());
+
+        // a NAT-PMP gateway's unsupported version error
+        let resp = [0, 0x80, 0, 1, 0, 0, 0, 1];
+        assert_eq!(decode_pcp_map(&resp, &nonce).unwrap(), None);
+    }
+
+    #[test]
+    fn should_encode_and_decode_natpmp_map() {
+        assert_eq!(
+            encode_natpmp_map(Protocol::Udp, 6881, 3600),
+            vec![0, 1, 0, 0, 0x1a, 0xe1, 0x1a, 0xe1, 0, 0, 0x0e, 0x10]
+        );
+        assert_eq!(
+            encode_natpmp_map(Protocol::Tcp, 6881, 0),
+            vec![0, 2, 0, 0, 0x1a, 0xe1, 0, 0, 0, 0, 0, 0]
+        );
+
+        let resp = [
+            0, 0x82, 0, 0, 0, 0, 0, 1, 0x1a, 0xe1, 0x1a, 0xe2, 0, 0, 0x0e, 0x10,
+        ];
+        assert_eq!(
+            decode_natpmp_map(&resp, Protocol::Tcp).unwrap(),
+            Mapping {
+                external_port: 6882,
+                external_ip: None,
+                lifetime: Duration::from_secs(3600),
+            }
+        );
+        // the response to a request of another protocol
+        assert!(decode_natpmp_map(&resp, Protocol::Udp).is_err());
+
+        let resp = [0, 0x80, 0, 0, 0, 0, 0, 1, 1, 2, 3, 4];
+        assert_eq!(
+            decode_natpmp_external_addr(&resp).unwrap(),
+            Ipv4Addr::new(1, 2, 3, 4)
+        );
+        let resp = [0, 0x80, 0, 3, 0, 0, 0, 1, 0, 0, 0, 0];
+        assert!(decode_natpmp_external_addr(&resp).is_err());
+    }
+
+    #[test]
+    fn should_parse_default_gateway() {
+        let gateway = u32::from_ne_bytes([192, 168, 2, 1]);
+        let routes = format!(
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+             eth0\t0002A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\n\
+             eth0\t00000000\t{:08X}\t0003\t0\t0\t100\t00000000\n",
+            gateway
+        );
+        assert_eq!(
+            parse_default_gateway(&routes),
+            Some(Ipv4Addr::new(192, 168, 2, 1))
+        );
+        assert_eq!(parse_default_gateway(routes.lines().next().unwrap()), None);
+    }
+}
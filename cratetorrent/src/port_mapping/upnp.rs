@@ -0,0 +1,534 @@
+//! Port mapping with a UPnP Internet Gateway Device.
+//!
+//! The gateway is found with SSDP: a search request is multicast on the local
+//! network, and the gateway answers with the URL of its description, an XML
+//! document listing its services. Ports are then mapped by sending SOAP
+//! requests to the control URL of its WAN connection service.
+//!
+//! Gateways' XML is simple and mostly generated from templates, so rather than
+//! pulling in an XML parser, elements are found by their name.
+
+use std::{
+    error::Error,
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket as StdUdpSocket},
+    str,
+    time::{Duration, Instant},
+};
+
+use reqwest::{Client, StatusCode, Url};
+use tokio::net::UdpSocket;
+
+use super::Protocol;
+use crate::{logging, rt};
+
+/// The multicast address to which SSDP search requests are sent.
+const SSDP_ADDR: ([u8; 4], u16) = ([239, 255, 255, 250], 1900);
+
+/// The device type searched for.
+const SEARCH_TARGET: &str =
+    "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+
+/// Gateways that don't answer the search request in this time are not found.
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The types of the services through which ports can be mapped, in order of
+/// preference.
+const SERVICE_TYPES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+/// The error code of gateways that only support mappings without a lease
+/// duration.
+const ONLY_PERMANENT_LEASES_SUPPORTED: u32 = 725;
+
+/// The description of the mappings we add, shown in the gateway's interface.
+const MAPPING_DESCRIPTION: &str = "cratetorrent";
+
+/// A gateway found on the local network.
+pub(super) struct Gateway {
+    /// The URL to which SOAP requests are sent.
+    control_url: Url,
+    /// The type of the service at the control URL.
+    service_type: &'static str,
+    /// The address of our interface on the gateway's network, to which the
+    /// gateway forwards the mapped ports.
+    local_ip: Ipv4Addr,
+    client: Client,
+}
+
+/// An error returned by the gateway in response to a SOAP request.
+#[derive(Debug)]
+pub(super) struct UpnpError {
+    pub code: u32,
+    pub description: String,
+}
+
+impl fmt::Display for UpnpError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "UPnP error {}: {}", self.code, self.description)
+    }
+}
+
+impl Error for UpnpError {}
+
+/// Searches for a gateway on the local network, returning the first one that
+/// has a WAN connection service.
+pub(super) async fn discover(client: Client) -> io::Result<Gateway> {
+    let mut socket =
+        UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))).await?;
+    socket
+        .send_to(encode_search().as_bytes(), SocketAddr::from(SSDP_ADDR))
+        .await?;
+
+    let deadline = Instant::now() + DISCOVERY_TIMEOUT;
+    let mut buf = [0; 2048];
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let (len, addr) =
+            match rt::timeout(timeout, socket.recv_from(&mut buf)).await {
+                Ok(result) => result?,
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "no UPnP gateway found",
+                    ))
+                }
+            };
+        let location = match parse_search_response(&buf[..len]) {
+            Some(location) => location,
+            None => continue,
+        };
+        log::debug!(
+            target: logging::ENGINE,
+            "UPnP device {} at {}",
+            addr,
+            location
+        );
+        match fetch_gateway(&client, &location).await {
+            Ok(Some(gateway)) => return Ok(gateway),
+            Ok(None) => {
+                log::debug!(
+                    target: logging::ENGINE,
+                    "UPnP device {} has no WAN connection",
+                    addr
+                )
+            }
+            Err(e) => {
+                log::debug!(
+                    target: logging::ENGINE,
+                    "Error fetching UPnP device {}: {}",
+                    addr,
+                    e
+                )
+            }
+        }
+    }
+}
+
+/// Fetches the device description at the location, returning the gateway if
+/// the device has a WAN connection service.
+async fn fetch_gateway(
+    client: &Client,
+    location: &Url,
+) -> io::Result<Option<Gateway>> {
+    let description = client
+        .get(location.clone())
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .map_err(http_to_io_error)?
+        .text()
+        .await
+        .map_err(http_to_io_error)?;
+    let (service_type, control_url) =
+        match parse_description(&description, location) {
+            Some(service) => service,
+            None => return Ok(None),
+        };
+    Ok(Some(Gateway {
+        local_ip: local_ip(&control_url)?,
+        control_url,
+        service_type,
+        client: client.clone(),
+    }))
+}
+
+/// Returns the address of the interface through which the gateway is
+/// reached.
+///
+/// Connecting a UDP socket doesn't send any packets, it only looks up the
+/// route to the remote address.
+fn local_ip(url: &Url) -> io::Result<Ipv4Addr> {
+    let gateway_ip = match url.host_str().map(str::parse::<IpAddr>) {
+        Some(Ok(IpAddr::V4(ip))) => ip,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "UPnP gateway has no IPv4 address",
+            ))
+        }
+    };
+    let socket = StdUdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect((gateway_ip, url.port_or_known_default().unwrap_or(80)))?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) => Ok(ip),
+        IpAddr::V6(_) => unreachable!("socket should be bound to IPv4"),
+    }
+}
+
+impl Gateway {
+    /// Maps the external port to the same port of our interface for the
+    /// lease duration.
+    ///
+    /// Gateways that only support permanent mappings are asked for one
+    /// instead, which is deleted on shutdown like any other mapping.
+    pub async fn add_mapping(
+        &self,
+        protocol: Protocol,
+        port: u16,
+        lease_duration: Duration,
+    ) -> io::Result<()> {
+        let result =
+            self.add_port_mapping(protocol, port, lease_duration).await;
+        if let Err(e) = &result {
+            if upnp_error_code(e) == Some(ONLY_PERMANENT_LEASES_SUPPORTED) {
+                log::debug!(
+                    target: logging::ENGINE,
+                    "UPnP gateway only supports permanent mappings"
+                );
+                let lease_duration = Duration::from_secs(0);
+                return self
+                    .add_port_mapping(protocol, port, lease_duration)
+                    .await;
+            }
+        }
+        result
+    }
+
+    async fn add_port_mapping(
+        &self,
+        protocol: Protocol,
+        port: u16,
+        lease_duration: Duration,
+    ) -> io::Result<()> {
+        let args = [
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", port.to_string()),
+            ("NewProtocol", protocol.to_string()),
+            ("NewInternalPort", port.to_string()),
+            ("NewInternalClient", self.local_ip.to_string()),
+            ("NewEnabled", "1".to_string()),
+            ("NewPortMappingDescription", MAPPING_DESCRIPTION.to_string()),
+            ("NewLeaseDuration", lease_duration.as_secs().to_string()),
+        ];
+        self.send_action("AddPortMapping", &args).await?;
+        Ok(())
+    }
+
+    /// Deletes the mapping of the external port.
+    pub async fn delete_mapping(
+        &self,
+        protocol: Protocol,
+        port: u16,
+    ) -> io::Result<()> {
+        let args = [
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", port.to_string()),
+            ("NewProtocol", protocol.to_string()),
+        ];
+        self.send_action("DeletePortMapping", &args).await?;
+        Ok(())
+    }
+
+    /// Returns the gateway's external address.
+    pub async fn external_ip(&self) -> io::Result<IpAddr> {
+        let resp = self.send_action("GetExternalIPAddress", &[]).await?;
+        xml_element(&resp, "NewExternalIPAddress")
+            .and_then(|ip| ip.parse().ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid UPnP external address",
+                )
+            })
+    }
+
+    /// Sends the SOAP request of the action, returning the response body.
+    async fn send_action(
+        &self,
+        action: &str,
+        args: &[(&str, String)],
+    ) -> io::Result<String> {
+        let resp = self
+            .client
+            .post(self.control_url.clone())
+            .header("Content-Type", "text/xml; charset=\"utf-8\"")
+            .header(
+                "SOAPAction",
+                format!("\"{}#{}\"", self.service_type, action),
+            )
+            .body(encode_action(self.service_type, action, args))
+            .send()
+            .await
+            .map_err(http_to_io_error)?;
+        let status = resp.status();
+        let body = resp.text().await.map_err(http_to_io_error)?;
+        if status == StatusCode::OK {
+            return Ok(body);
+        }
+        // errors are returned as SOAP faults, with a 500 status
+        let error = match xml_element(&body, "errorCode") {
+            Some(code) => UpnpError {
+                code: code.parse().unwrap_or_default(),
+                description: xml_element(&body, "errorDescription")
+                    .unwrap_or_default()
+                    .to_string(),
+            },
+            None => UpnpError {
+                code: 0,
+                description: format!("HTTP status {}", status),
+            },
+        };
+        Err(io::Error::new(io::ErrorKind::Other, error))
+    }
+}
+
+/// Returns the code of the UPnP error, if that's what the error is.
+fn upnp_error_code(e: &io::Error) -> Option<u32> {
+    e.get_ref()
+        .and_then(|e| e.downcast_ref::<UpnpError>())
+        .map(|e| e.code)
+}
+
+fn http_to_io_error(e: reqwest::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+fn encode_search() -> String {
+    format!(
+        "M-SEARCH * HTTP/1.1\r\n\
+         HOST: 239.255.255.250:1900\r\n\
+         ST: {}\r\n\
+         MAN: \"ssdp:discover\"\r\n\
+         MX: {}\r\n\
+         \r\n",
+        SEARCH_TARGET,
+        DISCOVERY_TIMEOUT.as_secs() - 1
+    )
+}
+
+/// Parses the device's location from a response to our search request.
+fn parse_search_response(buf: &[u8]) -> Option<Url> {
+    let msg = str::from_utf8(buf).ok()?;
+    let mut lines = msg.split("\r\n");
+    if !lines.next()?.starts_with("HTTP/1.1 200") {
+        return None;
+    }
+    lines.take_while(|line| !line.is_empty()).find_map(|line| {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next()?.trim();
+        let value = parts.next()?.trim();
+        if name.eq_ignore_ascii_case("location") {
+            Url::parse(value).ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// Parses the type and the control URL of the preferred WAN connection
+/// service from the device description at the location.
+fn parse_description(xml: &str, location: &Url) -> Option<(&'static str, Url)> {
+    // control URLs are usually relative to the location, but older devices
+    // may specify another base
+    let base = xml_element(xml, "URLBase")
+        .and_then(|base| Url::parse(base).ok())
+        .unwrap_or_else(|| location.clone());
+    let services: Vec<_> = xml_elements(xml, "service")
+        .into_iter()
+        .filter_map(|service| {
+            let service_type = xml_element(service, "serviceType")?;
+            let control_url = xml_element(service, "controlURL")?;
+            Some((service_type, control_url))
+        })
+        .collect();
+    SERVICE_TYPES.iter().find_map(|&service_type| {
+        let (_, control_url) =
+            services.iter().find(|(t, _)| *t == service_type)?;
+        Some((service_type, base.join(control_url).ok()?))
+    })
+}
+
+fn encode_action(
+    service_type: &str,
+    action: &str,
+    args: &[(&str, String)],
+) -> String {
+    let args: String = args
+        .iter()
+        .map(|(name, value)| format!("<{0}>{1}</{0}>", name, value))
+        .collect();
+    format!(
+        "<?xml version=\"1.0\"?>\
+         <s:Envelope \
+         xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body>\
+         <u:{0} xmlns:u=\"{1}\">{2}</u:{0}>\
+         </s:Body>\
+         </s:Envelope>",
+        action, service_type, args
+    )
+}
+
+/// Returns the trimmed content of the first element with the name.
+fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    xml_elements(xml, name).into_iter().next()
+}
+
+/// Returns the trimmed contents of the elements with the name, ignoring
+/// namespace prefixes. Elements with the name must not be nested in each
+/// other.
+fn xml_elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    // returns the local name of the tag that starts at the beginning of the
+    // string, and the rest of the string after the tag
+    fn next_tag(xml: &str) -> Option<(&str, &str)> {
+        let end = xml.find('>')?;
+        let tag = xml[..end].split_whitespace().next().unwrap_or_default();
+        let local_name = tag.rsplit(':').next().unwrap_or_default();
+        Some((local_name, &xml[end + 1..]))
+    }
+
+    let mut elements = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        let is_closing = rest[start + 1..].starts_with('/');
+        let (tag, after) = match next_tag(&rest[start + 1..]) {
+            Some(tag) => tag,
+            None => break,
+        };
+        rest = after;
+        if is_closing || tag != name {
+            continue;
+        }
+        // find the matching closing tag
+        let mut content_len = 0;
+        let mut content = rest;
+        while let Some(close) = content.find("</") {
+            match next_tag(&content[close + 2..]) {
+                Some((tag, after)) if tag == name => {
+                    elements.push(rest[..content_len + close].trim());
+                    rest = after;
+                    break;
+                }
+                Some(_) => {
+                    content_len += close + 2;
+                    content = &content[close + 2..];
+                }
+                None => return elements,
+            }
+        }
+    }
+    elements
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_search_response() {
+        let resp = b"HTTP/1.1 200 OK\r\n\
+            CACHE-CONTROL: max-age=120\r\n\
+            ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+            Location: http://192.168.1.1:5000/rootDesc.xml\r\n\
+            \r\n";
+        assert_eq!(
+            parse_search_response(resp),
+            Some(Url::parse("http://192.168.1.1:5000/rootDesc.xml").unwrap())
+        );
+        assert_eq!(parse_search_response(b"NOTIFY * HTTP/1.1\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn should_parse_description() {
+        let xml = r#"<?xml version="1.0"?>
+            <root xmlns="urn:schemas-upnp-org:device-1-0">
+              <device>
+                <serviceList>
+                  <service>
+                    <serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>
+                    <controlURL>/ctl/L3F</controlURL>
+                  </service>
+                </serviceList>
+                <deviceList>
+                  <device>
+                    <serviceList>
+                      <service>
+                        <serviceType>urn:schemas-upnp-org:service:WANPPPConnection:1</serviceType>
+                        <controlURL>/ctl/PPP</controlURL>
+                      </service>
+                      <service>
+                        <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+                        <controlURL>/ctl/IPConn</controlURL>
+                      </service>
+                    </serviceList>
+                  </device>
+                </deviceList>
+              </device>
+            </root>"#;
+        let location =
+            Url::parse("http://192.168.1.1:5000/rootDesc.xml").unwrap();
+        assert_eq!(
+            parse_description(xml, &location),
+            Some((
+                "urn:schemas-upnp-org:service:WANIPConnection:1",
+                Url::parse("http://192.168.1.1:5000/ctl/IPConn").unwrap()
+            ))
+        );
+        assert_eq!(parse_description("<root></root>", &location), None);
+    }
+
+    #[test]
+    fn should_find_xml_elements() {
+        let xml = r#"<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/">
+            <s:Body>
+              <s:Fault>
+                <detail>
+                  <UPnPError xmlns="urn:schemas-upnp-org:control-1-0">
+                    <errorCode>725</errorCode>
+                    <errorDescription>OnlyPermanentLeasesSupported</errorDescription>
+                  </UPnPError>
+                </detail>
+              </s:Fault>
+            </s:Body>
+          </s:Envelope>"#;
+        assert_eq!(xml_element(xml, "errorCode"), Some("725"));
+        assert_eq!(
+            xml_element(xml, "errorDescription"),
+            Some("OnlyPermanentLeasesSupported")
+        );
+        assert!(xml_element(xml, "Body").unwrap().starts_with("<s:Fault>"));
+        assert_eq!(xml_element(xml, "NewExternalIPAddress"), None);
+        assert_eq!(
+            xml_elements("<a>1</a><b>2</b><a>3</a>", "a"),
+            vec!["1", "3"]
+        );
+    }
+
+    #[test]
+    fn should_encode_action() {
+        let args = [("NewExternalPort", "6881".to_string())];
+        let body = encode_action(SERVICE_TYPES[1], "DeletePortMapping", &args);
+        assert!(body.contains(
+            "<u:DeletePortMapping \
+             xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">\
+             <NewExternalPort>6881</NewExternalPort>\
+             </u:DeletePortMapping>"
+        ));
+        assert_eq!(xml_element(&body, "NewExternalPort"), Some("6881"));
+    }
+}
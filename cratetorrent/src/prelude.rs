@@ -3,7 +3,7 @@
 pub use crate::{
     alert::{Alert, AlertReceiver},
     conf::Conf,
-    engine::{self, EngineHandle, Mode, TorrentParams},
+    engine::{self, AddTorrent, EngineHandle, Mode, TorrentParams},
     error::Error,
     metainfo::Metainfo,
     TorrentId,
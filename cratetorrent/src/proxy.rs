@@ -0,0 +1,268 @@
+//! This module implements a minimal SOCKS5 client
+//! ([RFC 1928](https://tools.ietf.org/html/rfc1928)), with optional
+//! username/password authentication
+//! ([RFC 1929](https://tools.ietf.org/html/rfc1929)), used to tunnel outbound
+//! peer connections through a proxy.
+//!
+//! Tracker announces are proxied by the HTTP client itself, see
+//! [`crate::tracker`].
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{conf::ProxyConf, local_addr::LocalAddr};
+
+/// The SOCKS protocol version.
+const VERSION: u8 = 5;
+/// The version of the username/password authentication subnegotiation.
+const AUTH_VERSION: u8 = 1;
+
+/// The "no authentication required" method.
+const METHOD_NO_AUTH: u8 = 0x00;
+/// The username/password authentication method.
+const METHOD_USER_PASS: u8 = 0x02;
+/// The proxy's reply if none of our offered methods are acceptable.
+const METHOD_NOT_ACCEPTABLE: u8 = 0xff;
+
+/// The CONNECT command, the only one we need.
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// Connects to `addr` through the SOCKS5 proxy in `conf`, from the local
+/// address, if one is configured.
+///
+/// The returned stream is connected to the proxy, which relays all traffic to
+/// and from `addr`, so it can be used as if it were connected directly to
+/// `addr`.
+pub(crate) async fn connect(
+    conf: &ProxyConf,
+    local_addr: &LocalAddr,
+    addr: SocketAddr,
+) -> io::Result<TcpStream> {
+    let mut socket = local_addr.connect(conf.addr).await?;
+    handshake(&mut socket, conf, addr).await?;
+    Ok(socket)
+}
+
+/// Performs the SOCKS5 method negotiation, authentication (if needed) and
+/// connect request on a stream already connected to the proxy.
+async fn handshake(
+    socket: &mut TcpStream,
+    conf: &ProxyConf,
+    addr: SocketAddr,
+) -> io::Result<()> {
+    // offer authentication only if we have credentials
+    let greeting: &[u8] = if conf.auth.is_some() {
+        &[VERSION, 2, METHOD_NO_AUTH, METHOD_USER_PASS]
+    } else {
+        &[VERSION, 1, METHOD_NO_AUTH]
+    };
+    socket.write_all(greeting).await?;
+
+    let mut reply = [0; 2];
+    socket.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(proxy_error("invalid SOCKS version in reply"));
+    }
+    match (reply[1], &conf.auth) {
+        (METHOD_NO_AUTH, _) => {}
+        (METHOD_USER_PASS, Some(auth)) => {
+            let username = auth.username.as_bytes();
+            let password = auth.password.as_bytes();
+            if username.len() > 255 || password.len() > 255 {
+                return Err(proxy_error("proxy credentials too long"));
+            }
+            let mut buf =
+                Vec::with_capacity(3 + username.len() + password.len());
+            buf.push(AUTH_VERSION);
+            buf.push(username.len() as u8);
+            buf.extend_from_slice(username);
+            buf.push(password.len() as u8);
+            buf.extend_from_slice(password);
+            socket.write_all(&buf).await?;
+
+            let mut reply = [0; 2];
+            socket.read_exact(&mut reply).await?;
+            if reply[1] != 0 {
+                return Err(proxy_error("proxy authentication failed"));
+            }
+        }
+        (METHOD_NOT_ACCEPTABLE, _) => {
+            return Err(proxy_error("no acceptable proxy auth method"));
+        }
+        _ => return Err(proxy_error("invalid proxy auth method")),
+    }
+
+    socket.write_all(&encode_connect_request(addr)).await?;
+
+    // the reply has the same format as the request, with the command replaced
+    // by the reply status
+    let mut reply = [0; 4];
+    socket.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(proxy_error("invalid SOCKS version in reply"));
+    }
+    if reply[1] != 0 {
+        return Err(proxy_error(reply_error(reply[1])));
+    }
+    // skip the address to which the proxy bound the connection, we don't need
+    // it
+    let bound_addr_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => socket.read_u8().await? as usize,
+        _ => return Err(proxy_error("invalid address type in reply")),
+    };
+    let mut bound_addr = vec![0; bound_addr_len + 2];
+    socket.read_exact(&mut bound_addr).await?;
+
+    Ok(())
+}
+
+/// Encodes the CONNECT request to the given address.
+fn encode_connect_request(addr: SocketAddr) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(22);
+    buf.extend_from_slice(&[VERSION, CMD_CONNECT, 0]);
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+    buf
+}
+
+/// Returns the description of the proxy's reply status.
+fn reply_error(status: u8) -> &'static str {
+    match status {
+        1 => "general SOCKS server failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown SOCKS error",
+    }
+}
+
+fn proxy_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("SOCKS5 proxy: {}", msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::conf::ProxyAuth;
+
+    /// Tests the encoding of the connect request for both IPv4 and IPv6
+    /// addresses.
+    #[test]
+    fn test_encode_connect_request() {
+        let addr: SocketAddr = "192.168.1.2:6881".parse().unwrap();
+        assert_eq!(
+            encode_connect_request(addr),
+            vec![5, 1, 0, 1, 192, 168, 1, 2, 0x1a, 0xe1]
+        );
+
+        let addr: SocketAddr = "[::1]:6881".parse().unwrap();
+        let mut expected = vec![5, 1, 0, 4];
+        expected.extend_from_slice(&[0; 15]);
+        expected.extend_from_slice(&[1, 0x1a, 0xe1]);
+        assert_eq!(encode_connect_request(addr), expected);
+    }
+
+    /// Tests a full handshake with username/password authentication against
+    /// a mock proxy.
+    #[tokio::test]
+    async fn should_connect_through_proxy_with_auth() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let conf = ProxyConf {
+            addr: listener.local_addr().unwrap(),
+            auth: Some(ProxyAuth {
+                username: "user".into(),
+                password: "pass".into(),
+            }),
+        };
+        let target: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+
+        let proxy = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+
+            let mut greeting = [0; 4];
+            socket.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 2, METHOD_NO_AUTH, METHOD_USER_PASS]);
+            socket.write_all(&[5, METHOD_USER_PASS]).await.unwrap();
+
+            let mut auth = [0; 11];
+            socket.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x04pass");
+            socket.write_all(&[1, 0]).await.unwrap();
+
+            let mut request = [0; 10];
+            socket.read_exact(&mut request).await.unwrap();
+            assert_eq!(request, [5, 1, 0, 1, 10, 0, 0, 1, 0x1a, 0xe1]);
+            socket
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0x1f, 0x90])
+                .await
+                .unwrap();
+
+            // relay some data to the client to check that the handshake
+            // consumed exactly the reply
+            socket.write_all(b"hello").await.unwrap();
+        });
+
+        let mut socket =
+            connect(&conf, &LocalAddr::default(), target).await.unwrap();
+        let mut buf = [0; 5];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        proxy.await.unwrap();
+    }
+
+    /// Tests that an error reply from the proxy fails the connection.
+    #[tokio::test]
+    async fn should_fail_on_proxy_error_reply() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let conf = ProxyConf {
+            addr: listener.local_addr().unwrap(),
+            auth: None,
+        };
+        let target: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+
+        let proxy = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 3];
+            socket.read_exact(&mut greeting).await.unwrap();
+            socket.write_all(&[5, METHOD_NO_AUTH]).await.unwrap();
+            let mut request = [0; 10];
+            socket.read_exact(&mut request).await.unwrap();
+            // connection refused
+            socket
+                .write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+        });
+
+        assert!(connect(&conf, &LocalAddr::default(), target).await.is_err());
+        proxy.await.unwrap();
+    }
+}
@@ -0,0 +1,240 @@
+//! The queue of torrents, which limits how many of them are active at once.
+//!
+//! Every torrent in the engine has a position in the queue. Downloading
+//! torrents and seeds each have their own limit on how many may be active at
+//! the same time, and the slots are given to the torrents closest to the front
+//! of the queue. The others are queued: they are stopped like paused torrents,
+//! but are activated automatically as soon as a slot frees up, e.g. because an
+//! active torrent completed, was paused, or was removed.
+//!
+//! Paused torrents keep their position but don't take up a slot. Torrents
+//! that are not auto-managed keep their position too, but are left out of the
+//! queue's limits: they're always active, and only stopped by the user.
+
+use crate::{conf::QueueConf, TorrentId};
+
+/// A torrent's entry in the queue.
+#[derive(Debug)]
+struct QueueEntry {
+    id: TorrentId,
+    /// Whether the user paused the torrent.
+    is_paused: bool,
+    /// Whether the torrent has all its pieces.
+    is_seed: bool,
+    /// Whether the torrent is subject to the queue's limits.
+    is_auto_managed: bool,
+    /// Whether the torrent was given a slot.
+    is_active: bool,
+}
+
+/// The torrents in queue order, along with their state.
+#[derive(Debug, Default)]
+pub(crate) struct TorrentQueue {
+    entries: Vec<QueueEntry>,
+}
+
+impl TorrentQueue {
+    /// Adds the torrent to the back of the queue. It's queued until it's given
+    /// a slot by [`Self::update`].
+    pub fn push(&mut self, id: TorrentId, is_seed: bool) {
+        self.entries.push(QueueEntry {
+            id,
+            is_paused: false,
+            is_seed,
+            is_auto_managed: true,
+            is_active: false,
+        });
+    }
+
+    /// Removes the torrent from the queue, moving the ones after it forward.
+    pub fn remove(&mut self, id: TorrentId) {
+        self.entries.retain(|e| e.id != id);
+    }
+
+    /// Returns the ids of the torrents in queue order.
+    pub fn ids(&self) -> Vec<TorrentId> {
+        self.entries.iter().map(|e| e.id).collect()
+    }
+
+    /// Returns whether the torrent was given a slot.
+    pub fn is_active(&self, id: TorrentId) -> bool {
+        self.entries.iter().any(|e| e.id == id && e.is_active)
+    }
+
+    /// Moves the torrent to the given position, 0 being the front of the
+    /// queue. Positions past the back of the queue move it to the back.
+    pub fn set_position(&mut self, id: TorrentId, position: usize) {
+        if let Some(index) = self.entries.iter().position(|e| e.id == id) {
+            let entry = self.entries.remove(index);
+            let position = position.min(self.entries.len());
+            self.entries.insert(position, entry);
+        }
+    }
+
+    /// Records whether the user paused the torrent.
+    pub fn set_paused(&mut self, id: TorrentId, is_paused: bool) {
+        if let Some(entry) = self.entries.iter_mut().find(|e| e.id == id) {
+            entry.is_paused = is_paused;
+        }
+    }
+
+    /// Records whether the torrent has all its pieces and is seeding.
+    pub fn set_seed(&mut self, id: TorrentId, is_seed: bool) {
+        if let Some(entry) = self.entries.iter_mut().find(|e| e.id == id) {
+            entry.is_seed = is_seed;
+        }
+    }
+
+    /// Records whether the torrent is subject to the queue's limits.
+    pub fn set_auto_managed(&mut self, id: TorrentId, is_auto_managed: bool) {
+        if let Some(entry) = self.entries.iter_mut().find(|e| e.id == id) {
+            entry.is_auto_managed = is_auto_managed;
+        }
+    }
+
+    /// Hands out the slots to the torrents closest to the front of the queue,
+    /// and returns the torrents whose state changed, along with whether they
+    /// are now active.
+    ///
+    /// Paused torrents are skipped and keep their state, as they are stopped
+    /// either way. Torrents that are not auto-managed are always active,
+    /// without taking up a slot.
+    pub fn update(&mut self, conf: &QueueConf) -> Vec<(TorrentId, bool)> {
+        let mut download_count = 0;
+        let mut seed_count = 0;
+        let mut changes = Vec::new();
+        for entry in self.entries.iter_mut().filter(|e| !e.is_paused) {
+            let (count, limit) = if entry.is_seed {
+                (&mut seed_count, conf.active_seeds)
+            } else {
+                (&mut download_count, conf.active_downloads)
+            };
+            let is_active = !entry.is_auto_managed
+                || limit.map_or(true, |limit| *count < limit);
+            if is_active && entry.is_auto_managed {
+                *count += 1;
+            }
+            if entry.is_active != is_active {
+                entry.is_active = is_active;
+                changes.push((entry.id, is_active));
+            }
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u32) -> TorrentId {
+        TorrentId(n)
+    }
+
+    fn conf(
+        active_downloads: Option<usize>,
+        active_seeds: Option<usize>,
+    ) -> QueueConf {
+        QueueConf {
+            active_downloads,
+            active_seeds,
+        }
+    }
+
+    #[test]
+    fn should_activate_torrents_in_queue_order() {
+        let conf = conf(Some(2), Some(1));
+        let mut queue = TorrentQueue::default();
+        queue.push(id(0), false);
+        queue.push(id(1), true);
+        queue.push(id(2), false);
+        queue.push(id(3), false);
+        queue.push(id(4), true);
+
+        // downloads and seeds have separate slots
+        assert_eq!(
+            queue.update(&conf),
+            vec![(id(0), true), (id(1), true), (id(2), true)]
+        );
+        assert!(!queue.is_active(id(3)));
+        assert!(!queue.is_active(id(4)));
+        // nothing changes until the queue does
+        assert!(queue.update(&conf).is_empty());
+
+        // a completed download frees up a download slot, and being ahead of
+        // the active seed, it takes its seed slot
+        queue.set_seed(id(0), true);
+        assert_eq!(queue.update(&conf), vec![(id(1), false), (id(3), true)]);
+
+        // a removed torrent frees up its slot
+        queue.remove(id(0));
+        assert_eq!(queue.update(&conf), vec![(id(1), true)]);
+        assert_eq!(queue.ids(), vec![id(1), id(2), id(3), id(4)]);
+    }
+
+    #[test]
+    fn should_not_give_paused_torrents_a_slot() {
+        let conf = conf(Some(1), None);
+        let mut queue = TorrentQueue::default();
+        queue.push(id(0), false);
+        queue.push(id(1), false);
+        assert_eq!(queue.update(&conf), vec![(id(0), true)]);
+
+        queue.set_paused(id(1), true);
+        queue.set_paused(id(0), true);
+        assert!(queue.update(&conf).is_empty());
+
+        // the first resumed torrent takes the free slot, but gives it back
+        // when the torrent ahead of it in the queue is resumed
+        queue.set_paused(id(1), false);
+        assert_eq!(queue.update(&conf), vec![(id(1), true)]);
+        queue.set_paused(id(0), false);
+        assert_eq!(queue.update(&conf), vec![(id(1), false)]);
+    }
+
+    #[test]
+    fn should_not_limit_manual_torrents() {
+        let conf = conf(Some(1), None);
+        let mut queue = TorrentQueue::default();
+        queue.push(id(0), false);
+        queue.push(id(1), false);
+        queue.set_auto_managed(id(1), false);
+        queue.push(id(2), false);
+
+        // the manual torrent doesn't take up the only slot
+        assert_eq!(queue.update(&conf), vec![(id(0), true), (id(1), true)]);
+        assert!(!queue.is_active(id(2)));
+
+        // once auto-managed again, it waits for a slot like the others
+        queue.set_auto_managed(id(1), true);
+        assert_eq!(queue.update(&conf), vec![(id(1), false)]);
+        queue.set_auto_managed(id(2), false);
+        assert_eq!(queue.update(&conf), vec![(id(2), true)]);
+    }
+
+    #[test]
+    fn should_reorder_queue() {
+        let conf = conf(Some(1), None);
+        let mut queue = TorrentQueue::default();
+        queue.push(id(0), false);
+        queue.push(id(1), false);
+        queue.push(id(2), false);
+        queue.update(&conf);
+
+        queue.set_position(id(2), 0);
+        assert_eq!(queue.ids(), vec![id(2), id(0), id(1)]);
+        assert_eq!(queue.update(&conf), vec![(id(2), true), (id(0), false)]);
+
+        queue.set_position(id(2), 100);
+        assert_eq!(queue.ids(), vec![id(0), id(1), id(2)]);
+    }
+
+    #[test]
+    fn should_activate_all_without_limits() {
+        let conf = conf(None, None);
+        let mut queue = TorrentQueue::default();
+        queue.push(id(0), false);
+        queue.push(id(1), true);
+        assert_eq!(queue.update(&conf), vec![(id(0), true), (id(1), true)]);
+    }
+}
@@ -0,0 +1,523 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::conf::{AltRateLimitConf, ScheduleRule, Weekday};
+
+/// A token bucket used to limit the throughput of a transfer direction.
+///
+/// Tokens correspond to bytes and are replenished continuously at the
+/// configured rate, up to one second's worth of tokens. Transferring data
+/// consumes tokens, and as long as the bucket has a positive balance, data may
+/// be transferred.
+///
+/// Since blocks are transferred in whole, the balance is allowed to go into
+/// debt: a block may be sent as long as there is at least one token, after
+/// which the transfer is paused until the debt is paid off. This way rates
+/// below the block size may be enforced too, and the average rate still
+/// matches the configured one.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    /// The number of bytes per second replenished.
+    rate: u64,
+    /// The current balance, which may be negative.
+    tokens: i64,
+    /// The last time the bucket was replenished.
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a new bucket that is initially full.
+    pub fn new(rate: u64, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate as i64,
+            last_refill: now,
+        }
+    }
+
+    /// Returns whether data may be transferred at this time.
+    pub fn has_tokens(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens > 0
+    }
+
+    /// Consumes the given number of bytes from the bucket, even if this puts
+    /// the balance into debt.
+    pub fn consume(&mut self, len: u64, now: Instant) {
+        self.refill(now);
+        self.tokens -= len as i64;
+    }
+
+    /// Changes the rate of the bucket, keeping the current balance but capping
+    /// it at a second's worth of tokens at the new rate.
+    pub fn set_rate(&mut self, rate: u64, now: Instant) {
+        self.refill(now);
+        self.rate = rate;
+        self.tokens = self.tokens.min(rate as i64);
+    }
+
+    /// Replenishes the tokens for the time elapsed since the last refill.
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        let tokens = (self.rate as u128 * elapsed.as_nanos()
+            / Duration::from_secs(1).as_nanos()) as i64;
+        // if the elapsed time was too short to yield a token, don't advance the
+        // refill time so that the fractions are not lost
+        if tokens > 0 {
+            self.tokens = (self.tokens + tokens).min(self.rate as i64);
+            self.last_refill = now;
+        }
+    }
+}
+
+/// A torrent's share of one of the engine-wide rate limits, either download or
+/// upload.
+///
+/// The engine periodically divides the global rate among its torrents (see
+/// [`reallocate_shares`]), and the peer sessions of the torrent draw from this
+/// share before making requests or sending blocks, so that torrents added
+/// earlier can't starve the others.
+///
+/// The torrent may also have its own cap, in which case its rate is the lower
+/// of the cap and the rate allotted by the engine, if any.
+#[derive(Debug)]
+pub(crate) struct BandwidthShare {
+    /// The torrent's weight relative to other torrents.
+    priority: u32,
+    /// The rates and the bucket enforcing them.
+    state: Mutex<ShareState>,
+    /// The number of bytes transferred since the last reallocation.
+    consumed: AtomicU64,
+}
+
+#[derive(Debug)]
+struct ShareState {
+    /// The rate allotted by the engine, or `None` if the engine has no global
+    /// limit.
+    allotted: Option<u64>,
+    /// The torrent's own limit, if any.
+    cap: Option<u64>,
+    /// The bucket limiting the torrent's rate, or `None` if neither of the
+    /// above is set.
+    bucket: Option<TokenBucket>,
+}
+
+impl ShareState {
+    /// Updates the bucket to the lower of the allotted rate and the cap.
+    fn update_bucket(&mut self, now: Instant) {
+        let rate = match (self.allotted, self.cap) {
+            (Some(allotted), Some(cap)) => Some(allotted.min(cap)),
+            (allotted, cap) => allotted.or(cap),
+        };
+        match (rate, self.bucket.as_mut()) {
+            (Some(rate), Some(bucket)) => bucket.set_rate(rate, now),
+            (Some(rate), None) => {
+                self.bucket = Some(TokenBucket::new(rate, now))
+            }
+            (None, _) => self.bucket = None,
+        }
+    }
+}
+
+impl BandwidthShare {
+    /// Creates a new share with the given priority, initial allotted rate, and
+    /// cap.
+    ///
+    /// A zero priority is treated as the lowest non-zero priority, so that
+    /// no torrent is starved entirely.
+    pub fn new(
+        priority: u32,
+        rate: Option<u64>,
+        cap: Option<u64>,
+        now: Instant,
+    ) -> Self {
+        let mut state = ShareState {
+            allotted: rate,
+            cap,
+            bucket: None,
+        };
+        state.update_bucket(now);
+        Self {
+            priority: priority.max(1),
+            state: Mutex::new(state),
+            consumed: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the torrent's weight relative to other torrents.
+    pub fn priority(&self) -> u32 {
+        self.priority
+    }
+
+    /// Returns the rate the torrent is currently limited to, if limited.
+    pub fn rate(&self) -> Option<u64> {
+        self.state
+            .lock()
+            .unwrap()
+            .bucket
+            .as_ref()
+            .map(|bucket| bucket.rate)
+    }
+
+    /// Returns the torrent's own limit, if any.
+    pub fn cap(&self) -> Option<u64> {
+        self.state.lock().unwrap().cap
+    }
+
+    /// Changes the rate allotted to the torrent by the engine.
+    pub fn set_rate(&self, rate: u64, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        state.allotted = Some(rate);
+        state.update_bucket(now);
+    }
+
+    /// Removes the rate allotted to the torrent, e.g. because the engine-wide
+    /// limit was lifted. The torrent's cap, if any, still applies.
+    pub fn clear_rate(&self, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        state.allotted = None;
+        state.update_bucket(now);
+    }
+
+    /// Changes or lifts the torrent's own limit.
+    pub fn set_cap(&self, cap: Option<u64>, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        state.cap = cap;
+        state.update_bucket(now);
+    }
+
+    /// Returns whether the torrent may transfer data at this time.
+    pub fn has_tokens(&self, now: Instant) -> bool {
+        match self.state.lock().unwrap().bucket.as_mut() {
+            Some(bucket) => bucket.has_tokens(now),
+            None => true,
+        }
+    }
+
+    /// Records that the given number of bytes were transferred.
+    pub fn consume(&self, len: u64, now: Instant) {
+        self.consumed.fetch_add(len, Ordering::Relaxed);
+        if let Some(bucket) = self.state.lock().unwrap().bucket.as_mut() {
+            bucket.consume(len, now);
+        }
+    }
+
+    /// Returns the number of bytes transferred since the last call and resets
+    /// the counter.
+    pub fn take_consumed(&self) -> u64 {
+        self.consumed.swap(0, Ordering::Relaxed)
+    }
+}
+
+/// Divides the engine-wide rate limit among the shares of the torrents, based
+/// on their priorities and on how much of their previous share they used in
+/// the `elapsed` time.
+pub(crate) fn reallocate_shares(
+    limit: u64,
+    shares: &[&BandwidthShare],
+    elapsed: Duration,
+    now: Instant,
+) {
+    let elapsed_ms = elapsed.as_millis();
+    if shares.is_empty() || elapsed_ms == 0 {
+        return;
+    }
+
+    let demands: Vec<_> = shares
+        .iter()
+        .map(|share| {
+            let consumed = share.take_consumed();
+            let used_rate = (consumed as u128 * 1000 / elapsed_ms) as u64;
+            // if the torrent used (nearly) all of its share, it could
+            // probably use more, so its demand is unknown
+            let demand = match share.rate() {
+                Some(rate) if used_rate < rate / 10 * 9 => Some(used_rate),
+                _ => None,
+            };
+            // but a torrent never needs more than its own cap, so the rest
+            // is left to the others
+            let demand = match (demand, share.cap()) {
+                (Some(demand), Some(cap)) => Some(demand.min(cap)),
+                (demand, cap) => demand.or(cap),
+            };
+            (share.priority(), demand)
+        })
+        .collect();
+
+    let rates = allocate_rates(limit, &demands);
+    for (share, rate) in shares.iter().zip(rates) {
+        share.set_rate(rate, now);
+    }
+}
+
+/// Divides the total rate among torrents in proportion to their priorities.
+///
+/// Each torrent is described by its priority and its demand, which is the rate
+/// it could use, or `None` if it could use any rate. Torrents that need less
+/// than their proportional share get their demand, and the rest is shared
+/// among the others, using the same rule (weighted max-min fairness). If all
+/// demands are met, the leftover rate is again shared by all torrents so that
+/// those whose demand is growing may use it.
+pub(crate) fn allocate_rates(
+    total: u64,
+    torrents: &[(u32, Option<u64>)],
+) -> Vec<u64> {
+    let mut rates = vec![0; torrents.len()];
+    let mut remaining = total;
+    let mut unsatisfied: Vec<usize> = (0..torrents.len()).collect();
+
+    while !unsatisfied.is_empty() {
+        let weight_sum: u64 =
+            unsatisfied.iter().map(|&i| torrents[i].0 as u64).sum();
+        let share = |i: usize, remaining: u64| {
+            (remaining as u128 * torrents[i].0 as u128 / weight_sum as u128)
+                as u64
+        };
+
+        // give torrents that need no more than their share their demand
+        let prev_remaining = remaining;
+        let prev_unsatisfied_count = unsatisfied.len();
+        unsatisfied.retain(|&i| match torrents[i].1 {
+            Some(demand) if demand <= share(i, prev_remaining) => {
+                rates[i] = demand;
+                remaining -= demand;
+                false
+            }
+            _ => true,
+        });
+
+        // if no demand was met this round, the remaining torrents want more
+        // than their share, so they get exactly that
+        if unsatisfied.len() == prev_unsatisfied_count {
+            for &i in unsatisfied.iter() {
+                rates[i] = share(i, prev_remaining);
+            }
+            return rates;
+        }
+    }
+
+    // all demands were met, so share the leftover by priority
+    let weight_sum: u64 = torrents.iter().map(|t| t.0 as u64).sum();
+    if weight_sum > 0 {
+        for (rate, torrent) in rates.iter_mut().zip(torrents.iter()) {
+            *rate += (remaining as u128 * torrent.0 as u128
+                / weight_sum as u128) as u64;
+        }
+    }
+
+    rates
+}
+
+/// Returns whether the alternative rate limits are in effect at the given
+/// time, i.e. whether it falls into any of the periods of their schedule.
+pub(crate) fn is_alt_rate_limit_active(
+    conf: &AltRateLimitConf,
+    now: SystemTime,
+) -> bool {
+    const DAY: i64 = 24 * 60 * 60;
+    let secs = match now.duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => since_epoch.as_secs() as i64,
+        Err(_) => return false,
+    } + conf.utc_offset as i64;
+    let days = secs.div_euclid(DAY);
+    let time = Duration::from_secs(secs.rem_euclid(DAY) as u64);
+    // the Unix epoch was on a Thursday
+    let weekday = weekday_from_index((days + 3).rem_euclid(7));
+    let yesterday = weekday_from_index((days + 2).rem_euclid(7));
+    conf.schedule
+        .iter()
+        .any(|rule| is_in_period(rule, weekday, yesterday, time))
+}
+
+/// Returns whether the time of day on the given weekday falls into the
+/// rule's period, which may have started on the previous day.
+fn is_in_period(
+    rule: &ScheduleRule,
+    weekday: Weekday,
+    yesterday: Weekday,
+    time: Duration,
+) -> bool {
+    let starts_on = |day| rule.days.is_empty() || rule.days.contains(&day);
+    if rule.start < rule.end {
+        starts_on(weekday) && time >= rule.start && time < rule.end
+    } else {
+        // the period ends on the next day
+        (starts_on(weekday) && time >= rule.start)
+            || (starts_on(yesterday) && time < rule.end)
+    }
+}
+
+/// Returns the weekday at the given index, counted from Monday.
+fn weekday_from_index(index: i64) -> Weekday {
+    match index {
+        0 => Weekday::Monday,
+        1 => Weekday::Tuesday,
+        2 => Weekday::Wednesday,
+        3 => Weekday::Thursday,
+        4 => Weekday::Friday,
+        5 => Weekday::Saturday,
+        _ => Weekday::Sunday,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, start);
+
+        // the bucket is initially full
+        assert!(bucket.has_tokens(start));
+        bucket.consume(600, start);
+        assert!(bucket.has_tokens(start));
+        // the balance may go into debt
+        bucket.consume(600, start);
+        assert!(!bucket.has_tokens(start));
+
+        // after 100 ms we're still 100 bytes in debt
+        assert!(!bucket.has_tokens(start + Duration::from_millis(100)));
+        // but after 250 ms we're in the positive again
+        assert!(bucket.has_tokens(start + Duration::from_millis(250)));
+
+        // the bucket can't hold more than a second's worth of tokens
+        let later = start + Duration::from_secs(10);
+        assert!(bucket.has_tokens(later));
+        bucket.consume(1000, later);
+        assert!(!bucket.has_tokens(later));
+    }
+
+    #[test]
+    fn test_allocate_rates() {
+        // equal priorities and unbounded demands share the rate equally
+        assert_eq!(
+            allocate_rates(1000, &[(1, None), (1, None)]),
+            vec![500, 500]
+        );
+        // higher priority torrents get a proportionally larger share
+        assert_eq!(
+            allocate_rates(900, &[(1, None), (2, None)]),
+            vec![300, 600]
+        );
+        // a torrent needing less than its share leaves the rest to others
+        assert_eq!(
+            allocate_rates(1000, &[(1, Some(100)), (1, None), (1, None)]),
+            vec![100, 450, 450]
+        );
+        // if all demands are met, the leftover is shared by all
+        assert_eq!(
+            allocate_rates(1000, &[(1, Some(100)), (1, Some(300))]),
+            vec![400, 600]
+        );
+        assert!(allocate_rates(1000, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_reallocate_shares() {
+        let start = Instant::now();
+        let idle = BandwidthShare::new(1, Some(500), None, start);
+        let busy = BandwidthShare::new(1, Some(500), None, start);
+        idle.consume(100, start);
+        busy.consume(500, start);
+
+        // the share the idle torrent didn't use goes to the busy one
+        let now = start + Duration::from_secs(1);
+        reallocate_shares(1000, &[&idle, &busy], Duration::from_secs(1), now);
+        assert_eq!(idle.rate(), Some(100));
+        assert_eq!(busy.rate(), Some(900));
+        // the consumed bytes are counted anew for the next reallocation
+        assert_eq!(busy.take_consumed(), 0);
+    }
+
+    #[test]
+    fn test_share_cap() {
+        let start = Instant::now();
+        let capped = BandwidthShare::new(1, Some(500), Some(200), start);
+        let uncapped = BandwidthShare::new(1, Some(500), None, start);
+        assert_eq!(capped.rate(), Some(200));
+
+        // a capped torrent using its whole cap leaves the rest to the others
+        capped.consume(200, start);
+        uncapped.consume(500, start);
+        let now = start + Duration::from_secs(1);
+        reallocate_shares(
+            1000,
+            &[&capped, &uncapped],
+            Duration::from_secs(1),
+            now,
+        );
+        assert_eq!(capped.rate(), Some(200));
+        assert_eq!(uncapped.rate(), Some(800));
+
+        // the cap still applies without an engine-wide limit
+        capped.clear_rate(now);
+        assert_eq!(capped.rate(), Some(200));
+        capped.set_cap(None, now);
+        assert_eq!(capped.rate(), None);
+        capped.set_cap(Some(100), now);
+        assert_eq!(capped.rate(), Some(100));
+    }
+
+    #[test]
+    fn test_alt_rate_limit_schedule() {
+        let hours = |h: u64| Duration::from_secs(h * 60 * 60);
+        // 2021-01-01 was a Friday
+        let friday = UNIX_EPOCH + Duration::from_secs(1_609_459_200);
+        let conf = AltRateLimitConf {
+            schedule: vec![
+                // work hours
+                ScheduleRule {
+                    days: vec![
+                        Weekday::Monday,
+                        Weekday::Tuesday,
+                        Weekday::Wednesday,
+                        Weekday::Thursday,
+                        Weekday::Friday,
+                    ],
+                    start: hours(9),
+                    end: hours(17),
+                },
+                // Saturday night into Sunday
+                ScheduleRule {
+                    days: vec![Weekday::Saturday],
+                    start: hours(22),
+                    end: hours(6),
+                },
+            ],
+            ..AltRateLimitConf::default()
+        };
+
+        let is_active = |t| is_alt_rate_limit_active(&conf, t);
+        assert!(!is_active(friday + hours(8)));
+        assert!(is_active(friday + hours(9)));
+        assert!(is_active(friday + hours(16)));
+        assert!(!is_active(friday + hours(17)));
+        // Saturday
+        assert!(!is_active(friday + hours(24 + 12)));
+        assert!(is_active(friday + hours(24 + 23)));
+        // Sunday
+        assert!(is_active(friday + hours(48 + 5)));
+        assert!(!is_active(friday + hours(48 + 6)));
+        // the work hours period doesn't wrap into Saturday
+        assert!(!is_active(friday + hours(24 + 9)));
+
+        // local time is ahead of UTC by 2 hours, so 9:00 local is 7:00 UTC
+        let conf = AltRateLimitConf {
+            utc_offset: 2 * 60 * 60,
+            ..conf
+        };
+        assert!(is_alt_rate_limit_active(&conf, friday + hours(7)));
+        assert!(!is_alt_rate_limit_active(&conf, friday + hours(15)));
+
+        // an empty schedule is never in effect
+        let conf = AltRateLimitConf::default();
+        assert!(!is_alt_rate_limit_active(&conf, friday + hours(12)));
+    }
+}
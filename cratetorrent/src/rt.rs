@@ -0,0 +1,262 @@
+//! The async runtime on which the engine's tasks run and its timers fire.
+//!
+//! The engine spawns its tasks, and creates its timers, only through this
+//! module, which delegates to the [`Runtime`] set with [`set_runtime`], or to
+//! [`Tokio`] if none is set. An application driven by another executor, such
+//! as async-std's, implements [`Runtime`] for it and sets it before spawning
+//! the engine.
+//!
+//! The engine's sockets, and its HTTP tracker client, are still tokio's, so
+//! they need tokio's reactor: with another runtime, the engine must still be
+//! started within the context of a tokio runtime, e.g. one entered on the
+//! application's executor threads, which then only drives the IO.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use cratetorrent::rt::{self, Runtime};
+//! use futures::future::BoxFuture;
+//!
+//! struct MyRuntime;
+//!
+//! impl Runtime for MyRuntime {
+//!     fn spawn(&self, task: BoxFuture<'static, ()>) {
+//!         // hand the task to the executor
+//!         # drop(task);
+//!     }
+//!
+//!     fn spawn_blocking(&self, f: Box<dyn FnOnce() + Send>) {
+//!         std::thread::spawn(f);
+//!     }
+//!
+//!     fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+//!         // return the executor's timer
+//!         # unimplemented!()
+//!     }
+//! }
+//!
+//! rt::set_runtime(MyRuntime).unwrap();
+//! ```
+
+use std::{
+    fmt,
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    task::{Context, Poll},
+    thread,
+    time::{Duration, Instant},
+};
+
+use futures::{
+    channel::oneshot,
+    future::{self, BoxFuture, Either, FutureExt},
+    ready,
+    stream::{self, Stream, StreamExt},
+};
+use once_cell::sync::OnceCell;
+
+/// An executor that runs the engine's tasks and provides its timers.
+pub trait Runtime: Send + Sync + 'static {
+    /// Runs the task to completion in the background.
+    fn spawn(&self, task: BoxFuture<'static, ()>);
+
+    /// Runs the function on a thread where blocking is acceptable.
+    fn spawn_blocking(&self, f: Box<dyn FnOnce() + Send>);
+
+    /// Returns a future that completes once the duration has elapsed.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// The tokio runtime, used unless another one is set.
+///
+/// The engine must then be started from within a tokio runtime.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Tokio;
+
+impl Runtime for Tokio {
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        tokio::task::spawn(task);
+    }
+
+    fn spawn_blocking(&self, f: Box<dyn FnOnce() + Send>) {
+        tokio::task::spawn_blocking(f);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        tokio::time::delay_for(duration).boxed()
+    }
+}
+
+static RUNTIME: OnceCell<Box<dyn Runtime>> = OnceCell::new();
+
+/// Sets the runtime of all engines in the process.
+///
+/// This must be done before the first engine is spawned, as the runtime
+/// can't be changed once used. If it has already been set, or [`Tokio`] has
+/// been defaulted to, an error is returned.
+pub fn set_runtime(runtime: impl Runtime) -> Result<(), RuntimeAlreadySet> {
+    RUNTIME
+        .set(Box::new(runtime))
+        .map_err(|_| RuntimeAlreadySet)
+}
+
+fn runtime() -> &'static dyn Runtime {
+    RUNTIME.get_or_init(|| Box::new(Tokio)).as_ref()
+}
+
+/// The error returned when setting the runtime after it was already set or
+/// used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RuntimeAlreadySet;
+
+impl fmt::Display for RuntimeAlreadySet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("runtime already set")
+    }
+}
+
+impl std::error::Error for RuntimeAlreadySet {}
+
+/// A handle with which the result of a spawned task may be awaited.
+///
+/// Dropping the handle detaches the task, which keeps running.
+pub(crate) struct JoinHandle<T> {
+    rx: oneshot::Receiver<thread::Result<T>>,
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Self::Output> {
+        let result = match ready!(Pin::new(&mut self.rx).poll(cx)) {
+            Ok(Ok(output)) => Ok(output),
+            Ok(Err(_)) => Err(JoinError::Panicked),
+            // the runtime dropped the task before it completed
+            Err(oneshot::Canceled) => Err(JoinError::Cancelled),
+        };
+        Poll::Ready(result)
+    }
+}
+
+/// The reason a task didn't complete.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum JoinError {
+    Panicked,
+    Cancelled,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Panicked => f.write_str("task panicked"),
+            Self::Cancelled => f.write_str("task cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for JoinError {}
+
+/// The error returned by [`timeout`] if the future didn't complete in time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Elapsed;
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("deadline elapsed")
+    }
+}
+
+impl std::error::Error for Elapsed {}
+
+/// Spawns a new asynchronous task, returning the handle with which its result
+/// may be awaited.
+pub(crate) fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    runtime().spawn(
+        async move {
+            let output = AssertUnwindSafe(future).catch_unwind().await;
+            // the handle may have been dropped
+            let _ = tx.send(output);
+        }
+        .boxed(),
+    );
+    JoinHandle { rx }
+}
+
+/// Runs the blocking function on a thread where blocking is acceptable,
+/// returning the handle with which its result may be awaited.
+pub(crate) fn spawn_blocking<F, R>(f: F) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    runtime().spawn_blocking(Box::new(move || {
+        let output = panic::catch_unwind(AssertUnwindSafe(f));
+        let _ = tx.send(output);
+    }));
+    JoinHandle { rx }
+}
+
+/// Returns a stream that yields the current time every period, starting
+/// right away.
+///
+/// If a tick is late, the next ones are yielded right away until the stream
+/// has caught up.
+pub(crate) fn interval(
+    period: Duration,
+) -> impl Stream<Item = Instant> + Unpin {
+    stream::unfold(Instant::now(), move |deadline| async move {
+        let now = Instant::now();
+        if deadline > now {
+            runtime().sleep(deadline - now).await;
+        }
+        Some((deadline, deadline + period))
+    })
+    .boxed()
+}
+
+/// Awaits the future for at most the given duration, after which
+/// [`Elapsed`] is returned.
+pub(crate) async fn timeout<F: Future>(
+    duration: Duration,
+    future: F,
+) -> Result<F::Output, Elapsed> {
+    futures::pin_mut!(future);
+    match future::select(future, runtime().sleep(duration)).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(_) => Err(Elapsed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn should_join_task() {
+        assert_eq!(spawn(async { 1 }).await, Ok(1));
+        assert_eq!(spawn_blocking(|| 2).await, Ok(2));
+        let handle = spawn(async { panic!("boom") });
+        assert_eq!(handle.await, Err::<(), _>(JoinError::Panicked));
+    }
+
+    #[tokio::test]
+    async fn should_time_out() {
+        let never = future::pending::<()>();
+        assert_eq!(
+            timeout(Duration::from_millis(10), never).await,
+            Err(Elapsed)
+        );
+        let ready = future::ready(3);
+        assert_eq!(timeout(Duration::from_secs(10), ready).await, Ok(3));
+    }
+}
@@ -0,0 +1,405 @@
+//! The state of the engine's session that is persisted across restarts.
+//!
+//! The session is saved in a state directory, with one file for the session
+//! itself and two for each torrent:
+//! - `session.state` lists the info hashes of the torrents in queue order,
+//!   along with the engine-wide rate limits.
+//! - `<info hash>.torrent` is the torrent's metainfo file, saved when the
+//!   torrent is added, from which it's added again on restart.
+//! - `<info hash>.resume` is the torrent's resume data: the pieces it has,
+//!   whether it's paused or managed manually, its transfer statistics, its
+//!   own rate limits, its download directory, if it's not the engine's, its
+//!   labels, and the paths of its renamed files and directory.
+//!
+//! The pieces in the resume data were verified when they were downloaded, so
+//! a restored torrent continues where it stopped without hashing its files
+//! again.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde_bytes::ByteBuf;
+
+use crate::{conf::RateLimitConf, logging, Bitfield, FileIndex, Sha1Hash};
+
+/// The name of the file that lists the torrents of the session.
+const SESSION_FILE: &str = "session.state";
+
+/// The bencoded state of the session, as saved in the session file.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(crate = "serde_crate")]
+struct RawSession {
+    /// The hex encoded info hashes of the torrents, in queue order.
+    torrents: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    download_rate_limit: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    upload_rate_limit: Option<u64>,
+}
+
+/// The bencoded resume data of a torrent, as saved in its resume file.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(crate = "serde_crate")]
+struct RawResumeData {
+    /// The pieces we have, in the format of the bitfield message.
+    pieces: ByteBuf,
+    /// The number of pieces, as the bitfield is padded to a whole byte.
+    piece_count: usize,
+    /// 1 if the torrent is paused, 0 otherwise.
+    paused: u8,
+    /// 1 if the torrent is managed manually, 0 if it's auto-managed.
+    #[serde(default)]
+    manual: u8,
+    uploaded: u64,
+    downloaded: u64,
+    /// The time the torrent has been running, in seconds.
+    run_duration: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    download_rate_limit: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    upload_rate_limit: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    download_dir: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    labels: Vec<String>,
+    /// The new name of the torrent's directory, if it was renamed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    root_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    renamed_files: Vec<RawRenamedFile>,
+}
+
+/// A file that was moved from the path in the torrent's metainfo.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(crate = "serde_crate")]
+struct RawRenamedFile {
+    index: FileIndex,
+    /// The file's path relative to the torrent's root.
+    path: PathBuf,
+}
+
+/// The state of the engine's session restored from the state directory.
+#[derive(Debug)]
+pub(crate) struct Session {
+    /// The torrents in queue order.
+    pub torrents: Vec<SavedTorrent>,
+    pub download_rate_limit: Option<u64>,
+    pub upload_rate_limit: Option<u64>,
+}
+
+/// A torrent restored from the state directory.
+#[derive(Debug)]
+pub(crate) struct SavedTorrent {
+    /// The bencoded metainfo file of the torrent.
+    pub metainfo: Vec<u8>,
+    /// The torrent's resume data, if it was saved.
+    pub resume_data: Option<ResumeData>,
+}
+
+/// The state of a torrent that is carried over to the next run.
+#[derive(Debug, PartialEq)]
+pub(crate) struct ResumeData {
+    pub state: TorrentState,
+    /// The torrent's own rate limits.
+    pub rate_limit: RateLimitConf,
+    /// The directory the torrent was added with, if it's not the engine's
+    /// download directory.
+    pub download_dir: Option<PathBuf>,
+    pub labels: Vec<String>,
+    /// Whether the torrent is managed by the engine's queue, seed goals and
+    /// error retries, or only by the user.
+    pub is_auto_managed: bool,
+    /// The new name of the torrent's directory, if it was renamed.
+    pub root_name: Option<String>,
+    /// The paths of the files that were renamed, relative to the torrent's
+    /// root.
+    pub file_paths: BTreeMap<FileIndex, PathBuf>,
+}
+
+/// The state of a torrent as reported by the torrent itself.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct TorrentState {
+    /// The pieces the torrent has.
+    pub own_pieces: Bitfield,
+    pub is_paused: bool,
+    pub stats: SavedStats,
+}
+
+/// The statistics of a torrent that accumulate across runs.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct SavedStats {
+    /// The number of payload bytes uploaded.
+    pub uploaded: u64,
+    /// The number of payload bytes downloaded.
+    pub downloaded: u64,
+    /// The time the torrent has been running.
+    pub run_duration: Duration,
+}
+
+/// Loads the session saved in the given directory.
+///
+/// Torrents whose metainfo file can't be read are skipped, and those whose
+/// resume data can't be read are restored without it, so that a single
+/// corrupt file doesn't lose the whole session.
+pub(crate) fn load(dir: &Path) -> io::Result<Session> {
+    let buf = fs::read(dir.join(SESSION_FILE))?;
+    let raw: RawSession = decode(&buf)?;
+
+    let mut torrents = Vec::with_capacity(raw.torrents.len());
+    for hex_info_hash in raw.torrents.iter() {
+        let metainfo = match fs::read(metainfo_path(dir, hex_info_hash)) {
+            Ok(metainfo) => metainfo,
+            Err(e) => {
+                log::warn!(
+                    target: logging::ENGINE,
+                    "Skipping saved torrent {} as its metainfo can't be \
+                    read: {}",
+                    hex_info_hash,
+                    e
+                );
+                continue;
+            }
+        };
+        let resume_data = match load_resume_data(dir, hex_info_hash) {
+            Ok(resume_data) => Some(resume_data),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => {
+                log::warn!(
+                    target: logging::ENGINE,
+                    "Ignoring resume data of torrent {}: {}",
+                    hex_info_hash,
+                    e
+                );
+                None
+            }
+        };
+        torrents.push(SavedTorrent {
+            metainfo,
+            resume_data,
+        });
+    }
+
+    Ok(Session {
+        torrents,
+        download_rate_limit: raw.download_rate_limit,
+        upload_rate_limit: raw.upload_rate_limit,
+    })
+}
+
+fn load_resume_data(dir: &Path, hex_info_hash: &str) -> io::Result<ResumeData> {
+    let buf = fs::read(resume_data_path(dir, hex_info_hash))?;
+    let raw: RawResumeData = decode(&buf)?;
+    let mut own_pieces = Bitfield::from_vec(raw.pieces.into_vec());
+    if own_pieces.len() < raw.piece_count {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "resume data bitfield too short",
+        ));
+    }
+    own_pieces.truncate(raw.piece_count);
+    Ok(ResumeData {
+        state: TorrentState {
+            own_pieces,
+            is_paused: raw.paused != 0,
+            stats: SavedStats {
+                uploaded: raw.uploaded,
+                downloaded: raw.downloaded,
+                run_duration: Duration::from_secs(raw.run_duration),
+            },
+        },
+        rate_limit: RateLimitConf {
+            download: raw.download_rate_limit,
+            upload: raw.upload_rate_limit,
+        },
+        download_dir: raw.download_dir,
+        labels: raw.labels,
+        is_auto_managed: raw.manual == 0,
+        root_name: raw.root_name,
+        file_paths: raw
+            .renamed_files
+            .into_iter()
+            .map(|file| (file.index, file.path))
+            .collect(),
+    })
+}
+
+/// Saves the list of torrents, in queue order, and the engine-wide rate
+/// limits in the given directory, creating it if necessary.
+pub(crate) fn save_session(
+    dir: &Path,
+    info_hashes: &[Sha1Hash],
+    download_rate_limit: Option<u64>,
+    upload_rate_limit: Option<u64>,
+) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let raw = RawSession {
+        torrents: info_hashes.iter().map(hex::encode).collect(),
+        download_rate_limit,
+        upload_rate_limit,
+    };
+    fs::write(dir.join(SESSION_FILE), encode(&raw)?)
+}
+
+/// Saves the metainfo file of the torrent in the given directory, creating it
+/// if necessary.
+pub(crate) fn save_metainfo(
+    dir: &Path,
+    info_hash: &Sha1Hash,
+    metainfo: &[u8],
+) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    fs::write(metainfo_path(dir, &hex::encode(info_hash)), metainfo)
+}
+
+/// Saves the resume data of the torrent in the given directory, creating it
+/// if necessary.
+pub(crate) fn save_resume_data(
+    dir: &Path,
+    info_hash: &Sha1Hash,
+    resume_data: &ResumeData,
+) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let state = &resume_data.state;
+    let raw = RawResumeData {
+        pieces: ByteBuf::from(state.own_pieces.as_slice().to_vec()),
+        piece_count: state.own_pieces.len(),
+        paused: state.is_paused as u8,
+        manual: !resume_data.is_auto_managed as u8,
+        uploaded: state.stats.uploaded,
+        downloaded: state.stats.downloaded,
+        run_duration: state.stats.run_duration.as_secs(),
+        download_rate_limit: resume_data.rate_limit.download,
+        upload_rate_limit: resume_data.rate_limit.upload,
+        download_dir: resume_data.download_dir.clone(),
+        labels: resume_data.labels.clone(),
+        root_name: resume_data.root_name.clone(),
+        renamed_files: resume_data
+            .file_paths
+            .iter()
+            .map(|(&index, path)| RawRenamedFile {
+                index,
+                path: path.clone(),
+            })
+            .collect(),
+    };
+    fs::write(
+        resume_data_path(dir, &hex::encode(info_hash)),
+        encode(&raw)?,
+    )
+}
+
+/// Removes the files of a torrent that was removed from the session.
+pub(crate) fn remove_torrent(
+    dir: &Path,
+    info_hash: &Sha1Hash,
+) -> io::Result<()> {
+    let hex_info_hash = hex::encode(info_hash);
+    for path in [
+        metainfo_path(dir, &hex_info_hash),
+        resume_data_path(dir, &hex_info_hash),
+    ]
+    .iter()
+    {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+fn metainfo_path(dir: &Path, hex_info_hash: &str) -> PathBuf {
+    dir.join(format!("{}.torrent", hex_info_hash))
+}
+
+fn resume_data_path(dir: &Path, hex_info_hash: &str) -> PathBuf {
+    dir.join(format!("{}.resume", hex_info_hash))
+}
+
+fn encode<T: serde::Serialize>(value: &T) -> io::Result<Vec<u8>> {
+    serde_bencode::to_bytes(value)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+fn decode<'de, T: serde::Deserialize<'de>>(buf: &'de [u8]) -> io::Result<T> {
+    serde_bencode::from_bytes(buf)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resume_data() -> ResumeData {
+        let mut own_pieces = Bitfield::repeat(false, 11);
+        own_pieces.set(0, true);
+        own_pieces.set(10, true);
+        ResumeData {
+            state: TorrentState {
+                own_pieces,
+                is_paused: true,
+                stats: SavedStats {
+                    uploaded: 1000,
+                    downloaded: 2000,
+                    run_duration: Duration::from_secs(60),
+                },
+            },
+            rate_limit: RateLimitConf {
+                download: Some(100),
+                upload: None,
+            },
+            download_dir: Some(PathBuf::from("/downloads")),
+            labels: vec!["movies".into(), "hd".into()],
+            is_auto_managed: false,
+            root_name: Some("renamed".into()),
+            file_paths: vec![(2, PathBuf::from("sub/file"))]
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn should_restore_saved_session() {
+        let dir = std::env::temp_dir().join("cratetorrent_session_test");
+        let resume_data = resume_data();
+        save_metainfo(&dir, &[1; 20], b"metainfo 1").unwrap();
+        save_resume_data(&dir, &[1; 20], &resume_data).unwrap();
+        // a torrent without resume data
+        save_metainfo(&dir, &[2; 20], b"metainfo 2").unwrap();
+        // a torrent whose metainfo was lost is skipped
+        save_session(&dir, &[[2; 20], [3; 20], [1; 20]], Some(500), None)
+            .unwrap();
+
+        let session = load(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let session = session.unwrap();
+
+        assert_eq!(session.download_rate_limit, Some(500));
+        assert_eq!(session.upload_rate_limit, None);
+        assert_eq!(session.torrents.len(), 2);
+        assert_eq!(session.torrents[0].metainfo, b"metainfo 2");
+        assert_eq!(session.torrents[0].resume_data, None);
+        assert_eq!(session.torrents[1].metainfo, b"metainfo 1");
+        assert_eq!(session.torrents[1].resume_data, Some(resume_data));
+    }
+
+    #[test]
+    fn should_remove_torrent_files() {
+        let dir = std::env::temp_dir().join("cratetorrent_session_remove_test");
+        save_metainfo(&dir, &[1; 20], b"metainfo").unwrap();
+        save_resume_data(&dir, &[1; 20], &resume_data()).unwrap();
+        save_session(&dir, &[[1; 20]], None, None).unwrap();
+
+        remove_torrent(&dir, &[1; 20]).unwrap();
+        // removing a torrent without files is not an error
+        remove_torrent(&dir, &[2; 20]).unwrap();
+        let session = load(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(session.unwrap().torrents.is_empty());
+    }
+}
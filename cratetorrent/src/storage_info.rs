@@ -1,9 +1,17 @@
-use std::{ops::Range, path::PathBuf};
+use std::{
+    ops::Range,
+    path::{Component, Path, PathBuf},
+};
 
-use crate::{metainfo::Metainfo, FileIndex, PieceIndex};
+use crate::{logging, metainfo::Metainfo, FileIndex, PieceIndex};
 
 /// Information about a torrent's file.
 #[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 pub struct FileInfo {
     /// The file's relative path from the download directory.
     pub path: PathBuf,
@@ -69,6 +77,63 @@ pub struct FileSlice {
     pub len: u64,
 }
 
+/// How a torrent's files are stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub enum StorageMode {
+    /// The files are created empty and grow as pieces are written to them.
+    /// On most file systems, the parts not yet written take up no space.
+    Sparse,
+    /// The files are allocated in full when the torrent is added, so that
+    /// the disk can't run out of space during the download, and the files
+    /// are less fragmented.
+    Allocate,
+    /// The files are kept in memory instead of on disk, and are lost when
+    /// the torrent is removed or the engine shuts down. This is meant for
+    /// torrents that are streamed or processed right away, and for tests.
+    Memory,
+}
+
+impl Default for StorageMode {
+    fn default() -> Self {
+        Self::Sparse
+    }
+}
+
+/// A change to where a torrent's data is stored.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Rename {
+    /// Moves a file to the path, relative to the torrent's root.
+    File { index: FileIndex, path: PathBuf },
+    /// Renames the torrent's own directory, if it's an archive, or its file
+    /// otherwise.
+    Root(String),
+}
+
+impl Rename {
+    /// Returns whether the new path is relative and stays within the
+    /// torrent's root, and whether the new root name is a single path
+    /// component.
+    pub fn is_valid(&self) -> bool {
+        let path = match self {
+            Self::File { path, .. } => path.as_path(),
+            Self::Root(name) => {
+                let path = Path::new(name);
+                if path.components().count() != 1 {
+                    return false;
+                }
+                path
+            }
+        };
+        path.components().next().is_some()
+            && path.components().all(|c| matches!(c, Component::Normal(_)))
+    }
+}
+
 /// Information about a torrent's storage details, such as the piece count and
 /// length, download length, etc.
 #[derive(Clone, Debug)]
@@ -96,6 +161,9 @@ pub struct StorageInfo {
     /// E.g. downloading files into ~/Downloads/<torrent> instead of just
     /// ~/Downloads.
     pub download_dir: PathBuf,
+    /// Whether the torrent is an archive, whose files are downloaded into its
+    /// own directory, even if it has only one file.
+    pub is_archive: bool,
     /// All files in torrent.
     pub files: Vec<FileInfo>,
 }
@@ -123,10 +191,35 @@ impl StorageInfo {
             last_piece_len,
             download_len,
             download_dir,
+            is_archive: metainfo.is_archive(),
             files: metainfo.files.clone(),
         }
     }
 
+    /// Returns the rename with the torrent's paths changed accordingly, or
+    /// `None` if the file index is invalid.
+    ///
+    /// As a single file torrent has no directory of its own, renaming its
+    /// root renames its file, so the returned rename is that of the file.
+    pub(crate) fn rename(&mut self, rename: Rename) -> Option<Rename> {
+        match rename {
+            Rename::Root(name) if !self.is_archive => {
+                self.rename(Rename::File {
+                    index: 0,
+                    path: name.into(),
+                })
+            }
+            Rename::Root(name) => {
+                self.download_dir.set_file_name(&name);
+                Some(Rename::Root(name))
+            }
+            Rename::File { index, path } => {
+                self.files.get_mut(index)?.path = path.clone();
+                Some(Rename::File { index, path })
+            }
+        }
+    }
+
     /// Returns the zero-based indices of the files of torrent that intersect
     /// with the piece.
     ///
@@ -139,7 +232,11 @@ impl StorageInfo {
         &self,
         index: PieceIndex,
     ) -> Range<FileIndex> {
-        log::trace!("Returning files intersecting piece {}", index);
+        log::trace!(
+            target: logging::DISK,
+            "Returning files intersecting piece {}",
+            index
+        );
         let piece_offset = index as u64 * self.piece_len as u64;
         let piece_end = piece_offset + self.piece_len(index) as u64;
         self.files_intersecting_bytes(piece_offset..piece_end)
@@ -206,6 +303,27 @@ impl StorageInfo {
         }
     }
 
+    /// Returns the zero-based indices of the pieces that intersect with the
+    /// file, or an empty range if the file is empty.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the file index is invalid.
+    pub fn pieces_intersecting_file(
+        &self,
+        index: FileIndex,
+    ) -> Range<PieceIndex> {
+        let file = &self.files[index];
+        if file.len == 0 {
+            return 0..0;
+        }
+        let piece_len = self.piece_len as u64;
+        let first_piece = (file.torrent_offset / piece_len) as PieceIndex;
+        let last_piece =
+            ((file.torrent_end_offset() - 1) / piece_len) as PieceIndex;
+        first_piece..last_piece + 1
+    }
+
     /// Returns the piece's absolute offset in the torrent.
     pub fn torrent_piece_offset(&self, index: PieceIndex) -> u64 {
         index as u64 * self.piece_len as u64
@@ -320,6 +438,7 @@ mod tests {
             last_piece_len,
             download_len,
             download_dir: PathBuf::from("/"),
+            is_archive: false,
             files,
         };
         // all 4 pieces are in the same file
@@ -400,6 +519,7 @@ mod tests {
             last_piece_len,
             download_len,
             download_dir: PathBuf::from("/"),
+            is_archive: true,
             files,
         };
         // piece 0 intersects with files 0 and 1
@@ -412,6 +532,14 @@ mod tests {
         assert_eq!(info.files_intersecting_piece(3), 5..6);
         // last piece 4 intersects with only file 6
         assert_eq!(info.files_intersecting_piece(4), 6..7);
+
+        // and the other way around
+        assert_eq!(info.pieces_intersecting_file(0), 0..1);
+        assert_eq!(info.pieces_intersecting_file(1), 0..2);
+        assert_eq!(info.pieces_intersecting_file(2), 1..2);
+        assert_eq!(info.pieces_intersecting_file(4), 2..3);
+        assert_eq!(info.pieces_intersecting_file(5), 3..4);
+        assert_eq!(info.pieces_intersecting_file(6), 4..5);
     }
 
     #[test]
@@ -429,6 +557,7 @@ mod tests {
             last_piece_len: 2,
             download_len,
             download_dir: PathBuf::from("/"),
+            is_archive: false,
             files,
         };
         assert_eq!(info.files_intersecting_bytes(0..0), 0..1);
@@ -466,6 +595,7 @@ mod tests {
             last_piece_len: 2,
             download_len,
             download_dir: PathBuf::from("/"),
+            is_archive: true,
             files,
         };
 
@@ -492,4 +622,71 @@ mod tests {
         // bytes not intersecting any files
         assert_eq!(info.files_intersecting_bytes(30..38), 0..0);
     }
+
+    #[test]
+    fn test_rename() {
+        let file = |path: &str, torrent_offset| FileInfo {
+            path: PathBuf::from(path),
+            torrent_offset,
+            len: 4,
+        };
+        let mut info = StorageInfo {
+            piece_count: 2,
+            piece_len: 4,
+            last_piece_len: 4,
+            download_len: 8,
+            download_dir: PathBuf::from("/downloads/torrent"),
+            is_archive: true,
+            files: vec![file("a", 0), file("b", 4)],
+        };
+
+        let rename = Rename::File {
+            index: 1,
+            path: PathBuf::from("sub/c"),
+        };
+        assert!(rename.is_valid());
+        assert_eq!(info.rename(rename.clone()), Some(rename));
+        assert_eq!(info.files[1].path, PathBuf::from("sub/c"));
+        assert_eq!(
+            info.rename(Rename::File {
+                index: 2,
+                path: PathBuf::from("d"),
+            }),
+            None
+        );
+
+        // an archive's own directory is renamed
+        let rename = Rename::Root("renamed".into());
+        assert_eq!(info.rename(rename.clone()), Some(rename));
+        assert_eq!(info.download_dir, PathBuf::from("/downloads/renamed"));
+
+        // an archive with a single file is still renamed as a directory
+        info.files.truncate(1);
+        let rename = Rename::Root("torrent".into());
+        assert_eq!(info.rename(rename.clone()), Some(rename));
+        assert_eq!(info.download_dir, PathBuf::from("/downloads/torrent"));
+
+        // the only file of a single file torrent is renamed
+        info.is_archive = false;
+        assert_eq!(
+            info.rename(Rename::Root("single".into())),
+            Some(Rename::File {
+                index: 0,
+                path: PathBuf::from("single"),
+            })
+        );
+        assert_eq!(info.download_dir, PathBuf::from("/downloads/torrent"));
+        assert_eq!(info.files[0].path, PathBuf::from("single"));
+
+        // paths must stay within the torrent's root
+        for path in &["", "/abs", "../up", "a/../../up"] {
+            assert!(!Rename::File {
+                index: 0,
+                path: PathBuf::from(path),
+            }
+            .is_valid());
+        }
+        assert!(!Rename::Root("a/b".into()).is_valid());
+        assert!(!Rename::Root("..".into()).is_valid());
+    }
 }
@@ -0,0 +1,301 @@
+//! Streaming a torrent's files while they are being downloaded.
+//!
+//! A [`FileStream`] is opened with
+//! [`EngineHandle::open_file`](crate::engine::EngineHandle::open_file). It
+//! reads the file's pieces through the torrent, which has the disk task read
+//! the pieces it has, and holds back the reads of those it doesn't until they
+//! are downloaded. The pieces being waited for are given a deadline, so that
+//! they're downloaded before all others. This makes it possible to e.g. play
+//! a media file while it's downloading, seeking to any position in it.
+
+use std::{
+    fmt,
+    future::Future,
+    io::{self, SeekFrom},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::ready;
+use tokio::{
+    io::{AsyncRead, AsyncSeek},
+    sync::oneshot,
+};
+
+use crate::{
+    disk::error::ReadError, logging, storage_info::FileInfo, torrent,
+    CachedBlock, PieceIndex, BLOCK_LEN,
+};
+
+/// A file of a torrent, which can be read while the torrent is downloading.
+///
+/// Reading a part of the file that's not downloaded yet waits until it is,
+/// and has the torrent download it before the other pieces. Reads fail with
+/// [`io::ErrorKind::BrokenPipe`] once the torrent is removed or the engine is
+/// shut down.
+///
+/// The piece the stream's position is in is kept in memory, so reads in
+/// small chunks don't each go to the disk.
+pub struct FileStream {
+    /// The file's length and its offset in the torrent.
+    file: FileInfo,
+    /// The torrent's nominal piece length.
+    piece_len: u32,
+    /// The current position in the file.
+    pos: u64,
+    /// The blocks of the last piece read.
+    piece: Option<(PieceIndex, Vec<CachedBlock>)>,
+    /// The piece being read, which the torrent sends once it has it.
+    pending_read: Option<(PieceIndex, PieceReceiver)>,
+    /// The pieces are read by sending the torrent commands on this channel.
+    torrent_tx: torrent::Sender,
+}
+
+/// The channel on which the blocks of a piece read for the stream arrive.
+type PieceReceiver =
+    oneshot::Receiver<std::result::Result<Vec<CachedBlock>, ReadError>>;
+
+impl FileStream {
+    pub(crate) fn new(
+        file: FileInfo,
+        piece_len: u32,
+        torrent_tx: torrent::Sender,
+    ) -> Self {
+        Self {
+            file,
+            piece_len,
+            pos: 0,
+            piece: None,
+            pending_read: None,
+            torrent_tx,
+        }
+    }
+
+    /// Returns the length of the file.
+    pub fn len(&self) -> u64 {
+        self.file.len
+    }
+
+    /// Returns whether the file is empty.
+    pub fn is_empty(&self) -> bool {
+        self.file.len == 0
+    }
+
+    /// Returns the current position in the file.
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+}
+
+impl fmt::Debug for FileStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the piece in memory is left out, as it's large
+        f.debug_struct("FileStream")
+            .field("file", &self.file)
+            .field("pos", &self.pos)
+            .finish()
+    }
+}
+
+impl AsyncRead for FileStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.pos >= this.file.len || buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let torrent_offset = this.file.torrent_offset + this.pos;
+        let piece_index =
+            (torrent_offset / this.piece_len as u64) as PieceIndex;
+        loop {
+            if let Some((index, blocks)) = &this.piece {
+                if *index == piece_index {
+                    let piece_offset =
+                        torrent_offset - *index as u64 * this.piece_len as u64;
+                    // don't read past the end of the file, into the next
+                    let file_left = this.file.len - this.pos;
+                    let len = buf.len().min(file_left as usize);
+                    let read_count = copy_from_blocks(
+                        blocks,
+                        piece_offset as usize,
+                        &mut buf[..len],
+                    );
+                    this.pos += read_count as u64;
+                    return Poll::Ready(Ok(read_count));
+                }
+            }
+
+            match &mut this.pending_read {
+                Some((index, rx)) if *index == piece_index => {
+                    let result = ready!(Pin::new(rx).poll(cx));
+                    this.pending_read = None;
+                    match result {
+                        Ok(Ok(blocks)) => {
+                            this.piece = Some((piece_index, blocks));
+                        }
+                        Ok(Err(ReadError::Io(e))) => {
+                            return Poll::Ready(Err(e));
+                        }
+                        Ok(Err(e)) => {
+                            return Poll::Ready(Err(io::Error::new(
+                                io::ErrorKind::Other,
+                                e.to_string(),
+                            )));
+                        }
+                        Err(_) => return Poll::Ready(Err(torrent_gone())),
+                    }
+                }
+                // the position was moved to another piece, in which case
+                // the earlier read is abandoned
+                _ => {
+                    log::trace!(
+                        target: logging::TORRENT,
+                        "Reading piece {} for stream",
+                        piece_index
+                    );
+                    let (result_tx, rx) = oneshot::channel();
+                    let cmd = torrent::Command::ReadPiece {
+                        piece_index,
+                        result_tx,
+                    };
+                    if this.torrent_tx.send(cmd).is_err() {
+                        return Poll::Ready(Err(torrent_gone()));
+                    }
+                    this.pending_read = Some((piece_index, rx));
+                }
+            }
+        }
+    }
+}
+
+impl AsyncSeek for FileStream {
+    fn start_seek(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        position: SeekFrom,
+    ) -> Poll<io::Result<()>> {
+        let pos = match position {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => offset(self.file.len, delta),
+            SeekFrom::Current(delta) => offset(self.pos, delta),
+        };
+        match pos {
+            // seeking past the end is allowed, after which reads return 0
+            // bytes, as with files
+            Some(pos) => {
+                self.pos = pos;
+                Poll::Ready(Ok(()))
+            }
+            None => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            ))),
+        }
+    }
+
+    fn poll_complete(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.pos))
+    }
+}
+
+/// Returns the position moved by the signed delta, if it's valid.
+fn offset(pos: u64, delta: i64) -> Option<u64> {
+    if delta >= 0 {
+        pos.checked_add(delta as u64)
+    } else {
+        pos.checked_sub(delta.wrapping_neg() as u64)
+    }
+}
+
+/// Copies the bytes of the piece's blocks starting at the offset in piece
+/// into the buffer, up to the end of the block the offset is in, and returns
+/// the number of bytes copied.
+fn copy_from_blocks(
+    blocks: &[CachedBlock],
+    piece_offset: usize,
+    buf: &mut [u8],
+) -> usize {
+    // all blocks but the last are of the default length
+    let block_len = BLOCK_LEN as usize;
+    let block = match blocks.get(piece_offset / block_len) {
+        Some(block) => &block[piece_offset % block_len..],
+        None => return 0,
+    };
+    let len = block.len().min(buf.len());
+    buf[..len].copy_from_slice(&block[..len]);
+    len
+}
+
+fn torrent_gone() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "torrent no longer running")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use tokio::{io::AsyncReadExt, io::AsyncSeekExt, sync::mpsc};
+
+    use super::*;
+
+    /// Tests that the stream reads the file's pieces through the torrent,
+    /// from its position and within the file's bounds.
+    #[tokio::test]
+    async fn should_read_file_pieces_through_torrent() {
+        let piece_len = 2 * BLOCK_LEN;
+        // the file starts in the middle of the first piece and ends in the
+        // middle of the second
+        let file = FileInfo {
+            path: PathBuf::from("stream"),
+            torrent_offset: BLOCK_LEN as u64 + 10,
+            len: piece_len as u64,
+        };
+        let pieces: Vec<Vec<u8>> = (0..2)
+            .map(|i| {
+                (0..piece_len).map(|b| (b as u8).wrapping_add(i)).collect()
+            })
+            .collect();
+        let torrent_data = pieces.concat();
+
+        let (torrent_tx, mut torrent_rx) = mpsc::unbounded_channel();
+        let torrent = tokio::spawn(async move {
+            while let Some(cmd) = torrent_rx.recv().await {
+                if let torrent::Command::ReadPiece {
+                    piece_index,
+                    result_tx,
+                } = cmd
+                {
+                    let blocks = pieces[piece_index]
+                        .chunks(BLOCK_LEN as usize)
+                        .map(|block| Arc::new(block.to_vec()))
+                        .collect();
+                    result_tx.send(Ok(blocks)).unwrap();
+                }
+            }
+        });
+
+        let mut stream = FileStream::new(file.clone(), piece_len, torrent_tx);
+        let mut content = Vec::new();
+        stream.read_to_end(&mut content).await.unwrap();
+        let file_start = file.torrent_offset as usize;
+        let file_end = file.torrent_end_offset() as usize;
+        assert_eq!(content, &torrent_data[file_start..file_end]);
+
+        // seek back into the first piece
+        stream.seek(SeekFrom::Start(5)).await.unwrap();
+        let mut buf = [0; 3];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, &torrent_data[file_start + 5..file_start + 8]);
+
+        // the mock torrent stops once the stream is dropped
+        drop(stream);
+        torrent.await.unwrap();
+    }
+}
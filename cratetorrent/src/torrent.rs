@@ -1,6 +1,7 @@
 use std::{
-    collections::HashMap,
-    net::SocketAddr,
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -9,36 +10,63 @@ use futures::{
     select,
     stream::{Fuse, StreamExt},
 };
+use reqwest::Url;
 use tokio::{
     net::{TcpListener, TcpStream},
     sync::{
         mpsc::{self, UnboundedReceiver, UnboundedSender},
-        RwLock,
+        oneshot, RwLock,
     },
-    task, time,
 };
+use tracing::Instrument;
 
 use crate::{
     alert::{Alert, AlertSender},
-    conf::TorrentConf,
+    conf::{AnnounceConf, ProxyConf, RateLimitConf, SocketConf, TorrentConf},
     counter::ThruputCounters,
+    dht,
     disk::{
         self,
         error::{ReadError, WriteError},
     },
-    download::PieceDownload,
-    error::Error,
+    download::{InFlightRequests, PieceDownload},
+    engine,
+    error::{DiskError, Error},
+    external_ip::{ExternalIp, Voter},
+    ip_filter::SharedIpFilter,
+    local_addr::LocalAddr,
+    lsd, metrics,
     peer::{self, ConnectionState, PeerSession, SessionState, SessionTick},
-    piece_picker::PiecePicker,
+    piece_picker::{PiecePicker, PiecePickerFactory, RarestFirstPicker},
+    port_mapping::{self, Protocol},
+    rate_limit::BandwidthShare,
+    rt,
+    state::{SavedStats, TorrentState},
     storage_info::StorageInfo,
-    tracker::{Announce, Event, Tracker},
-    Bitfield, BlockInfo, PeerId, PieceIndex, Sha1Hash, TorrentId,
+    stream::FileStream,
+    tracker::{Announce, Event, Response, Tracker, TrackerError},
+    Bitfield, BlockInfo, FileIndex, FilePriority, PeerId, PieceIndex, Sha1Hash,
+    TorrentId, MAX_BLOCK_LEN,
 };
+use connectability::ConnectabilityCheck;
 use error::*;
-use stats::{Peers, PieceStats, ThruputStats, TorrentStats};
+use error_retry::ErrorRetry;
+use peer_sources::{PeerSource, PeerSources};
+use seed_goals::SeedGoalCheck;
+use stats::{
+    Connectability, MessageStats, Peers, PieceInfo, PieceState, PieceStats,
+    SwarmStats, ThruputStats, TorrentStats, TrackerInfo, TrackerStatus,
+};
+use trackers::{AnnounceTarget, TrackerEntry, Trackers};
 
+mod connectability;
 pub mod error;
+mod error_retry;
+pub(crate) mod metadata;
+mod peer_sources;
+mod seed_goals;
 pub mod stats;
+mod trackers;
 
 /// The channel for communicating with torrent.
 pub(crate) type Sender = UnboundedSender<Command>;
@@ -51,9 +79,13 @@ pub(crate) type Receiver = UnboundedReceiver<Command>;
 /// engine.
 #[derive(Debug)]
 pub(crate) enum Command {
-    /// Sent when some blocks were written to disk or an error ocurred while
-    /// writing.
-    PieceCompletion(Result<PieceCompletion, WriteError>),
+    /// Sent when a piece was hashed, and if valid, written to disk.
+    PieceCompletion(PieceCompletion),
+    /// There was an error writing a valid piece to disk.
+    PieceWriteFailed {
+        index: PieceIndex,
+        error: WriteError,
+    },
     /// There was an error reading a block.
     ReadError {
         block_info: BlockInfo,
@@ -64,6 +96,88 @@ pub(crate) enum Command {
     /// Peer sessions periodically send this message when they have a state
     /// change.
     PeerState { addr: SocketAddr, info: SessionTick },
+    /// Sets or clears the deadline of a piece.
+    SetPieceDeadline {
+        piece_index: PieceIndex,
+        deadline: Option<Instant>,
+    },
+    /// Sets the download priority of a file.
+    SetFilePriority {
+        file_index: FileIndex,
+        priority: FilePriority,
+    },
+    /// Opens a file of the torrent for streaming and sends it via the sender.
+    OpenFile {
+        file_index: FileIndex,
+        result_tx: oneshot::Sender<std::result::Result<FileStream, Error>>,
+    },
+    /// Reads a piece for a file stream once the torrent has it, and sends its
+    /// blocks via the sender.
+    ReadPiece {
+        piece_index: PieceIndex,
+        result_tx: disk::PieceSender,
+    },
+    /// Posts the stats of the last tick to the user.
+    QueryStats,
+    /// Posts the state of each piece to the user.
+    QueryPieces,
+    /// Posts the status of each tracker to the user.
+    QueryTrackers,
+    /// Announces to trackers, or only to the tracker with the given URL, as
+    /// soon as their minimum announce interval allows, or right away if it is
+    /// to be ignored.
+    ForceReannounce {
+        tracker: Option<Url>,
+        ignore_min_interval: bool,
+    },
+    /// Looks up and announces the torrent in the DHT right away.
+    ForceDhtAnnounce,
+    /// Adds a tracker at the end of the tier with the given index.
+    AddTracker { tracker: Tracker, tier: usize },
+    /// Removes the tracker with the given URL.
+    RemoveTracker(Url),
+    /// The result of an announce with the given event to the tracker with
+    /// the given URL, and how long the tracker took to respond.
+    TrackerAnnounced {
+        url: Url,
+        event: Option<Event>,
+        result: std::result::Result<Response, TrackerError>,
+        response_time: Duration,
+    },
+    /// Peers of the torrent found in the DHT.
+    DhtPeers(Vec<SocketAddr>),
+    /// Peers of the torrent found on the local network.
+    LsdPeers(Vec<SocketAddr>),
+    /// Probes whether the listen port is reachable right away and posts the
+    /// result to the user.
+    CheckConnectability,
+    /// The result of the probe of the listen port through our external
+    /// address.
+    ConnectabilityProbe { is_reachable: bool },
+    /// Disconnects all peers and stops announcing the torrent until resumed.
+    Pause,
+    /// Continues a paused torrent.
+    Resume,
+    /// Stops the torrent like [`Command::Pause`] if set, as it has no slot in
+    /// the engine's queue, or starts it again once it was given one.
+    SetQueued(bool),
+    /// Stops the torrent like [`Command::Pause`] if set, as the whole session
+    /// is paused, or starts it again once the session is resumed. The
+    /// torrent's own paused state is left as it is.
+    SetSessionPaused(bool),
+    /// Changes whether the torrent is auto-managed, and so whether it's
+    /// retried automatically after an error.
+    SetAutoManaged(bool),
+    /// Starts the torrent stopped by an error again.
+    ClearError,
+    /// Stops the torrent and has the disk task hash all its pieces, after
+    /// which the torrent continues with the pieces found valid.
+    ForceRecheck,
+    /// The pieces found valid by the disk task when rechecking the torrent.
+    PiecesChecked(Bitfield),
+    /// Sends the engine the state of the torrent that is saved in its resume
+    /// data.
+    SaveState,
     /// Gracefully shut down the torrent.
     ///
     /// This command tells all active peer sessions of torrent to do the same,
@@ -94,6 +208,35 @@ pub(crate) struct TorrentContext {
     /// The arbitrary client id, chosen by the user of this library. This is
     /// advertised to peers and trackers.
     pub client_id: PeerId,
+    /// The options applied to the sockets of all peer connections.
+    pub socket_conf: SocketConf,
+    /// If set, outbound peer connections are made through this proxy.
+    pub proxy: Option<ProxyConf>,
+    /// The rate limits applied to each peer session individually.
+    pub peer_rate_limit: RateLimitConf,
+    /// The torrent's share of the engine-wide download rate limit, shared by
+    /// all its peer sessions.
+    pub download_bandwidth: Arc<BandwidthShare>,
+    /// The torrent's share of the engine-wide upload rate limit, shared by all
+    /// its peer sessions.
+    pub upload_bandwidth: Arc<BandwidthShare>,
+    /// If set, the sessions of peers on the local network don't draw from the
+    /// torrent's bandwidth shares.
+    pub exempt_local_peers_from_rate_limit: bool,
+    /// If set, protocol messages count towards the rate limits along with
+    /// the block payloads.
+    pub rate_limit_protocol_overhead: bool,
+    /// Collects the external IP address reports of trackers and peers across
+    /// the engine.
+    pub external_ip: Arc<ExternalIp>,
+    /// The engine's IP filter, which the peers of all sources must pass.
+    pub ip_filter: Arc<SharedIpFilter>,
+    /// The local address to which outbound peer connections are bound.
+    pub local_addr: Arc<LocalAddr>,
+    /// The maximum number of pieces that may be downloaded at the same time.
+    pub max_partial_piece_count: usize,
+    /// The length of the blocks in which pieces are requested.
+    pub block_len: u32,
 
     /// A copy of the torrent channel sender. This is not used by torrent iself,
     /// but by the peer session tasks to which an arc copy of this torrent
@@ -102,7 +245,7 @@ pub(crate) struct TorrentContext {
 
     /// The piece picker picks the next most optimal piece to download and is
     /// shared by all peers in a torrent.
-    pub piece_picker: Arc<RwLock<PiecePicker>>,
+    pub piece_picker: Arc<RwLock<Box<dyn PiecePicker>>>,
     /// These are the active piece downloads in which the peer sessions in this
     /// torrent are participating.
     ///
@@ -115,6 +258,12 @@ pub(crate) struct TorrentContext {
     // TODO: Benchmark whether using the nested locking approach isn't too slow.
     // For mvp it should do.
     pub downloads: RwLock<HashMap<PieceIndex, RwLock<PieceDownload>>>,
+    /// The block requests that are in flight, and the peers to which they
+    /// were sent.
+    ///
+    /// When locked together with `downloads`, `downloads` must be locked
+    /// first.
+    pub in_flight_requests: RwLock<InFlightRequests>,
 
     /// The channel on which to post alerts to user.
     pub alert_tx: AlertSender,
@@ -124,6 +273,9 @@ pub(crate) struct TorrentContext {
     pub disk_tx: disk::Sender,
     /// Info about the torrent's storage (piece length, download length, etc).
     pub storage: StorageInfo,
+    /// The torrent's bencoded info dictionary, which is served to peers that
+    /// download it with the metadata exchange extension, if we have it.
+    pub metadata: Option<Vec<u8>>,
 }
 
 /// Parameters for the torrent constructor.
@@ -132,11 +284,47 @@ pub(crate) struct Params {
     pub disk_tx: disk::Sender,
     pub info_hash: Sha1Hash,
     pub storage_info: StorageInfo,
+    /// The torrent's bencoded info dictionary, if known.
+    pub metadata: Option<Vec<u8>>,
     pub own_pieces: Bitfield,
-    pub trackers: Vec<Tracker>,
+    pub piece_picker: Option<PiecePickerFactory>,
+    /// The tiers of trackers, in order of preference.
+    pub trackers: Vec<Vec<Tracker>>,
     pub client_id: PeerId,
+    pub socket_conf: SocketConf,
+    pub proxy: Option<ProxyConf>,
     pub listen_addr: SocketAddr,
+    /// The ports to listen on if the listen address' port is 0 or taken.
+    pub listen_ports: Option<RangeInclusive<u16>>,
+    pub announce_conf: AnnounceConf,
+    pub external_ip: Arc<ExternalIp>,
+    pub ip_filter: Arc<SharedIpFilter>,
+    pub local_addr: Arc<LocalAddr>,
+    /// Set if the torrent should look for peers in the DHT.
+    pub dht_tx: Option<dht::Sender>,
+    /// Set if the torrent should look for peers on the local network.
+    pub lsd_tx: Option<lsd::Sender>,
+    /// Set if the torrent's listening port should be mapped on the gateway.
+    pub port_mapping_tx: Option<port_mapping::Sender>,
+    /// The engine's channel, on which it's told when the torrent becomes
+    /// a seed.
+    pub engine_tx: engine::Sender,
+    /// Whether the torrent starts out queued, without a slot.
+    pub is_queued: bool,
+    /// Whether the torrent starts out paused, as restored from its resume
+    /// data.
+    pub is_paused: bool,
+    /// Whether the torrent starts out stopped as the whole session is paused.
+    pub is_session_paused: bool,
+    /// Whether the torrent is retried automatically after an error.
+    pub is_auto_managed: bool,
+    /// The statistics carried over from the torrent's earlier runs.
+    pub saved_stats: SavedStats,
     pub conf: TorrentConf,
+    pub download_bandwidth: Arc<BandwidthShare>,
+    pub upload_bandwidth: Arc<BandwidthShare>,
+    pub exempt_local_peers_from_rate_limit: bool,
+    pub rate_limit_protocol_overhead: bool,
     pub alert_tx: AlertSender,
 }
 
@@ -149,8 +337,11 @@ pub(crate) struct Params {
 pub(crate) struct Torrent {
     /// The peers in this torrent.
     peers: HashMap<SocketAddr, PeerSessionEntry>,
-    /// The peers returned by tracker to which we can connect.
-    available_peers: Vec<SocketAddr>,
+    /// The peers we can connect to, from all sources.
+    peer_sources: PeerSources,
+    /// The IPs of the peers that were found to have sent corrupt data. We
+    /// neither connect to them nor accept their connections.
+    banned_peers: HashSet<IpAddr>,
     /// Information that is shared with peer sessions.
     ctx: Arc<TorrentContext>,
     /// The port on which other entities in the engine send this torrent
@@ -159,11 +350,53 @@ pub(crate) struct Torrent {
     /// The channel has to be wrapped in a `stream::Fuse` so that we can
     /// `select!` on it in the torrent event loop.
     cmd_rx: Fuse<Receiver>,
-    /// The trackers we can announce to.
-    trackers: Vec<TrackerEntry>,
+    /// The tiers of trackers we can announce to.
+    trackers: Trackers,
+    /// Set when the user requested a reannounce, which is performed on the
+    /// next tick in which the tracker's minimum announce interval allows it.
+    is_reannounce_pending: bool,
 
     /// The address on which torrent should listen for new peers.
     listen_addr: SocketAddr,
+    /// The ports tried, in order, if the port of `listen_addr` is 0 or taken.
+    listen_ports: Option<RangeInclusive<u16>>,
+    /// The parameters with which we identify ourselves to trackers.
+    announce_conf: AnnounceConf,
+    /// The channel of the engine's DHT node, if the torrent is looked up in
+    /// the DHT.
+    dht_tx: Option<dht::Sender>,
+    /// The channel of the engine's LSD service, if the torrent is announced
+    /// on the local network.
+    lsd_tx: Option<lsd::Sender>,
+    /// The channel of the engine's port mapper, if port mapping is enabled.
+    port_mapping_tx: Option<port_mapping::Sender>,
+    /// The engine's channel, on which it's told when the torrent becomes
+    /// a seed, so that it's moved to a seeding slot, when it reached a seed
+    /// goal, and of its state to be saved.
+    engine_tx: engine::Sender,
+    /// Whether peers can connect to our listen port.
+    connectability: ConnectabilityCheck,
+    /// The progress towards the seed goals, once the torrent is a seed.
+    seed_goals: SeedGoalCheck,
+
+    /// Set while the torrent is paused, in which case it's not connected to
+    /// any peers and is not announced anywhere.
+    is_paused: bool,
+    /// Set while the torrent has no slot in the engine's queue, in which case
+    /// it's stopped the same way as when paused.
+    is_queued: bool,
+    /// Set while the engine's whole session is paused, in which case it's
+    /// stopped the same way as when paused.
+    is_session_paused: bool,
+    /// Whether the torrent is stopped by an error accessing its files, and
+    /// when it's retried.
+    error_retry: ErrorRetry,
+    /// Whether the torrent is auto-managed, in which case it's retried
+    /// automatically after an error.
+    is_auto_managed: bool,
+    /// Set while the disk task is rechecking the torrent's pieces, during
+    /// which it's stopped the same way as when paused.
+    is_checking: bool,
 
     /// The time the torrent was first started.
     start_time: Option<Instant>,
@@ -172,8 +405,6 @@ pub(crate) struct Torrent {
     /// This is a separate field as `Instant::now() - start_time` cannot be
     /// relied upon due to the fact that it is possible to pause a torrent, in
     /// which case we don't want to record the run time.
-    // TODO: pausing a torrent is not actually at this point, but this is done
-    // in expectation of that feature
     run_duration: Duration,
 
     /// In the last part of the download the torrent is in what's called the
@@ -188,6 +419,14 @@ pub(crate) struct Torrent {
 
     /// Measures various transfer statistics.
     counters: ThruputCounters,
+    /// The peer count last added to the engine-wide peer count metric.
+    reported_peer_count: usize,
+    /// The number and length of the messages exchanged with all peers, by
+    /// message type.
+    messages: MessageStats,
+    /// The stats built in the last tick, which are posted on request without
+    /// having to lock the piece picker and downloads again.
+    stats: Box<TorrentStats>,
 
     /// The configuration of this particular torrent.
     conf: TorrentConf,
@@ -199,6 +438,15 @@ pub(crate) struct Torrent {
     /// This is set to some if the configuration is enabled, and set to none if
     /// disabled.
     completed_pieces: Option<Vec<PieceIndex>>,
+
+    /// The download priority of each file, from which the priorities of the
+    /// pieces in the piece picker are derived.
+    file_priorities: Vec<FilePriority>,
+    /// The number of pieces intersecting each file that we don't have yet.
+    file_missing_piece_counts: Vec<usize>,
+    /// The reads of file streams waiting for pieces that are not downloaded
+    /// yet, which are sent to the disk task once they are.
+    piece_reads: HashMap<PieceIndex, Vec<disk::PieceSender>>,
 }
 
 impl Torrent {
@@ -214,48 +462,140 @@ impl Torrent {
             disk_tx,
             info_hash,
             storage_info,
+            metadata,
             own_pieces,
+            piece_picker,
             trackers,
             client_id,
+            socket_conf,
+            proxy,
             listen_addr,
+            listen_ports,
+            announce_conf,
+            external_ip,
+            ip_filter,
+            local_addr,
+            dht_tx,
+            lsd_tx,
+            port_mapping_tx,
+            engine_tx,
+            is_queued,
+            is_paused,
+            is_session_paused,
+            is_auto_managed,
+            saved_stats,
             conf,
+            download_bandwidth,
+            upload_bandwidth,
+            exempt_local_peers_from_rate_limit,
+            rate_limit_protocol_overhead,
             alert_tx,
         } = params;
 
         let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
-        let piece_picker = PiecePicker::new(own_pieces);
+        let mut piece_picker = match piece_picker {
+            Some(make_piece_picker) => make_piece_picker(own_pieces),
+            None => {
+                let mut piece_picker = RarestFirstPicker::new(own_pieces);
+                piece_picker
+                    .set_random_first_count(conf.random_first_piece_count);
+                if conf.sequential_download {
+                    piece_picker
+                        .set_sequential(Some(conf.sequential_lookahead));
+                }
+                Box::new(piece_picker) as Box<dyn PiecePicker>
+            }
+        };
+        debug_assert_eq!(
+            piece_picker.own_pieces().len(),
+            storage_info.piece_count
+        );
         let cmd_rx = cmd_rx.fuse();
-        let trackers = trackers.into_iter().map(TrackerEntry::new).collect();
+        let file_priorities =
+            vec![FilePriority::default(); storage_info.files.len()];
+        let file_missing_piece_counts = (0..storage_info.files.len())
+            .map(|file_index| {
+                storage_info
+                    .pieces_intersecting_file(file_index)
+                    .filter(|index| !piece_picker.own_pieces()[*index])
+                    .count()
+            })
+            .collect();
+        update_piece_boosts(
+            &storage_info,
+            &file_priorities,
+            &conf,
+            &mut piece_picker,
+        );
         let completed_pieces = if conf.alerts.completed_pieces {
             Some(Vec::new())
         } else {
             None
         };
+        let mut counters = ThruputCounters::default();
+        counters.payload.up.set_total(saved_stats.uploaded);
+        counters.payload.down.set_total(saved_stats.downloaded);
 
         (
             Self {
                 peers: HashMap::new(),
-                available_peers: Vec::new(),
+                peer_sources: PeerSources::default(),
+                banned_peers: HashSet::new(),
                 ctx: Arc::new(TorrentContext {
                     id,
                     cmd_tx: cmd_tx.clone(),
                     piece_picker: Arc::new(RwLock::new(piece_picker)),
                     downloads: RwLock::new(HashMap::new()),
+                    in_flight_requests: RwLock::new(InFlightRequests::default()),
                     info_hash,
                     client_id,
+                    socket_conf,
+                    proxy,
+                    peer_rate_limit: conf.peer_rate_limit,
+                    download_bandwidth,
+                    upload_bandwidth,
+                    exempt_local_peers_from_rate_limit,
+                    rate_limit_protocol_overhead,
+                    external_ip,
+                    ip_filter,
+                    local_addr,
+                    max_partial_piece_count: conf.max_partial_piece_count,
+                    block_len: conf.block_len.max(1).min(MAX_BLOCK_LEN),
                     alert_tx,
                     disk_tx,
                     storage: storage_info,
+                    metadata,
                 }),
+                is_paused,
+                is_queued,
+                is_session_paused,
+                error_retry: ErrorRetry::default(),
+                is_auto_managed,
+                is_checking: false,
                 start_time: None,
-                run_duration: Duration::default(),
+                run_duration: saved_stats.run_duration,
                 cmd_rx,
-                trackers,
+                trackers: Trackers::new(trackers),
+                is_reannounce_pending: false,
                 in_endgame: false,
-                counters: Default::default(),
+                counters,
+                reported_peer_count: 0,
+                messages: Default::default(),
+                stats: Default::default(),
                 listen_addr,
+                listen_ports,
+                announce_conf,
+                dht_tx,
+                lsd_tx,
+                port_mapping_tx,
+                engine_tx,
+                connectability: ConnectabilityCheck::new(Instant::now()),
+                seed_goals: SeedGoalCheck::default(),
                 conf,
                 completed_pieces,
+                file_priorities,
+                file_missing_piece_counts,
+                piece_reads: HashMap::new(),
             },
             cmd_tx,
         )
@@ -265,7 +605,7 @@ impl Torrent {
     pub async fn start(&mut self, peers: &[SocketAddr]) -> Result<()> {
         log::info!("Starting torrent");
 
-        self.available_peers.extend_from_slice(peers);
+        self.add_peers(peers.to_vec(), PeerSource::Manual);
 
         // record the torrent starttime
         self.start_time = Some(Instant::now());
@@ -278,8 +618,9 @@ impl Torrent {
             } else {
                 Some(Event::Started)
             };
+        self.trackers.pending_event = tracker_event;
         if let Err(e) = self
-            .announce_to_trackers(Instant::now(), tracker_event)
+            .announce_to_trackers(Instant::now(), tracker_event, false)
             .await
         {
             // this is a torrent error, not a tracker error, as that is handled
@@ -309,21 +650,43 @@ impl Torrent {
 
     /// Starts the torrent and runs until an error is encountered.
     async fn run(&mut self) -> Result<()> {
-        let mut tick_timer = time::interval(Duration::from_secs(1)).fuse();
+        let mut tick_timer = rt::interval(Duration::from_secs(1)).fuse();
         let mut last_tick_time = None;
 
-        let mut listener = TcpListener::bind(&self.listen_addr).await?;
+        let mut listener = self.bind_listener().await?;
         // the bind port may have been 0, so we need to get the actual port in
         // use
         self.listen_addr = listener.local_addr()?;
+        log::info!("Listening on {}", self.listen_addr);
+        self.ctx
+            .alert_tx
+            .send(Alert::Listening {
+                id: self.ctx.id,
+                addr: self.listen_addr,
+            })
+            .ok();
         let mut incoming = listener.incoming().fuse();
 
+        // now that the port is known, the torrent can be announced in the DHT
+        // and on the local network, unless it starts out queued
+        if !self.is_stopped() {
+            self.add_to_dht_and_lsd();
+        }
+        if let Some(port_mapping_tx) = &self.port_mapping_tx {
+            port_mapping_tx
+                .send(port_mapping::Command::AddPort {
+                    protocol: Protocol::Tcp,
+                    port: self.listen_addr.port(),
+                })
+                .ok();
+        }
+
         // the torrent loop is triggered every second by the loop timer and by
         // disk IO events
         loop {
             select! {
                 tick_time = tick_timer.select_next_some() => {
-                    self.tick(&mut last_tick_time, tick_time.into_std()).await?;
+                    self.tick(&mut last_tick_time, tick_time).await?;
                 }
                 peer_conn_result = incoming.select_next_some() => {
                     let socket = match peer_conn_result {
@@ -340,14 +703,35 @@ impl Torrent {
                             continue;
                         }
                     };
+                    if self.banned_peers.contains(&addr.ip()) {
+                        log::info!("Rejecting connection from banned peer {}", addr);
+                        continue;
+                    }
+                    if self.is_stopped() {
+                        log::info!(
+                            "Rejecting connection from {} while stopped",
+                            addr
+                        );
+                        continue;
+                    }
+                    if !self.ctx.ip_filter.allows_connection(&addr) {
+                        continue;
+                    }
                     log::info!("New connection {:?}", addr);
 
                     // start inbound session
+                    let is_local = self.is_local_peer(&addr);
                     let (session, tx) = PeerSession::new(
                         Arc::clone(&self.ctx),
                         addr,
+                        is_local,
+                    );
+                    self.peers.insert(
+                        addr,
+                        PeerSessionEntry::start_inbound(
+                            socket, session, tx, is_local,
+                        ),
                     );
-                    self.peers.insert(addr, PeerSessionEntry::start_inbound(socket, session, tx));
                 }
                 cmd = self.cmd_rx.select_next_some() => {
                     match cmd {
@@ -359,24 +743,39 @@ impl Torrent {
                                     addr, String::from_utf8_lossy(&id)
                                 );
                                 peer.id = Some(id);
+                                self.peer_sources.handle_connected(&addr);
+                                self.ctx
+                                    .alert_tx
+                                    .send(Alert::PeerConnected {
+                                        id: self.ctx.id,
+                                        addr,
+                                        peer_id: id,
+                                    })
+                                    .ok();
+                                if peer.is_inbound
+                                    && self
+                                        .connectability
+                                        .handle_incoming_peer(addr.ip())
+                                {
+                                    self.post_connectability();
+                                }
                             }
                         }
                         Command::PeerState { addr, info } => {
                             self.handle_peer_state_change(addr, info);
                         }
-                        Command::PieceCompletion(write_result) => {
-                            log::debug!("Disk write result {:?}", write_result);
-                            match write_result {
-                                Ok(piece) => {
-                                    self.handle_piece_completion(piece).await?;
-                                }
-                                Err(e) => {
-                                    log::error!(
-                                        "Failed to write piece to disk: {}",
-                                        e
-                                    );
-                                }
-                            }
+                        Command::PieceCompletion(piece) => {
+                            log::debug!("Disk write result {:?}", piece);
+                            self.handle_piece_completion(piece).await?;
+                        }
+                        Command::PieceWriteFailed { index, error } => {
+                            log::error!(
+                                "Failed to write piece {} to disk: {}",
+                                index,
+                                error
+                            );
+                            self.handle_piece_write_failure(index, error)
+                                .await?;
                         }
                         Command::ReadError { block_info, error } => {
                             log::error!(
@@ -384,24 +783,501 @@ impl Torrent {
                                 block_info,
                                 error
                             );
-                            // TODO: For now we just log for simplicity's sake, but in the
-                            // future we'll need error recovery mechanisms here.
-                            // For instance, it may be that the torrent file got moved while
-                            // the torrent was still seeding. In this case we'd need to stop
-                            // torrent and send an alert to the API consumer.
+                            // e.g. the torrent's files were moved or their
+                            // permissions changed, so the torrent is stopped
+                            // until it's retried
+                            if let ReadError::Io(e) = error {
+                                self.post_disk_error(DiskError::Read(e));
+                                self.enter_error_state(Instant::now()).await?;
+                            }
+                        }
+                        Command::SetPieceDeadline { piece_index, deadline } => {
+                            self.set_piece_deadline(piece_index, deadline)
+                                .await;
+                        }
+                        Command::SetFilePriority { file_index, priority } => {
+                            self.set_file_priority(file_index, priority).await;
+                        }
+                        Command::OpenFile { file_index, result_tx } => {
+                            // the caller may have given up in the meantime
+                            result_tx.send(self.open_file(file_index)).ok();
+                        }
+                        Command::ReadPiece { piece_index, result_tx } => {
+                            self.read_piece(piece_index, result_tx).await?;
+                        }
+                        Command::QueryStats => {
+                            self.ctx
+                                .alert_tx
+                                .send(Alert::TorrentStats {
+                                    id: self.ctx.id,
+                                    stats: self.stats.clone(),
+                                })
+                                .ok();
+                        }
+                        Command::QueryPieces => {
+                            self.send_piece_infos().await;
+                        }
+                        Command::QueryTrackers => {
+                            self.send_tracker_infos();
+                        }
+                        Command::ForceReannounce {
+                            tracker: None,
+                            ignore_min_interval,
+                        } => {
+                            self.is_reannounce_pending = true;
+                            if ignore_min_interval {
+                                self.announce_to_trackers(
+                                    Instant::now(),
+                                    None,
+                                    true,
+                                )
+                                .await?;
+                            }
+                        }
+                        Command::ForceReannounce {
+                            tracker: Some(url),
+                            ignore_min_interval,
+                        } => {
+                            self.force_tracker_reannounce(
+                                &url,
+                                ignore_min_interval,
+                            )
+                            .await?;
+                        }
+                        Command::ForceDhtAnnounce => {
+                            self.force_dht_announce();
+                        }
+                        Command::AddTracker { tracker, tier } => {
+                            self.add_tracker(tracker, tier);
+                        }
+                        Command::RemoveTracker(url) => {
+                            self.remove_tracker(&url).await;
+                        }
+                        Command::TrackerAnnounced {
+                            url,
+                            event,
+                            result,
+                            response_time,
+                        } => {
+                            self.handle_tracker_announced(
+                                &url,
+                                event,
+                                result,
+                                response_time,
+                            )
+                            .await?;
+                        }
+                        Command::DhtPeers(peers) => {
+                            self.add_peers(peers, PeerSource::Dht);
+                        }
+                        Command::LsdPeers(peers) => {
+                            self.add_peers(peers, PeerSource::Lsd);
+                        }
+                        Command::CheckConnectability => {
+                            self.connectability.force();
+                            self.probe_connectability(Instant::now());
+                        }
+                        Command::ConnectabilityProbe { is_reachable } => {
+                            let is_forced = self.connectability.is_forced();
+                            if self
+                                .connectability
+                                .handle_probe_result(is_reachable)
+                                || is_forced
+                            {
+                                self.post_connectability();
+                            }
                         }
-                        Command::Shutdown => {
-                            self.shutdown().await?;
-                            break;
+                        Command::Pause => {
+                            self.pause().await?;
                         }
+                        Command::Resume => {
+                            self.resume().await?;
+                        }
+                        Command::SetQueued(is_queued) => {
+                            self.set_queued(is_queued).await?;
+                        }
+                        Command::SetSessionPaused(is_paused) => {
+                            self.set_session_paused(is_paused).await?;
+                        }
+                        Command::SetAutoManaged(is_auto_managed) => {
+                            self.set_auto_managed(is_auto_managed);
+                        }
+                        Command::ClearError => {
+                            self.clear_error().await?;
+                        }
+                        Command::ForceRecheck => {
+                            self.force_recheck().await?;
+                        }
+                        Command::PiecesChecked(pieces) => {
+                            self.handle_pieces_checked(pieces).await?;
+                        }
+                        Command::SaveState => {
+                            self.send_state().await;
+                        }
+                        Command::Shutdown => break,
                     }
                 }
             }
         }
 
+        // stop accepting peers first, so that no sessions are started while
+        // the others are shut down
+        drop(incoming);
+        drop(listener);
+        self.shutdown().await
+    }
+
+    /// Binds the listener for peer connections to the listen address, or if
+    /// its port is 0 or taken, to the first free port of the listen port
+    /// range. If there is none, the OS assigns a port.
+    ///
+    /// If the listen address' IP is unspecified but the engine binds peer
+    /// connections to a local address, the listener is bound to that too.
+    async fn bind_listener(&self) -> Result<TcpListener> {
+        let mut ip = self.listen_addr.ip();
+        if ip.is_unspecified() {
+            if let Some(local_ip) = self.ctx.local_addr.ip()? {
+                ip = local_ip;
+            }
+        }
+        let preferred_port =
+            Some(self.listen_addr.port()).filter(|port| *port != 0);
+        let ports = preferred_port
+            .into_iter()
+            .chain(self.listen_ports.clone().into_iter().flatten());
+        for port in ports {
+            match TcpListener::bind(&SocketAddr::new(ip, port)).await {
+                Ok(listener) => return Ok(listener),
+                Err(e) => log::debug!("Cannot listen on port {}: {}", port, e),
+            }
+        }
+        Ok(TcpListener::bind(&SocketAddr::new(ip, 0)).await?)
+    }
+
+    /// Disconnects all peers, tells trackers, the DHT, and the local network
+    /// that we're leaving, and stops connecting to peers until resumed.
+    ///
+    /// The torrent's downloads are kept, as are the blocks of partially
+    /// downloaded pieces buffered by the disk task, so the download continues
+    /// where it stopped once resumed.
+    async fn pause(&mut self) -> Result<()> {
+        if self.is_paused {
+            return Ok(());
+        }
+        log::info!("Pausing torrent");
+        let was_stopped = self.is_stopped();
+        self.is_paused = true;
+        if !was_stopped {
+            self.stop().await?;
+        }
+
+        self.ctx
+            .alert_tx
+            .send(Alert::TorrentPaused(self.ctx.id))
+            .ok();
+        Ok(())
+    }
+
+    /// Continues a paused torrent, announcing it to trackers, the DHT, and the
+    /// local network again. Peers are connected to on the next tick.
+    ///
+    /// If the torrent is also queued, it only starts once it's given a slot.
+    async fn resume(&mut self) -> Result<()> {
+        if !self.is_paused {
+            return Ok(());
+        }
+        log::info!("Resuming torrent");
+        self.is_paused = false;
+        if !self.is_stopped() {
+            self.restart().await?;
+        }
+
+        self.ctx
+            .alert_tx
+            .send(Alert::TorrentResumed(self.ctx.id))
+            .ok();
+        Ok(())
+    }
+
+    /// Stops the torrent if it lost its slot in the engine's queue, or starts
+    /// it again once it was given one, unless it's also paused.
+    async fn set_queued(&mut self, is_queued: bool) -> Result<()> {
+        if self.is_queued == is_queued {
+            return Ok(());
+        }
+        let was_stopped = self.is_stopped();
+        self.is_queued = is_queued;
+
+        let alert = if is_queued {
+            log::info!("Queueing torrent");
+            if !was_stopped {
+                self.stop().await?;
+            }
+            Alert::TorrentQueued(self.ctx.id)
+        } else {
+            log::info!("Activating torrent");
+            if !self.is_stopped() {
+                self.restart().await?;
+            }
+            Alert::TorrentActivated(self.ctx.id)
+        };
+        self.ctx.alert_tx.send(alert).ok();
+        Ok(())
+    }
+
+    /// Stops the torrent as the engine's session was paused, or starts it
+    /// again once the session was resumed, unless it's also stopped for
+    /// another reason.
+    ///
+    /// No alerts are posted for the torrent, as the engine posts one for the
+    /// whole session.
+    async fn set_session_paused(&mut self, is_paused: bool) -> Result<()> {
+        if self.is_session_paused == is_paused {
+            return Ok(());
+        }
+        let was_stopped = self.is_stopped();
+        self.is_session_paused = is_paused;
+        if is_paused {
+            if !was_stopped {
+                self.stop().await?;
+            }
+        } else if !self.is_stopped() {
+            self.restart().await?;
+        }
+        Ok(())
+    }
+
+    /// Stops the torrent after an error accessing its files, e.g. because the
+    /// disk is full or the files' permissions changed, and schedules its
+    /// retry if it's auto-managed and retries are enabled.
+    ///
+    /// Errors of other disk operations that were in flight only post their
+    /// error, as the torrent is already stopped.
+    async fn enter_error_state(&mut self, now: Instant) -> Result<()> {
+        let was_stopped = self.is_stopped();
+        if !self
+            .error_retry
+            .enter(&self.conf, self.is_auto_managed, now)
+        {
+            return Ok(());
+        }
+        if !was_stopped {
+            self.stop().await?;
+        }
+
+        let retry_in = self.error_retry.retry_in(now);
+        log::warn!("Torrent stopped by error, retrying in {:?}", retry_in);
+        self.ctx
+            .alert_tx
+            .send(Alert::TorrentErrored {
+                id: self.ctx.id,
+                retry_in,
+            })
+            .ok();
+        Ok(())
+    }
+
+    /// Starts the errored torrent again, unless it's also stopped for another
+    /// reason, as its retry is due or the user cleared its error. If the
+    /// error persists, the torrent is errored again on the next failed disk
+    /// operation.
+    async fn clear_error(&mut self) -> Result<()> {
+        if !self.error_retry.clear() {
+            return Ok(());
+        }
+        log::info!("Clearing torrent error");
+        if !self.is_stopped() {
+            self.restart().await?;
+        }
+
+        self.ctx
+            .alert_tx
+            .send(Alert::TorrentErrorCleared(self.ctx.id))
+            .ok();
+        Ok(())
+    }
+
+    /// Changes whether the torrent is auto-managed, which schedules or cancels
+    /// the retry of the torrent if it's errored.
+    fn set_auto_managed(&mut self, is_auto_managed: bool) {
+        self.is_auto_managed = is_auto_managed;
+        self.error_retry
+            .schedule(&self.conf, is_auto_managed, Instant::now());
+    }
+
+    /// Stops the torrent and has the disk task hash all pieces on disk, e.g.
+    /// because its files were modified outside of the engine. The torrent is
+    /// started again once the pieces are checked.
+    async fn force_recheck(&mut self) -> Result<()> {
+        if self.is_checking {
+            log::info!("Torrent already being rechecked");
+            return Ok(());
+        }
+        log::info!("Rechecking torrent");
+        let was_stopped = self.is_stopped();
+        self.is_checking = true;
+        if !was_stopped {
+            self.stop().await?;
+        }
+        self.ctx
+            .disk_tx
+            .send(disk::Command::CheckPieces(self.ctx.id))?;
+        Ok(())
+    }
+
+    /// Replaces the pieces we have with the pieces found valid by a recheck,
+    /// and starts the torrent again, unless it's paused or queued.
+    ///
+    /// Pieces that are being downloaded are left as they are, as they are
+    /// completed by their download.
+    async fn handle_pieces_checked(&mut self, pieces: Bitfield) -> Result<()> {
+        let mut piece_picker = self.ctx.piece_picker.write().await;
+        let was_seed = piece_picker.missing_piece_count() == 0;
+        {
+            let downloads = self.ctx.downloads.read().await;
+            apply_checked_pieces(&mut **piece_picker, &pieces, |index| {
+                downloads.contains_key(&index)
+            });
+        }
+
+        let storage = &self.ctx.storage;
+        self.file_missing_piece_counts = (0..storage.files.len())
+            .map(|file_index| {
+                storage
+                    .pieces_intersecting_file(file_index)
+                    .filter(|index| !piece_picker.own_pieces()[*index])
+                    .count()
+            })
+            .collect();
+        update_piece_boosts(
+            storage,
+            &self.file_priorities,
+            &self.conf,
+            &mut **piece_picker,
+        );
+        self.in_endgame = piece_picker.wanted_missing_piece_count() > 0
+            && piece_picker.all_pieces_picked();
+        let piece_count = piece_picker.own_pieces().count_ones();
+        let is_seed = piece_picker.missing_piece_count() == 0;
+        drop(piece_picker);
+        log::info!(
+            "Rechecked torrent, {} of {} pieces valid",
+            piece_count,
+            storage.piece_count
+        );
+
+        // the engine moves the torrent between seeding and downloading slots
+        if is_seed != was_seed {
+            self.engine_tx
+                .send(engine::Command::TorrentSeeding {
+                    id: self.ctx.id,
+                    is_seed,
+                })
+                .ok();
+        }
+        self.ctx
+            .alert_tx
+            .send(Alert::TorrentChecked {
+                id: self.ctx.id,
+                piece_count,
+            })
+            .ok();
+
+        self.is_checking = false;
+        if !self.is_stopped() {
+            self.restart().await?;
+        }
         Ok(())
     }
 
+    /// Returns whether the torrent is paused, queued, being rechecked,
+    /// errored, or stopped with the whole session, in which case it's not
+    /// connected to any peers and is not announced anywhere.
+    fn is_stopped(&self) -> bool {
+        self.is_paused
+            || self.is_queued
+            || self.is_checking
+            || self.error_retry.is_errored()
+            || self.is_session_paused
+    }
+
+    /// Disconnects all peers and tells trackers, the DHT, and the local
+    /// network that we're leaving.
+    async fn stop(&mut self) -> Result<()> {
+        self.remove_from_dht_and_lsd();
+        for peer in self.peers.values() {
+            if let Some(tx) = &peer.tx {
+                // the peer session may no longer be running
+                tx.send(peer::Command::Shutdown).ok();
+            }
+        }
+        self.announce_stop().await
+    }
+
+    /// Announces the stopped torrent to trackers, the DHT, and the local
+    /// network again.
+    async fn restart(&mut self) -> Result<()> {
+        self.add_to_dht_and_lsd();
+        // as when starting, trackers forgot about us when we stopped
+        let tracker_event =
+            if self.ctx.piece_picker.read().await.missing_piece_count() == 0 {
+                None
+            } else {
+                Some(Event::Started)
+            };
+        self.trackers.pending_event = tracker_event;
+        self.announce_to_trackers(Instant::now(), tracker_event, false)
+            .await
+    }
+
+    /// Announces the torrent in the DHT and on the local network, if enabled.
+    fn add_to_dht_and_lsd(&self) {
+        let port = self
+            .announce_conf
+            .port
+            .unwrap_or_else(|| self.listen_addr.port());
+        if let Some(dht_tx) = &self.dht_tx {
+            dht_tx
+                .send(dht::Command::AddTorrent {
+                    info_hash: self.ctx.info_hash,
+                    port,
+                    torrent_tx: self.ctx.cmd_tx.clone(),
+                })
+                .ok();
+        }
+        if let Some(lsd_tx) = &self.lsd_tx {
+            // local peers connect to the port we actually listen on, which
+            // may differ from the one announced to trackers
+            lsd_tx
+                .send(lsd::Command::AddTorrent {
+                    info_hash: self.ctx.info_hash,
+                    port: self.listen_addr.port(),
+                    torrent_tx: self.ctx.cmd_tx.clone(),
+                })
+                .ok();
+        }
+    }
+
+    /// Stops announcing the torrent in the DHT and on the local network.
+    fn remove_from_dht_and_lsd(&self) {
+        if let Some(dht_tx) = &self.dht_tx {
+            // the DHT may have been shut down already
+            dht_tx
+                .send(dht::Command::RemoveTorrent {
+                    info_hash: self.ctx.info_hash,
+                })
+                .ok();
+        }
+        if let Some(lsd_tx) = &self.lsd_tx {
+            // the LSD service may have been shut down already
+            lsd_tx
+                .send(lsd::Command::RemoveTorrent {
+                    info_hash: self.ctx.info_hash,
+                })
+                .ok();
+        }
+    }
+
     /// The torrent tick, as in "the tick of a clock", which runs every second
     /// to perform periodic updates.
     ///
@@ -418,17 +1294,75 @@ impl Torrent {
             .or(self.start_time)
             .map(|t| now.saturating_duration_since(t))
             .unwrap_or_default();
-        self.run_duration += elapsed_since_last_tick;
+        if !self.is_stopped() {
+            self.run_duration += elapsed_since_last_tick;
+        }
         *last_tick_time = Some(now);
 
-        // check if we can connect some peers
-        // NOTE: do this before announcing as we don't want to block new
-        // connections with the potentially long running announce requests
-        self.connect_peers();
+        if self.error_retry.is_retry_due(now) {
+            log::info!("Retrying errored torrent");
+            self.clear_error().await?;
+        }
+
+        // a paused or queued torrent only reports its stats
+        if !self.is_stopped() {
+            // check if we can connect some peers
+            // NOTE: do this before announcing as we don't want to block new
+            // connections with the potentially long running announce requests
+            self.connect_peers(now);
+
+            // if we're running out of peers, ask the DHT for more rather than
+            // waiting for its next periodic lookup (it decides whether it's
+            // been long enough since the last one)
+            if let Some(dht_tx) = &self.dht_tx {
+                if self.peer_sources.connectable_count(now) == 0
+                    && self.peers.len() < self.conf.min_requested_peer_count
+                {
+                    dht_tx
+                        .send(dht::Command::GetPeers {
+                            info_hash: self.ctx.info_hash,
+                        })
+                        .ok();
+                }
+            }
+
+            self.probe_connectability(now);
+
+            // check if we need to announce to some trackers
+            let event = None;
+            self.announce_to_trackers(now, event, false).await?;
+            self.announce_to_pending_trackers(now, false).await?;
+
+            if self.ctx.piece_picker.read().await.missing_piece_count() == 0 {
+                self.check_seed_goals(elapsed_since_last_tick);
+            }
+        }
 
-        // check if we need to announce to some trackers
-        let event = None;
-        self.announce_to_trackers(now, event).await?;
+        // free blocks whose requests were left unanswered for too long so
+        // that other peers may download them, and cancel the requests
+        let mut timed_out_blocks = Vec::new();
+        for download in self.ctx.downloads.read().await.values() {
+            timed_out_blocks.extend(
+                download.write().await.free_timed_out_blocks(
+                    now,
+                    self.conf.block_request_timeout,
+                ),
+            );
+        }
+        if !timed_out_blocks.is_empty() {
+            log::info!(
+                "Re-queued {} timed out block request(s)",
+                timed_out_blocks.len()
+            );
+            let mut in_flight_requests =
+                self.ctx.in_flight_requests.write().await;
+            for block in timed_out_blocks.iter() {
+                for (_, tx) in in_flight_requests.take(block) {
+                    // the peer session may no longer be running
+                    tx.send(peer::Command::CancelRequest(*block)).ok();
+                }
+            }
+        }
 
         log::debug!(
             "Stats: \
@@ -449,14 +1383,9 @@ impl Torrent {
         // piece picker
         if log::log_enabled!(log::Level::Debug) {
             let piece_picker_guard = self.ctx.piece_picker.read().await;
-            let unavailable_piece_count =
-                piece_picker_guard.pieces().iter().fold(0, |acc, piece| {
-                    if piece.frequency == 0 {
-                        acc + 1
-                    } else {
-                        acc
-                    }
-                });
+            let unavailable_piece_count = (0..self.ctx.storage.piece_count)
+                .filter(|&index| piece_picker_guard.availability(index) == 0)
+                .count();
             if unavailable_piece_count > 0 {
                 log::debug!(
                     "Torrent swarm doesn't have all pieces (missing: {})",
@@ -465,191 +1394,617 @@ impl Torrent {
             }
         }
 
-        // send periodic stats update to api user
-        let stats = self.build_stats().await;
+        // send periodic stats update to api user, and keep it for queries
+        self.stats = Box::new(self.build_stats().await);
         self.ctx
             .alert_tx
             .send(Alert::TorrentStats {
                 id: self.ctx.id,
-                stats: Box::new(stats),
+                stats: self.stats.clone(),
             })
             .ok();
 
+        metrics::add_payload(
+            self.counters.payload.down.round(),
+            self.counters.payload.up.round(),
+        );
+        metrics::update_peer_count(self.reported_peer_count, self.peers.len());
+        self.reported_peer_count = self.peers.len();
+
         self.counters.reset();
 
         Ok(())
     }
 
-    /// Attempts to connect available peers, if we have any.
-    fn connect_peers(&mut self) {
-        let connect_count = self
+    /// Records the tick of the seeding torrent and if it reached one of its
+    /// seed goals, tells the engine to pause or remove it.
+    fn check_seed_goals(&mut self, elapsed: Duration) {
+        let goal = self.seed_goals.tick(
+            &self.conf.seed_goals,
+            elapsed,
+            self.counters.payload.up.round(),
+            self.counters.payload.up.total(),
+            self.counters.payload.down.total(),
+            self.ctx.storage.download_len,
+        );
+        if let Some(goal) = goal {
+            log::info!("Torrent reached seed goal {:?}", goal);
+            self.engine_tx
+                .send(engine::Command::SeedGoalReached {
+                    id: self.ctx.id,
+                    goal,
+                    action: self.conf.seed_goals.action,
+                })
+                .ok();
+        }
+    }
+
+    /// Connects to the best peers we know of, if we have room for more
+    /// connections.
+    fn connect_peers(&mut self, now: Instant) {
+        let room = self
             .conf
             .max_connected_peer_count
-            .saturating_sub(self.peers.len())
-            .min(self.available_peers.len());
-        if connect_count == 0 {
+            .saturating_sub(self.peers.len());
+        let banned_peers = &self.banned_peers;
+        let connected_peers = &self.peers;
+        let external_ip = &self.ctx.external_ip;
+        let addrs = self.peer_sources.pick(
+            room,
+            now,
+            |addr| {
+                !banned_peers.contains(&addr.ip())
+                    && !connected_peers.contains_key(addr)
+            },
+            |addr| external_ip.is_local_peer(addr.ip()),
+        );
+        if addrs.is_empty() {
             log::trace!("Cannot connect to peers");
             return;
         }
 
-        log::debug!("Connecting {} peer(s)", connect_count);
-        for addr in self.available_peers.drain(0..connect_count) {
+        log::debug!("Connecting {} peer(s)", addrs.len());
+        for addr in addrs {
             log::info!("Connecting to peer {}", addr);
-            let (session, tx) = PeerSession::new(Arc::clone(&self.ctx), addr);
-            self.peers
-                .insert(addr, PeerSessionEntry::start_outbound(session, tx));
+            let is_local = self.is_local_peer(&addr);
+            let (session, tx) =
+                PeerSession::new(Arc::clone(&self.ctx), addr, is_local);
+            self.peers.insert(
+                addr,
+                PeerSessionEntry::start_outbound(session, tx, is_local),
+            );
         }
     }
 
-    /// Chacks whether we need to announce to any trackers of if we need to request
-    /// peers.
+    /// Returns whether the peer is on our local network: if it was found via
+    /// LSD, or if its address tells so.
+    fn is_local_peer(&self, addr: &SocketAddr) -> bool {
+        self.peer_sources.is_lsd(addr)
+            || self.ctx.external_ip.is_local_peer(addr.ip())
+    }
+
+    /// Adds the peers returned by the source that pass the IP filter to the
+    /// peers we can connect to.
+    fn add_peers(&mut self, peers: Vec<SocketAddr>, source: PeerSource) {
+        let ip_filter = &self.ctx.ip_filter;
+        self.peer_sources.add(
+            peers.into_iter().filter(|addr| ip_filter.allows_peer(addr)),
+            source,
+        );
+    }
+
+    /// Checks whether we need to announce to any trackers or if we need to
+    /// request peers, and if so, starts the announces.
+    ///
+    /// Trackers are announced to in the order of their tiers, as defined in
+    /// [BEP 12](https://www.bittorrent.org/beps/bep_0012.html), unless they
+    /// are all announced to on their own schedule, see [`Trackers::pick`].
+    /// The announces are made on their own tasks, and their results are
+    /// handled in [`Self::handle_tracker_announced`].
+    ///
+    /// If `force` is set, we announce regardless of the trackers' announce
+    /// intervals.
     async fn announce_to_trackers(
         &mut self,
         now: Instant,
         event: Option<Event>,
+        force: bool,
     ) -> Result<()> {
-        // calculate transfer statistics in advance
-        let uploaded = self.counters.payload.up.total();
-        let downloaded = self.counters.payload.down.total();
-        let left = self.ctx.storage.download_len - downloaded;
-
-        // skip trackers that errored too often
-        // TODO: introduce a retry timeout
-        let tracker_error_threshold = self.conf.tracker_error_threshold;
-        for tracker in self
-            .trackers
-            .iter_mut()
-            .filter(|t| t.error_count < tracker_error_threshold)
-        {
-            // Check if the torrent's peer count has fallen below the minimum.
-            // But don't request new peers otherwise or if we're about to stop
-            // torrent.
-            let peer_count = self.peers.len() + self.available_peers.len();
-            let needed_peer_count = if peer_count
-                >= self.conf.min_requested_peer_count
-                || event == Some(Event::Stopped)
-            {
-                None
-            } else {
-                debug_assert!(self.conf.max_connected_peer_count >= peer_count);
-                let needed = self.conf.max_connected_peer_count - peer_count;
-                // Download at least this numbe of peers, even if we don't need
-                // as many. This is because later we may be able to connect to
-                // more peers and in that case we don't want to wait till the
-                // next tracker request.
-                Some(self.conf.min_requested_peer_count.max(needed))
-            };
+        // trackers were told we stopped when the torrent was paused or
+        // queued
+        if self.is_stopped() {
+            return Ok(());
+        }
 
-            // we can override the normal annoucne interval if we need peers or
-            // if we have an event to announce
-            if event.is_some()
-                || (needed_peer_count > Some(0)
-                    && tracker.can_announce(now, self.conf.announce_interval))
-                || tracker.should_announce(now, self.conf.announce_interval)
-            {
-                let params = Announce {
-                    tracker_id: tracker.id.clone(),
-                    info_hash: self.ctx.info_hash,
-                    peer_id: self.ctx.client_id,
-                    port: self.listen_addr.port(),
-                    peer_count: needed_peer_count,
-                    uploaded,
-                    downloaded,
-                    left,
-                    ip: None,
-                    event,
-                };
-                // TODO: We probably don't want to block the torrent event loop
-                // here waiting on the tracker response. Instead, poll the
-                // future in the event loop select call, or spawn the tracker
-                // announce on a separate task and return the result as
-                // an mpsc message.
-                match tracker.client.announce(params).await {
-                    Ok(resp) => {
-                        log::info!(
-                            "Announced to tracker {}, response: {:?}",
-                            tracker.client,
-                            resp
-                        );
-                        if let Some(tracker_id) = resp.tracker_id {
-                            tracker.id = Some(tracker_id);
-                        }
-                        if let Some(failure_reason) = resp.failure_reason {
-                            log::warn!(
-                                "Error contacting tracker {}: {}",
-                                tracker.client,
-                                failure_reason
-                            );
-                        }
-                        if let Some(warning_message) = resp.warning_message {
-                            log::warn!(
-                                "Warning from tracker {}: {}",
-                                tracker.client,
-                                warning_message
-                            );
-                        }
-                        if let Some(interval) = resp.interval {
-                            log::info!(
-                                "Tracker {} interval: {} s",
-                                tracker.client,
-                                interval.as_secs()
-                            );
-                            tracker.interval = Some(interval);
-                        }
-                        if let Some(min_interval) = resp.min_interval {
-                            log::info!(
-                                "Tracker {} min min_interval: {} s",
-                                tracker.client,
-                                min_interval.as_secs()
-                            );
-                            tracker.min_interval = Some(min_interval);
-                        }
+        // an event that no tracker received yet is sent with the next
+        // announce, but unlike a new event, it doesn't make us announce
+        // sooner than the minimum announce interval
+        let retried_event = match event {
+            Some(_) => None,
+            None => self.trackers.pending_event,
+        };
 
-                        if let (Some(seeder_count), Some(leecher_count)) =
-                            (resp.seeder_count, resp.leecher_count)
-                        {
-                            log::debug!(
-                                "Torrent seeds: {} and leeches: {}",
-                                seeder_count,
-                                leecher_count
-                            );
-                        }
+        // the swarm is thin if the torrent's peer count has fallen below the
+        // minimum, in which case we announce sooner to get more peers
+        let peer_count = self.peer_count(now);
+        let is_thin = peer_count < self.conf.max_connected_peer_count
+            && peer_count < self.conf.min_requested_peer_count;
+
+        let announce_interval = self.conf.announce_interval;
+        let min_announce_interval = self.conf.min_announce_interval;
+        // We can override the normal announce interval if we need peers, if
+        // the user asked for it, or if we have an event to announce.
+        let is_urgent = self.is_reannounce_pending || retried_event.is_some();
+        // a failing tracker is retried as soon as its backoff elapses
+        let is_due = |tracker: &TrackerEntry| {
+            force
+                || event.is_some()
+                || tracker.next_retry_time.is_some()
+                || (is_urgent
+                    && tracker.can_announce(now, min_announce_interval))
+                || (is_thin
+                    && tracker
+                        .can_announce_for_peers(now, min_announce_interval))
+                || tracker.should_announce(now, announce_interval)
+        };
 
-                        if !resp.peers.is_empty() {
-                            log::debug!(
-                                "Received peers from tracker {}: {:?}",
-                                tracker.client,
-                                resp.peers
-                            );
-                            self.available_peers.extend(resp.peers.into_iter());
-                        }
-                    }
-                    Err(e) => {
-                        log::warn!(
-                            "Error announcing to tracker {}: {}",
-                            tracker.client,
-                            e
-                        );
-                        tracker.error_count += 1;
-                        self.ctx.alert_tx.send(Alert::Error(
-                            Error::Tracker {
-                                id: self.ctx.id,
-                                error: e,
-                            },
-                        ))?;
-                    }
-                }
-                tracker.last_announce_time = Some(now);
+        let announce_event = event.or(retried_event);
+        let targets = self.trackers.pick(
+            now,
+            announce_event,
+            force,
+            self.conf.announce_to_all_trackers,
+            is_due,
+        );
+        if targets.is_empty() {
+            return Ok(());
+        }
+        self.is_reannounce_pending = false;
+
+        let requested_peer_count = self.requested_peer_count(now);
+        let params = self
+            .announce_params(announce_event, Some(requested_peer_count))
+            .await;
+        for target in targets {
+            self.spawn_announce(target, params.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Makes the announce on a new task, so as not to hold up the torrent,
+    /// and sends the torrent its result.
+    fn spawn_announce(&self, target: AnnounceTarget, params: Announce) {
+        let cmd_tx = self.ctx.cmd_tx.clone();
+        rt::spawn(
+            async move {
+                let url = target.url.clone();
+                let event = target.event;
+                let (result, response_time) = target.announce(params).await;
+                // the torrent may have shut down in the meantime
+                cmd_tx
+                    .send(Command::TrackerAnnounced {
+                        url,
+                        event,
+                        result,
+                        response_time,
+                    })
+                    .ok();
             }
+            .in_current_span(),
+        );
+    }
+
+    /// Updates the tracker from the result of an announce, and if it was
+    /// a regular announce that failed, fails over to the next tracker.
+    async fn handle_tracker_announced(
+        &mut self,
+        url: &Url,
+        event: Option<Event>,
+        result: std::result::Result<Response, TrackerError>,
+        response_time: Duration,
+    ) -> Result<()> {
+        let now = Instant::now();
+        let tracker = match self.trackers.get_mut(url) {
+            Some(tracker) => tracker,
+            None => {
+                log::debug!("Ignoring announce to removed tracker {}", url);
+                return Ok(());
+            }
+        };
+        tracker.finish_announce(response_time);
+        let is_success = Self::handle_announce_result(
+            &self.ctx,
+            &self.conf,
+            &mut self.peer_sources,
+            tracker,
+            result,
+            now,
+        )?;
+
+        if let Some(target) =
+            self.trackers.handle_result(url, event, is_success, now)
+        {
+            let requested_peer_count = self.requested_peer_count(now);
+            let params = self
+                .announce_params(target.event, Some(requested_peer_count))
+                .await;
+            self.spawn_announce(target, params);
         }
 
         Ok(())
     }
 
+    /// Returns the number of peers that we're connected to or may connect to.
+    fn peer_count(&self, now: Instant) -> usize {
+        self.peers.len() + self.peer_sources.connectable_count(now)
+    }
+
+    /// Returns the number of peers to request in a regular announce.
+    ///
+    /// We request only as many peers as we have room for. If we're
+    /// well-connected, we don't request any, which spares the tracker from
+    /// looking them up.
+    fn requested_peer_count(&self, now: Instant) -> usize {
+        let needed_peer_count = self
+            .conf
+            .max_connected_peer_count
+            .saturating_sub(self.peer_count(now));
+        if needed_peer_count == 0 {
+            0
+        } else {
+            // Download at least this number of peers, even if we don't need
+            // as many. This is because later we may be able to connect to
+            // more peers and in that case we don't want to wait till the
+            // next tracker request.
+            self.conf.min_requested_peer_count.max(needed_peer_count)
+        }
+    }
+
+    /// Returns the parameters of an announce with the given event, in which we
+    /// request the given number of peers, without the tracker specific
+    /// tracker id.
+    async fn announce_params(
+        &self,
+        event: Option<Event>,
+        needed_peer_count: Option<usize>,
+    ) -> Announce {
+        let left = left_len(
+            self.ctx.piece_picker.read().await.own_pieces(),
+            &self.ctx.storage,
+        );
+        Announce {
+            info_hash: self.ctx.info_hash,
+            peer_id: self.ctx.client_id,
+            // the user may override what we announce to trackers
+            port: self
+                .announce_conf
+                .port
+                .unwrap_or_else(|| self.listen_addr.port()),
+            peer_count: self.announce_conf.numwant.or(needed_peer_count),
+            uploaded: self.counters.payload.up.total(),
+            downloaded: self.counters.payload.down.total(),
+            left,
+            ip: None,
+            event,
+            tracker_id: None,
+            key: self.announce_conf.key,
+        }
+    }
+
+    /// Announces to the tracker with the given URL outside of the regular
+    /// announce schedule, as soon as its minimum announce interval allows, or
+    /// right away if it is to be ignored.
+    async fn force_tracker_reannounce(
+        &mut self,
+        url: &Url,
+        ignore_min_interval: bool,
+    ) -> Result<()> {
+        match self.trackers.get_mut(url) {
+            Some(tracker) => tracker.is_reannounce_pending = true,
+            None => {
+                log::warn!("Cannot reannounce to unknown tracker {}", url);
+                return Ok(());
+            }
+        }
+        self.announce_to_pending_trackers(Instant::now(), ignore_min_interval)
+            .await
+    }
+
+    /// Announces to the trackers that the user asked to reannounce to, once
+    /// their minimum announce interval and failure backoff allow it, or right
+    /// away if `force` is set.
+    ///
+    /// Unlike regular announces, these are made to the trackers regardless of
+    /// their tier.
+    async fn announce_to_pending_trackers(
+        &mut self,
+        now: Instant,
+        force: bool,
+    ) -> Result<()> {
+        // the trackers are announced to once the torrent starts again
+        if self.is_stopped() {
+            return Ok(());
+        }
+        let min_announce_interval = self.conf.min_announce_interval;
+        let is_due = |tracker: &TrackerEntry| {
+            tracker.is_reannounce_pending
+                && (force || tracker.can_announce(now, min_announce_interval))
+        };
+        let announce_event = self.trackers.pending_event;
+        let targets =
+            self.trackers.pick(now, announce_event, force, true, is_due);
+        if targets.is_empty() {
+            return Ok(());
+        }
+
+        let requested_peer_count = self
+            .conf
+            .max_connected_peer_count
+            .saturating_sub(self.peer_count(now))
+            .max(self.conf.min_requested_peer_count);
+        let params = self
+            .announce_params(announce_event, Some(requested_peer_count))
+            .await;
+        for target in targets {
+            if let Some(tracker) = self.trackers.get_mut(&target.url) {
+                tracker.is_reannounce_pending = false;
+            }
+            self.spawn_announce(target, params.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Looks up and announces the torrent in the DHT right away, rather than
+    /// waiting for its next periodic lookup.
+    fn force_dht_announce(&self) {
+        if self.is_stopped() {
+            log::info!("Not announcing stopped torrent in DHT");
+            return;
+        }
+        match &self.dht_tx {
+            Some(dht_tx) => {
+                log::info!("Forcing DHT announce");
+                // the DHT may have been shut down already
+                dht_tx
+                    .send(dht::Command::AnnounceTorrent {
+                        info_hash: self.ctx.info_hash,
+                    })
+                    .ok();
+            }
+            None => log::warn!("Cannot announce torrent in DHT: not enabled"),
+        }
+    }
+
+    /// Adds the tracker at the end of the tier with the given index, or in
+    /// a new last tier if there is no such tier.
+    fn add_tracker(&mut self, tracker: Tracker, tier: usize) {
+        let url = tracker.url().clone();
+        if self.trackers.add(tracker, tier) {
+            log::info!("Added tracker {} to tier {}", url, tier);
+        } else {
+            log::info!("Torrent already has tracker {}", url);
+        }
+    }
+
+    /// Removes the tracker at the given URL, if the torrent has it.
+    ///
+    /// If we announced to the tracker, it is told we're leaving in the
+    /// background, so as not to hold up the torrent.
+    async fn remove_tracker(&mut self, url: &Url) {
+        let tracker = match self.trackers.remove(url) {
+            Some(tracker) => tracker,
+            None => {
+                log::info!("Torrent has no tracker {}", url);
+                return;
+            }
+        };
+        log::info!("Removed tracker {}", url);
+        if tracker.last_success_time.is_none() {
+            return;
+        }
+        let target = tracker.target(Some(Event::Stopped));
+        let params = self.announce_params(Some(Event::Stopped), None).await;
+        let stop_announce_timeout = self.conf.stop_announce_timeout;
+        rt::spawn(
+            trackers::announce_stop(
+                vec![target],
+                params,
+                stop_announce_timeout,
+            )
+            .in_current_span(),
+        );
+    }
+
+    /// Updates the tracker's state from the result of an announce, collecting
+    /// the returned peers and notifying the user of warnings and errors.
+    ///
+    /// Returns whether the announce succeeded.
+    fn handle_announce_result(
+        ctx: &TorrentContext,
+        conf: &TorrentConf,
+        peer_sources: &mut PeerSources,
+        tracker: &mut TrackerEntry,
+        result: Result<Response, TrackerError>,
+        now: Instant,
+    ) -> Result<bool> {
+        tracker.last_announce_time = Some(now);
+        tracker.jitter = rand::random::<f64>() * MAX_ANNOUNCE_JITTER;
+        if let Ok(Response {
+            external_ip: Some(ip),
+            ..
+        }) = &result
+        {
+            let voter = Voter::Tracker(tracker.url.clone());
+            ctx.external_ip.vote(voter, *ip);
+        }
+        match result.and_then(|resp| tracker.handle_response(resp, now)) {
+            Ok(peers) => {
+                if let Some(message) = &tracker.message {
+                    ctx.alert_tx
+                        .send(Alert::TrackerWarning {
+                            id: ctx.id,
+                            url: tracker.url.clone(),
+                            message: message.clone(),
+                        })
+                        .ok();
+                }
+                let peers: Vec<_> = peers
+                    .into_iter()
+                    .filter(|addr| ctx.ip_filter.allows_peer(addr))
+                    .collect();
+                ctx.alert_tx
+                    .send(Alert::TrackerAnnounced {
+                        id: ctx.id,
+                        url: tracker.url.clone(),
+                        peer_count: peers.len(),
+                    })
+                    .ok();
+                // if the tracker doesn't know of any other peers, we announce
+                // to it less often while we need peers
+                if peer_sources.add(peers, PeerSource::Tracker) == 0 {
+                    tracker.fruitless_announce_count += 1;
+                } else {
+                    tracker.fruitless_announce_count = 0;
+                }
+                Ok(true)
+            }
+            Err(e) => {
+                log::warn!(
+                    "Error announcing to tracker {}: {}",
+                    tracker.url,
+                    e
+                );
+                tracker.back_off(
+                    now,
+                    conf.tracker_retry_interval,
+                    conf.max_tracker_retry_interval,
+                );
+                tracker.status = TrackerStatus::Failed;
+                tracker.message = Some(e.to_string());
+                tracker.failure_count += 1;
+                tracker.last_error = tracker.message.clone();
+                ctx.alert_tx.send(Alert::Error(Error::Tracker {
+                    id: ctx.id,
+                    url: tracker.url.clone(),
+                    error: e,
+                }))?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Sends the user the status of each tracker.
+    fn send_tracker_infos(&self) {
+        let trackers = self
+            .trackers
+            .tiers()
+            .iter()
+            .enumerate()
+            .flat_map(|(tier_index, tier)| {
+                tier.iter().map(move |tracker| TrackerInfo {
+                    url: tracker.url.clone(),
+                    tier: tier_index,
+                    status: tracker.status,
+                    message: tracker.message.clone(),
+                    seeder_count: tracker.seeder_count,
+                    leecher_count: tracker.leecher_count,
+                    last_success_time: tracker.last_success_time,
+                    error_count: tracker.error_count,
+                    next_retry_time: tracker.next_retry_time,
+                    announce_count: tracker.announce_count,
+                    failure_count: tracker.failure_count,
+                    last_error: tracker.last_error.clone(),
+                    average_response_time: tracker.average_response_time(),
+                    received_peer_count: tracker.received_peer_count,
+                })
+            })
+            .collect();
+        self.ctx
+            .alert_tx
+            .send(Alert::TorrentTrackers {
+                id: self.ctx.id,
+                trackers,
+            })
+            .ok();
+    }
+
+    /// Sends the engine the state of the torrent, which it saves as the
+    /// torrent's resume data.
+    async fn send_state(&self) {
+        let state = TorrentState {
+            own_pieces: self.ctx.piece_picker.read().await.own_pieces().clone(),
+            is_paused: self.is_paused,
+            stats: SavedStats {
+                uploaded: self.counters.payload.up.total(),
+                downloaded: self.counters.payload.down.total(),
+                run_duration: self.run_duration,
+            },
+        };
+        // the engine may no longer be running
+        self.engine_tx
+            .send(engine::Command::TorrentState {
+                id: self.ctx.id,
+                state,
+            })
+            .ok();
+    }
+
+    /// Sends the user the state and availability of each piece.
+    async fn send_piece_infos(&self) {
+        let piece_picker = self.ctx.piece_picker.read().await;
+        let downloads = self.ctx.downloads.read().await;
+        let own_pieces = piece_picker.own_pieces();
+        let mut pieces = Vec::with_capacity(own_pieces.len());
+        for index in 0..own_pieces.len() {
+            let state = if own_pieces[index] {
+                PieceState::Complete
+            } else if let Some(download) = downloads.get(&index) {
+                let download = download.read().await;
+                if download.has_requested_blocks() {
+                    PieceState::Downloading
+                } else if download.has_received_blocks() {
+                    PieceState::Partial
+                } else {
+                    PieceState::Missing
+                }
+            } else {
+                PieceState::Missing
+            };
+            pieces.push(PieceInfo {
+                state,
+                availability: piece_picker.availability(index),
+            });
+        }
+
+        self.ctx
+            .alert_tx
+            .send(Alert::TorrentPieces {
+                id: self.ctx.id,
+                pieces,
+            })
+            .ok();
+    }
+
     /// Returns high-level statistics about the torrent for sending to the user.
     async fn build_stats(&mut self) -> TorrentStats {
-        let missing_piece_count =
-            self.ctx.piece_picker.read().await.missing_piece_count();
+        let (
+            missing_piece_count,
+            selected_len,
+            selected_complete_len,
+            distributed_copies,
+        ) = {
+            let piece_picker = self.ctx.piece_picker.read().await;
+            let (selected_len, selected_complete_len) =
+                self.selected_lens(&**piece_picker);
+            (
+                piece_picker.missing_piece_count(),
+                selected_len,
+                selected_complete_len,
+                piece_picker.distributed_copies(),
+            )
+        };
         let piece_count = self.ctx.storage.piece_count;
         let completed_pieces = self
             .completed_pieces
@@ -662,9 +2017,11 @@ impl Torrent {
                 .map(|(addr, entry)| stats::PeerSessionStats {
                     addr: *addr,
                     id: entry.id,
+                    is_local: entry.is_local,
                     state: entry.state,
                     piece_count: entry.piece_count,
                     thruput: entry.thruput,
+                    messages: entry.messages,
                 })
                 .collect();
             Peers::Full(peers)
@@ -675,17 +2032,155 @@ impl Torrent {
         TorrentStats {
             start_time: self.start_time,
             run_duration: self.run_duration,
+            is_paused: self.is_paused,
+            is_queued: self.is_queued,
+            is_errored: self.error_retry.is_errored(),
             pieces: PieceStats {
                 total: piece_count,
                 complete: piece_count - missing_piece_count,
                 pending: self.ctx.downloads.read().await.len(),
+                selected_len,
+                selected_complete_len,
                 latest_completed: completed_pieces,
             },
+            distributed_copies,
+            swarm: self.swarm_stats(),
+            connectability: self.connectability.status(),
             thruput: ThruputStats::from(&self.counters),
+            messages: self.messages,
             peers,
+            known_peer_count: self.peer_sources.len(),
+        }
+    }
+
+    /// Probes whether our listen port is reachable, if it's time to, by
+    /// connecting to it through our external address in a separate task.
+    ///
+    /// Connections made through a proxy don't tell us anything about our own
+    /// port, and connecting directly would reveal our address, so no probes
+    /// are made if a proxy is set.
+    fn probe_connectability(&mut self, now: Instant) {
+        if self.ctx.proxy.is_some() {
+            return;
+        }
+        let port = self
+            .announce_conf
+            .port
+            .unwrap_or_else(|| self.listen_addr.port());
+        let addr = match self.connectability.probe_target(
+            now,
+            self.ctx.external_ip.ipv4(),
+            port,
+        ) {
+            Some(addr) => addr,
+            None => return,
+        };
+        log::debug!("Probing connectability of listen port via {}", addr);
+        let cmd_tx = self.ctx.cmd_tx.clone();
+        rt::spawn(
+            async move {
+                let is_reachable = matches!(
+                    rt::timeout(
+                        connectability::PROBE_TIMEOUT,
+                        TcpStream::connect(addr)
+                    )
+                    .await,
+                    Ok(Ok(_))
+                );
+                // the torrent may have been shut down in the meantime
+                cmd_tx
+                    .send(Command::ConnectabilityProbe { is_reachable })
+                    .ok();
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Frees the blocks of the piece that couldn't be written so that it's
+    /// downloaded again, and if the disk failed, stops the torrent until the
+    /// error may have cleared.
+    async fn handle_piece_write_failure(
+        &mut self,
+        index: PieceIndex,
+        error: WriteError,
+    ) -> Result<()> {
+        if let Some(download) = self.ctx.downloads.read().await.get(&index) {
+            download.write().await.reset();
+        }
+        if let WriteError::Io(e) = error {
+            self.post_disk_error(DiskError::Write(e));
+            self.enter_error_state(Instant::now()).await?;
+        }
+        Ok(())
+    }
+
+    /// Posts an error accessing the torrent's files to the user.
+    fn post_disk_error(&self, error: DiskError) {
+        self.ctx
+            .alert_tx
+            .send(Alert::Error(Error::Disk {
+                id: self.ctx.id,
+                error,
+            }))
+            .ok();
+    }
+
+    /// Posts the connectability of our listen port to the user.
+    fn post_connectability(&self) {
+        let connectability = self.connectability.status();
+        if connectability == Connectability::Firewalled {
+            log::warn!("Listen port {} is firewalled", self.listen_addr.port());
+        }
+        self.ctx
+            .alert_tx
+            .send(Alert::ConnectabilityChanged {
+                id: self.ctx.id,
+                connectability,
+            })
+            .ok();
+    }
+
+    /// Aggregates the swarm size reported by the trackers.
+    fn swarm_stats(&self) -> SwarmStats {
+        let trackers = self.trackers.iter();
+        SwarmStats {
+            seeder_count: trackers.clone().filter_map(|t| t.seeder_count).max(),
+            leecher_count: trackers
+                .clone()
+                .filter_map(|t| t.leecher_count)
+                .max(),
+            last_announce_time: trackers
+                .filter_map(|t| t.last_success_time)
+                .max(),
         }
     }
 
+    /// Returns the length of the wanted pieces and the length of those of them
+    /// that we have, in bytes.
+    fn selected_lens(&self, piece_picker: &dyn PiecePicker) -> (u64, u64) {
+        let storage = &self.ctx.storage;
+        let piece_len = storage.piece_len as u64;
+        let wanted_count = piece_picker.wanted_piece_count() as u64;
+        let wanted_complete_count =
+            wanted_count - piece_picker.wanted_missing_piece_count() as u64;
+        let mut selected_len = wanted_count * piece_len;
+        let mut selected_complete_len = wanted_complete_count * piece_len;
+
+        // all pieces but the last are of the same length
+        if storage.piece_count > 0 {
+            let last_index = storage.piece_count - 1;
+            let diff = piece_len - storage.last_piece_len as u64;
+            if piece_picker.is_wanted(last_index) {
+                selected_len -= diff;
+                if piece_picker.own_pieces()[last_index] {
+                    selected_complete_len -= diff;
+                }
+            }
+        }
+
+        (selected_len, selected_complete_len)
+    }
+
     /// Handles the message that peer sessions send to torrent when their state
     /// changed.
     ///
@@ -704,28 +2199,153 @@ impl Torrent {
             peer.state = info.state;
             peer.piece_count = info.piece_count;
             peer.thruput = ThruputStats::from(&info.counters);
+            peer.messages += &info.messages;
 
             // update torrent thruput stats
             self.counters += &info.counters;
+            self.messages += &info.messages;
 
             // if we disconnected peer, remove it
             if peer.state.connection == ConnectionState::Disconnected {
+                // the peer sends us its id once connected
+                let was_connected = peer.id.is_some();
                 self.peers.remove(&addr);
+                self.peer_sources.handle_disconnected(
+                    &addr,
+                    was_connected,
+                    Instant::now(),
+                );
             }
         } else {
             log::debug!("Tried updating non-existent peer {}", addr);
         }
     }
 
+    /// Sets or clears the deadline of a piece in the piece picker, from where
+    /// peer sessions pick it up the next time they make requests.
+    async fn set_piece_deadline(
+        &mut self,
+        piece_index: PieceIndex,
+        deadline: Option<Instant>,
+    ) {
+        if piece_index >= self.ctx.storage.piece_count {
+            log::warn!("Invalid piece {} deadline", piece_index);
+            return;
+        }
+        log::info!("Setting piece {} deadline: {:?}", piece_index, deadline);
+        self.ctx
+            .piece_picker
+            .write()
+            .await
+            .set_piece_deadline(piece_index, deadline);
+    }
+
+    /// Returns a stream of the file with the given index.
+    fn open_file(
+        &self,
+        file_index: FileIndex,
+    ) -> std::result::Result<FileStream, Error> {
+        let storage = &self.ctx.storage;
+        let file = storage.files.get(file_index).ok_or_else(|| {
+            log::warn!("Invalid file {} to open", file_index);
+            Error::InvalidFileIndex
+        })?;
+        log::info!("Opening file {} for streaming", file_index);
+        Ok(FileStream::new(
+            file.clone(),
+            storage.piece_len,
+            self.ctx.cmd_tx.clone(),
+        ))
+    }
+
+    /// Has the disk task read the piece for a file stream if we have it.
+    ///
+    /// Otherwise the read waits until the piece is downloaded, and the piece
+    /// is given a deadline so that it's downloaded before all pieces without
+    /// one, as the stream's reader is waiting for it.
+    async fn read_piece(
+        &mut self,
+        piece_index: PieceIndex,
+        result_tx: disk::PieceSender,
+    ) -> Result<()> {
+        if piece_index >= self.ctx.storage.piece_count {
+            log::warn!("Invalid piece {} to read", piece_index);
+            result_tx.send(Err(ReadError::InvalidPieceIndex)).ok();
+            return Ok(());
+        }
+        let has_piece =
+            self.ctx.piece_picker.read().await.own_pieces()[piece_index];
+        if has_piece {
+            self.ctx.disk_tx.send(disk::Command::ReadPiece {
+                id: self.ctx.id,
+                piece_index,
+                result_tx,
+            })?;
+        } else {
+            log::debug!("Stream waiting for piece {}", piece_index);
+            self.set_piece_deadline(
+                piece_index,
+                Some(Instant::now() + STREAM_PIECE_DEADLINE),
+            )
+            .await;
+            self.piece_reads
+                .entry(piece_index)
+                .or_default()
+                .push(result_tx);
+        }
+        Ok(())
+    }
+
+    /// Sets the priority of a file and updates the priorities of the pieces
+    /// it intersects in the piece picker.
+    async fn set_file_priority(
+        &mut self,
+        file_index: FileIndex,
+        priority: FilePriority,
+    ) {
+        let storage = &self.ctx.storage;
+        if file_index >= storage.files.len() {
+            log::warn!("Invalid file {} priority", file_index);
+            return;
+        }
+        log::info!("Setting file {} priority: {:?}", file_index, priority);
+        self.file_priorities[file_index] = priority;
+
+        // a piece's priority is the highest priority of the files it
+        // intersects
+        let mut piece_picker = self.ctx.piece_picker.write().await;
+        for index in storage.pieces_intersecting_file(file_index) {
+            let piece_priority = storage
+                .files_intersecting_piece(index)
+                .map(|file_index| self.file_priorities[file_index])
+                .max()
+                .unwrap_or_default();
+            piece_picker.set_piece_priority(index, piece_priority);
+        }
+
+        // the file may have been selected or deselected
+        update_piece_boosts(
+            storage,
+            &self.file_priorities,
+            &self.conf,
+            &mut **piece_picker,
+        );
+    }
+
     /// Does some bookkeeping to mark the piece as finished. All peer sessions
     /// are notified of the newly downloaded piece.
     async fn handle_piece_completion(
         &mut self,
         piece: PieceCompletion,
     ) -> Result<()> {
+        metrics::add_piece(piece.is_valid);
+
         // if this write completed a piece, check torrent
         // completion
         if piece.is_valid {
+            // the disk is writable again
+            self.error_retry.reset();
+
             // remove download entry
             self.ctx.downloads.write().await.remove(&piece.index);
 
@@ -734,8 +2354,45 @@ impl Torrent {
                 self.ctx.piece_picker.write().await;
 
             piece_picker_write_guard.received_piece(piece.index);
+
+            // the files the piece intersects are now closer to completion, and
+            // if a file is missing a single piece, that piece is preferred
+            for file_index in
+                self.ctx.storage.files_intersecting_piece(piece.index)
+            {
+                let missing_count =
+                    &mut self.file_missing_piece_counts[file_index];
+                if *missing_count == 1 {
+                    log::info!("Downloaded file {}", file_index);
+                    self.ctx
+                        .alert_tx
+                        .send(Alert::FileComplete {
+                            id: self.ctx.id,
+                            file_index,
+                        })
+                        .ok();
+                }
+                *missing_count = missing_count.saturating_sub(1);
+                if *missing_count == 1
+                    && self.conf.prioritize_file_completion
+                    && self.file_priorities[file_index] != FilePriority::Skip
+                {
+                    let last_missing_piece = self
+                        .ctx
+                        .storage
+                        .pieces_intersecting_file(file_index)
+                        .find(|index| {
+                            !piece_picker_write_guard.own_pieces()[*index]
+                        });
+                    if let Some(index) = last_missing_piece {
+                        piece_picker_write_guard.set_piece_boost(index, true);
+                    }
+                }
+            }
+            // only the wanted pieces count towards completion, skipped pieces
+            // are never downloaded
             let missing_piece_count =
-                piece_picker_write_guard.missing_piece_count();
+                piece_picker_write_guard.wanted_missing_piece_count();
 
             // Even if we don't have all pieces, they may all have already
             // been picked. In this case we need to enter endgame mode, if not
@@ -760,6 +2417,24 @@ impl Torrent {
             if let Some(latest_completed_pieces) = &mut self.completed_pieces {
                 latest_completed_pieces.push(piece.index);
             }
+            // the file streams waiting for the piece can now read it
+            for result_tx in
+                self.piece_reads.remove(&piece.index).unwrap_or_default()
+            {
+                self.ctx.disk_tx.send(disk::Command::ReadPiece {
+                    id: self.ctx.id,
+                    piece_index: piece.index,
+                    result_tx,
+                })?;
+            }
+            self.ctx
+                .alert_tx
+                .send(Alert::PieceComplete {
+                    id: self.ctx.id,
+                    piece_index: piece.index,
+                    is_valid: true,
+                })
+                .ok();
 
             // tell all sessions that we got a new piece so that they can send
             // a "have(piece)" message to their peers or cancel potential
@@ -792,31 +2467,115 @@ impl Torrent {
                     .send(Alert::TorrentComplete(self.ctx.id))
                     .ok();
 
-                // tell trackers we've finished
-                self.announce_to_trackers(
-                    Instant::now(),
-                    Some(Event::Completed),
-                )
-                .await?;
+                // Tell trackers we've finished, but only if we have all
+                // pieces, as the completed event means we've become a seed.
+                // Pieces are only lost by a recheck, after which the event
+                // is sent again once they are downloaded again.
+                if self.ctx.piece_picker.read().await.missing_piece_count() == 0
+                {
+                    // the engine moves the torrent to a seeding slot, or
+                    // queues it if there is none
+                    self.engine_tx
+                        .send(engine::Command::TorrentSeeding {
+                            id: self.ctx.id,
+                            is_seed: true,
+                        })
+                        .ok();
+                    self.trackers.pending_event = Some(Event::Completed);
+                    self.announce_to_trackers(
+                        Instant::now(),
+                        Some(Event::Completed),
+                        false,
+                    )
+                    .await?;
+                }
             }
         } else {
-            // TODO(https://github.com/mandreyel/cratetorrent/issues/61):
-            // implement parole mode for the peers that sent corrupt data
             log::warn!("Piece {} is invalid", piece.index);
-            // mark all blocks free to be requested in piece
-            if let Some(piece) =
-                self.ctx.downloads.read().await.get(&piece.index)
-            {
-                piece.write().await.free_all_blocks();
+            self.ctx
+                .alert_tx
+                .send(Alert::PieceComplete {
+                    id: self.ctx.id,
+                    piece_index: piece.index,
+                    is_valid: false,
+                })
+                .ok();
+            // mark all blocks free to be requested in piece, taking note of
+            // the peers that sent the corrupt data
+            let (contributors, exclusive_peer) =
+                match self.ctx.downloads.read().await.get(&piece.index) {
+                    Some(download) => {
+                        let mut download = download.write().await;
+                        let contributors: Vec<_> =
+                            download.contributors().iter().copied().collect();
+                        let exclusive_peer = download.exclusive_peer();
+                        download.reset();
+                        (contributors, exclusive_peer)
+                    }
+                    None => return Ok(()),
+                };
+
+            // If only a single peer sent us blocks of the piece, it is to
+            // blame. Otherwise we can't tell which of the contributors sent
+            // the corrupt data, so we put them all on parole, in which they
+            // download whole pieces on their own, until the culprit is found.
+            if let Some(addr) = exclusive_peer {
+                self.ban_peer(addr);
+            } else if contributors.len() == 1 {
+                self.ban_peer(contributors[0]);
+            } else {
+                for addr in contributors {
+                    if let Some(tx) =
+                        self.peers.get(&addr).and_then(|p| p.tx.as_ref())
+                    {
+                        tx.send(peer::Command::EnterParole).ok();
+                    }
+                }
             }
         }
 
         Ok(())
     }
 
-    /// Shuts down torrent and all peer sessions, and also announces torrent's
-    /// exit to tracker.
+    /// Disconnects the peer for sending corrupt data and refuses to connect
+    /// to it again.
+    fn ban_peer(&mut self, addr: SocketAddr) {
+        log::warn!("Banning peer {} for sending corrupt data", addr);
+        self.banned_peers.insert(addr.ip());
+        self.peer_sources.remove_ip(addr.ip());
+        if let Some(tx) = self.peers.get(&addr).and_then(|p| p.tx.as_ref()) {
+            tx.send(peer::Command::Shutdown).ok();
+        }
+    }
+
+    /// Shuts down the torrent once it stopped accepting peers: announces its
+    /// exit to trackers, then shuts down all peer sessions, and finally sends
+    /// its state to the engine.
+    ///
+    /// Trackers are told first, so that they stop handing out our address
+    /// while the sessions are closed. The announce is bounded by
+    /// [`TorrentConf::stop_announce_timeout`], so unresponsive trackers don't
+    /// hold up the shutdown.
     async fn shutdown(&mut self) -> Result<()> {
+        self.remove_from_dht_and_lsd();
+        if let Some(port_mapping_tx) = &self.port_mapping_tx {
+            // the port mapper may have been shut down already
+            port_mapping_tx
+                .send(port_mapping::Command::RemovePort {
+                    protocol: Protocol::Tcp,
+                    port: self.listen_addr.port(),
+                })
+                .ok();
+        }
+
+        // a paused or queued torrent already told trackers it stopped, and
+        // the peers are shut down even if the announce fails
+        let announce_result = if self.is_stopped() {
+            Ok(())
+        } else {
+            self.announce_stop().await
+        };
+
         // send shutdown command to all connected peers
         for peer in self.peers.values() {
             if let Some(tx) = &peer.tx {
@@ -838,9 +2597,75 @@ impl Torrent {
             }
         }
 
-        // tell trackers we're leaving
-        self.announce_to_trackers(Instant::now(), Some(Event::Stopped))
-            .await
+        metrics::update_peer_count(self.reported_peer_count, 0);
+        self.reported_peer_count = 0;
+
+        // for the engine to save, if it's shutting down
+        self.send_state().await;
+        announce_result
+    }
+
+    /// Tells trackers we're leaving, but doesn't hold up the torrent for long
+    /// if they are unresponsive.
+    async fn announce_stop(&mut self) -> Result<()> {
+        let targets = self.trackers.stop();
+        if targets.is_empty() {
+            return Ok(());
+        }
+        let params = self.announce_params(Some(Event::Stopped), Some(0)).await;
+        trackers::announce_stop(
+            targets,
+            params,
+            self.conf.stop_announce_timeout,
+        )
+        .await;
+        Ok(())
+    }
+}
+
+/// Boosts the pieces of wanted files in the piece picker that the
+/// configuration asks for, so that they are picked before the other pieces of
+/// the same priority, and removes the boost from all other pieces.
+///
+/// These are:
+/// - the first and last pieces of each file, which media players usually need
+///   (as they contain the headers and indices of media files) to start
+///   playback or to show a preview,
+/// - and the last missing piece of each file, which completes the file.
+fn update_piece_boosts(
+    storage: &StorageInfo,
+    file_priorities: &[FilePriority],
+    conf: &TorrentConf,
+    piece_picker: &mut dyn PiecePicker,
+) {
+    if !conf.prioritize_first_last_pieces && !conf.prioritize_file_completion {
+        return;
+    }
+    let mut boosted = vec![false; storage.piece_count];
+    for (file_index, priority) in file_priorities.iter().enumerate() {
+        if *priority == FilePriority::Skip {
+            continue;
+        }
+        let pieces = storage.pieces_intersecting_file(file_index);
+        if pieces.is_empty() {
+            continue;
+        }
+        if conf.prioritize_first_last_pieces {
+            boosted[pieces.start] = true;
+            boosted[pieces.end - 1] = true;
+        }
+        if conf.prioritize_file_completion {
+            let own_pieces = piece_picker.own_pieces();
+            let mut missing_pieces = pieces.filter(|index| !own_pieces[*index]);
+            if let (Some(index), None) =
+                (missing_pieces.next(), missing_pieces.next())
+            {
+                boosted[index] = true;
+            }
+        }
+    }
+    for (index, is_boosted) in boosted.into_iter().enumerate() {
+        piece_picker.set_piece_boost(index, is_boosted);
     }
 }
 
@@ -855,6 +2680,10 @@ struct PeerSessionEntry {
     /// Peer's 20 byte BitTorrent id. Updated when the peer sends us its peer
     /// id, in the handshake.
     id: Option<PeerId>,
+    /// Whether the peer connected to us.
+    is_inbound: bool,
+    /// Whether the peer is on our local network.
+    is_local: bool,
     /// Cached information about the session state. Updated every time peer
     /// updates us.
     state: SessionState,
@@ -863,116 +2692,144 @@ struct PeerSessionEntry {
 
     /// Most recent throughput statistics of this peer.
     thruput: ThruputStats,
+    /// The messages exchanged with this peer so far, by message type.
+    messages: MessageStats,
 
     /// The peer session task's join handle, used during shutdown.
-    join_handle: Option<task::JoinHandle<peer::error::Result<()>>>,
+    join_handle: Option<rt::JoinHandle<peer::error::Result<()>>>,
 }
 
 impl PeerSessionEntry {
-    fn start_outbound(mut session: PeerSession, tx: peer::Sender) -> Self {
-        let join_handle =
-            task::spawn(async move { session.start_outbound().await });
-        Self::new(tx, join_handle)
+    fn start_outbound(
+        mut session: PeerSession,
+        tx: peer::Sender,
+        is_local: bool,
+    ) -> Self {
+        // the session's span is a child of the torrent's
+        let join_handle = rt::spawn(
+            async move { session.start_outbound().await }.in_current_span(),
+        );
+        Self::new(tx, join_handle, false, is_local)
     }
 
     fn start_inbound(
         socket: TcpStream,
         mut session: PeerSession,
         tx: peer::Sender,
+        is_local: bool,
     ) -> Self {
-        let join_handle =
-            task::spawn(async move { session.start_inbound(socket).await });
-        Self::new(tx, join_handle)
+        let join_handle = rt::spawn(
+            async move { session.start_inbound(socket).await }
+                .in_current_span(),
+        );
+        Self::new(tx, join_handle, true, is_local)
     }
 
     fn new(
         tx: peer::Sender,
-        join_handle: task::JoinHandle<peer::error::Result<()>>,
+        join_handle: rt::JoinHandle<peer::error::Result<()>>,
+        is_inbound: bool,
+        is_local: bool,
     ) -> Self {
         Self {
             tx: Some(tx),
             id: None,
+            is_inbound,
+            is_local,
             state: SessionState {
                 connection: ConnectionState::Connecting,
                 ..Default::default()
             },
             piece_count: 0,
             thruput: Default::default(),
+            messages: Default::default(),
             join_handle: Some(join_handle),
         }
     }
 }
 
-/// Contains the tracker client as well as additional metadata about the
-/// tracker.
-struct TrackerEntry {
-    client: Tracker,
-    /// If a previous announce contained a tracker_id, it should be included in
-    /// next announces. Therefore it is cached here.
-    id: Option<String>,
-    /// The last announce time is kept here so that we don't request too often.
-    last_announce_time: Option<Instant>,
-    /// The interval at which we should update the tracker of our progress.
-    /// This is set after the first announce request.
-    interval: Option<Duration>,
-    /// The absolute minimum interval at which we can contact tracker.
-    /// This is set after the first announce request.
-    min_interval: Option<Duration>,
-    /// Each time we fail to requet from tracker, this counter is incremented.
-    /// If it fails too often, we stop requesting from tracker.
-    error_count: usize,
-}
-
-impl TrackerEntry {
-    fn new(client: Tracker) -> Self {
-        Self {
-            client,
-            id: None,
-            last_announce_time: None,
-            interval: None,
-            min_interval: None,
-            error_count: 0,
+/// Updates the pieces we have in the piece picker to those found valid by
+/// a recheck, except for the pieces being downloaded, whose state the recheck
+/// may have missed.
+fn apply_checked_pieces(
+    piece_picker: &mut dyn PiecePicker,
+    pieces: &Bitfield,
+    is_downloading: impl Fn(PieceIndex) -> bool,
+) {
+    for (index, is_valid) in pieces.iter().enumerate() {
+        if is_downloading(index) {
+            continue;
         }
-    }
-
-    /// Determines whether we should announce to the tracker at the given time,
-    /// based on when we last announced.
-    ///
-    /// Later this function should take into consideration the client's minimum
-    /// announce frequency settings.
-    fn should_announce(
-        &self,
-        t: Instant,
-        default_announce_interval: Duration,
-    ) -> bool {
-        if let Some(last_announce_time) = self.last_announce_time {
-            let min_next_announce_time = last_announce_time
-                + self.interval.unwrap_or(default_announce_interval);
-            t > min_next_announce_time
-        } else {
-            true
+        let have_piece = piece_picker.own_pieces()[index];
+        if have_piece && !*is_valid {
+            piece_picker.lost_piece(index);
+        } else if !have_piece && *is_valid {
+            piece_picker.received_piece(index);
         }
     }
+}
 
-    /// Determines whether we're allowed to announce at the given time.
-    ///
-    /// We may need peers before the next step in the announce interval.
-    /// However, we can't do this too often, so we need to check our last
-    /// announce time first.
-    fn can_announce(
-        &self,
-        t: Instant,
-        default_announce_interval: Duration,
-    ) -> bool {
-        if let Some(last_announce_time) = self.last_announce_time {
-            let min_next_announce_time = last_announce_time
-                + self
-                    .min_interval
-                    .or(self.min_interval)
-                    .unwrap_or(default_announce_interval);
-            t > min_next_announce_time
-        } else {
-            true
-        }
+/// Returns the number of bytes we still need to download to have all pieces,
+/// which we report to trackers.
+fn left_len(own_pieces: &Bitfield, storage: &StorageInfo) -> u64 {
+    (0..own_pieces.len())
+        .filter(|&index| !own_pieces[index])
+        .map(|index| storage.piece_len(index) as u64)
+        .sum()
+}
+
+/// The regular announce interval of a tracker is extended by a random fraction
+/// of itself up to this value.
+const MAX_ANNOUNCE_JITTER: f64 = 0.1;
+
+/// The deadline given to a piece that a file stream is waiting for, from the
+/// time the stream reaches it.
+const STREAM_PIECE_DEADLINE: Duration = Duration::from_secs(2);
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::storage_info::FileInfo;
+
+    /// Tests that the number of bytes left to download, which we announce to
+    /// trackers, accounts for the pieces lost and found by a recheck.
+    #[test]
+    fn should_update_left_len_after_recheck() {
+        // 3 full length pieces and a shorter last piece
+        let storage = StorageInfo {
+            piece_count: 4,
+            piece_len: 4,
+            last_piece_len: 2,
+            download_len: 3 * 4 + 2,
+            download_dir: PathBuf::from("/"),
+            is_archive: false,
+            files: vec![FileInfo {
+                path: PathBuf::from("/bogus"),
+                torrent_offset: 0,
+                len: 3 * 4 + 2,
+            }],
+        };
+        let mut own_pieces = Bitfield::repeat(true, 4);
+        own_pieces.set(0, false);
+        let mut piece_picker = RarestFirstPicker::new(own_pieces);
+        assert_eq!(left_len(piece_picker.own_pieces(), &storage), 4);
+
+        // piece 0 was completed on disk and pieces 1 and 3 were corrupted,
+        // but piece 2, which is being downloaded, is left as is
+        let mut checked_pieces = Bitfield::repeat(true, 4);
+        checked_pieces.set(1, false);
+        checked_pieces.set(2, false);
+        checked_pieces.set(3, false);
+        apply_checked_pieces(&mut piece_picker, &checked_pieces, |index| {
+            index == 2
+        });
+        assert_eq!(left_len(piece_picker.own_pieces(), &storage), 4 + 2);
+
+        // nothing is left once the pieces are downloaded again
+        piece_picker.received_piece(1);
+        piece_picker.received_piece(3);
+        assert_eq!(left_len(piece_picker.own_pieces(), &storage), 0);
     }
 }
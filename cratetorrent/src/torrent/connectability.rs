@@ -0,0 +1,213 @@
+//! Determines whether peers on the internet can connect to the torrent's
+//! listen port.
+//!
+//! Two kinds of evidence are used. An inbound connection from a global
+//! address proves that the port is reachable, as the peer must have gone
+//! through our public address. Until such a connection arrives, the torrent
+//! periodically probes the port itself by connecting to our external address,
+//! as reported by trackers and peers, and the announced port. If the probe
+//! fails, the port is considered firewalled.
+//!
+//! The probe is not conclusive by itself: some routers don't forward
+//! connections made from within the network to their external address (they
+//! lack "hairpinning"), in which case a reachable port is reported as
+//! firewalled until a peer connects to us.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use crate::external_ip;
+
+use super::stats::Connectability;
+
+/// The first probe is delayed by this long after the torrent starts, giving
+/// trackers time to report our external address and port mappings time to
+/// be made.
+const INITIAL_PROBE_DELAY: Duration = Duration::from_secs(30);
+
+/// While the port is not known to be reachable, it is probed again this
+/// often, as a port mapping may have been made in the meantime.
+const PROBE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// A probe that can't connect within this long fails.
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The state of the connectability check of a torrent's listen port.
+#[derive(Debug)]
+pub(crate) struct ConnectabilityCheck {
+    /// What we currently know of the port's reachability.
+    status: Connectability,
+    /// When the torrent was started, to delay the first probe.
+    start_time: Instant,
+    /// The time the last probe was started, if any.
+    last_probe_time: Option<Instant>,
+    /// Whether a probe is currently in progress.
+    is_probing: bool,
+    /// Set when the user asks for a check, in which case the next probe is
+    /// started right away and its result is reported even if the status did
+    /// not change.
+    is_forced: bool,
+}
+
+impl ConnectabilityCheck {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            status: Connectability::Unknown,
+            start_time: now,
+            last_probe_time: None,
+            is_probing: false,
+            is_forced: false,
+        }
+    }
+
+    pub fn status(&self) -> Connectability {
+        self.status
+    }
+
+    /// Whether the result of the probe in progress was asked for by the user.
+    pub fn is_forced(&self) -> bool {
+        self.is_forced
+    }
+
+    /// Asks for the port to be probed as soon as possible, regardless of what
+    /// we already know.
+    pub fn force(&mut self) {
+        self.is_forced = true;
+    }
+
+    /// Records an inbound connection from the given address. Returns whether
+    /// the status changed.
+    pub fn handle_incoming_peer(&mut self, ip: IpAddr) -> bool {
+        // peers on the local network may reach us without going through the
+        // gateway, so they prove nothing
+        if !external_ip::is_global(ip) {
+            return false;
+        }
+        self.set_status(Connectability::Connectable)
+    }
+
+    /// Returns the address to probe if it's time to do so, and marks the probe
+    /// as started.
+    ///
+    /// The probe targets our external address and the port we announce to
+    /// peers. If the external address is not known yet, no probe is made.
+    pub fn probe_target(
+        &mut self,
+        now: Instant,
+        external_ip: Option<Ipv4Addr>,
+        port: u16,
+    ) -> Option<SocketAddr> {
+        if self.is_probing {
+            return None;
+        }
+        if !self.is_forced {
+            if self.status == Connectability::Connectable
+                || now.saturating_duration_since(self.start_time)
+                    < INITIAL_PROBE_DELAY
+            {
+                return None;
+            }
+            if let Some(last_probe_time) = self.last_probe_time {
+                if now.saturating_duration_since(last_probe_time)
+                    < PROBE_INTERVAL
+                {
+                    return None;
+                }
+            }
+        }
+        let external_ip = external_ip?;
+        self.is_probing = true;
+        self.last_probe_time = Some(now);
+        Some(SocketAddr::from((external_ip, port)))
+    }
+
+    /// Records the outcome of the probe started by the last
+    /// [`Self::probe_target`] call. Returns whether the status changed.
+    pub fn handle_probe_result(&mut self, is_reachable: bool) -> bool {
+        self.is_probing = false;
+        self.is_forced = false;
+        if is_reachable {
+            self.set_status(Connectability::Connectable)
+        } else if self.status == Connectability::Connectable {
+            // a peer connecting to us is stronger evidence than a failed
+            // probe, which may be due to the router rather than the port
+            false
+        } else {
+            self.set_status(Connectability::Firewalled)
+        }
+    }
+
+    fn set_status(&mut self, status: Connectability) -> bool {
+        let is_changed = self.status != status;
+        self.status = status;
+        is_changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXTERNAL_IP: Ipv4Addr = Ipv4Addr::new(93, 184, 216, 34);
+
+    #[test]
+    fn should_probe_after_initial_delay() {
+        let start = Instant::now();
+        let mut check = ConnectabilityCheck::new(start);
+        assert_eq!(check.probe_target(start, Some(EXTERNAL_IP), 6881), None);
+
+        let now = start + INITIAL_PROBE_DELAY;
+        // the external address is not known yet
+        assert_eq!(check.probe_target(now, None, 6881), None);
+        assert_eq!(
+            check.probe_target(now, Some(EXTERNAL_IP), 6881),
+            Some(SocketAddr::from((EXTERNAL_IP, 6881)))
+        );
+        // the probe is in progress
+        assert_eq!(check.probe_target(now, Some(EXTERNAL_IP), 6881), None);
+
+        assert!(check.handle_probe_result(false));
+        assert_eq!(check.status(), Connectability::Firewalled);
+        assert_eq!(check.probe_target(now, Some(EXTERNAL_IP), 6881), None);
+
+        // the port is probed again later
+        let now = now + PROBE_INTERVAL;
+        assert!(check.probe_target(now, Some(EXTERNAL_IP), 6881).is_some());
+        assert!(check.handle_probe_result(true));
+        assert_eq!(check.status(), Connectability::Connectable);
+
+        // but no longer once it's known to be reachable
+        let now = now + PROBE_INTERVAL;
+        assert_eq!(check.probe_target(now, Some(EXTERNAL_IP), 6881), None);
+    }
+
+    #[test]
+    fn should_be_connectable_after_incoming_global_peer() {
+        let now = Instant::now();
+        let mut check = ConnectabilityCheck::new(now);
+        assert!(!check.handle_incoming_peer([192, 168, 1, 2].into()));
+        assert_eq!(check.status(), Connectability::Unknown);
+        assert!(check.handle_incoming_peer(EXTERNAL_IP.into()));
+        assert_eq!(check.status(), Connectability::Connectable);
+        assert!(!check.handle_incoming_peer(EXTERNAL_IP.into()));
+
+        // a failed probe doesn't override an incoming connection
+        check.force();
+        assert!(check.probe_target(now, Some(EXTERNAL_IP), 6881).is_some());
+        assert!(!check.handle_probe_result(false));
+        assert_eq!(check.status(), Connectability::Connectable);
+    }
+
+    #[test]
+    fn should_probe_right_away_when_forced() {
+        let now = Instant::now();
+        let mut check = ConnectabilityCheck::new(now);
+        check.force();
+        assert!(check.is_forced());
+        assert!(check.probe_target(now, Some(EXTERNAL_IP), 6881).is_some());
+        check.handle_probe_result(false);
+        assert!(!check.is_forced());
+    }
+}
@@ -0,0 +1,208 @@
+//! Tracks whether a torrent is stopped by an error accessing its files, and
+//! when it's retried.
+//!
+//! An errored torrent is retried after
+//! [`TorrentConf::error_retry_interval`], in case the condition, such as
+//! a full disk, has cleared. The wait is doubled with each consecutive error,
+//! up to [`TorrentConf::max_error_retry_interval`], and the count of
+//! consecutive errors is reset once the torrent writes a piece again. Only
+//! auto-managed torrents are retried; others stay errored until the user
+//! clears the error.
+
+use std::time::{Duration, Instant};
+
+use crate::conf::TorrentConf;
+
+/// The error state of a torrent.
+#[derive(Debug, Default)]
+pub(crate) struct ErrorRetry {
+    /// Set while the torrent is stopped by an error, the same way as when
+    /// paused, until it's retried.
+    is_errored: bool,
+    /// The time the errored torrent is retried, if it's auto-managed and
+    /// retries are enabled.
+    retry_time: Option<Instant>,
+    /// The number of consecutive times the torrent was errored, from which
+    /// the backoff before the next retry is derived.
+    error_count: usize,
+}
+
+impl ErrorRetry {
+    /// Returns whether the torrent is stopped by an error.
+    pub fn is_errored(&self) -> bool {
+        self.is_errored
+    }
+
+    /// Returns the time left until the errored torrent is retried, if it's
+    /// retried automatically.
+    pub fn retry_in(&self, now: Instant) -> Option<Duration> {
+        self.retry_time.map(|t| t.saturating_duration_since(now))
+    }
+
+    /// Records an error of the torrent and schedules its retry.
+    ///
+    /// Returns false if the torrent was already errored, in which case the
+    /// error is of a disk operation that was in flight when the torrent was
+    /// stopped, and is not counted.
+    pub fn enter(
+        &mut self,
+        conf: &TorrentConf,
+        is_auto_managed: bool,
+        now: Instant,
+    ) -> bool {
+        if self.is_errored {
+            return false;
+        }
+        self.is_errored = true;
+        self.error_count += 1;
+        self.schedule(conf, is_auto_managed, now);
+        true
+    }
+
+    /// Sets the time at which the errored torrent is retried, doubling the
+    /// wait with each consecutive error up to the max retry interval, or
+    /// clears it if the torrent is not auto-managed or retries are disabled.
+    pub fn schedule(
+        &mut self,
+        conf: &TorrentConf,
+        is_auto_managed: bool,
+        now: Instant,
+    ) {
+        if !self.is_errored {
+            return;
+        }
+        self.retry_time = match conf.error_retry_interval {
+            Some(retry_interval) if is_auto_managed => {
+                let max_retry_interval = conf.max_error_retry_interval;
+                // cap the exponent so that the multiplication can't overflow
+                let exp = (self.error_count.max(1) - 1).min(16) as u32;
+                let backoff = retry_interval
+                    .checked_mul(2u32.pow(exp))
+                    .unwrap_or(max_retry_interval)
+                    .min(max_retry_interval);
+                Some(now + backoff)
+            }
+            _ => None,
+        };
+    }
+
+    /// Returns whether the errored torrent is due to be retried.
+    pub fn is_retry_due(&self, now: Instant) -> bool {
+        self.is_errored && self.retry_time.map_or(false, |t| t <= now)
+    }
+
+    /// Clears the error, as its retry is due or the user cleared it, but
+    /// keeps the count of consecutive errors, so that if the error persists,
+    /// the next retry waits longer.
+    ///
+    /// Returns false if the torrent wasn't errored.
+    pub fn clear(&mut self) -> bool {
+        if !self.is_errored {
+            return false;
+        }
+        self.is_errored = false;
+        self.retry_time = None;
+        true
+    }
+
+    /// Resets the count of consecutive errors, as the torrent's files are
+    /// accessible again.
+    pub fn reset(&mut self) {
+        self.error_count = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conf() -> TorrentConf {
+        TorrentConf {
+            error_retry_interval: Some(Duration::from_secs(60)),
+            max_error_retry_interval: Duration::from_secs(200),
+            ..Default::default()
+        }
+    }
+
+    /// Tests that an error is only counted once until the torrent is retried.
+    #[test]
+    fn should_enter_error_state_once() {
+        let conf = conf();
+        let now = Instant::now();
+        let mut retry = ErrorRetry::default();
+        assert!(!retry.is_errored());
+        assert!(!retry.clear());
+
+        assert!(retry.enter(&conf, true, now));
+        assert!(retry.is_errored());
+        assert_eq!(retry.retry_in(now), Some(Duration::from_secs(60)));
+        // an error of another disk operation that was in flight
+        let later = now + Duration::from_secs(1);
+        assert!(!retry.enter(&conf, true, later));
+        assert_eq!(retry.retry_in(later), Some(Duration::from_secs(59)));
+    }
+
+    /// Tests that the retry is due once its backoff elapses, that it's
+    /// doubled with each consecutive error up to the cap, and that it
+    /// starts over once the torrent writes a piece.
+    #[test]
+    fn should_back_off_retries() {
+        let conf = conf();
+        let mut now = Instant::now();
+        let mut retry = ErrorRetry::default();
+        let mut backoffs = Vec::new();
+        for _ in 0..4 {
+            assert!(retry.enter(&conf, true, now));
+            let backoff = retry.retry_in(now).unwrap();
+            backoffs.push(backoff.as_secs());
+            let early = now + backoff - Duration::from_secs(1);
+            assert!(!retry.is_retry_due(early));
+            now += backoff;
+            assert!(retry.is_retry_due(now));
+            assert!(retry.clear());
+            assert!(!retry.is_errored());
+            assert!(!retry.is_retry_due(now));
+        }
+        assert_eq!(backoffs, [60, 120, 200, 200]);
+
+        retry.reset();
+        retry.enter(&conf, true, now);
+        assert_eq!(retry.retry_in(now), Some(Duration::from_secs(60)));
+    }
+
+    /// Tests that a torrent that is not auto-managed, or if retries are
+    /// disabled, stays errored until the error is cleared, and that it's
+    /// retried once it's made auto-managed.
+    #[test]
+    fn should_not_retry_unless_auto_managed() {
+        let now = Instant::now();
+        let later = now + Duration::from_secs(24 * 60 * 60);
+
+        let no_retries = TorrentConf {
+            error_retry_interval: None,
+            ..conf()
+        };
+        let mut retry = ErrorRetry::default();
+        retry.enter(&no_retries, true, now);
+        assert_eq!(retry.retry_in(now), None);
+        assert!(!retry.is_retry_due(later));
+
+        let conf = conf();
+        let mut retry = ErrorRetry::default();
+        retry.enter(&conf, false, now);
+        assert_eq!(retry.retry_in(now), None);
+        assert!(!retry.is_retry_due(later));
+
+        retry.schedule(&conf, true, now);
+        assert!(retry.is_retry_due(now + Duration::from_secs(60)));
+        // and it's not retried anymore once it's no longer auto-managed
+        retry.schedule(&conf, false, now);
+        assert!(!retry.is_retry_due(later));
+        assert!(retry.clear());
+        assert!(!retry.is_errored());
+
+        // a torrent that isn't errored isn't scheduled for a retry
+        retry.schedule(&conf, true, now);
+        assert_eq!(retry.retry_in(now), None);
+    }
+}
@@ -0,0 +1,327 @@
+//! Fetches the metadata of a torrent added from a magnet link.
+//!
+//! Until its metadata is known, a torrent's pieces and files are not, so the
+//! torrent itself can't be created yet. In the meantime this task stands in
+//! for it: it finds peers through the magnet link, its trackers, and the DHT,
+//! and downloads the metadata from a few of them at a time. Once a peer sent
+//! metadata that matches the info hash, it's handed to the engine, which
+//! creates the torrent with it.
+//!
+//! The DHT sends the peers it finds on a [torrent channel](super::Sender),
+//! so the task is given one, on which it also receives the shutdown command.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    mem,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::{
+    future::{self, BoxFuture, FutureExt},
+    select,
+    stream::{Fuse, FuturesUnordered, StreamExt},
+};
+use tokio::sync::mpsc;
+
+use super::{Command, Receiver, Sender};
+use crate::{
+    conf::{AnnounceConf, ProxyConf, TorrentConf},
+    dht, engine,
+    ip_filter::SharedIpFilter,
+    local_addr::LocalAddr,
+    peer::{self, error::PeerError},
+    rt,
+    tracker::{Announce, Tracker},
+    PeerId, Sha1Hash, TorrentId,
+};
+
+/// The number of peers from which the metadata is downloaded at the same
+/// time.
+const MAX_DOWNLOAD_COUNT: usize = 8;
+
+/// The address of the peer from which the metadata was downloaded, and the
+/// metadata or the reason it couldn't be.
+type DownloadResult = (SocketAddr, Result<Vec<u8>, PeerError>);
+
+/// Parameters for the metadata fetch task.
+pub(crate) struct Params {
+    pub id: TorrentId,
+    pub info_hash: Sha1Hash,
+    pub client_id: PeerId,
+    /// The trackers of the magnet link.
+    pub trackers: Vec<Tracker>,
+    /// The peers of the magnet link.
+    pub peers: Vec<SocketAddr>,
+    pub proxy: Option<ProxyConf>,
+    pub announce_conf: AnnounceConf,
+    pub ip_filter: Arc<SharedIpFilter>,
+    pub local_addr: Arc<LocalAddr>,
+    /// Set if peers should be looked up in the DHT.
+    pub dht_tx: Option<dht::Sender>,
+    pub engine_tx: engine::Sender,
+    pub conf: TorrentConf,
+}
+
+pub(crate) struct MetadataFetch {
+    id: TorrentId,
+    info_hash: Sha1Hash,
+    client_id: PeerId,
+    /// The trackers, which are taken out while they are being announced to.
+    trackers: Vec<Tracker>,
+    /// When the trackers are announced to next.
+    next_announce_time: Instant,
+    proxy: Option<ProxyConf>,
+    announce_conf: AnnounceConf,
+    ip_filter: Arc<SharedIpFilter>,
+    local_addr: Arc<LocalAddr>,
+    dht_tx: Option<dht::Sender>,
+    engine_tx: engine::Sender,
+    conf: TorrentConf,
+    cmd_rx: Fuse<Receiver>,
+    /// A copy of the channel sender, given to the DHT.
+    cmd_tx: Sender,
+    /// The peers we haven't tried downloading the metadata from yet.
+    candidates: VecDeque<SocketAddr>,
+    /// All peers found, so that peers are only tried once, and so that they
+    /// can be handed to the torrent once it's created.
+    peers: HashSet<SocketAddr>,
+}
+
+impl MetadataFetch {
+    pub fn new(params: Params) -> (Self, Sender) {
+        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        let mut fetch = Self {
+            id: params.id,
+            info_hash: params.info_hash,
+            client_id: params.client_id,
+            trackers: params.trackers,
+            next_announce_time: Instant::now(),
+            proxy: params.proxy,
+            announce_conf: params.announce_conf,
+            ip_filter: params.ip_filter,
+            local_addr: params.local_addr,
+            dht_tx: params.dht_tx,
+            engine_tx: params.engine_tx,
+            conf: params.conf,
+            cmd_rx: cmd_rx.fuse(),
+            cmd_tx: cmd_tx.clone(),
+            candidates: VecDeque::new(),
+            peers: HashSet::new(),
+        };
+        fetch.add_peers(params.peers);
+        (fetch, cmd_tx)
+    }
+
+    /// Runs the task until the metadata is downloaded, or until the engine
+    /// shuts it down because the torrent was removed.
+    pub async fn start(&mut self) {
+        log::info!("Fetching metadata of torrent {}", self.id);
+        if let Some(dht_tx) = &self.dht_tx {
+            // the torrent doesn't accept connections yet, so it's only looked
+            // up in the DHT, not announced
+            dht_tx
+                .send(dht::Command::AddTorrent {
+                    info_hash: self.info_hash,
+                    port: 0,
+                    torrent_tx: self.cmd_tx.clone(),
+                })
+                .ok();
+        }
+
+        let mut tick_timer = rt::interval(Duration::from_secs(1)).fuse();
+        let mut downloads = FuturesUnordered::new();
+        let mut announces = FuturesUnordered::new();
+        loop {
+            select! {
+                now = tick_timer.select_next_some() => {
+                    if !self.trackers.is_empty()
+                        && now >= self.next_announce_time
+                    {
+                        let trackers = mem::take(&mut self.trackers);
+                        announces.push(announce(trackers, self.announce()));
+                    }
+                    self.start_downloads(&mut downloads);
+                    if downloads.is_empty() && self.candidates.is_empty() {
+                        if let Some(dht_tx) = &self.dht_tx {
+                            dht_tx
+                                .send(dht::Command::GetPeers {
+                                    info_hash: self.info_hash,
+                                })
+                                .ok();
+                        }
+                    }
+                }
+                (addr, result) = downloads.select_next_some() => {
+                    match result {
+                        Ok(metadata) => {
+                            log::info!(
+                                "Torrent {} metadata downloaded from {}",
+                                self.id,
+                                addr
+                            );
+                            // the engine may be shutting down
+                            self.engine_tx
+                                .send(engine::Command::MetadataReceived {
+                                    id: self.id,
+                                    metadata,
+                                    peers: self.peers.iter().copied().collect(),
+                                })
+                                .ok();
+                            break;
+                        }
+                        Err(e) => {
+                            log::debug!(
+                                "Cannot download metadata from {}: {}",
+                                addr,
+                                e
+                            );
+                            self.start_downloads(&mut downloads);
+                        }
+                    }
+                }
+                (trackers, peers, interval) = announces.select_next_some() => {
+                    self.trackers = trackers;
+                    self.next_announce_time = Instant::now()
+                        + interval
+                            .unwrap_or(self.conf.tracker_retry_interval);
+                    self.add_peers(peers);
+                    self.start_downloads(&mut downloads);
+                }
+                cmd = self.cmd_rx.select_next_some() => match cmd {
+                    Command::DhtPeers(peers) => {
+                        self.add_peers(peers);
+                        self.start_downloads(&mut downloads);
+                    }
+                    Command::Shutdown => {
+                        log::info!("Stopping torrent {} metadata fetch", self.id);
+                        break;
+                    }
+                    // the other commands are for the torrent proper
+                    _ => (),
+                },
+            }
+        }
+
+        if let Some(dht_tx) = &self.dht_tx {
+            // the DHT may have been shut down already
+            dht_tx
+                .send(dht::Command::RemoveTorrent {
+                    info_hash: self.info_hash,
+                })
+                .ok();
+        }
+    }
+
+    /// Adds the peers that pass the IP filter and were not found before to
+    /// the candidates.
+    fn add_peers(&mut self, peers: Vec<SocketAddr>) {
+        for addr in peers {
+            if self.ip_filter.allows_peer(&addr) && self.peers.insert(addr) {
+                self.candidates.push_back(addr);
+            }
+        }
+    }
+
+    /// Starts downloading the metadata from candidates, as long as there are
+    /// free download slots.
+    fn start_downloads(
+        &mut self,
+        downloads: &mut FuturesUnordered<BoxFuture<'static, DownloadResult>>,
+    ) {
+        while downloads.len() < MAX_DOWNLOAD_COUNT {
+            match self.candidates.pop_front() {
+                Some(addr) => downloads.push(self.download(addr)),
+                None => break,
+            }
+        }
+    }
+
+    /// Returns the future that downloads the metadata from the peer, along
+    /// with the peer's address.
+    fn download(&self, addr: SocketAddr) -> BoxFuture<'static, DownloadResult> {
+        let info_hash = self.info_hash;
+        let client_id = self.client_id;
+        let proxy = self.proxy.clone();
+        let local_addr = Arc::clone(&self.local_addr);
+        async move {
+            let result = peer::metadata::download(
+                addr,
+                info_hash,
+                client_id,
+                proxy.as_ref(),
+                &local_addr,
+            )
+            .await;
+            (addr, result)
+        }
+        .boxed()
+    }
+
+    /// Returns the parameters of the announces to trackers.
+    fn announce(&self) -> Announce {
+        Announce {
+            info_hash: self.info_hash,
+            peer_id: self.client_id,
+            // we don't accept connections until the torrent is created, so
+            // unless the user overrides the port, none is announced
+            port: self.announce_conf.port.unwrap_or(0),
+            peer_count: self
+                .announce_conf
+                .numwant
+                .or(Some(self.conf.max_connected_peer_count)),
+            uploaded: 0,
+            downloaded: 0,
+            // the torrent's length is not known yet, but announcing that
+            // nothing is left would make us look like a seed, to which
+            // trackers may not return other seeds
+            left: 1,
+            ip: None,
+            event: None,
+            tracker_id: None,
+            key: self.announce_conf.key,
+        }
+    }
+}
+
+/// Announces to all trackers concurrently, returning them along with the
+/// peers they returned, and the shortest announce interval among those that
+/// responded, if any did.
+async fn announce(
+    mut trackers: Vec<Tracker>,
+    params: Announce,
+) -> (Vec<Tracker>, Vec<SocketAddr>, Option<Duration>) {
+    let announces = trackers.iter_mut().map(|tracker| {
+        let params = params.clone();
+        async move {
+            let url = tracker.url().clone();
+            (url, tracker.announce(params).await)
+        }
+    });
+    let mut peers = Vec::new();
+    let mut interval: Option<Duration> = None;
+    for (url, result) in future::join_all(announces).await {
+        match result {
+            Ok(resp) => {
+                log::debug!(
+                    "Tracker {} returned {} peer(s)",
+                    url,
+                    resp.peers.len()
+                );
+                peers.extend(resp.peers);
+                let tracker_interval = resp
+                    .interval
+                    .or(resp.min_interval)
+                    .unwrap_or_default()
+                    .max(resp.min_interval.unwrap_or_default());
+                interval = Some(match interval {
+                    Some(interval) => interval.min(tracker_interval),
+                    None => tracker_interval,
+                });
+            }
+            Err(e) => log::warn!("Error announcing to tracker {}: {}", url, e),
+        }
+    }
+    (trackers, peers, interval)
+}
@@ -0,0 +1,330 @@
+//! The peers a torrent may connect to, gathered from all the places peers are
+//! learned from.
+//!
+//! Each source (trackers, the DHT, the local network, and the seeds given by
+//! the user) reports the addresses it finds, which are merged into a single
+//! set of candidates keyed by address. When the torrent has room for more
+//! connections, it asks for the best candidates, which are ranked by how
+//! reliable their sources are and by how connecting to them went in the past.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+/// A candidate we couldn't connect to this many times in a row is dropped.
+const MAX_FAILURE_COUNT: u32 = 3;
+
+/// After a failed connection attempt, the candidate is not tried again for
+/// this long, doubled with each consecutive failure.
+const RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+/// A candidate whose connection we had and lost is not tried again for this
+/// long, so that peers that drop us are not reconnected to in a tight loop.
+const RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// The maximum number of candidates kept. Sources return far fewer peers
+/// than this in practice, but it keeps a misbehaving source from exhausting
+/// memory.
+const MAX_CANDIDATE_COUNT: usize = 2000;
+
+/// Where a peer was learned from.
+///
+/// The variants are in increasing order of how likely the peers they return
+/// are to be reachable and part of the swarm.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum PeerSource {
+    /// The DHT, where anyone can announce anything.
+    Dht,
+    /// A tracker.
+    Tracker,
+    /// Local Service Discovery, which only finds peers on our network.
+    Lsd,
+    /// The seeds given by the user.
+    Manual,
+}
+
+/// A peer we may connect to.
+#[derive(Debug)]
+struct Candidate {
+    /// The most reliable source that returned the peer.
+    source: PeerSource,
+    /// The sources that returned the peer, as a bitmask of the
+    /// [`PeerSource`] discriminants. Peers known to several sources are more
+    /// likely to be active.
+    source_mask: u8,
+    /// Whether we are connected, or connecting, to the peer.
+    is_connected: bool,
+    /// The number of consecutive failed connection attempts.
+    failure_count: u32,
+    /// The peer is not connected to before this time.
+    next_attempt_time: Option<Instant>,
+}
+
+impl Candidate {
+    fn new(source: PeerSource) -> Self {
+        Self {
+            source,
+            source_mask: 1 << source as u8,
+            is_connected: false,
+            failure_count: 0,
+            next_attempt_time: None,
+        }
+    }
+
+    fn is_connectable(&self, now: Instant) -> bool {
+        !self.is_connected && self.next_attempt_time.map_or(true, |t| t <= now)
+    }
+
+    /// Whether the candidate was found on the local network.
+    fn is_lsd(&self) -> bool {
+        self.source_mask & 1 << PeerSource::Lsd as u8 != 0
+    }
+
+    /// The rank of the candidate: the greater, the sooner it's connected to.
+    ///
+    /// Peers on the local network are preferred to all others with the same
+    /// failure count, as their bandwidth is essentially free.
+    fn rank(&self, is_local: bool) -> (u32, bool, PeerSource, u32) {
+        (
+            MAX_FAILURE_COUNT - self.failure_count,
+            is_local || self.is_lsd(),
+            self.source,
+            self.source_mask.count_ones(),
+        )
+    }
+}
+
+/// The peers of a torrent that we know of, from all sources, and their
+/// connection history.
+#[derive(Debug, Default)]
+pub(crate) struct PeerSources {
+    candidates: HashMap<SocketAddr, Candidate>,
+}
+
+impl PeerSources {
+    /// Adds the peers returned by the source, merging them with the peers we
+    /// already know, and returns the number of peers that were new.
+    pub fn add(
+        &mut self,
+        peers: impl IntoIterator<Item = SocketAddr>,
+        source: PeerSource,
+    ) -> usize {
+        let mut new_count = 0;
+        for addr in peers {
+            if let Some(candidate) = self.candidates.get_mut(&addr) {
+                candidate.source_mask |= 1 << source as u8;
+                candidate.source = candidate.source.max(source);
+            } else if self.candidates.len() < MAX_CANDIDATE_COUNT {
+                log::debug!("Received peer {} from {:?}", addr, source);
+                self.candidates.insert(addr, Candidate::new(source));
+                new_count += 1;
+            }
+        }
+        new_count
+    }
+
+    /// Returns the number of peers we know of, connected or not.
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    /// Returns the number of peers that we could connect to right now.
+    pub fn connectable_count(&self, now: Instant) -> usize {
+        self.candidates
+            .values()
+            .filter(|c| c.is_connectable(now))
+            .count()
+    }
+
+    /// Returns whether the peer was found via Local Service Discovery, and is
+    /// thus on our local network.
+    pub fn is_lsd(&self, addr: &SocketAddr) -> bool {
+        self.candidates.get(addr).map_or(false, Candidate::is_lsd)
+    }
+
+    /// Picks at most `count` of the best peers to connect to, for which
+    /// `is_allowed` returns true, and marks them as connected.
+    ///
+    /// Peers for which `is_local` returns true are considered to be on our
+    /// local network and are picked first, as are peers found via LSD.
+    pub fn pick(
+        &mut self,
+        count: usize,
+        now: Instant,
+        is_allowed: impl Fn(&SocketAddr) -> bool,
+        is_local: impl Fn(&SocketAddr) -> bool,
+    ) -> Vec<SocketAddr> {
+        if count == 0 {
+            return Vec::new();
+        }
+        let mut picked: Vec<_> = self
+            .candidates
+            .iter()
+            .filter(|(addr, c)| c.is_connectable(now) && is_allowed(addr))
+            .map(|(addr, c)| (*addr, c.rank(is_local(addr))))
+            .collect();
+        picked.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+        picked.truncate(count);
+        picked
+            .into_iter()
+            .map(|(addr, _)| {
+                if let Some(candidate) = self.candidates.get_mut(&addr) {
+                    candidate.is_connected = true;
+                }
+                addr
+            })
+            .collect()
+    }
+
+    /// Records that the connection to the peer was established.
+    pub fn handle_connected(&mut self, addr: &SocketAddr) {
+        if let Some(candidate) = self.candidates.get_mut(addr) {
+            candidate.failure_count = 0;
+        }
+    }
+
+    /// Records that the connection to the peer was closed, or that it could
+    /// not be established if `was_connected` is not set.
+    ///
+    /// A peer that we fail to connect to is retried with a growing backoff,
+    /// and dropped after a few attempts. A peer we were connected to may be
+    /// reconnected to after a while.
+    pub fn handle_disconnected(
+        &mut self,
+        addr: &SocketAddr,
+        was_connected: bool,
+        now: Instant,
+    ) {
+        let candidate = match self.candidates.get_mut(addr) {
+            Some(candidate) => candidate,
+            None => return,
+        };
+        candidate.is_connected = false;
+        if was_connected {
+            candidate.next_attempt_time = Some(now + RECONNECT_DELAY);
+            return;
+        }
+        candidate.failure_count += 1;
+        if candidate.failure_count >= MAX_FAILURE_COUNT {
+            log::debug!("Dropping unreachable peer {}", addr);
+            self.candidates.remove(addr);
+        } else {
+            let backoff = RETRY_BACKOFF * 2u32.pow(candidate.failure_count - 1);
+            candidate.next_attempt_time = Some(now + backoff);
+        }
+    }
+
+    /// Forgets all peers with the IP address, e.g. because it was banned.
+    pub fn remove_ip(&mut self, ip: IpAddr) {
+        self.candidates.retain(|addr, _| addr.ip() != ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn should_merge_sources() {
+        let mut sources = PeerSources::default();
+        assert_eq!(sources.add(vec![addr(1), addr(2)], PeerSource::Tracker), 2);
+        assert_eq!(sources.add(vec![addr(2), addr(3)], PeerSource::Dht), 1);
+        // the same source returning a peer again doesn't count twice
+        assert_eq!(sources.add(vec![addr(2)], PeerSource::Dht), 0);
+
+        let candidate = &sources.candidates[&addr(2)];
+        assert_eq!(candidate.source, PeerSource::Tracker);
+        assert_eq!(candidate.source_mask.count_ones(), 2);
+        assert_eq!(sources.connectable_count(Instant::now()), 3);
+    }
+
+    #[test]
+    fn should_pick_best_candidates() {
+        let mut sources = PeerSources::default();
+        let now = Instant::now();
+        sources.add(vec![addr(1)], PeerSource::Dht);
+        sources.add(vec![addr(2)], PeerSource::Tracker);
+        sources.add(vec![addr(3)], PeerSource::Dht);
+        sources.add(vec![addr(3)], PeerSource::Lsd);
+        sources.add(vec![addr(4)], PeerSource::Manual);
+
+        assert_eq!(
+            sources.pick(3, now, |a| *a != addr(4), |_| false),
+            vec![addr(3), addr(2), addr(1)]
+        );
+        // picked peers are not picked again while connected
+        assert_eq!(sources.pick(3, now, |_| true, |_| false), vec![addr(4)]);
+        assert_eq!(sources.connectable_count(now), 0);
+    }
+
+    #[test]
+    fn should_prefer_local_peers() {
+        let mut sources = PeerSources::default();
+        let now = Instant::now();
+        sources.add(vec![addr(1)], PeerSource::Manual);
+        sources.add(vec![addr(2)], PeerSource::Dht);
+        sources.add(vec![addr(3)], PeerSource::Tracker);
+        sources.add(vec![addr(3)], PeerSource::Lsd);
+        assert!(sources.is_lsd(&addr(3)));
+        assert!(!sources.is_lsd(&addr(1)));
+
+        assert_eq!(
+            sources.pick(3, now, |_| true, |a| *a == addr(2)),
+            vec![addr(3), addr(2), addr(1)]
+        );
+    }
+
+    #[test]
+    fn should_back_off_and_drop_unreachable_peers() {
+        let mut sources = PeerSources::default();
+        let now = Instant::now();
+        sources.add(vec![addr(1), addr(2)], PeerSource::Tracker);
+        assert_eq!(sources.pick(2, now, |_| true, |_| false).len(), 2);
+
+        // a peer we were connected to is retried after a delay
+        sources.handle_connected(&addr(1));
+        sources.handle_disconnected(&addr(1), true, now);
+        assert!(sources
+            .pick(1, now, |a| *a == addr(1), |_| false)
+            .is_empty());
+        let later = now + RECONNECT_DELAY;
+        assert_eq!(
+            sources.pick(1, later, |a| *a == addr(1), |_| false),
+            vec![addr(1)]
+        );
+
+        // a peer we can't connect to is retried with a growing backoff and
+        // is then dropped
+        let mut now = now;
+        for failure_count in 1..MAX_FAILURE_COUNT {
+            sources.handle_disconnected(&addr(2), false, now);
+            let backoff = RETRY_BACKOFF * 2u32.pow(failure_count - 1);
+            assert_eq!(sources.connectable_count(now + backoff), 1);
+            now += backoff;
+            assert_eq!(
+                sources.pick(1, now, |_| true, |_| false),
+                vec![addr(2)]
+            );
+        }
+        sources.handle_disconnected(&addr(2), false, now);
+        assert!(!sources.candidates.contains_key(&addr(2)));
+    }
+
+    #[test]
+    fn should_remove_ip() {
+        let mut sources = PeerSources::default();
+        sources.add(vec![addr(1), addr(2)], PeerSource::Tracker);
+        sources.add(
+            vec![SocketAddr::from(([10, 0, 0, 2], 1))],
+            PeerSource::Tracker,
+        );
+        sources.remove_ip(addr(1).ip());
+        assert_eq!(sources.connectable_count(Instant::now()), 1);
+    }
+}
@@ -0,0 +1,140 @@
+//! Checks whether a seeding torrent reached one of its seed goals, after which
+//! it's stopped.
+//!
+//! Only the time the torrent spends seeding counts towards the seed time and
+//! idle time goals, so the time it was downloading, paused, or queued doesn't.
+//! Once a goal is reached it's reported once per run: a torrent resumed after
+//! being paused for reaching a goal keeps seeding until it's stopped
+//! otherwise.
+
+use std::time::Duration;
+
+use crate::conf::{SeedGoal, SeedGoalConf};
+
+/// The progress of a seeding torrent towards its seed goals.
+#[derive(Debug, Default)]
+pub(crate) struct SeedGoalCheck {
+    /// How long the torrent has been seeding.
+    seed_duration: Duration,
+    /// How long the torrent has been seeding without uploading anything.
+    idle_duration: Duration,
+    /// Set once a goal was reached, so that it's only reported once.
+    is_reached: bool,
+}
+
+impl SeedGoalCheck {
+    /// Records a tick of a seeding torrent, and returns the goal that was
+    /// reached with it, if any.
+    ///
+    /// `uploaded` is the number of payload bytes uploaded in the tick, while
+    /// `total_uploaded` and `total_downloaded` are the totals of the torrent.
+    /// The ratio is computed against at least the size of the torrent, so
+    /// that a torrent added as a seed doesn't reach its ratio by uploading
+    /// a few bytes.
+    pub fn tick(
+        &mut self,
+        conf: &SeedGoalConf,
+        elapsed: Duration,
+        uploaded: u64,
+        total_uploaded: u64,
+        total_downloaded: u64,
+        download_len: u64,
+    ) -> Option<SeedGoal> {
+        self.seed_duration += elapsed;
+        if uploaded > 0 {
+            self.idle_duration = Duration::default();
+        } else {
+            self.idle_duration += elapsed;
+        }
+        if self.is_reached {
+            return None;
+        }
+
+        let ratio = total_uploaded as f64
+            / total_downloaded.max(download_len).max(1) as f64;
+        let goal = if conf.ratio.map_or(false, |goal| ratio >= goal) {
+            SeedGoal::Ratio
+        } else if conf
+            .seed_time
+            .map_or(false, |goal| self.seed_duration >= goal)
+        {
+            SeedGoal::SeedTime
+        } else if conf
+            .idle_time
+            .map_or(false, |goal| self.idle_duration >= goal)
+        {
+            SeedGoal::IdleTime
+        } else {
+            return None;
+        };
+        self.is_reached = true;
+        Some(goal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf::SeedGoalAction;
+
+    const TICK: Duration = Duration::from_secs(1);
+
+    fn conf() -> SeedGoalConf {
+        SeedGoalConf {
+            ratio: None,
+            seed_time: None,
+            idle_time: None,
+            action: SeedGoalAction::Pause,
+        }
+    }
+
+    #[test]
+    fn should_reach_ratio() {
+        let conf = SeedGoalConf {
+            ratio: Some(2.0),
+            ..conf()
+        };
+        let mut check = SeedGoalCheck::default();
+        // the ratio is computed against the torrent's size if we downloaded
+        // less than that
+        assert_eq!(check.tick(&conf, TICK, 100, 100, 0, 100), None);
+        assert_eq!(
+            check.tick(&conf, TICK, 100, 200, 0, 100),
+            Some(SeedGoal::Ratio)
+        );
+        // the goal is only reported once
+        assert_eq!(check.tick(&conf, TICK, 100, 300, 0, 100), None);
+    }
+
+    #[test]
+    fn should_reach_seed_time() {
+        let conf = SeedGoalConf {
+            seed_time: Some(3 * TICK),
+            ..conf()
+        };
+        let mut check = SeedGoalCheck::default();
+        assert_eq!(check.tick(&conf, TICK, 10, 10, 0, 100), None);
+        assert_eq!(check.tick(&conf, TICK, 10, 20, 0, 100), None);
+        assert_eq!(
+            check.tick(&conf, TICK, 10, 30, 0, 100),
+            Some(SeedGoal::SeedTime)
+        );
+    }
+
+    #[test]
+    fn should_reach_idle_time() {
+        let conf = SeedGoalConf {
+            idle_time: Some(2 * TICK),
+            ..conf()
+        };
+        let mut check = SeedGoalCheck::default();
+        assert_eq!(check.tick(&conf, TICK, 0, 0, 0, 100), None);
+        // uploading resets the idle time
+        assert_eq!(check.tick(&conf, TICK, 10, 10, 0, 100), None);
+        assert_eq!(check.tick(&conf, TICK, 0, 10, 0, 100), None);
+        assert_eq!(
+            check.tick(&conf, TICK, 0, 10, 0, 100),
+            Some(SeedGoal::IdleTime)
+        );
+    }
+}
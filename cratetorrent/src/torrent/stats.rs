@@ -1,8 +1,11 @@
 use std::{
     net::SocketAddr,
+    ops::AddAssign,
     time::{Duration, Instant},
 };
 
+use reqwest::Url;
+
 use crate::{
     counter::{ChannelCounter, Counter, ThruputCounters},
     PeerId, PieceIndex,
@@ -11,17 +14,58 @@ use crate::{
 pub use crate::peer::{ConnectionState, SessionState};
 
 /// Aggregated statistics of a torrent.
+///
+/// With the `serde` feature, the stats and the types they are made of
+/// can be serialized, apart from the points in time ([`Instant`]s), which only
+/// have meaning within the process. These are skipped, and are unset in
+/// deserialized stats.
 #[derive(Clone, Debug, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 pub struct TorrentStats {
     /// When the torrent was _first_ started.
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub start_time: Option<Instant>,
 
-    /// How long the torrent has been running.
+    /// How long the torrent has been running, not counting the time it was
+    /// paused or queued.
     pub run_duration: Duration,
 
+    /// Whether the torrent is paused.
+    pub is_paused: bool,
+
+    /// Whether the torrent is waiting in the queue for a slot. A torrent may
+    /// be both paused and queued, in which case it must be resumed and given
+    /// a slot to start again.
+    pub is_queued: bool,
+
+    /// Whether the torrent is stopped by an error accessing its files, until
+    /// it's retried.
+    pub is_errored: bool,
+
     /// Aggregate statistics about a torrent's pieces.
     pub pieces: PieceStats,
 
+    /// The number of complete copies of the torrent available among the
+    /// connected peers, not counting ourselves.
+    ///
+    /// The integer part is the availability of the rarest piece(s), while the
+    /// fractional part is the fraction of pieces that are more available than
+    /// that. For instance, 1.25 means that every piece is available from at
+    /// least one peer and a quarter of the pieces from at least two. Below
+    /// 1.0 some pieces can't be downloaded from the connected peers.
+    pub distributed_copies: f64,
+
+    /// The size of the swarm as reported by the torrent's trackers.
+    pub swarm: SwarmStats,
+
+    /// Whether peers on the internet can connect to the torrent's listen
+    /// port.
+    pub connectability: Connectability,
+
     /// The peers of the torrent.
     ///
     /// By default, only the number of connected peers are sent with each
@@ -31,12 +75,138 @@ pub struct TorrentStats {
     /// with aggregate statistics is sent with each tick.
     pub peers: Peers,
 
+    /// The number of peers we know of from all peer sources, including those
+    /// that we are not connected to.
+    pub known_peer_count: usize,
+
     /// Various thruput statistics of the torrent.
     pub thruput: ThruputStats,
+
+    /// The number and length of the messages exchanged with all peers of the
+    /// torrent, by message type.
+    pub messages: MessageStats,
+}
+
+impl TorrentStats {
+    /// Returns what the torrent is doing.
+    pub fn status(&self) -> Status {
+        if self.is_paused {
+            Status::Paused
+        } else if self.is_queued {
+            Status::Queued
+        } else if self.pieces.selected_complete_len < self.pieces.selected_len {
+            Status::Downloading
+        } else {
+            Status::Seeding
+        }
+    }
+
+    /// Returns the completion of the download of the selected files, between
+    /// 0.0 and 1.0.
+    pub fn progress(&self) -> f64 {
+        if self.pieces.selected_len == 0 {
+            1.0
+        } else {
+            self.pieces.selected_complete_len as f64
+                / self.pieces.selected_len as f64
+        }
+    }
+
+    /// Returns the estimated time until the selected files are downloaded, at
+    /// the current (smoothed) download rate.
+    ///
+    /// If nothing is being downloaded, the time can't be estimated and `None`
+    /// is returned.
+    pub fn eta(&self) -> Option<Duration> {
+        let left = self
+            .pieces
+            .selected_len
+            .saturating_sub(self.pieces.selected_complete_len);
+        if left == 0 {
+            return Some(Duration::from_secs(0));
+        }
+        let rate = self.thruput.payload.down.rate;
+        if rate == 0 {
+            return None;
+        }
+        Some(Duration::from_secs((left + rate - 1) / rate))
+    }
+}
+
+/// What a torrent is doing, as returned by [`TorrentStats::status`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub enum Status {
+    /// The torrent is paused by the user.
+    Paused,
+    /// The torrent is waiting in the queue for a slot.
+    Queued,
+    /// The torrent is downloading the selected files.
+    Downloading,
+    /// The selected files are downloaded and the torrent only uploads.
+    Seeding,
+}
+
+/// Whether peers can connect to a torrent's listen port.
+///
+/// If not, we are said to be firewalled: only outbound connections can be
+/// made, so we can't connect to other firewalled peers at all.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub enum Connectability {
+    /// The port has not been checked yet, e.g. because our external address
+    /// is not known.
+    Unknown,
+    /// A peer connected to us from the internet, or we could connect to our
+    /// own port through our external address.
+    Connectable,
+    /// We could not connect to our own port through our external address, and
+    /// no peer has connected to us from the internet.
+    Firewalled,
+}
+
+impl Default for Connectability {
+    fn default() -> Self {
+        Self::Unknown
+    }
+}
+
+/// The size of a torrent's swarm, aggregated from the announce responses of
+/// its trackers.
+///
+/// As the trackers of a torrent largely track the same peers, the counts are
+/// the highest ones reported by any tracker rather than their sum.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct SwarmStats {
+    /// The number of seeders, if any tracker reported it.
+    pub seeder_count: Option<usize>,
+    /// The number of leechers, if any tracker reported it.
+    pub leecher_count: Option<usize>,
+    /// The time of the last successful announce to any tracker.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub last_announce_time: Option<Instant>,
 }
 
 /// Statistics of a torrent's pieces.
 #[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 pub struct PieceStats {
     /// The total number of pieces in torrent.
     pub total: usize,
@@ -44,6 +214,16 @@ pub struct PieceStats {
     pub pending: usize,
     /// The number of pieces that the torrent has downloaded.
     pub complete: usize,
+    /// The total length of the pieces that are downloaded, i.e. those
+    /// that intersect a file that is not skipped, in bytes.
+    ///
+    /// This is the length of the whole torrent unless some files are skipped.
+    pub selected_len: u64,
+    /// The length of the selected pieces that have been downloaded, in bytes.
+    ///
+    /// Together with `selected_len` this gives the completion of the
+    /// download of the selected files.
+    pub selected_complete_len: u64,
     /// The pieces that were completed since the last tick.
     ///
     /// By default this information is not sent, as it has some overhead. It
@@ -65,8 +245,111 @@ impl PieceStats {
     }
 }
 
+/// The download state of a single piece.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub enum PieceState {
+    /// No part of the piece has been downloaded or requested.
+    Missing,
+    /// Some blocks of the piece have been downloaded, but none are being
+    /// requested at the moment.
+    Partial,
+    /// Blocks of the piece are being requested from peers.
+    Downloading,
+    /// The piece has been downloaded and verified.
+    Complete,
+}
+
+/// The state and availability of a single piece, as returned by
+/// [`EngineHandle::query_pieces`](crate::engine::EngineHandle::query_pieces).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct PieceInfo {
+    /// The download state of the piece.
+    pub state: PieceState,
+    /// The number of connected peers that have the piece.
+    pub availability: u32,
+}
+
+/// Whether the last announce to a tracker succeeded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub enum TrackerStatus {
+    /// The tracker has not been announced to yet, e.g. because a tracker
+    /// before it in its tier is working.
+    NotContacted,
+    /// The last announce to the tracker succeeded.
+    Working,
+    /// The last announce to the tracker failed.
+    Failed,
+}
+
+/// The status of a single tracker, as returned by
+/// [`EngineHandle::query_trackers`](crate::engine::EngineHandle::query_trackers).
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct TrackerInfo {
+    /// The announce URL of the tracker.
+    pub url: Url,
+    /// The index of the tracker's tier. Lower tiers are preferred.
+    pub tier: usize,
+    /// Whether the last announce to the tracker succeeded.
+    pub status: TrackerStatus,
+    /// The error or warning message of the last announce, if any.
+    pub message: Option<String>,
+    /// The number of seeders in the last successful announce response, if
+    /// the tracker reported it.
+    pub seeder_count: Option<usize>,
+    /// The number of leechers in the last successful announce response, if
+    /// the tracker reported it.
+    pub leecher_count: Option<usize>,
+    /// The time of the last successful announce.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub last_success_time: Option<Instant>,
+    /// The number of consecutive failed announces.
+    pub error_count: usize,
+    /// If the tracker is failing, the time before which it is not retried.
+    /// The wait is doubled with each consecutive failure.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub next_retry_time: Option<Instant>,
+    /// The total number of announces made to the tracker, including failed
+    /// ones.
+    pub announce_count: usize,
+    /// The total number of failed announces.
+    pub failure_count: usize,
+    /// The error of the last failed announce, even if the tracker has worked
+    /// since.
+    pub last_error: Option<String>,
+    /// The average time it took the tracker to respond to an announce, or
+    /// for the announce to fail.
+    pub average_response_time: Option<Duration>,
+    /// The total number of peers the tracker returned.
+    pub received_peer_count: usize,
+}
+
 /// Limited or full information of a torrent's peer sessions.
 #[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 pub enum Peers {
     /// The number of connected peers.
     Count(usize),
@@ -97,29 +380,46 @@ impl Default for Peers {
 
 /// Aggregate statistics of a peer session.
 #[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 pub struct PeerSessionStats {
     /// The IP-port pair of the peer.
     pub addr: SocketAddr,
     /// Peer's 20 byte BitTorrent id. Updated when the peer sends us its peer
     /// id, in the handshake.
     pub id: Option<PeerId>,
+    /// Whether the peer is on our local network.
+    pub is_local: bool,
     /// The current state of the session.
     pub state: SessionState,
     /// The number of pieces the peer has.
     pub piece_count: usize,
     /// Various thruput statistics of ths peer.
     pub thruput: ThruputStats,
+    /// The number and length of the messages exchanged with this peer, by
+    /// message type.
+    pub messages: MessageStats,
 }
 
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 pub struct ThruputStats {
     /// Statistics about the protocol transfer rates in both directions.
     pub protocol: Channel,
     /// Statistics about the payload transfer rates in both directions.
     pub payload: Channel,
     /// If a peer has sent us data that we already have, it is recorded here as
-    /// waste.
+    /// waste. This is the waste of the last round.
     pub waste: u64,
+    /// The total number of bytes wasted.
+    pub total_waste: u64,
 }
 
 impl From<&ThruputCounters> for ThruputStats {
@@ -128,6 +428,7 @@ impl From<&ThruputCounters> for ThruputStats {
             protocol: Channel::from(&c.protocol),
             payload: Channel::from(&c.payload),
             waste: c.waste.round(),
+            total_waste: c.waste.total(),
         }
     }
 }
@@ -135,6 +436,11 @@ impl From<&ThruputCounters> for ThruputStats {
 /// Aggregate statistics about a communication channel, e.g. protocol chatter
 /// or exchanged payload.
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 pub struct Channel {
     pub down: Thruput,
     pub up: Thruput,
@@ -151,6 +457,11 @@ impl From<&ChannelCounter> for Channel {
 
 /// Statistics of a torrent's current thruput.
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 pub struct Thruput {
     pub total: u64,
     pub rate: u64,
@@ -166,3 +477,193 @@ impl From<&Counter> for Thruput {
         }
     }
 }
+
+/// Statistics about the messages exchanged with peers, in both directions.
+///
+/// This is useful for debugging and tuning the protocol, e.g. to see how much
+/// of the protocol chatter is made up of `have` or `request` messages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct MessageStats {
+    /// The messages received from peers.
+    pub down: MessageTypeStats,
+    /// The messages sent to peers.
+    pub up: MessageTypeStats,
+}
+
+impl AddAssign<&MessageStats> for MessageStats {
+    fn add_assign(&mut self, rhs: &MessageStats) {
+        self.down += &rhs.down;
+        self.up += &rhs.up;
+    }
+}
+
+/// The number and length of messages, by message type, in a single direction.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct MessageTypeStats {
+    pub keep_alive: MessageCount,
+    pub bitfield: MessageCount,
+    pub choke: MessageCount,
+    pub unchoke: MessageCount,
+    pub interested: MessageCount,
+    pub not_interested: MessageCount,
+    pub have: MessageCount,
+    pub request: MessageCount,
+    pub block: MessageCount,
+    pub cancel: MessageCount,
+    // the below messages are part of the Fast extension
+    pub suggest_piece: MessageCount,
+    pub have_all: MessageCount,
+    pub have_none: MessageCount,
+    pub reject_request: MessageCount,
+    pub allowed_fast: MessageCount,
+    /// The messages of the extension protocol, of all extensions.
+    pub extended: MessageCount,
+}
+
+impl MessageTypeStats {
+    /// Returns the sum of all message types.
+    pub fn total(&self) -> MessageCount {
+        let mut total = MessageCount::default();
+        for count in self.counts().iter() {
+            total += count;
+        }
+        total
+    }
+
+    /// Returns the counts of all message types, in the order of their
+    /// declaration.
+    fn counts(&self) -> [&MessageCount; 16] {
+        [
+            &self.keep_alive,
+            &self.bitfield,
+            &self.choke,
+            &self.unchoke,
+            &self.interested,
+            &self.not_interested,
+            &self.have,
+            &self.request,
+            &self.block,
+            &self.cancel,
+            &self.suggest_piece,
+            &self.have_all,
+            &self.have_none,
+            &self.reject_request,
+            &self.allowed_fast,
+            &self.extended,
+        ]
+    }
+}
+
+impl AddAssign<&MessageTypeStats> for MessageTypeStats {
+    fn add_assign(&mut self, rhs: &MessageTypeStats) {
+        self.keep_alive += &rhs.keep_alive;
+        self.bitfield += &rhs.bitfield;
+        self.choke += &rhs.choke;
+        self.unchoke += &rhs.unchoke;
+        self.interested += &rhs.interested;
+        self.not_interested += &rhs.not_interested;
+        self.have += &rhs.have;
+        self.request += &rhs.request;
+        self.block += &rhs.block;
+        self.cancel += &rhs.cancel;
+        self.suggest_piece += &rhs.suggest_piece;
+        self.have_all += &rhs.have_all;
+        self.have_none += &rhs.have_none;
+        self.reject_request += &rhs.reject_request;
+        self.allowed_fast += &rhs.allowed_fast;
+        self.extended += &rhs.extended;
+    }
+}
+
+/// The number of messages of some type and their total length on the wire,
+/// including the message header.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct MessageCount {
+    /// The number of messages.
+    pub count: u64,
+    /// The total number of bytes of these messages.
+    pub bytes: u64,
+}
+
+impl MessageCount {
+    /// Records a single message of the given length.
+    pub(crate) fn record(&mut self, len: u64) {
+        self.count += 1;
+        self.bytes += len;
+    }
+}
+
+impl AddAssign<&MessageCount> for MessageCount {
+    fn add_assign(&mut self, rhs: &MessageCount) {
+        self.count += rhs.count;
+        self.bytes += rhs.bytes;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(selected_len: u64, complete_len: u64, rate: u64) -> TorrentStats {
+        TorrentStats {
+            pieces: PieceStats {
+                selected_len,
+                selected_complete_len: complete_len,
+                ..Default::default()
+            },
+            thruput: ThruputStats {
+                payload: Channel {
+                    down: Thruput {
+                        rate,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn should_estimate_eta() {
+        assert_eq!(stats(100, 40, 20).eta(), Some(Duration::from_secs(3)));
+        // partial seconds are rounded up
+        assert_eq!(stats(100, 40, 50).eta(), Some(Duration::from_secs(2)));
+        // nothing is left
+        assert_eq!(stats(100, 100, 0).eta(), Some(Duration::from_secs(0)));
+        // nothing is being downloaded
+        assert_eq!(stats(100, 40, 0).eta(), None);
+    }
+
+    #[test]
+    fn should_return_progress_and_status() {
+        let mut stats = stats(100, 25, 0);
+        assert_eq!(stats.progress(), 0.25);
+        assert_eq!(stats.status(), Status::Downloading);
+
+        stats.pieces.selected_complete_len = 100;
+        assert_eq!(stats.progress(), 1.0);
+        assert_eq!(stats.status(), Status::Seeding);
+
+        stats.is_queued = true;
+        assert_eq!(stats.status(), Status::Queued);
+        stats.is_paused = true;
+        assert_eq!(stats.status(), Status::Paused);
+    }
+}
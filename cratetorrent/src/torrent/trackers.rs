@@ -0,0 +1,929 @@
+//! The trackers of a torrent and the scheduling of announces to them.
+//!
+//! Trackers are organized in tiers, as defined in
+//! [BEP 12](https://www.bittorrent.org/beps/bep_0012.html). The trackers
+//! within a tier are shuffled when the torrent is created, so that load is
+//! spread among them. A regular announce goes to the first usable tracker in
+//! tier order, and if that fails, it fails over to the next trackers of the
+//! tier, and then of the next tiers, until one responds, which is then moved
+//! to the front of its tier. Alternatively, each tracker may be announced to
+//! on its own schedule, regardless of its tier.
+//!
+//! This module only decides which trackers to announce to. The torrent makes
+//! the announces on their own tasks, so as not to hold up its event loop,
+//! and reports their results back.
+
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use rand::seq::SliceRandom;
+use reqwest::Url;
+use tokio::sync::Mutex;
+
+use crate::{
+    rt,
+    tracker::{Announce, Event, Response, Tracker, TrackerError},
+};
+
+use super::stats::TrackerStatus;
+
+/// The trackers of a torrent, in tiers.
+pub(crate) struct Trackers {
+    /// The tiers of trackers, in order of preference.
+    ///
+    /// Within a tier, the tracker that last responded is at the front.
+    tiers: Vec<Vec<TrackerEntry>>,
+    /// The regular announce in progress, if any.
+    failover: Option<Failover>,
+    /// The started or completed event that no tracker has received yet. It is
+    /// sent with subsequent announces until one succeeds, so that trackers'
+    /// statistics remain accurate.
+    pub pending_event: Option<Event>,
+}
+
+/// A regular announce in progress, which fails over to the next usable
+/// tracker if the one announced to fails.
+struct Failover {
+    /// The tracker being announced to.
+    url: Url,
+    event: Option<Event>,
+    /// Whether the announce was forced, in which case trackers are tried even
+    /// if the backoff following their last failure hasn't elapsed.
+    force: bool,
+}
+
+/// An announce to be made to a tracker.
+pub(crate) struct AnnounceTarget {
+    pub url: Url,
+    client: Arc<Mutex<Tracker>>,
+    /// The tracker id the tracker gave us, if any.
+    tracker_id: Option<String>,
+    pub event: Option<Event>,
+}
+
+/// The result of an announce, and how long the tracker took to respond.
+pub(crate) type AnnounceResult = (Result<Response, TrackerError>, Duration);
+
+impl AnnounceTarget {
+    /// Announces to the tracker with the parameters, which are completed with
+    /// the tracker id and the event.
+    pub async fn announce(self, mut params: Announce) -> AnnounceResult {
+        params.tracker_id = self.tracker_id;
+        params.event = self.event;
+        // an earlier announce to the tracker may still be in progress, e.g.
+        // when telling it that we're stopping
+        let mut client = self.client.lock().await;
+        let start = Instant::now();
+        let result = client.announce(params).await;
+        (result, start.elapsed())
+    }
+}
+
+/// Tells the trackers that we're stopping, but waits for their responses at
+/// most for the timeout, so that unresponsive trackers don't hold up the
+/// torrent.
+pub(crate) async fn announce_stop(
+    targets: Vec<AnnounceTarget>,
+    params: Announce,
+    timeout: Duration,
+) {
+    let mut announces: FuturesUnordered<_> = targets
+        .into_iter()
+        .map(|target| {
+            let params = params.clone();
+            async move {
+                let url = target.url.clone();
+                (url, target.announce(params).await)
+            }
+        })
+        .collect();
+    let announce = async {
+        while let Some((url, (result, _))) = announces.next().await {
+            if let Err(e) = result {
+                log::warn!("Error announcing stop to tracker {}: {}", url, e);
+            }
+        }
+    };
+    if rt::timeout(timeout, announce).await.is_err() {
+        log::warn!("Timed out announcing stop to trackers");
+    }
+}
+
+impl Trackers {
+    /// Creates the tiers of trackers, shuffling the trackers within each.
+    pub fn new(tiers: Vec<Vec<Tracker>>) -> Self {
+        let tiers = tiers
+            .into_iter()
+            .map(|tier| {
+                let mut tier: Vec<_> =
+                    tier.into_iter().map(TrackerEntry::new).collect();
+                tier.shuffle(&mut rand::thread_rng());
+                tier
+            })
+            .collect();
+        Self::from_tiers(tiers)
+    }
+
+    fn from_tiers(tiers: Vec<Vec<TrackerEntry>>) -> Self {
+        Self {
+            tiers,
+            failover: None,
+            pending_event: None,
+        }
+    }
+
+    /// Returns the tiers of trackers.
+    pub fn tiers(&self) -> &[Vec<TrackerEntry>] {
+        &self.tiers
+    }
+
+    /// Returns all trackers, in tier order.
+    pub fn iter(&self) -> impl Iterator<Item = &TrackerEntry> + Clone {
+        self.tiers.iter().flatten()
+    }
+
+    /// Returns all trackers, in tier order.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut TrackerEntry> {
+        self.tiers.iter_mut().flatten()
+    }
+
+    /// Returns the tracker with the URL, if the torrent has it.
+    pub fn get_mut(&mut self, url: &Url) -> Option<&mut TrackerEntry> {
+        self.iter_mut().find(|tracker| &tracker.url == url)
+    }
+
+    /// Adds the tracker at the end of the tier with the given index, or in
+    /// a new last tier if there is no such tier.
+    ///
+    /// Returns false if the torrent already has the tracker.
+    pub fn add(&mut self, tracker: Tracker, tier: usize) -> bool {
+        if self.iter().any(|entry| &entry.url == tracker.url()) {
+            return false;
+        }
+        let entry = TrackerEntry::new(tracker);
+        match self.tiers.get_mut(tier) {
+            Some(tier) => tier.push(entry),
+            None => self.tiers.push(vec![entry]),
+        }
+        true
+    }
+
+    /// Removes the tracker with the URL, if the torrent has it.
+    ///
+    /// If a regular announce to the tracker is in progress, it doesn't fail
+    /// over to other trackers anymore.
+    pub fn remove(&mut self, url: &Url) -> Option<TrackerEntry> {
+        let (tier_index, pos) = self.position(url)?;
+        let removed = self.tiers[tier_index].remove(pos);
+        self.tiers.retain(|tier| !tier.is_empty());
+        if self.failover.as_ref().map(|f| &f.url) == Some(url) {
+            self.failover = None;
+        }
+        Some(removed)
+    }
+
+    /// Returns the trackers to announce to with the event, which are marked
+    /// as being announced to.
+    ///
+    /// A tracker is usable if it's not being announced to, and the backoff
+    /// following its last failure has elapsed or `force` is set. If
+    /// `announce_to_all` is set, all usable trackers that are due, as decided
+    /// by `is_due`, are returned. Otherwise, the first usable tracker in tier
+    /// order decides whether a regular announce is due, and is returned if
+    /// so. A regular announce then fails over to the next trackers
+    /// regardless of whether they are due, see [`Self::handle_result`].
+    /// While a regular announce is in progress, no other one is started.
+    pub fn pick(
+        &mut self,
+        now: Instant,
+        event: Option<Event>,
+        force: bool,
+        announce_to_all: bool,
+        is_due: impl Fn(&TrackerEntry) -> bool,
+    ) -> Vec<AnnounceTarget> {
+        let is_usable = |tracker: &TrackerEntry| {
+            !tracker.is_announcing && (force || tracker.can_retry(now))
+        };
+        if announce_to_all {
+            return self
+                .iter_mut()
+                .filter(|tracker| is_usable(tracker) && is_due(tracker))
+                .map(|tracker| tracker.start_announce(event))
+                .collect();
+        }
+
+        if self.failover.is_some() {
+            return Vec::new();
+        }
+        let tracker = match self.iter_mut().find(|tracker| is_usable(tracker)) {
+            Some(tracker) => tracker,
+            None => return Vec::new(),
+        };
+        if !is_due(tracker) {
+            return Vec::new();
+        }
+        let target = tracker.start_announce(event);
+        self.failover = Some(Failover {
+            url: target.url.clone(),
+            event,
+            force,
+        });
+        vec![target]
+    }
+
+    /// Handles the end of the announce with the event to the tracker with the
+    /// URL, after the tracker's state was updated from its result.
+    ///
+    /// If the announce delivered the pending event, it's cleared. If it was
+    /// a regular announce that succeeded, the tracker is moved to the front
+    /// of its tier, so that it's tried first next time. If it failed, the
+    /// next usable tracker of the tier, or else of the next tiers, is
+    /// returned, to which the announce fails over.
+    pub fn handle_result(
+        &mut self,
+        url: &Url,
+        event: Option<Event>,
+        is_success: bool,
+        now: Instant,
+    ) -> Option<AnnounceTarget> {
+        if is_success && event.is_some() && self.pending_event == event {
+            self.pending_event = None;
+        }
+
+        if self.failover.as_ref().map(|f| &f.url) != Some(url) {
+            return None;
+        }
+        let failover = self.failover.take()?;
+        let (tier_index, pos) = self.position(url)?;
+        if is_success {
+            self.tiers[tier_index][..=pos].rotate_right(1);
+            return None;
+        }
+
+        let (tiers, next_tiers) = self.tiers.split_at_mut(tier_index + 1);
+        let next = tiers[tier_index][pos + 1..]
+            .iter_mut()
+            .chain(next_tiers.iter_mut().flatten())
+            .find(|tracker| {
+                !tracker.is_announcing
+                    && (failover.force || tracker.can_retry(now))
+            });
+        let next = match next {
+            Some(next) => next,
+            None => {
+                log::debug!("No tracker responded to announce");
+                return None;
+            }
+        };
+        log::debug!("Failing over from tracker {} to {}", url, next.url);
+        let target = next.start_announce(failover.event);
+        self.failover = Some(Failover {
+            url: target.url.clone(),
+            ..failover
+        });
+        Some(target)
+    }
+
+    /// Returns the trackers to tell that we're stopping, which are those that
+    /// we successfully announced to.
+    ///
+    /// The regular announce in progress, if any, stops failing over, and the
+    /// pending event is moot at this point.
+    pub fn stop(&mut self) -> Vec<AnnounceTarget> {
+        self.failover = None;
+        self.pending_event = None;
+        self.iter()
+            .filter(|tracker| tracker.last_success_time.is_some())
+            .map(|tracker| tracker.target(Some(Event::Stopped)))
+            .collect()
+    }
+
+    /// Returns the tier index and position within the tier of the tracker
+    /// with the URL.
+    fn position(&self, url: &Url) -> Option<(usize, usize)> {
+        self.tiers
+            .iter()
+            .enumerate()
+            .find_map(|(tier_index, tier)| {
+                tier.iter()
+                    .position(|tracker| &tracker.url == url)
+                    .map(|pos| (tier_index, pos))
+            })
+    }
+}
+
+/// Contains the tracker client as well as additional metadata about the
+/// tracker.
+pub(crate) struct TrackerEntry {
+    /// The URL of the tracker, by which it's identified.
+    pub url: Url,
+    /// The client, which is shared with the task of the announce in progress,
+    /// if any.
+    client: Arc<Mutex<Tracker>>,
+    /// If a previous announce contained a tracker_id, it should be included in
+    /// next announces. Therefore it is cached here.
+    pub id: Option<String>,
+    /// The last announce time is kept here so that we don't request too often.
+    pub last_announce_time: Option<Instant>,
+    /// The interval at which we should update the tracker of our progress.
+    /// This is set after the first announce request.
+    pub interval: Option<Duration>,
+    /// The absolute minimum interval at which we can contact tracker.
+    /// This is set after the first announce request.
+    pub min_interval: Option<Duration>,
+    /// The fraction of the announce interval by which the next regular
+    /// announce is delayed. It is picked randomly after each announce so that
+    /// torrents started at the same time don't announce at the same time.
+    pub jitter: f64,
+    /// The number of consecutive failed announces, from which the backoff
+    /// before the next retry is derived. It is reset on success.
+    pub error_count: usize,
+    /// If the last announce failed, we don't retry the tracker before this
+    /// time.
+    pub next_retry_time: Option<Instant>,
+    /// The number of consecutive successful announces that yielded no new
+    /// peers. While the swarm is thin, the interval of the announces we make
+    /// to get more peers is doubled with each such announce.
+    pub fruitless_announce_count: u32,
+    /// Whether the last announce succeeded.
+    pub status: TrackerStatus,
+    /// The error or warning message of the last announce, if any.
+    pub message: Option<String>,
+    /// The swarm size reported in the last successful announce response.
+    pub seeder_count: Option<usize>,
+    pub leecher_count: Option<usize>,
+    /// The time of the last successful announce.
+    pub last_success_time: Option<Instant>,
+    /// Lifetime statistics of the tracker, so that the user can tell which
+    /// trackers are worth keeping.
+    pub announce_count: usize,
+    pub failure_count: usize,
+    pub last_error: Option<String>,
+    /// The sum of the durations of all announces, from which the average
+    /// response time is derived.
+    pub total_response_time: Duration,
+    pub received_peer_count: usize,
+    /// Set when the user requested a reannounce to this tracker in
+    /// particular, which is performed as soon as its minimum announce
+    /// interval allows it.
+    pub is_reannounce_pending: bool,
+    /// Set while an announce to the tracker is in progress.
+    pub is_announcing: bool,
+}
+
+impl TrackerEntry {
+    pub fn new(client: Tracker) -> Self {
+        Self {
+            url: client.url().clone(),
+            client: Arc::new(Mutex::new(client)),
+            id: None,
+            last_announce_time: None,
+            interval: None,
+            min_interval: None,
+            jitter: 0.0,
+            error_count: 0,
+            next_retry_time: None,
+            fruitless_announce_count: 0,
+            status: TrackerStatus::NotContacted,
+            message: None,
+            seeder_count: None,
+            leecher_count: None,
+            last_success_time: None,
+            announce_count: 0,
+            failure_count: 0,
+            last_error: None,
+            total_response_time: Duration::default(),
+            received_peer_count: 0,
+            is_reannounce_pending: false,
+            is_announcing: false,
+        }
+    }
+
+    /// Returns the announce to the tracker with the event.
+    pub fn target(&self, event: Option<Event>) -> AnnounceTarget {
+        AnnounceTarget {
+            url: self.url.clone(),
+            client: Arc::clone(&self.client),
+            tracker_id: self.id.clone(),
+            event,
+        }
+    }
+
+    /// Returns the announce to the tracker with the event, and marks the
+    /// tracker as being announced to.
+    fn start_announce(&mut self, event: Option<Event>) -> AnnounceTarget {
+        self.is_announcing = true;
+        self.target(event)
+    }
+
+    /// Records the end of an announce to the tracker, which took the given
+    /// time.
+    pub fn finish_announce(&mut self, response_time: Duration) {
+        self.is_announcing = false;
+        self.announce_count += 1;
+        self.total_response_time += response_time;
+    }
+
+    /// Returns the average duration of the announces made to the tracker.
+    pub fn average_response_time(&self) -> Option<Duration> {
+        if self.announce_count == 0 {
+            None
+        } else {
+            Some(self.total_response_time / self.announce_count as u32)
+        }
+    }
+
+    /// Updates the tracker's state from its announce response and returns the
+    /// peers in it.
+    ///
+    /// A response with a failure reason is treated as an error.
+    pub fn handle_response(
+        &mut self,
+        resp: Response,
+        now: Instant,
+    ) -> Result<Vec<SocketAddr>, TrackerError> {
+        log::info!("Announced to tracker {}, response: {:?}", self.url, resp);
+        if let Some(failure_reason) = resp.failure_reason {
+            return Err(TrackerError::Failure(failure_reason));
+        }
+        if let Some(tracker_id) = resp.tracker_id {
+            self.id = Some(tracker_id);
+        }
+        if let Some(warning_message) = &resp.warning_message {
+            log::warn!(
+                "Warning from tracker {}: {}",
+                self.url,
+                warning_message
+            );
+        }
+        self.status = TrackerStatus::Working;
+        self.message = resp.warning_message;
+        self.error_count = 0;
+        self.next_retry_time = None;
+        self.last_success_time = Some(now);
+        if let Some(interval) = resp.interval {
+            log::info!(
+                "Tracker {} interval: {} s",
+                self.url,
+                interval.as_secs()
+            );
+            self.interval = Some(interval);
+        }
+        if let Some(min_interval) = resp.min_interval {
+            log::info!(
+                "Tracker {} min min_interval: {} s",
+                self.url,
+                min_interval.as_secs()
+            );
+            self.min_interval = Some(min_interval);
+        }
+
+        if let (Some(seeder_count), Some(leecher_count)) =
+            (resp.seeder_count, resp.leecher_count)
+        {
+            log::debug!(
+                "Torrent seeds: {} and leeches: {}",
+                seeder_count,
+                leecher_count
+            );
+        }
+        self.seeder_count = resp.seeder_count;
+        self.leecher_count = resp.leecher_count;
+        self.received_peer_count += resp.peers.len();
+
+        Ok(resp.peers)
+    }
+
+    /// Determines whether we should announce to the tracker at the given time,
+    /// based on when we last announced and the tracker's announce interval,
+    /// extended by the jitter.
+    pub fn should_announce(
+        &self,
+        t: Instant,
+        default_announce_interval: Duration,
+    ) -> bool {
+        if let Some(last_announce_time) = self.last_announce_time {
+            let interval = self.interval.unwrap_or(default_announce_interval);
+            let min_next_announce_time =
+                last_announce_time + interval + interval.mul_f64(self.jitter);
+            t > min_next_announce_time
+        } else {
+            true
+        }
+    }
+
+    /// Determines whether we may announce at the given time to get more peers.
+    ///
+    /// This is allowed after the minimum announce interval, which is doubled
+    /// for each of the tracker's last consecutive announces that yielded no
+    /// new peers, as it's unlikely to know of more peers right away.
+    pub fn can_announce_for_peers(
+        &self,
+        t: Instant,
+        default_min_announce_interval: Duration,
+    ) -> bool {
+        if let Some(last_announce_time) = self.last_announce_time {
+            let min_interval =
+                self.min_interval.unwrap_or(default_min_announce_interval);
+            // cap the exponent so that the multiplication can't overflow,
+            // after which the regular announce interval takes over anyway
+            let exp = self.fruitless_announce_count.min(16);
+            let interval = min_interval
+                .checked_mul(2u32.pow(exp))
+                .unwrap_or(min_interval);
+            t > last_announce_time + interval
+        } else {
+            true
+        }
+    }
+
+    /// Returns whether the backoff following a failed announce has elapsed
+    /// by the given time.
+    pub fn can_retry(&self, t: Instant) -> bool {
+        match self.next_retry_time {
+            Some(next_retry_time) => t >= next_retry_time,
+            None => true,
+        }
+    }
+
+    /// Records a failed announce and schedules the next retry, doubling the
+    /// wait with each consecutive failure up to the max retry interval.
+    pub fn back_off(
+        &mut self,
+        t: Instant,
+        retry_interval: Duration,
+        max_retry_interval: Duration,
+    ) {
+        self.error_count += 1;
+        // cap the exponent so that the multiplication can't overflow
+        let exp = (self.error_count - 1).min(16) as u32;
+        let backoff = retry_interval
+            .checked_mul(2u32.pow(exp))
+            .unwrap_or(max_retry_interval)
+            .min(max_retry_interval);
+        log::debug!("Retrying tracker {} in {} s", self.url, backoff.as_secs());
+        self.next_retry_time = Some(t + backoff);
+    }
+
+    /// Determines whether we're allowed to announce at the given time.
+    ///
+    /// We may need peers before the next step in the announce interval.
+    /// However, we can't do this too often, so we need to check our last
+    /// announce time first.
+    pub fn can_announce(
+        &self,
+        t: Instant,
+        default_min_announce_interval: Duration,
+    ) -> bool {
+        if let Some(last_announce_time) = self.last_announce_time {
+            let min_next_announce_time = last_announce_time
+                + self.min_interval.unwrap_or(default_min_announce_interval);
+            t > min_next_announce_time
+        } else {
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::{mock, Matcher};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        conf::{AnnounceConf, TrackerTlsConf},
+        tracker,
+    };
+
+    fn url(host: &str) -> Url {
+        Url::parse(&format!("http://{}/announce", host)).unwrap()
+    }
+
+    fn tracker(host: &str) -> TrackerEntry {
+        let clients = tracker::http_clients(
+            None,
+            None,
+            &AnnounceConf::default(),
+            &TrackerTlsConf::default(),
+        )
+        .unwrap();
+        TrackerEntry::new(Tracker::new(url(host), clients))
+    }
+
+    fn response(failure_reason: Option<&str>) -> Response {
+        Response {
+            tracker_id: None,
+            failure_reason: failure_reason.map(str::to_string),
+            warning_message: None,
+            interval: None,
+            min_interval: None,
+            seeder_count: None,
+            leecher_count: None,
+            peers: Vec::new(),
+            peers6: Vec::new(),
+            external_ip: None,
+        }
+    }
+
+    fn urls(targets: &[AnnounceTarget]) -> Vec<&str> {
+        targets.iter().map(|t| t.url.host_str().unwrap()).collect()
+    }
+
+    fn tier_urls(trackers: &Trackers, tier: usize) -> Vec<&str> {
+        trackers.tiers()[tier]
+            .iter()
+            .map(|t| t.url.host_str().unwrap())
+            .collect()
+    }
+
+    /// Records the end of the announce to the tracker as the torrent does,
+    /// and returns the tracker to fail over to, if any.
+    fn finish(
+        trackers: &mut Trackers,
+        host: &str,
+        is_success: bool,
+        now: Instant,
+    ) -> Option<AnnounceTarget> {
+        let tracker = trackers.get_mut(&url(host)).unwrap();
+        tracker.finish_announce(Duration::from_millis(100));
+        if !is_success {
+            tracker.back_off(
+                now,
+                Duration::from_secs(30),
+                Duration::from_secs(3600),
+            );
+        }
+        trackers.handle_result(&url(host), None, is_success, now)
+    }
+
+    /// Tests that a failed regular announce fails over to the next trackers
+    /// of its tier and then of the next tiers, even if they are not due, and
+    /// that no other regular announce is started in the meantime.
+    #[test]
+    fn should_fail_over_through_tiers() {
+        let now = Instant::now();
+        let mut trackers = Trackers::from_tiers(vec![
+            vec![tracker("a"), tracker("b")],
+            vec![tracker("c")],
+        ]);
+        // only the first tracker is due
+        let is_due = |t: &TrackerEntry| t.url == url("a");
+
+        let targets = trackers.pick(now, None, false, false, is_due);
+        assert_eq!(urls(&targets), ["a"]);
+        assert!(trackers.pick(now, None, false, false, is_due).is_empty());
+
+        let next = finish(&mut trackers, "a", false, now).unwrap();
+        assert_eq!(next.url, url("b"));
+        assert!(trackers.pick(now, None, false, false, is_due).is_empty());
+
+        let next = finish(&mut trackers, "b", false, now).unwrap();
+        assert_eq!(next.url, url("c"));
+
+        assert!(finish(&mut trackers, "c", true, now).is_none());
+        // the failed trackers are kept, and retried once their backoff
+        // elapses, until which the next regular announce goes to the tracker
+        // that responded
+        assert_eq!(tier_urls(&trackers, 0), ["a", "b"]);
+        let targets = trackers.pick(now, None, false, false, |_| true);
+        assert_eq!(urls(&targets), ["c"]);
+        finish(&mut trackers, "c", true, now);
+        let later = now + Duration::from_secs(60);
+        let targets = trackers.pick(later, None, false, false, |_| true);
+        assert_eq!(urls(&targets), ["a"]);
+    }
+
+    /// Tests that the tracker that responded after others of its tier failed
+    /// is moved to the front of the tier, as per BEP 12.
+    #[test]
+    fn should_promote_responding_tracker_within_tier() {
+        let now = Instant::now();
+        let mut trackers = Trackers::from_tiers(vec![vec![
+            tracker("a"),
+            tracker("b"),
+            tracker("c"),
+        ]]);
+
+        let targets = trackers.pick(now, None, false, false, |_| true);
+        assert_eq!(urls(&targets), ["a"]);
+        let next = finish(&mut trackers, "a", false, now).unwrap();
+        assert_eq!(next.url, url("b"));
+        assert!(finish(&mut trackers, "b", true, now).is_none());
+        assert_eq!(tier_urls(&trackers, 0), ["b", "a", "c"]);
+
+        let later = now + Duration::from_secs(60);
+        let targets = trackers.pick(later, None, false, false, |_| true);
+        assert_eq!(urls(&targets), ["b"]);
+    }
+
+    /// Tests that a failover skips the trackers whose backoff hasn't elapsed,
+    /// unless the announce was forced.
+    #[test]
+    fn should_skip_backed_off_trackers_in_failover() {
+        let now = Instant::now();
+        let mut trackers = Trackers::from_tiers(vec![vec![
+            tracker("a"),
+            tracker("b"),
+            tracker("c"),
+        ]]);
+        trackers.get_mut(&url("b")).unwrap().next_retry_time =
+            Some(now + Duration::from_secs(30));
+
+        trackers.pick(now, None, false, false, |_| true);
+        let next = finish(&mut trackers, "a", false, now).unwrap();
+        assert_eq!(next.url, url("c"));
+        assert!(finish(&mut trackers, "c", false, now).is_none());
+
+        // all trackers are backed off now, but a forced announce goes through
+        assert!(trackers.pick(now, None, false, false, |_| true).is_empty());
+        let targets = trackers.pick(now, None, true, false, |_| true);
+        assert_eq!(urls(&targets), ["a"]);
+        let next = finish(&mut trackers, "a", false, now).unwrap();
+        assert_eq!(next.url, url("b"));
+    }
+
+    /// Tests that all usable trackers that are due are announced to if they
+    /// are announced to on their own schedule.
+    #[test]
+    fn should_pick_all_due_trackers() {
+        let now = Instant::now();
+        let mut trackers = Trackers::from_tiers(vec![
+            vec![tracker("a"), tracker("b")],
+            vec![tracker("c")],
+        ]);
+        let is_due = |t: &TrackerEntry| t.url != url("b");
+        let targets = trackers.pick(now, None, false, true, is_due);
+        assert_eq!(urls(&targets), ["a", "c"]);
+        // trackers being announced to are skipped
+        let targets = trackers.pick(now, None, false, true, |_| true);
+        assert_eq!(urls(&targets), ["b"]);
+        // results of such announces don't fail over
+        assert!(finish(&mut trackers, "a", false, now).is_none());
+    }
+
+    /// Tests that the backoff after each consecutive failure doubles from
+    /// the retry interval, and that it's capped at the max retry interval.
+    #[test]
+    fn should_back_off_exponentially_up_to_cap() {
+        let now = Instant::now();
+        let retry_interval = Duration::from_secs(30);
+        let max_retry_interval = Duration::from_secs(200);
+        let mut tracker = tracker("a");
+        let mut backoffs = Vec::new();
+        for _ in 0..5 {
+            tracker.back_off(now, retry_interval, max_retry_interval);
+            backoffs.push(tracker.next_retry_time.unwrap() - now);
+        }
+        assert_eq!(
+            backoffs,
+            [30, 60, 120, 200, 200]
+                .iter()
+                .map(|secs| Duration::from_secs(*secs))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(tracker.error_count, 5);
+
+        // the backoff doesn't overflow however many failures there are
+        tracker.error_count = usize::MAX - 1;
+        tracker.back_off(now, retry_interval, max_retry_interval);
+        assert_eq!(tracker.next_retry_time, Some(now + max_retry_interval));
+    }
+
+    /// Tests that a failing tracker is retried once its backoff elapses, and
+    /// that a successful announce resets its backoff.
+    #[test]
+    fn should_re_enable_tracker_after_backoff() {
+        let now = Instant::now();
+        let retry_interval = Duration::from_secs(30);
+        let max_retry_interval = Duration::from_secs(3600);
+        let mut trackers = Trackers::from_tiers(vec![vec![tracker("a")]]);
+        let tracker = trackers.get_mut(&url("a")).unwrap();
+        assert!(tracker.can_retry(now));
+        tracker.back_off(now, retry_interval, max_retry_interval);
+        tracker.back_off(now, retry_interval, max_retry_interval);
+        let retry_time = now + Duration::from_secs(60);
+        assert!(!tracker.can_retry(retry_time - Duration::from_secs(1)));
+        assert!(tracker.can_retry(retry_time));
+
+        // the tracker is skipped while backed off, but not dropped
+        let early = retry_time - Duration::from_secs(1);
+        assert!(trackers
+            .pick(early, None, false, false, |_| true)
+            .is_empty());
+        let targets = trackers.pick(retry_time, None, false, false, |_| true);
+        assert_eq!(urls(&targets), ["a"]);
+
+        // a failure reason in the response counts as a failure
+        let tracker = trackers.get_mut(&url("a")).unwrap();
+        assert!(tracker
+            .handle_response(response(Some("unregistered")), retry_time)
+            .is_err());
+        assert_eq!(tracker.error_count, 2);
+
+        tracker.handle_response(response(None), retry_time).unwrap();
+        assert_eq!(tracker.error_count, 0);
+        assert_eq!(tracker.next_retry_time, None);
+        assert!(tracker.can_retry(retry_time));
+        // after a success, the backoff starts again from the retry interval
+        tracker.back_off(retry_time, retry_interval, max_retry_interval);
+        assert_eq!(tracker.next_retry_time, Some(retry_time + retry_interval));
+    }
+
+    /// Tests that the completed event is sent until a tracker receives it,
+    /// and not again after that.
+    #[test]
+    fn should_send_completed_event_once() {
+        let now = Instant::now();
+        let mut trackers =
+            Trackers::from_tiers(vec![vec![tracker("a"), tracker("b")]]);
+        let completed = Some(Event::Completed);
+        trackers.pending_event = completed;
+
+        let targets = trackers.pick(now, completed, false, false, |_| true);
+        assert_eq!(targets[0].event, completed);
+        let tracker = trackers.get_mut(&url("a")).unwrap();
+        tracker.finish_announce(Duration::from_millis(100));
+        // the event is retried with the failover
+        let next = trackers.handle_result(&url("a"), completed, false, now);
+        assert_eq!(next.unwrap().event, completed);
+        assert_eq!(trackers.pending_event, completed);
+
+        // a regular announce that started before the torrent completed
+        // doesn't deliver the event
+        trackers.handle_result(&url("b"), None, true, now);
+        assert_eq!(trackers.pending_event, completed);
+
+        trackers.handle_result(&url("b"), completed, true, now);
+        assert_eq!(trackers.pending_event, None);
+        // the torrent announces the pending event, if any, with the next
+        // regular announces
+        let later = now + Duration::from_secs(60);
+        let event = trackers.pending_event;
+        let targets = trackers.pick(later, event, false, false, |_| true);
+        assert_eq!(targets[0].event, None);
+    }
+
+    /// Tests that the stopped event is sent to the trackers that we
+    /// announced to, and that unresponsive trackers don't hold up the
+    /// torrent beyond the timeout.
+    #[tokio::test]
+    async fn should_announce_stop_within_timeout() {
+        let stop_mock = mock("GET", "/announce")
+            .match_query(Matcher::UrlEncoded("event".into(), "stopped".into()))
+            .with_status(200)
+            .with_body("d8:intervali1800ee")
+            .expect(1)
+            .create();
+        let responsive = mockito::server_address().to_string();
+        // a tracker that accepts connections but never responds
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let unresponsive = listener.local_addr().unwrap().to_string();
+        rt::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let now = Instant::now();
+        let mut trackers = Trackers::from_tiers(vec![
+            vec![tracker(&responsive), tracker(&unresponsive)],
+            // never announced to, so it's not told we're stopping
+            vec![tracker("c")],
+        ]);
+        for tracker in trackers.iter_mut().take(2) {
+            tracker.last_success_time = Some(now);
+        }
+        trackers.pending_event = Some(Event::Completed);
+
+        let targets = trackers.stop();
+        assert_eq!(trackers.pending_event, None);
+        assert_eq!(targets.len(), 2);
+        assert!(targets
+            .iter()
+            .all(|target| target.event == Some(Event::Stopped)));
+        let params = Announce {
+            info_hash: [0; 20],
+            peer_id: [0; 20],
+            port: 6881,
+            downloaded: 0,
+            uploaded: 0,
+            left: 0,
+            peer_count: Some(0),
+            ip: None,
+            event: None,
+            tracker_id: None,
+            key: 0,
+        };
+        let timeout = Duration::from_millis(500);
+        let start = Instant::now();
+        announce_stop(targets, params, timeout).await;
+        assert!(start.elapsed() < timeout + Duration::from_secs(1));
+        stop_mock.assert();
+    }
+}
@@ -1,18 +1,44 @@
+//! The tracker clients used by torrents to find peers.
+//!
+//! HTTP, UDP ([BEP 15](https://www.bittorrent.org/beps/bep_0015.html)) and,
+//! with the `websocket-trackers` feature, WebSocket trackers are supported.
+//!
+//! Besides being used by the engine, a single announce can be made with
+//! [`announce`], e.g. by tools that monitor swarms, without setting up an
+//! engine.
+
 use std::{
-    fmt,
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
     time::Duration,
 };
 
 use bytes::Buf;
 use percent_encoding::{AsciiSet, NON_ALPHANUMERIC};
-use reqwest::{Client, Url};
+use reqwest::{Client, Proxy, Url};
 use serde::de;
 
-use crate::{metainfo::BencodeError, PeerId, Sha1Hash};
+use crate::{
+    conf::{
+        AnnounceConf, ProxyAuth, ProxyConf, TrackerProxyConf, TrackerProxyKind,
+        TrackerTlsConf, CRATETORRENT_USER_AGENT,
+    },
+    error::Error,
+    external_ip::parse_ip,
+    metainfo::BencodeError,
+    PeerId, Sha1Hash,
+};
 
+pub use native_tls::Error as TlsError;
 pub use reqwest::Error as HttpError;
 
+mod udp;
+#[cfg(feature = "websocket-trackers")]
+mod ws;
+
+#[cfg(feature = "websocket-trackers")]
+pub use ws::WebSocketError;
+
 pub(crate) type Result<T, E = TrackerError> = crate::error::Result<T, E>;
 
 /// The possible errors that may occur when contating the tracker.
@@ -23,6 +49,17 @@ pub enum TrackerError {
     Bencode(BencodeError),
     /// HTTP related errors when contacting the tracker.
     Http(HttpError),
+    /// IO errors when contacting a UDP tracker.
+    Io(io::Error),
+    /// A UDP tracker did not respond after all retransmissions.
+    Timeout,
+    /// A UDP tracker sent a malformed response.
+    InvalidResponse,
+    /// The tracker rejected the request with the contained message.
+    Failure(String),
+    /// WebSocket related errors when contacting the tracker.
+    #[cfg(feature = "websocket-trackers")]
+    WebSocket(WebSocketError),
 }
 
 impl From<BencodeError> for TrackerError {
@@ -37,18 +74,40 @@ impl From<HttpError> for TrackerError {
     }
 }
 
+impl From<io::Error> for TrackerError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+#[cfg(feature = "websocket-trackers")]
+impl From<WebSocketError> for TrackerError {
+    fn from(e: WebSocketError) -> Self {
+        Self::WebSocket(e)
+    }
+}
+
 impl fmt::Display for TrackerError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Self::Bencode(e) => e.fmt(f),
             Self::Http(e) => e.fmt(f),
+            Self::Io(e) => e.fmt(f),
+            Self::Timeout => write!(f, "tracker timed out"),
+            Self::InvalidResponse => write!(f, "invalid tracker response"),
+            Self::Failure(msg) => write!(f, "tracker failure: {}", msg),
+            #[cfg(feature = "websocket-trackers")]
+            Self::WebSocket(e) => e.fmt(f),
         }
     }
 }
 
 /// Parameters for announcing to a tracker.
-pub(crate) struct Announce {
+#[derive(Clone, Debug)]
+pub struct Announce {
+    /// The info hash of the torrent being announced.
     pub info_hash: Sha1Hash,
+    /// Our peer id.
     pub peer_id: PeerId,
 
     /// The port on which we are listening.
@@ -77,6 +136,10 @@ pub(crate) struct Announce {
     /// announce.
     pub tracker_id: Option<String>,
 
+    /// A random value that identifies us to the tracker even if our IP address
+    /// changes.
+    pub key: u32,
+
     /// Only need be set during the special events defined in [`Event`].
     /// Otherwise when just requesting peers, no event needs to be set.
     pub event: Option<Event>,
@@ -84,7 +147,7 @@ pub(crate) struct Announce {
 
 /// The optional announce event.
 #[derive(Clone, Copy, Debug, PartialEq)]
-pub(crate) enum Event {
+pub enum Event {
     /// The first request to tracker must include this value.
     Started,
     /// Must be sent to the tracker when the client becomes a seeder. Must not be
@@ -94,10 +157,23 @@ pub(crate) enum Event {
     Stopped,
 }
 
+impl Event {
+    /// Returns the value of the event as it is expected in the announce query
+    /// string.
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Started => "started",
+            Self::Completed => "completed",
+            Self::Stopped => "stopped",
+        }
+    }
+}
+
 /// The tracker announce response.
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
+#[serde(crate = "serde_crate")]
 #[cfg_attr(test, derive(PartialEq, Serialize))]
-pub(crate) struct Response {
+pub struct Response {
     /// The tracker id. If set, we must send it with each subsequent announce.
     #[serde(rename = "tracker id")]
     pub tracker_id: Option<String>,
@@ -123,31 +199,241 @@ pub(crate) struct Response {
     #[serde(deserialize_with = "deserialize_seconds")]
     pub min_interval: Option<Duration>,
 
+    /// The number of seeders in the swarm, if reported.
     #[serde(rename = "complete")]
     pub seeder_count: Option<usize>,
+    /// The number of leechers in the swarm, if reported.
     #[serde(rename = "incomplete")]
     pub leecher_count: Option<usize>,
 
+    /// The addresses of the peers returned by the tracker.
     #[serde(default)]
     #[serde(deserialize_with = "deserialize_peers")]
     pub peers: Vec<SocketAddr>,
+
+    /// IPv6 peers, sent by HTTP trackers in a separate compact string, see
+    /// [BEP 7](https://www.bittorrent.org/beps/bep_0007.html). These are
+    /// merged into `peers` before the response is returned.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_peers6")]
+    pub peers6: Vec<SocketAddr>,
+
+    /// Our IP address, as seen by the tracker.
+    #[serde(default)]
+    #[serde(rename = "external ip")]
+    #[serde(deserialize_with = "deserialize_external_ip")]
+    pub external_ip: Option<IpAddr>,
+}
+
+/// The HTTP clients shared by all trackers in the engine.
+#[derive(Clone)]
+pub(crate) struct HttpClients {
+    /// The client used for all HTTP announces. If the host is dual-stack, it
+    /// is bound to IPv4.
+    default: Client,
+    /// If the host is dual-stack, this client is bound to IPv6 and is used to
+    /// announce over IPv6 as well, so that we receive peers of both address
+    /// families.
+    ipv6: Option<Client>,
+    /// Our IPv4 address, sent along IPv6 announces.
+    local_ipv4: Option<Ipv4Addr>,
+    /// Our IPv6 address, sent along IPv4 announces.
+    local_ipv6: Option<Ipv6Addr>,
+}
+
+/// Creates the HTTP clients used for contacting trackers.
+///
+/// If a tracker proxy is given, all requests are sent through it. Otherwise,
+/// if a peer proxy is given, requests are tunneled through it, and tracker host
+/// names are resolved by the proxy. Without a proxy, if the host has both an
+/// IPv4 and an IPv6 address, a client is created for each address family.
+pub(crate) fn http_clients(
+    proxy: Option<&ProxyConf>,
+    tracker_proxy: Option<&TrackerProxyConf>,
+    announce_conf: &AnnounceConf,
+    tls_conf: &TrackerTlsConf,
+) -> crate::error::Result<HttpClients> {
+    let tls = tls_connector(tls_conf)?;
+    let builder = || {
+        let builder = Client::builder().user_agent(&announce_conf.user_agent);
+        match &tls {
+            Some(tls) => builder.use_preconfigured_tls(tls.clone()),
+            None => builder,
+        }
+    };
+    let proxy_url = match (tracker_proxy, proxy) {
+        (Some(proxy), _) => {
+            let scheme = match proxy.kind {
+                TrackerProxyKind::Http => "http",
+                TrackerProxyKind::Socks5 => "socks5h",
+            };
+            Some(proxy_url(scheme, proxy.addr, proxy.auth.as_ref()))
+        }
+        // the socks5h scheme (as opposed to socks5) makes the proxy resolve
+        // host names
+        (None, Some(proxy)) => {
+            Some(proxy_url("socks5h", proxy.addr, proxy.auth.as_ref()))
+        }
+        (None, None) => None,
+    };
+    if let Some(url) = proxy_url {
+        return Ok(HttpClients {
+            default: builder().proxy(Proxy::all(url)?).build()?,
+            ipv6: None,
+            local_ipv4: None,
+            local_ipv6: None,
+        });
+    }
+
+    let local_ipv4 = match local_ip(false) {
+        Some(IpAddr::V4(ip)) => Some(ip),
+        _ => None,
+    };
+    let local_ipv6 = match local_ip(true) {
+        Some(IpAddr::V6(ip)) => Some(ip),
+        _ => None,
+    };
+    if local_ipv4.is_some() && local_ipv6.is_some() {
+        log::info!("Host is dual-stack, announcing over IPv4 and IPv6");
+        Ok(HttpClients {
+            default: builder()
+                .local_address(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+                .build()?,
+            ipv6: Some(
+                builder()
+                    .local_address(IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+                    .build()?,
+            ),
+            local_ipv4,
+            local_ipv6,
+        })
+    } else {
+        Ok(HttpClients {
+            default: builder().build()?,
+            ipv6: None,
+            local_ipv4,
+            local_ipv6,
+        })
+    }
 }
 
-/// The HTTP tracker for a torrent for which we can request peers as well as to
-/// announce transfer progress.
+/// Builds the TLS connector of HTTPS tracker connections, or returns `None` if
+/// the HTTP client's default connector suffices.
+fn tls_connector(
+    conf: &TrackerTlsConf,
+) -> crate::error::Result<Option<native_tls::TlsConnector>> {
+    if conf.is_default() {
+        return Ok(None);
+    }
+    let mut builder = native_tls::TlsConnector::builder();
+    for cert in conf.root_certs.iter() {
+        builder.add_root_certificate(
+            native_tls::Certificate::from_pem(cert).map_err(Error::Tls)?,
+        );
+    }
+    if conf.accept_invalid_certs {
+        log::warn!("Tracker certificate verification is disabled");
+    }
+    let connector = builder
+        .danger_accept_invalid_certs(conf.accept_invalid_certs)
+        .danger_accept_invalid_hostnames(conf.accept_invalid_hostnames)
+        .use_sni(!conf.disable_sni)
+        .build()
+        .map_err(Error::Tls)?;
+    Ok(Some(connector))
+}
+
+/// Returns the URL of the proxy at the given address, including the
+/// credentials, if any.
+fn proxy_url(scheme: &str, addr: SocketAddr, auth: Option<&ProxyAuth>) -> Url {
+    let mut url = Url::parse(&format!("{}://{}", scheme, addr))
+        .expect("socket address should be a valid URL host");
+    if let Some(auth) = auth {
+        // setting these can only fail for URLs that can't have credentials,
+        // which is not the case here
+        url.set_username(&auth.username).ok();
+        url.set_password(Some(&auth.password)).ok();
+    }
+    url
+}
+
+/// Returns the address of the local interface through which the internet is
+/// reached over the given address family, if any.
+///
+/// Connecting a UDP socket doesn't send any packets, it only looks up the
+/// route to the remote address, which is an arbitrary public one.
+fn local_ip(ipv6: bool) -> Option<IpAddr> {
+    let (local_ip, remote_ip): (IpAddr, IpAddr) = if ipv6 {
+        let remote_ip =
+            Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888);
+        (Ipv6Addr::UNSPECIFIED.into(), remote_ip.into())
+    } else {
+        let remote_ip = Ipv4Addr::new(8, 8, 8, 8);
+        (Ipv4Addr::UNSPECIFIED.into(), remote_ip.into())
+    };
+    let socket = UdpSocket::bind((local_ip, 0)).ok()?;
+    socket.connect((remote_ip, 80)).ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    if ip.is_unspecified() || ip.is_loopback() {
+        None
+    } else {
+        Some(ip)
+    }
+}
+
+/// The HTTP or UDP tracker for a torrent for which we can request peers as
+/// well as to announce transfer progress.
 pub(crate) struct Tracker {
-    /// The HTTP client.
-    client: Client,
     /// The URL of the tracker.
     url: Url,
+    /// The protocol specific client.
+    transport: Transport,
+}
+
+enum Transport {
+    /// The HTTP clients.
+    Http(HttpClients),
+    /// The UDP tracker client, which keeps per tracker connection state.
+    Udp(udp::UdpTracker),
+    /// The WebSocket tracker client.
+    #[cfg(feature = "websocket-trackers")]
+    WebSocket(ws::WsTracker),
+}
+
+/// The clients' state is not of interest, so only the URL is printed.
+impl fmt::Debug for Tracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tracker").field("url", &self.url).finish()
+    }
 }
 
 impl Tracker {
-    pub fn new(url: Url) -> Self {
-        Self {
-            client: Client::new(),
-            url,
-        }
+    /// Creates a new tracker at the given URL. The protocol is determined by
+    /// the URL's scheme.
+    ///
+    /// The HTTP clients are expected to be shared among all trackers in the
+    /// engine, see [`http_clients`].
+    pub fn new(url: Url, clients: HttpClients) -> Self {
+        let transport = match url.scheme() {
+            "udp" => Transport::Udp(udp::UdpTracker::new(url.clone())),
+            #[cfg(feature = "websocket-trackers")]
+            "ws" | "wss" => {
+                Transport::WebSocket(ws::WsTracker::new(url.clone()))
+            }
+            _ => Transport::Http(clients),
+        };
+        Self { url, transport }
+    }
+
+    /// Returns the announce URL of the tracker.
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Returns whether the tracker can be reached through a proxy, which is
+    /// only the case for HTTP trackers.
+    pub fn can_use_proxy(&self) -> bool {
+        matches!(self.transport, Transport::Http(_))
     }
 
     /// Sends an announce request to the tracker with the specified parameters.
@@ -159,77 +445,173 @@ impl Tracker {
     ///
     /// The tracker may not be contacted more often than the minimum interval
     /// returned in the first announce response.
-    pub async fn announce(&self, params: Announce) -> Result<Response> {
-        // announce parameters are built up in the query string, see:
-        // https://www.bittorrent.org/beps/bep_0003.html trackers section
-        let mut query = vec![
-            ("port", params.port.to_string()),
-            ("downloaded", params.downloaded.to_string()),
-            ("uploaded", params.uploaded.to_string()),
-            ("left", params.left.to_string()),
-            // Indicates that client accepts a compact response (each peer takes
-            // up only 6 bytes where the first four bytes constitute the IP
-            // address and the last 2 the port number, in Network Byte Order).
-            // The is always true to save network traffic (many trackers don't
-            // consider this and send compact lists anyway).
-            ("compact", "1".to_string()),
-        ];
-        if let Some(peer_count) = params.peer_count {
-            query.push(("numwant", peer_count.to_string()));
-        }
-        if let Some(ip) = &params.ip {
-            query.push(("ip", ip.to_string()));
+    pub async fn announce(&mut self, params: Announce) -> Result<Response> {
+        match &mut self.transport {
+            Transport::Http(clients) => match &clients.ipv6 {
+                Some(ipv6_client) => {
+                    // announce over both address families at once, telling
+                    // the tracker our address of the other family
+                    let url = &self.url;
+                    let ipv4 = clients.local_ipv4.map(IpAddr::V4);
+                    let ipv6 = clients.local_ipv6.map(IpAddr::V6);
+                    let (ipv4_resp, ipv6_resp) = futures::join!(
+                        http_announce(&clients.default, url, &params, ipv6),
+                        http_announce(ipv6_client, url, &params, ipv4),
+                    );
+                    merge_responses(ipv4_resp, ipv6_resp)
+                }
+                None => {
+                    http_announce(&clients.default, &self.url, &params, None)
+                        .await
+                }
+            },
+            Transport::Udp(tracker) => tracker.announce(params).await,
+            #[cfg(feature = "websocket-trackers")]
+            Transport::WebSocket(tracker) => tracker.announce(params).await,
         }
+    }
+}
 
-        // hack:
-        // reqwest uses serde_urlencoded which doesn't support encoding a raw
-        // byte array into a percent encoded string. However, the tracker
-        // expects the url encoded form of the raw info hash, so we need to be
-        // able to map the raw bytes to its url encoded form. The peer id is
-        // also stored as a raw byte array. Using `String::from_utf8_lossy`
-        // would cause information loss.
-        //
-        // We do this using the separate percent_encoding crate, and by
-        // "hard-coding" the info hash and the peer id into the url string. This
-        // is the only way in which reqwest doesn't url encode again the custom
-        // url encoded info hash. All other methods, such as mutating the query
-        // parameters on the `Url` object, or by serializing the info hash with
-        // `serde_bytes` do not work: they throw an error due to expecting valid
-        // utf8.
-        //
-        // However, this is decidedly _not_ great: we're relying on an
-        // undocumented edge case of a third party library (reqwest) that may
-        // very well break in a future update.
-        let url = format!(
-            "{url}\
-            ?info_hash={info_hash}\
-            &peer_id={peer_id}",
-            url = self.url,
-            info_hash = percent_encoding::percent_encode(
-                &params.info_hash,
-                URL_ENCODE_RESERVED
-            ),
-            peer_id = percent_encoding::percent_encode(
-                &params.peer_id,
-                URL_ENCODE_RESERVED
-            ),
-        );
+/// Sends a single announce request with the given parameters to the tracker
+/// at the given URL.
+///
+/// This is meant for using the tracker client on its own, without an engine,
+/// so no proxy or TLS settings are applied, and HTTP trackers are only
+/// announced to over the default address family.
+///
+/// # Important
+///
+/// The tracker may not be contacted more often than the minimum interval
+/// returned in the response.
+pub async fn announce(url: Url, params: Announce) -> Result<Response> {
+    let clients = HttpClients {
+        default: Client::builder()
+            .user_agent(CRATETORRENT_USER_AGENT)
+            .build()?,
+        ipv6: None,
+        local_ipv4: None,
+        local_ipv6: None,
+    };
+    Tracker::new(url, clients).announce(params).await
+}
 
-        // send request
-        let resp = self
-            .client
-            .get(&url)
-            .query(&query)
-            .send()
-            .await?
-            .error_for_status()?
-            .bytes()
-            .await?;
-        let resp = serde_bencode::from_bytes(&resp)?;
-        Ok(resp)
+/// Merges the responses of the announces over the two address families.
+///
+/// The IPv4 response is used as the basis, with the peers of the IPv6
+/// response added. The announce only fails if both requests failed.
+fn merge_responses(
+    ipv4_resp: Result<Response>,
+    ipv6_resp: Result<Response>,
+) -> Result<Response> {
+    match (ipv4_resp, ipv6_resp) {
+        (Ok(mut resp), Ok(ipv6_resp)) => {
+            for peer in ipv6_resp.peers.into_iter() {
+                if !resp.peers.contains(&peer) {
+                    resp.peers.push(peer);
+                }
+            }
+            resp.external_ip = resp.external_ip.or(ipv6_resp.external_ip);
+            Ok(resp)
+        }
+        (Ok(resp), Err(e)) | (Err(e), Ok(resp)) => {
+            log::debug!("Announce over one address family failed: {}", e);
+            Ok(resp)
+        }
+        (Err(e), Err(_)) => Err(e),
     }
 }
 
+/// Sends an announce request to the HTTP tracker at the given URL.
+///
+/// If given, our IP address of the address family other than the one the
+/// request is sent over is included in the request.
+async fn http_announce(
+    client: &Client,
+    url: &Url,
+    params: &Announce,
+    other_ip: Option<IpAddr>,
+) -> Result<Response> {
+    // announce parameters are built up in the query string, see:
+    // https://www.bittorrent.org/beps/bep_0003.html trackers section
+    let mut query = vec![
+        ("port", params.port.to_string()),
+        ("downloaded", params.downloaded.to_string()),
+        ("uploaded", params.uploaded.to_string()),
+        ("left", params.left.to_string()),
+        ("key", format!("{:08x}", params.key)),
+        // Indicates that client accepts a compact response (each peer takes
+        // up only 6 bytes where the first four bytes constitute the IP
+        // address and the last 2 the port number, in Network Byte Order).
+        // The is always true to save network traffic (many trackers don't
+        // consider this and send compact lists anyway).
+        ("compact", "1".to_string()),
+    ];
+    if let Some(peer_count) = params.peer_count {
+        query.push(("numwant", peer_count.to_string()));
+    }
+    if let Some(ip) = &params.ip {
+        query.push(("ip", ip.to_string()));
+    }
+    if let Some(event) = params.event {
+        query.push(("event", event.as_str().to_string()));
+    }
+    if let Some(tracker_id) = &params.tracker_id {
+        query.push(("trackerid", tracker_id.clone()));
+    }
+    match other_ip {
+        Some(ip @ IpAddr::V4(_)) => query.push(("ipv4", ip.to_string())),
+        Some(ip @ IpAddr::V6(_)) => query.push(("ipv6", ip.to_string())),
+        None => (),
+    }
+
+    // hack:
+    // reqwest uses serde_urlencoded which doesn't support encoding a raw
+    // byte array into a percent encoded string. However, the tracker
+    // expects the url encoded form of the raw info hash, so we need to be
+    // able to map the raw bytes to its url encoded form. The peer id is
+    // also stored as a raw byte array. Using `String::from_utf8_lossy`
+    // would cause information loss.
+    //
+    // We do this using the separate percent_encoding crate, and by
+    // "hard-coding" the info hash and the peer id into the url string. This
+    // is the only way in which reqwest doesn't url encode again the custom
+    // url encoded info hash. All other methods, such as mutating the query
+    // parameters on the `Url` object, or by serializing the info hash with
+    // `serde_bytes` do not work: they throw an error due to expecting valid
+    // utf8.
+    //
+    // However, this is decidedly _not_ great: we're relying on an
+    // undocumented edge case of a third party library (reqwest) that may
+    // very well break in a future update.
+    let url = format!(
+        "{url}\
+        ?info_hash={info_hash}\
+        &peer_id={peer_id}",
+        url = url,
+        info_hash = percent_encoding::percent_encode(
+            &params.info_hash,
+            URL_ENCODE_RESERVED
+        ),
+        peer_id = percent_encoding::percent_encode(
+            &params.peer_id,
+            URL_ENCODE_RESERVED
+        ),
+    );
+
+    // send request
+    let resp = client
+        .get(&url)
+        .query(&query)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    let mut resp: Response = serde_bencode::from_bytes(&resp)?;
+    resp.peers.append(&mut resp.peers6);
+    Ok(resp)
+}
+
 impl fmt::Display for Tracker {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "'{}'", self.url)
@@ -270,33 +652,15 @@ where
         /// Each entry is 6 bytes long, where the first 4 bytes are the IPv4
         /// address of the peer, and the last 2 bytes are the port of the peer.
         /// Both are in network byte order.
-        fn visit_bytes<E>(self, mut b: &[u8]) -> Result<Self::Value, E>
+        fn visit_bytes<E>(self, b: &[u8]) -> Result<Self::Value, E>
         where
             E: de::Error,
         {
-            // in compact representation each peer must be 6 bytes
-            // long
-            const ENTRY_LEN: usize = 6;
-            let buf_len = b.len();
-
-            if buf_len % ENTRY_LEN != 0 {
-                return Err(TrackerError::Bencode(BencodeError::InvalidValue(
+            parse_compact_peers(b, false).map_err(|_| {
+                E::custom(TrackerError::Bencode(BencodeError::InvalidValue(
                     "peers compact string must be a multiple of 6".into(),
                 )))
-                .map_err(E::custom);
-            }
-
-            let buf_len = b.len();
-            let mut peers = Vec::with_capacity(buf_len / ENTRY_LEN);
-
-            for _ in (0..buf_len).step_by(ENTRY_LEN) {
-                let addr = Ipv4Addr::from(b.get_u32());
-                let port = b.get_u16();
-                let peer = SocketAddr::new(IpAddr::V4(addr), port);
-                peers.push(peer);
-            }
-
-            Ok(peers)
+            })
         }
 
         /// Deserializes a list of dicts containing the peer information.
@@ -305,6 +669,7 @@ where
             A: de::SeqAccess<'de>,
         {
             #[derive(Debug, Deserialize)]
+            #[serde(crate = "serde_crate")]
             struct RawPeer {
                 ip: String,
                 port: u16,
@@ -327,6 +692,57 @@ where
     deserializer.deserialize_any(Visitor)
 }
 
+/// Deserializes the compact string of IPv6 peers, in which each entry is
+/// 18 bytes long: the 16 byte IPv6 address followed by the 2 byte port.
+fn deserialize_peers6<'de, D>(
+    deserializer: D,
+) -> Result<Vec<SocketAddr>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    let b: serde_bytes::ByteBuf = de::Deserialize::deserialize(deserializer)?;
+    parse_compact_peers(&b, true).map_err(|_| {
+        de::Error::custom(TrackerError::Bencode(BencodeError::InvalidValue(
+            "peers6 compact string must be a multiple of 18".into(),
+        )))
+    })
+}
+
+/// Deserializes our 4 or 16 byte IP address, as seen by the tracker.
+///
+/// An address of invalid length is ignored, as it's not essential to the
+/// announce.
+fn deserialize_external_ip<'de, D>(
+    deserializer: D,
+) -> Result<Option<IpAddr>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    let b: serde_bytes::ByteBuf = de::Deserialize::deserialize(deserializer)?;
+    Ok(parse_ip(&b))
+}
+
+/// Parses a compact list of peers. Each entry is an IPv4 or IPv6 address
+/// followed by a 2 byte port, all in network byte order.
+fn parse_compact_peers(mut b: &[u8], is_ipv6: bool) -> Result<Vec<SocketAddr>> {
+    let entry_len = if is_ipv6 { 18 } else { 6 };
+    if b.len() % entry_len != 0 {
+        return Err(TrackerError::InvalidResponse);
+    }
+
+    let mut peers = Vec::with_capacity(b.len() / entry_len);
+    while b.has_remaining() {
+        let ip = if is_ipv6 {
+            IpAddr::V6(Ipv6Addr::from(b.get_u128()))
+        } else {
+            IpAddr::V4(Ipv4Addr::from(b.get_u32()))
+        };
+        let port = b.get_u16();
+        peers.push(SocketAddr::new(ip, port));
+    }
+    Ok(peers)
+}
+
 /// Deserializes an integer representing seconds into a `Duration`.
 fn deserialize_seconds<'de, D>(
     deserializer: D,
@@ -353,6 +769,7 @@ mod tests {
     use super::*;
 
     #[derive(Deserialize)]
+    #[serde(crate = "serde_crate")]
     struct PeersResponse {
         #[serde(deserialize_with = "deserialize_peers")]
         peers: Vec<SocketAddr>,
@@ -378,12 +795,14 @@ mod tests {
     #[test]
     fn should_parse_full_peer_list() {
         #[derive(Debug, Serialize)]
+        #[serde(crate = "serde_crate")]
         struct RawPeer {
             ip: String,
             port: u16,
         }
 
         #[derive(Debug, Serialize)]
+        #[serde(crate = "serde_crate")]
         struct RawPeers {
             peers: Vec<RawPeer>,
         }
@@ -420,7 +839,13 @@ mod tests {
     #[tokio::test]
     async fn should_return_peers_on_announce() {
         let addr = mockito::server_url();
-        let tracker = Tracker::new(addr.parse().unwrap());
+        let clients = HttpClients {
+            default: Client::new(),
+            ipv6: None,
+            local_ipv4: None,
+            local_ipv6: None,
+        };
+        let mut tracker = Tracker::new(addr.parse().unwrap(), clients);
 
         let info_hash_str = "abcdefghij1234567890";
         let mut info_hash = [0; 20];
@@ -439,11 +864,13 @@ mod tests {
             left: 1234,
             peer_count: Some(2),
             ip: None,
-            event: None,
-            tracker_id: None,
+            event: Some(Event::Started),
+            tracker_id: Some("tracker-1".into()),
+            key: 0xc0ffee,
         };
         let peer_ip = Ipv4Addr::new(2, 156, 201, 254);
         let peer_port = 49123;
+        let peer6_ip = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
         let expected_resp = Response {
             tracker_id: None,
             failure_reason: None,
@@ -452,7 +879,12 @@ mod tests {
             min_interval: Some(Duration::from_secs(10)),
             seeder_count: Some(5),
             leecher_count: Some(3),
-            peers: vec![SocketAddr::new(peer_ip.into(), peer_port)],
+            peers: vec![
+                SocketAddr::new(peer_ip.into(), peer_port),
+                SocketAddr::new(peer6_ip.into(), peer_port),
+            ],
+            peers6: Vec::new(),
+            external_ip: Some(Ipv4Addr::new(1, 2, 3, 4).into()),
         };
 
         let mut encoded_resp = Vec::new();
@@ -469,6 +901,13 @@ mod tests {
         encoded_resp.extend_from_slice(&encode_compact_peers_list(&[(
             peer_ip, peer_port,
         )]));
+        // insert IPv6 peers field into dict
+        encoded_resp.extend_from_slice(b"6:peers618:");
+        encoded_resp.extend_from_slice(&peer6_ip.octets());
+        encoded_resp.extend_from_slice(&peer_port.to_be_bytes());
+        // insert our external IP into dict
+        encoded_resp.extend_from_slice(b"11:external ip4:");
+        encoded_resp.extend_from_slice(&[1, 2, 3, 4]);
         // terminate dict
         encoded_resp.push(b'e');
 
@@ -487,10 +926,13 @@ mod tests {
                     announce.uploaded.to_string(),
                 ),
                 Matcher::UrlEncoded("left".into(), announce.left.to_string()),
+                Matcher::UrlEncoded("key".into(), "00c0ffee".into()),
                 Matcher::UrlEncoded(
                     "numwant".into(),
                     announce.peer_count.unwrap().to_string(),
                 ),
+                Matcher::UrlEncoded("event".into(), "started".into()),
+                Matcher::UrlEncoded("trackerid".into(), "tracker-1".into()),
             ]))
             .with_status(200)
             .with_body(encoded_resp)
@@ -500,6 +942,58 @@ mod tests {
         assert_eq!(resp, expected_resp);
     }
 
+    #[test]
+    fn should_parse_ipv6_peers() {
+        let mut b = Vec::new();
+        b.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        b.extend_from_slice(&6881u16.to_be_bytes());
+        let peers = parse_compact_peers(&b, true).unwrap();
+        assert_eq!(
+            peers,
+            vec![SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 6881)]
+        );
+        assert!(parse_compact_peers(&b[..10], true).is_err());
+    }
+
+    #[test]
+    fn should_merge_dual_stack_responses() {
+        let response = |peers: Vec<SocketAddr>| Response {
+            tracker_id: None,
+            failure_reason: None,
+            warning_message: None,
+            interval: Some(Duration::from_secs(15)),
+            min_interval: None,
+            seeder_count: None,
+            leecher_count: None,
+            peers,
+            peers6: Vec::new(),
+            external_ip: None,
+        };
+        let peer: SocketAddr = "2.156.201.254:49123".parse().unwrap();
+        let peer6: SocketAddr = "[2001:db8::1]:49123".parse().unwrap();
+
+        // peers of both responses are merged without duplicates
+        let resp = merge_responses(
+            Ok(response(vec![peer])),
+            Ok(response(vec![peer, peer6])),
+        )
+        .unwrap();
+        assert_eq!(resp.peers, vec![peer, peer6]);
+
+        // the announce succeeds if either of the requests succeeds
+        let resp = merge_responses(
+            Err(TrackerError::Timeout),
+            Ok(response(vec![peer6])),
+        )
+        .unwrap();
+        assert_eq!(resp.peers, vec![peer6]);
+        assert!(merge_responses(
+            Err(TrackerError::Timeout),
+            Err(TrackerError::Timeout)
+        )
+        .is_err());
+    }
+
     fn encode_compact_peers_list(peers: &[(Ipv4Addr, u16)]) -> Vec<u8> {
         let encoded_peers: Vec<_> = peers
             .into_iter()
@@ -0,0 +1,456 @@
+//! The UDP tracker protocol, as specified in
+//! [BEP 15](https://www.bittorrent.org/beps/bep_0015.html).
+//!
+//! Before announcing, the client must obtain a connection ID from the tracker,
+//! which is valid for a minute and which is used to prevent address spoofing.
+//! Each request carries a random transaction ID that the tracker echoes back,
+//! so that responses can be matched to requests. As UDP is unreliable,
+//! requests that aren't answered in time are retransmitted with an
+//! exponentially growing timeout.
+//!
+//! Scrape requests are not implemented: the announce response already carries
+//! the seeder and leecher counts of the swarm, which is all torrents report.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, BufMut};
+use reqwest::Url;
+use tokio::net::UdpSocket;
+
+use super::{
+    parse_compact_peers, Announce, Event, Response, Result, TrackerError,
+};
+use crate::rt;
+
+/// The magic constant that identifies the protocol in connect requests.
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_ERROR: u32 = 3;
+
+/// A connection ID may be used for this long after it was received.
+const CONNECTION_ID_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The timeout of the first transmission of a request. The nth retransmission
+/// waits `15 * 2 ^ n` seconds.
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The spec allows up to 8 retransmissions, which would take over two hours.
+/// As the torrent only fails over to the next tracker in the tier once an
+/// announce has failed, we give up much sooner.
+const MAX_RETRANSMIT_COUNT: u32 = 2;
+
+/// The largest response we expect. An announce response has a 20 byte header
+/// followed by 6 bytes (or 18 for IPv6) per peer.
+const MAX_RESPONSE_LEN: usize = 4096;
+
+/// The UDP tracker client.
+pub(crate) struct UdpTracker {
+    /// The URL of the tracker, whose host and port is where requests are sent.
+    url: Url,
+    /// The socket connected to the tracker. It is created on the first
+    /// request.
+    socket: Option<UdpSocket>,
+    /// Whether the tracker is reached over IPv6, which determines the format
+    /// of the peers it returns.
+    is_ipv6: bool,
+    /// The last connection ID received from the tracker and the time it was
+    /// received.
+    connection: Option<(u64, Instant)>,
+    /// The timeout of a request's first transmission.
+    retransmit_timeout: Duration,
+}
+
+impl UdpTracker {
+    pub fn new(url: Url) -> Self {
+        Self {
+            url,
+            socket: None,
+            is_ipv6: false,
+            connection: None,
+            retransmit_timeout: RETRANSMIT_TIMEOUT,
+        }
+    }
+
+    /// Sends an announce request to the tracker and returns its response.
+    pub async fn announce(&mut self, params: Announce) -> Result<Response> {
+        let event = match params.event {
+            None => 0,
+            Some(Event::Completed) => 1,
+            Some(Event::Started) => 2,
+            Some(Event::Stopped) => 3,
+        };
+        // only IPv4 addresses may be sent, otherwise the tracker uses the
+        // source address of the packet
+        let ip = match params.ip {
+            Some(IpAddr::V4(ip)) => u32::from(ip),
+            _ => 0,
+        };
+        // -1 signals the tracker to pick the number of peers
+        let peer_count = params.peer_count.map(|c| c as i32).unwrap_or(-1);
+
+        let resp = self
+            .request(ACTION_ANNOUNCE, |buf| {
+                buf.put_slice(&params.info_hash);
+                buf.put_slice(&params.peer_id);
+                buf.put_u64(params.downloaded);
+                buf.put_u64(params.left);
+                buf.put_u64(params.uploaded);
+                buf.put_u32(event);
+                buf.put_u32(ip);
+                buf.put_u32(params.key);
+                buf.put_i32(peer_count);
+                buf.put_u16(params.port);
+            })
+            .await?;
+
+        let resp = match resp {
+            Ok(resp) => resp,
+            Err(failure_reason) => {
+                return Ok(Response {
+                    tracker_id: None,
+                    failure_reason: Some(failure_reason),
+                    warning_message: None,
+                    interval: None,
+                    min_interval: None,
+                    seeder_count: None,
+                    leecher_count: None,
+                    peers: Vec::new(),
+                    peers6: Vec::new(),
+                    external_ip: None,
+                });
+            }
+        };
+
+        if resp.len() < 12 {
+            return Err(TrackerError::InvalidResponse);
+        }
+        let mut resp = &resp[..];
+        let interval = Duration::from_secs(resp.get_u32() as u64);
+        let leecher_count = resp.get_u32() as usize;
+        let seeder_count = resp.get_u32() as usize;
+        // the address family of the peers is that of the tracker
+        let peers = parse_compact_peers(resp, self.is_ipv6)?;
+
+        Ok(Response {
+            tracker_id: None,
+            failure_reason: None,
+            warning_message: None,
+            interval: Some(interval),
+            min_interval: None,
+            seeder_count: Some(seeder_count),
+            leecher_count: Some(leecher_count),
+            peers,
+            peers6: Vec::new(),
+            external_ip: None,
+        })
+    }
+
+    /// Sends a request with the given action to the tracker, connecting to it
+    /// first if we don't have a valid connection ID, and returns the body of
+    /// the response following the action and transaction ID.
+    ///
+    /// The body of the request after the header is written by `write_body`.
+    /// If the tracker responds with an error, its message is returned as the
+    /// inner error.
+    async fn request(
+        &mut self,
+        action: u32,
+        write_body: impl Fn(&mut Vec<u8>),
+    ) -> Result<std::result::Result<Vec<u8>, String>> {
+        self.ensure_socket().await?;
+
+        let mut buf = [0; MAX_RESPONSE_LEN];
+        for n in 0..=MAX_RETRANSMIT_COUNT {
+            // the connection ID may have expired while waiting for a previous
+            // transmission, in which case we reconnect
+            let connection_id = self.connection_id().await?;
+            let transaction_id = rand::random();
+
+            let mut req = Vec::with_capacity(98);
+            req.put_u64(connection_id);
+            req.put_u32(action);
+            req.put_u32(transaction_id);
+            write_body(&mut req);
+
+            if let Some(len) = self
+                .send_and_recv(&req, transaction_id, n, &mut buf)
+                .await?
+            {
+                let mut resp = &buf[..len];
+                let resp_action = resp.get_u32();
+                // skip the transaction id, it was already checked
+                resp.advance(4);
+                return if resp_action == action {
+                    Ok(Ok(resp.to_vec()))
+                } else if resp_action == ACTION_ERROR {
+                    // the connection ID may have been rejected, so get a new
+                    // one next time
+                    self.connection = None;
+                    Ok(Err(String::from_utf8_lossy(resp).into_owned()))
+                } else {
+                    Err(TrackerError::InvalidResponse)
+                };
+            }
+
+            log::debug!(
+                "UDP tracker {} request timed out (attempt {})",
+                self.url,
+                n + 1
+            );
+        }
+
+        Err(TrackerError::Timeout)
+    }
+
+    /// Returns the cached connection ID if it's still valid, or connects to
+    /// the tracker to get a new one.
+    async fn connection_id(&mut self) -> Result<u64> {
+        if let Some((id, time)) = self.connection {
+            if time.elapsed() < CONNECTION_ID_TIMEOUT {
+                return Ok(id);
+            }
+        }
+
+        let mut buf = [0; 16];
+        for n in 0..=MAX_RETRANSMIT_COUNT {
+            let transaction_id = rand::random();
+            let mut req = Vec::with_capacity(16);
+            req.put_u64(PROTOCOL_ID);
+            req.put_u32(ACTION_CONNECT);
+            req.put_u32(transaction_id);
+
+            if let Some(len) = self
+                .send_and_recv(&req, transaction_id, n, &mut buf)
+                .await?
+            {
+                let mut resp = &buf[..len];
+                if len < 16 || resp.get_u32() != ACTION_CONNECT {
+                    return Err(TrackerError::InvalidResponse);
+                }
+                resp.advance(4);
+                let id = resp.get_u64();
+                self.connection = Some((id, Instant::now()));
+                return Ok(id);
+            }
+        }
+
+        Err(TrackerError::Timeout)
+    }
+
+    /// Sends the request and waits for the response with the matching
+    /// transaction ID for the duration of the nth transmission's timeout.
+    ///
+    /// Returns the length of the response or `None` if it timed out.
+    async fn send_and_recv(
+        &mut self,
+        req: &[u8],
+        transaction_id: u32,
+        n: u32,
+        buf: &mut [u8],
+    ) -> Result<Option<usize>> {
+        let timeout = self.retransmit_timeout * 2u32.pow(n);
+        let socket = self
+            .socket
+            .as_mut()
+            .expect("socket must be created before sending requests");
+        socket.send(req).await?;
+
+        let recv = async {
+            loop {
+                let len = socket.recv(buf).await?;
+                // responses to previous transmissions or malformed packets
+                // are dropped
+                if len >= 8 {
+                    let mut header = &buf[4..8];
+                    if header.get_u32() == transaction_id {
+                        return Ok::<_, io::Error>(len);
+                    }
+                }
+            }
+        };
+        match rt::timeout(timeout, recv).await {
+            Ok(len) => Ok(Some(len?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Resolves the tracker's address and creates a socket connected to it, if
+    /// not already done.
+    async fn ensure_socket(&mut self) -> Result<()> {
+        if self.socket.is_some() {
+            return Ok(());
+        }
+
+        let host = self.url.host_str().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "tracker has no host")
+        })?;
+        let port = self.url.port().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "tracker has no port")
+        })?;
+        let addr = tokio::net::lookup_host((host, port))
+            .await?
+            .next()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "could not resolve tracker host",
+                )
+            })?;
+
+        let local_addr: SocketAddr = if addr.is_ipv6() {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(local_addr).await?;
+        socket.connect(addr).await?;
+        self.socket = Some(socket);
+        self.is_ipv6 = addr.is_ipv6();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Starts a mock tracker that answers connect requests with the given
+    /// connection ID and announce requests with a single peer. The first
+    /// `drop_count` requests are ignored. Returns the tracker's URL.
+    async fn start_tracker(
+        connection_id: u64,
+        drop_count: usize,
+    ) -> (Url, rt::JoinHandle<Vec<u32>>) {
+        let mut socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let url = format!("udp://{}/announce", addr).parse().unwrap();
+
+        let handle = rt::spawn(async move {
+            // the actions of the requests received by the tracker
+            let mut actions = Vec::new();
+            let mut buf = [0; 1024];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let mut req = &buf[..len];
+                let conn_id = req.get_u64();
+                let action = req.get_u32();
+                let transaction_id = req.get_u32();
+                actions.push(action);
+                if actions.len() <= drop_count {
+                    continue;
+                }
+
+                let mut resp = Vec::new();
+                match action {
+                    ACTION_CONNECT => {
+                        assert_eq!(conn_id, PROTOCOL_ID);
+                        resp.put_u32(ACTION_CONNECT);
+                        resp.put_u32(transaction_id);
+                        resp.put_u64(connection_id);
+                    }
+                    ACTION_ANNOUNCE => {
+                        assert_eq!(conn_id, connection_id);
+                        assert_eq!(req.len(), 82);
+                        // skip to the event
+                        req.advance(20 + 20 + 24);
+                        assert_eq!(req.get_u32(), 2);
+                        // skip the IP address to the key
+                        req.advance(4);
+                        assert_eq!(req.get_u32(), 0xc0ffee);
+                        resp.put_u32(ACTION_ANNOUNCE);
+                        resp.put_u32(transaction_id);
+                        resp.put_u32(1800);
+                        resp.put_u32(3);
+                        resp.put_u32(5);
+                        resp.put_slice(&[2, 156, 201, 254, 0xbf, 0xe3]);
+                    }
+                    _ => break,
+                }
+                socket.send_to(&resp, from).await.unwrap();
+            }
+            actions
+        });
+
+        (url, handle)
+    }
+
+    fn announce_params() -> Announce {
+        Announce {
+            info_hash: [1; 20],
+            peer_id: [2; 20],
+            port: 16,
+            downloaded: 1234,
+            uploaded: 1234,
+            left: 1234,
+            peer_count: None,
+            ip: None,
+            event: Some(Event::Started),
+            tracker_id: None,
+            key: 0xc0ffee,
+        }
+    }
+
+    /// Sends a request with an unknown action to stop the mock tracker.
+    async fn stop_tracker(tracker: &mut UdpTracker) {
+        let mut req = Vec::new();
+        req.put_u64(0);
+        req.put_u32(u32::MAX);
+        req.put_u32(0);
+        tracker.socket.as_mut().unwrap().send(&req).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_connect_and_announce() {
+        let (url, handle) = start_tracker(42, 0).await;
+        let mut tracker = UdpTracker::new(url);
+
+        let resp = tracker.announce(announce_params()).await.unwrap();
+        assert_eq!(resp.interval, Some(Duration::from_secs(1800)));
+        assert_eq!(resp.leecher_count, Some(3));
+        assert_eq!(resp.seeder_count, Some(5));
+        assert_eq!(
+            resp.peers,
+            vec![SocketAddr::new(
+                Ipv4Addr::new(2, 156, 201, 254).into(),
+                49123
+            )]
+        );
+
+        // the connection ID should be reused for the next request
+        tracker.announce(announce_params()).await.unwrap();
+
+        stop_tracker(&mut tracker).await;
+        assert_eq!(
+            handle.await.unwrap(),
+            vec![ACTION_CONNECT, ACTION_ANNOUNCE, ACTION_ANNOUNCE, u32::MAX]
+        );
+    }
+
+    #[tokio::test]
+    async fn should_retransmit_unanswered_requests() {
+        let (url, handle) = start_tracker(42, 2).await;
+        let mut tracker = UdpTracker::new(url);
+        tracker.retransmit_timeout = Duration::from_millis(50);
+
+        tracker.announce(announce_params()).await.unwrap();
+
+        stop_tracker(&mut tracker).await;
+        assert_eq!(
+            handle.await.unwrap(),
+            vec![
+                ACTION_CONNECT,
+                ACTION_CONNECT,
+                ACTION_CONNECT,
+                ACTION_ANNOUNCE,
+                u32::MAX
+            ]
+        );
+    }
+}
@@ -0,0 +1,182 @@
+//! WebSocket trackers, as used by WebTorrent.
+//!
+//! Announces are JSON messages sent over a WebSocket connection, in which the
+//! info hash and peer id are encoded as strings whose characters are the raw
+//! bytes. Browser peers in the swarm can only be reached over WebRTC, which
+//! cratetorrent doesn't support, so we don't send any WebRTC offers and the
+//! announces only serve to make our presence and transfer statistics known and
+//! to learn the size of the swarm.
+
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use reqwest::Url;
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{Announce, Event, Response, Result, TrackerError};
+use crate::rt;
+
+pub use tokio_tungstenite::tungstenite::Error as WebSocketError;
+
+/// The time we wait for the tracker to connect and respond to an announce.
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The WebSocket tracker client.
+pub(crate) struct WsTracker {
+    url: Url,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "serde_crate")]
+struct AnnounceRequest {
+    action: &'static str,
+    info_hash: String,
+    peer_id: String,
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trackerid: Option<String>,
+    /// As we can't answer WebRTC offers, we don't want any.
+    numwant: usize,
+    offers: Vec<()>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate")]
+struct AnnounceResponse {
+    action: Option<String>,
+    info_hash: Option<String>,
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    #[serde(rename = "warning message")]
+    warning_message: Option<String>,
+    interval: Option<u64>,
+    #[serde(rename = "min interval")]
+    min_interval: Option<u64>,
+    #[serde(rename = "tracker id")]
+    tracker_id: Option<String>,
+    complete: Option<usize>,
+    incomplete: Option<usize>,
+}
+
+impl WsTracker {
+    pub fn new(url: Url) -> Self {
+        Self { url }
+    }
+
+    /// Connects to the tracker and sends an announce request, returning its
+    /// response. The returned peer list is always empty.
+    pub async fn announce(&mut self, params: Announce) -> Result<Response> {
+        match rt::timeout(ANNOUNCE_TIMEOUT, self.send_announce(params)).await {
+            Ok(result) => result,
+            Err(_) => Err(TrackerError::Timeout),
+        }
+    }
+
+    async fn send_announce(&self, params: Announce) -> Result<Response> {
+        let info_hash = binary_string(&params.info_hash);
+        let req = AnnounceRequest {
+            action: "announce",
+            info_hash: info_hash.clone(),
+            peer_id: binary_string(&params.peer_id),
+            uploaded: params.uploaded,
+            downloaded: params.downloaded,
+            left: params.left,
+            event: params.event.map(|e| e.as_str()),
+            trackerid: params.tracker_id,
+            numwant: 0,
+            offers: Vec::new(),
+        };
+        let req = serde_json::to_string(&req)
+            .expect("announce request should serialize");
+
+        let (mut socket, _) =
+            tokio_tungstenite::connect_async(self.url.clone()).await?;
+        socket.send(Message::Text(req)).await?;
+
+        // the stopped event doesn't need a response
+        if params.event == Some(Event::Stopped) {
+            socket.close(None).await.ok();
+            return Ok(empty_response());
+        }
+
+        // the tracker may relay messages of other torrents or peers on the
+        // same connection, so wait for the response to our announce
+        while let Some(msg) = socket.next().await {
+            let text = match msg? {
+                Message::Text(text) => text,
+                Message::Close(_) => break,
+                _ => continue,
+            };
+            let resp: AnnounceResponse = match serde_json::from_str(&text) {
+                Ok(resp) => resp,
+                Err(_) => return Err(TrackerError::InvalidResponse),
+            };
+            if let Some(failure_reason) = resp.failure_reason {
+                socket.close(None).await.ok();
+                return Ok(Response {
+                    failure_reason: Some(failure_reason),
+                    ..empty_response()
+                });
+            }
+            if resp.action.as_deref() != Some("announce")
+                || resp.info_hash.as_ref() != Some(&info_hash)
+            {
+                continue;
+            }
+
+            socket.close(None).await.ok();
+            return Ok(Response {
+                tracker_id: resp.tracker_id,
+                failure_reason: None,
+                warning_message: resp.warning_message,
+                interval: resp.interval.map(Duration::from_secs),
+                min_interval: resp.min_interval.map(Duration::from_secs),
+                seeder_count: resp.complete,
+                leecher_count: resp.incomplete,
+                peers: Vec::new(),
+                peers6: Vec::new(),
+                external_ip: None,
+            });
+        }
+
+        Err(TrackerError::InvalidResponse)
+    }
+}
+
+/// Encodes the bytes as a string in which each character's code point is the
+/// value of a byte, the way WebTorrent encodes info hashes and peer ids.
+fn binary_string(b: &[u8]) -> String {
+    b.iter().map(|&b| b as char).collect()
+}
+
+fn empty_response() -> Response {
+    Response {
+        tracker_id: None,
+        failure_reason: None,
+        warning_message: None,
+        interval: None,
+        min_interval: None,
+        seeder_count: None,
+        leecher_count: None,
+        peers: Vec::new(),
+        peers6: Vec::new(),
+        external_ip: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_encode_binary_string() {
+        let s = binary_string(&[0x00, 0x61, 0xff]);
+        assert_eq!(s, "\u{0}a\u{ff}");
+        // JSON escapes control characters but keeps the code points
+        assert_eq!(serde_json::to_string(&s).unwrap(), "\"\\u0000a\u{ff}\"");
+    }
+}
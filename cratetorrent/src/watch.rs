@@ -0,0 +1,338 @@
+//! Watching a directory for torrents to add.
+//!
+//! Headless deployments commonly add torrents by dropping their metainfo
+//! files into a directory. The engine runs a single watch task if
+//! [`EngineConf::watch`](crate::conf::EngineConf::watch) is set, which scans
+//! the directory periodically and adds the `.torrent` and `.magnet` files it
+//! finds with the default options, as if they were added with
+//! [`EngineHandle::add_torrent`](crate::engine::EngineHandle::add_torrent).
+//!
+//! The consumed files are then moved or renamed, as set out in
+//! [`WatchConf`], so that they are not added again on the next scan. The
+//! directory is polled rather than watched with OS specific notifications,
+//! as a few seconds of delay don't matter for adding torrents.
+
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use futures::{select, stream::StreamExt};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tracing::Instrument;
+
+use crate::{
+    alert::{Alert, AlertSender},
+    conf::WatchConf,
+    engine::{self, AddTorrent},
+    error::Error,
+    logging, rt,
+};
+
+/// Files modified more recently than this are skipped until the next scan,
+/// as they may still be being written.
+const MIN_FILE_AGE: Duration = Duration::from_secs(2);
+
+/// Spawns the watch task.
+pub(crate) fn spawn(
+    conf: WatchConf,
+    engine_tx: engine::Sender,
+    alert_tx: AlertSender,
+) -> (JoinHandle, Sender) {
+    log::info!(
+        target: logging::ENGINE,
+        "Spawning watch task for directory {:?}",
+        conf.dir
+    );
+    let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+    let scan_interval = conf.scan_interval;
+    let scanner = Arc::new(Scanner::new(conf, engine_tx, alert_tx));
+    let join_handle = rt::spawn(
+        run(scanner, scan_interval, cmd_rx)
+            .instrument(tracing::info_span!("watch")),
+    );
+    log::info!(target: logging::ENGINE, "Spawned watch task");
+    (join_handle, cmd_tx)
+}
+
+pub(crate) type JoinHandle = rt::JoinHandle<()>;
+
+/// The channel for sending commands to the watch task.
+pub(crate) type Sender = UnboundedSender<Command>;
+/// The channel on which the watch task listens for commands.
+type Receiver = UnboundedReceiver<Command>;
+
+/// The commands the watch task can receive.
+#[derive(Debug)]
+pub(crate) enum Command {
+    /// Shuts down the watch task.
+    Shutdown,
+}
+
+/// Scans the directory every interval until the task is shut down.
+async fn run(scanner: Arc<Scanner>, scan_interval: Duration, cmd_rx: Receiver) {
+    let mut scan_timer = rt::interval(scan_interval).fuse();
+    let mut cmd_rx = cmd_rx.fuse();
+    loop {
+        select! {
+            _ = scan_timer.select_next_some() => {
+                // the scan does blocking file system IO
+                let scanner = Arc::clone(&scanner);
+                let scan = rt::spawn_blocking(move || scanner.scan());
+                if let Err(e) = scan.await.expect("watch scan has panicked") {
+                    log::warn!(
+                        target: logging::ENGINE,
+                        "Cannot scan watched directory: {}",
+                        e
+                    );
+                }
+            }
+            cmd = cmd_rx.select_next_some() => match cmd {
+                Command::Shutdown => {
+                    log::info!(
+                        target: logging::ENGINE,
+                        "Shutting down watch task"
+                    );
+                    break;
+                }
+            },
+        }
+    }
+}
+
+/// What a watched file is added as, by its extension.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FileKind {
+    /// A metainfo file.
+    Metainfo,
+    /// A text file of magnet links, one per line.
+    Magnet,
+}
+
+impl FileKind {
+    fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "torrent" => Some(Self::Metainfo),
+            "magnet" => Some(Self::Magnet),
+            _ => None,
+        }
+    }
+}
+
+struct Scanner {
+    conf: WatchConf,
+    /// The torrents are added by sending them to the engine on this channel.
+    engine_tx: engine::Sender,
+    alert_tx: AlertSender,
+    /// How long after its last modification a file is added.
+    min_file_age: Duration,
+    /// The files that were added but couldn't be moved out of the way, which
+    /// are skipped so that they are not added again.
+    stuck_files: Mutex<HashSet<PathBuf>>,
+}
+
+impl Scanner {
+    fn new(
+        conf: WatchConf,
+        engine_tx: engine::Sender,
+        alert_tx: AlertSender,
+    ) -> Self {
+        Self {
+            conf,
+            engine_tx,
+            alert_tx,
+            min_file_age: MIN_FILE_AGE,
+            stuck_files: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Adds the torrents of the new files in the directory, then moves the
+    /// files out of the way.
+    ///
+    /// For each torrent added, an [`Alert::WatchedTorrentAdded`] alert is
+    /// posted, and for each that can't be, an [`Alert::WatchedFileRejected`]
+    /// alert. A file of magnet links is considered added if any of its links
+    /// were.
+    fn scan(&self) -> io::Result<()> {
+        log::trace!(
+            target: logging::ENGINE,
+            "Scanning watched directory {:?}",
+            self.conf.dir
+        );
+        let now = SystemTime::now();
+        let mut stuck_files = self.stuck_files.lock().unwrap();
+        for entry in fs::read_dir(&self.conf.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let kind = match FileKind::from_path(&path) {
+                Some(kind) => kind,
+                None => continue,
+            };
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() || stuck_files.contains(&path) {
+                continue;
+            }
+            let age =
+                now.duration_since(metadata.modified()?).unwrap_or_default();
+            if age < self.min_file_age {
+                log::trace!(
+                    target: logging::ENGINE,
+                    "Skipping recently modified file {:?}",
+                    path
+                );
+                continue;
+            }
+
+            let torrents = match kind {
+                FileKind::Metainfo => vec![Ok(AddTorrent::file(&path))],
+                FileKind::Magnet => match fs::read_to_string(&path) {
+                    Ok(links) => links
+                        .lines()
+                        .map(str::trim)
+                        .filter(|line| !line.is_empty())
+                        .map(|link| Ok(AddTorrent::magnet(link)))
+                        .collect(),
+                    Err(e) => vec![Err(Error::Io(e))],
+                },
+            };
+            let mut is_added = false;
+            for torrent in torrents {
+                let result = torrent.and_then(|torrent| {
+                    engine::add_torrent(&self.engine_tx, torrent)
+                });
+                match result {
+                    Ok(id) => {
+                        log::info!(
+                            target: logging::ENGINE,
+                            "Added torrent {} from {:?}",
+                            id,
+                            path
+                        );
+                        is_added = true;
+                        self.alert_tx
+                            .send(Alert::WatchedTorrentAdded {
+                                id,
+                                path: path.clone(),
+                            })
+                            .ok();
+                    }
+                    Err(e) => {
+                        log::warn!(
+                            target: logging::ENGINE,
+                            "Cannot add torrent from {:?}: {}",
+                            path,
+                            e
+                        );
+                        self.alert_tx
+                            .send(Alert::WatchedFileRejected {
+                                path: path.clone(),
+                                error: e,
+                            })
+                            .ok();
+                    }
+                }
+            }
+
+            if let Err(e) = self.consume(&path, is_added) {
+                log::warn!(
+                    target: logging::ENGINE,
+                    "Cannot move watched file {:?}: {}",
+                    path,
+                    e
+                );
+                stuck_files.insert(path);
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the added file to the configured directory or renames it with
+    /// the `.added` suffix, or if it was not added, renames it with the
+    /// `.invalid` suffix.
+    fn consume(&self, path: &Path, is_added: bool) -> io::Result<()> {
+        let new_path = match &self.conf.added_dir {
+            Some(dir) if is_added => {
+                // the file name is known to exist, as the file has an
+                // extension
+                dir.join(path.file_name().unwrap())
+            }
+            _ => {
+                let mut name = OsString::from(path);
+                name.push(if is_added { ".added" } else { ".invalid" });
+                PathBuf::from(name)
+            }
+        };
+        log::debug!(
+            target: logging::ENGINE,
+            "Moving watched file {:?} to {:?}",
+            path,
+            new_path
+        );
+        fs::rename(path, &new_path).or_else(|_| {
+            // the directory may be on another file system
+            fs::copy(path, &new_path)?;
+            fs::remove_file(path)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that the torrent and magnet files in the directory are added and
+    /// renamed, that invalid files are marked as such, and that other files
+    /// are left alone.
+    #[test]
+    fn should_add_and_rename_watched_files() {
+        let dir = std::env::temp_dir().join("cratetorrent_watch_test");
+        fs::create_dir_all(&dir).unwrap();
+        let magnet = "magnet:?xt=urn:btih:\
+            c12fe1c06bba254a9dc9f519b335aa7c1367a88a";
+        fs::write(dir.join("links.magnet"), format!("{}\n\n", magnet)).unwrap();
+        fs::write(dir.join("bad.torrent"), b"not bencode").unwrap();
+        fs::write(dir.join("notes.txt"), b"not a torrent").unwrap();
+
+        let (engine_tx, mut engine_rx) = mpsc::unbounded_channel();
+        let (alert_tx, mut alert_rx) = mpsc::unbounded_channel();
+        let mut scanner =
+            Scanner::new(WatchConf::new(&dir), engine_tx, alert_tx);
+        scanner.min_file_age = Duration::from_secs(0);
+        scanner.scan().unwrap();
+
+        assert!(matches!(
+            engine_rx.try_recv(),
+            Ok(engine::Command::AddTorrent { .. })
+        ));
+        assert!(engine_rx.try_recv().is_err());
+        let mut added_count = 0;
+        let mut rejected_count = 0;
+        while let Ok(alert) = alert_rx.try_recv() {
+            match alert {
+                Alert::WatchedTorrentAdded { path, .. } => {
+                    assert_eq!(path, dir.join("links.magnet"));
+                    added_count += 1;
+                }
+                Alert::WatchedFileRejected { path, .. } => {
+                    assert_eq!(path, dir.join("bad.torrent"));
+                    rejected_count += 1;
+                }
+                _ => panic!("unexpected alert"),
+            }
+        }
+        assert_eq!(added_count, 1);
+        assert_eq!(rejected_count, 1);
+
+        assert!(dir.join("links.magnet.added").is_file());
+        assert!(dir.join("bad.torrent.invalid").is_file());
+        assert!(dir.join("notes.txt").is_file());
+        assert!(!dir.join("links.magnet").exists());
+        assert!(!dir.join("bad.torrent").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -64,6 +64,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let _torrent_id = handle.create_torrent(TorrentParams {
         metainfo,
         listen_addr: args.listen,
+        piece_picker: None,
         mode: args.mode,
         conf: None,
     })?;